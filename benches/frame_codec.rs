@@ -0,0 +1,63 @@
+//! Encoding and decoding of one frame (serialize, compress, encrypt and back) for 1 KB, 1 MB and 100 MB payloads with every codec.
+//! This is the cost that every chunk and every result pays twice, once on each side.
+//! A regression of more than 5 % in the throughput of one codec (for 1 MB and 100 MB) needs a reason in the pull request,
+//! for 1 KB the fixed cost per frame counts and 10 % is still fine.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use node_crunch::{NCConfiguration, NCCodec};
+use node_crunch::nc_communicator::NCCommunicator;
+
+const SIZES: [(&str, usize); 3] = [("1KB", 1024), ("1MB", 1024 * 1024), ("100MB", 100 * 1024 * 1024)];
+
+const CODECS: [NCCodec; 3] = [NCCodec::None, NCCodec::Lz4, NCCodec::Zstd(3)];
+
+/// Data that can be compressed, but not too well: runs of bytes from a simple random number generator.
+fn payload(size: usize) -> Vec<u8> {
+    let mut state: u32 = 7;
+
+    (0..size).map(|i| {
+        if i % 8 == 0 {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        }
+
+        (state >> 24) as u8
+    }).collect()
+}
+
+fn communicator(encrypt: bool) -> NCCommunicator {
+    NCCommunicator::new(&NCConfiguration { compress: true, compression_min_size: 0, encrypt, key: "u8PqN2lO3ZJbm6DHkTnR0cyVaSx1FWgi".to_string(), ..Default::default() }).unwrap()
+}
+
+fn bench_frames(c: &mut Criterion) {
+    for encrypt in [false, true] {
+        let mut group = c.benchmark_group(if encrypt { "frame_encrypted" } else { "frame" });
+        let mut nc_communicator = communicator(encrypt);
+
+        for (name, size) in SIZES {
+            let data = payload(size);
+            group.throughput(Throughput::Bytes(size as u64));
+
+            if size > 1024 * 1024 {
+                group.sample_size(10);
+            }
+
+            for codec in CODECS {
+                let id = format!("{:?}/{}", codec, name);
+                group.bench_with_input(BenchmarkId::new("encode", &id), &data, |b, data| {
+                    b.iter(|| nc_communicator.nc_encode_data_codec(data, codec).unwrap())
+                });
+
+                let frame = nc_communicator.nc_encode_data_codec(&data, codec).unwrap();
+                group.bench_with_input(BenchmarkId::new("decode", &id), &frame, |b, frame| {
+                    b.iter(|| nc_communicator.nc_decode_data::<Vec<u8>>(frame).unwrap())
+                });
+            }
+        }
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_frames);
+criterion_main!(benches);
@@ -0,0 +1,60 @@
+//! Heartbeats from 1000 registered nodes, sent by 4 threads over the loopback interface while the server is waiting for the nodes
+//! to finish the job. Every heartbeat is one connection, the server only updates the shard of the heartbeat table for the node.
+//! The throughput is the number of heartbeats per second; only compare runs on the same machine, more than 10 % slower needs a reason.
+
+use std::thread;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use node_crunch::{NCConfiguration, NCServerStarter, NCNodeStarter, NCClient, NCResourceReport, NodeID, RangeServer, RangeNode};
+
+const NODES: usize = 1000;
+const THREADS: usize = 4;
+const HEARTBEATS: usize = 4000;
+
+/// One heartbeat for every node, the nodes are split up between the threads.
+fn send_heartbeats(config: &NCConfiguration, node_ids: &[NodeID]) {
+    thread::scope(|scope| {
+        for n in 0..THREADS {
+            scope.spawn(move || {
+                let mut nc_client = NCClient::connect(config).unwrap();
+
+                for (count, node_id) in node_ids.iter().cycle().take(HEARTBEATS).enumerate().skip(n).step_by(THREADS) {
+                    nc_client.set_node_id(*node_id);
+                    nc_client.heartbeat_with_resources(NCResourceReport { free_disk_bytes: Some(count as u64), ..Default::default() }).unwrap();
+                }
+            });
+        }
+    });
+}
+
+fn bench_heartbeat(c: &mut Criterion) {
+    let config = NCConfiguration { port: 0, delay_request_data: 1, heartbeat: 60, ..Default::default() };
+    let mut server_starter = NCServerStarter::new(config.clone());
+    let server_handle = server_starter.server_handle();
+    let server_thread = thread::spawn(move || {
+        server_starter.start(RangeServer::new(0..1, 1, 0u64, |sum: &mut u64, _range, values: &[u64]| *sum += values.iter().sum::<u64>()))
+    });
+    let port = server_handle.wait_local_addr(Duration::from_secs(10)).expect("the server has not started").port();
+    let config = NCConfiguration { port, ..config };
+
+    let node_ids: Vec<NodeID> = (0..NODES).map(|_| {
+        let mut nc_client = NCClient::connect(&config).unwrap();
+        nc_client.register::<()>().unwrap();
+        nc_client.node_id()
+    }).collect();
+
+    let mut group = c.benchmark_group("heartbeat");
+    group.throughput(Throughput::Elements(HEARTBEATS as u64));
+    group.sample_size(10);
+    group.bench_function(format!("{}_nodes/{}_threads", NODES, THREADS), |b| b.iter(|| send_heartbeats(&config, &node_ids)));
+    group.finish();
+
+    // A node finishes the job, so the server stops
+    NCNodeStarter::new(config).start(RangeNode::new(|i| i)).unwrap();
+    server_thread.join().unwrap().unwrap();
+}
+
+criterion_group!(benches, bench_heartbeat);
+criterion_main!(benches);
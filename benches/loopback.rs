@@ -0,0 +1,40 @@
+//! Round trips of small chunks between the server and the nodes over the loopback interface, with the in-process runner
+//! ([`nc_local::run()`](node_crunch::nc_local::run)): every chunk is one connection for the data and one for the result.
+//! This is the per chunk overhead of the protocol, the user code does almost nothing.
+//! The time includes starting and stopping the job, so only compare runs on the same machine; more than 10 % slower needs a reason.
+
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use node_crunch::{NCConfiguration, NCCodec, RangeServer, RangeNode, nc_local};
+
+const CHUNKS: u64 = 500;
+
+fn run_job(config: &NCConfiguration, num_nodes: usize) {
+    let server = RangeServer::new(0..CHUNKS, 1, 0u64, |sum: &mut u64, _range, values: &[u64]| *sum += values.iter().sum::<u64>());
+    let sum = server.accumulator();
+
+    nc_local::run(config, server, |_| RangeNode::new(|i| i * 2), num_nodes).unwrap();
+    assert_eq!(*sum.lock().unwrap(), CHUNKS * (CHUNKS - 1));
+}
+
+fn bench_loopback(c: &mut Criterion) {
+    let plain = NCConfiguration { port: 0, delay_request_data: 1, heartbeat: 1, ..Default::default() };
+    let encrypted = NCConfiguration { encrypt: true, key: "u8PqN2lO3ZJbm6DHkTnR0cyVaSx1FWgi".to_string(), allowed_codecs: vec![NCCodec::Lz4], ..plain.clone() };
+
+    let mut group = c.benchmark_group("loopback");
+    group.throughput(Throughput::Elements(CHUNKS));
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(30));
+
+    for num_nodes in [1, 4] {
+        group.bench_function(format!("round_trip/{}_nodes", num_nodes), |b| b.iter(|| run_job(&plain, num_nodes)));
+        group.bench_function(format!("round_trip_encrypted/{}_nodes", num_nodes), |b| b.iter(|| run_job(&encrypted, num_nodes)));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_loopback);
+criterion_main!(benches);
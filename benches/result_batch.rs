@@ -0,0 +1,134 @@
+//! Very many tiny results (one number per chunk) that have to be stored safely before the node gets the answer:
+//! the server appends every result to a file and syncs it, once per result with process_data_from_node() and once per batch
+//! with process_result_batch() (result_batch_size in the NCConfiguration). With 16 nodes a batch has up to 16 results, so there are
+//! up to 16 times fewer syncs. How much faster the job is depends on how long a sync takes compared to the protocol: on a disk
+//! that really writes the data the sync dominates, on a single core with a write cache the protocol does.
+//! Only compare runs on the same machine and file system.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use node_crunch::{NCConfiguration, NCServer, NCError, NCJobError, NCBatchedResult, ChunkAssignment, ChunkID, NodeID,
+    RangeServer, RangeNode, RangeBatch, RangeResults, nc_local};
+
+const CHUNKS: u64 = 2000;
+const NODES: usize = 16;
+
+type Sum = RangeServer<u64, u64, fn(&mut u64, Range<u64>, &[u64])>;
+
+/// A RangeServer that writes every result to a file before it's folded into the sum.
+struct ResultStore {
+    range_server: Sum,
+    path: PathBuf,
+    file: File,
+}
+
+impl ResultStore {
+    fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("nc_bench_results_{}.bin", std::process::id()));
+        let file = OpenOptions::new().create(true).append(true).open(&path).unwrap();
+        let range_server: Sum = RangeServer::new(0..CHUNKS, 1, 0, |sum, _range, values| *sum += values.iter().sum::<u64>());
+
+        ResultStore { range_server, path, file }
+    }
+
+    fn write(&mut self, data: &RangeResults<u64>) -> Result<(), NCError> {
+        for value in &data.values {
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for ResultStore {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl NCServer for ResultStore {
+    type InitialDataT = ();
+    type NewDataT = RangeBatch;
+    type ProcessedDataT = RangeResults<u64>;
+    type CustomMessageT = ();
+
+    fn assign_chunk(&mut self, node_id: NodeID) -> Result<ChunkAssignment<RangeBatch>, NCError> {
+        self.range_server.assign_chunk(node_id)
+    }
+
+    fn chunk_sent(&mut self, chunk_id: ChunkID) {
+        self.range_server.chunk_sent(chunk_id)
+    }
+
+    fn chunk_send_failed(&mut self, chunk_id: ChunkID) {
+        self.range_server.chunk_send_failed(chunk_id)
+    }
+
+    fn process_data_from_node(&mut self, node_id: NodeID, data: &RangeResults<u64>) -> Result<(), NCError> {
+        self.write(data)?;
+        self.file.sync_data()?;
+        self.range_server.process_data_from_node(node_id, data)
+    }
+
+    fn process_result_batch(&mut self, batch: Vec<NCBatchedResult<RangeResults<u64>>>) -> Result<(), NCError> {
+        for result in &batch {
+            self.write(&result.data)?;
+        }
+
+        self.file.sync_data()?;
+
+        for result in &batch {
+            self.range_server.process_data_from_node(result.node_id, &result.data)?;
+        }
+
+        Ok(())
+    }
+
+    fn process_node_error(&mut self, node_id: NodeID, error: &NCJobError) {
+        self.range_server.process_node_error(node_id, error)
+    }
+
+    fn job_finished(&self) -> bool {
+        self.range_server.job_finished()
+    }
+
+    fn heartbeat_timeout(&mut self, nodes: Vec<NodeID>) {
+        self.range_server.heartbeat_timeout(nodes)
+    }
+
+    fn finish_job(&mut self) {
+        self.range_server.finish_job()
+    }
+}
+
+fn run_job(config: &NCConfiguration) {
+    let server = ResultStore::new();
+    let sum = server.range_server.accumulator();
+
+    nc_local::run(config, server, |_| RangeNode::new(|i| i * 2), NODES).unwrap();
+    assert_eq!(*sum.lock().unwrap(), CHUNKS * (CHUNKS - 1));
+}
+
+fn bench_result_batch(c: &mut Criterion) {
+    let per_result = NCConfiguration { port: 0, delay_request_data: 1, heartbeat: 1, pool_size: NODES as u64, finish_linger_ms: 0, ..Default::default() };
+    let batched = NCConfiguration { result_batch_size: NODES, result_batch_ms: 2, ..per_result.clone() };
+
+    let mut group = c.benchmark_group("result_store");
+    group.throughput(Throughput::Elements(CHUNKS));
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(30));
+
+    group.bench_function(format!("per_result/{}_nodes", NODES), |b| b.iter(|| run_job(&per_result)));
+    group.bench_function(format!("batched/{}_nodes", NODES), |b| b.iter(|| run_job(&batched)));
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_result_batch);
+criterion_main!(benches);
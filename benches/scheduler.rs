@@ -0,0 +1,87 @@
+//! The bookkeeping of the server for 1M chunks: assign every chunk, complete every chunk and expire the chunks of nodes
+//! that have missed their heartbeat (the chunks go back to the pool and are assigned again).
+//! LazyChunkList is the list for jobs of this size, ChunkList scans its chunks, so only its heartbeat sweep is measured here.
+//! The server does this while it holds the lock of the job, so a regression of more than 10 % slows down every node and needs a reason.
+
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use node_crunch::{ChunkList, LazyChunkList, ChunkID, NodeID};
+
+const CHUNKS: u64 = 1_000_000;
+const NODES: u64 = 100;
+
+fn nodes() -> Vec<NodeID> {
+    (1..=NODES).map(|i| i.to_string().parse().unwrap()).collect()
+}
+
+/// Hands out all the chunks, one after another to every node.
+fn assign_all<F: FnMut(ChunkID) -> u64>(chunk_list: &mut LazyChunkList<u64, F>, nodes: &[NodeID]) {
+    for i in 0..CHUNKS {
+        chunk_list.assign_next_chunk(nodes[(i % NODES) as usize]).unwrap();
+    }
+}
+
+fn bench_lazy_chunk_list(c: &mut Criterion) {
+    let nodes = nodes();
+    let new_list = || ChunkList::from_generator(CHUNKS, |chunk_id: ChunkID| chunk_id * 2);
+    let assigned_list = || { let mut chunk_list = new_list(); assign_all(&mut chunk_list, &nodes); chunk_list };
+
+    let mut group = c.benchmark_group("lazy_chunk_list");
+    group.throughput(Throughput::Elements(CHUNKS));
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(10));
+
+    group.bench_function("assign", |b| {
+        b.iter_batched(new_list, |mut chunk_list| { assign_all(&mut chunk_list, &nodes); chunk_list }, BatchSize::PerIteration)
+    });
+
+    group.bench_function("complete", |b| {
+        b.iter_batched(assigned_list, |mut chunk_list| {
+            for chunk_id in 0..CHUNKS {
+                chunk_list.set_finished(chunk_id);
+            }
+
+            chunk_list
+        }, BatchSize::PerIteration)
+    });
+
+    // All nodes miss their heartbeat, every chunk goes back to the pool and is assigned again
+    group.bench_function("expire", |b| {
+        b.iter_batched(assigned_list, |mut chunk_list| {
+            chunk_list.heartbeat_timeout(&nodes);
+            assign_all(&mut chunk_list, &nodes);
+            chunk_list
+        }, BatchSize::PerIteration)
+    });
+
+    group.finish();
+}
+
+fn bench_chunk_list(c: &mut Criterion) {
+    let nodes = nodes();
+    let mut chunk_list = ChunkList::new();
+
+    for i in 0..CHUNKS {
+        chunk_list.push(i);
+    }
+
+    for i in 0..CHUNKS {
+        chunk_list.get(i as usize).set_processing(nodes[(i % NODES) as usize]);
+    }
+
+    let mut group = c.benchmark_group("chunk_list");
+    group.throughput(Throughput::Elements(CHUNKS));
+    group.sample_size(10);
+
+    // One node misses its heartbeat, all chunks are checked
+    group.bench_function("expire_one_node", |b| {
+        b.iter_batched(|| chunk_list.clone(), |mut chunk_list| { chunk_list.heartbeat_timeout(&nodes[..1]); chunk_list }, BatchSize::PerIteration)
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_lazy_chunk_list, bench_chunk_list);
+criterion_main!(benches);
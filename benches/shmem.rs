@@ -0,0 +1,57 @@
+//! Sends 1 GiB of results from the nodes to the server with the in-process runner ([`nc_local::run()`](node_crunch::nc_local::run)),
+//! once over the loopback interface and once with the shared memory transport (shmem feature, see the nc_shmem module).
+//! Every result is 16 MiB and not compressed, so the time is mostly spent moving the bytes. Only compare runs on the same machine,
+//! the shared memory transport should have the higher throughput.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use node_crunch::{NCConfiguration, NCTransportKind, RangeServer, RangeNode, nc_local};
+
+const CHUNKS: u64 = 64;
+
+const RESULT_BYTES: usize = 16 * 1024 * 1024;
+
+fn run_job(config: &NCConfiguration, num_nodes: usize) {
+    let server = RangeServer::new(0..CHUNKS, 1, 0u64, |bytes: &mut u64, _range, results: &[Vec<u8>]| {
+        *bytes += results.iter().map(|result| result.len() as u64).sum::<u64>()
+    });
+    let bytes = server.accumulator();
+
+    nc_local::run(config, server, |_| RangeNode::new(|i| vec![i as u8; RESULT_BYTES]), num_nodes).unwrap();
+    assert_eq!(*bytes.lock().unwrap(), CHUNKS * RESULT_BYTES as u64);
+}
+
+/// A directory on a memory file system if there is one.
+fn segment_dir() -> PathBuf {
+    let shm = PathBuf::from("/dev/shm");
+    let base = if shm.is_dir() { shm } else { std::env::temp_dir() };
+
+    base.join(format!("node_crunch_bench_{}", std::process::id()))
+}
+
+fn bench_shmem(c: &mut Criterion) {
+    let tcp = NCConfiguration { port: 0, delay_request_data: 1, heartbeat: 1, compress: false, ..Default::default() };
+    let shmem = NCConfiguration { transport: NCTransportKind::SharedMemory { path: segment_dir() }, ..tcp.clone() };
+
+    let mut group = c.benchmark_group("transport");
+    group.throughput(Throughput::Bytes(CHUNKS * RESULT_BYTES as u64));
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(60));
+
+    for num_nodes in [1, 4] {
+        group.bench_function(format!("tcp/{}_nodes", num_nodes), |b| b.iter(|| run_job(&tcp, num_nodes)));
+        group.bench_function(format!("shmem/{}_nodes", num_nodes), |b| b.iter(|| run_job(&shmem, num_nodes)));
+    }
+
+    group.finish();
+
+    if let NCTransportKind::SharedMemory { path } = &shmem.transport {
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+criterion_group!(benches, bench_shmem);
+criterion_main!(benches);
@@ -0,0 +1,39 @@
+//! Putting an 8K image (8192 * 8192 pixels) together from the 64 tiles of the nodes, like the server does at the end of a stencil job,
+//! and splitting it up into these tiles in the first place. Both copy the whole image once, so they should stay close to memcpy speed:
+//! a regression of more than 10 % needs a reason.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use node_crunch::Array2D;
+
+const SIZE: u64 = 8192;
+const TILE_SIZE: u64 = SIZE / 8;
+const HALO: u64 = 2;
+
+fn image() -> Array2D<u32> {
+    let data = (0..SIZE * SIZE).map(|i| (i % 65_521) as u32).collect();
+    Array2D::from_vec(SIZE, SIZE, data).unwrap()
+}
+
+fn bench_stitch(c: &mut Criterion) {
+    let image = image();
+    let tiles = image.split_with_halo(TILE_SIZE, TILE_SIZE, HALO, 0);
+    assert_eq!(tiles.len(), 64);
+
+    let mut group = c.benchmark_group("array2d_8k");
+    group.throughput(Throughput::Bytes(SIZE * SIZE * 4));
+    group.sample_size(10);
+
+    // Freeing the 256 MB is not measured, one result at a time is kept
+    group.bench_function("split_with_halo", |b| {
+        b.iter_batched(|| (), |_| image.split_with_halo(TILE_SIZE, TILE_SIZE, HALO, 0), BatchSize::PerIteration)
+    });
+    group.bench_function("stitch", |b| {
+        b.iter_batched(|| (), |_| Array2D::stitch(SIZE, SIZE, 0, &tiles).unwrap(), BatchSize::PerIteration)
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_stitch);
+criterion_main!(benches);
@@ -0,0 +1,148 @@
+
+
+use clap::{Parser, Subcommand};
+
+use node_crunch::{NCClient, NCConfiguration, NCJobStatus, NodeID};
+
+/// Small command line tool to talk to a running node_crunch server.
+#[derive(Parser, Debug)]
+#[clap(name = "admin_cli")]
+pub struct AdminOpt {
+    #[clap(long = "ip", default_value = "127.0.0.1")]
+    ip: String,
+
+    #[clap(short = 'p', long = "port", default_value = "2020")]
+    port: u16,
+
+    /// The key must be the same as the one used by the server, exactly 32 chars.
+    #[clap(short = 'k', long = "key")]
+    key: Option<String>,
+
+    /// The admin key must be the same as the one used by the server.
+    #[clap(short = 'a', long = "admin-key", default_value = "")]
+    admin_key: String,
+
+    #[clap(subcommand)]
+    command: AdminCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AdminCommand {
+    /// Ask the server if the job is still running.
+    Status,
+    /// Print some statistics about the registered nodes.
+    Statistics,
+    /// Abort the job, the server will call finish_job() and exit.
+    Abort {
+        #[clap(default_value = "aborted by admin")]
+        reason: String,
+    },
+    /// Pause the job, nodes will not get any new data.
+    Pause,
+    /// Resume a paused job.
+    Resume,
+    /// Disable the node with the given node id.
+    Disable {
+        node_id: NodeID,
+    },
+    /// Move all nodes to a new server.
+    NewServer {
+        address: String,
+        port: u16,
+    },
+    /// Use a new encryption key, exactly 32 chars. The nodes get it the next time they contact the server.
+    RotateKey {
+        new_key: String,
+    },
+    /// Nodes that don't run the given build are asked to restart after their current chunk. Without a build every build is accepted.
+    RequireBuild {
+        build: Option<String>,
+    },
+}
+
+fn main() {
+    let options = AdminOpt::parse();
+
+    let mut configuration = NCConfiguration {
+        address: options.ip,
+        port: options.port,
+        admin_key: options.admin_key,
+        ..Default::default()
+    };
+
+    if let Some(key) = options.key {
+        configuration.encrypt = true;
+        configuration.key = key;
+    }
+
+    let mut client = match NCClient::connect(&configuration) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Could not create client: {}", e);
+            return
+        }
+    };
+
+    let result = match options.command {
+        AdminCommand::Status => {
+            client.query_status().map(|status| {
+                match status {
+                    NCJobStatus::Finished => println!("Job is finished"),
+                    _ => println!("Job is still running"),
+                }
+            })
+        }
+        AdminCommand::Statistics => {
+            client.get_statistics().map(|statistics| {
+                println!("Number of nodes: {}", statistics.num_of_nodes());
+                println!("Time taken: {:.1} s", statistics.time_taken());
+                println!("Result queue: {} results, {} bytes in memory, {} on disk",
+                    statistics.result_queue_len(), statistics.result_queue_bytes(), statistics.result_queue_spilled());
+                println!("Empty chunks: {}", statistics.empty_chunks());
+                println!("Chunk cache: {} hits, {} misses", statistics.chunk_cache_hits(), statistics.chunk_cache_misses());
+                println!("Connections: {} in flight, {} rejected, {} closed by watchdog",
+                    statistics.connections_in_flight(), statistics.rejected_connections(), statistics.killed_connections());
+                println!("Post processing failures: {}", statistics.post_process_failures());
+                println!("Restart requests: {}", statistics.restart_requests());
+                println!("Uncompressed frames: {}", statistics.uncompressed_frames());
+
+                for (reason, count) in statistics.skipped_chunks() {
+                    println!("Skipped chunks: {}, reason: {}", count, reason);
+                }
+
+                for (node_id, time_stamp) in statistics.hb_time_stamps() {
+                    println!("Node: {}, last heartbeat: {:.1} s ago", node_id, time_stamp);
+                }
+            })
+        }
+        AdminCommand::Abort { reason } => {
+            client.abort_job(reason).map(|_| println!("Job aborted"))
+        }
+        AdminCommand::Pause => {
+            client.pause_job().map(|_| println!("Job paused"))
+        }
+        AdminCommand::Resume => {
+            client.resume_job().map(|_| println!("Job resumed"))
+        }
+        AdminCommand::Disable { node_id } => {
+            client.disable_node(node_id).map(|_| println!("Node {} disabled", node_id))
+        }
+        AdminCommand::NewServer { address, port } => {
+            client.new_server(address, port).map(|_| println!("Nodes will move to the new server"))
+        }
+        AdminCommand::RotateKey { new_key } => {
+            client.rotate_key(&new_key).map(|_| println!("Encryption key rotated"))
+        }
+        AdminCommand::RequireBuild { build } => {
+            let message = match &build {
+                Some(build) => format!("Nodes must run build {}", build),
+                None => "Every node build is accepted".to_string(),
+            };
+            client.set_required_node_build(build).map(|_| println!("{}", message))
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("An error occurred: {}", e);
+    }
+}
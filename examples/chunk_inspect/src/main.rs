@@ -0,0 +1,99 @@
+
+
+use std::time::{Duration, SystemTime};
+
+use clap::Parser;
+
+use node_crunch::{NCCheckpoint, ChunkStatus, ChunkID};
+
+/// Small command line tool to look at a checkpoint file of a node_crunch server.
+/// This works offline, for example on a copy of the checkpoint file from a running server.
+#[derive(Parser, Debug)]
+#[clap(name = "chunk_inspect")]
+pub struct InspectOpt {
+    /// The checkpoint file, see checkpoint_file in the NCConfiguration.
+    checkpoint_file: String,
+
+    /// Only show the chunks that have failed permanently.
+    #[clap(long = "failed")]
+    failed: bool,
+
+    /// Only show the chunks that have been assigned to a node for longer than n seconds (at the time of the checkpoint).
+    #[clap(long = "assigned-longer-than")]
+    assigned_longer_than: Option<u64>,
+
+    /// Only show the chunks of the given phase.
+    #[clap(long = "phase")]
+    phase: Option<u32>,
+}
+
+const ALL_STATUS: [ChunkStatus; 5] = [ChunkStatus::Empty, ChunkStatus::Assigned, ChunkStatus::Processing, ChunkStatus::Finished, ChunkStatus::Failed];
+
+/// Seconds between the two time stamps, 0 if the second one is before the first one.
+fn secs_between(earlier: SystemTime, later: SystemTime) -> u64 {
+    later.duration_since(earlier).map_or(0, |duration| duration.as_secs())
+}
+
+fn main() {
+    let options = InspectOpt::parse();
+
+    let checkpoint = match NCCheckpoint::load(&options.checkpoint_file) {
+        Ok(checkpoint) => checkpoint,
+        Err(e) => {
+            eprintln!("Could not load checkpoint: {}", e);
+            return
+        }
+    };
+
+    let saved_at = checkpoint.saved_at();
+    let chunk_list = checkpoint.chunk_list();
+
+    println!("Checkpoint written {} s ago, current phase: {}, chunks: {}",
+        secs_between(saved_at, SystemTime::now()), chunk_list.current_phase(), chunk_list.chunks().len());
+
+    // Summary table: number of chunks for each phase and status
+    let mut phases: Vec<u32> = chunk_list.chunks().iter().map(|chunk| chunk.phase()).collect();
+    phases.sort_unstable();
+    phases.dedup();
+
+    println!();
+    println!("{:>6} {:>10} {:>10} {:>10} {:>10} {:>10}", "phase", "empty", "assigned", "processing", "finished", "failed");
+
+    for phase in phases {
+        let counts: Vec<usize> = ALL_STATUS.iter().map(|status| {
+            chunk_list.chunks().iter().filter(|chunk| chunk.phase() == phase && chunk.status() == *status).count()
+        }).collect();
+
+        println!("{:>6} {:>10} {:>10} {:>10} {:>10} {:>10}", phase, counts[0], counts[1], counts[2], counts[3], counts[4]);
+    }
+
+    // Details, only if a filter is given
+    if !options.failed && options.assigned_longer_than.is_none() && options.phase.is_none() {
+        return
+    }
+
+    let chunks: Box<dyn Iterator<Item=_>> = match options.assigned_longer_than {
+        Some(secs) => Box::new(chunk_list.assigned_before(saved_at - Duration::from_secs(secs))),
+        None => Box::new(chunk_list.chunks().iter().enumerate().map(|(index, chunk)| (index as ChunkID, chunk))),
+    };
+
+    println!();
+    println!("{:>8} {:>6} {:>10} {:>20} {:>12}", "chunk", "phase", "status", "node", "assigned (s)");
+
+    for (chunk_id, chunk) in chunks {
+        if options.failed && chunk.status() != ChunkStatus::Failed {
+            continue
+        }
+
+        if options.phase.is_some_and(|phase| chunk.phase() != phase) {
+            continue
+        }
+
+        let (node, assigned) = match chunk.assigned_at() {
+            Some(assigned_at) => (chunk.node_id.to_string(), secs_between(assigned_at, saved_at).to_string()),
+            None => ("-".to_string(), "-".to_string()),
+        };
+
+        println!("{:>8} {:>6} {:>10} {:>20} {:>12}", chunk_id, chunk.phase(), format!("{:?}", chunk.status()), node, assigned);
+    }
+}
@@ -23,8 +23,16 @@ pub struct Mandel1Opt {
     port: u16,
 }
 
+/// The job has two phases: first the mandelbrot set is calculated, then the iteration values are normalized
+/// with the maximum value of the whole image. The second phase can only start when all chunks of the first phase are done.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ServerData {
+pub enum ServerData {
+    Mandel(MandelData),
+    Normalize(NormalizeData),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MandelData {
     chunk_id: u64,
     max_iter: u32,
     x: u64,
@@ -37,6 +45,13 @@ pub struct ServerData {
     im: f64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NormalizeData {
+    chunk_id: u64,
+    max_value: u32,
+    source: Array2D::<u32>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NodeData {
     chunk_id: u64,
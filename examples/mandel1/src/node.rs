@@ -1,25 +1,17 @@
-use log::{info, error};
+use log::{info, warn, error, debug};
 use num::complex::Complex64;
 
-use node_crunch::{NCNode, NCError, NCConfiguration, Array2D, NCNodeStarter};
+use node_crunch::{nc_context, NCNode, NCError, NCConfiguration, Array2D, NCNodeStarter, NodeResult, NCNodeProgress, NodeExit};
 
-use crate::{Mandel1Opt, ServerData, NodeData};
+use crate::{Mandel1Opt, ServerData, MandelData, NormalizeData, NodeData};
 
 /// In this example the NCNode data struct has no useful data, just code.
 struct MandelNode {
 }
 
-impl NCNode for MandelNode {
-    type InitialDataT = ();
-    type NewDataT = ServerData;
-    type ProcessedDataT = NodeData;
-    type CustomMessageT = ();
-
-    /// This processes the data that has been send from the server to this node.
-    /// In here the whole number crunching is happening in this example the mandelbrot set.
-    /// The result is returned in a Ok(Vec<u8>).
-    /// Return an error otherwise.
-    fn process_data_from_server(&mut self, data: &Self::NewDataT) -> Result<Self::ProcessedDataT, NCError> {
+impl MandelNode {
+    /// Phase 0: calculate the mandelbrot set for the given chunk.
+    fn calc_mandel(&self, data: &MandelData) -> NodeData {
         let mut array2d = Array2D::<u32>::new(data.width, data.height, 0);
         let mut c: Complex64;
         let mut z: Complex64;
@@ -40,8 +32,43 @@ impl NCNode for MandelNode {
             }
         }
 
-        let result = NodeData { chunk_id: data.chunk_id, source: array2d };
-        Ok(result)
+        NodeData { chunk_id: data.chunk_id, source: array2d }
+    }
+
+    /// Phase 1: scale all the iteration values to 0 - 255 using the maximum value of the whole image.
+    fn normalize(&self, data: &NormalizeData) -> NodeData {
+        let (width, height) = data.source.dimensions();
+        let mut array2d = Array2D::<u32>::new(width, height, 0);
+        let max_value = data.max_value.max(1) as u64;
+
+        for x in 0..width {
+            for y in 0..height {
+                let value = data.source.get(x, y) as u64;
+                array2d.set(x, y, ((value * 255) / max_value) as u32);
+            }
+        }
+
+        NodeData { chunk_id: data.chunk_id, source: array2d }
+    }
+}
+
+impl NCNode for MandelNode {
+    type InitialDataT = ();
+    type NewDataT = ServerData;
+    type ProcessedDataT = NodeData;
+    type CustomMessageT = ();
+
+    /// This processes the data that has been send from the server to this node.
+    /// In here the whole number crunching is happening in this example the mandelbrot set.
+    /// Depending on the phase of the job the mandelbrot set is calculated or the result is normalized.
+    /// Return an error otherwise.
+    fn process_data_from_server(&mut self, data: &Self::NewDataT) -> Result<NodeResult<Self::ProcessedDataT>, NCError> {
+        debug!("Process {}", nc_context::current());
+
+        match data {
+            ServerData::Mandel(data) => Ok(NodeResult::Data(self.calc_mandel(data))),
+            ServerData::Normalize(data) => Ok(NodeResult::Data(self.normalize(data))),
+        }
     }
 }
 
@@ -59,11 +86,28 @@ pub fn run_node(options: Mandel1Opt) {
 
     let node = MandelNode{};
     let mut node_starter = NCNodeStarter::new(configuration);
+    let progress = NCNodeProgress::start(node_starter.progress_events());
+    let result = node_starter.start(node);
+    progress.finish();
 
-    match node_starter.start(node) {
-        Ok(_) => {
+    match result {
+        Ok(NodeExit::JobFinished) | Ok(NodeExit::StoppedByUser) => {
             info!("Calculation finished");
         }
+        Ok(NodeExit::RestartRequested) => {
+            // Tell the wrapper script to restart the node with the new build
+            info!("Restart requested by server");
+            std::process::exit(3);
+        }
+        Ok(NodeExit::Disabled { reason }) => {
+            // Tell the wrapper script not to start the node again
+            warn!("Node has been disabled by the server: {}", reason);
+            std::process::exit(4);
+        }
+        Ok(NodeExit::JobAborted { reason }) => {
+            error!("Job has been aborted by the server: {}", reason);
+            std::process::exit(2);
+        }
         Err(e) => {
             error!("An error occurred: {}", e);
         }
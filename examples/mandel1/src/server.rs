@@ -2,10 +2,10 @@ use log::{info, error, debug};
 use num::complex::Complex64;
 use image;
 
-use node_crunch::{NCServer, NCJobStatus, NCConfiguration, NCError,
-    Array2DChunk, ChunkList, ChunkData, NodeID, NCServerStarter};
+use node_crunch::{nc_context, NCServer, ChunkAssignment, NCConfiguration, NCError, NCJobError,
+    Array2DChunk, ChunkList, ChunkData, ChunkID, NodeID, NCServerStarter, NCServerProgress};
 
-use crate::{Mandel1Opt, ServerData, NodeData};
+use crate::{Mandel1Opt, ServerData, MandelData, NormalizeData, NodeData};
 
 /// This has the image data, mandelbrot set configuration and the chunks list.
 #[derive(Debug, Clone)]
@@ -20,6 +20,8 @@ struct MandelServer {
     y_step: f64,
     /// Maximum number of iteration as escape time limit.
     max_iter: u32,
+    /// The maximum iteration value of the whole image, calculated after the first phase.
+    max_value: u32,
     /// This holds the image data (pixels) for the final mandelbrot image.
     array2d_chunk: Array2DChunk<u32>,
     /// Book keeping chunk list, which node is processing which part of the image.
@@ -27,22 +29,33 @@ struct MandelServer {
 }
 
 impl MandelServer {
-    /// Saves the image data to disk with a fancy color scheme.
-    fn save_image(&self) {
+    /// Saves the normalized image data to disk as a grey scale image.
+    fn save_image(&self) -> Result<(), NCError> {
         let (width, height) = self.array2d_chunk.dimensions();
         let mut buffer = image::ImageBuffer::new(width as u32, height as u32);
 
         for (x, y, pixel) in buffer.enumerate_pixels_mut() {
-            let value = self.array2d_chunk.get(x as u64, y as u64);
+            let value = self.array2d_chunk.get(x as u64, y as u64) as u8;
 
-            if (value & 1) == 0 {
-                *pixel = image::Rgb([0 as u8, 0 as u8, 0 as u8]);
-            } else {
-                *pixel = image::Rgb([255 as u8, 255 as u8, 255 as u8]);
+            *pixel = image::Rgb([value, value, value]);
+        }
+
+        // The image error is kept as the source, so the log shows the whole chain.
+        buffer.save("mandel.png").map_err(|e| NCError::with_source("could not save mandel.png", e))
+    }
+
+    /// Returns the maximum iteration value of the whole image.
+    fn calc_max_value(&self) -> u32 {
+        let (width, height) = self.array2d_chunk.dimensions();
+        let mut max_value = 0;
+
+        for x in 0..width {
+            for y in 0..height {
+                max_value = max_value.max(self.array2d_chunk.get(x, y));
             }
         }
 
-        buffer.save("mandel.png").unwrap();
+        max_value
     }
 
     /// Checks if the job (calculating the mandelbrot set) is already done.
@@ -62,39 +75,75 @@ impl NCServer for MandelServer {
 
     /// Every node needs some data to process. Here this data is prepared for each node and some book keeping is saved in the chunks list.
     /// The whole mandelbrot image is split up into equally sized pieces and processed separately.
-    /// Returns the NCJobStatus that is checked by the server.
-    fn prepare_data_for_node(&mut self, node_id: NodeID) -> Result<NCJobStatus<Self::NewDataT>, NCError> {
-        debug!("Server::prepare_data_for_node, node_id: {}", node_id);
-
-        if let Some((i, free_chunk)) = self.chunk_list.get_next_free_chunk() {
-            let data_for_node = ServerData {
-                chunk_id: i as u64,
-                max_iter: self.max_iter,
-                x: free_chunk.data.x,
-                y: free_chunk.data.y,
-                width: free_chunk.data.width,
-                height: free_chunk.data.height,
-                x_step: self.x_step,
-                y_step: self.y_step,
-                re: self.start.re,
-                im: self.start.im,
+    /// In the first phase the mandelbrot set is calculated, in the second phase the result is normalized.
+    /// Returns the ChunkAssignment that is checked by the server.
+    /// The chunk is only marked as assigned here, the server calls chunk_sent() once the data has arrived at the node.
+    fn assign_chunk(&mut self, node_id: NodeID) -> Result<ChunkAssignment<Self::NewDataT>, NCError> {
+        debug!("Server::assign_chunk, node_id: {}", node_id);
+
+        if let Some((i, free_chunk)) = self.chunk_list.assign_next_chunk(node_id) {
+            let data_for_node = if free_chunk.phase() == 0 {
+                ServerData::Mandel(MandelData {
+                    chunk_id: i,
+                    max_iter: self.max_iter,
+                    x: free_chunk.data.x,
+                    y: free_chunk.data.y,
+                    width: free_chunk.data.width,
+                    height: free_chunk.data.height,
+                    x_step: self.x_step,
+                    y_step: self.y_step,
+                    re: self.start.re,
+                    im: self.start.im,
+                })
+            } else {
+                ServerData::Normalize(NormalizeData {
+                    chunk_id: i,
+                    max_value: self.max_value,
+                    source: self.array2d_chunk.get_chunk(i % self.array2d_chunk.num_of_chunks()),
+                })
             };
 
-            free_chunk.set_processing(node_id);
             debug!("preparing chunk {} for node {}", i, node_id);
-            Ok(NCJobStatus::Unfinished(data_for_node))
+            Ok(ChunkAssignment::Assigned(i, data_for_node))
+        } else if let Some(phase) = self.chunk_list.finish_phase() {
+            Ok(ChunkAssignment::PhaseFinished(phase))
         } else {
             if self.is_job_done() {
-                Ok(NCJobStatus::Finished)
+                Ok(ChunkAssignment::Finished)
             } else {
-                Ok(NCJobStatus::Waiting)
+                Ok(ChunkAssignment::Waiting)
             }
         }
     }
 
+    /// The mandelbrot set is done, now all the chunks for the second phase can be created since we know the maximum value.
+    fn phase_finished(&mut self, phase: u32) -> Result<(), NCError> {
+        if phase == 0 {
+            self.max_value = self.calc_max_value();
+            self.chunk_list.initialize_phase(&self.array2d_chunk, 1);
+        }
+
+        Ok(())
+    }
+
+    /// The data for the chunk has been sent to the node, now it is really in processing state.
+    fn chunk_sent(&mut self, chunk_id: ChunkID) {
+        self.chunk_list.chunk_sent(chunk_id)
+    }
+
+    /// The data for the chunk could not be sent to the node, give it to another node.
+    fn chunk_send_failed(&mut self, chunk_id: ChunkID) {
+        self.chunk_list.chunk_send_failed(chunk_id)
+    }
+
+    fn process_node_error(&mut self, node_id: NodeID, error: &NCJobError) {
+        self.chunk_list.chunk_failed(node_id, error.retryable)
+    }
+
     /// If one of the nodes has finished processing the small chunk the server writes the data back to the whole image Array2D.
+    /// The chunks of the second phase are appended to the chunk list, so the chunk id has to be mapped back to the position in the image.
     fn process_data_from_node(&mut self, node_id: NodeID, node_data: &Self::ProcessedDataT) -> Result<(), NCError> {
-        debug!("Server::process_data_from_node, node_id: {}", node_id);
+        debug!("Server::process_data_from_node, {}", nc_context::current());
 
         let chunk_id = node_data.chunk_id;
         let source = &node_data.source;
@@ -102,13 +151,18 @@ impl NCServer for MandelServer {
 
         if current_chunk.is_processing(node_id) {
             current_chunk.set_finished();
-            self.array2d_chunk.set_chunk(chunk_id, &source)
+            self.array2d_chunk.set_chunk(chunk_id % self.array2d_chunk.num_of_chunks(), &source)
         } else {
             error!("Mismatch data, should be Processing with node_id: {}, but is {:?}", node_id, current_chunk.node_id);
             Err(NCError::NodeIDMismatch(node_id, current_chunk.node_id))
         }
     }
 
+    /// Shows the number of finished chunks in the progress bar.
+    fn job_progress(&self) -> Option<(u64, u64)> {
+        Some(self.chunk_list.progress())
+    }
+
     /// If some nodes have crashed or lost the network connection the internal chunks list is updated.
     fn heartbeat_timeout(&mut self, nodes: Vec<NodeID>) {
         self.chunk_list.heartbeat_timeout(&nodes)
@@ -116,7 +170,9 @@ impl NCServer for MandelServer {
 
     /// When all processing is done the server calls this method. Here we just save the final image to disk.
     fn finish_job(&mut self) {
-        self.save_image();
+        if let Err(e) = self.save_image() {
+            error!("{}", e);
+        }
     }
 }
 
@@ -143,12 +199,15 @@ pub fn run_server(options: Mandel1Opt) {
     chunk_list.initialize(&array2d_chunk);
 
     let server = MandelServer {
-        start, end, x_step, y_step, max_iter, array2d_chunk, chunk_list,
+        start, end, x_step, y_step, max_iter, max_value: 0, array2d_chunk, chunk_list,
     };
 
     let mut server_starter = NCServerStarter::new(configuration);
+    let progress = NCServerProgress::start(server_starter.progress_events());
+    let result = server_starter.start(server);
+    progress.finish();
 
-    match server_starter.start(server) {
+    match result {
         Ok(_) => {
             info!("Calculation finished");
         }
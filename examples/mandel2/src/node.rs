@@ -1,8 +1,8 @@
-use log::{info, error};
+use log::{info, error, debug};
 use num::complex::Complex64;
 use rayon::prelude::*;
 
-use node_crunch::{NCNode, NCError, NCConfiguration, Array2D, NCNodeStarter};
+use node_crunch::{nc_context, NCNode, NCError, NCConfiguration, Array2D, NCNodeStarter, NodeResult, NCNodeProgress, NodeExit};
 
 use crate::{Mandel1Opt, ServerData, NodeData};
 
@@ -18,9 +18,10 @@ impl NCNode for MandelNode {
 
     /// This processes the data that has been send from the server to this node.
     /// In here the whole number crunching is happening in this example the mandelbrot set.
-    /// The result is returned in a Ok(Vec<u8>).
+    /// The result is returned in a Ok(NodeResult::Data(Vec<u8>)).
     /// Return an error otherwise.
-    fn process_data_from_server(&mut self, data: &Self::NewDataT) -> Result<Self::ProcessedDataT, NCError> {
+    fn process_data_from_server(&mut self, data: &Self::NewDataT) -> Result<NodeResult<Self::ProcessedDataT>, NCError> {
+        debug!("Process {}", nc_context::current());
         let mut array2d = Array2D::<u32>::new(data.width, data.height, 0);
 
         // This shows that you can use rayon with Node Crunch: par_bridge() creates a parallel iterator
@@ -42,7 +43,7 @@ impl NCNode for MandelNode {
         });
 
         let result = NodeData { chunk_id: data.chunk_id, source: array2d };
-        Ok(result)
+        Ok(NodeResult::Data(result))
     }
 }
 
@@ -56,10 +57,16 @@ pub fn run_node(options: Mandel1Opt) {
 
     let node = MandelNode{};
     let mut node_starter = NCNodeStarter::new(configuration);
+    let progress = NCNodeProgress::start(node_starter.progress_events());
+    let result = node_starter.start(node);
+    progress.finish();
 
-    match node_starter.start(node) {
-        Ok(_) => {
-            info!("Calculation finished");
+    match result {
+        Ok(NodeExit::JobAborted { reason }) => {
+            error!("Job has been aborted by the server: {}", reason);
+        }
+        Ok(node_exit) => {
+            info!("Calculation finished: {:?}", node_exit);
         }
         Err(e) => {
             error!("An error occurred: {}", e);
@@ -0,0 +1,79 @@
+
+
+use std::ops::Range;
+use structopt::StructOpt;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use node_crunch::{NCConfiguration, NCServerStarter, NCNodeStarter, RangeServer, RangeNode, nc_local};
+
+/// Estimates π by throwing random darts at a square with a circle inside. This is the "hello world" of node_crunch:
+/// the server hands out batches of rounds (RangeServer), every node throws the darts of its rounds (RangeNode).
+#[derive(StructOpt, Debug)]
+#[structopt(name = "monte_carlo_pi")]
+pub struct PiOpt {
+    #[structopt(short = "s", long = "server")]
+    server: bool,
+
+    #[structopt(long = "ip", default_value = "127.0.0.1")]
+    ip: String,
+
+    #[structopt(short = "p", long = "port", default_value = "2020")]
+    port: u16,
+
+    /// Number of rounds, every round throws --darts darts.
+    #[structopt(long = "rounds", default_value = "10000")]
+    rounds: u64,
+
+    /// Number of darts per round.
+    #[structopt(long = "darts", default_value = "10000")]
+    darts: u32,
+
+    /// Number of rounds that are sent to a node at once.
+    #[structopt(long = "batch-size", default_value = "100")]
+    batch_size: u64,
+
+    /// Run the server and this many nodes in one process.
+    #[structopt(long = "local")]
+    local: Option<usize>,
+}
+
+/// Throws the darts of one round and returns how many of them have hit the circle.
+/// The random numbers only depend on the round, so the estimate is the same for any number of nodes.
+fn throw_darts(round: u64, darts: u32) -> u32 {
+    let mut rng = StdRng::seed_from_u64(round);
+
+    (0..darts).filter(|_| {
+        let (x, y): (f64, f64) = (rng.gen(), rng.gen());
+        x * x + y * y <= 1.0
+    }).count() as u32
+}
+
+fn main() {
+    let options = PiOpt::from_args();
+    let darts = options.darts;
+
+    let config = NCConfiguration {
+        address: options.ip.clone(),
+        port: options.port,
+        ..Default::default()
+    };
+
+    let total_darts = options.rounds * darts as u64;
+    let server = RangeServer::new(0..options.rounds, options.batch_size, 0u64, |hits: &mut u64, _rounds: Range<u64>, round_hits: &[u32]| {
+        *hits += round_hits.iter().map(|hits| *hits as u64).sum::<u64>()
+    });
+    let hits = server.accumulator();
+
+    if let Some(num_nodes) = options.local {
+        nc_local::run(&config, server, |_| RangeNode::new(move |round| throw_darts(round, darts)), num_nodes).unwrap();
+    } else if options.server {
+        NCServerStarter::new(config).start(server).unwrap();
+    } else {
+        NCNodeStarter::new(config).start(RangeNode::new(move |round| throw_darts(round, darts))).unwrap();
+        return
+    }
+
+    let hits = *hits.lock().unwrap();
+    println!("π ≈ {:.6} ({} of {} darts have hit the circle)", 4.0 * hits as f64 / total_darts as f64, hits, total_darts);
+}
@@ -23,6 +23,10 @@ pub struct RayTracer1Opt {
     #[structopt(short = "p", long = "port", default_value = "2020")]
     port: u16,
 
+    /// Time limit for the job in seconds, 0 = no limit. The nodes take fewer samples per pixel when the time is short.
+    #[structopt(long = "max-time", default_value = "0")]
+    max_time: u64,
+
     #[structopt(default_value = "1024")]
     width: u64,
 
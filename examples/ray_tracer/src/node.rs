@@ -1,6 +1,6 @@
-use log::{info, error};
+use log::{info, error, debug};
 
-use node_crunch::{NCNode, NCError, NCConfiguration, Array2D, NCNodeStarter};
+use node_crunch::{nc_context, NCNode, NCError, NCConfiguration, Array2D, NCNodeStarter, NodeResult, NCNodeProgress, NodeExit, NCProcessContext};
 
 use ray_tracer::scene::Scene;
 use ray_tracer::camera::perspective::PerspectiveCamera;
@@ -10,6 +10,11 @@ use crate::ray_scene::RayScene;
 
 use crate::{RayTracer1Opt, ServerData, NodeData};
 
+/// Samples per pixel for the full quality.
+const SAMPLES_PER_PIXEL: usize = 200;
+/// The image gets noisy but we take at least this many samples per pixel when the job is running out of time.
+const MIN_SAMPLES_PER_PIXEL: usize = 8;
+
 /// In this example the NCNode data struct has no useful data, just code.
 struct RayTracerNode {
     width: u64,
@@ -27,11 +32,37 @@ impl NCNode for RayTracerNode {
 
     /// This processes the data that has been send from the server to this node.
     /// In here the whole number crunching is happening in this example the ray tracing image.
-    /// The result is returned in a Ok(Self::ProcessedDataT).
+    /// The result is returned in a Ok(NodeResult::Data(Self::ProcessedDataT)).
     /// Return an error otherwise.
-    fn process_data_from_server(&mut self, data: &Self::NewDataT) -> Result<Self::ProcessedDataT, NCError> {
+    fn process_data_from_server(&mut self, data: &Self::NewDataT) -> Result<NodeResult<Self::ProcessedDataT>, NCError> {
+        self.render(data, SAMPLES_PER_PIXEL)
+    }
+
+    /// If the server has a time limit (max_job_duration) and a chunk with all the samples would not be ready in time,
+    /// only render as many samples per pixel as there is time left for.
+    fn process_data_with_context(&mut self, data: &Self::NewDataT, context: &NCProcessContext) -> Result<NodeResult<Self::ProcessedDataT>, NCError> {
+        let samples = match (context.job_time_remaining(), context.average_chunk_time()) {
+            (Some(remaining), Some(average)) if remaining < average => {
+                let samples = (SAMPLES_PER_PIXEL as f64 * remaining.as_secs_f64() / average.as_secs_f64()) as usize;
+                samples.max(MIN_SAMPLES_PER_PIXEL)
+            }
+            _ => SAMPLES_PER_PIXEL,
+        };
+
+        if samples < SAMPLES_PER_PIXEL {
+            info!("Job time is short, render chunk {} with {} samples per pixel", data.chunk_id, samples);
+        }
+
+        self.render(data, samples)
+    }
+}
+
+impl RayTracerNode {
+    /// Renders the part of the image for the given chunk.
+    fn render(&self, data: &ServerData, samples: usize) -> Result<NodeResult<NodeData>, NCError> {
         let mut array2d = Array2D::<(u8, u8, u8)>::new(data.width, data.height, (0, 0, 0));
         // debug!("Data from server: chunk: {}, x: {}, y: {}, w: {}, h: {}", data.chunk_id, data.x, data.y, data.width, data.height);
+        debug!("Process {}", nc_context::current());
 
         let renderer = Renderer::new(
             data.x as usize,
@@ -40,7 +71,7 @@ impl NCNode for RayTracerNode {
             (data.y + data.height) as usize,
             self.width as usize,
             self.height as usize,
-            200, 16, true);
+            samples, 16, true);
         let image = renderer.render(&self.scene, &self.camera);
 
         for x in 0..data.width {
@@ -59,7 +90,7 @@ impl NCNode for RayTracerNode {
             img: array2d,
         };
 
-        Ok(result)
+        Ok(NodeResult::Data(result))
     }
 }
 
@@ -86,10 +117,16 @@ pub fn run_node(options: RayTracer1Opt) {
     };
 
     let mut node_starter = NCNodeStarter::new(configuration);
+    let progress = NCNodeProgress::start(node_starter.progress_events());
+    let result = node_starter.start(node);
+    progress.finish();
 
-    match node_starter.start(node) {
-        Ok(_) => {
-            info!("Calculation finished");
+    match result {
+        Ok(NodeExit::JobAborted { reason }) => {
+            error!("Job has been aborted by the server: {}", reason);
+        }
+        Ok(node_exit) => {
+            info!("Calculation finished: {:?}", node_exit);
         }
         Err(e) => {
             error!("An error occurred: {}", e);
@@ -1,8 +1,10 @@
+use std::time::Duration;
+
 use log::{info, error, debug};
 use image;
 
-use node_crunch::{NCServer, NCJobStatus, NCConfiguration, NCError,
-    Array2DChunk, ChunkList, ChunkData, NodeID, NCServerStarter};
+use node_crunch::{nc_context, NCServer, ChunkAssignment, NCConfiguration, NCError, NCJobError,
+    Array2DChunk, ChunkList, ChunkData, ChunkID, NodeID, NCServerStarter, NCServerProgress};
 
 use crate::{RayTracer1Opt, ServerData, NodeData};
 
@@ -21,7 +23,7 @@ struct RayTracerServer {
 
 impl RayTracerServer {
     /// Saves the image data to disk with a fancy color scheme.
-    fn save_image(&self) {
+    fn save_image(&self) -> Result<(), NCError> {
         let (width, height) = self.array2d_chunk.dimensions();
         let mut buffer = image::ImageBuffer::new(width as u32, height as u32);
 
@@ -31,7 +33,8 @@ impl RayTracerServer {
             *pixel = image::Rgb([value.0, value.1, value.2]);
         }
 
-        buffer.save("ray_tace1.png").unwrap();
+        // The image error is kept as the source, so the log shows the whole chain.
+        buffer.save("ray_tace1.png").map_err(|e| NCError::with_source("could not save ray_tace1.png", e))
     }
 
     /// Checks if the job (calculating the mandelbrot set) is already done.
@@ -51,36 +54,50 @@ impl NCServer for RayTracerServer {
 
     /// Every node needs some data to process. Here this data is prepared for each node and some book keeping is saved in the chunks list.
     /// The whole ray tracing image is split up into equally sized pieces and processed separately.
-    /// Returns the NCJobStatus that is checked by the server.
-    fn prepare_data_for_node(&mut self, node_id: NodeID) -> Result<NCJobStatus<Self::NewDataT>, NCError> {
-        debug!("Server::prepare_data_for_node, node_id: {}", node_id);
+    /// Returns the ChunkAssignment that is checked by the server.
+    /// The chunk is only marked as assigned here, the server calls chunk_sent() once the data has arrived at the node.
+    fn assign_chunk(&mut self, node_id: NodeID) -> Result<ChunkAssignment<Self::NewDataT>, NCError> {
+        debug!("Server::assign_chunk, node_id: {}", node_id);
 
-        if let Some((i, free_chunk)) = self.chunk_list.get_next_free_chunk() {
+        if let Some((i, free_chunk)) = self.chunk_list.assign_next_chunk(node_id) {
             let data = &free_chunk.data;
             let data_for_node = ServerData {
-                chunk_id: i as u64,
+                chunk_id: i,
                 x: data.x,
                 y: data.y,
                 width: data.width,
                 height: data.height,
             };
 
-            free_chunk.set_processing(node_id);
             // debug!("preparing chunk {} for node {}", i, node_id);
             // debug!("x: {}, y: {}, w: {}, h: {}", data_for_node.x, data_for_node.y, data_for_node.width, data_for_node.height);
-            Ok(NCJobStatus::Unfinished(data_for_node))
+            Ok(ChunkAssignment::Assigned(i, data_for_node))
         } else {
             if self.is_job_done() {
-                Ok(NCJobStatus::Finished)
+                Ok(ChunkAssignment::Finished)
             } else {
-                Ok(NCJobStatus::Waiting)
+                Ok(ChunkAssignment::Waiting)
             }
         }
     }
 
+    /// The data for the chunk has been sent to the node, now it is really in processing state.
+    fn chunk_sent(&mut self, chunk_id: ChunkID) {
+        self.chunk_list.chunk_sent(chunk_id)
+    }
+
+    /// The data for the chunk could not be sent to the node, give it to another node.
+    fn chunk_send_failed(&mut self, chunk_id: ChunkID) {
+        self.chunk_list.chunk_send_failed(chunk_id)
+    }
+
+    fn process_node_error(&mut self, node_id: NodeID, error: &NCJobError) {
+        self.chunk_list.chunk_failed(node_id, error.retryable)
+    }
+
     /// If one of the nodes has finished processing the small chunk the server writes the data back to the whole image Array2D.
     fn process_data_from_node(&mut self, node_id: NodeID, node_data: &Self::ProcessedDataT) -> Result<(), NCError> {
-        debug!("Server::process_data_from_node, node_id: {}", node_id);
+        debug!("Server::process_data_from_node, {}", nc_context::current());
 
         let chunk_id = node_data.chunk_id;
         let source = &node_data.img;
@@ -102,6 +119,11 @@ impl NCServer for RayTracerServer {
         }
     }
 
+    /// Shows the number of finished chunks in the progress bar.
+    fn job_progress(&self) -> Option<(u64, u64)> {
+        Some(self.chunk_list.progress())
+    }
+
     /// If some nodes have crashed or lost the network connection the internal chunks list is updated.
     fn heartbeat_timeout(&mut self, nodes: Vec<NodeID>) {
         self.chunk_list.heartbeat_timeout(&nodes)
@@ -109,7 +131,9 @@ impl NCServer for RayTracerServer {
 
     /// When all processing is done the server calls this method. Here we just save the final image to disk.
     fn finish_job(&mut self) {
-        self.save_image();
+        if let Err(e) = self.save_image() {
+            error!("{}", e);
+        }
     }
 }
 
@@ -117,6 +141,7 @@ impl NCServer for RayTracerServer {
 pub fn run_server(options: RayTracer1Opt) {
     let configuration = NCConfiguration {
         port: options.port,
+        max_job_duration: if options.max_time > 0 { Some(Duration::from_secs(options.max_time)) } else { None },
         compress: true,
         encrypt: true,
         // The key should be read from a config file
@@ -143,8 +168,11 @@ pub fn run_server(options: RayTracer1Opt) {
     };
 
     let mut server_starter = NCServerStarter::new(configuration);
+    let progress = NCServerProgress::start(server_starter.progress_events());
+    let result = server_starter.start(server);
+    progress.finish();
 
-    match server_starter.start(server) {
+    match result {
         Ok(_) => {
             info!("Calculation finished");
         }
@@ -0,0 +1,96 @@
+
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::thread;
+use structopt::StructOpt;
+
+use node_crunch::{NCConfiguration, NCServerStarter, NCNodeStarter, NCResultStream, RangeServer, RangeNode, RangeResults};
+
+/// Computes the number of Collatz steps for every number of a range. Instead of collecting the results in
+/// process_data_from_node() the server pulls them from the result stream and writes every batch to the output file
+/// as soon as it arrives, so the results never have to fit into memory.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "result_stream")]
+pub struct StreamOpt {
+    #[structopt(short = "s", long = "server")]
+    server: bool,
+
+    #[structopt(long = "ip", default_value = "127.0.0.1")]
+    ip: String,
+
+    #[structopt(short = "p", long = "port", default_value = "2020")]
+    port: u16,
+
+    /// The numbers 1..=n are computed.
+    #[structopt(short = "n", long = "numbers", default_value = "1000000")]
+    numbers: u64,
+
+    /// Number of numbers that are sent to a node at once.
+    #[structopt(long = "batch-size", default_value = "10000")]
+    batch_size: u64,
+
+    /// The results are written to this file, one line per number: "number steps".
+    #[structopt(short = "o", long = "output", default_value = "collatz.txt")]
+    output: String,
+}
+
+/// Returns the number of steps until n reaches 1.
+fn collatz_steps(mut n: u64) -> u32 {
+    let mut steps = 0;
+
+    while n > 1 {
+        n = if n % 2 == 0 { n / 2 } else { 3 * n + 1 };
+        steps += 1;
+    }
+
+    steps
+}
+
+/// Writes the batches in the order they arrive, until the job is done.
+fn write_results(results: NCResultStream<RangeResults<u32>>, batch_size: u64, output: &str) -> std::io::Result<u64> {
+    let mut file = BufWriter::new(File::create(output)?);
+    let mut batches = 0;
+
+    for (_, result) in results {
+        let start = 1 + result.chunk_id * batch_size;
+
+        for (i, steps) in result.values.iter().enumerate() {
+            writeln!(file, "{} {}", start + i as u64, steps)?;
+        }
+
+        // Every batch is on disk before the next one is taken from the stream
+        file.flush()?;
+        batches += 1;
+    }
+
+    Ok(batches)
+}
+
+fn main() {
+    let options = StreamOpt::from_args();
+
+    let config = NCConfiguration {
+        address: options.ip.clone(),
+        port: options.port,
+        ..Default::default()
+    };
+
+    if options.server {
+        let mut server_starter = NCServerStarter::new(config);
+        // At most 8 batches wait for the writer, after that the nodes are slowed down.
+        let results: NCResultStream<RangeResults<u32>> = server_starter.results(8);
+        let batch_size = options.batch_size;
+        let output = options.output.clone();
+        let writer = thread::spawn(move || write_results(results, batch_size, &output));
+
+        // Nothing to fold, the results go to the stream.
+        let server = RangeServer::new(1..options.numbers + 1, options.batch_size, (), |_: &mut (), _, _: &[u32]| ());
+        let job_summary = server_starter.start(server).unwrap();
+        let batches = writer.join().unwrap().unwrap();
+
+        println!("{} batches written to {}, chunks sent: {}", batches, options.output, job_summary.chunks_sent);
+    } else {
+        NCNodeStarter::new(config).start(RangeNode::new(collatz_steps)).unwrap();
+    }
+}
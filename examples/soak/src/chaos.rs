@@ -0,0 +1,213 @@
+use std::fs;
+use std::io;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn, error};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::{SoakOpt, SoakReport};
+
+/// Time between two checks of the child processes.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What the chaos controller has done.
+#[derive(Debug, Default)]
+struct ChaosStats {
+    /// Number of node processes that have been killed.
+    nodes_killed: u64,
+    /// Number of node processes that have exited by themselves before the job was done.
+    nodes_exited: u64,
+    /// Number of times the server has been paused.
+    server_pauses: u64,
+    /// The total time the server has been paused.
+    pause_time: Duration,
+}
+
+/// Starts this program again with the given mode (--server or --node) and the options of the controller.
+fn spawn(mode: &str, options: &SoakOpt) -> io::Result<Child> {
+    Command::new(std::env::current_exe()?)
+        .arg(mode)
+        .args(["--ip", &options.ip])
+        .args(["--port", &options.port.to_string()])
+        .args(["--chunks", &options.chunks.to_string()])
+        .args(["--chunk-ms", &options.chunk_ms.to_string()])
+        .args(["--distribution", &format!("{:?}", options.distribution).to_lowercase()])
+        .args(["--seed", &options.seed.to_string()])
+        .args(["--pause-ms", &options.pause_ms.to_string()])
+        .args(["--heartbeat", &options.heartbeat.to_string()])
+        .args(["--report", &options.report])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+}
+
+/// Sends the given signal (SIGSTOP or SIGCONT) to the given process.
+fn signal(child: &Child, signal: libc::c_int) -> io::Result<()> {
+    if unsafe { libc::kill(child.id() as libc::pid_t, signal) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Kills the given process and waits for it, so that it doesn't become a zombie.
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Returns a random time with an exponential distribution and the given mean.
+fn random_interval(rng: &mut StdRng, mean_ms: u64) -> Duration {
+    Duration::from_millis((-(mean_ms as f64) * (1.0 - rng.gen::<f64>()).ln()) as u64)
+}
+
+/// Runs the server and the nodes until the job is done, causes chaos in the meantime and checks the report of the server.
+/// Returns true if every chunk has been processed exactly once and the attempt history of the chunks fits the chaos events.
+pub fn run_chaos(options: SoakOpt) -> bool {
+    let _ = fs::remove_file(&options.report);
+
+    // Not the same numbers as the chunk times.
+    let mut rng = StdRng::seed_from_u64(options.seed ^ 0x5EED_C4A0);
+    let mut stats = ChaosStats::default();
+    let start = Instant::now();
+    let max_time = Duration::from_secs(options.max_time);
+
+    let mut server = match spawn("--server", &options) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("Could not start server: {}", e);
+            println!("Could not start server: {}", e);
+            return false
+        }
+    };
+
+    // Give the server some time to open the port.
+    thread::sleep(Duration::from_millis(500));
+
+    let mut nodes: Vec<Child> = Vec::with_capacity(options.nodes);
+
+    for _ in 0..options.nodes {
+        match spawn("--node", &options) {
+            Ok(node) => nodes.push(node),
+            Err(e) => error!("Could not start node: {}", e),
+        }
+    }
+
+    println!("Soak test: {} chunks, {} nodes, seed: {}", options.chunks, nodes.len(), options.seed);
+
+    let mut next_event = Instant::now() + random_interval(&mut rng, options.chaos_ms);
+    let mut timed_out = false;
+
+    loop {
+        thread::sleep(POLL_INTERVAL);
+
+        if let Ok(Some(status)) = server.try_wait() {
+            info!("Server has exited: {}", status);
+            break
+        }
+
+        if start.elapsed() > max_time {
+            error!("Job is not done after {} seconds, give up", options.max_time);
+            kill(&mut server);
+            timed_out = true;
+            break
+        }
+
+        for node in nodes.iter_mut() {
+            if let Ok(Some(status)) = node.try_wait() {
+                warn!("Node {} has exited: {}, start a new one", node.id(), status);
+                stats.nodes_exited += 1;
+
+                match spawn("--node", &options) {
+                    Ok(new_node) => *node = new_node,
+                    Err(e) => error!("Could not start node: {}", e),
+                }
+            }
+        }
+
+        if Instant::now() < next_event {
+            continue
+        }
+
+        if rng.gen::<f64>() < options.pause_probability {
+            let pause = Duration::from_millis(rng.gen_range(0, options.pause_ms.max(1)));
+            info!("Pause server for {} ms", pause.as_millis());
+
+            if let Err(e) = signal(&server, libc::SIGSTOP) {
+                error!("Could not pause server: {}", e);
+            } else {
+                thread::sleep(pause);
+
+                if let Err(e) = signal(&server, libc::SIGCONT) {
+                    error!("Could not continue server: {}", e);
+                }
+
+                stats.server_pauses += 1;
+                stats.pause_time += pause;
+            }
+        } else if !nodes.is_empty() {
+            let index = rng.gen_range(0, nodes.len());
+            info!("Kill node {} and start a new one", nodes[index].id());
+            kill(&mut nodes[index]);
+            stats.nodes_killed += 1;
+
+            match spawn("--node", &options) {
+                Ok(new_node) => nodes[index] = new_node,
+                Err(e) => error!("Could not start node: {}", e),
+            }
+        }
+
+        next_event = Instant::now() + random_interval(&mut rng, options.chaos_ms);
+    }
+
+    // The nodes usually exit by themselves once the server is done, the others are stopped after a short time.
+    let stop_time = Instant::now() + Duration::from_secs(2 * options.heartbeat);
+
+    for node in nodes.iter_mut() {
+        while Instant::now() < stop_time && matches!(node.try_wait(), Ok(None)) {
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        kill(node);
+    }
+
+    println!("Chaos: {} nodes killed, {} nodes exited, server paused {} times ({:.1} s), wall time: {:.1} s",
+        stats.nodes_killed, stats.nodes_exited, stats.server_pauses, stats.pause_time.as_secs_f64(), start.elapsed().as_secs_f64());
+
+    if timed_out {
+        println!("FAILED: the job is not done after {} seconds", options.max_time);
+        return false
+    }
+
+    let report: SoakReport = match fs::read_to_string(&options.report).map_err(|e| e.to_string())
+        .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string())) {
+        Ok(report) => report,
+        Err(e) => {
+            println!("FAILED: could not read the report {}: {}", options.report, e);
+            return false
+        }
+    };
+
+    println!("{}", report.summary);
+
+    let faults = stats.nodes_killed > 0 || stats.nodes_exited > 0 || stats.server_pauses > 0;
+    let history_errors = report.history_errors(faults);
+
+    for error in history_errors.iter() {
+        println!("FAILED: chunk history: {}", error);
+    }
+
+    if report.is_ok() {
+        println!("OK: all {} chunks have been processed exactly once", report.chunks);
+    } else {
+        println!("FAILED: {} chunks missing, {} chunks processed more than once, {} wrong checksums",
+            report.missing.len(), report.duplicates.len(), report.wrong_checksums.len());
+        println!("Missing: {:?}", report.missing);
+        println!("Duplicates (chunk, count): {:?}", report.duplicates);
+        println!("Wrong checksums: {:?}", report.wrong_checksums);
+    }
+
+    report.is_ok() && history_errors.is_empty()
+}
@@ -0,0 +1,220 @@
+
+
+use std::fs;
+use std::str::FromStr;
+use structopt::StructOpt;
+use log4rs;
+use serde::{Serialize, Deserialize};
+
+use node_crunch::{ChunkID, NCJobSummary, NCAttemptOutcome};
+
+mod server;
+mod node;
+mod chaos;
+
+/// A long running system test for the fault tolerance of node_crunch: the chaos controller (started without --server or --node)
+/// starts the server and the nodes as child processes, kills and restarts the nodes at random and pauses the server
+/// with SIGSTOP / SIGCONT. At the end it checks that every chunk has been processed exactly once, that the attempt history
+/// of the chunks fits the chaos events and prints a report.
+/// The same --seed gives the same chunks and the same sequence of chaos events.
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(name = "soak")]
+pub struct SoakOpt {
+    /// Run as the server, this is done by the chaos controller.
+    #[structopt(short = "s", long = "server")]
+    server: bool,
+
+    /// Run as a node, this is done by the chaos controller.
+    #[structopt(short = "n", long = "node")]
+    node: bool,
+
+    #[structopt(long = "ip", default_value = "127.0.0.1")]
+    ip: String,
+
+    #[structopt(short = "p", long = "port", default_value = "2020")]
+    port: u16,
+
+    /// Number of chunks in the job.
+    #[structopt(long = "chunks", default_value = "500")]
+    chunks: u64,
+
+    /// Mean time a node needs for one chunk in milliseconds.
+    #[structopt(long = "chunk-ms", default_value = "200")]
+    chunk_ms: u64,
+
+    /// Distribution of the chunk times: fixed, uniform (0 - 2 * mean) or exponential.
+    #[structopt(long = "distribution", default_value = "uniform")]
+    distribution: Distribution,
+
+    /// Number of node processes.
+    #[structopt(long = "nodes", default_value = "4")]
+    nodes: usize,
+
+    /// Seed for the chunk times and the chaos events.
+    #[structopt(long = "seed", default_value = "1")]
+    seed: u64,
+
+    /// Mean time between two chaos events in milliseconds.
+    #[structopt(long = "chaos-ms", default_value = "2000")]
+    chaos_ms: u64,
+
+    /// Probability that a chaos event pauses the server instead of killing a node.
+    #[structopt(long = "pause-probability", default_value = "0.2")]
+    pause_probability: f64,
+
+    /// Maximum time the server is paused in milliseconds.
+    #[structopt(long = "pause-ms", default_value = "5000")]
+    pause_ms: u64,
+
+    /// Heartbeat of the server and the nodes in seconds.
+    #[structopt(long = "heartbeat", default_value = "2")]
+    heartbeat: u64,
+
+    /// Give up if the job is not done after this many seconds.
+    #[structopt(long = "max-time", default_value = "1800")]
+    max_time: u64,
+
+    /// The server writes its report to this file.
+    #[structopt(long = "report", default_value = "soak_report.json")]
+    report: String,
+}
+
+/// How the time for the chunks is chosen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distribution {
+    /// Every chunk takes the mean time.
+    Fixed,
+    /// Between 0 and two times the mean time.
+    Uniform,
+    /// Exponential distribution with the mean time, some chunks take much longer.
+    Exponential,
+}
+
+impl FromStr for Distribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(Distribution::Fixed),
+            "uniform" => Ok(Distribution::Uniform),
+            "exponential" => Ok(Distribution::Exponential),
+            _ => Err(format!("Unknown distribution: '{}', use fixed, uniform or exponential", s)),
+        }
+    }
+}
+
+/// The work for one chunk: the node waits for the given time and then returns the checksum of the value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerData {
+    chunk_id: ChunkID,
+    duration_ms: u64,
+    value: u64,
+}
+
+/// The result of one chunk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeData {
+    chunk_id: ChunkID,
+    checksum: u64,
+}
+
+/// The checksum that the node calculates for the value of a chunk.
+pub fn checksum(value: u64) -> u64 {
+    value.wrapping_mul(0x9E37_79B9_7F4A_7C15).rotate_left(17)
+}
+
+/// What the server has seen, written to the report file when the job is done.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SoakReport {
+    /// Number of chunks in the job.
+    chunks: u64,
+    /// The chunks that have never been processed.
+    missing: Vec<ChunkID>,
+    /// The chunks that have been processed more than once and how often.
+    duplicates: Vec<(ChunkID, u32)>,
+    /// The chunks with a wrong checksum.
+    wrong_checksums: Vec<ChunkID>,
+    /// The job summary from the server.
+    summary: NCJobSummary,
+}
+
+impl SoakReport {
+    /// Every chunk has been processed exactly once with the right checksum.
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.duplicates.is_empty() && self.wrong_checksums.is_empty()
+    }
+
+    /// Checks the attempt history of the chunks in the summary against the chaos events, returns what doesn't fit.
+    /// faults is true if the chaos controller has killed a node, a node has exited or the server has been paused.
+    pub fn history_errors(&self, faults: bool) -> Vec<String> {
+        let mut errors = Vec::new();
+        let history = &self.summary.chunk_history;
+
+        if !faults && !history.is_empty() {
+            errors.push(format!("{} chunks have been sent more than once without any chaos event", history.len()));
+        }
+
+        for (chunk_id, attempts) in history.iter() {
+            if attempts.len() < 2 {
+                errors.push(format!("chunk {} has a history with only {} attempt", chunk_id, attempts.len()));
+            }
+
+            // The nodes of the soak test never fail, only kills and pauses end an attempt early.
+            if let Some(attempt) = attempts.iter().find(|attempt| matches!(attempt.outcome, NCAttemptOutcome::Failed(_))) {
+                errors.push(format!("chunk {} has failed on node {}: {}", chunk_id, attempt.node_id, attempt.outcome));
+            }
+        }
+
+        // Every retry is an additional attempt in the history, unless the history of the chunk is full.
+        let additional: u64 = history.values().map(|attempts| attempts.len() as u64 - 1).sum();
+        let full = history.values().any(|attempts| attempts.len() >= 16);
+
+        if additional > self.summary.retries || (!full && additional != self.summary.retries) {
+            errors.push(format!("{} retries, but {} additional attempts in the history", self.summary.retries, additional));
+        }
+
+        errors
+    }
+}
+
+fn create_logger(filename: &str) {
+    let file_logger = log4rs::append::file::FileAppender::builder()
+        .encoder(Box::new(log4rs::encode::pattern::PatternEncoder::new("{d} {l} - {m}{n}")))
+        .build(filename).unwrap();
+
+    let config = log4rs::config::Config::builder()
+        .appender(log4rs::config::Appender::builder().build("file_logger", Box::new(file_logger)))
+        .build(log4rs::config::Root::builder().appender("file_logger").build(log::LevelFilter::Info))
+        .unwrap();
+
+    let _log_handle = log4rs::init_config(config).unwrap();
+}
+
+fn main() {
+    let options = SoakOpt::from_args();
+
+    if options.server {
+        create_logger("nc_server.log");
+        server::run_server(options);
+    } else if options.node {
+        let mut postfix: u64 = 1;
+        let mut filename = format!("nc_node_{:08}.log", postfix);
+
+        loop {
+            if fs::metadata(&filename).is_ok() {
+                // Filename for logging already exists, try another one...
+                postfix += 1;
+                filename = format!("nc_node_{:08}.log", postfix);
+            } else {
+                break
+            }
+        }
+
+        create_logger(&filename);
+        node::run_node(options)
+    } else {
+        create_logger("soak_chaos.log");
+        let ok = chaos::run_chaos(options);
+        std::process::exit(if ok { 0 } else { 1 })
+    }
+}
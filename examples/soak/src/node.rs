@@ -0,0 +1,63 @@
+use std::thread;
+use std::time::Duration;
+
+use log::{info, error};
+
+use node_crunch::{NCNode, NCError, NCConfiguration, NCNodeStarter, NodeResult, NodeExit};
+
+use crate::{SoakOpt, ServerData, NodeData, checksum};
+
+/// The fake work has no state.
+struct SoakNode {
+}
+
+impl NCNode for SoakNode {
+    type InitialDataT = ();
+    type NewDataT = ServerData;
+    type ProcessedDataT = NodeData;
+    type CustomMessageT = ();
+
+    /// Waits for the time of the chunk and returns the checksum of its value.
+    fn process_data_from_server(&mut self, data: &Self::NewDataT) -> Result<NodeResult<Self::ProcessedDataT>, NCError> {
+        thread::sleep(Duration::from_millis(data.duration_ms));
+
+        Ok(NodeResult::Data(NodeData { chunk_id: data.chunk_id, checksum: checksum(data.value) }))
+    }
+}
+
+/// Starts the node with the given options.
+pub fn run_node(options: SoakOpt) {
+    let configuration = NCConfiguration {
+        port: options.port,
+        address: options.ip,
+        heartbeat: options.heartbeat,
+        compress: true,
+        delay_request_data: 1,
+        // The node has to reconnect after the server has been paused, so it must not give up too early.
+        retry_counter: (options.pause_ms / 1000 + 5) * 10,
+        ..Default::default()
+    };
+
+    let node = SoakNode{};
+    let mut node_starter = NCNodeStarter::new(configuration);
+
+    match node_starter.start(node) {
+        Ok(NodeExit::JobFinished) | Ok(NodeExit::StoppedByUser) => {
+            info!("Calculation finished");
+        }
+        Ok(NodeExit::RestartRequested) => {
+            info!("Restart requested by server");
+        }
+        Ok(NodeExit::Disabled { reason }) => {
+            info!("Node has been disabled by the server: {}", reason);
+        }
+        Ok(NodeExit::JobAborted { reason }) => {
+            error!("Job has been aborted by the server: {}", reason);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            error!("An error occurred: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
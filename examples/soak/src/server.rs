@@ -0,0 +1,189 @@
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use log::{info, warn, error, debug};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use node_crunch::{NCServer, ChunkAssignment, NCConfiguration, NCError, NCJobError, NCJobSummary,
+    ChunkList, ChunkID, ChunkStatus, NodeID, NCServerStarter};
+
+use crate::{SoakOpt, Distribution, ServerData, NodeData, SoakReport, checksum};
+
+/// The accumulator: how often every chunk has been processed. It's shared with run_server(),
+/// since the server data structure is gone after the job.
+#[derive(Debug, Default)]
+struct Accumulator {
+    /// Number of results for every chunk.
+    counts: Vec<u32>,
+    /// The chunks with a wrong checksum.
+    wrong_checksums: Vec<ChunkID>,
+}
+
+impl Accumulator {
+    /// Creates the report for the given job summary.
+    fn report(&self, summary: NCJobSummary) -> SoakReport {
+        let chunks = || self.counts.iter().enumerate().map(|(chunk_id, count)| (chunk_id as ChunkID, *count));
+
+        SoakReport {
+            chunks: self.counts.len() as u64,
+            missing: chunks().filter(|(_, count)| *count == 0).map(|(chunk_id, _)| chunk_id).collect(),
+            duplicates: chunks().filter(|(_, count)| *count > 1).collect(),
+            wrong_checksums: self.wrong_checksums.clone(),
+            summary,
+        }
+    }
+}
+
+/// The chunk list with the fake work and the accumulator.
+struct SoakServer {
+    /// Book keeping chunk list, which node is processing which chunk.
+    chunk_list: ChunkList<ServerData>,
+    /// Counts the results.
+    accumulator: Arc<Mutex<Accumulator>>,
+}
+
+impl SoakServer {
+    /// Creates the chunks with the given options, the time for every chunk is drawn from the distribution.
+    fn new(options: &SoakOpt, accumulator: Arc<Mutex<Accumulator>>) -> Self {
+        let mut rng = StdRng::seed_from_u64(options.seed);
+        let mean = options.chunk_ms as f64;
+        let mut chunk_list = ChunkList::new();
+
+        for chunk_id in 0..options.chunks {
+            let duration_ms = match options.distribution {
+                Distribution::Fixed => mean,
+                Distribution::Uniform => rng.gen_range(0.0, 2.0 * mean),
+                Distribution::Exponential => -mean * (1.0 - rng.gen::<f64>()).ln(),
+            };
+
+            chunk_list.push(ServerData { chunk_id, duration_ms: duration_ms as u64, value: rng.gen() });
+        }
+
+        accumulator.lock().unwrap().counts = vec![0; options.chunks as usize];
+
+        SoakServer { chunk_list, accumulator }
+    }
+
+    /// Checks if the job is already done.
+    fn is_job_done(&self) -> bool {
+        let (empty, processing, finished) = self.chunk_list.stats();
+        debug!("Job status: empty: {}, processing: {}, finished: {}", empty, processing, finished);
+
+        empty == 0 && processing == 0
+    }
+}
+
+impl NCServer for SoakServer {
+    type InitialDataT = ();
+    type NewDataT = ServerData;
+    type ProcessedDataT = NodeData;
+    type CustomMessageT = ();
+
+    /// Every node gets the next free chunk.
+    fn assign_chunk(&mut self, node_id: NodeID) -> Result<ChunkAssignment<Self::NewDataT>, NCError> {
+        debug!("Server::assign_chunk, node_id: {}", node_id);
+
+        if let Some((chunk_id, free_chunk)) = self.chunk_list.assign_next_chunk(node_id) {
+            Ok(ChunkAssignment::Assigned(chunk_id, free_chunk.data.clone()))
+        } else if self.is_job_done() {
+            Ok(ChunkAssignment::Finished)
+        } else {
+            Ok(ChunkAssignment::Waiting)
+        }
+    }
+
+    /// The data for the chunk has been sent to the node, now it is really in processing state.
+    fn chunk_sent(&mut self, chunk_id: ChunkID) {
+        self.chunk_list.chunk_sent(chunk_id)
+    }
+
+    /// The data for the chunk could not be sent to the node (it has been killed), give it to another node.
+    fn chunk_send_failed(&mut self, chunk_id: ChunkID) {
+        self.chunk_list.chunk_send_failed(chunk_id)
+    }
+
+    /// The chunks are sent again from the cache of the server.
+    fn reassign_chunk(&mut self, chunk_id: ChunkID, node_id: NodeID) -> bool {
+        self.chunk_list.reassign_chunk(chunk_id, node_id)
+    }
+
+    fn process_node_error(&mut self, node_id: NodeID, error: &NCJobError) {
+        self.chunk_list.chunk_failed(node_id, error.retryable)
+    }
+
+    fn chunk_rejected(&mut self, chunk_id: ChunkID, requeue: bool) {
+        self.chunk_list.chunk_rejected(chunk_id, requeue)
+    }
+
+    /// Every result is counted, also the ones from nodes that have been given up in the meantime.
+    /// The server has to drop the duplicates, otherwise the report shows them.
+    fn process_data_from_node(&mut self, node_id: NodeID, node_data: &Self::ProcessedDataT) -> Result<(), NCError> {
+        debug!("Server::process_data_from_node, node_id: {}, chunk: {}", node_id, node_data.chunk_id);
+
+        let chunk = self.chunk_list.get(node_data.chunk_id as usize);
+        let mut accumulator = self.accumulator.lock()?;
+        accumulator.counts[node_data.chunk_id as usize] += 1;
+
+        if node_data.checksum != checksum(chunk.data.value) {
+            error!("Wrong checksum for chunk {} from node {}", node_data.chunk_id, node_id);
+            accumulator.wrong_checksums.push(node_data.chunk_id);
+        }
+
+        if chunk.status() == ChunkStatus::Finished {
+            warn!("Chunk {} from node {} has already been processed", node_data.chunk_id, node_id);
+        }
+
+        chunk.set_finished();
+        Ok(())
+    }
+
+    fn job_progress(&self) -> Option<(u64, u64)> {
+        Some(self.chunk_list.progress())
+    }
+
+    /// The node with the last result exits right away.
+    fn job_finished(&self) -> bool {
+        self.is_job_done()
+    }
+
+    /// The killed nodes and the nodes that could not reach the paused server.
+    fn heartbeat_timeout(&mut self, nodes: Vec<NodeID>) {
+        info!("Heartbeat timeout: {} nodes", nodes.len());
+        self.chunk_list.heartbeat_timeout(&nodes)
+    }
+
+    fn finish_job(&mut self) {
+        info!("Job finished");
+    }
+}
+
+/// Starts the server with the given options and writes the report file when the job is done.
+pub fn run_server(options: SoakOpt) {
+    let configuration = NCConfiguration {
+        port: options.port,
+        heartbeat: options.heartbeat,
+        compress: true,
+        // The features that are tested: results in chunk order without duplicates and chunks sent again from the cache.
+        ordered_results: true,
+        cache_chunk_payloads: true,
+        ..Default::default()
+    };
+
+    let accumulator = Arc::new(Mutex::new(Accumulator::default()));
+    let server = SoakServer::new(&options, accumulator.clone());
+    let mut server_starter = NCServerStarter::new(configuration);
+
+    match server_starter.start(server) {
+        Ok(summary) => {
+            info!("{}", summary);
+            let report = accumulator.lock().unwrap().report(summary);
+
+            if let Err(e) = fs::write(&options.report, serde_json::to_string_pretty(&report).unwrap()) {
+                error!("Could not write report {}: {}", options.report, e);
+            }
+        }
+        Err(e) => {
+            error!("An error occurred: {}", e);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+
+
+use std::fs;
+use std::collections::HashMap;
+use structopt::StructOpt;
+use log4rs;
+use serde::{Serialize, Deserialize};
+
+use node_crunch::ChunkID;
+
+mod server;
+mod node;
+
+/// Counts the words of a text file. The nodes started with --aggregator-port also combine the counts of the other nodes,
+/// so the server only gets a few big results instead of one for every chunk.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "word_count")]
+pub struct WordCountOpt {
+    #[structopt(short = "s", long = "server")]
+    server: bool,
+
+    #[structopt(long = "ip", default_value = "127.0.0.1")]
+    ip: String,
+
+    #[structopt(short = "p", long = "port", default_value = "2020")]
+    port: u16,
+
+    /// The text file to count, only used by the server. If not given some text is generated.
+    #[structopt(long = "input")]
+    input: Option<String>,
+
+    /// The node also works as an aggregator for the other nodes and listens on this port.
+    #[structopt(long = "aggregator-port")]
+    aggregator_port: Option<u16>,
+}
+
+/// Some lines of the text.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerData {
+    chunk_id: ChunkID,
+    lines: Vec<String>,
+}
+
+/// The word counts of one or more chunks (if they have been combined on an aggregator).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeData {
+    chunks: Vec<ChunkID>,
+    counts: HashMap<String, u64>,
+}
+
+fn create_logger(filename: &str) {
+    let file_logger = log4rs::append::file::FileAppender::builder()
+        .encoder(Box::new(log4rs::encode::pattern::PatternEncoder::new("{d} {l} - {m}{n}")))
+        .build(filename).unwrap();
+
+    let config = log4rs::config::Config::builder()
+        .appender(log4rs::config::Appender::builder().build("file_logger", Box::new(file_logger)))
+        .build(log4rs::config::Root::builder().appender("file_logger").build(log::LevelFilter::Debug))
+        .unwrap();
+
+    let _log_handle = log4rs::init_config(config).unwrap();
+}
+
+fn main() {
+    let options = WordCountOpt::from_args();
+
+    if options.server {
+        create_logger("nc_server.log");
+        server::run_server(options);
+    } else {
+        let mut postfix: u64 = 1;
+        let mut filename = format!("nc_node_{:08}.log", postfix);
+
+        loop {
+            if fs::metadata(&filename).is_ok() {
+                // Filename for logging already exists, try another one...
+                postfix += 1;
+                filename = format!("nc_node_{:08}.log", postfix);
+            } else {
+                break
+            }
+        }
+
+        create_logger(&filename);
+        node::run_node(options)
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+
+use log::{info, warn, error};
+
+use node_crunch::{NCNode, NCError, NCConfiguration, NCNodeStarter, NodeResult, NCNodeProgress, NodeExit};
+
+use crate::{WordCountOpt, ServerData, NodeData};
+
+/// In this example the NCNode data struct has no useful data, just code.
+struct WordCountNode {
+}
+
+impl NCNode for WordCountNode {
+    type InitialDataT = ();
+    type NewDataT = ServerData;
+    type ProcessedDataT = NodeData;
+    type CustomMessageT = ();
+
+    /// Counts all the words in the lines of the chunk, upper and lower case are the same word.
+    fn process_data_from_server(&mut self, data: &Self::NewDataT) -> Result<NodeResult<Self::ProcessedDataT>, NCError> {
+        let mut counts = HashMap::new();
+
+        for line in data.lines.iter() {
+            for word in line.split_whitespace() {
+                let word = word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase();
+
+                if !word.is_empty() {
+                    *counts.entry(word).or_insert(0) += 1;
+                }
+            }
+        }
+
+        Ok(NodeResult::Data(NodeData { chunks: vec![data.chunk_id], counts }))
+    }
+
+    /// This runs on the aggregator nodes: the word counts of two results are just added up.
+    fn reduce(mut aggregate: Self::ProcessedDataT, data: Self::ProcessedDataT) -> Result<Self::ProcessedDataT, NCError> {
+        aggregate.chunks.extend(data.chunks);
+
+        for (word, count) in data.counts {
+            *aggregate.counts.entry(word).or_insert(0) += count;
+        }
+
+        Ok(aggregate)
+    }
+}
+
+/// Starts the node with the given configuration.
+pub fn run_node(options: WordCountOpt) {
+    let configuration = NCConfiguration {
+        port: options.port,
+        address: options.ip,
+        compress: true,
+        encrypt: true,
+        // The key should be read from a config file
+        key: "ZKS1GQ3MYWEKFILSN6KESXU2GD9015CH".to_string(),
+        aggregator_port: options.aggregator_port,
+        aggregate_interval: 5,
+        ..Default::default()
+    };
+
+    let node = WordCountNode{};
+    let mut node_starter = NCNodeStarter::new(configuration);
+    let progress = NCNodeProgress::start(node_starter.progress_events());
+    let result = node_starter.start(node);
+    progress.finish();
+
+    match result {
+        Ok(NodeExit::JobFinished) | Ok(NodeExit::StoppedByUser) => {
+            info!("Calculation finished");
+        }
+        Ok(NodeExit::RestartRequested) => {
+            // Tell the wrapper script to restart the node with the new build
+            info!("Restart requested by server");
+            std::process::exit(3);
+        }
+        Ok(NodeExit::Disabled { reason }) => {
+            // Tell the wrapper script not to start the node again
+            warn!("Node has been disabled by the server: {}", reason);
+            std::process::exit(4);
+        }
+        Ok(NodeExit::JobAborted { reason }) => {
+            error!("Job has been aborted by the server: {}", reason);
+            std::process::exit(2);
+        }
+        Err(e) => {
+            error!("An error occurred: {}", e);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fs;
+
+use log::{info, error, debug};
+
+use node_crunch::{NCServer, ChunkAssignment, NCConfiguration, NCError, NCJobError,
+    ChunkList, ChunkID, NodeID, NCServerStarter, NCServerProgress};
+
+use crate::{WordCountOpt, ServerData, NodeData};
+
+/// Number of lines in one chunk.
+const LINES_PER_CHUNK: usize = 1000;
+
+/// This has the word counts so far and the chunks list, every chunk has some lines of the text.
+#[derive(Debug, Clone)]
+struct WordCountServer {
+    /// The word counts of all the finished chunks.
+    counts: HashMap<String, u64>,
+    /// Book keeping chunk list, which node is processing which part of the text.
+    chunk_list: ChunkList<Vec<String>>,
+}
+
+impl WordCountServer {
+    /// Marks the given chunks as finished and adds up the word counts.
+    /// All the chunks must belong to the given node, otherwise nothing is counted.
+    fn add_counts(&mut self, node_id: NodeID, chunks: &[ChunkID], counts: &HashMap<String, u64>) -> Result<(), NCError> {
+        for chunk_id in chunks {
+            let chunk = self.chunk_list.get(*chunk_id as usize);
+
+            if !chunk.is_processing(node_id) {
+                error!("Mismatch data, chunk {} should be Processing with node_id: {}, but is {:?}", chunk_id, node_id, chunk.node_id);
+                return Err(NCError::NodeIDMismatch(node_id, chunk.node_id))
+            }
+        }
+
+        for chunk_id in chunks {
+            self.chunk_list.get(*chunk_id as usize).set_finished();
+        }
+
+        for (word, count) in counts {
+            *self.counts.entry(word.clone()).or_insert(0) += count;
+        }
+
+        Ok(())
+    }
+
+    /// Writes all the words and their counts to disk, the most frequent ones first.
+    fn save_counts(&self) -> Result<(), NCError> {
+        let mut counts: Vec<(&String, &u64)> = self.counts.iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+        let lines: Vec<String> = counts.iter().map(|(word, count)| format!("{} {}", word, count)).collect();
+
+        // The io error is kept as the source, so the log shows the whole chain.
+        fs::write("word_count.txt", lines.join("\n")).map_err(|e| NCError::with_source("could not save word_count.txt", e))
+    }
+
+    /// Checks if the job (counting all the words) is already done.
+    fn is_job_done(&self) -> bool {
+        let (empty, processing, finished) = self.chunk_list.stats();
+        debug!("Job status: empty: {}, processing: {}, finished: {}", empty, processing, finished);
+
+        empty == 0 && processing == 0
+    }
+}
+
+impl NCServer for WordCountServer {
+    type InitialDataT = ();
+    type NewDataT = ServerData;
+    type ProcessedDataT = NodeData;
+    type CustomMessageT = ();
+
+    /// Every node gets the lines of the next free chunk.
+    fn assign_chunk(&mut self, node_id: NodeID) -> Result<ChunkAssignment<Self::NewDataT>, NCError> {
+        debug!("Server::assign_chunk, node_id: {}", node_id);
+
+        if let Some((chunk_id, free_chunk)) = self.chunk_list.assign_next_chunk(node_id) {
+            debug!("preparing chunk {} for node {}", chunk_id, node_id);
+            Ok(ChunkAssignment::Assigned(chunk_id, ServerData { chunk_id, lines: free_chunk.data.clone() }))
+        } else if self.is_job_done() {
+            Ok(ChunkAssignment::Finished)
+        } else {
+            Ok(ChunkAssignment::Waiting)
+        }
+    }
+
+    /// The data for the chunk has been sent to the node, now it is really in processing state.
+    fn chunk_sent(&mut self, chunk_id: ChunkID) {
+        self.chunk_list.chunk_sent(chunk_id)
+    }
+
+    /// The data for the chunk could not be sent to the node, give it to another node.
+    fn chunk_send_failed(&mut self, chunk_id: ChunkID) {
+        self.chunk_list.chunk_send_failed(chunk_id)
+    }
+
+    fn process_node_error(&mut self, node_id: NodeID, error: &NCJobError) {
+        self.chunk_list.chunk_failed(node_id, error.retryable)
+    }
+
+    /// The counts could not be used (or reduce() has failed on an aggregator), give the chunk to another node.
+    fn chunk_rejected(&mut self, chunk_id: ChunkID, requeue: bool) {
+        self.chunk_list.chunk_rejected(chunk_id, requeue)
+    }
+
+    /// A node without an aggregator has sent the word counts of its chunk.
+    fn process_data_from_node(&mut self, node_id: NodeID, node_data: &Self::ProcessedDataT) -> Result<(), NCError> {
+        debug!("Server::process_data_from_node, node_id: {}", node_id);
+
+        self.add_counts(node_id, &node_data.chunks, &node_data.counts)
+    }
+
+    /// An aggregator takes over the chunk of a node, from now on the chunk belongs to the aggregator.
+    fn chunk_delegated(&mut self, chunk_id: ChunkID, from: NodeID, to: NodeID) -> bool {
+        self.chunk_list.delegate_chunk(chunk_id, from, to)
+    }
+
+    /// An aggregator has sent the combined word counts of the given chunks.
+    fn process_aggregate(&mut self, node_id: NodeID, chunks: &[ChunkID], data: &Self::ProcessedDataT) -> Result<(), NCError> {
+        info!("Server::process_aggregate, node_id: {}, chunks: {}", node_id, chunks.len());
+
+        self.add_counts(node_id, chunks, &data.counts)
+    }
+
+    /// Shows the number of finished chunks in the progress bar.
+    fn job_progress(&self) -> Option<(u64, u64)> {
+        Some(self.chunk_list.progress())
+    }
+
+    /// The node with the last result exits right away.
+    fn job_finished(&self) -> bool {
+        self.is_job_done()
+    }
+
+    /// If some nodes have crashed or lost the network connection the internal chunks list is updated.
+    /// For an aggregator this includes all the chunks it has taken over from other nodes.
+    fn heartbeat_timeout(&mut self, nodes: Vec<NodeID>) {
+        self.chunk_list.heartbeat_timeout(&nodes)
+    }
+
+    /// When all processing is done the server calls this method. Here we just save the word counts to disk.
+    fn finish_job(&mut self) {
+        info!("Number of different words: {}", self.counts.len());
+
+        if let Err(e) = self.save_counts() {
+            error!("{}", e);
+        }
+    }
+}
+
+/// Some text if no input file is given.
+fn generate_text() -> Vec<String> {
+    let words = ["node", "crunch", "server", "chunk", "aggregator", "reduce", "heartbeat", "result"];
+
+    (0..100_000).map(|i| {
+        (0..10).map(|j| words[(i * 7 + j * 3) % words.len()]).collect::<Vec<_>>().join(" ")
+    }).collect()
+}
+
+/// Starts the server with the given configuration
+pub fn run_server(options: WordCountOpt) {
+    let configuration = NCConfiguration {
+        port: options.port,
+        compress: true,
+        encrypt: true,
+        // The key should be read from a config file
+        key: "ZKS1GQ3MYWEKFILSN6KESXU2GD9015CH".to_string(),
+        ..Default::default()
+    };
+
+    let lines = match &options.input {
+        Some(input) => match fs::read_to_string(input) {
+            Ok(text) => text.lines().map(String::from).collect(),
+            Err(e) => {
+                error!("Could not read input file {}: {}", input, e);
+                return
+            }
+        },
+        None => generate_text(),
+    };
+
+    let mut chunk_list = ChunkList::new();
+
+    for chunk in lines.chunks(LINES_PER_CHUNK) {
+        chunk_list.push(chunk.to_vec());
+    }
+
+    let server = WordCountServer { counts: HashMap::new(), chunk_list };
+
+    let mut server_starter = NCServerStarter::new(configuration);
+    let progress = NCServerProgress::start(server_starter.progress_events());
+    let result = server_starter.start(server);
+    progress.finish();
+
+    match result {
+        Ok(_) => {
+            info!("Calculation finished");
+        }
+        Err(e) => {
+            error!("An error occurred: {}", e);
+        }
+    }
+}
@@ -1,16 +1,37 @@
 //! This module contains helper structures to deal with 2D data.
 //! [`Array2D`] and [`Array2DChunk`] take care of splitting up the 2D array into chunks
 //! that can be sent to the node in order to process them.
+//! For stencil computations [`Array2D::split_with_halo()`] creates tiles that include a border from their neighbors,
+//! see [`HaloTile`] and [`Array2D::stitch()`].
 
+use std::collections::{HashMap, BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::mem::size_of;
 use std::slice::{Chunks, ChunksMut};
+use std::time::SystemTime;
 
 use serde::{Serialize, Deserialize};
 
 use crate::nc_node_info::NodeID;
 use crate::nc_error::NCError;
+use crate::nc_locality::LocalityMap;
+
+/// The id of a chunk, this is the index into the [`ChunkList`].
+pub type ChunkID = u64;
+
+/// Key / value metadata of a chunk (for example tile coordinates or sample counts), see [`ChunkList::push_with_meta()`].
+/// It's sent to the node together with the data and comes back with the result, so the server doesn't have to decode the data for it.
+pub type ChunkMeta = HashMap<String, String>;
+
+/// The size of the metadata in bytes (keys and values), this is checked against max_chunk_meta_bytes in the NCConfiguration.
+#[cfg(feature = "net")]
+pub(crate) fn chunk_meta_size(meta: &ChunkMeta) -> usize {
+    meta.iter().map(|(key, value)| key.len() + value.len()).sum()
+}
 
 /// Contains the 2D data, the width and the height of the 2D array.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Array2D<T> {
     /// The width of the 2D array.
     width: u64,
@@ -33,6 +54,24 @@ impl<T: Clone + Copy> Array2D<T> {
         }
     }
 
+    /// Creates a new [`Array2D`] with the given dimension from the values, row by row.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::Array2DDimensionMismatch`] error if the number of values doesn't match the dimension or the dimension is 0.
+    pub fn from_vec(width: u64, height: u64, data: Vec<T>) -> Result<Array2D<T>, NCError> {
+        if width == 0 || height == 0 || data.len() as u64 != width * height {
+            return Err(NCError::Array2DDimensionMismatch((width, height), (data.len() as u64, 1)))
+        }
+
+        Ok(Array2D { width, height, data })
+    }
+
+    /// Returns all values, row by row.
+    pub fn as_slice(&self) -> &[T] {
+        &self.data
+    }
+
     /// Calculates the correct index into the [`Vec`] for the given `(x, y)` position.
     fn index(&self, x: u64, y: u64) -> usize {
         ((self.width * y) + x) as usize
@@ -49,6 +88,11 @@ impl<T: Clone + Copy> Array2D<T> {
         self.data[index] = value;
     }
 
+    /// Returns the dimension `(width, height)`.
+    pub fn dimensions(&self) -> (u64, u64) {
+        (self.width, self.height)
+    }
+
     /// Sets a whole 2D region of data values.
     pub fn set_region(&mut self, dest_x: u64, dest_y: u64, source: &Array2D<T>) {
         for x in 0..source.width {
@@ -69,6 +113,181 @@ impl<T: Clone + Copy> Array2D<T> {
     pub fn split_row_mut(&mut self) -> ChunksMut<'_, T> {
         self.data.chunks_mut(self.width as usize)
     }
+
+    /// Splits the array into tiles of `tile_width` * `tile_height` (the last tiles in each direction may be smaller, like in
+    /// [`Array2DChunk`]), row by row. Every tile also contains a border of `halo` cells from its neighbors on all four sides.
+    /// Cells of the border that are outside of the array are set to `boundary`.
+    pub fn split_with_halo(&self, tile_width: u64, tile_height: u64, halo: u64, boundary: T) -> Vec<HaloTile<T>> {
+        assert!(tile_width > 0);
+        assert!(tile_height > 0);
+
+        let mut tiles = Vec::new();
+
+        for y in (0..self.height).step_by(tile_height as usize) {
+            for x in (0..self.width).step_by(tile_width as usize) {
+                let region = TileRegion { x, y, width: tile_width.min(self.width - x), height: tile_height.min(self.height - y), halo };
+                tiles.push(HaloTile { region, data: self.with_halo(&region, boundary) });
+            }
+        }
+
+        tiles
+    }
+
+    /// Returns a copy of the given region together with its border.
+    fn with_halo(&self, region: &TileRegion, boundary: T) -> Array2D<T> {
+        let (width, height) = region.padded_dimensions();
+        let mut result = Array2D::new(width, height, boundary);
+
+        for ty in 0..height {
+            for tx in 0..width {
+                // The position in this array, the border of the edge tiles is outside.
+                let x = (region.x + tx).checked_sub(region.halo).filter(|x| *x < self.width);
+                let y = (region.y + ty).checked_sub(region.halo).filter(|y| *y < self.height);
+
+                if let (Some(x), Some(y)) = (x, y) {
+                    result.set(tx, ty, self.get(x, y))
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Returns a copy without the border of `halo` cells on all four sides, for example the result of a node for a tile
+    /// from [`split_with_halo()`](Array2D::split_with_halo). The array must be larger than the two borders.
+    pub fn strip_halo(&self, halo: u64) -> Array2D<T> {
+        assert!(self.width > 2 * halo);
+        assert!(self.height > 2 * halo);
+
+        let mut result = Array2D::new(self.width - 2 * halo, self.height - 2 * halo, self.get(halo, halo));
+
+        for y in 0..result.height {
+            for x in 0..result.width {
+                result.set(x, y, self.get(x + halo, y + halo))
+            }
+        }
+
+        result
+    }
+
+    /// Puts the tiles from [`split_with_halo()`](Array2D::split_with_halo) together into a new array of the given dimension,
+    /// cells without a tile are set to `initial`. The data of a tile may still have its border (it's stripped here) or not.
+    /// Only the region of each tile is written and the borders are always dropped, so the order of the tiles doesn't matter.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::Array2DDimensionMismatch`] error if the data of a tile doesn't match its region
+    /// or the region is outside of the array.
+    pub fn stitch(width: u64, height: u64, initial: T, tiles: &[HaloTile<T>]) -> Result<Array2D<T>, NCError> {
+        let mut result = Array2D::new(width, height, initial);
+
+        for tile in tiles {
+            let region = &tile.region;
+
+            if region.x + region.width > width || region.y + region.height > height {
+                return Err(NCError::Array2DDimensionMismatch((width, height), (region.x + region.width, region.y + region.height)))
+            }
+
+            let dimensions = tile.data.dimensions();
+
+            if dimensions == (region.width, region.height) {
+                result.set_region(region.x, region.y, &tile.data);
+            } else if dimensions == region.padded_dimensions() {
+                result.set_region(region.x, region.y, &tile.data.strip_halo(region.halo));
+            } else {
+                return Err(NCError::Array2DDimensionMismatch((region.width, region.height), dimensions))
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Creates a new [`Array2D`] with the same dimension where every value is converted with the given function.
+    pub fn convert<U: Clone + Copy, F: Fn(&T) -> U>(&self, f: F) -> Array2D<U> {
+        Array2D { width: self.width, height: self.height, data: self.data.iter().map(f).collect() }
+    }
+
+    /// Returns all values as bytes, row by row, without copying them.
+    /// The bytes are in the byte order and the layout of this machine, to send them to another one use the
+    /// [`nc_compact`](crate::nc_compact) module.
+    #[cfg(feature = "bytemuck")]
+    pub fn as_bytes(&self) -> &[u8] where T: bytemuck::Pod {
+        bytemuck::cast_slice(&self.data)
+    }
+}
+
+/// Pixels with N interleaved channels, for example `[u8; 4]` for RGBA.
+impl<const N: usize> Array2D<[u8; N]> {
+    /// Creates a new [`Array2D`] from packed bytes (N bytes per value), row by row.
+    /// With the feature bytemuck the buffer is used as it is, otherwise the bytes are copied.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::Array2DDimensionMismatch`] error if the number of bytes isn't width * height * N or the dimension is 0.
+    pub fn from_raw_bytes(width: u64, height: u64, bytes: Vec<u8>) -> Result<Array2D<[u8; N]>, NCError> {
+        if N == 0 || width == 0 || height == 0 || bytes.len() as u64 != width * height * N as u64 {
+            return Err(NCError::Array2DDimensionMismatch((width * N as u64, height), (bytes.len() as u64, 1)))
+        }
+
+        // The capacity of the buffer must be a multiple of N as well, otherwise it can't be used as it is.
+        #[cfg(feature = "bytemuck")]
+        let data = bytemuck::allocation::try_cast_vec(bytes).unwrap_or_else(|(_, bytes)| Self::copy_pixels(&bytes));
+        #[cfg(not(feature = "bytemuck"))]
+        let data = Self::copy_pixels(&bytes);
+
+        Ok(Array2D { width, height, data })
+    }
+
+    /// Splits the bytes into pixels, the length must be a multiple of N.
+    fn copy_pixels(bytes: &[u8]) -> Vec<[u8; N]> {
+        bytes.chunks_exact(N).map(|pixel| {
+            let mut value = [0; N];
+            value.copy_from_slice(pixel);
+            value
+        }).collect()
+    }
+
+    /// Returns the values as packed bytes (N bytes per value), row by row.
+    /// With the feature bytemuck the buffer is used as it is, otherwise the bytes are copied.
+    pub fn into_raw_bytes(self) -> Vec<u8> {
+        #[cfg(feature = "bytemuck")]
+        return bytemuck::allocation::cast_vec(self.data);
+        #[cfg(not(feature = "bytemuck"))]
+        return self.data.concat();
+    }
+}
+
+/// Where a tile from [`Array2D::split_with_halo()`] belongs: the position and the dimension of the tile inside the whole array
+/// without the border and the width of the border.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TileRegion {
+    /// X position of the tile inside the whole array.
+    pub x: u64,
+    /// Y position of the tile inside the whole array.
+    pub y: u64,
+    /// Width of the tile without the border.
+    pub width: u64,
+    /// Height of the tile without the border.
+    pub height: u64,
+    /// Width of the border on each side.
+    pub halo: u64,
+}
+
+impl TileRegion {
+    /// Returns the dimension `(width, height)` of the tile including the border on both sides.
+    pub fn padded_dimensions(&self) -> (u64, u64) {
+        (self.width + 2 * self.halo, self.height + 2 * self.halo)
+    }
+}
+
+/// A tile of an [`Array2D`] with a border from its neighbors, see [`Array2D::split_with_halo()`].
+/// The region and the data (or the result of the node for the tile) can be put together again with [`Array2D::stitch()`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HaloTile<T> {
+    /// Where the tile belongs in the whole array.
+    pub region: TileRegion,
+    /// The data of the tile including the border.
+    pub data: Array2D<T>,
 }
 
 /// A data structure that can be split up into several chunks that are send to individual nodes to be processed.
@@ -147,6 +366,20 @@ impl<T: Clone + Copy> Array2DChunk<T> {
         }
     }
 
+    /// Returns a copy of the data for the given chunk id.
+    pub fn get_chunk(&self, chunk_id: u64) -> Array2D<T> {
+        let (x, y, width, height) = self.get_chunk_property(chunk_id);
+        let mut result = Array2D::new(width, height, self.array2d.get(x, y));
+
+        for cx in 0..width {
+            for cy in 0..height {
+                result.set(cx, cy, self.array2d.get(x + cx, y + cy))
+            }
+        }
+
+        result
+    }
+
     /// Returns the data at the given `(x, y)` position
     pub fn get(&self, x: u64, y: u64) -> T {
         self.array2d.get(x, y)
@@ -158,19 +391,23 @@ impl<T: Clone + Copy> Array2DChunk<T> {
     }
 }
 
-/// A Chunk of data can have three states.
-#[derive(Debug, Clone, PartialEq)]
-enum ChunkStatus {
+/// A Chunk of data can have five states.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ChunkStatus {
     /// 1. empty: no node has been assigned to this data.
     Empty,
-    /// 2. processing: at least one node is processing the data.
+    /// 2. assigned: the chunk has been assigned to a node but the data has not been sent yet.
+    Assigned,
+    /// 3. processing: at least one node is processing the data.
     Processing,
-    /// 3. finished: this piece of data has been processed successfully.
+    /// 4. finished: this piece of data has been processed successfully.
     Finished,
+    /// 5. failed: a node reported an error that can not be fixed by processing the data again.
+    Failed,
 }
 
 /// The actual data and some book keeping information.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Chunk<T> {
     /// The data itself.
     pub data: T,
@@ -178,12 +415,22 @@ pub struct Chunk<T> {
     pub node_id: NodeID,
     /// Has the data already been processed ? Is is not assigned yet ?
     status: ChunkStatus,
+    /// The phase of the job this chunk belongs to, see [`ChunkList::finish_phase()`].
+    phase: u32,
+    /// When the chunk has been assigned to the node, [`None`] if it's empty.
+    /// This is wall clock time, so that it still makes sense when a checkpoint is loaded (see [`NCCheckpoint`](crate::NCCheckpoint)).
+    assigned_at: Option<SystemTime>,
+    /// The metadata of the chunk, [`None`] if there is none.
+    meta: Option<ChunkMeta>,
+    /// Only nodes of this group get the chunk, [`None`] if every node can get it.
+    group: Option<String>,
 }
 
 impl<T> Chunk<T> {
     /// Sets the chunk status to empty.
     pub fn set_empty(&mut self) {
-        self.status = ChunkStatus::Empty
+        self.status = ChunkStatus::Empty;
+        self.assigned_at = None;
     }
 
     /// Checks if the chunk has been assigned yet or not.
@@ -195,11 +442,20 @@ impl<T> Chunk<T> {
     pub fn set_processing(&mut self, node_id: NodeID) {
         self.status = ChunkStatus::Processing;
         self.node_id = node_id;
+        self.assigned_at = Some(SystemTime::now());
     }
 
-    /// Checks if the chunk is currently being processed.
+    /// Sets the chunk status to assigned with the corresponding node id.
+    /// The data has not been sent to the node yet, see [`ChunkList::chunk_sent()`].
+    pub fn set_assigned(&mut self, node_id: NodeID) {
+        self.status = ChunkStatus::Assigned;
+        self.node_id = node_id;
+        self.assigned_at = Some(SystemTime::now());
+    }
+
+    /// Checks if the chunk is currently being processed (or at least assigned) by the given node.
     pub fn is_processing(&self, node_id: NodeID) -> bool {
-        self.status == ChunkStatus::Processing &&
+        (self.status == ChunkStatus::Processing || self.status == ChunkStatus::Assigned) &&
         self.node_id == node_id
     }
 
@@ -207,24 +463,84 @@ impl<T> Chunk<T> {
     pub fn set_finished(&mut self) {
         self.status = ChunkStatus::Finished;
     }
+
+    /// Returns the phase of the job this chunk belongs to.
+    pub fn phase(&self) -> u32 {
+        self.phase
+    }
+
+    /// Returns the current status of the chunk.
+    pub fn status(&self) -> ChunkStatus {
+        self.status
+    }
+
+    /// Returns the time when the chunk has been assigned to its node, [`None`] if it's empty.
+    /// The time is kept when the chunk is finished or has failed.
+    pub fn assigned_at(&self) -> Option<SystemTime> {
+        self.assigned_at
+    }
+
+    /// Returns the metadata of the chunk, [`None`] if there is none.
+    pub fn meta(&self) -> Option<&ChunkMeta> {
+        self.meta.as_ref()
+    }
+
+    /// Sets the metadata of the chunk, empty metadata is not stored (and not sent to the node).
+    pub fn set_meta(&mut self, meta: ChunkMeta) {
+        self.meta = if meta.is_empty() { None } else { Some(meta) };
+    }
+
+    /// Returns the group whose nodes get this chunk, [`None`] if every node can get it.
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    /// Sets the group whose nodes get this chunk, [`None`] lets every node get it.
+    pub fn set_group(&mut self, group: Option<String>) {
+        self.group = group;
+    }
+
+    /// The chunk is free, belongs to the given phase and can be given to a node of the given groups.
+    fn is_free_for(&self, phase: u32, tags: &[&str]) -> bool {
+        self.is_empty() && self.phase == phase && self.group.as_deref().is_none_or(|group| tags.contains(&group))
+    }
+
+    /// The chunk is done, either finished or failed permanently.
+    fn is_done(&self) -> bool {
+        self.status == ChunkStatus::Finished || self.status == ChunkStatus::Failed
+    }
+
+    /// Creates a chunk with the same book keeping information and the given data.
+    pub(crate) fn with_data<U>(&self, data: U) -> Chunk<U> {
+        Chunk { data, node_id: self.node_id, status: self.status, phase: self.phase,
+            assigned_at: self.assigned_at, meta: self.meta.clone(), group: self.group.clone() }
+    }
 }
 
 /// A list of chunks and some helper methods.
-#[derive(Debug, Clone)]
+/// Every chunk belongs to a phase of the job (default 0). Only chunks of the current phase are handed out,
+/// so all chunks of phase n must be done before phase n + 1 starts.
+/// The list can be saved to disk and loaded again, see [`NCCheckpoint`](crate::NCCheckpoint).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ChunkList<T> {
-    chunks: Vec<Chunk<T>>
+    chunks: Vec<Chunk<T>>,
+    /// Only chunks of this phase are handed out.
+    current_phase: u32,
 }
 
 impl<T> ChunkList<T> {
     /// Creates a new and empty chunk list.
     pub fn new() -> Self {
-        ChunkList{ chunks: Vec::new() }
+        ChunkList{ chunks: Vec::new(), current_phase: 0 }
     }
 
     /// Returns some statistics about the chunks in the list as a three tuple:
     /// 1. `empty`: how many chunks have not been assigned yet
-    /// 2. `processing`: how many chunks are assigned to nodes.
+    /// 2. `processing`: how many chunks are assigned to nodes (including chunks that have not been sent yet).
     /// 3. `finished`: how many chunks are done with processing.
+    ///
+    /// Chunks that have failed permanently are not counted, see [`failed_chunks()`](ChunkList::failed_chunks).
+    ///
     /// `(empty, processing, finished)`
     pub fn stats(&self) -> (u64, u64, u64) {
         let mut empty: u64 = 0;
@@ -234,18 +550,233 @@ impl<T> ChunkList<T> {
         for chunk in self.chunks.iter() {
             match chunk.status {
                 ChunkStatus::Empty => empty += 1,
-                ChunkStatus::Processing => processing += 1,
+                ChunkStatus::Assigned | ChunkStatus::Processing => processing += 1,
                 ChunkStatus::Finished => finished += 1,
+                ChunkStatus::Failed => (),
             }
         }
 
         (empty, processing, finished)
     }
 
-    /// If there is a free chunk in the list return the index and a mutable reference to it.
-    /// Else return [`None`] if all chunks are in processing or finished state.
+    /// Returns the number of chunks that are done (finished or failed permanently) and the total number of chunks,
+    /// for example for the NCServer trait method job_progress().
+    ///
+    /// `(done, total)`
+    pub fn progress(&self) -> (u64, u64) {
+        let done = self.chunks.iter().filter(|chunk| matches!(chunk.status, ChunkStatus::Finished | ChunkStatus::Failed)).count();
+        (done as u64, self.chunks.len() as u64)
+    }
+
+    /// If there is a free chunk of the current phase in the list return the index and a mutable reference to it.
+    /// Else return [`None`] if all chunks of the current phase are in processing or finished state.
+    /// Chunks for a group are skipped, see [`next_unassigned_for_group()`](ChunkList::next_unassigned_for_group).
     pub fn get_next_free_chunk(&mut self) -> Option<(usize, &mut Chunk<T>)> {
-        self.chunks.iter().position(|chunk| chunk.is_empty()).map(move |index| (index, &mut self.chunks[index]))
+        self.next_free_chunk_for(&[])
+    }
+
+    /// Same as [`get_next_free_chunk()`](ChunkList::get_next_free_chunk), but chunks for the given group are also returned.
+    pub fn next_unassigned_for_group(&mut self, tag: &str) -> Option<(usize, &mut Chunk<T>)> {
+        self.next_free_chunk_for(&[tag])
+    }
+
+    /// Returns the first free chunk of the current phase that is for one of the given groups or for every node.
+    fn next_free_chunk_for(&mut self, tags: &[&str]) -> Option<(usize, &mut Chunk<T>)> {
+        let current_phase = self.current_phase;
+
+        self.chunks.iter().position(|chunk| chunk.is_free_for(current_phase, tags))
+            .map(move |index| (index, &mut self.chunks[index]))
+    }
+
+    /// Returns the phase whose chunks are currently handed out.
+    pub fn current_phase(&self) -> u32 {
+        self.current_phase
+    }
+
+    /// Sets the phase whose chunks are handed out, this is used by the standby server (see the nc_replication module).
+    #[cfg(feature = "net")]
+    pub(crate) fn set_current_phase(&mut self, phase: u32) {
+        self.current_phase = phase;
+    }
+
+    /// Checks if all chunks of the current phase are done (finished or failed).
+    /// If yes the next phase starts and the number of the finished phase is returned, so that it can be given to the server
+    /// with [`ChunkAssignment::PhaseFinished`](crate::ChunkAssignment::PhaseFinished).
+    /// Returns [`None`] if chunks of the current phase are still being processed or if the current phase has no chunks at all.
+    pub fn finish_phase(&mut self) -> Option<u32> {
+        let current_phase = self.current_phase;
+        let mut chunks = self.chunks.iter().filter(|chunk| chunk.phase == current_phase).peekable();
+
+        if chunks.peek().is_some() && chunks.all(|chunk| chunk.is_done()) {
+            self.current_phase += 1;
+            Some(current_phase)
+        } else {
+            None
+        }
+    }
+
+    /// Assigns the next free chunk to the given node and returns its id and a mutable reference to it.
+    /// The chunk is not handed out again until [`chunk_send_failed()`](ChunkList::chunk_send_failed) is called for it.
+    /// Returns [`None`] if all chunks are assigned, in processing or finished state.
+    pub fn assign_next_chunk(&mut self, node_id: NodeID) -> Option<(ChunkID, &mut Chunk<T>)> {
+        self.get_next_free_chunk().map(|(index, chunk)| {
+            chunk.set_assigned(node_id);
+            (index as ChunkID, chunk)
+        })
+    }
+
+    /// Same as [`assign_next_chunk()`](ChunkList::assign_next_chunk), but the chunk may also be for one of the groups of the node.
+    /// Use this with the tags of the node, see [`NCAssignContext::tags()`](crate::NCAssignContext::tags).
+    pub fn assign_next_chunk_for_groups(&mut self, node_id: NodeID, tags: &[String]) -> Option<(ChunkID, &mut Chunk<T>)> {
+        let tags: Vec<&str> = tags.iter().map(String::as_str).collect();
+
+        self.next_free_chunk_for(&tags).map(|(index, chunk)| {
+            chunk.set_assigned(node_id);
+            (index as ChunkID, chunk)
+        })
+    }
+
+    /// Same as [`assign_next_chunk_for_groups()`](ChunkList::assign_next_chunk_for_groups), but with the shard tags of the chunks
+    /// from the given locality map: the first free chunk whose shard the node has, otherwise the first free chunk that has waited
+    /// long enough for a node with its shard (see the [`nc_locality`](crate::nc_locality) module).
+    /// Use this with [`NCAssignContext::tags()`](crate::NCAssignContext::tags) and [`NCAssignContext::locality()`](crate::NCAssignContext::locality),
+    /// without a locality map it's the same as assign_next_chunk_for_groups().
+    pub fn assign_next_chunk_with_locality(&mut self, node_id: NodeID, tags: &[String], locality: Option<&LocalityMap>) -> Option<(ChunkID, &mut Chunk<T>)> {
+        let locality = match locality {
+            Some(locality) => locality,
+            None => return self.assign_next_chunk_for_groups(node_id, tags),
+        };
+
+        let current_phase = self.current_phase;
+        let group_tags: Vec<&str> = tags.iter().map(String::as_str).collect();
+        let free: Vec<ChunkID> = self.chunks.iter().enumerate()
+            .filter(|(_, chunk)| chunk.is_free_for(current_phase, &group_tags))
+            .map(|(index, _)| index as ChunkID)
+            .collect();
+
+        let chunk_id = match free.iter().find(|chunk_id| locality.is_local(**chunk_id, tags)) {
+            Some(chunk_id) => *chunk_id,
+            None => locality.first_fallback(free.into_iter())?,
+        };
+
+        locality.chunk_assigned(chunk_id);
+        let chunk = &mut self.chunks[chunk_id as usize];
+        chunk.set_assigned(node_id);
+        Some((chunk_id, chunk))
+    }
+
+    /// Assigns the given chunk to the given node again, the server sends the cached data for it
+    /// (see cache_chunk_payloads in the [`NCConfiguration`](crate::NCConfiguration)).
+    /// Returns false if the chunk is not free, for example because a late result has finished it in the meantime.
+    pub fn reassign_chunk(&mut self, chunk_id: ChunkID, node_id: NodeID) -> bool {
+        match self.chunk_mut(chunk_id) {
+            Some(chunk) if chunk.is_empty() => {
+                chunk.set_assigned(node_id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The aggregator (to) has taken over the given chunk from the node (from), see the [`nc_aggregator`](crate::nc_aggregator) module.
+    /// From now on the chunk belongs to the aggregator, so a heartbeat timeout of the aggregator gives it to a node again.
+    /// Returns false if the chunk is not processed by the node (anymore).
+    pub fn delegate_chunk(&mut self, chunk_id: ChunkID, from: NodeID, to: NodeID) -> bool {
+        match self.chunk_mut(chunk_id) {
+            Some(chunk) if chunk.is_processing(from) => {
+                chunk.node_id = to;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the chunk with the given id, [`None`] if there is no such chunk (for example a stale chunk id from a node).
+    fn chunk_mut(&mut self, chunk_id: ChunkID) -> Option<&mut Chunk<T>> {
+        usize::try_from(chunk_id).ok().and_then(move |index| self.chunks.get_mut(index))
+    }
+
+    /// The data for the given chunk has been sent to the node successfully.
+    /// This and the following methods ignore unknown chunk ids.
+    pub fn chunk_sent(&mut self, chunk_id: ChunkID) {
+        if let Some(chunk) = self.chunk_mut(chunk_id) {
+            if chunk.status == ChunkStatus::Assigned {
+                chunk.status = ChunkStatus::Processing
+            }
+        }
+    }
+
+    /// The data for the given chunk could not be sent to the node, so the chunk is returned to the pool of free chunks.
+    pub fn chunk_send_failed(&mut self, chunk_id: ChunkID) {
+        if let Some(chunk) = self.chunk_mut(chunk_id) {
+            if chunk.status == ChunkStatus::Assigned || chunk.status == ChunkStatus::Processing {
+                chunk.set_empty()
+            }
+        }
+    }
+
+    /// The given node could not process its chunk (see [`NCJobError`](crate::NCJobError)).
+    /// If the error is retryable the chunk is returned to the pool of free chunks, otherwise it's marked as failed
+    /// and will not be handed out again.
+    pub fn chunk_failed(&mut self, node_id: NodeID, retryable: bool) {
+        for chunk in self.chunks.iter_mut() {
+            if chunk.is_processing(node_id) {
+                if retryable {
+                    chunk.set_empty()
+                } else {
+                    chunk.status = ChunkStatus::Failed
+                }
+            }
+        }
+    }
+
+    /// The node didn't produce any data for the given chunk (see [`NodeResult::Empty`](crate::NodeResult::Empty)),
+    /// so the chunk is marked as finished.
+    pub fn chunk_empty(&mut self, chunk_id: ChunkID) {
+        if let Some(chunk) = self.chunk_mut(chunk_id) {
+            chunk.set_finished()
+        }
+    }
+
+    /// The node has skipped the given chunk (see [`NodeResult::Skip`](crate::NodeResult::Skip)),
+    /// so the chunk is returned to the pool of free chunks.
+    pub fn chunk_skipped(&mut self, chunk_id: ChunkID) {
+        if let Some(chunk) = self.chunk_mut(chunk_id) {
+            chunk.set_empty()
+        }
+    }
+
+    /// The given chunk has been revoked (see [`NCServerHandle::cancel_chunk()`](crate::NCServerHandle::cancel_chunk)),
+    /// so the chunk is marked as finished without a result.
+    pub fn chunk_revoked(&mut self, chunk_id: ChunkID) {
+        if let Some(chunk) = self.chunk_mut(chunk_id) {
+            chunk.set_finished()
+        }
+    }
+
+    /// The given chunk is not part of the sample of a sampled run (see the [`nc_sample`](crate::nc_sample) module),
+    /// so the chunk is marked as finished without a result.
+    pub fn chunk_left_out(&mut self, chunk_id: ChunkID) {
+        if let Some(chunk) = self.chunk_mut(chunk_id) {
+            chunk.set_finished()
+        }
+    }
+
+    /// The server could not process the result for the given chunk (see [`OnProcessError`](crate::OnProcessError)).
+    /// If requeue is true the chunk is returned to the pool of free chunks, otherwise it's marked as failed.
+    pub fn chunk_rejected(&mut self, chunk_id: ChunkID, requeue: bool) {
+        if let Some(chunk) = self.chunk_mut(chunk_id) {
+            if requeue {
+                chunk.set_empty()
+            } else {
+                chunk.status = ChunkStatus::Failed
+            }
+        }
+    }
+
+    /// Returns the ids of all the chunks that have failed permanently.
+    pub fn failed_chunks(&self) -> impl Iterator<Item=ChunkID> + '_ {
+        self.chunks.iter().enumerate().filter(|(_, chunk)| chunk.status == ChunkStatus::Failed).map(|(index, _)| index as ChunkID)
     }
 
     /// Returns a mutable reference to the chunk at the given index.
@@ -253,6 +784,45 @@ impl<T> ChunkList<T> {
         &mut self.chunks[index]
     }
 
+    /// Returns all the chunks, the index is the chunk id.
+    pub fn chunks(&self) -> &[Chunk<T>] {
+        &self.chunks
+    }
+
+    /// Returns all the chunks that are assigned to a node or in processing since at least the given time,
+    /// for example to find chunks that look stuck.
+    pub fn assigned_before(&self, time: SystemTime) -> impl Iterator<Item=(ChunkID, &Chunk<T>)> + '_ {
+        self.chunks.iter().enumerate()
+            .filter(move |(_, chunk)| {
+                (chunk.status == ChunkStatus::Assigned || chunk.status == ChunkStatus::Processing) &&
+                chunk.assigned_at.is_some_and(|assigned_at| assigned_at <= time)
+            })
+            .map(|(index, chunk)| (index as ChunkID, chunk))
+    }
+
+    /// All the chunks that are assigned to a node or in processing are returned to the pool of free chunks.
+    /// This is needed when the list is restored after a restart of the server, since the nodes don't know about it anymore.
+    pub fn reset_unfinished(&mut self) {
+        self.reset_unfinished_except(&[])
+    }
+
+    /// Like [`reset_unfinished()`](ChunkList::reset_unfinished), but the given chunks keep their state.
+    /// These are the chunks of offline batches, they are still being processed somewhere else.
+    pub fn reset_unfinished_except(&mut self, keep: &[ChunkID]) {
+        for (chunk_id, chunk) in self.chunks.iter_mut().enumerate() {
+            if (chunk.status == ChunkStatus::Assigned || chunk.status == ChunkStatus::Processing) && !keep.contains(&(chunk_id as ChunkID)) {
+                chunk.set_empty()
+            }
+        }
+    }
+
+    /// Creates a new list with the same book keeping information and the data converted with the given function.
+    pub fn try_map<U, E, F: FnMut(&T) -> Result<U, E>>(&self, mut f: F) -> Result<ChunkList<U>, E> {
+        let chunks = self.chunks.iter().map(|chunk| Ok(chunk.with_data(f(&chunk.data)?))).collect::<Result<Vec<_>, E>>()?;
+
+        Ok(ChunkList { chunks, current_phase: self.current_phase })
+    }
+
     /// Some nodes may have crashed or lost the network connection.
     /// Sets all the chunks that these nodes have been processing to the empty state.
     pub fn heartbeat_timeout(&mut self, nodes: &[NodeID]) {
@@ -267,22 +837,367 @@ impl<T> ChunkList<T> {
 
     /// Adds a new chunk with the given data to the list of chunks.
     pub fn push(&mut self, data: T) {
-        self.chunks.push(Chunk{ data, node_id: NodeID::random(), status: ChunkStatus::Empty});
+        self.push_phase(data, 0)
+    }
+
+    /// Adds a new chunk with the given data to the list of chunks, it will be handed out in the given phase.
+    pub fn push_phase(&mut self, data: T, phase: u32) {
+        self.chunks.push(Chunk{ data, node_id: NodeID::random(), status: ChunkStatus::Empty, phase, assigned_at: None, meta: None, group: None });
+    }
+
+    /// Adds a new chunk with the given data to the list of chunks, it's only given to nodes of the group with the given tag.
+    pub fn push_for_group(&mut self, data: T, tag: &str) {
+        self.push(data);
+        self.chunks.last_mut().unwrap().set_group(Some(tag.to_string()));
+    }
+
+    /// Adds a new chunk with the given data and metadata to the list of chunks.
+    /// The NCServer trait method chunk_meta() can then return it with [`chunk_meta()`](ChunkList::chunk_meta).
+    pub fn push_with_meta(&mut self, data: T, meta: ChunkMeta) {
+        self.push_phase_with_meta(data, 0, meta)
+    }
+
+    /// Adds a new chunk with the given data and metadata to the list of chunks, it will be handed out in the given phase.
+    pub fn push_phase_with_meta(&mut self, data: T, phase: u32, meta: ChunkMeta) {
+        self.push_phase(data, phase);
+        self.chunks.last_mut().unwrap().set_meta(meta);
+    }
+
+    /// Returns a copy of the metadata of the given chunk, this is what the NCServer trait method chunk_meta() usually returns.
+    pub fn chunk_meta(&self, chunk_id: ChunkID) -> Option<ChunkMeta> {
+        self.chunks.get(chunk_id as usize).and_then(|chunk| chunk.meta.clone())
+    }
+
+    /// Creates a list of total chunks (ids 0..total) whose data is created by the generator when it's needed, see [`LazyChunkList`].
+    pub fn from_generator<F: FnMut(ChunkID) -> T>(total: u64, generator: F) -> LazyChunkList<T, F> {
+        LazyChunkList::new(total, generator)
     }
 }
 
 impl ChunkList<ChunkData> {
     pub fn initialize<T: Clone + Copy>(&mut self, array2d_chunk: &Array2DChunk<T>) {
+        self.initialize_phase(array2d_chunk, 0)
+    }
+
+    /// Adds one chunk for every chunk in the given Array2DChunk, they will be handed out in the given phase.
+    pub fn initialize_phase<T: Clone + Copy>(&mut self, array2d_chunk: &Array2DChunk<T>, phase: u32) {
         for i in 0..array2d_chunk.num_of_chunks() {
             let (x, y, width, height) = array2d_chunk.get_chunk_property(i);
 
-            self.push(ChunkData { x, y, width, height });
+            self.push_phase(ChunkData { x, y, width, height }, phase);
+        }
+    }
+}
+
+impl<T: Serialize> ChunkList<T> {
+    /// Same as [`assign_next_chunk()`](ChunkList::assign_next_chunk), but if max_bytes is given the first free chunk whose data fits
+    /// (serialized size) is assigned. If none of the free chunks fits the smallest one is assigned, since max_bytes is just a hint.
+    /// Use this with the work hint of the node, see [`NCAssignContext::max_bytes()`](crate::NCAssignContext::max_bytes).
+    pub fn assign_next_chunk_fitting(&mut self, node_id: NodeID, max_bytes: Option<u64>) -> Option<(ChunkID, &mut Chunk<T>)> {
+        let max_bytes = match max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return self.assign_next_chunk(node_id),
+        };
+
+        let current_phase = self.current_phase;
+        let free_chunks = self.chunks.iter().enumerate().filter(|(_, chunk)| chunk.is_free_for(current_phase, &[]))
+            .map(|(index, chunk)| (index, bincode::serialized_size(&chunk.data).unwrap_or(u64::MAX)));
+
+        let mut smallest: Option<(usize, u64)> = None;
+        let mut fitting = None;
+
+        for (index, size) in free_chunks {
+            if size <= max_bytes {
+                fitting = Some(index);
+                break
+            }
+
+            if smallest.is_none_or(|(_, smallest_size)| size < smallest_size) {
+                smallest = Some((index, size));
+            }
+        }
+
+        fitting.or(smallest.map(|(index, _)| index)).map(move |index| {
+            let chunk = &mut self.chunks[index];
+            chunk.set_assigned(node_id);
+            (index as ChunkID, chunk)
+        })
+    }
+}
+
+impl<T> Default for ChunkList<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A chunk that has been assigned to a node, see [`LazyChunkList`].
+#[derive(Debug, Clone, Copy)]
+struct InFlight {
+    /// ID of the node that is processing the chunk.
+    node_id: NodeID,
+    /// ChunkStatus::Assigned or ChunkStatus::Processing.
+    status: ChunkStatus,
+    /// When the chunk has been assigned to the node.
+    assigned_at: SystemTime,
+}
+
+/// A list of chunks for jobs with millions of chunks, created with [`ChunkList::from_generator()`].
+/// The data of a chunk is not stored, the generator creates it from the chunk id when the chunk is assigned and every time
+/// it's needed again (for example when the chunk is given to another node after a heartbeat timeout), so it must return the same data
+/// for the same id. Only the chunks that are in flight, back in the pool or failed are kept in maps and sets,
+/// the finished chunks are one bit each. Chunks are handed out by id, the ones that are back in the pool first.
+///
+/// Unlike ChunkList there are no phases, groups or metadata and the list can't be saved in a checkpoint.
+pub struct LazyChunkList<T, F> {
+    /// Creates the data of the chunk with the given id.
+    generator: F,
+    /// The number of chunks, the ids are 0..total.
+    total: u64,
+    /// The chunks from this id on have never been handed out.
+    next_chunk: ChunkID,
+    /// Chunks that have been handed out before and are free again.
+    free: BTreeSet<ChunkID>,
+    /// Chunks that are assigned to a node or in processing.
+    in_flight: BTreeMap<ChunkID, InFlight>,
+    /// Chunks that have failed permanently.
+    failed: BTreeSet<ChunkID>,
+    /// One bit for every finished chunk.
+    finished: Vec<u64>,
+    /// Number of bits that are set in finished.
+    num_finished: u64,
+    data: PhantomData<fn() -> T>,
+}
+
+impl<T, F: FnMut(ChunkID) -> T> LazyChunkList<T, F> {
+    /// Creates a list of total chunks, see [`ChunkList::from_generator()`].
+    pub fn new(total: u64, generator: F) -> Self {
+        LazyChunkList { generator, total, next_chunk: 0, free: BTreeSet::new(), in_flight: BTreeMap::new(), failed: BTreeSet::new(),
+            finished: Vec::new(), num_finished: 0, data: PhantomData }
+    }
+
+    /// Returns the number of chunks.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Creates the data of the given chunk again, [`None`] if there is no chunk with this id.
+    pub fn data(&mut self, chunk_id: ChunkID) -> Option<T> {
+        if chunk_id < self.total {
+            Some((self.generator)(chunk_id))
+        } else {
+            None
+        }
+    }
+
+    /// Assigns the next free chunk to the given node and returns its id and its data.
+    /// The chunk is not handed out again until [`chunk_send_failed()`](LazyChunkList::chunk_send_failed) is called for it.
+    /// Returns [`None`] if all chunks are assigned, in processing or done.
+    pub fn assign_next_chunk(&mut self, node_id: NodeID) -> Option<(ChunkID, T)> {
+        let chunk_id = match self.free.pop_first() {
+            Some(chunk_id) => chunk_id,
+            None => {
+                // Chunks can be finished or failed before they are handed out
+                while self.next_chunk < self.total && self.status(self.next_chunk) != ChunkStatus::Empty {
+                    self.next_chunk += 1;
+                }
+
+                if self.next_chunk == self.total {
+                    return None
+                }
+
+                self.next_chunk += 1;
+                self.next_chunk - 1
+            }
+        };
+
+        self.in_flight.insert(chunk_id, InFlight { node_id, status: ChunkStatus::Assigned, assigned_at: SystemTime::now() });
+        Some((chunk_id, (self.generator)(chunk_id)))
+    }
+
+    /// Returns the current status of the given chunk.
+    pub fn status(&self, chunk_id: ChunkID) -> ChunkStatus {
+        if let Some(in_flight) = self.in_flight.get(&chunk_id) {
+            in_flight.status
+        } else if self.is_finished(chunk_id) {
+            ChunkStatus::Finished
+        } else if self.failed.contains(&chunk_id) {
+            ChunkStatus::Failed
+        } else {
+            ChunkStatus::Empty
+        }
+    }
+
+    /// Checks if the given chunk is currently being processed (or at least assigned) by the given node.
+    pub fn is_processing(&self, chunk_id: ChunkID, node_id: NodeID) -> bool {
+        self.in_flight.get(&chunk_id).is_some_and(|in_flight| in_flight.node_id == node_id)
+    }
+
+    /// Checks if the given chunk is finished.
+    pub fn is_finished(&self, chunk_id: ChunkID) -> bool {
+        self.finished.get((chunk_id / 64) as usize).is_some_and(|bits| bits & (1 << (chunk_id % 64)) != 0)
+    }
+
+    /// Marks the given chunk as finished. Unknown chunk ids (not less than the total) are ignored, like in ChunkList.
+    pub fn set_finished(&mut self, chunk_id: ChunkID) {
+        if chunk_id >= self.total {
+            return
+        }
+
+        self.take(chunk_id);
+        let word = (chunk_id / 64) as usize;
+
+        if word >= self.finished.len() {
+            // Grows like a Vec but never beyond one bit per chunk
+            let capacity = (self.finished.len() * 2).min(self.total.div_ceil(64) as usize).max(word + 1);
+            self.finished.reserve_exact(capacity - self.finished.len());
+            self.finished.resize(word + 1, 0);
+        }
+
+        self.finished[word] |= 1 << (chunk_id % 64);
+        self.num_finished += 1;
+    }
+
+    /// The data for the given chunk has been sent to the node successfully.
+    pub fn chunk_sent(&mut self, chunk_id: ChunkID) {
+        if let Some(in_flight) = self.in_flight.get_mut(&chunk_id) {
+            in_flight.status = ChunkStatus::Processing;
+        }
+    }
+
+    /// The data for the given chunk could not be sent to the node, so the chunk is returned to the pool of free chunks.
+    pub fn chunk_send_failed(&mut self, chunk_id: ChunkID) {
+        if self.in_flight.contains_key(&chunk_id) {
+            self.set_free(chunk_id);
+        }
+    }
+
+    /// The given node could not process its chunk (see [`NCJobError`](crate::NCJobError)).
+    /// If the error is retryable the chunk is returned to the pool of free chunks, otherwise it's marked as failed
+    /// and will not be handed out again.
+    pub fn chunk_failed(&mut self, node_id: NodeID, retryable: bool) {
+        for chunk_id in self.chunks_of(&[node_id]) {
+            if retryable {
+                self.set_free(chunk_id)
+            } else {
+                self.set_failed(chunk_id)
+            }
+        }
+    }
+
+    /// The node didn't produce any data for the given chunk (see [`NodeResult::Empty`](crate::NodeResult::Empty)),
+    /// so the chunk is marked as finished.
+    pub fn chunk_empty(&mut self, chunk_id: ChunkID) {
+        self.set_finished(chunk_id)
+    }
+
+    /// The node has skipped the given chunk (see [`NodeResult::Skip`](crate::NodeResult::Skip)),
+    /// so the chunk is returned to the pool of free chunks.
+    pub fn chunk_skipped(&mut self, chunk_id: ChunkID) {
+        self.set_free(chunk_id)
+    }
+
+    /// The given chunk has been revoked (see [`NCServerHandle::cancel_chunk()`](crate::NCServerHandle::cancel_chunk)),
+    /// so the chunk is marked as finished without a result.
+    pub fn chunk_revoked(&mut self, chunk_id: ChunkID) {
+        self.set_finished(chunk_id)
+    }
+
+    /// The given chunk is not part of the sample of a sampled run (see the [`nc_sample`](crate::nc_sample) module),
+    /// so the chunk is marked as finished without a result.
+    pub fn chunk_left_out(&mut self, chunk_id: ChunkID) {
+        self.set_finished(chunk_id)
+    }
+
+    /// The server could not process the result for the given chunk (see [`OnProcessError`](crate::OnProcessError)).
+    /// If requeue is true the chunk is returned to the pool of free chunks, otherwise it's marked as failed.
+    pub fn chunk_rejected(&mut self, chunk_id: ChunkID, requeue: bool) {
+        if requeue {
+            self.set_free(chunk_id)
+        } else {
+            self.set_failed(chunk_id)
+        }
+    }
+
+    /// Some nodes may have crashed or lost the network connection.
+    /// The chunks that these nodes have been processing are returned to the pool of free chunks.
+    pub fn heartbeat_timeout(&mut self, nodes: &[NodeID]) {
+        for chunk_id in self.chunks_of(nodes) {
+            self.set_free(chunk_id)
+        }
+    }
+
+    /// Returns all the chunks that are assigned to a node or in processing since at least the given time,
+    /// for example to find chunks that look stuck.
+    pub fn assigned_before(&self, time: SystemTime) -> impl Iterator<Item=ChunkID> + '_ {
+        self.in_flight.iter().filter(move |(_, in_flight)| in_flight.assigned_at <= time).map(|(chunk_id, _)| *chunk_id)
+    }
+
+    /// Returns the ids of all the chunks that have failed permanently.
+    pub fn failed_chunks(&self) -> impl Iterator<Item=ChunkID> + '_ {
+        self.failed.iter().copied()
+    }
+
+    /// Returns some statistics about the chunks, like [`ChunkList::stats()`]: `(empty, processing, finished)`.
+    /// Chunks that have failed permanently are not counted.
+    pub fn stats(&self) -> (u64, u64, u64) {
+        let processing = self.in_flight.len() as u64;
+        let empty = self.total - processing - self.num_finished - self.failed.len() as u64;
+
+        (empty, processing, self.num_finished)
+    }
+
+    /// Returns the number of chunks that are done (finished or failed permanently) and the total number of chunks,
+    /// for example for the NCServer trait method job_progress().
+    ///
+    /// `(done, total)`
+    pub fn progress(&self) -> (u64, u64) {
+        (self.num_finished + self.failed.len() as u64, self.total)
+    }
+
+    /// An estimate of the memory that the book keeping uses in bytes: the chunks in flight, the chunks that are back in the pool
+    /// or failed and one bit for every chunk up to the last finished one.
+    pub fn heap_bytes(&self) -> usize {
+        self.in_flight.len() * size_of::<(ChunkID, InFlight)>() + (self.free.len() + self.failed.len()) * size_of::<ChunkID>() +
+            self.finished.capacity() * size_of::<u64>()
+    }
+
+    /// The chunks in flight of the given nodes.
+    fn chunks_of(&self, nodes: &[NodeID]) -> Vec<ChunkID> {
+        self.in_flight.iter().filter(|(_, in_flight)| nodes.contains(&in_flight.node_id)).map(|(chunk_id, _)| *chunk_id).collect()
+    }
+
+    /// Removes the given chunk from all the states.
+    fn take(&mut self, chunk_id: ChunkID) {
+        self.in_flight.remove(&chunk_id);
+        self.free.remove(&chunk_id);
+        self.failed.remove(&chunk_id);
+
+        if self.is_finished(chunk_id) {
+            self.finished[(chunk_id / 64) as usize] &= !(1 << (chunk_id % 64));
+            self.num_finished -= 1;
+        }
+    }
+
+    /// Returns the given chunk to the pool of free chunks, the chunks that have never been handed out are free already.
+    fn set_free(&mut self, chunk_id: ChunkID) {
+        if chunk_id < self.next_chunk {
+            self.take(chunk_id);
+            self.free.insert(chunk_id);
+        }
+    }
+
+    /// Marks the given chunk as failed permanently.
+    fn set_failed(&mut self, chunk_id: ChunkID) {
+        if chunk_id < self.total {
+            self.take(chunk_id);
+            self.failed.insert(chunk_id);
         }
     }
 }
 
 /// This is the data that is stored in the chunks list.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChunkData {
     /// X position of the chunk inside the Array2D = x position in the final image.
     pub x: u64,
@@ -383,6 +1298,188 @@ mod tests {
         assert_eq!(a2d.get(1, 1), 8765);
     }
 
+    #[test]
+    fn test_chunk_list_assign_and_sent() {
+        let mut chunk_list = ChunkList::new();
+        let node_id = NodeID::random();
+
+        chunk_list.push(1);
+        chunk_list.push(2);
+
+        let (chunk_id, chunk) = chunk_list.assign_next_chunk(node_id).unwrap();
+        assert_eq!(chunk_id, 0);
+        assert_eq!(chunk.data, 1);
+        assert_eq!(chunk_list.stats(), (1, 1, 0));
+
+        chunk_list.chunk_sent(chunk_id);
+        assert!(chunk_list.get(0).is_processing(node_id));
+        assert_eq!(chunk_list.stats(), (1, 1, 0));
+
+        let (chunk_id, _) = chunk_list.assign_next_chunk(node_id).unwrap();
+        assert_eq!(chunk_id, 1);
+        assert!(chunk_list.assign_next_chunk(node_id).is_none());
+    }
+
+    #[test]
+    fn test_chunk_list_send_failed() {
+        let mut chunk_list = ChunkList::new();
+        let node_id1 = NodeID::random();
+        let node_id2 = NodeID::random();
+
+        chunk_list.push(1);
+
+        let (chunk_id, _) = chunk_list.assign_next_chunk(node_id1).unwrap();
+        assert!(chunk_list.assign_next_chunk(node_id2).is_none());
+
+        chunk_list.chunk_send_failed(chunk_id);
+        assert_eq!(chunk_list.stats(), (1, 0, 0));
+
+        let (chunk_id, _) = chunk_list.assign_next_chunk(node_id2).unwrap();
+        assert_eq!(chunk_id, 0);
+        assert!(chunk_list.get(0).is_processing(node_id2));
+        assert!(!chunk_list.get(0).is_processing(node_id1));
+    }
+
+    #[test]
+    fn test_chunk_list_unknown_chunk() {
+        let mut chunk_list = ChunkList::new();
+        let node_id = NodeID::random();
+
+        chunk_list.push(1);
+        chunk_list.assign_next_chunk(node_id).unwrap();
+
+        // A stale or made up chunk id from a node is ignored
+        for chunk_id in [1, 1000, u64::MAX] {
+            chunk_list.chunk_sent(chunk_id);
+            chunk_list.chunk_send_failed(chunk_id);
+            chunk_list.chunk_empty(chunk_id);
+            chunk_list.chunk_skipped(chunk_id);
+            chunk_list.chunk_revoked(chunk_id);
+            chunk_list.chunk_left_out(chunk_id);
+            chunk_list.chunk_rejected(chunk_id, false);
+            assert!(!chunk_list.reassign_chunk(chunk_id, node_id));
+            assert!(!chunk_list.delegate_chunk(chunk_id, node_id, NodeID::random()));
+        }
+
+        assert!(chunk_list.get(0).is_processing(node_id));
+        assert_eq!(chunk_list.stats(), (0, 1, 0));
+    }
+
+    #[test]
+    fn test_chunk_list_send_failed_finished() {
+        let mut chunk_list = ChunkList::new();
+
+        chunk_list.push(1);
+
+        let (chunk_id, _) = chunk_list.assign_next_chunk(NodeID::random()).unwrap();
+        chunk_list.chunk_sent(chunk_id);
+        chunk_list.get(0).set_finished();
+        chunk_list.chunk_send_failed(chunk_id);
+
+        assert_eq!(chunk_list.stats(), (0, 0, 1));
+    }
+
+    #[test]
+    fn test_chunk_list_delegate() {
+        let mut chunk_list = ChunkList::new();
+        let worker = NodeID::random();
+        let aggregator = NodeID::random();
+
+        chunk_list.push(1);
+
+        let (chunk_id, _) = chunk_list.assign_next_chunk(worker).unwrap();
+        chunk_list.chunk_sent(chunk_id);
+        assert!(!chunk_list.delegate_chunk(chunk_id, aggregator, worker));
+        assert!(chunk_list.delegate_chunk(chunk_id, worker, aggregator));
+        assert!(chunk_list.get(0).is_processing(aggregator));
+
+        chunk_list.heartbeat_timeout(&[aggregator]);
+        assert!(chunk_list.get(0).is_empty());
+    }
+
+    #[test]
+    fn test_chunk_list_reassign() {
+        let mut chunk_list = ChunkList::new();
+        let node_id1 = NodeID::random();
+        let node_id2 = NodeID::random();
+
+        chunk_list.push(1);
+        chunk_list.push(2);
+
+        let (chunk_id, _) = chunk_list.assign_next_chunk(node_id1).unwrap();
+        chunk_list.chunk_sent(chunk_id);
+        assert!(!chunk_list.reassign_chunk(chunk_id, node_id2));
+
+        chunk_list.heartbeat_timeout(&[node_id1]);
+        assert!(chunk_list.reassign_chunk(chunk_id, node_id2));
+        assert!(chunk_list.get(0).is_processing(node_id2));
+
+        let (chunk_id, _) = chunk_list.assign_next_chunk(node_id1).unwrap();
+        assert_eq!(chunk_id, 1);
+    }
+
+    #[test]
+    fn test_chunk_list_phases() {
+        let mut chunk_list = ChunkList::new();
+        let node_id = NodeID::random();
+
+        chunk_list.push(1);
+        chunk_list.push_phase(2, 1);
+        chunk_list.push(3);
+
+        let (chunk_id1, _) = chunk_list.assign_next_chunk(node_id).unwrap();
+        let (chunk_id2, _) = chunk_list.assign_next_chunk(node_id).unwrap();
+        assert_eq!((chunk_id1, chunk_id2), (0, 2));
+
+        // Barrier: phase 1 doesn't start until phase 0 is done
+        assert!(chunk_list.assign_next_chunk(node_id).is_none());
+        assert_eq!(chunk_list.finish_phase(), None);
+
+        chunk_list.get(0).set_finished();
+        assert_eq!(chunk_list.finish_phase(), None);
+
+        chunk_list.chunk_failed(node_id, false);
+        assert_eq!(chunk_list.finish_phase(), Some(0));
+        assert_eq!(chunk_list.current_phase(), 1);
+
+        let (chunk_id, chunk) = chunk_list.assign_next_chunk(node_id).unwrap();
+        assert_eq!((chunk_id, chunk.data, chunk.phase()), (1, 2, 1));
+
+        chunk_list.get(1).set_finished();
+        assert_eq!(chunk_list.finish_phase(), Some(1));
+
+        // Phase 2 has no chunks
+        assert_eq!(chunk_list.finish_phase(), None);
+        assert_eq!(chunk_list.current_phase(), 2);
+    }
+
+    #[test]
+    fn test_chunk_list_chunk_failed() {
+        let mut chunk_list = ChunkList::new();
+        let node_id1 = NodeID::random();
+        let node_id2 = NodeID::random();
+
+        chunk_list.push(1);
+        chunk_list.push(2);
+
+        let (chunk_id1, _) = chunk_list.assign_next_chunk(node_id1).unwrap();
+        chunk_list.chunk_sent(chunk_id1);
+        let (chunk_id2, _) = chunk_list.assign_next_chunk(node_id2).unwrap();
+        chunk_list.chunk_sent(chunk_id2);
+
+        chunk_list.chunk_failed(node_id1, true);
+        assert_eq!(chunk_list.stats(), (1, 1, 0));
+
+        chunk_list.chunk_failed(node_id2, false);
+        assert_eq!(chunk_list.stats(), (1, 0, 0));
+        assert_eq!(chunk_list.failed_chunks().collect::<Vec<ChunkID>>(), vec![chunk_id2]);
+
+        // The failed chunk is not handed out again
+        let (chunk_id, _) = chunk_list.assign_next_chunk(node_id2).unwrap();
+        assert_eq!(chunk_id, chunk_id1);
+        assert!(chunk_list.assign_next_chunk(node_id2).is_none());
+    }
+
     #[test]
     fn test_a2d_chunk_new1() {
         let a2d_chunk = Array2DChunk::new(100, 100, 20, 20, 0);
@@ -522,4 +1619,334 @@ mod tests {
         assert_eq!(a2d_chunk.get(3, 2), 456456);
         assert_eq!(a2d_chunk.get(3, 3), 456456);
     }
+
+    #[test]
+    fn test_a2d_chunk_get_chunk() {
+        let mut a2d_chunk = Array2DChunk::new(5, 5, 2, 2, 0);
+        let mut a2d = Array2D::new(1, 2, 0);
+        a2d.set(0, 1, 77);
+
+        a2d_chunk.set_chunk(2, &a2d).unwrap();
+
+        let chunk = a2d_chunk.get_chunk(2);
+        assert_eq!(chunk.dimensions(), (1, 2));
+        assert_eq!(chunk.get(0, 0), 0);
+        assert_eq!(chunk.get(0, 1), 77);
+        assert_eq!(a2d_chunk.get_chunk(0).dimensions(), (2, 2));
+    }
+
+    #[test]
+    fn test_chunk_list_assigned_before() {
+        let mut chunk_list = ChunkList::new();
+        let node_id = NodeID::random();
+
+        chunk_list.push(1);
+        chunk_list.push(2);
+        chunk_list.push(3);
+
+        let before = SystemTime::now();
+        chunk_list.assign_next_chunk(node_id);
+        chunk_list.assign_next_chunk(node_id);
+        chunk_list.chunk_sent(1);
+        chunk_list.chunk_empty(0);
+
+        assert_eq!(chunk_list.assigned_before(before).count(), 0);
+        assert_eq!(chunk_list.assigned_before(SystemTime::now()).map(|(chunk_id, _)| chunk_id).collect::<Vec<_>>(), vec![1]);
+        assert!(chunk_list.chunks()[0].assigned_at().unwrap() >= before);
+        assert_eq!(chunk_list.chunks()[2].assigned_at(), None);
+
+        let mut mapped = chunk_list.try_map::<_, (), _>(|data| Ok(data * 10)).unwrap();
+        assert_eq!(mapped.get(2).data, 30);
+        assert_eq!(mapped.chunks()[1].status(), ChunkStatus::Processing);
+
+        mapped.reset_unfinished();
+        assert_eq!(mapped.stats(), (2, 0, 1));
+        assert_eq!(mapped.chunks()[1].assigned_at(), None);
+    }
+
+    #[test]
+    fn test_chunk_list_assign_next_chunk_fitting() {
+        let mut chunk_list = ChunkList::new();
+        let node_id = NodeID::random();
+
+        // Serialized size: 8 bytes for the length + 1 byte per element
+        chunk_list.push(vec![0u8; 100]);
+        chunk_list.push(vec![1u8; 50]);
+        chunk_list.push(vec![2u8; 10]);
+        chunk_list.push(vec![3u8; 20]);
+
+        assert_eq!(chunk_list.assign_next_chunk_fitting(node_id, Some(60)).map(|(chunk_id, _)| chunk_id), Some(1));
+        assert_eq!(chunk_list.assign_next_chunk_fitting(node_id, None).map(|(chunk_id, _)| chunk_id), Some(0));
+        // Nothing fits, the smallest chunk is assigned
+        assert_eq!(chunk_list.assign_next_chunk_fitting(node_id, Some(5)).map(|(chunk_id, _)| chunk_id), Some(2));
+        assert_eq!(chunk_list.assign_next_chunk_fitting(node_id, Some(5)).map(|(chunk_id, _)| chunk_id), Some(3));
+        assert!(chunk_list.assign_next_chunk_fitting(node_id, Some(5)).is_none());
+        assert!(chunk_list.get(1).is_processing(node_id));
+    }
+
+    #[test]
+    fn test_chunk_list_groups() {
+        let mut chunk_list = ChunkList::new();
+        let node_id = NodeID::random();
+
+        chunk_list.push_for_group(0, "gpu");
+        chunk_list.push_for_group(1, "cpu");
+        chunk_list.push(2);
+        chunk_list.push_for_group(3, "gpu");
+
+        // Nodes without a group only get chunks for every node
+        assert_eq!(chunk_list.get_next_free_chunk().map(|(index, _)| index), Some(2));
+        assert_eq!(chunk_list.next_unassigned_for_group("cpu").map(|(index, _)| index), Some(1));
+        assert_eq!(chunk_list.next_unassigned_for_group("fpga").map(|(index, _)| index), Some(2));
+        assert_eq!(chunk_list.get(0).group(), Some("gpu"));
+
+        let gpu = vec!["gpu".to_string()];
+        assert_eq!(chunk_list.assign_next_chunk_for_groups(node_id, &gpu).map(|(chunk_id, _)| chunk_id), Some(0));
+        assert_eq!(chunk_list.assign_next_chunk_for_groups(node_id, &gpu).map(|(chunk_id, _)| chunk_id), Some(2));
+        assert_eq!(chunk_list.assign_next_chunk_for_groups(node_id, &gpu).map(|(chunk_id, _)| chunk_id), Some(3));
+        assert!(chunk_list.assign_next_chunk_for_groups(node_id, &gpu).is_none());
+        assert!(chunk_list.assign_next_chunk(node_id).is_none());
+        assert_eq!(chunk_list.assign_next_chunk_for_groups(node_id, &["cpu".to_string(), "gpu".to_string()]).map(|(chunk_id, _)| chunk_id), Some(1));
+
+        // The group is kept when the chunk is free again
+        chunk_list.heartbeat_timeout(&[node_id]);
+        assert_eq!(chunk_list.assign_next_chunk(node_id).map(|(chunk_id, _)| chunk_id), Some(2));
+        assert_eq!(chunk_list.try_map::<_, (), _>(|data| Ok(*data)).unwrap().chunks()[3].group(), Some("gpu"));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_chunk_list_meta() {
+        let mut chunk_list = ChunkList::new();
+        let meta: ChunkMeta = vec![("x".to_string(), "64".to_string()), ("samples".to_string(), "16".to_string())].into_iter().collect();
+
+        chunk_list.push(1);
+        chunk_list.push_with_meta(2, meta.clone());
+        chunk_list.push_with_meta(3, ChunkMeta::new());
+
+        assert_eq!(chunk_list.chunk_meta(0), None);
+        assert_eq!(chunk_list.chunk_meta(1), Some(meta.clone()));
+        // Empty metadata is not stored
+        assert_eq!(chunk_list.chunk_meta(2), None);
+        assert_eq!(chunk_list.chunk_meta(3), None);
+        assert_eq!(chunk_meta_size(&meta), 12);
+
+        let mapped = chunk_list.try_map::<_, (), _>(|data| Ok(data * 10)).unwrap();
+        assert_eq!(mapped.chunks()[1].meta(), Some(&meta));
+    }
+
+    #[test]
+    fn test_a2d_split_with_halo() {
+        let mut a2d = Array2D::new(3, 2, 0);
+
+        for y in 0..2 {
+            for x in 0..3 {
+                a2d.set(x, y, 10 * y + x + 1);
+            }
+        }
+
+        let tiles = a2d.split_with_halo(2, 2, 1, 99);
+        assert_eq!(tiles.len(), 2);
+        assert_eq!(tiles[0].region, TileRegion { x: 0, y: 0, width: 2, height: 2, halo: 1 });
+        assert_eq!(tiles[1].region, TileRegion { x: 2, y: 0, width: 1, height: 2, halo: 1 });
+
+        // The first tile gets the left column of its right neighbor, the rest of the border is outside
+        let rows: Vec<Vec<u64>> = tiles[0].data.split_rows().map(|row| row.to_vec()).collect();
+        assert_eq!(rows, vec![vec![99, 99, 99, 99], vec![99, 1, 2, 3], vec![99, 11, 12, 13], vec![99, 99, 99, 99]]);
+
+        let rows: Vec<Vec<u64>> = tiles[1].data.split_rows().map(|row| row.to_vec()).collect();
+        assert_eq!(rows, vec![vec![99, 99, 99], vec![2, 3, 99], vec![12, 13, 99], vec![99, 99, 99]]);
+
+        assert_eq!(tiles[1].data.strip_halo(1).split_rows().map(|row| row.to_vec()).collect::<Vec<_>>(), vec![vec![3], vec![13]]);
+    }
+
+    #[test]
+    fn test_a2d_stitch_errors() {
+        let a2d = Array2D::new(4, 4, 1);
+        let mut tiles = a2d.split_with_halo(2, 2, 1, 0);
+
+        tiles[0].data = Array2D::new(3, 3, 1);
+        assert!(matches!(Array2D::stitch(4, 4, 0, &tiles), Err(NCError::Array2DDimensionMismatch((2, 2), (3, 3)))));
+
+        assert!(matches!(Array2D::stitch(3, 4, 0, &tiles[1..]), Err(NCError::Array2DDimensionMismatch((3, 4), (4, 2)))));
+
+        // Missing tiles are filled
+        let result = Array2D::stitch(4, 4, 0, &tiles[3..]).unwrap();
+        assert_eq!(result.get(0, 0), 0);
+        assert_eq!(result.get(3, 3), 1);
+    }
+
+    #[test]
+    fn test_a2d_halo_round_trip() {
+        use rand::{Rng, SeedableRng, rngs::StdRng};
+
+        let mut rng = StdRng::seed_from_u64(0x4A10);
+
+        for _ in 0..200 {
+            let width = rng.gen_range(1, 20);
+            let height = rng.gen_range(1, 20);
+            let tile_width = rng.gen_range(1, width + 3);
+            let tile_height = rng.gen_range(1, height + 3);
+            let halo = rng.gen_range(0, 4);
+            let mut a2d = Array2D::new(width, height, 0u32);
+
+            for y in 0..height {
+                for x in 0..width {
+                    a2d.set(x, y, rng.gen());
+                }
+            }
+
+            let mut tiles = a2d.split_with_halo(tile_width, tile_height, halo, u32::MAX);
+            let expected_tiles = width.div_ceil(tile_width) * height.div_ceil(tile_height);
+            assert_eq!(tiles.len() as u64, expected_tiles);
+
+            // With the border
+            assert_eq!(Array2D::stitch(width, height, 0, &tiles).unwrap(), a2d);
+
+            // The node returns its tile without the border, in any order
+            for tile in tiles.iter_mut() {
+                assert_eq!(tile.data.dimensions(), tile.region.padded_dimensions());
+                tile.data = tile.data.strip_halo(halo);
+            }
+
+            tiles.reverse();
+            assert_eq!(Array2D::stitch(width, height, 0, &tiles).unwrap(), a2d, "{} x {}, tile: {} x {}, halo: {}", width, height, tile_width, tile_height, halo);
+        }
+    }
+
+    #[test]
+    fn test_a2d_raw_bytes() {
+        for (width, height) in [(1, 1), (3, 5), (7, 1), (1, 3), (5, 5)] {
+            let bytes: Vec<u8> = (0..width * height * 4).map(|i| i as u8).collect();
+            let a2d = Array2D::<[u8; 4]>::from_raw_bytes(width, height, bytes.clone()).unwrap();
+            assert_eq!(a2d.dimensions(), (width, height));
+            assert_eq!(a2d.get(width - 1, 0), [(4 * width - 4) as u8, (4 * width - 3) as u8, (4 * width - 2) as u8, (4 * width - 1) as u8]);
+            assert_eq!(a2d.into_raw_bytes(), bytes);
+
+            let bytes: Vec<u8> = (0..width * height * 3).map(|i| i as u8).collect();
+            assert_eq!(Array2D::<[u8; 3]>::from_raw_bytes(width, height, bytes.clone()).unwrap().into_raw_bytes(), bytes);
+        }
+
+        // The buffer has more capacity than bytes
+        let mut bytes = Vec::with_capacity(15);
+        bytes.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(Array2D::<[u8; 4]>::from_raw_bytes(2, 1, bytes).unwrap().as_slice(), &[[1, 2, 3, 4], [5, 6, 7, 8]]);
+
+        assert!(matches!(Array2D::<[u8; 4]>::from_raw_bytes(3, 1, vec![0; 11]), Err(NCError::Array2DDimensionMismatch((12, 1), (11, 1)))));
+        assert!(Array2D::<[u8; 4]>::from_raw_bytes(3, 1, vec![0; 13]).is_err());
+        assert!(Array2D::<[u8; 4]>::from_raw_bytes(0, 1, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_a2d_convert() {
+        let a2d = Array2D::from_vec(3, 1, vec![(1u8, 2u8, 3u8), (4, 5, 6), (7, 8, 9)]).unwrap();
+        let rgba = a2d.convert(|&(r, g, b)| [r, g, b, 255]);
+        assert_eq!(rgba.dimensions(), (3, 1));
+        assert_eq!(rgba.into_raw_bytes(), vec![1, 2, 3, 255, 4, 5, 6, 255, 7, 8, 9, 255]);
+    }
+
+    #[cfg(feature = "bytemuck")]
+    #[test]
+    fn test_a2d_zero_copy() {
+        let bytes: Vec<u8> = (0..3 * 5 * 4).map(|i| i as u8).collect();
+        let pointer = bytes.as_ptr();
+        let a2d = Array2D::<[u8; 4]>::from_raw_bytes(3, 5, bytes).unwrap();
+        assert_eq!(a2d.as_bytes().as_ptr(), pointer);
+        assert_eq!(a2d.as_bytes()[4..8], [4, 5, 6, 7]);
+        let bytes = a2d.into_raw_bytes();
+        assert_eq!(bytes.as_ptr(), pointer);
+
+        let a2d = Array2D::new(2, 2, 0x0102_0304u32);
+        assert_eq!(a2d.as_bytes().len(), 16);
+        assert_eq!(a2d.as_bytes()[0..4], 0x0102_0304u32.to_ne_bytes());
+    }
+
+    #[test]
+    fn test_lazy_chunk_list() {
+        let node1 = NodeID::random();
+        let node2 = NodeID::random();
+        let mut chunks = ChunkList::from_generator(5, |chunk_id| chunk_id * 10);
+
+        assert_eq!(chunks.assign_next_chunk(node1), Some((0, 0)));
+        assert_eq!(chunks.assign_next_chunk(node1), Some((1, 10)));
+        assert_eq!(chunks.assign_next_chunk(node2), Some((2, 20)));
+        chunks.chunk_sent(0);
+        assert_eq!(chunks.status(0), ChunkStatus::Processing);
+        assert_eq!(chunks.status(1), ChunkStatus::Assigned);
+        assert!(chunks.is_processing(2, node2));
+        assert!(!chunks.is_processing(2, node1));
+        assert_eq!(chunks.stats(), (2, 3, 0));
+
+        // node1 is gone, its chunks are handed out again with the same data, the lowest id first
+        chunks.heartbeat_timeout(&[node1]);
+        assert_eq!(chunks.status(1), ChunkStatus::Empty);
+        assert_eq!(chunks.assign_next_chunk(node2), Some((0, 0)));
+        assert_eq!(chunks.assign_next_chunk(node2), Some((1, 10)));
+        assert_eq!(chunks.data(1), Some(10));
+        assert_eq!(chunks.data(5), None);
+
+        chunks.set_finished(0);
+        chunks.chunk_empty(2);
+        chunks.chunk_failed(node2, false);
+        assert_eq!(chunks.failed_chunks().collect::<Vec<_>>(), vec![1]);
+        assert!(chunks.is_finished(2));
+        assert_eq!(chunks.progress(), (3, 5));
+
+        assert_eq!(chunks.assign_next_chunk(node1), Some((3, 30)));
+        chunks.chunk_send_failed(3);
+        assert_eq!(chunks.assign_next_chunk(node1), Some((3, 30)));
+        chunks.chunk_rejected(3, true);
+        assert_eq!(chunks.assign_next_chunk(node1), Some((3, 30)));
+        assert_eq!(chunks.assigned_before(SystemTime::now()).collect::<Vec<_>>(), vec![3]);
+        chunks.set_finished(3);
+        chunks.chunk_skipped(4);
+        chunks.set_finished(4);
+        assert_eq!(chunks.assign_next_chunk(node1), None);
+        assert_eq!(chunks.progress(), (5, 5));
+        assert_eq!(chunks.stats(), (0, 0, 4));
+
+        // Unknown chunk ids are ignored
+        chunks.set_finished(5);
+        chunks.chunk_empty(6);
+        chunks.chunk_revoked(u64::MAX);
+        chunks.chunk_left_out(7);
+        assert!(!chunks.is_finished(5));
+        assert_eq!(chunks.progress(), (5, 5));
+        assert_eq!(chunks.stats(), (0, 0, 4));
+
+        let mut chunks = ChunkList::from_generator(2, |chunk_id| chunk_id);
+        chunks.set_finished(0);
+        chunks.set_finished(1);
+        chunks.chunk_empty(2);
+        assert_eq!(chunks.progress(), (2, 2));
+        assert_eq!(chunks.stats(), (0, 0, 2));
+    }
+
+    #[test]
+    fn test_lazy_chunk_list_memory() {
+        const TOTAL: u64 = 10_000_000;
+        let node = NodeID::random();
+        let mut chunks = ChunkList::from_generator(TOTAL, |chunk_id| (chunk_id, chunk_id.wrapping_mul(0x9E37_79B9_7F4A_7C15)));
+        let mut expired = Vec::new();
+        let mut max_bytes = 0;
+
+        while let Some((chunk_id, data)) = chunks.assign_next_chunk(node) {
+            assert_eq!(data.1, chunk_id.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+
+            // Every 100000th chunk expires once and is handed out again later
+            if chunk_id % 100_000 == 0 && !expired.contains(&chunk_id) {
+                expired.push(chunk_id);
+                chunks.heartbeat_timeout(&[node]);
+            } else {
+                chunks.set_finished(chunk_id);
+            }
+
+            max_bytes = max_bytes.max(chunks.heap_bytes());
+        }
+
+        assert_eq!(expired.len(), 100);
+        assert_eq!(chunks.progress(), (TOTAL, TOTAL));
+        // One bit per chunk plus a few chunks in the maps
+        assert!(max_bytes <= (TOTAL / 8) as usize + 64 * 1024, "max bytes: {}", max_bytes);
+    }
 }
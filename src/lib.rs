@@ -3,6 +3,11 @@
 //! Usually the code for the server and the node is inside the same binary and the choice if to
 //! run in server mode or node mode is done via configuration or command line argument.
 //! See some of the programs in the example folders.
+//! For jobs of the form "for i in 0..n compute f(i)" the [`RangeServer`] and the [`RangeNode`] implement both traits already,
+//! see the [`nc_range`] module and the monte_carlo_pi example.
+
+// Without the core feature only the wire types in nc_proto are left, they only need alloc (no-std-proto feature).
+#![cfg_attr(not(feature = "core"), no_std)]
 
 // TODO:
 // Examples to add:
@@ -18,17 +23,199 @@
 // - Darwin: https://github.com/willi-kappler/darwin-rs
 // - GRONN: https://github.com/willi-kappler/gronn
 
+#[cfg(feature = "net")]
 pub mod nc_server;
+#[cfg(feature = "net")]
 pub mod nc_node;
+#[cfg(feature = "core")]
+pub mod nc_message;
+#[cfg(feature = "core")]
+pub mod nc_envelope;
+#[cfg(feature = "core")]
 pub mod nc_node_info;
+#[cfg(feature = "core")]
 pub mod nc_error;
+#[cfg(feature = "core")]
 pub mod nc_config;
+#[cfg(feature = "core")]
 pub mod array2d;
+#[cfg(feature = "core")]
 pub mod nc_communicator;
+#[cfg(feature = "core")]
+pub mod nc_proxy;
+#[cfg(feature = "net")]
+pub mod nc_client;
+#[cfg(feature = "core")]
+pub mod nc_admin;
+#[cfg(feature = "net")]
+pub mod nc_result_queue;
+#[cfg(feature = "net")]
+pub mod nc_reorder_buffer;
+#[cfg(feature = "core")]
+pub mod nc_result_batch;
+#[cfg(feature = "core")]
+pub mod nc_scratch_dir;
+#[cfg(feature = "net")]
+pub mod nc_throttle;
+#[cfg(feature = "net")]
+pub mod nc_chunk_cache;
+#[cfg(feature = "net")]
+pub mod nc_frame_cache;
+#[cfg(feature = "core")]
+pub mod nc_dry_run;
+#[cfg(feature = "net")]
+pub mod nc_frame_writer;
+#[cfg(feature = "core")]
+pub mod nc_checkpoint;
+#[cfg(feature = "net")]
+pub mod nc_watchdog;
+#[cfg(feature = "net")]
+pub mod nc_listener;
+#[cfg(feature = "net")]
+pub mod nc_transport;
+#[cfg(feature = "net")]
+pub mod nc_artifacts;
+#[cfg(feature = "net")]
+pub mod nc_result_cache;
+#[cfg(feature = "net")]
+pub mod nc_node_id_file;
+#[cfg(feature = "net")]
+pub mod nc_upload;
+#[cfg(feature = "core")]
+pub mod nc_post_process;
+#[cfg(feature = "net")]
+pub mod nc_multi_server;
+#[cfg(feature = "net")]
+pub mod nc_aggregator;
+#[cfg(feature = "net")]
+pub mod nc_local;
+#[cfg(feature = "net")]
+pub mod nc_chunk_queue;
+#[cfg(feature = "net")]
+pub mod nc_range;
+#[cfg(feature = "net")]
+pub mod nc_broadcast;
+#[cfg(feature = "net")]
+pub mod nc_result_stream;
+#[cfg(feature = "net")]
+pub mod nc_events;
+#[cfg(feature = "core")]
+pub mod nc_resources;
+#[cfg(feature = "core")]
+pub mod nc_clock;
+#[cfg(feature = "core")]
+pub mod nc_transform;
+#[cfg(feature = "core")]
+pub mod nc_job_summary;
+#[cfg(feature = "core")]
+pub mod nc_offline;
+#[cfg(feature = "core")]
+pub mod nc_job_definition;
+#[cfg(feature = "core")]
+pub mod nc_replication;
+#[cfg(feature = "core")]
+pub mod nc_sample;
+#[cfg(feature = "core")]
+pub mod nc_context;
+#[cfg(feature = "core")]
+pub mod nc_dictionary;
+#[cfg(feature = "core")]
+pub mod nc_compact;
+#[cfg(feature = "core")]
+pub mod nc_locality;
+#[cfg(feature = "progress")]
+pub mod nc_progress;
+#[cfg(feature = "debug-protocol")]
+pub mod nc_text_protocol;
+#[cfg(feature = "ed25519")]
+pub mod nc_keys;
+#[cfg(feature = "mdns")]
+pub mod nc_mdns;
+#[cfg(feature = "subprocess")]
+pub mod nc_subprocess;
+#[cfg(feature = "shmem")]
+pub mod nc_shmem;
+#[cfg(any(feature = "core", feature = "no-std-proto"))]
+pub mod nc_proto;
+
+#[cfg(any(feature = "core", feature = "no-std-proto"))]
+extern crate alloc;
 
-pub use nc_server::{NCServer, NCJobStatus, NCServerStarter};
-pub use nc_node::{NCNode, NCNodeStarter};
+#[cfg(feature = "net")]
+pub use nc_server::{NCServer, ChunkAssignment, NCGapAction, NCDeadChunkAction, NCServerStarter, NCServerHandle, NCProgressEvent, NCAssignContext};
+#[cfg(feature = "net")]
+pub use nc_node::{NCNode, NCNodeStarter, NCProcessContext, NodeResult, NodeExit, NCNodeProgressEvent};
+#[cfg(feature = "core")]
+pub use nc_message::{NCServerMessage, NCJobStatus, NCChunkInfo, NCServerStatistics, NCWorkHint, NCRejectReason};
+#[cfg(feature = "core")]
 pub use nc_node_info::NodeID;
-pub use nc_error::NCError;
-pub use nc_config::NCConfiguration;
-pub use array2d::{Array2D, Array2DChunk, ChunkList, Chunk, ChunkData};
+#[cfg(feature = "core")]
+pub use nc_error::{NCError, NCJobError, NCUserError};
+#[cfg(feature = "core")]
+pub use nc_config::{NCConfiguration, NCResultQueueMode, OnProcessError, BindMode, NCTransportKind, ConfigDiff, ConfigDiffSeverity};
+#[cfg(feature = "core")]
+pub use nc_communicator::{NCCodec, NCServerAddr, nc_encoded_size};
+#[cfg(feature = "core")]
+pub use nc_proxy::{NCProxyConfig, NCProxyKind};
+#[cfg(feature = "net")]
+pub use nc_client::NCClient;
+#[cfg(feature = "core")]
+pub use nc_scratch_dir::ScratchDir;
+#[cfg(feature = "core")]
+pub use nc_dry_run::{DryRunReport, DryRunCheck};
+#[cfg(feature = "core")]
+pub use nc_checkpoint::NCCheckpoint;
+#[cfg(feature = "core")]
+pub use nc_post_process::NCPostProcessor;
+#[cfg(feature = "net")]
+pub use nc_multi_server::NCMultiServerStarter;
+#[cfg(feature = "net")]
+pub use nc_chunk_queue::{ChunkQueueHandle, ChunkQueueWaker};
+#[cfg(feature = "net")]
+pub use nc_range::{RangeServer, RangeNode, RangeBatch, RangeResults};
+#[cfg(feature = "net")]
+pub use nc_broadcast::NCBroadcastHandle;
+#[cfg(feature = "net")]
+pub use nc_result_stream::{NCResultStream, NCResultItem};
+#[cfg(feature = "net")]
+pub use nc_events::{NCEvent, NCEventRecord, NCEventReader};
+#[cfg(feature = "core")]
+pub use nc_admin::NCAdminCommand;
+#[cfg(feature = "core")]
+pub use nc_resources::NCResourceReport;
+#[cfg(feature = "core")]
+pub use nc_clock::NCClockSkew;
+#[cfg(feature = "core")]
+pub use nc_transform::{Transform, Transformed, FloatData, QuantizeU16, quantize_f32_to_u16};
+#[cfg(feature = "core")]
+pub use nc_job_summary::{NCJobSummary, NCJobEndReason, NCNodeContribution, NCChunkAttempt, NCAttemptOutcome, NCDeadChunk};
+#[cfg(feature = "core")]
+pub use nc_offline::NCOfflineHandle;
+#[cfg(feature = "core")]
+pub use nc_job_definition::{NCJobDefinition, NCJobSettings, NCJobFile, NCJobInput, NCJobOutput};
+#[cfg(feature = "net")]
+pub use nc_job_definition::{NCDefinitionServer, NCDefinitionNode};
+#[cfg(feature = "core")]
+pub use nc_replication::NCAcceptedResult;
+#[cfg(feature = "net")]
+pub use nc_replication::NCStandby;
+#[cfg(feature = "core")]
+pub use nc_sample::NCSample;
+#[cfg(feature = "core")]
+pub use nc_context::{NCCallContext, NCContextNodeInfo};
+#[cfg(feature = "core")]
+pub use nc_result_batch::NCBatchedResult;
+#[cfg(feature = "core")]
+pub use nc_compact::PodElement;
+#[cfg(feature = "core")]
+pub use nc_locality::LocalityMap;
+#[cfg(feature = "ed25519")]
+pub use nc_keys::{NCKeypair, NCPublicKey, NCKeyRing};
+#[cfg(feature = "mdns")]
+pub use nc_mdns::{NCMdnsServer, MDNS_SERVICE_TYPE};
+#[cfg(feature = "subprocess")]
+pub use nc_subprocess::{SubprocessRunner, SubprocessOutput, SubprocessNode, SubprocessFormat};
+#[cfg(feature = "progress")]
+pub use nc_progress::{NCServerProgress, NCNodeProgress};
+#[cfg(feature = "core")]
+pub use array2d::{Array2D, Array2DChunk, TileRegion, HaloTile, ChunkList, LazyChunkList, Chunk, ChunkStatus, ChunkData, ChunkID, ChunkMeta};
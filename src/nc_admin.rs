@@ -0,0 +1,256 @@
+//! This module contains the admin protocol.
+//! Admin commands (query status, abort / pause / resume the job, disable a node) are not sent by regular compute nodes
+//! but by admin clients. They must be signed with the admin key from the NCConfiguration (HMAC-SHA256).
+//! Every signed message contains a time stamp and a random nonce, so that the server can reject replayed messages
+//! (see admin_message_max_age in the NCConfiguration).
+//! If the server has an admin key it also rejects the unsigned NCNodeMessage::ShutDown and NCNodeMessage::NewServer messages,
+//! the admin client sends the commands ShutDown and NewServer instead.
+
+#[cfg(feature = "net")]
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "net")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "net")]
+use hmac::{Hmac, Mac};
+#[cfg(feature = "net")]
+use sha2::Sha256;
+#[cfg(feature = "net")]
+use rand::random;
+use serde::{Serialize, Deserialize};
+#[cfg(feature = "net")]
+use log::debug;
+
+#[cfg(feature = "net")]
+use crate::nc_config::NCConfiguration;
+#[cfg(feature = "net")]
+use crate::nc_error::NCError;
+use crate::nc_node_info::NodeID;
+use crate::array2d::ChunkID;
+
+#[cfg(feature = "net")]
+type HmacSha256 = Hmac<Sha256>;
+
+/// All the commands that need the admin key. The Debug output masks the new key of RotateKey.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub enum NCAdminCommand {
+    /// Ask the server for the current job status. The server answers with a NCServerMessage::Status message.
+    QueryStatus,
+    /// Abort the job with the given reason. The server calls finish_job() and exits.
+    AbortJob(String),
+    /// Do not hand out new data to the nodes until the job is resumed. Nodes receive a NCJobStatus::Waiting.
+    PauseJob,
+    /// Resume a paused job.
+    ResumeJob,
+    /// Do not send any more data to the given node.
+    DisableNode(NodeID),
+    /// Use the given encryption key for sending from now on, the current key is still accepted for receiving
+    /// (see previous_keys in the NCConfiguration). The nodes get the new key the next time they contact the server.
+    RotateKey(String),
+    /// Nodes that don't run the given build are asked to restart after their current chunk, [`None`] = every build is accepted.
+    /// See required_node_build in the NCConfiguration.
+    SetRequiredNodeBuild(Option<String>),
+    /// Promote the standby server (see the [`nc_replication`](crate::nc_replication) module): it takes no more updates from the server
+    /// and hands the replicated state over, so that the job can be continued. A server that is already running just acknowledges it.
+    Promote,
+    /// Ask the server for the attempts to process the given chunk (see the [`nc_job_summary`](crate::nc_job_summary) module).
+    /// The server answers with a NCServerMessage::ChunkHistory message.
+    ChunkHistory(ChunkID),
+    /// Take the given chunk from the dead-letter list (see max_chunk_attempts in the NCConfiguration), the server gives it
+    /// to the nodes again. The chunks on the list of a checkpoint are only sent again after a restart if they have been reset.
+    ResetDeadChunk(ChunkID),
+    /// Shut the server down, like the NCNodeMessage::ShutDown message. The server calls finish_job() and exits.
+    ShutDown,
+    /// Move all the nodes to the new server given by address and port, like the NCNodeMessage::NewServer message.
+    NewServer(String, u16),
+}
+
+impl fmt::Debug for NCAdminCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NCAdminCommand::QueryStatus => f.write_str("QueryStatus"),
+            NCAdminCommand::AbortJob(reason) => f.debug_tuple("AbortJob").field(reason).finish(),
+            NCAdminCommand::PauseJob => f.write_str("PauseJob"),
+            NCAdminCommand::ResumeJob => f.write_str("ResumeJob"),
+            NCAdminCommand::DisableNode(node_id) => f.debug_tuple("DisableNode").field(node_id).finish(),
+            NCAdminCommand::RotateKey(_) => f.debug_tuple("RotateKey").field(&"***").finish(),
+            NCAdminCommand::SetRequiredNodeBuild(build) => f.debug_tuple("SetRequiredNodeBuild").field(build).finish(),
+            NCAdminCommand::Promote => f.write_str("Promote"),
+            NCAdminCommand::ChunkHistory(chunk_id) => f.debug_tuple("ChunkHistory").field(chunk_id).finish(),
+            NCAdminCommand::ResetDeadChunk(chunk_id) => f.debug_tuple("ResetDeadChunk").field(chunk_id).finish(),
+            NCAdminCommand::ShutDown => f.write_str("ShutDown"),
+            NCAdminCommand::NewServer(address, port) => f.debug_tuple("NewServer").field(address).field(port).finish(),
+        }
+    }
+}
+
+/// A signed admin command.
+#[cfg(feature = "net")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct NCAdminMessage {
+    /// Seconds since UNIX epoch when the message was created.
+    time_stamp: u64,
+    /// Random number, every message must have a different nonce.
+    nonce: u64,
+    /// The actual command.
+    command: NCAdminCommand,
+    /// HMAC over time stamp, nonce and command.
+    mac: Vec<u8>,
+}
+
+/// Returns the current time as seconds since UNIX epoch.
+#[cfg(feature = "net")]
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Calculates the HMAC for the given parts of the message.
+#[cfg(feature = "net")]
+fn calc_mac(admin_key: &str, time_stamp: u64, nonce: u64, command: &NCAdminCommand) -> Result<HmacSha256, NCError> {
+    let data = bincode::serialize(&(time_stamp, nonce, command)).map_err(NCError::Serialize)?;
+    let mut mac = HmacSha256::new_from_slice(admin_key.as_bytes()).map_err(|_| NCError::Unauthorized)?;
+    mac.update(&data);
+    Ok(mac)
+}
+
+#[cfg(feature = "net")]
+impl NCAdminMessage {
+    /// Creates a new signed admin message with the current time stamp and a random nonce.
+    pub(crate) fn new(admin_key: &str, command: NCAdminCommand) -> Result<Self, NCError> {
+        debug!("NCAdminMessage::new()");
+
+        Self::with_time_stamp(admin_key, command, now_secs(), random())
+    }
+
+    /// Creates a new signed admin message with the given time stamp and nonce.
+    fn with_time_stamp(admin_key: &str, command: NCAdminCommand, time_stamp: u64, nonce: u64) -> Result<Self, NCError> {
+        let mac = calc_mac(admin_key, time_stamp, nonce, &command)?.finalize().into_bytes().to_vec();
+
+        Ok(NCAdminMessage { time_stamp, nonce, command, mac })
+    }
+
+    /// Checks the signature with the given admin key and returns the command if it is valid.
+    /// An empty admin key disables the admin protocol, every message is rejected then.
+    pub(crate) fn verify(&self, admin_key: &str) -> Result<&NCAdminCommand, NCError> {
+        debug!("NCAdminMessage::verify()");
+
+        if admin_key.is_empty() {
+            return Err(NCError::Unauthorized)
+        }
+
+        calc_mac(admin_key, self.time_stamp, self.nonce, &self.command)?
+            .verify_slice(&self.mac).map_err(|_| NCError::Unauthorized)?;
+
+        Ok(&self.command)
+    }
+}
+
+/// Remembers all the nonces the server has seen in the last `max_age` seconds.
+/// Messages that are older than that are rejected anyway.
+#[cfg(feature = "net")]
+#[derive(Debug)]
+pub(crate) struct NCReplayGuard {
+    /// Maximum age of a message in seconds, also the maximum allowed clock difference between server and admin client.
+    max_age: u64,
+    /// Nonce and time stamp of all valid messages.
+    seen: HashMap<u64, u64>,
+}
+
+#[cfg(feature = "net")]
+impl NCReplayGuard {
+    /// Creates a new replay guard that accepts messages up to admin_message_max_age seconds old (see the NCConfiguration).
+    pub(crate) fn new(config: &NCConfiguration) -> Self {
+        NCReplayGuard { max_age: config.admin_message_max_age, seen: HashMap::new() }
+    }
+
+    /// Checks if the message is fresh and its nonce has not been used before.
+    /// The message must already be verified.
+    pub(crate) fn check(&mut self, message: &NCAdminMessage) -> Result<(), NCError> {
+        self.check_at(message, now_secs())
+    }
+
+    fn check_at(&mut self, message: &NCAdminMessage, now: u64) -> Result<(), NCError> {
+        debug!("NCReplayGuard::check()");
+
+        let max_age = self.max_age;
+        self.seen.retain(|_, time_stamp| now.saturating_sub(*time_stamp) <= max_age);
+
+        let diff = now.abs_diff(message.time_stamp);
+
+        if diff > max_age || self.seen.contains_key(&message.nonce) {
+            return Err(NCError::Unauthorized)
+        }
+
+        self.seen.insert(message.nonce, message.time_stamp);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::*;
+
+    const ADMIN_KEY: &str = "Ylt9GDdxs4ACqKrpofkwuP3Hqv1uMzbQ";
+
+    #[test]
+    fn test_verify() {
+        let message = NCAdminMessage::new(ADMIN_KEY, NCAdminCommand::PauseJob).unwrap();
+
+        assert_eq!(message.verify(ADMIN_KEY).unwrap(), &NCAdminCommand::PauseJob);
+    }
+
+    #[test]
+    fn test_verify_wrong_key() {
+        let message = NCAdminMessage::new("wrong key", NCAdminCommand::AbortJob("test".to_string())).unwrap();
+
+        assert!(matches!(message.verify(ADMIN_KEY), Err(NCError::Unauthorized)));
+    }
+
+    #[test]
+    fn test_verify_empty_key() {
+        let message = NCAdminMessage::new("", NCAdminCommand::QueryStatus).unwrap();
+
+        assert!(matches!(message.verify(""), Err(NCError::Unauthorized)));
+    }
+
+    #[test]
+    fn test_verify_tampered_command() {
+        let mut message = NCAdminMessage::new(ADMIN_KEY, NCAdminCommand::PauseJob).unwrap();
+        message.command = NCAdminCommand::AbortJob("evil".to_string());
+
+        assert!(matches!(message.verify(ADMIN_KEY), Err(NCError::Unauthorized)));
+    }
+
+    #[test]
+    fn test_replay_rejected() {
+        let mut replay_guard = NCReplayGuard::new(&NCConfiguration::default());
+        let message = NCAdminMessage::new(ADMIN_KEY, NCAdminCommand::ResumeJob).unwrap();
+
+        message.verify(ADMIN_KEY).unwrap();
+        assert!(replay_guard.check(&message).is_ok());
+        assert!(matches!(replay_guard.check(&message.clone()), Err(NCError::Unauthorized)));
+
+        let message = NCAdminMessage::new(ADMIN_KEY, NCAdminCommand::ResumeJob).unwrap();
+        assert!(replay_guard.check(&message).is_ok());
+    }
+
+    #[test]
+    fn test_replay_too_old() {
+        let mut replay_guard = NCReplayGuard::new(&NCConfiguration { admin_message_max_age: 60, ..Default::default() });
+        let message = NCAdminMessage::with_time_stamp(ADMIN_KEY, NCAdminCommand::QueryStatus, 1000, 1).unwrap();
+
+        assert!(message.verify(ADMIN_KEY).is_ok());
+        assert!(replay_guard.check_at(&message, 1030).is_ok());
+        assert!(matches!(replay_guard.check_at(&message, 1031), Err(NCError::Unauthorized)));
+
+        let message = NCAdminMessage::with_time_stamp(ADMIN_KEY, NCAdminCommand::QueryStatus, 1000, 2).unwrap();
+        assert!(matches!(replay_guard.check_at(&message, 1061), Err(NCError::Unauthorized)));
+
+        // A longer window from the configuration
+        let mut replay_guard = NCReplayGuard::new(&NCConfiguration { admin_message_max_age: 300, ..Default::default() });
+        assert!(replay_guard.check_at(&message, 1061).is_ok());
+        let message = NCAdminMessage::with_time_stamp(ADMIN_KEY, NCAdminCommand::QueryStatus, 1000, 3).unwrap();
+        assert!(matches!(replay_guard.check_at(&message, 1301), Err(NCError::Unauthorized)));
+    }
+}
@@ -0,0 +1,369 @@
+//! This module contains the aggregator that combines the results of several nodes before they are sent to the server.
+//! For jobs with big results that can be combined (histograms, word counts, summed up matrices, ...) sending every single result
+//! to the server can be the bottleneck. A node with aggregator_port in the NCConfiguration also listens on that port and the server
+//! tells the other nodes with the same aggregator_tag to send their results there (see [`NCChunkInfo`](crate::NCChunkInfo)).
+//! The aggregator combines them with the NCNode trait method reduce() and sends the combined result to the server every
+//! aggregate_interval seconds, there the NCServer trait method process_aggregate() is called with it.
+//! The nodes talk to the aggregator with the same protocol (and keys) as with the server.
+//!
+//! Failure handling:
+//! - Before the aggregator accepts a result, the server has to hand the chunk over to it (NCServer trait method chunk_delegated()).
+//!   From then on the chunk belongs to the aggregator.
+//! - If the aggregator can't be reached or the chunk is not handed over, the node sends its result to the server directly.
+//! - If the aggregator dies (heartbeat timeout) its chunks are given to a node again. A combined result that arrives later
+//!   for one of these chunks is dropped and its other chunks are given to a node again, so no result is counted twice.
+//! - If reduce() fails, all the chunks of the combined result are given to a node again.
+//!
+//! The combined results are processed right away and not through the result queue, so this can't be used with ordered_results.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{error, info, debug};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::nc_error::NCError;
+use crate::nc_config::NCConfiguration;
+use crate::nc_client::NCClient;
+use crate::nc_communicator::NCCommunicator;
+use crate::nc_node::NCNodeMessage;
+use crate::nc_node_info::NodeID;
+use crate::nc_server::NCServerMessage;
+use crate::array2d::ChunkID;
+
+/// The NCNode trait method reduce().
+type ReduceFn<P> = fn(P, P) -> Result<P, NCError>;
+
+/// The results that have not been sent to the server yet.
+struct NCPartialAggregate<P> {
+    /// The chunks and their combined result, if any.
+    aggregate: Option<(Vec<ChunkID>, P)>,
+    /// The chunks whose results are lost because reduce() has failed, they have to be given to a node again.
+    failed: Vec<ChunkID>,
+}
+
+/// The state that is shared between the listener thread and the flush thread.
+struct NCAggregatorState<P> {
+    /// The results that have not been sent to the server yet.
+    partial: Mutex<NCPartialAggregate<P>>,
+    /// Combines two results.
+    reduce: ReduceFn<P>,
+    /// Connection to the server, shares the server address and the encryption keys with the main loop of the node.
+    nc_client: Mutex<NCClient>,
+    /// Handles the communication with the other nodes.
+    nc_communicator: Mutex<NCCommunicator>,
+    /// A node that doesn't send anything is disconnected after this time.
+    read_timeout: Duration,
+    /// Frames from the other nodes must not be longer, see max_frame_bytes in the NCConfiguration.
+    max_frame_bytes: u64,
+}
+
+impl<P: Serialize + DeserializeOwned> NCAggregatorState<P> {
+    /// Reads one NCNodeMessage::Aggregate message from the stream and answers it, see aggregate().
+    /// A connection that is closed before anything has been sent is ignored, this is used to stop the listener thread.
+    fn handle_connection(&self, mut stream: TcpStream) -> Result<(), NCError> {
+        debug!("NCAggregator::handle_connection()");
+
+        stream.set_read_timeout(Some(self.read_timeout))?;
+
+        let data = match NCCommunicator::nc_receive_frame(&mut stream, self.max_frame_bytes) {
+            Ok(data) => data,
+            Err(NCError::UnexpectedEof("length", 0, _)) => {
+                debug!("Connection closed without a message");
+                return Ok(())
+            }
+            Err(e) => return Err(e)
+        };
+
+        let message: NCNodeMessage<P, ()> = self.nc_communicator.lock()?.nc_decode_message(&data)?;
+
+        let answer: NCServerMessage<(), (), ()> = match message {
+            NCNodeMessage::Aggregate(node_id, chunk_id, data) => self.aggregate(node_id, chunk_id, data)?,
+            _ => {
+                error!("Error in handle_connection(), NCNodeMessage mismatch, expected: Aggregate");
+                return Err(NCError::NodeMsgMismatch)
+            }
+        };
+
+        self.nc_communicator.lock()?.nc_send_data2(&answer, &mut stream)
+    }
+
+    /// Asks the server to hand the chunk of the given node over to this aggregator and combines the result with the others.
+    /// Returns the answer for the node: NCServerMessage::ResultAck or NCServerMessage::ResultRejected if the server
+    /// doesn't hand the chunk over, then the node sends the result to the server itself.
+    fn aggregate(&self, node_id: NodeID, chunk_id: ChunkID, data: P) -> Result<NCServerMessage<(), (), ()>, NCError> {
+        debug!("NCAggregator::aggregate()");
+
+        match self.nc_client.lock()?.delegate_chunk(node_id, chunk_id) {
+            Ok(()) => {}
+            Err(NCError::Job(job_error)) => {
+                info!("Server doesn't hand over chunk {} of node {}: {}", chunk_id, node_id, job_error);
+                return Ok(NCServerMessage::ResultRejected(job_error))
+            }
+            Err(e) => return Err(e)
+        }
+
+        self.merge(vec![chunk_id], data)?;
+        Ok(NCServerMessage::ResultAck { job_finished: false })
+    }
+
+    /// Combines the given result with the results that have not been sent to the server yet.
+    /// If reduce() fails, all these chunks have to be given to a node again.
+    fn merge(&self, chunks: Vec<ChunkID>, data: P) -> Result<(), NCError> {
+        let mut guard = self.partial.lock()?;
+        let partial = &mut *guard;
+
+        partial.aggregate = match partial.aggregate.take() {
+            None => Some((chunks, data)),
+            Some((mut all_chunks, aggregate)) => {
+                all_chunks.extend(chunks);
+
+                match (self.reduce)(aggregate, data) {
+                    Ok(aggregate) => Some((all_chunks, aggregate)),
+                    Err(e) => {
+                        error!("reduce() has failed: {}, chunks will be given to a node again: {:?}", e, all_chunks);
+                        partial.failed.extend(all_chunks);
+                        None
+                    }
+                }
+            }
+        };
+
+        Ok(())
+    }
+
+    /// Sends the combined result and the failed chunks to the server using the NCNodeMessage::Aggregated message.
+    /// If the server can't be reached, they are kept for the next time.
+    fn flush(&self) -> Result<(), NCError> {
+        debug!("NCAggregator::flush()");
+
+        let (aggregate, failed) = {
+            let mut partial = self.partial.lock()?;
+            (partial.aggregate.take(), std::mem::take(&mut partial.failed))
+        };
+
+        let mut result = Ok(());
+
+        if !failed.is_empty() {
+            if let Err(e) = self.nc_client.lock()?.submit_aggregate::<P>(&failed, None) {
+                self.partial.lock()?.failed.extend(failed);
+                result = Err(e);
+            }
+        }
+
+        if let Some((chunks, data)) = aggregate {
+            info!("Send combined result of {} chunks to server", chunks.len());
+
+            if let Err(e) = self.nc_client.lock()?.submit_aggregate(&chunks, Some(&data)) {
+                self.merge(chunks, data)?;
+                result = Err(e);
+            }
+        }
+
+        result
+    }
+}
+
+/// Runs the listener thread and the flush thread of an aggregator node.
+pub(crate) struct NCAggregator {
+    /// Accepts the results of the other nodes.
+    listener_thread: JoinHandle<()>,
+    /// Sends the combined result to the server every aggregate_interval seconds.
+    flush_thread: JoinHandle<()>,
+    /// Stops the flush thread, it sends the combined result one last time.
+    stop_flush: mpsc::Sender<()>,
+    /// Stops the listener thread.
+    stopped: Arc<AtomicBool>,
+    /// The port of the listener, a connection to it wakes up the listener thread.
+    port: u16,
+}
+
+impl NCAggregator {
+    /// Opens the listener on the given port (0 = any free port), this is done before the node registers with the server,
+    /// so that the actual port can be sent to the server.
+    pub(crate) fn bind(port: u16) -> Result<TcpListener, NCError> {
+        debug!("NCAggregator::bind()");
+
+        Ok(TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))?)
+    }
+
+    /// Starts the listener thread and the flush thread with the given reduce() function.
+    /// The client must be registered with the server already.
+    pub(crate) fn start<P>(listener: TcpListener, reduce: ReduceFn<P>, nc_client: NCClient, config: &NCConfiguration) -> Result<Self, NCError>
+        where P: Serialize + DeserializeOwned + Send + 'static {
+        debug!("NCAggregator::start()");
+
+        let port = listener.local_addr()?.port();
+        info!("Aggregator listens on port: {}", port);
+
+        let state = Arc::new(NCAggregatorState {
+            partial: Mutex::new(NCPartialAggregate { aggregate: None, failed: Vec::new() }),
+            reduce,
+            nc_client: Mutex::new(nc_client),
+            nc_communicator: Mutex::new(NCCommunicator::new(config)?),
+            read_timeout: Duration::from_secs(config.heartbeat.max(1)),
+            max_frame_bytes: config.max_frame_bytes,
+        });
+
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let listener_state = state.clone();
+        let listener_stopped = stopped.clone();
+        let listener_thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if listener_stopped.load(Ordering::Relaxed) {
+                    break
+                }
+
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = listener_state.handle_connection(stream) {
+                            error!("Error in aggregator handle_connection(): {}", e);
+                        }
+                    }
+                    Err(e) => error!("Aggregator could not accept connection: {}", e),
+                }
+            }
+
+            debug!("Exit aggregator listener thread");
+        });
+
+        let (stop_flush, stop_receiver) = mpsc::channel::<()>();
+        let interval = Duration::from_secs(config.aggregate_interval);
+        let flush_thread = thread::spawn(move || {
+            loop {
+                let stop = !matches!(stop_receiver.recv_timeout(interval), Err(mpsc::RecvTimeoutError::Timeout));
+
+                if let Err(e) = state.flush() {
+                    error!("Could not send combined result to server: {}", e);
+                }
+
+                if stop {
+                    break
+                }
+            }
+
+            debug!("Exit aggregator flush thread");
+        });
+
+        Ok(NCAggregator { listener_thread, flush_thread, stop_flush, stopped, port })
+    }
+
+    /// Stops accepting results from the other nodes and sends the combined result to the server one last time.
+    pub(crate) fn stop(self) {
+        debug!("NCAggregator::stop()");
+
+        self.stopped.store(true, Ordering::Relaxed);
+        // Wake up the listener thread, it may be gone already.
+        let _ = TcpStream::connect((Ipv4Addr::LOCALHOST, self.port));
+
+        if self.listener_thread.join().is_err() {
+            error!("Aggregator listener thread panicked");
+        }
+
+        let _ = self.stop_flush.send(());
+
+        if self.flush_thread.join().is_err() {
+            error!("Aggregator flush thread panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(aggregate: u64, data: u64) -> Result<u64, NCError> {
+        if data == 0 {
+            Err(NCError::custom("zero"))
+        } else {
+            Ok(aggregate + data)
+        }
+    }
+
+    /// Answers every message with a ResultAck message and returns the Delegated and Aggregated messages.
+    fn fake_server(listener: TcpListener, messages: usize) -> JoinHandle<Vec<NCNodeMessage<u64, ()>>> {
+        thread::spawn(move || {
+            let mut nc_communicator = NCCommunicator::new(&NCConfiguration::default()).unwrap();
+
+            (0..messages).map(|_| {
+                let (mut stream, _) = listener.accept().unwrap();
+                let message: NCNodeMessage<u64, ()> = nc_communicator.nc_receive_data(&mut stream).unwrap();
+                let answer: NCServerMessage<(), (), ()> = NCServerMessage::ResultAck { job_finished: false };
+                nc_communicator.nc_send_data2(&answer, &mut stream).unwrap();
+                message
+            }).collect()
+        })
+    }
+
+    fn client_for(listener: &TcpListener) -> NCClient {
+        let config = NCConfiguration { port: listener.local_addr().unwrap().port(), ..Default::default() };
+        NCClient::connect(&config).unwrap()
+    }
+
+    #[test]
+    fn test_aggregate_and_flush() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = NCConfiguration { aggregate_interval: 3600, ..Default::default() };
+        let aggregator = NCAggregator::start(NCAggregator::bind(0).unwrap(), add, client_for(&server), &config).unwrap();
+        let fake_server = fake_server(server.try_clone().unwrap(), 4);
+
+        let aggregator_addr = SocketAddr::from(([127, 0, 0, 1], aggregator.port));
+        let mut worker = client_for(&server);
+
+        for (chunk_id, data) in [(1, 10u64), (2, 20), (3, 12)] {
+            worker.submit_to_aggregator(aggregator_addr, chunk_id, &data).unwrap();
+        }
+
+        // The last result is sent to the server when the aggregator stops
+        aggregator.stop();
+
+        let messages = fake_server.join().unwrap();
+        assert!(matches!(messages[0], NCNodeMessage::Delegated(_, _, 1)));
+        assert!(matches!(messages[2], NCNodeMessage::Delegated(_, _, 3)));
+
+        match &messages[3] {
+            NCNodeMessage::Aggregated(_, chunks, data) => {
+                assert_eq!(chunks, &vec![1, 2, 3]);
+                assert_eq!(*data, Some(42));
+            }
+            message => panic!("Unexpected message: {:?}", message),
+        }
+    }
+
+    #[test]
+    fn test_reduce_failed() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let state = NCAggregatorState {
+            partial: Mutex::new(NCPartialAggregate { aggregate: None, failed: Vec::new() }),
+            reduce: add,
+            nc_client: Mutex::new(client_for(&server)),
+            nc_communicator: Mutex::new(NCCommunicator::new(&NCConfiguration::default()).unwrap()),
+            read_timeout: Duration::from_secs(1),
+            max_frame_bytes: u64::MAX,
+        };
+
+        state.merge(vec![1], 10).unwrap();
+        state.merge(vec![2], 0).unwrap();
+        state.merge(vec![3], 5).unwrap();
+
+        // The server can't be reached, everything is kept
+        drop(server);
+        assert!(state.flush().is_err());
+        assert_eq!(state.partial.lock().unwrap().failed, vec![1, 2]);
+        assert_eq!(state.partial.lock().unwrap().aggregate, Some((vec![3], 5)));
+
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        *state.nc_client.lock().unwrap() = client_for(&server);
+        let fake_server = fake_server(server.try_clone().unwrap(), 2);
+        state.flush().unwrap();
+
+        let messages = fake_server.join().unwrap();
+        assert!(matches!(&messages[0], NCNodeMessage::Aggregated(_, chunks, None) if chunks == &vec![1, 2]));
+        assert!(matches!(&messages[1], NCNodeMessage::Aggregated(_, chunks, Some(5)) if chunks == &vec![3]));
+        assert!(state.partial.lock().unwrap().aggregate.is_none());
+    }
+}
@@ -0,0 +1,209 @@
+//! This module contains the artifacts: auxiliary files like debug images or logs that the user code on the node attaches to a chunk
+//! with [`NCProcessContext::attach_artifact()`](crate::NCProcessContext::attach_artifact).
+//! After the server has acknowledged the result of the chunk (or the report that it has failed) the node sends every artifact
+//! in its own NCNodeMessage::Artifact message.
+//! The server stores it in `artifacts_dir/chunk_id/name` and sends a [`NCProgressEvent::ArtifactStored`](crate::NCProgressEvent::ArtifactStored) event.
+//!
+//! Artifacts are only sent and stored if artifacts_dir is set in the NCConfiguration (of the node and of the server).
+//! An artifact that is bigger than max_artifact_bytes, has an invalid name or goes beyond max_artifacts_per_chunk is dropped
+//! with a warning, the chunk itself doesn't fail.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::{debug, warn};
+
+use crate::nc_config::NCConfiguration;
+use crate::nc_error::NCError;
+use crate::array2d::ChunkID;
+
+/// An artifact: its name and its data.
+pub(crate) type Artifact = (String, Vec<u8>);
+
+/// Checks that the name can be used as a file name inside the folder of the chunk.
+pub(crate) fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > 255 || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+        Err(format!("invalid artifact name: '{}'", name))
+    } else {
+        Ok(())
+    }
+}
+
+/// The artifacts of the chunk that is currently processed on the node, the clones share them.
+#[derive(Debug, Clone)]
+pub(crate) struct NCArtifacts {
+    /// False if artifacts_dir is not set in the NCConfiguration.
+    enabled: bool,
+    /// Maximum size of one artifact in bytes.
+    max_bytes: u64,
+    /// Maximum number of artifacts per chunk.
+    max_count: usize,
+    /// The attached artifacts.
+    artifacts: Arc<Mutex<Vec<Artifact>>>,
+}
+
+impl NCArtifacts {
+    /// Creates a new empty list with the limits from the given configuration.
+    pub(crate) fn new(config: &NCConfiguration) -> Self {
+        NCArtifacts {
+            enabled: config.artifacts_dir.is_some(),
+            max_bytes: config.max_artifact_bytes,
+            max_count: config.max_artifacts_per_chunk,
+            artifacts: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// A list that drops every artifact, for chunks whose artifacts are not sent (offline batches, dry run).
+    pub(crate) fn disabled() -> Self {
+        NCArtifacts::new(&NCConfiguration { artifacts_dir: None, ..Default::default() })
+    }
+
+    /// A new empty list with the same limits, for the next chunk.
+    pub(crate) fn for_next_chunk(&self) -> Self {
+        NCArtifacts { artifacts: Arc::new(Mutex::new(Vec::new())), ..self.clone() }
+    }
+
+    /// Attaches the artifact, returns false (with a warning) if it has been dropped.
+    pub(crate) fn attach(&self, name: &str, data: Vec<u8>) -> bool {
+        debug!("NCArtifacts::attach()");
+
+        if !self.enabled {
+            debug!("Artifact '{}' dropped, artifacts are not sent", name);
+            return false
+        }
+
+        if let Err(problem) = check_name(name) {
+            warn!("Artifact dropped: {}", problem);
+            return false
+        }
+
+        if data.len() as u64 > self.max_bytes {
+            warn!("Artifact '{}' dropped, it has {} bytes, max_artifact_bytes: {}", name, data.len(), self.max_bytes);
+            return false
+        }
+
+        let mut artifacts = match self.artifacts.lock() {
+            Ok(artifacts) => artifacts,
+            Err(_) => return false,
+        };
+
+        if artifacts.len() >= self.max_count {
+            warn!("Artifact '{}' dropped, the chunk has already {} artifacts (max_artifacts_per_chunk)", name, artifacts.len());
+            return false
+        }
+
+        artifacts.push((name.to_string(), data));
+        true
+    }
+
+    /// Takes all the artifacts out of the list, in the order they have been attached.
+    pub(crate) fn take(&self) -> Vec<Artifact> {
+        self.artifacts.lock().map(|mut artifacts| std::mem::take(&mut *artifacts)).unwrap_or_default()
+    }
+}
+
+/// Stores the artifacts from the nodes on the server.
+#[derive(Debug)]
+pub(crate) struct NCArtifactStore {
+    /// The artifacts of each chunk are stored in a sub folder named after the chunk id.
+    dir: PathBuf,
+    /// Maximum size of one artifact in bytes.
+    max_bytes: u64,
+    /// Maximum number of artifacts per chunk.
+    max_count: usize,
+    /// The number of artifacts that have been stored for each chunk.
+    counts: Mutex<HashMap<ChunkID, usize>>,
+}
+
+impl NCArtifactStore {
+    /// Creates the store for artifacts_dir in the given configuration, [`None`] if it's not set.
+    pub(crate) fn new(config: &NCConfiguration) -> Option<Self> {
+        config.artifacts_dir.as_ref().map(|dir| NCArtifactStore {
+            dir: dir.clone(),
+            max_bytes: config.max_artifact_bytes,
+            max_count: config.max_artifacts_per_chunk,
+            counts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Writes the artifact to `dir/chunk_id/name`, an existing file with the same name is replaced.
+    /// Returns the path of the file, or [`None`] (with a warning) if the artifact has been dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::IOError`] if the file could not be written.
+    pub(crate) fn store(&self, chunk_id: ChunkID, name: &str, data: &[u8]) -> Result<Option<PathBuf>, NCError> {
+        debug!("NCArtifactStore::store()");
+
+        if let Err(problem) = check_name(name) {
+            warn!("Artifact for chunk {} dropped: {}", chunk_id, problem);
+            return Ok(None)
+        }
+
+        if data.len() as u64 > self.max_bytes {
+            warn!("Artifact '{}' for chunk {} dropped, it has {} bytes, max_artifact_bytes: {}", name, chunk_id, data.len(), self.max_bytes);
+            return Ok(None)
+        }
+
+        let mut counts = self.counts.lock()?;
+        let count = counts.entry(chunk_id).or_insert(0);
+
+        if *count >= self.max_count {
+            warn!("Artifact '{}' for chunk {} dropped, max_artifacts_per_chunk: {}", name, chunk_id, self.max_count);
+            return Ok(None)
+        }
+
+        let chunk_dir = self.dir.join(chunk_id.to_string());
+        fs::create_dir_all(&chunk_dir)?;
+        let path = chunk_dir.join(name);
+        write_file(&path, data)?;
+        *count += 1;
+
+        Ok(Some(path))
+    }
+}
+
+/// Writes the data to a temporary file first, so that a half written artifact never has the final name.
+fn write_file(path: &Path, data: &[u8]) -> Result<(), NCError> {
+    let tmp_path = path.with_extension("tmp-artifact");
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_artifacts() {
+        let dir = std::env::temp_dir().join(format!("nc_artifacts_{}", std::process::id()));
+        let config = NCConfiguration { artifacts_dir: Some(dir.clone()), max_artifact_bytes: 4, max_artifacts_per_chunk: 2, ..Default::default() };
+
+        // Node
+        let artifacts = NCArtifacts::new(&config);
+        assert!(artifacts.attach("a.log", vec![1, 2, 3]));
+        assert!(!artifacts.attach("big.png", vec![0; 5]));
+        assert!(!artifacts.attach("../escape", vec![1]));
+        assert!(artifacts.attach("b.log", vec![4]));
+        assert!(!artifacts.attach("c.log", vec![5]));
+        assert_eq!(artifacts.for_next_chunk().take(), Vec::new());
+        assert_eq!(artifacts.take(), vec![("a.log".to_string(), vec![1, 2, 3]), ("b.log".to_string(), vec![4])]);
+        assert!(!NCArtifacts::new(&NCConfiguration::default()).attach("a.log", vec![1]));
+
+        // Server
+        let store = NCArtifactStore::new(&config).unwrap();
+        assert_eq!(store.store(7, "a.log", &[1, 2, 3]).unwrap(), Some(dir.join("7").join("a.log")));
+        assert_eq!(fs::read(dir.join("7").join("a.log")).unwrap(), vec![1, 2, 3]);
+        assert_eq!(store.store(7, "big.png", &[0; 5]).unwrap(), None);
+        assert_eq!(store.store(7, "..", &[1]).unwrap(), None);
+        assert!(store.store(7, "b.log", &[4]).unwrap().is_some());
+        assert_eq!(store.store(7, "c.log", &[5]).unwrap(), None);
+        assert!(store.store(8, "c.log", &[5]).unwrap().is_some());
+        assert!(NCArtifactStore::new(&NCConfiguration::default()).is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
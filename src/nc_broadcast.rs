@@ -0,0 +1,88 @@
+//! This module contains the NCBroadcastHandle, it sends custom messages from the server program to all nodes of a group
+//! (see node_tags in the NCConfiguration) while the server is running. Like every custom message they are delivered the next time
+//! a node asks for new data and the NCNode trait method process_custom_message() is called with them:
+//!
+//! ```no_run
+//! use node_crunch::{NCConfiguration, NCServer, NCServerStarter, NCBroadcastHandle};
+//!
+//! fn run<S: NCServer<CustomMessageT = f64> + Send + 'static>(my_server: S) {
+//!     let mut server_starter = NCServerStarter::new(NCConfiguration::default());
+//!     // The messages have the type CustomMessageT of the server
+//!     let broadcast: NCBroadcastHandle<f64> = server_starter.broadcast_handle();
+//!
+//!     std::thread::spawn(move || {
+//!         // Later, for example when a better step size has been found
+//!         let num_of_nodes = broadcast.broadcast_to("gpu", 0.25).unwrap();
+//!         println!("Sent to {} gpu nodes, groups: {:?}", num_of_nodes, broadcast.group_sizes().unwrap());
+//!     });
+//!
+//!     server_starter.start(my_server).unwrap();
+//! }
+//! ```
+//!
+//! Nodes and other tools can do the same with [`NCClient::send_custom_to_group()`](crate::NCClient::send_custom_to_group).
+
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+
+use crate::nc_error::NCError;
+
+/// The part of the server that sends messages to groups, implemented by the server process.
+pub(crate) trait NCBroadcastJob<M>: Send + Sync {
+    /// See [`NCBroadcastHandle::broadcast_to()`].
+    fn broadcast_to(&self, tag: &str, message: M) -> Result<usize, NCError>;
+    /// See [`NCBroadcastHandle::group_sizes()`].
+    fn group_sizes(&self) -> Result<Vec<(String, u64)>, NCError>;
+}
+
+/// Sends custom messages to the nodes of a group while the server is running, see [`NCServerStarter::broadcast_handle()`](crate::NCServerStarter::broadcast_handle).
+/// The handle can be cloned and used from other threads.
+pub struct NCBroadcastHandle<M> {
+    /// The running job, [`None`] before start() and after the job is done.
+    job: Arc<Mutex<Option<Arc<dyn NCBroadcastJob<M>>>>>,
+}
+
+impl<M> Clone for NCBroadcastHandle<M> {
+    fn clone(&self) -> Self {
+        NCBroadcastHandle { job: self.job.clone() }
+    }
+}
+
+impl<M> Default for NCBroadcastHandle<M> {
+    fn default() -> Self {
+        NCBroadcastHandle { job: Arc::new(Mutex::new(None)) }
+    }
+}
+
+impl<M> NCBroadcastHandle<M> {
+    /// Sets (or clears) the running job.
+    pub(crate) fn set_job(&self, job: Option<Arc<dyn NCBroadcastJob<M>>>) -> Result<(), NCError> {
+        *self.job.lock()? = job;
+        Ok(())
+    }
+
+    /// Returns the running job.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::ServerNotRunning`] error before start() and after the job is done.
+    fn job(&self) -> Result<Arc<dyn NCBroadcastJob<M>>, NCError> {
+        self.job.lock()?.clone().ok_or(NCError::ServerNotRunning)
+    }
+
+    /// Queues the message for every node of the group with the given tag and returns the number of these nodes.
+    /// Nodes that join the group later don't get the message.
+    pub fn broadcast_to(&self, tag: &str, message: M) -> Result<usize, NCError> {
+        debug!("NCBroadcastHandle::broadcast_to()");
+
+        self.job()?.broadcast_to(tag, message)
+    }
+
+    /// Returns every group and its number of nodes sorted by tag, this includes inactive nodes.
+    pub fn group_sizes(&self) -> Result<Vec<(String, u64)>, NCError> {
+        debug!("NCBroadcastHandle::group_sizes()");
+
+        self.job()?.group_sizes()
+    }
+}
@@ -0,0 +1,242 @@
+//! This module contains the checkpoint file for the server.
+//! If checkpoint_file is set in the NCConfiguration, the server calls the NCServer trait method checkpoint() every
+//! checkpoint_interval seconds and at the end of the job and writes the [`ChunkList`] it returns to disk.
+//! The data of the chunks is stored as bincode bytes, so the state of the chunks (done, assigned, failed, ...) can be inspected
+//! without knowing the data type, see the chunk_inspect example. With [`NCCheckpoint::restore()`] the chunk list can be
+//! loaded again when the server is restarted.
+//!
+//! The server adds the nodes that are registered (with what they have contributed) and the offline batches that wait for results.
+//! When it's started again with the same checkpoint file it takes both over: a node that keeps sending messages with its old id
+//! is registered again and keeps its numbers in the job summary, but not its chunks, they are free again. The chunks of the
+//! offline batches stay assigned, so their results can still be imported.
+//!
+//! The checkpoint of a sampled run (sample_fraction in the NCConfiguration) also contains the sample, see the nc_sample module.
+//! The chunks that have run out of attempts (max_chunk_attempts in the NCConfiguration) are in it as well, a restarted server
+//! doesn't send them again, see the nc_job_summary module.
+
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use log::debug;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+use crate::nc_error::NCError;
+use crate::nc_job_summary::{NCNodeContribution, NCDeadChunk};
+use crate::nc_offline::NCSavedOfflineBatch;
+use crate::nc_sample::NCSample;
+use crate::array2d::{ChunkList, ChunkID};
+
+/// The state of all the chunks at a given time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NCCheckpoint {
+    /// When the checkpoint has been created.
+    saved_at: SystemTime,
+    /// The chunk list with the serialized data of each chunk.
+    chunk_list: ChunkList<Vec<u8>>,
+    /// The nodes that were registered, see nodes().
+    nodes: Vec<NCNodeContribution>,
+    /// The offline batches that wait for results.
+    offline_batches: Vec<NCSavedOfflineBatch>,
+    /// The sample of a sampled run, see sample().
+    sample: Option<NCSample>,
+    /// The chunks on the dead-letter list, see dead_chunks().
+    dead_chunks: Vec<NCDeadChunk>,
+}
+
+impl NCCheckpoint {
+    /// Creates a new checkpoint for the given chunk list, the data of each chunk is serialized.
+    pub fn new<T: Serialize>(chunk_list: &ChunkList<T>) -> Result<Self, NCError> {
+        debug!("NCCheckpoint::new()");
+
+        Ok(NCCheckpoint {
+            saved_at: SystemTime::now(),
+            chunk_list: chunk_list.try_map(|data| bincode::serialize(data).map_err(NCError::Serialize))?,
+            nodes: Vec::new(),
+            offline_batches: Vec::new(),
+            sample: None,
+            dead_chunks: Vec::new(),
+        })
+    }
+
+    /// Adds the state of the server, this is done by the server before the checkpoint is written.
+    #[cfg(feature = "net")]
+    pub(crate) fn set_server_state(&mut self, nodes: Vec<NCNodeContribution>, offline_batches: Vec<NCSavedOfflineBatch>) {
+        self.nodes = nodes;
+        self.offline_batches = offline_batches;
+    }
+
+    /// Sets the sample of a sampled run, this is done by the server before the checkpoint is written.
+    #[cfg(feature = "net")]
+    pub(crate) fn set_sample(&mut self, sample: Option<NCSample>) {
+        self.sample = sample;
+    }
+
+    /// Sets the dead-letter list, this is done by the server before the checkpoint is written.
+    #[cfg(feature = "net")]
+    pub(crate) fn set_dead_chunks(&mut self, dead_chunks: Vec<NCDeadChunk>) {
+        self.dead_chunks = dead_chunks;
+    }
+
+    /// The offline batches that wait for results, see set_server_state().
+    #[cfg(feature = "net")]
+    pub(crate) fn offline_batches(&self) -> &[NCSavedOfflineBatch] {
+        &self.offline_batches
+    }
+
+    /// Sets the time of the checkpoint, this is done by the standby server when it applies an update (see the nc_replication module).
+    #[cfg(feature = "net")]
+    pub(crate) fn set_saved_at(&mut self, saved_at: SystemTime) {
+        self.saved_at = saved_at;
+    }
+
+    /// The chunk list with the serialized data, the standby server applies the updates from the server to it.
+    #[cfg(feature = "net")]
+    pub(crate) fn chunk_list_mut(&mut self) -> &mut ChunkList<Vec<u8>> {
+        &mut self.chunk_list
+    }
+
+    /// Returns the state of the server, see set_server_state().
+    #[cfg(feature = "net")]
+    pub(crate) fn into_server_state(self) -> (Vec<NCNodeContribution>, Vec<NCSavedOfflineBatch>) {
+        (self.nodes, self.offline_batches)
+    }
+
+    /// Writes the checkpoint to the given file. A temporary file is written first and then renamed,
+    /// so a copy taken while the server is running is always complete.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), NCError> {
+        debug!("NCCheckpoint::save()");
+
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+
+        fs::write(&tmp_path, bincode::serialize(self).map_err(NCError::Serialize)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Reads the checkpoint from the given file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, NCError> {
+        debug!("NCCheckpoint::load()");
+
+        let data = fs::read(path)?;
+        bincode::deserialize(&data).map_err(NCError::Deserialize)
+    }
+
+    /// When the checkpoint has been created.
+    pub fn saved_at(&self) -> SystemTime {
+        self.saved_at
+    }
+
+    /// The chunk list with the serialized data, for inspecting the state of the chunks.
+    pub fn chunk_list(&self) -> &ChunkList<Vec<u8>> {
+        &self.chunk_list
+    }
+
+    /// The nodes that were registered when the checkpoint was written and what they had contributed to the job,
+    /// the ones without a result have zero results.
+    pub fn nodes(&self) -> &[NCNodeContribution] {
+        &self.nodes
+    }
+
+    /// The sample if the checkpoint is from a sampled run, [`None`] for a full run.
+    pub fn sample(&self) -> Option<NCSample> {
+        self.sample
+    }
+
+    /// The chunks that had run out of attempts when the checkpoint was written, the server doesn't send them again after a restart.
+    pub fn dead_chunks(&self) -> &[NCDeadChunk] {
+        &self.dead_chunks
+    }
+
+    /// The chunks that have been exported to offline batches and wait for their results.
+    pub fn exported_chunks(&self) -> Vec<ChunkID> {
+        self.offline_batches.iter().flat_map(|batch| batch.chunks.iter().copied()).collect()
+    }
+
+    /// Returns the chunk list with the data deserialized again, for example when the server is restarted.
+    /// The chunks that were assigned to a node or in processing are free again, since their assignments died with the old server,
+    /// see [`ChunkList::reset_unfinished_except()`]. Only the exported chunks of offline batches stay assigned.
+    pub fn restore<T: DeserializeOwned>(&self) -> Result<ChunkList<T>, NCError> {
+        debug!("NCCheckpoint::restore()");
+
+        let mut chunk_list = self.chunk_list.try_map(|data| bincode::deserialize(data).map_err(NCError::Deserialize))?;
+        chunk_list.reset_unfinished_except(&self.exported_chunks());
+        Ok(chunk_list)
+    }
+}
+
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use crate::array2d::ChunkStatus;
+    use crate::nc_node_info::NodeID;
+
+    #[test]
+    fn test_save_restore() {
+        let path = std::env::temp_dir().join("nc_test_checkpoint.bin");
+        let node_id = NodeID::random();
+        let mut chunk_list = ChunkList::new();
+
+        chunk_list.push("first".to_string());
+        chunk_list.push("second".to_string());
+        chunk_list.push("third".to_string());
+        chunk_list.assign_next_chunk(node_id);
+        chunk_list.assign_next_chunk(node_id);
+        chunk_list.chunk_sent(1);
+        chunk_list.chunk_empty(0);
+
+        let mut checkpoint = NCCheckpoint::new(&chunk_list).unwrap();
+        let dead_chunk = NCDeadChunk { chunk_id: 2, attempts: 5, last_error: "timeout".to_string() };
+        checkpoint.set_dead_chunks(vec![dead_chunk.clone()]);
+        checkpoint.save(&path).unwrap();
+        let checkpoint = NCCheckpoint::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(checkpoint.dead_chunks().to_vec(), vec![dead_chunk]);
+
+        // The raw state is kept for inspection
+        let statuses: Vec<ChunkStatus> = checkpoint.chunk_list().chunks().iter().map(|chunk| chunk.status()).collect();
+        assert_eq!(statuses, vec![ChunkStatus::Finished, ChunkStatus::Processing, ChunkStatus::Empty]);
+        assert_eq!(checkpoint.chunk_list().assigned_before(SystemTime::now()).count(), 1);
+        assert!(checkpoint.saved_at() <= SystemTime::now());
+
+        let mut restored: ChunkList<String> = checkpoint.restore().unwrap();
+        assert_eq!(restored.stats(), (2, 0, 1));
+        assert_eq!(restored.get(1).data, "second");
+        assert!(restored.get(1).assigned_at().is_none());
+    }
+
+    #[test]
+    fn test_exported_chunks() {
+        let node_id = NodeID::random();
+        let batch_node_id = NodeID::random();
+        let mut chunk_list = ChunkList::new();
+
+        for i in 0..4 {
+            chunk_list.push(i);
+        }
+
+        chunk_list.assign_next_chunk(node_id);
+        chunk_list.assign_next_chunk(batch_node_id);
+        chunk_list.assign_next_chunk(batch_node_id);
+        chunk_list.chunk_sent(2);
+
+        let mut checkpoint = NCCheckpoint::new(&chunk_list).unwrap();
+        let contribution = NCNodeContribution { node_id, results: 3, result_bytes: 24, busy_time: Duration::from_secs(1) };
+        let batch = NCSavedOfflineBatch { batch_id: 7, node_id: batch_node_id, chunks: vec![1, 2], age: Duration::from_secs(60) };
+        checkpoint.set_server_state(vec![contribution.clone()], vec![batch.clone()]);
+        assert_eq!(checkpoint.exported_chunks(), vec![1, 2]);
+
+        // Only the chunk of the regular node is free again
+        let restored: ChunkList<u32> = checkpoint.restore().unwrap();
+        let statuses: Vec<ChunkStatus> = restored.chunks().iter().map(|chunk| chunk.status()).collect();
+        assert_eq!(statuses, vec![ChunkStatus::Empty, ChunkStatus::Assigned, ChunkStatus::Processing, ChunkStatus::Empty]);
+        assert!(restored.chunks()[2].is_processing(batch_node_id));
+
+        assert_eq!(checkpoint.nodes().to_vec(), vec![contribution.clone()]);
+        assert_eq!(checkpoint.into_server_state(), (vec![contribution], vec![batch]));
+    }
+}
@@ -0,0 +1,237 @@
+//! This module contains the chunk payload cache for the server.
+//! If cache_chunk_payloads is set in the NCConfiguration, the serialized data of every chunk that has been assigned to a node
+//! is kept until the chunk is done. If the chunk has to be sent again (heartbeat timeout, send failure or a retryable node error)
+//! the cached data is used and the NCServer trait method assign_chunk() is not called for it.
+//! The cache is bounded by chunk_cache_max_bytes, the least recently used payloads are dropped first.
+
+use std::collections::{HashMap, VecDeque};
+
+use log::debug;
+
+use crate::nc_config::NCConfiguration;
+use crate::array2d::ChunkID;
+
+/// Keeps the serialized data of the unfinished chunks.
+pub(crate) struct NCChunkCache {
+    /// The serialized data for each chunk.
+    payloads: HashMap<ChunkID, Vec<u8>>,
+    /// The chunk ids in the order of use, the least recently used one first.
+    order: VecDeque<ChunkID>,
+    /// Total size of all the payloads in bytes.
+    bytes: u64,
+    /// Maximum total size of all the payloads in bytes.
+    max_bytes: u64,
+    /// Chunks that have to be sent again.
+    redispatch: VecDeque<ChunkID>,
+    /// Number of chunks that have been sent again from the cache.
+    hits: u64,
+    /// Number of chunks that had to be sent again but were not in the cache anymore.
+    misses: u64,
+}
+
+impl NCChunkCache {
+    /// Creates a new empty cache with the settings from the given configuration.
+    pub(crate) fn new(config: &NCConfiguration) -> Self {
+        debug!("NCChunkCache::new()");
+
+        NCChunkCache {
+            payloads: HashMap::new(),
+            order: VecDeque::new(),
+            bytes: 0,
+            max_bytes: config.chunk_cache_max_bytes,
+            redispatch: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Stores the serialized data for the given chunk. If the cache is full the least recently used payloads are dropped.
+    /// Payloads that are bigger than chunk_cache_max_bytes are not stored at all.
+    pub(crate) fn insert(&mut self, chunk_id: ChunkID, payload: Vec<u8>) {
+        debug!("NCChunkCache::insert()");
+
+        self.remove(chunk_id);
+
+        let size = payload.len() as u64;
+
+        if size > self.max_bytes {
+            debug!("Payload for chunk {} is too big for the cache: {} bytes", chunk_id, size);
+            return
+        }
+
+        while self.bytes + size > self.max_bytes {
+            match self.order.front().copied() {
+                Some(old_chunk_id) => self.remove(old_chunk_id),
+                None => break,
+            }
+        }
+
+        self.bytes += size;
+        self.payloads.insert(chunk_id, payload);
+        self.order.push_back(chunk_id);
+    }
+
+    /// Drops the payload of the given chunk, usually because the chunk is done.
+    pub(crate) fn remove(&mut self, chunk_id: ChunkID) {
+        if let Some(payload) = self.payloads.remove(&chunk_id) {
+            self.bytes -= payload.len() as u64;
+            self.order.retain(|other| *other != chunk_id);
+        }
+    }
+
+    /// The given chunk has to be sent again, this is done the next time a node needs data.
+    /// If the payload is not in the cache, the chunk counts as a miss and assign_chunk() has to prepare the data again.
+    pub(crate) fn redispatch(&mut self, chunk_id: ChunkID) {
+        debug!("NCChunkCache::redispatch()");
+
+        if self.payloads.contains_key(&chunk_id) {
+            if !self.redispatch.contains(&chunk_id) {
+                self.redispatch.push_back(chunk_id)
+            }
+        } else {
+            self.misses += 1
+        }
+    }
+
+    /// Returns the next chunk that has to be sent again together with its payload.
+    /// If max_bytes is given the first chunk whose payload fits is taken, if none fits the chunks are taken in order anyway.
+    /// The reassign function is called with the chunk id, if it returns false the chunk doesn't need to be sent again
+    /// and its payload is dropped.
+    pub(crate) fn next_redispatch<F: FnMut(ChunkID) -> bool>(&mut self, max_bytes: Option<u64>, mut reassign: F) -> Option<(ChunkID, Vec<u8>)> {
+        debug!("NCChunkCache::next_redispatch()");
+
+        if let Some(max_bytes) = max_bytes {
+            let payloads = &self.payloads;
+            let fitting = self.redispatch.iter().position(|chunk_id| payloads.get(chunk_id).is_some_and(|payload| payload.len() as u64 <= max_bytes));
+
+            if let Some(chunk_id) = fitting.and_then(|index| self.redispatch.remove(index)) {
+                self.redispatch.push_front(chunk_id);
+            }
+        }
+
+        while let Some(chunk_id) = self.redispatch.pop_front() {
+            if !self.payloads.contains_key(&chunk_id) {
+                // Has been dropped in the meantime
+                self.misses += 1;
+                continue
+            }
+
+            if !reassign(chunk_id) {
+                self.remove(chunk_id);
+                continue
+            }
+
+            self.hits += 1;
+            self.order.retain(|other| *other != chunk_id);
+            self.order.push_back(chunk_id);
+            return Some((chunk_id, self.payloads[&chunk_id].clone()))
+        }
+
+        None
+    }
+
+    /// Number of chunks that have been sent again from the cache.
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Number of chunks that had to be sent again but were not in the cache anymore.
+    pub(crate) fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_cache_for_test(max_bytes: u64) -> NCChunkCache {
+        let config = NCConfiguration { cache_chunk_payloads: true, chunk_cache_max_bytes: max_bytes, ..Default::default() };
+        NCChunkCache::new(&config)
+    }
+
+    #[test]
+    fn test_redispatch() {
+        let mut chunk_cache = chunk_cache_for_test(100);
+
+        chunk_cache.insert(0, vec![0; 10]);
+        chunk_cache.insert(1, vec![1; 10]);
+        assert!(chunk_cache.next_redispatch(None, |_| true).is_none());
+
+        chunk_cache.redispatch(1);
+        chunk_cache.redispatch(1);
+        chunk_cache.redispatch(2);
+        assert_eq!(chunk_cache.next_redispatch(None, |_| true), Some((1, vec![1; 10])));
+        assert!(chunk_cache.next_redispatch(None, |_| true).is_none());
+        assert_eq!(chunk_cache.hits(), 1);
+        assert_eq!(chunk_cache.misses(), 1);
+
+        // Chunk is done
+        chunk_cache.remove(1);
+        chunk_cache.redispatch(1);
+        assert!(chunk_cache.next_redispatch(None, |_| true).is_none());
+        assert_eq!(chunk_cache.misses(), 2);
+        assert_eq!(chunk_cache.bytes, 10);
+    }
+
+    #[test]
+    fn test_reassign_refused() {
+        let mut chunk_cache = chunk_cache_for_test(100);
+
+        chunk_cache.insert(0, vec![0; 10]);
+        chunk_cache.insert(1, vec![1; 10]);
+        chunk_cache.redispatch(0);
+        chunk_cache.redispatch(1);
+
+        assert_eq!(chunk_cache.next_redispatch(None, |chunk_id| chunk_id != 0), Some((1, vec![1; 10])));
+        assert!(!chunk_cache.payloads.contains_key(&0));
+        assert_eq!(chunk_cache.hits(), 1);
+        assert_eq!(chunk_cache.misses(), 0);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut chunk_cache = chunk_cache_for_test(30);
+
+        chunk_cache.insert(0, vec![0; 10]);
+        chunk_cache.insert(1, vec![1; 10]);
+        chunk_cache.insert(2, vec![2; 10]);
+
+        // Chunk 0 is used again, so chunk 1 is the least recently used one
+        chunk_cache.redispatch(0);
+        assert!(chunk_cache.next_redispatch(None, |_| true).is_some());
+        chunk_cache.insert(3, vec![3; 10]);
+        assert_eq!(chunk_cache.bytes, 30);
+        assert!(!chunk_cache.payloads.contains_key(&1));
+
+        // Too big for the cache
+        chunk_cache.insert(4, vec![4; 31]);
+        assert!(!chunk_cache.payloads.contains_key(&4));
+        assert_eq!(chunk_cache.bytes, 30);
+
+        // Dropped while waiting to be sent again
+        chunk_cache.redispatch(2);
+        chunk_cache.insert(5, vec![5; 30]);
+        assert!(chunk_cache.next_redispatch(None, |_| true).is_none());
+        assert_eq!(chunk_cache.misses(), 1);
+        assert_eq!(chunk_cache.order, vec![5]);
+    }
+
+    #[test]
+    fn test_redispatch_max_bytes() {
+        let mut chunk_cache = chunk_cache_for_test(100);
+
+        chunk_cache.insert(0, vec![0; 30]);
+        chunk_cache.insert(1, vec![1; 10]);
+        chunk_cache.insert(2, vec![2; 20]);
+        chunk_cache.redispatch(0);
+        chunk_cache.redispatch(1);
+        chunk_cache.redispatch(2);
+
+        // The first chunk that fits, then in order if nothing fits
+        assert_eq!(chunk_cache.next_redispatch(Some(25), |_| true).map(|(chunk_id, _)| chunk_id), Some(1));
+        assert_eq!(chunk_cache.next_redispatch(Some(5), |_| true).map(|(chunk_id, _)| chunk_id), Some(0));
+        assert_eq!(chunk_cache.next_redispatch(Some(5), |_| true).map(|(chunk_id, _)| chunk_id), Some(2));
+        assert!(chunk_cache.next_redispatch(Some(5), |_| true).is_none());
+    }
+}
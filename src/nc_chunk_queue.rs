@@ -0,0 +1,365 @@
+//! This module contains a chunk queue for jobs whose chunks are not known up front, for example an adaptive subdivision
+//! where the result of a chunk decides if it's split into more chunks.
+//!
+//! The [`ChunkQueueHandle`] can be cloned and used from any thread (or from the NCServer trait method process_data_from_node())
+//! to enqueue new chunks while the job is running. The NCServer trait method assign_chunk() just calls
+//! [`ChunkQueueHandle::assign_chunk()`]: the job is finished when no chunk is pending or assigned anymore and
+//! [`ChunkQueueHandle::seal()`] has been called.
+//!
+//! If the NCServer trait method chunk_queue_waker() returns the [`ChunkQueueWaker`] of the queue, a node that would get a
+//! NCJobStatus::Waiting waits at the server for up to long_poll_ms (see [`NCConfiguration`](crate::NCConfiguration))
+//! and gets the new chunk right away when one is enqueued.
+
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::Duration;
+
+use log::debug;
+
+use crate::nc_error::NCError;
+use crate::nc_node_info::NodeID;
+use crate::nc_server::ChunkAssignment;
+use crate::array2d::{ChunkList, ChunkID, ChunkMeta};
+
+/// Wakes up the nodes that wait at the server for new chunks, see the NCServer trait method chunk_queue_waker().
+/// It counts the changes of the queue, so that a change between looking at the queue and waiting is not missed.
+#[derive(Debug, Clone, Default)]
+pub struct ChunkQueueWaker {
+    changes: Arc<(Mutex<u64>, Condvar)>,
+}
+
+impl ChunkQueueWaker {
+    /// Creates a new waker, usually it's created by the [`ChunkQueueHandle`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wakes up all the nodes that wait at the server, they ask the NCServer trait method assign_chunk() again.
+    /// Call this if a custom scheduler has new work.
+    pub fn wake(&self) -> Result<(), NCError> {
+        debug!("ChunkQueueWaker::wake()");
+
+        let (changes, condvar) = &*self.changes;
+        *changes.lock()? += 1;
+        condvar.notify_all();
+
+        Ok(())
+    }
+
+    /// Returns the number of changes so far, pass it to wait().
+    pub(crate) fn changes(&self) -> Result<u64, NCError> {
+        Ok(*self.changes.0.lock()?)
+    }
+
+    /// Waits until there has been a change since the given number of changes or the timeout is over.
+    /// Returns true if there has been a change.
+    pub(crate) fn wait(&self, since: u64, timeout: Duration) -> Result<bool, NCError> {
+        debug!("ChunkQueueWaker::wait()");
+
+        let (changes, condvar) = &*self.changes;
+        let (changes, _) = condvar.wait_timeout_while(changes.lock()?, timeout, |changes| *changes == since)
+            .map_err(|_| NCError::poisoned::<u64>("ChunkQueueWaker::wait"))?;
+
+        Ok(*changes != since)
+    }
+}
+
+/// The chunks and the state of the queue.
+#[derive(Debug)]
+struct ChunkQueue<T> {
+    /// All the chunks that have been enqueued so far.
+    chunk_list: ChunkList<T>,
+    /// No more chunks are expected, except for the ones that are enqueued while the current chunks are processed.
+    sealed: bool,
+    /// assign_chunk() has returned ChunkAssignment::Finished.
+    finished: bool,
+}
+
+/// A thread safe handle to a queue of chunks that can grow while the job is running, see the [`nc_chunk_queue`](crate::nc_chunk_queue) module.
+/// All clones share the same queue.
+#[derive(Debug)]
+pub struct ChunkQueueHandle<T> {
+    queue: Arc<Mutex<ChunkQueue<T>>>,
+    waker: ChunkQueueWaker,
+}
+
+impl<T> Clone for ChunkQueueHandle<T> {
+    fn clone(&self) -> Self {
+        ChunkQueueHandle { queue: self.queue.clone(), waker: self.waker.clone() }
+    }
+}
+
+impl<T> Default for ChunkQueueHandle<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> ChunkQueueHandle<T> {
+    /// Creates a new queue without any chunks.
+    pub fn new() -> Self {
+        Self::from_chunk_list(ChunkList::new())
+    }
+
+    /// Creates a new queue with the given chunks, for example from a checkpoint.
+    /// All the chunks are handed out in one phase, so the phases of the chunk list are not used.
+    pub fn from_chunk_list(chunk_list: ChunkList<T>) -> Self {
+        ChunkQueueHandle {
+            queue: Arc::new(Mutex::new(ChunkQueue { chunk_list, sealed: false, finished: false })),
+            waker: ChunkQueueWaker::new(),
+        }
+    }
+
+    /// Returns the waker of this queue, the NCServer trait method chunk_queue_waker() should return it.
+    pub fn waker(&self) -> ChunkQueueWaker {
+        self.waker.clone()
+    }
+
+    /// Adds a new chunk with the given data and returns its id. Waiting nodes are woken up.
+    /// Chunks can still be enqueued after seal(), for example the children of a chunk in process_data_from_node().
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::ChunkQueueFinished`] error if the job has already finished, the chunk would never be processed.
+    pub fn enqueue(&self, data: T) -> Result<ChunkID, NCError> {
+        self.with_chunks_checked(|chunk_list| {
+            chunk_list.push(data);
+            (chunk_list.chunks().len() - 1) as ChunkID
+        })
+    }
+
+    /// Same as [`enqueue()`](ChunkQueueHandle::enqueue), but with metadata for the chunk, see [`ChunkList::push_with_meta()`].
+    pub fn enqueue_with_meta(&self, data: T, meta: ChunkMeta) -> Result<ChunkID, NCError> {
+        self.with_chunks_checked(|chunk_list| {
+            chunk_list.push_with_meta(data, meta);
+            (chunk_list.chunks().len() - 1) as ChunkID
+        })
+    }
+
+    /// No more chunks are expected from outside of the job: once all chunks are done the job is finished.
+    /// Waiting nodes are woken up, so that they can exit.
+    pub fn seal(&self) -> Result<(), NCError> {
+        debug!("ChunkQueueHandle::seal()");
+
+        self.queue.lock()?.sealed = true;
+        self.waker.wake()
+    }
+
+    /// Returns true if seal() has been called.
+    pub fn is_sealed(&self) -> Result<bool, NCError> {
+        Ok(self.queue.lock()?.sealed)
+    }
+
+    /// Calls the given function with the chunk list of the queue, for example to set a chunk to finished or to call
+    /// [`ChunkList::heartbeat_timeout()`]. Chunks that are pushed here are handed out too and waiting nodes are woken up afterwards.
+    /// Setting a chunk to finished and pushing its children in the same call makes sure that the job can't finish in between.
+    pub fn with_chunks<R, F: FnOnce(&mut ChunkList<T>) -> R>(&self, f: F) -> Result<R, NCError> {
+        let result = f(&mut self.queue.lock()?.chunk_list);
+        self.waker.wake()?;
+
+        Ok(result)
+    }
+
+    /// Same as with_chunks(), but returns an error if the job has already finished.
+    fn with_chunks_checked<R, F: FnOnce(&mut ChunkList<T>) -> R>(&self, f: F) -> Result<R, NCError> {
+        let result = {
+            let mut queue = self.queue.lock()?;
+
+            if queue.finished {
+                return Err(NCError::ChunkQueueFinished)
+            }
+
+            f(&mut queue.chunk_list)
+        };
+
+        self.waker.wake()?;
+
+        Ok(result)
+    }
+}
+
+impl<T: Clone> ChunkQueueHandle<T> {
+    /// Assigns the next pending chunk to the given node, this is what the NCServer trait method assign_chunk() usually returns.
+    /// - ChunkAssignment::Assigned: the id and a copy of the data of the chunk.
+    /// - ChunkAssignment::Finished: the queue is sealed and all chunks are done (finished or failed).
+    /// - ChunkAssignment::Waiting: in all other cases, new chunks may still be enqueued.
+    pub fn assign_chunk(&self, node_id: NodeID) -> Result<ChunkAssignment<T>, NCError> {
+        debug!("ChunkQueueHandle::assign_chunk()");
+
+        let mut queue = self.queue.lock()?;
+
+        if let Some((chunk_id, chunk)) = queue.chunk_list.assign_next_chunk(node_id) {
+            return Ok(ChunkAssignment::Assigned(chunk_id, chunk.data.clone()))
+        }
+
+        let (empty, processing, _) = queue.chunk_list.stats();
+
+        if queue.sealed && empty == 0 && processing == 0 {
+            queue.finished = true;
+            Ok(ChunkAssignment::Finished)
+        } else {
+            Ok(ChunkAssignment::Waiting)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use std::time::Instant;
+
+    use crate::nc_config::NCConfiguration;
+    use crate::nc_server::NCServer;
+    use crate::nc_node::{NCNode, NodeResult};
+    use crate::nc_local;
+
+    #[test]
+    fn test_assign_and_seal() {
+        let queue = ChunkQueueHandle::new();
+        let node_id = NodeID::random();
+
+        assert_eq!(queue.assign_chunk(node_id).unwrap(), ChunkAssignment::Waiting);
+        assert_eq!(queue.enqueue(7).unwrap(), 0);
+        assert_eq!(queue.assign_chunk(node_id).unwrap(), ChunkAssignment::Assigned(0, 7));
+
+        // The assigned chunk is not done yet
+        queue.seal().unwrap();
+        assert_eq!(queue.assign_chunk(node_id).unwrap(), ChunkAssignment::Waiting);
+
+        // The children of the chunk are enqueued together with setting it to finished
+        queue.with_chunks(|chunk_list| {
+            chunk_list.get(0).set_finished();
+            chunk_list.push(8);
+        }).unwrap();
+
+        assert_eq!(queue.assign_chunk(node_id).unwrap(), ChunkAssignment::Assigned(1, 8));
+        queue.with_chunks(|chunk_list| chunk_list.get(1).set_finished()).unwrap();
+        assert_eq!(queue.assign_chunk(node_id).unwrap(), ChunkAssignment::Finished);
+        assert!(matches!(queue.enqueue(9), Err(NCError::ChunkQueueFinished)));
+    }
+
+    #[test]
+    fn test_waker() {
+        let queue = ChunkQueueHandle::new();
+        let waker = queue.waker();
+        let changes = waker.changes().unwrap();
+
+        assert!(!waker.wait(changes, Duration::from_millis(10)).unwrap());
+
+        let producer = queue.clone();
+        let enqueue_thread = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            producer.enqueue(1).unwrap();
+        });
+
+        assert!(waker.wait(changes, Duration::from_secs(10)).unwrap());
+        enqueue_thread.join().unwrap();
+
+        // A change before wait() is not missed
+        let changes = waker.changes().unwrap();
+        queue.seal().unwrap();
+        assert!(waker.wait(changes, Duration::ZERO).unwrap());
+    }
+
+    /// A square of the unit square: depth and position.
+    type Cell = (u32, u32, u32);
+
+    /// Splits every cell into four cells until the maximum depth is reached.
+    struct SubdivisionServer {
+        queue: ChunkQueueHandle<(ChunkID, Cell)>,
+        max_depth: u32,
+        cells: Vec<Cell>,
+    }
+
+    impl NCServer for SubdivisionServer {
+        type InitialDataT = ();
+        type NewDataT = (ChunkID, Cell);
+        type ProcessedDataT = (ChunkID, Cell, bool);
+        type CustomMessageT = ();
+
+        fn assign_chunk(&mut self, node_id: NodeID) -> Result<ChunkAssignment<(ChunkID, Cell)>, NCError> {
+            self.queue.assign_chunk(node_id)
+        }
+
+        fn chunk_queue_waker(&mut self) -> Option<ChunkQueueWaker> {
+            Some(self.queue.waker())
+        }
+
+        fn process_data_from_node(&mut self, node_id: NodeID, data: &(ChunkID, Cell, bool)) -> Result<(), NCError> {
+            let (chunk_id, (depth, x, y), split) = *data;
+            let cells = &mut self.cells;
+            let max_depth = self.max_depth;
+
+            self.queue.with_chunks(|chunk_list| {
+                let chunk = chunk_list.get(chunk_id as usize);
+
+                if !chunk.is_processing(node_id) {
+                    return
+                }
+
+                chunk.set_finished();
+                cells.push((depth, x, y));
+
+                if split && depth < max_depth {
+                    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let child_id = chunk_list.chunks().len() as ChunkID;
+                        chunk_list.push((child_id, (depth + 1, 2 * x + dx, 2 * y + dy)));
+                    }
+                }
+            })
+        }
+
+        fn heartbeat_timeout(&mut self, nodes: Vec<NodeID>) {
+            let _ = self.queue.with_chunks(|chunk_list| chunk_list.heartbeat_timeout(&nodes));
+        }
+
+        fn finish_job(&mut self) {}
+    }
+
+    /// Only the cells on the diagonal are split.
+    struct DiagonalNode;
+
+    impl NCNode for DiagonalNode {
+        type InitialDataT = ();
+        type NewDataT = (ChunkID, Cell);
+        type ProcessedDataT = (ChunkID, Cell, bool);
+        type CustomMessageT = ();
+
+        fn process_data_from_server(&mut self, data: &(ChunkID, Cell)) -> Result<NodeResult<(ChunkID, Cell, bool)>, NCError> {
+            let (chunk_id, (depth, x, y)) = *data;
+            Ok(NodeResult::Data((chunk_id, (depth, x, y), x == y)))
+        }
+    }
+
+    #[test]
+    fn test_adaptive_subdivision() {
+        let queue = ChunkQueueHandle::new();
+        let nc_server = SubdivisionServer { queue: queue.clone(), max_depth: 4, cells: Vec::new() };
+        // Nodes that get a NCJobStatus::Waiting would sleep for a long time, so the job is only fast if they are woken up.
+        let config = NCConfiguration { port: 0, delay_request_data: 30, heartbeat: 1, long_poll_ms: 5000, ..Default::default() };
+        let start = Instant::now();
+
+        // The first chunk arrives after the nodes have started.
+        let enqueue_thread = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            queue.enqueue((0, (0, 0, 0))).unwrap();
+            queue.seal().unwrap();
+        });
+
+        let (nc_server, _) = nc_local::run(&config, nc_server, |_| DiagonalNode, 3).unwrap();
+        enqueue_thread.join().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(20));
+
+        let mut cells = nc_server.cells.clone();
+        cells.sort_unstable();
+        cells.dedup();
+        assert_eq!(cells.len(), nc_server.cells.len());
+
+        for depth in 0..=4 {
+            // Every diagonal cell of the previous depth has four children, two of them are on the diagonal again.
+            let expected = if depth == 0 { 1 } else { 4 * (1 << (depth - 1)) };
+            assert_eq!(cells.iter().filter(|cell| cell.0 == depth).count(), expected, "depth: {}", depth);
+        }
+    }
+}
@@ -0,0 +1,993 @@
+//! This module contains the NCClient, the low level building block that speaks the node protocol.
+//! The NCNode trait and the NCNodeStarter are built on top of it, but it can also be used directly
+//! to write small tools (an admin command line tool for example) that talk to the server without
+//! implementing the NCNode trait.
+//! Every method opens a new tcp connection to the server, sends one message and optionally waits for the answer.
+//! Authentication and encryption are the same as for regular nodes, so the key in the NCConfiguration must match.
+//! Admin commands are additionally signed with the admin key, see the [`nc_admin`](crate::nc_admin) module.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+use log::{error, warn, info, debug};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::nc_error::{NCError, NCJobError, retry_transient};
+use crate::nc_server::{NCServerMessage, NCJobStatus, NCServerStatistics};
+use crate::nc_node::{NCNodeMessage, NCWorkHint};
+use crate::nc_config::{NCConfiguration, NCSharedSettings};
+use crate::nc_node_info::NodeID;
+use crate::nc_communicator::{NCCommunicator, NCCodec, NCServerAddr};
+use crate::nc_dictionary::dictionary_id;
+use crate::nc_admin::{NCAdminMessage, NCAdminCommand};
+use crate::nc_resources::NCResourceReport;
+use crate::nc_replication::NCReplicationUpdate;
+use crate::nc_clock::{NCClockSkew, unix_micros};
+use crate::nc_job_summary::NCChunkAttempt;
+use crate::array2d::{ChunkID, ChunkMeta};
+#[cfg(feature = "ed25519")]
+use crate::nc_keys::NCKeypair;
+
+/// Low level connection to the server.
+pub struct NCClient {
+    /// IP address or host name and port of the server.
+    server_addr: Arc<Mutex<NCServerAddr>>,
+    /// The node id for this client, will be set in the method register().
+    node_id: NodeID,
+    /// Handles all the communication
+    nc_communicator: NCCommunicator,
+    /// Key for signing admin messages.
+    admin_key: String,
+    /// The codecs this client can use, sent to the server in the method register().
+    codecs: Vec<NCCodec>,
+    /// The build of this node, sent to the server in the method register().
+    node_build: Option<String>,
+    /// The port of the aggregator on this node and its tag, sent to the server in the method register().
+    aggregator: (Option<u16>, Option<String>),
+    /// The groups of this node, sent to the server in the method register().
+    node_tags: Vec<String>,
+    /// The settings that must match the server, sent to the server in the method register().
+    shared_settings: NCSharedSettings,
+    /// Set when the server has answered a message with NCServerMessage::ResultAck and job_finished set, see job_finished().
+    /// Shared with the clients from share(), so that a result sent in the background counts too.
+    job_finished: Arc<AtomicBool>,
+    /// Signs the results, see the nc_keys module.
+    #[cfg(feature = "ed25519")]
+    keypair: Option<NCKeypair>,
+}
+
+impl NCClient {
+    /// Create a new NCClient for the server given in the configuration (address and port).
+    /// No message is sent here, the tcp connection is opened for every message separately.
+    /// The address can also be a host name, it's resolved for every connection (see [`NCServerAddr`]).
+    /// With the address "mdns:" the server is searched once here (mdns feature, see the nc_mdns module).
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::DnsResolve`] error if the address in the configuration is empty, with "mdns:" the errors of the search
+    /// ([`NCError::Mdns`] or [`NCError::MdnsAmbiguous`]).
+    /// Returns a [`NCError::InvalidKey`] error if the encryption key or one of the previous keys in the configuration is not valid.
+    pub fn connect(config: &NCConfiguration) -> Result<Self, NCError> {
+        debug!("NCClient::connect()");
+
+        config.check_keys()?;
+        let server_addr = NCServerAddr::from_config(config)?;
+
+        Self::with_server_addr(Arc::new(Mutex::new(server_addr)), config)
+    }
+
+    /// Create a new NCClient that shares the server address with other clients.
+    /// This is used by the node so that the main loop and the heartbeat thread
+    /// both follow a server migration.
+    pub(crate) fn with_server_addr(server_addr: Arc<Mutex<NCServerAddr>>, config: &NCConfiguration) -> Result<Self, NCError> {
+        debug!("NCClient::with_server_addr()");
+
+        let mut nc_communicator = NCCommunicator::new(config)?;
+        nc_communicator.set_proxy(config.proxy.clone());
+
+        Ok(NCClient {
+            server_addr,
+            node_id: NodeID::unset(),
+            nc_communicator,
+            admin_key: config.admin_key.clone(),
+            codecs: config.codecs(),
+            node_build: config.node_build.clone(),
+            aggregator: (config.aggregator_port, config.aggregator_tag.clone()),
+            node_tags: config.node_tags.clone(),
+            shared_settings: config.shared_settings(),
+            job_finished: Arc::new(AtomicBool::new(false)),
+            #[cfg(feature = "ed25519")]
+            keypair: None,
+        })
+    }
+
+    /// Returns the shared server address, so that other clients can be created with the method with_server_addr().
+    pub(crate) fn shared_server_addr(&self) -> Arc<Mutex<NCServerAddr>> {
+        self.server_addr.clone()
+    }
+
+    /// Returns the node id that the server has assigned to this client.
+    pub fn node_id(&self) -> NodeID {
+        self.node_id
+    }
+
+    /// Sets the node id, this is needed if the client should act on behalf of an already registered node.
+    pub fn set_node_id(&mut self, node_id: NodeID) {
+        self.node_id = node_id
+    }
+
+    /// Returns true if the server has said with the answer to a result (NCServerMessage::ResultAck) that the job is finished.
+    /// Then the node can exit right away, without asking for new data first.
+    pub fn job_finished(&self) -> bool {
+        self.job_finished.load(Ordering::Relaxed)
+    }
+
+    /// Returns the address and port of the server.
+    pub fn server_addr(&self) -> Result<NCServerAddr, NCError> {
+        Ok(self.server_addr.lock()?.clone())
+    }
+
+    /// Change the address and port of the server, for example when the server sends a
+    /// NCServerMessage::NewServer message. The server can be an IP address or a host name.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::DnsResolve`] error if the server is empty.
+    pub fn set_server(&mut self, server: &str, port: u16) -> Result<(), NCError> {
+        debug!("NCClient::set_server()");
+
+        if server.trim().is_empty() {
+            return Err(NCError::DnsResolve(server.to_string()))
+        }
+
+        let mut server_addr = self.server_addr.lock()?;
+        *server_addr = NCServerAddr::new(server.trim(), port);
+        // The new server doesn't know the dictionary of the old one
+        self.nc_communicator.use_dictionary(None)
+    }
+
+    /// Send the given message to the server, don't wait for an answer.
+    fn send<P: Serialize, C: Serialize>(&mut self, message: NCNodeMessage<P, C>) -> Result<(), NCError> {
+        retry_transient("send()", message.can_resend(), || {
+            let server_addr = self.server_addr()?;
+            self.nc_communicator.nc_send_data(&message, &server_addr)
+        })
+    }
+
+    /// Send the given message to the server and wait for the answer.
+    /// Transient errors (see [`NCError::is_transient()`]) are retried a few times right away if the connection could not be opened
+    /// or if the message can be sent twice (for example GetStatistics), other errors are returned.
+    fn send_receive<P: Serialize, C: Serialize, I: DeserializeOwned, N: DeserializeOwned, M: DeserializeOwned>(&mut self, message: NCNodeMessage<P, C>)
+        -> Result<NCServerMessage<I, N, M>, NCError> {
+        retry_transient("send_receive()", message.can_resend(), || self.send_receive_once(&message))
+    }
+
+    /// Sends the message once and waits for the answer, see send_receive().
+    /// If this client still uses an old encryption key the server answers with a NCServerMessage::RotateKey message,
+    /// then the new key is used from now on and the message is sent again. The same for a NCServerMessage::UnknownDictionary answer,
+    /// then the message is sent again without the zstd dictionary.
+    /// A NCServerMessage::Rejected answer is returned as the error for its reason, see [`NCError::is_fatal_rejection()`].
+    fn send_receive_once<P: Serialize, C: Serialize, I: DeserializeOwned, N: DeserializeOwned, M: DeserializeOwned>(&mut self, message: &NCNodeMessage<P, C>)
+        -> Result<NCServerMessage<I, N, M>, NCError> {
+        let server_addr = self.server_addr()?;
+        let answer = self.nc_communicator.nc_send_receive_data(message, &server_addr)?;
+
+        match answer {
+            NCServerMessage::RotateKey(key) => {
+                info!("Server has sent a new encryption key, will use it from now on");
+                self.nc_communicator.rotate_key(&key)?;
+                let answer = self.nc_communicator.nc_send_receive_data(message, &server_addr)?;
+                self.check_rejected(answer)
+            }
+            NCServerMessage::UnknownDictionary(id) if self.nc_communicator.current_dictionary()? == Some(id) => {
+                warn!("Server doesn't know the zstd dictionary {:#010x}, the message is sent again without it", id);
+                self.nc_communicator.use_dictionary(None)?;
+                let answer = self.nc_communicator.nc_send_receive_data(message, &server_addr)?;
+                self.check_rejected(answer)
+            }
+            answer => self.check_rejected(answer)
+        }
+    }
+
+    /// Remembers that the server has finished the job, see job_finished().
+    fn set_job_finished(&self, job_finished: bool) {
+        if job_finished {
+            debug!("Server has acknowledged the message, the job is finished");
+            self.job_finished.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Turns a NCServerMessage::Rejected answer into the error for its reason, other answers are returned as they are.
+    fn check_rejected<I, N, M>(&self, answer: NCServerMessage<I, N, M>) -> Result<NCServerMessage<I, N, M>, NCError> {
+        match answer {
+            NCServerMessage::Rejected { reason, message, retry_after } => {
+                let error = NCError::rejected(reason, message, retry_after, &self.codecs);
+                warn!("Server has rejected the message: {}", error);
+                Err(error)
+            }
+            answer => Ok(answer)
+        }
+    }
+
+    /// Create a new client for the same server and node id that shares the encryption keys with this client.
+    /// If one of them gets a new key from the server, all of them use it.
+    pub(crate) fn share(&self) -> Self {
+        debug!("NCClient::share()");
+
+        NCClient {
+            server_addr: self.server_addr.clone(),
+            node_id: self.node_id,
+            nc_communicator: self.nc_communicator.share(),
+            admin_key: self.admin_key.clone(),
+            codecs: self.codecs.clone(),
+            node_build: self.node_build.clone(),
+            aggregator: self.aggregator.clone(),
+            node_tags: self.node_tags.clone(),
+            shared_settings: self.shared_settings.clone(),
+            job_finished: self.job_finished.clone(),
+            #[cfg(feature = "ed25519")]
+            keypair: self.keypair.clone(),
+        }
+    }
+
+    /// Send the NCNodeMessage::Register message together with the codecs, the build, the aggregator settings, the groups and the settings
+    /// that must match (see [`NCConfiguration::diff()`]) of this client to the server.
+    /// On success the new node id and the codec chosen by the server are stored in this client and the optional initial data is returned.
+    ///
+    /// # Errors
+    ///
+    /// If the server rejects the registration (NCServerMessage::Rejected) the error for the reason is returned:
+    /// If the server doesn't have a codec in common with this client a [`NCError::NoCommonCodec`] error is returned.
+    /// If the server doesn't run the job from job_id in the NCConfiguration a [`NCError::UnknownJob`] error is returned.
+    /// If settings like encrypt don't match the server a [`NCError::ConfigMismatch`] error is returned.
+    /// If the server can't decrypt or decode the message a [`NCError::WrongKey`] or [`NCError::VersionMismatch`] error is returned.
+    /// If the server is busy or the job is paused a [`NCError::ServerBusy`] or [`NCError::Paused`] error is returned, the node should try again later.
+    /// If the server doesn't respond with a NCServerMessage::InitialData message a [`NCError::ServerMsgMismatch`] error is returned.
+    pub fn register<InitialDataT: DeserializeOwned>(&mut self) -> Result<Option<InitialDataT>, NCError> {
+        debug!("NCClient::register()");
+
+        let (aggregator_port, aggregator_tag) = self.aggregator.clone();
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::Register(self.codecs.clone(), self.node_build.clone(), aggregator_port, aggregator_tag,
+            self.node_tags.clone(), Some(self.shared_settings.clone()));
+        self.registered(message)
+    }
+
+    /// Same as register(), but with the NCNodeMessage::Rejoin message: this client is a new process for the given node id with the given
+    /// incarnation, which must be higher than the one of the old process (see node_id_file in the NCConfiguration).
+    /// The server gives the chunks of the old process to other nodes and this client keeps the node id. If the server knows a higher
+    /// or the same incarnation for the node id, this client gets a new node id. Use node_id() afterwards to see which one it is.
+    ///
+    /// # Errors
+    ///
+    /// The same as for register().
+    pub fn register_again<InitialDataT: DeserializeOwned>(&mut self, node_id: NodeID, incarnation: u32) -> Result<Option<InitialDataT>, NCError> {
+        debug!("NCClient::register_again()");
+
+        let (aggregator_port, aggregator_tag) = self.aggregator.clone();
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::Rejoin(node_id, incarnation, self.codecs.clone(), self.node_build.clone(), aggregator_port,
+            aggregator_tag, self.node_tags.clone(), Some(self.shared_settings.clone()));
+        self.registered(message)
+    }
+
+    /// Sends the Register or Rejoin message and handles the answer, see register().
+    fn registered<InitialDataT: DeserializeOwned>(&mut self, message: NCNodeMessage<(), ()>) -> Result<Option<InitialDataT>, NCError> {
+        let answer: NCServerMessage<InitialDataT, (), ()> = self.send_receive(message)?;
+
+        match answer {
+            NCServerMessage::InitialData(node_id, initial_data, codec) => {
+                debug!("Codec chosen by server: {:?}", codec);
+                self.node_id = node_id;
+                self.nc_communicator.set_codec(codec);
+                Ok(initial_data)
+            }
+            // The rejections of older servers
+            NCServerMessage::NoCommonCodec(server_codecs) => {
+                error!("Error in register(), no common codec, server: {:?}, node: {:?}", server_codecs, self.codecs);
+                Err(NCError::NoCommonCodec(server_codecs, self.codecs.clone()))
+            }
+            NCServerMessage::UnknownJob(job_id) => {
+                error!("Error in register(), the server doesn't run the job: '{}'", job_id);
+                Err(NCError::UnknownJob(job_id))
+            }
+            NCServerMessage::ConfigMismatch(diffs) => {
+                let error = NCError::ConfigMismatch(diffs);
+                error!("Error in register(): {}", error);
+                Err(error)
+            }
+            _ => {
+                error!("Error in register(), NCServerMessage mismatch, expected: InitialData");
+                Err(NCError::ServerMsgMismatch)
+            }
+        }
+    }
+
+    /// Measures the clock skew to the server with the NCNodeMessage::ClockProbe message and sends the time stamps back with the
+    /// NCNodeMessage::ClockReport message, so that the server knows the skew too (see the [`nc_clock`](crate::nc_clock) module).
+    /// This is done right after register().
+    ///
+    /// # Errors
+    ///
+    /// If the server doesn't respond with a NCServerMessage::ClockTime message a [`NCError::ServerMsgMismatch`] error is returned.
+    pub fn measure_clock(&mut self) -> Result<NCClockSkew, NCError> {
+        debug!("NCClient::measure_clock()");
+
+        let node_sent = unix_micros(SystemTime::now());
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::ClockProbe(self.node_id, node_sent);
+        let answer: NCServerMessage<(), (), ()> = self.send_receive(message)?;
+        let node_received = unix_micros(SystemTime::now());
+
+        let server_time = match answer {
+            NCServerMessage::ClockTime(server_time) => server_time,
+            _ => {
+                error!("Error in measure_clock(), NCServerMessage mismatch, expected: ClockTime");
+                return Err(NCError::ServerMsgMismatch)
+            }
+        };
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::ClockReport(self.node_id, node_sent, server_time, node_received);
+        self.send(message)?;
+
+        Ok(NCClockSkew::from_timestamps(node_sent, server_time, node_received))
+    }
+
+    /// Send the NCNodeMessage::NeedsData message to the server and return the answer.
+    /// Usually this is a NCServerMessage::JobStatus message but the server may also send a custom message
+    /// or ask the node to move to a new server.
+    pub fn request_data<NewDataT: DeserializeOwned, CustomMessageT: DeserializeOwned>(&mut self) -> Result<NCServerMessage<(), NewDataT, CustomMessageT>, NCError> {
+        debug!("NCClient::request_data()");
+
+        self.request_data_with_hint(None)
+    }
+
+    /// Same as request_data(), but sends the given work hint with the NCNodeMessage::NeedsDataWithHint message.
+    /// Without a hint the NCNodeMessage::NeedsData message is sent, so this works with older servers too.
+    pub fn request_data_with_hint<NewDataT: DeserializeOwned, CustomMessageT: DeserializeOwned>(&mut self, work_hint: Option<NCWorkHint>) -> Result<NCServerMessage<(), NewDataT, CustomMessageT>, NCError> {
+        debug!("NCClient::request_data_with_hint()");
+
+        let message: NCNodeMessage<(), ()> = match work_hint {
+            Some(work_hint) => NCNodeMessage::NeedsDataWithHint(self.node_id, work_hint),
+            None => NCNodeMessage::NeedsData(self.node_id),
+        };
+        self.send_receive(message)
+    }
+
+    /// Send the NCNodeMessage::NeedsDataPrefetch message with the optional work hint to the server and return the answer without decoding it.
+    /// This is called from a separate thread while the node is still processing its current chunk, the answer is decoded later
+    /// with decode_prefetched(), so the user data doesn't have to be sent between threads.
+    pub(crate) fn request_data_prefetch(&mut self, work_hint: Option<NCWorkHint>) -> Result<Vec<u8>, NCError> {
+        debug!("NCClient::request_data_prefetch()");
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::NeedsDataPrefetch(self.node_id, work_hint);
+        let mut tcp_stream = self.nc_communicator.connect(&self.server_addr()?)?;
+
+        self.nc_communicator.nc_send_data2(&message, &mut tcp_stream)?;
+        NCCommunicator::nc_receive_frame(&mut tcp_stream, self.nc_communicator.max_frame_bytes())
+    }
+
+    /// Decodes the answer from request_data_prefetch(). If the server has sent a new encryption key, the key is used from now on
+    /// and the request is sent again.
+    pub(crate) fn decode_prefetched<NewDataT: DeserializeOwned, CustomMessageT: DeserializeOwned>(&mut self, data: &[u8], work_hint: Option<NCWorkHint>)
+        -> Result<NCServerMessage<(), NewDataT, CustomMessageT>, NCError> {
+        debug!("NCClient::decode_prefetched()");
+
+        match self.nc_communicator.nc_decode_message(data)? {
+            NCServerMessage::RotateKey(key) => {
+                info!("Server has sent a new encryption key, will use it from now on");
+                self.nc_communicator.rotate_key(&key)?;
+                let message: NCNodeMessage<(), ()> = NCNodeMessage::NeedsDataPrefetch(self.node_id, work_hint);
+                self.send_receive(message)
+            }
+            NCServerMessage::UnknownDictionary(id) if self.nc_communicator.current_dictionary()? == Some(id) => {
+                warn!("Server doesn't know the zstd dictionary {:#010x}, the request is sent again without it", id);
+                self.nc_communicator.use_dictionary(None)?;
+                let message: NCNodeMessage<(), ()> = NCNodeMessage::NeedsDataPrefetch(self.node_id, work_hint);
+                self.send_receive(message)
+            }
+            answer => Ok(answer)
+        }
+    }
+
+    /// Give back the chunk that has been fetched in advance without processing it using the NCNodeMessage::ReleaseChunk message.
+    /// The server calls the NCServer trait method chunk_send_failed(), so that another node gets the chunk.
+    ///
+    /// # Errors
+    ///
+    /// If the server doesn't respond with a NCServerMessage::ResultAck message a [`NCError::ServerMsgMismatch`] error is returned.
+    pub fn release_chunk(&mut self, chunk_id: ChunkID) -> Result<(), NCError> {
+        debug!("NCClient::release_chunk()");
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::ReleaseChunk(self.node_id, chunk_id);
+        self.send_receive_ack(message)
+    }
+
+    /// Send the processed data back to the server using the NCNodeMessage::HasData message.
+    /// This blocks until the server has put the result into its result queue, if the queue is full this may take a while.
+    ///
+    /// # Errors
+    ///
+    /// If the server doesn't respond with a NCServerMessage::ResultAck message a [`NCError::ServerMsgMismatch`] error is returned.
+    pub fn submit_result<ProcessedDataT: Serialize>(&mut self, data: ProcessedDataT) -> Result<(), NCError> {
+        debug!("NCClient::submit_result()");
+
+        self.submit_result_with_meta(data, None)
+    }
+
+    /// Same as submit_result(), but sends the metadata of the chunk back with the NCNodeMessage::HasDataWithMeta message
+    /// (see [`NCProcessContext::metadata()`](crate::NCProcessContext::metadata)).
+    /// Without metadata the NCNodeMessage::HasData message is sent, so this works with older servers too.
+    pub fn submit_result_with_meta<ProcessedDataT: Serialize>(&mut self, data: ProcessedDataT, meta: Option<ChunkMeta>) -> Result<(), NCError> {
+        debug!("NCClient::submit_result_with_meta()");
+
+        let message: NCNodeMessage<ProcessedDataT, ()> = match meta {
+            Some(meta) => NCNodeMessage::HasDataWithMeta(self.node_id, meta, data),
+            None => NCNodeMessage::HasData(self.node_id, data),
+        };
+        self.send_receive_ack(message)
+    }
+
+    /// Offers a result that has been cached on the node (see the [`nc_result_cache`](crate::nc_result_cache) module)
+    /// with the NCNodeMessage::HasCachedData message. Returns true if the server has taken it and false if it has the result
+    /// already or the chunk belongs to another node.
+    ///
+    /// # Errors
+    ///
+    /// If the server answers with anything else a [`NCError::ServerMsgMismatch`] error is returned.
+    pub(crate) fn offer_cached_result<ProcessedDataT: Serialize>(&mut self, chunk_id: ChunkID, meta: Option<ChunkMeta>, data: ProcessedDataT) -> Result<bool, NCError> {
+        debug!("NCClient::offer_cached_result()");
+
+        let message: NCNodeMessage<ProcessedDataT, ()> = NCNodeMessage::HasCachedData(self.node_id, chunk_id, meta, data);
+        let answer: NCServerMessage<(), (), ()> = self.send_receive(message)?;
+
+        match answer {
+            NCServerMessage::ResultAck { job_finished } => {
+                self.set_job_finished(job_finished);
+                Ok(true)
+            }
+            NCServerMessage::ResultRejected(job_error) => {
+                debug!("Server doesn't take the cached result of chunk {}: {}", chunk_id, job_error);
+                Ok(false)
+            }
+            _ => {
+                error!("Error in offer_cached_result(), NCServerMessage mismatch, expected: ResultAck or ResultRejected");
+                Err(NCError::ServerMsgMismatch)
+            }
+        }
+    }
+
+    /// Sets the keypair that signs the results, see submit_signed_result().
+    #[cfg(feature = "ed25519")]
+    pub fn set_keypair(&mut self, keypair: NCKeypair) {
+        self.keypair = Some(keypair)
+    }
+
+    /// Returns true if this client has a keypair and signs its results.
+    #[cfg(feature = "ed25519")]
+    pub(crate) fn has_keypair(&self) -> bool {
+        self.keypair.is_some()
+    }
+
+    /// Send the public key of the keypair to the server using the NCNodeMessage::RegisterKey message, this is done right after register().
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::Signature`] error if this client has no keypair, a [`NCError::Unauthorized`] error if the server doesn't accept the key
+    /// (for example because it's not in its keys file) and a [`NCError::ServerMsgMismatch`] error if the server sends anything else.
+    #[cfg(feature = "ed25519")]
+    pub fn register_key(&mut self) -> Result<(), NCError> {
+        debug!("NCClient::register_key()");
+
+        let public_key = self.keypair.as_ref().ok_or_else(|| NCError::Signature("no keypair".to_string()))?.public_key();
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::RegisterKey(self.node_id, public_key.as_bytes().to_vec());
+        let answer: NCServerMessage<(), (), ()> = self.send_receive(message)?;
+
+        match answer {
+            NCServerMessage::ResultAck { .. } => Ok(()),
+            NCServerMessage::Unauthorized => {
+                error!("Error in register_key(), the server doesn't accept the public key: {}", public_key);
+                Err(NCError::Unauthorized)
+            }
+            _ => {
+                error!("Error in register_key(), NCServerMessage mismatch, expected: ResultAck");
+                Err(NCError::ServerMsgMismatch)
+            }
+        }
+    }
+
+    /// Same as submit_result_with_meta(), but the serialized data and the chunk id are signed with the keypair of this client
+    /// and sent with the NCNodeMessage::HasSignedData message, see the [`nc_keys`](crate::nc_keys) module.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::Signature`] error if this client has no keypair.
+    /// If the server doesn't respond with a NCServerMessage::ResultAck message a [`NCError::ServerMsgMismatch`] error is returned.
+    #[cfg(feature = "ed25519")]
+    pub fn submit_signed_result<ProcessedDataT: Serialize>(&mut self, chunk_id: ChunkID, data: &ProcessedDataT, meta: Option<ChunkMeta>) -> Result<(), NCError> {
+        debug!("NCClient::submit_signed_result()");
+
+        let keypair = self.keypair.as_ref().ok_or_else(|| NCError::Signature("no keypair".to_string()))?;
+        let payload = bincode::serialize(data).map_err(NCError::Serialize)?;
+        let signature = keypair.sign(chunk_id, &payload);
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::HasSignedData(self.node_id, chunk_id, meta, signature, payload);
+        self.send_receive_ack(message)
+    }
+
+    /// Send the processed data of the given chunk to an aggregator using the NCNodeMessage::Aggregate message,
+    /// see the [`nc_aggregator`](crate::nc_aggregator) module.
+    ///
+    /// # Errors
+    ///
+    /// If the server doesn't hand the chunk over to the aggregator a [`NCError::Job`] error is returned,
+    /// then the data should be sent to the server with submit_result().
+    /// If the aggregator doesn't respond with a NCServerMessage::ResultAck message a [`NCError::ServerMsgMismatch`] error is returned.
+    pub fn submit_to_aggregator<ProcessedDataT: Serialize>(&mut self, aggregator: SocketAddr, chunk_id: ChunkID, data: &ProcessedDataT) -> Result<(), NCError> {
+        debug!("NCClient::submit_to_aggregator()");
+
+        let message: NCNodeMessage<&ProcessedDataT, ()> = NCNodeMessage::Aggregate(self.node_id, chunk_id, data);
+        let answer: NCServerMessage<(), (), ()> = self.nc_communicator.nc_send_receive_data(&message, &NCServerAddr::from(aggregator))?;
+
+        match answer {
+            NCServerMessage::ResultAck { .. } => Ok(()),
+            NCServerMessage::ResultRejected(job_error) => Err(NCError::Job(job_error)),
+            _ => {
+                error!("Error in submit_to_aggregator(), NCServerMessage mismatch, expected: ResultAck");
+                Err(NCError::ServerMsgMismatch)
+            }
+        }
+    }
+
+    /// Tell the server that this client (an aggregator) takes over the given chunk of the given node
+    /// using the NCNodeMessage::Delegated message. The server calls the NCServer trait method chunk_delegated().
+    ///
+    /// # Errors
+    ///
+    /// If the server refuses to hand the chunk over a [`NCError::Job`] error is returned.
+    /// If the server doesn't respond with a NCServerMessage::ResultAck message a [`NCError::ServerMsgMismatch`] error is returned.
+    pub fn delegate_chunk(&mut self, node_id: NodeID, chunk_id: ChunkID) -> Result<(), NCError> {
+        debug!("NCClient::delegate_chunk()");
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::Delegated(self.node_id, node_id, chunk_id);
+        let answer: NCServerMessage<(), (), ()> = self.send_receive(message)?;
+
+        match answer {
+            NCServerMessage::ResultAck { .. } => Ok(()),
+            NCServerMessage::ResultRejected(job_error) => Err(NCError::Job(job_error)),
+            _ => {
+                error!("Error in delegate_chunk(), NCServerMessage mismatch, expected: ResultAck");
+                Err(NCError::ServerMsgMismatch)
+            }
+        }
+    }
+
+    /// Send the combined result of the given chunks to the server using the NCNodeMessage::Aggregated message.
+    /// The server calls the NCServer trait method process_aggregate(). If data is [`None`] the chunks are given to a node again.
+    ///
+    /// # Errors
+    ///
+    /// If the server doesn't respond with a NCServerMessage::ResultAck message a [`NCError::ServerMsgMismatch`] error is returned.
+    pub fn submit_aggregate<ProcessedDataT: Serialize>(&mut self, chunks: &[ChunkID], data: Option<&ProcessedDataT>) -> Result<(), NCError> {
+        debug!("NCClient::submit_aggregate()");
+
+        let message: NCNodeMessage<&ProcessedDataT, ()> = NCNodeMessage::Aggregated(self.node_id, chunks.to_vec(), data);
+        self.send_receive_ack(message)
+    }
+
+    /// Tell the server that the current chunk didn't produce any data using the NCNodeMessage::Empty message.
+    /// The server calls the NCServer trait method chunk_empty().
+    ///
+    /// # Errors
+    ///
+    /// If the server doesn't respond with a NCServerMessage::ResultAck message a [`NCError::ServerMsgMismatch`] error is returned.
+    pub fn submit_empty(&mut self) -> Result<(), NCError> {
+        debug!("NCClient::submit_empty()");
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::Empty(self.node_id);
+        self.send_receive_ack(message)
+    }
+
+    /// Tell the server that the current chunk has been skipped for the given reason using the NCNodeMessage::Skip message.
+    /// The server calls the NCServer trait method chunk_skipped().
+    ///
+    /// # Errors
+    ///
+    /// If the server doesn't respond with a NCServerMessage::ResultAck message a [`NCError::ServerMsgMismatch`] error is returned.
+    pub fn submit_skip(&mut self, reason: String) -> Result<(), NCError> {
+        debug!("NCClient::submit_skip()");
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::Skip(self.node_id, reason);
+        self.send_receive_ack(message)
+    }
+
+    /// Send an artifact of the given chunk to the server using the NCNodeMessage::Artifact message, see the [`nc_artifacts`](crate::nc_artifacts) module.
+    ///
+    /// # Errors
+    ///
+    /// If the server doesn't respond with a NCServerMessage::ResultAck message a [`NCError::ServerMsgMismatch`] error is returned.
+    pub fn submit_artifact(&mut self, chunk_id: ChunkID, name: String, data: Vec<u8>) -> Result<(), NCError> {
+        debug!("NCClient::submit_artifact()");
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::Artifact(self.node_id, chunk_id, name, data);
+        self.send_receive_ack(message)
+    }
+
+    /// Send the given message to the server and expect a NCServerMessage::ResultAck message as answer.
+    /// If the answer says that the job is finished, job_finished() returns true from now on.
+    fn send_receive_ack<P: Serialize>(&mut self, message: NCNodeMessage<P, ()>) -> Result<(), NCError> {
+        let answer: NCServerMessage<(), (), ()> = self.send_receive(message)?;
+
+        match answer {
+            NCServerMessage::ResultAck { job_finished } => {
+                self.set_job_finished(job_finished);
+                Ok(())
+            }
+            _ => {
+                error!("NCServerMessage mismatch, expected: ResultAck");
+                Err(NCError::ServerMsgMismatch)
+            }
+        }
+    }
+
+    /// Tell the server that the data could not be processed using the NCNodeMessage::NodeFailed message.
+    /// The server calls the NCServer trait method process_node_error() with the given error.
+    pub fn report_failure(&mut self, error: NCJobError) -> Result<(), NCError> {
+        debug!("NCClient::report_failure()");
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::NodeFailed(self.node_id, error);
+        self.send(message)
+    }
+
+    /// Send a custom message to one node (`Some(node_id)`) or all nodes (`None`).
+    /// The server delivers it the next time the node asks for new data.
+    pub fn send_custom<CustomMessageT: Serialize>(&mut self, message: CustomMessageT, destination: Option<NodeID>) -> Result<(), NCError> {
+        debug!("NCClient::send_custom()");
+
+        let message: NCNodeMessage<(), CustomMessageT> = NCNodeMessage::CustomMessage(message, destination);
+        self.send(message)
+    }
+
+    /// Send a custom message to all nodes of the group with the given tag (see node_tags in the NCConfiguration).
+    /// The server delivers it the next time each of these nodes asks for new data.
+    pub fn send_custom_to_group<CustomMessageT: Serialize>(&mut self, message: CustomMessageT, tag: &str) -> Result<(), NCError> {
+        debug!("NCClient::send_custom_to_group()");
+
+        let message: NCNodeMessage<(), CustomMessageT> = NCNodeMessage::GroupMessage(message, tag.to_string());
+        self.send(message)
+    }
+
+    /// Changes the groups of this client. If it has already registered the server is told right away, the client leaves all its other groups.
+    pub fn set_tags(&mut self, tags: Vec<String>) -> Result<(), NCError> {
+        debug!("NCClient::set_tags()");
+
+        self.node_tags = tags.clone();
+
+        if self.node_id == NodeID::unset() {
+            return Ok(())
+        }
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::SetTags(self.node_id, tags);
+        self.send(message)
+    }
+
+    /// Send the NCNodeMessage::HeartBeat message to the server.
+    pub fn heartbeat(&mut self) -> Result<(), NCError> {
+        debug!("NCClient::heartbeat()");
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::HeartBeat(self.node_id, None);
+        self.send(message)
+    }
+
+    /// Send the NCNodeMessage::HeartBeat message together with the given resource report to the server.
+    pub fn heartbeat_with_resources(&mut self, resources: NCResourceReport) -> Result<(), NCError> {
+        debug!("NCClient::heartbeat_with_resources()");
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::HeartBeat(self.node_id, Some(resources));
+        self.send(message)
+    }
+
+    /// Send the NCNodeMessage::PollHeartBeat message (with the given resource report, if any) to the server.
+    /// Returns the chunks of this node that the server has revoked, see [`NCServerHandle::cancel_chunk()`](crate::NCServerHandle::cancel_chunk).
+    pub fn poll_heartbeat(&mut self, resources: Option<NCResourceReport>) -> Result<Vec<ChunkID>, NCError> {
+        debug!("NCClient::poll_heartbeat()");
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::PollHeartBeat(self.node_id, resources);
+
+        match self.send_receive(message)? {
+            NCServerMessage::<(), (), ()>::ChunksRevoked(chunks) => Ok(chunks),
+            _ => {
+                error!("Error in poll_heartbeat(), NCServerMessage mismatch, expected: ChunksRevoked");
+                Err(NCError::ServerMsgMismatch)
+            }
+        }
+    }
+
+    /// Tell the server with the NCNodeMessage::ChunkRevoked message that the given revoked chunk has been dropped without a result.
+    pub fn chunk_revoked(&mut self, chunk_id: ChunkID) -> Result<(), NCError> {
+        debug!("NCClient::chunk_revoked()");
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::ChunkRevoked(self.node_id, chunk_id);
+        self.send_receive_ack(message)
+    }
+
+    /// Compresses the messages with the zstd dictionary with the given id from now on (see the [`nc_dictionary`](crate::nc_dictionary) module),
+    /// [`None`]: without a dictionary. This affects all the clients that share the communicator. If this client doesn't have
+    /// the dictionary yet it asks the server for it with the NCNodeMessage::GetDictionary message.
+    ///
+    /// # Errors
+    ///
+    /// If the server doesn't have the dictionary a [`NCError::UnknownDictionary`] error is returned, if it doesn't answer
+    /// with the NCServerMessage::Dictionary message for the dictionary a [`NCError::ServerMsgMismatch`] error.
+    /// In both cases no dictionary is used.
+    pub(crate) fn use_dictionary(&mut self, id: Option<u32>) -> Result<(), NCError> {
+        debug!("NCClient::use_dictionary()");
+
+        if let Some(id) = id {
+            if self.nc_communicator.dictionary(id)?.is_none() {
+                self.nc_communicator.use_dictionary(None)?;
+                let message: NCNodeMessage<(), ()> = NCNodeMessage::GetDictionary(self.node_id, id);
+
+                match self.send_receive(message)? {
+                    NCServerMessage::<(), (), ()>::Dictionary(other, dictionary) if other == id && dictionary_id(&dictionary) == id => {
+                        info!("Got the zstd dictionary {:#010x} from the server, size: {} bytes", id, dictionary.len());
+                        self.nc_communicator.add_dictionary(dictionary)?;
+                    }
+                    NCServerMessage::UnknownDictionary(_) => return Err(NCError::UnknownDictionary(id)),
+                    _ => {
+                        error!("Error in use_dictionary(), NCServerMessage mismatch, expected: Dictionary");
+                        return Err(NCError::ServerMsgMismatch)
+                    }
+                }
+            }
+        }
+
+        self.nc_communicator.use_dictionary(id)
+    }
+
+    /// Send the NCNodeMessage::NodeMigrated message to the (new) server.
+    pub fn node_migrated(&mut self) -> Result<(), NCError> {
+        debug!("NCClient::node_migrated()");
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::NodeMigrated(self.node_id);
+        self.send(message)
+    }
+
+    /// Sign the admin command with the admin key and send it to the server.
+    /// Returns the answer from the server.
+    ///
+    /// # Errors
+    ///
+    /// If the server rejects the signature a [`NCError::Unauthorized`] error is returned.
+    pub fn admin(&mut self, command: NCAdminCommand) -> Result<NCServerMessage<(), (), ()>, NCError> {
+        debug!("NCClient::admin()");
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::Admin(NCAdminMessage::new(&self.admin_key, command)?);
+        let answer: NCServerMessage<(), (), ()> = self.send_receive(message)?;
+
+        match answer {
+            NCServerMessage::Unauthorized => {
+                error!("Error in admin(), the server rejected the admin key");
+                Err(NCError::Unauthorized)
+            }
+            answer => Ok(answer)
+        }
+    }
+
+    /// Send the admin command and expect a NCServerMessage::AdminAck message as answer.
+    fn admin_ack(&mut self, command: NCAdminCommand) -> Result<(), NCError> {
+        match self.admin(command)? {
+            NCServerMessage::AdminAck => Ok(()),
+            _ => {
+                error!("Error in admin_ack(), NCServerMessage mismatch, expected: AdminAck");
+                Err(NCError::ServerMsgMismatch)
+            }
+        }
+    }
+
+    /// Ask the server about the current state of the job with the NCAdminCommand::QueryStatus command.
+    /// The server does not call any of the NCServer trait methods for this request, so the answer is either
+    /// `NCJobStatus::Unfinished((), _)` or `NCJobStatus::Finished`.
+    pub fn query_status(&mut self) -> Result<NCJobStatus<()>, NCError> {
+        debug!("NCClient::query_status()");
+
+        match self.admin(NCAdminCommand::QueryStatus)? {
+            NCServerMessage::Status(job_status) => Ok(job_status),
+            _ => {
+                error!("Error in query_status(), NCServerMessage mismatch, expected: Status");
+                Err(NCError::ServerMsgMismatch)
+            }
+        }
+    }
+
+    /// Ask the server where and why the given chunk has failed before with the NCAdminCommand::ChunkHistory command.
+    /// The attempts are sorted from the oldest to the newest, the list is empty if the chunk hasn't been sent yet
+    /// or has been done with the first attempt.
+    pub fn chunk_history(&mut self, chunk_id: ChunkID) -> Result<Vec<NCChunkAttempt>, NCError> {
+        debug!("NCClient::chunk_history()");
+
+        match self.admin(NCAdminCommand::ChunkHistory(chunk_id))? {
+            NCServerMessage::ChunkHistory(attempts) => Ok(attempts),
+            _ => {
+                error!("Error in chunk_history(), NCServerMessage mismatch, expected: ChunkHistory");
+                Err(NCError::ServerMsgMismatch)
+            }
+        }
+    }
+
+    /// Abort the job with the given reason, the server calls finish_job() and exits.
+    pub fn abort_job(&mut self, reason: String) -> Result<(), NCError> {
+        debug!("NCClient::abort_job()");
+
+        self.admin_ack(NCAdminCommand::AbortJob(reason))
+    }
+
+    /// Pause the job, the nodes will not get any new data until the job is resumed.
+    pub fn pause_job(&mut self) -> Result<(), NCError> {
+        debug!("NCClient::pause_job()");
+
+        self.admin_ack(NCAdminCommand::PauseJob)
+    }
+
+    /// Resume a paused job.
+    pub fn resume_job(&mut self) -> Result<(), NCError> {
+        debug!("NCClient::resume_job()");
+
+        self.admin_ack(NCAdminCommand::ResumeJob)
+    }
+
+    /// Take the given chunk from the dead-letter list with the NCAdminCommand::ResetDeadChunk command, the server gives it to the nodes again.
+    pub fn reset_dead_chunk(&mut self, chunk_id: ChunkID) -> Result<(), NCError> {
+        debug!("NCClient::reset_dead_chunk()");
+
+        self.admin_ack(NCAdminCommand::ResetDeadChunk(chunk_id))
+    }
+
+    /// Disable the given node, it will not get any more data from the server.
+    pub fn disable_node(&mut self, node_id: NodeID) -> Result<(), NCError> {
+        debug!("NCClient::disable_node()");
+
+        self.admin_ack(NCAdminCommand::DisableNode(node_id))
+    }
+
+    /// Tell the server to use the given encryption key from now on. The nodes get the new key the next time they contact the server.
+    pub fn rotate_key(&mut self, key: &str) -> Result<(), NCError> {
+        debug!("NCClient::rotate_key()");
+
+        self.admin_ack(NCAdminCommand::RotateKey(key.to_string()))?;
+        self.nc_communicator.rotate_key(key)
+    }
+
+    /// Nodes that don't run the given build are asked to restart after their current chunk, [`None`] = every build is accepted.
+    pub fn set_required_node_build(&mut self, build: Option<String>) -> Result<(), NCError> {
+        debug!("NCClient::set_required_node_build()");
+
+        self.admin_ack(NCAdminCommand::SetRequiredNodeBuild(build))
+    }
+
+    /// Promote the standby server with the NCAdminCommand::Promote command, the port in the NCConfiguration must be the replication_port
+    /// of the standby. See the [`nc_replication`](crate::nc_replication) module.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::Job`] error if the standby has no state from the server yet.
+    pub fn promote(&mut self) -> Result<(), NCError> {
+        debug!("NCClient::promote()");
+
+        match self.admin(NCAdminCommand::Promote)? {
+            NCServerMessage::AdminAck => Ok(()),
+            NCServerMessage::ServerFailed(job_error) => Err(NCError::Job(job_error)),
+            _ => {
+                error!("Error in promote(), NCServerMessage mismatch, expected: AdminAck");
+                Err(NCError::ServerMsgMismatch)
+            }
+        }
+    }
+
+    /// Send the update to the standby server with the NCNodeMessage::Replicate message, see the nc_replication module.
+    pub(crate) fn replicate(&mut self, update: NCReplicationUpdate) -> Result<(), NCError> {
+        debug!("NCClient::replicate()");
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::Replicate(update);
+
+        match self.send_receive(message)? {
+            NCServerMessage::<(), (), ()>::ResultAck { .. } => Ok(()),
+            NCServerMessage::ServerFailed(job_error) => Err(NCError::Job(job_error)),
+            _ => {
+                error!("Error in replicate(), NCServerMessage mismatch, expected: ResultAck");
+                Err(NCError::ServerMsgMismatch)
+            }
+        }
+    }
+
+    /// Request some statistics from the server with the NCNodeMessage::GetStatistics message.
+    pub fn get_statistics(&mut self) -> Result<NCServerStatistics, NCError> {
+        debug!("NCClient::get_statistics()");
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::GetStatistics;
+        let answer: NCServerMessage<(), (), ()> = self.send_receive(message)?;
+
+        match answer {
+            NCServerMessage::Statistics(statistics) => Ok(statistics),
+            _ => {
+                error!("Error in get_statistics(), NCServerMessage mismatch, expected: Statistics");
+                Err(NCError::ServerMsgMismatch)
+            }
+        }
+    }
+
+    /// Tell the server to shut down with the NCNodeMessage::ShutDown message.
+    /// This aborts the job, the server calls finish_job() and exits.
+    /// If the client has an admin key (see the NCConfiguration) it sends the NCAdminCommand::ShutDown command instead and waits for
+    /// the acknowledgement, a server with an admin key rejects the unsigned message.
+    pub fn shut_down(&mut self) -> Result<(), NCError> {
+        debug!("NCClient::shut_down()");
+
+        if !self.admin_key.is_empty() {
+            return self.admin_ack(NCAdminCommand::ShutDown)
+        }
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::ShutDown;
+        self.send(message)
+    }
+
+    /// Tell the server that all nodes should move to a new server given by address and port.
+    /// With an admin key the NCAdminCommand::NewServer command is sent instead, like in shut_down().
+    pub fn new_server(&mut self, server: String, port: u16) -> Result<(), NCError> {
+        debug!("NCClient::new_server()");
+
+        if !self.admin_key.is_empty() {
+            return self.admin_ack(NCAdminCommand::NewServer(server, port))
+        }
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::NewServer(server, port);
+        self.send(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect() {
+        let config = NCConfiguration { address: "192.168.0.10".to_string(), port: 4040, ..Default::default() };
+        let nc_client = NCClient::connect(&config).unwrap();
+
+        assert_eq!(nc_client.server_addr().unwrap(), NCServerAddr::new("192.168.0.10", 4040));
+        assert_eq!(nc_client.node_id(), NodeID::unset());
+    }
+
+    #[test]
+    fn test_connect_invalid_address() {
+        let config = NCConfiguration { address: " ".to_string(), ..Default::default() };
+        assert!(matches!(NCClient::connect(&config), Err(NCError::DnsResolve(_))));
+
+        // A host name that doesn't exist fails when the first message is sent
+        let config = NCConfiguration { address: "node-crunch.invalid".to_string(), ..Default::default() };
+        let mut nc_client = NCClient::connect(&config).unwrap();
+        assert!(matches!(nc_client.register::<()>(), Err(NCError::DnsResolve(ref host)) if host == "node-crunch.invalid"));
+    }
+
+    #[test]
+    fn test_connect_invalid_key() {
+        let config = NCConfiguration { key: "too short".to_string(), ..Default::default() };
+        assert!(matches!(NCClient::connect(&config), Err(NCError::InvalidKey(_))));
+
+        let config = NCConfiguration { previous_keys: vec!["too short".to_string()], ..Default::default() };
+        assert!(matches!(NCClient::connect(&config), Err(NCError::InvalidKey(ref problem)) if problem.starts_with("previous_keys[0]")));
+        assert!(matches!(NCClient::with_server_addr(Arc::new(Mutex::new(NCServerAddr::new("127.0.0.1", 9000))), &config), Err(NCError::InvalidKey(_))));
+    }
+
+    #[test]
+    fn test_set_server_is_shared() {
+        let config = NCConfiguration::default();
+        let mut nc_client1 = NCClient::connect(&config).unwrap();
+        let nc_client2 = NCClient::with_server_addr(nc_client1.server_addr.clone(), &config).unwrap();
+
+        nc_client1.set_server("10.0.0.1", 3030).unwrap();
+
+        assert_eq!(nc_client2.server_addr().unwrap(), NCServerAddr::new("10.0.0.1", 3030));
+
+        nc_client1.set_server("crunch.example.com", 3031).unwrap();
+        assert_eq!(nc_client2.server_addr().unwrap().to_string(), "crunch.example.com:3031");
+    }
+}
@@ -0,0 +1,92 @@
+//! This module contains the clock skew between a node and the server.
+//! Right after the registration the node sends its time (t0) with the NCNodeMessage::ClockProbe message, the server answers
+//! with its own time (t1) in the NCServerMessage::ClockTime message and the node sends both together with the time the answer
+//! has arrived (t2) in the NCNodeMessage::ClockReport message. Like NTP the server assumes that the answer took as long as the probe:
+//!
+//! - offset = t1 - (t0 + t2) / 2, positive if the clock of the node is behind the server
+//! - round trip time = t2 - t0
+//!
+//! The server stores the skew in its node list, shows it in the statistics ([`NCServerStatistics::clock_skews()`](crate::NCServerStatistics::clock_skews))
+//! and reports it with a [`NCProgressEvent::ClockSkew`](crate::NCProgressEvent::ClockSkew) event, so that time stamps from the node
+//! can be converted into server time with [`NCClockSkew::to_server_time()`]. It warns if the offset is bigger than max_clock_skew_ms
+//! in the NCConfiguration.
+//!
+//! The timeouts of the server (heartbeats, chunk deadlines, connection lifetimes, ...) don't depend on the clock of a node at all,
+//! they are measured with the monotonic clock of the server from the moment a message arrives.
+
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, SystemTime};
+#[cfg(feature = "net")]
+use std::time::UNIX_EPOCH;
+
+use serde::{Serialize, Deserialize};
+
+/// Returns the given time in microseconds since the unix epoch, 0 for times before it.
+#[cfg(feature = "net")]
+pub(crate) fn unix_micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_micros() as u64)
+}
+
+/// The measured clock skew of a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NCClockSkew {
+    /// The time of the server minus the time of the node in microseconds.
+    pub offset_micros: i64,
+    /// The round trip time of the measurement in microseconds.
+    pub rtt_micros: u64,
+}
+
+impl NCClockSkew {
+    /// Calculates the skew from the three time stamps in microseconds since the unix epoch: the node sends the probe (t0, node clock),
+    /// the server answers (t1, server clock), the answer arrives at the node (t2, node clock).
+    pub fn from_timestamps(t0: u64, t1: u64, t2: u64) -> Self {
+        let (t0, t1, t2) = (t0 as i128, t1 as i128, t2.max(t0) as i128);
+
+        NCClockSkew { offset_micros: (t1 - (t0 + t2) / 2) as i64, rtt_micros: (t2 - t0) as u64 }
+    }
+
+    /// The offset as duration, regardless of the direction.
+    pub fn offset(&self) -> Duration {
+        Duration::from_micros(self.offset_micros.unsigned_abs())
+    }
+
+    /// Converts a time stamp from the node into server time.
+    pub fn to_server_time(&self, node_time: SystemTime) -> SystemTime {
+        if self.offset_micros >= 0 {
+            node_time + self.offset()
+        } else {
+            node_time - self.offset()
+        }
+    }
+}
+
+impl Display for NCClockSkew {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "offset: {:+.3} ms, round trip time: {:.3} ms", self.offset_micros as f64 / 1000.0, self.rtt_micros as f64 / 1000.0)
+    }
+}
+
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_skew() {
+        // The node is 10 s behind, 2 ms each way
+        let skew = NCClockSkew::from_timestamps(1_000_000_000, 1_010_002_000, 1_000_004_000);
+        assert_eq!(skew, NCClockSkew { offset_micros: 10_000_000, rtt_micros: 4000 });
+        assert_eq!(skew.to_string(), "offset: +10000.000 ms, round trip time: 4.000 ms");
+
+        let node_time = UNIX_EPOCH + Duration::from_secs(100);
+        assert_eq!(skew.to_server_time(node_time), UNIX_EPOCH + Duration::from_secs(110));
+
+        // The node is ahead
+        let skew = NCClockSkew::from_timestamps(50_000_000, 20_000_500, 50_001_000);
+        assert_eq!(skew.offset_micros, -30_000_000);
+        assert_eq!(skew.to_server_time(node_time), UNIX_EPOCH + Duration::from_secs(70));
+
+        // A node clock that jumps back doesn't give a negative round trip time
+        assert_eq!(NCClockSkew::from_timestamps(1000, 1000, 500).rtt_micros, 0);
+        assert_eq!(unix_micros(UNIX_EPOCH + Duration::from_millis(3)), 3000);
+    }
+}
@@ -1,53 +1,754 @@
 //! This module contains the NC_Communicator for serializing, deserializing, sending and receiving data.
-
-use std::net::{TcpStream, ToSocketAddrs};
-use std::io::{Write, Read};
-
-use serde::{Serialize, de::DeserializeOwned};
-use bincode::{deserialize, serialize};
-use lz4_flex::{compress_prepend_size, decompress_size_prepended};
+//! Every message starts with the length (u64, little endian) followed by one byte that tells which codec (compression algorithm)
+//! has been used for this message and then the (optionally compressed and encrypted) data itself. The data is compressed before it's encrypted.
+//! Since every message carries its codec, each node can use a different codec, see [`NCCodec`].
+//! If a job id is set in the NCConfiguration the codec byte is preceded by a job tag (JOB_TAG, the length of the job id as one byte and the job id).
+//! The tag is not encrypted, so a [`NCMultiServerStarter`](crate::NCMultiServerStarter) can route the message without decoding it.
+//! If type_check is set in the NCConfiguration the codec byte also has the TYPE_HASH_FLAG set for messages with user data and the serialized data starts
+//! with a hash of the type name of the user data (4 bytes), see [`NCTyped`].
+//! Frames that are smaller than compression_min_size in the NCConfiguration are sent with NCCodec::None, the codec byte tells the other side.
+//! A frame can also consist of two parts that have been compressed separately (SEGMENTS_FLAG in the codec byte), the length of the first part
+//! (u64, little endian) comes first. This way the server compresses the chunk data only once and sends it several times, see [`NCPreparedFrame`].
+//! Empty user data (for example an empty `Vec<u8>` as a placeholder chunk) needs no special handling, it's serialized, compressed and
+//! encrypted like any other value. So every frame has at least the codec byte, a frame without one is rejected with `NCError::UnknownCodec(None)`.
+//! If the server can't decrypt or decode a message it answers with a NCServerMessage::Rejected message (NCRejectReason::WrongKey or VersionMismatch)
+//! that is not encrypted (PLAIN_FLAG in the codec byte), since the node would not be able to decrypt it either. Apart from these two rejections
+//! a frame with the PLAIN_FLAG is not accepted if encrypt is set.
+//! A frame that has been compressed with zstd and a dictionary has the DICTIONARY_FLAG in the codec byte and the id of the dictionary
+//! (u32, little endian) right after it, see the [`nc_dictionary`](crate::nc_dictionary) module. Only the node compresses with a dictionary.
+
+use std::net::{SocketAddr, ToSocketAddrs};
+#[cfg(feature = "net")]
+use std::net::TcpStream;
+use std::io::{self, Read};
+#[cfg(feature = "net")]
+use std::io::Write;
+use std::convert::TryInto;
+use std::any::type_name;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "net")]
+use std::time::Duration;
+use std::fmt::{self, Display, Formatter};
+use std::path::PathBuf;
+
+use log::{debug, info, warn};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use bincode::{serialize, Options};
+use lz4_flex::{compress_prepend_size, block::DecompressError};
 use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
 use chacha20poly1305::aead::{Aead, NewAead};
+use sha2::{Digest, Sha256};
 
+#[cfg(feature = "net")]
+use crate::nc_proxy::{self, NCProxyConfig};
 use crate::nc_config::{NCConfiguration};
+#[cfg(feature = "net")]
+use crate::nc_config::NCTransportKind;
+#[cfg(feature = "net")]
+use crate::nc_transport::NCStream;
+#[cfg(feature = "shmem")]
+use crate::nc_shmem::NCShmemStream;
 use crate::nc_error::NCError;
+#[cfg(feature = "net")]
+use crate::nc_frame_writer;
+use crate::nc_proto::{self, JOB_TAG, TYPE_HASH_FLAG, SEGMENTS_FLAG, PLAIN_FLAG, DICTIONARY_FLAG};
+use crate::nc_dictionary::{self, NCDictionaries};
+#[cfg(feature = "net")]
+use crate::nc_message::{NCServerMessage, NCRejectReason};
+pub(crate) use crate::nc_proto::fnv1a;
+
+/// Messages that carry user data (the associated types of the NCServer and NCNode traits).
+/// If type_check is set in the NCConfiguration the hash of the type name of the user data is sent with the message
+/// and checked when the message is decoded.
+#[cfg(feature = "net")]
+pub(crate) trait NCTyped {
+    /// The type name of the user data in this message, [`None`] if the message doesn't contain any user data.
+    fn payload_type(&self) -> Option<&'static str>;
+    /// The type names of all the user data that this message type can contain.
+    fn payload_types() -> Vec<&'static str>;
+}
+
+/// The type names that have been hashed, so that a type mismatch can show the name of the type that has been received.
+static TYPE_NAMES: OnceLock<Mutex<HashMap<u32, &'static str>>> = OnceLock::new();
+
+/// The FNV-1a hash of the given type name, it's the same for every build and platform.
+/// References are ignored since they are serialized in the same way as the value itself.
+fn type_hash(name: &'static str) -> u32 {
+    let name = name.trim_start_matches('&');
+    let hash = nc_proto::type_hash(name);
+
+    if let Ok(mut type_names) = TYPE_NAMES.get_or_init(Default::default).lock() {
+        type_names.insert(hash, name);
+    }
+
+    hash
+}
+
+/// The serialized data of a message, the hash of the type name of its user data and the index of the previous key
+/// that has been used for encryption, see nc_decode_message_key().
+type DecodedFrame = (Vec<u8>, Option<u32>, Option<usize>);
+
+/// Returns a [`NCError::TypeMismatch`] error for the expected type name and the hash of the received type.
+#[cfg(feature = "net")]
+fn type_mismatch(expected: String, hash: u32) -> NCError {
+    let got = TYPE_NAMES.get()
+        .and_then(|type_names| type_names.lock().ok()?.get(&hash).map(|name| name.to_string()))
+        .unwrap_or_else(|| format!("unknown type with hash {:#010x}", hash));
+
+    NCError::TypeMismatch { expected, got }
+}
+
+/// Splits the job id from the front of an encoded message, if there is one.
+/// Returns the job id and the rest of the message, starting with the codec byte.
+pub(crate) fn split_job_id(data: &[u8]) -> Result<(Option<String>, &[u8]), NCError> {
+    match data {
+        [JOB_TAG, len, rest @ ..] => {
+            let len = *len as usize;
+
+            if rest.len() < len {
+                return Err(NCError::UnexpectedEof("job id", rest.len() as u64, len as u64))
+            }
+
+            let (job_id, rest) = rest.split_at(len);
+            Ok((Some(String::from_utf8_lossy(job_id).into_owned()), rest))
+        }
+        [JOB_TAG] => Err(NCError::UnexpectedEof("job id", 0, 1)),
+        _ => Ok((None, data)),
+    }
+}
+
+/// Deserializes the data like bincode::deserialize(), but reads at most data.len() bytes.
+/// A crafted length inside the message (for example a Vec with billions of elements) fails before the memory is allocated.
+/// The data itself is bounded by max_frame_bytes and max_decompressed_bytes in the NCConfiguration, so the limit is too.
+/// If the data ends too early (an empty buffer for example) a [`NCError::DataTooShort`] error with the name of the type is returned.
+/// This is also used for payloads from the other side that are decoded separately, for example signed results.
+pub(crate) fn deserialize_limited<D: DeserializeOwned>(data: &[u8]) -> Result<D, NCError> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(data.len() as u64)
+        .deserialize(data)
+        .map_err(|error| match *error {
+            bincode::ErrorKind::SizeLimit => NCError::DataTooShort(type_name::<D>().to_string(), data.len() as u64),
+            bincode::ErrorKind::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => NCError::DataTooShort(type_name::<D>().to_string(), data.len() as u64),
+            _ => NCError::Deserialize(error),
+        })
+}
+
+/// The data of a message is read in pieces of at most this size, so that a broken length doesn't allocate all the memory at once.
+#[cfg(feature = "net")]
+const READ_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Reads from the given Reader until the buffer is full or the other side has closed the connection.
+/// Returns the number of bytes read, this is only less than the size of the buffer if the connection has been closed.
+/// Reads that have been interrupted by a signal are repeated. All other errors are returned, including WouldBlock / TimedOut:
+/// the streams are blocking, so these only happen if a read timeout has expired and repeating the read would just spin.
+#[cfg(feature = "net")]
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut bytes_read = 0;
+
+    while bytes_read < buffer.len() {
+        match reader.read(&mut buffer[bytes_read..]) {
+            Ok(0) => break,
+            Ok(n) => bytes_read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(bytes_read)
+}
+
+/// lz4 doesn't compress better than this, a frame with a larger size prefix is broken.
+const LZ4_MAX_RATIO: u64 = 255;
+
+/// Reads the decompressed data from the given decoder, but not more than limit bytes (max_decompressed_bytes in the NCConfiguration).
+/// Returns a [`NCError::DecompressionLimit`] error if there is more.
+pub(crate) fn read_limited<R: Read>(decoder: R, limit: u64) -> Result<Vec<u8>, NCError> {
+    let mut data_out = Vec::new();
+    decoder.take(limit.saturating_add(1)).read_to_end(&mut data_out)?;
+
+    if data_out.len() as u64 > limit {
+        return Err(NCError::DecompressionLimit(limit))
+    }
+
+    Ok(data_out)
+}
+
+/// The compression algorithm that is used for the messages between the server and one node.
+/// During registration the node sends its list of codecs to the server and the server chooses the first codec of its own list
+/// that the node supports. The zstd level of the server is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NCCodec {
+    /// No compression.
+    None,
+    /// Fast compression with lz4.
+    Lz4,
+    /// Better compression with zstd with the given level (1 - 21), but needs more CPU time.
+    Zstd(i32),
+}
+
+/// Returns the serialized size (bincode) of the given data in bytes, before encryption and compression.
+/// This can be used to check the overhead of the serialization in tests, for example nested [`Vec`]s store the length
+/// of every inner vector.
+/// Returns 0 if the data can not be serialized.
+pub fn nc_encoded_size<T: Serialize>(data: &T) -> usize {
+    bincode::serialized_size(data).map_or(0, |size| size as usize)
+}
+
+impl NCCodec {
+    /// The id that is written in front of every message.
+    fn id(&self) -> u8 {
+        match self {
+            NCCodec::None => 0,
+            NCCodec::Lz4 => 1,
+            NCCodec::Zstd(_) => 2,
+        }
+    }
+
+    /// Compresses the given data with this codec.
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, NCError> {
+        Ok(match self {
+            NCCodec::None => data.to_vec(),
+            NCCodec::Lz4 => compress_prepend_size(data),
+            NCCodec::Zstd(level) => zstd::encode_all(data, *level)?,
+        })
+    }
+
+    /// Decompresses the given data with the codec that has the given id, the result must not be bigger than limit bytes.
+    /// The size prefix of lz4 is checked before the memory is allocated.
+    fn decompress(codec_id: u8, data: &[u8], limit: u64) -> Result<Vec<u8>, NCError> {
+        Ok(match codec_id {
+            0 => data.to_vec(),
+            1 => {
+                if data.len() < 4 {
+                    return Err(NCError::Decompress(DecompressError::ExpectedAnotherByte))
+                }
+
+                let (size, data) = data.split_at(4);
+                let size = u64::from(u32::from_le_bytes([size[0], size[1], size[2], size[3]]));
+
+                if size > limit {
+                    return Err(NCError::DecompressionLimit(limit))
+                }
+
+                if size > (data.len() as u64).saturating_mul(LZ4_MAX_RATIO) {
+                    return Err(NCError::Decompress(DecompressError::ExpectedAnotherByte))
+                }
+
+                lz4_flex::decompress(data, size as usize)?
+            }
+            2 => read_limited(zstd::stream::read::Decoder::with_buffer(data)?, limit)?,
+            _ => return Err(NCError::UnknownCodec(Some(codec_id))),
+        })
+    }
+
+    /// Chooses the first codec from the server list that is also in the node list. The level for zstd doesn't have to match.
+    /// Returns [`None`] if there is no common codec.
+    pub(crate) fn negotiate(server_codecs: &[NCCodec], node_codecs: &[NCCodec]) -> Option<NCCodec> {
+        server_codecs.iter().find(|codec| node_codecs.iter().any(|other| other.id() == codec.id())).copied()
+    }
+}
+
+/// The time for one connection attempt, after that the next address of the server is tried.
+#[cfg(feature = "net")]
+const CONNECT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The address of the server: an IP address or a host name and the port.
+/// A host name is resolved again for every connection, so a node follows a changed DNS entry (for example a fail over to another server)
+/// without a restart. All addresses of the host are tried one after another, IPv6 addresses first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NCServerAddr {
+    /// IP address or host name of the server.
+    pub host: String,
+    /// Port of the server.
+    pub port: u16,
+    /// The directory of the shared memory transport (shmem feature), then the host and the port are not used for the connection.
+    /// See NCTransportKind::SharedMemory in the NCConfiguration, default: None = tcp.
+    pub shared_memory: Option<PathBuf>,
+}
+
+impl NCServerAddr {
+    /// Creates a new server address, the host is not resolved here.
+    pub fn new<S: Into<String>>(host: S, port: u16) -> Self {
+        NCServerAddr { host: host.into(), port, shared_memory: None }
+    }
+
+    /// Creates the server address from the address and the port in the configuration. With the address "mdns:" the server is
+    /// searched with mDNS (mdns feature, see the nc_mdns module).
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::DnsResolve`] error if the address is empty and the errors from the mDNS search.
+    #[cfg(feature = "net")]
+    pub(crate) fn from_config(config: &NCConfiguration) -> Result<Self, NCError> {
+        if config.address.trim().is_empty() {
+            return Err(NCError::DnsResolve(config.address.clone()))
+        }
+
+        if config.uses_mdns() {
+            #[cfg(feature = "mdns")]
+            return crate::nc_mdns::find_server(config).map(NCServerAddr::from);
+            #[cfg(not(feature = "mdns"))]
+            return Err(NCError::Mdns(config.check_mdns().unwrap_err().to_string()));
+        }
+
+        let shared_memory = match &config.transport {
+            NCTransportKind::Tcp => None,
+            NCTransportKind::SharedMemory { path } => Some(path.clone()),
+        };
+
+        Ok(NCServerAddr { shared_memory, ..NCServerAddr::new(config.address.trim(), config.port) })
+    }
+
+    /// Returns all addresses of the host, IPv6 addresses first and otherwise in the order of the resolver.
+    /// An IP address is returned as is without asking the resolver.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::DnsResolve`] error if the host name can't be resolved or has no addresses.
+    pub fn resolve(&self) -> Result<Vec<SocketAddr>, NCError> {
+        debug!("NCServerAddr::resolve()");
+
+        let mut addresses: Vec<SocketAddr> = (self.host.as_str(), self.port).to_socket_addrs()
+            .map_err(|_| NCError::DnsResolve(self.host.clone()))?.collect();
+
+        if addresses.is_empty() {
+            return Err(NCError::DnsResolve(self.host.clone()))
+        }
+
+        // The sort is stable, so the order of the resolver is kept within each family
+        addresses.sort_by_key(|address| address.is_ipv4());
+        Ok(addresses)
+    }
+
+    /// Resolves the host and opens a tcp connection to the first address that answers within a few seconds.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::DnsResolve`] error if the host name can't be resolved and a [`NCError::Connect`] error with every address
+    /// that has been tried if no connection could be opened.
+    #[cfg(feature = "net")]
+    pub(crate) fn connect(&self) -> Result<TcpStream, NCError> {
+        debug!("NCServerAddr::connect()");
+
+        let mut attempts = Vec::new();
+
+        for address in self.resolve()? {
+            match TcpStream::connect_timeout(&address, CONNECT_ATTEMPT_TIMEOUT) {
+                Ok(tcp_stream) => return Ok(tcp_stream),
+                Err(e) => {
+                    debug!("Could not connect to {}: {}", address, e);
+                    attempts.push((address, e));
+                }
+            }
+        }
+
+        Err(NCError::Connect(self.to_string(), attempts))
+    }
+}
+
+impl From<SocketAddr> for NCServerAddr {
+    fn from(socket_addr: SocketAddr) -> Self {
+        NCServerAddr::new(socket_addr.ip().to_string(), socket_addr.port())
+    }
+}
+
+impl Display for NCServerAddr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if let Some(path) = &self.shared_memory {
+            write!(f, "shmem:{}", path.display())
+        } else if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Returns the first 8 bytes of the sha256 hash of the serialized message as hex digits. Both sides log the same hash
+/// for the same message (see log_payload_hashes in the NCConfiguration), the content itself is never logged.
+pub(crate) fn payload_hash(data: &[u8]) -> String {
+    Sha256::digest(data)[..8].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The first part of a message that has been serialized and compressed once, so that it can be sent several times
+/// with a different rest, see prepare_frame() and nc_send_prepared(). The server uses this for the chunk data, the rest is the chunk info.
+/// It's not encrypted, since every frame needs its own nonce.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct NCPreparedFrame {
+    /// The codec that has been used for the first part, the rest is compressed with the same codec.
+    codec: NCCodec,
+    /// True if the first part starts with the hash of the type name of the user data.
+    type_hash: bool,
+    /// The compressed first part.
+    data: Vec<u8>,
+    /// The id and the bytes of the zstd dictionary that has been used for the first part, the rest is compressed with the same one.
+    dictionary: Option<(u32, Arc<Vec<u8>>)>,
+}
+
+impl NCPreparedFrame {
+    /// The size of the compressed first part in bytes.
+    #[cfg(feature = "net")]
+    pub(crate) fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// The codec byte of the frame with the given flags, the DICTIONARY_FLAG is added if there is a dictionary.
+    fn codec_byte(&self, flags: u8) -> u8 {
+        self.codec.id() | flags | if self.type_hash { TYPE_HASH_FLAG } else { 0 } | if self.dictionary.is_some() { DICTIONARY_FLAG } else { 0 }
+    }
+
+    /// Compresses the given data with the codec and the dictionary of this frame.
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, NCError> {
+        match (self.codec, &self.dictionary) {
+            (NCCodec::Zstd(level), Some((_, dictionary))) => nc_dictionary::compress(data, level, dictionary),
+            (codec, _) => codec.compress(data),
+        }
+    }
+}
+
+/// The prefix of a key that is given as 64 hex digits.
+pub(crate) const HEX_KEY_PREFIX: &str = "hex:";
+/// The prefix of a key that is given as 32 bytes in base64.
+pub(crate) const BASE64_KEY_PREFIX: &str = "base64:";
+/// The formats that are accepted for an encryption key, this is part of every error message.
+const KEY_FORMATS: &str = "expected 32 printable ASCII characters without spaces, \"hex:\" followed by 64 hex digits or \"base64:\" followed by 32 bytes in base64";
+
+/// Decodes the encryption key, see [`NCConfiguration::key`]. The error describes what is wrong with the key,
+/// but it never contains the key itself, since it may end up in a log.
+pub(crate) fn decode_key(key: &str) -> Result<[u8; 32], String> {
+    let bytes = if let Some(digits) = key.strip_prefix(HEX_KEY_PREFIX) {
+        if !digits.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(format!("the hex key contains characters that are not hex digits, {}", KEY_FORMATS))
+        }
+
+        if digits.len() != 64 {
+            return Err(format!("the hex key has {} digits instead of 64, {}", digits.len(), KEY_FORMATS))
+        }
+
+        (0..digits.len()).step_by(2).map(|index| u8::from_str_radix(&digits[index..index + 2], 16).unwrap_or_default()).collect()
+    } else if let Some(digits) = key.strip_prefix(BASE64_KEY_PREFIX) {
+        let bytes = decode_base64(digits)
+            .ok_or_else(|| format!("the base64 key contains characters that are not base64 digits, {}", KEY_FORMATS))?;
+
+        if bytes.len() != 32 {
+            return Err(format!("the base64 key has {} bytes instead of 32, {}", bytes.len(), KEY_FORMATS))
+        }
+
+        bytes
+    } else {
+        if !key.bytes().all(|byte| byte.is_ascii_graphic()) {
+            return Err(format!("the key contains spaces, control or non ASCII characters, {}", KEY_FORMATS))
+        }
+
+        if key.len() != 32 {
+            return Err(format!("the key has {} characters instead of 32, {}", key.len(), KEY_FORMATS))
+        }
+
+        key.as_bytes().to_vec()
+    };
+
+    bytes.try_into().map_err(|_| KEY_FORMATS.to_string())
+}
+
+/// Decodes base64 with the standard or the URL safe alphabet, the padding at the end is optional.
+/// Returns [`None`] if there is an invalid character.
+fn decode_base64(digits: &str) -> Option<Vec<u8>> {
+    let digits = digits.strip_suffix("==").or_else(|| digits.strip_suffix('=')).unwrap_or(digits);
+    let mut bytes = Vec::with_capacity(digits.len() * 3 / 4);
+    let mut buffer = 0_u32;
+    let mut bits = 0;
+
+    for digit in digits.bytes() {
+        let value = match digit {
+            b'A'..=b'Z' => digit - b'A',
+            b'a'..=b'z' => digit - b'a' + 26,
+            b'0'..=b'9' => digit - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
+        };
+
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Some(bytes)
+}
+
+/// Creates the cipher for the given key, see decode_key() for the formats.
+fn new_cipher(key: &str) -> Result<ChaCha20Poly1305, NCError> {
+    let key = decode_key(key).map_err(NCError::InvalidKey)?;
+    Ok(ChaCha20Poly1305::new(&Key::from(key)))
+}
+
+/// The current encryption key and the previous keys that are still accepted.
+/// All the clients of one node share the same key ring, so that they all switch to a new key at the same time.
+pub(crate) struct NCKeyRing {
+    /// The key that is used for sending.
+    #[cfg(feature = "net")]
+    current_key: String,
+    /// The cipher for the current key.
+    current: ChaCha20Poly1305,
+    /// Ciphers for the previous keys, the newest one first. Frames encrypted with these keys are still accepted.
+    previous: Vec<ChaCha20Poly1305>,
+    /// Keep at most n previous keys when the key is rotated.
+    #[cfg(feature = "net")]
+    max_previous: usize,
+    /// The nonce for the next encrypted message.
+    nonce: u64,
+}
+
+impl NCKeyRing {
+    /// Creates a new key ring from the keys in the given configuration.
+    fn new(config: &NCConfiguration) -> Result<Self, NCError> {
+        config.check_keys()?;
+
+        Ok(NCKeyRing {
+            #[cfg(feature = "net")]
+            current_key: config.key.clone(),
+            current: new_cipher(&config.key)?,
+            previous: config.previous_keys.iter().map(|key| new_cipher(key)).collect::<Result<_, _>>()?,
+            #[cfg(feature = "net")]
+            max_previous: config.max_previous_keys,
+            nonce: 0,
+        })
+    }
+
+    /// Use the given key for sending from now on. The old key is still accepted until it's pushed out of the list of previous keys.
+    #[cfg(feature = "net")]
+    fn rotate(&mut self, key: &str) -> Result<(), NCError> {
+        let cipher = new_cipher(key)?;
+        let old_cipher = std::mem::replace(&mut self.current, cipher);
+
+        self.current_key = key.to_string();
+        self.previous.insert(0, old_cipher);
+        self.previous.truncate(self.max_previous);
+        Ok(())
+    }
+}
 
 pub struct NCCommunicator {
-    compress: bool,
     encrypt: bool,
-    cipher: ChaCha20Poly1305,
-    nonce: u64,
+    /// The encryption keys, maybe shared with other communicators, see [`NCKeyRing`].
+    keys: Arc<Mutex<NCKeyRing>>,
+    /// The codec that is used for encoding messages, see [`NCCodec`].
+    #[cfg(feature = "net")]
+    codec: NCCodec,
+    /// Log a warning if the serialized size of a message is bigger, 0 = never warn.
+    payload_warn_bytes: u64,
+    /// Log a short hash of every message instead of its content, see [`payload_hash()`].
+    log_payload_hashes: bool,
+    /// Every message starts with this job id, see [`split_job_id()`].
+    job_id: Option<String>,
+    /// Send and check the hash of the type name of the user data, see [`NCTyped`].
+    type_check: bool,
+    /// Smaller frames are not compressed.
+    compression_min_size: usize,
+    /// Received frames must not decompress to more bytes.
+    max_decompressed_bytes: u64,
+    /// Received frames must not be longer, see read_frame().
+    #[cfg(feature = "net")]
+    max_frame_bytes: u64,
+    /// Number of frames that have not been compressed because they are smaller than compression_min_size, shared with other communicators.
+    uncompressed_frames: Arc<AtomicU64>,
+    /// New connections are opened through this proxy, see set_proxy().
+    #[cfg(feature = "net")]
+    proxy: Option<NCProxyConfig>,
+    /// The zstd dictionaries for compressing and decompressing, shared with other communicators, see [`NCDictionaries`].
+    dictionaries: Arc<Mutex<NCDictionaries>>,
 }
 
 impl NCCommunicator {
-    pub fn new(config: &NCConfiguration) -> Self {
-        let key = Key::from_slice(config.key.as_bytes());
-        let cipher = ChaCha20Poly1305::new(key);
+    /// Creates a new communicator with the settings from the given configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::InvalidKey`] error if the encryption key or one of the previous keys is not valid.
+    pub fn new(config: &NCConfiguration) -> Result<Self, NCError> {
+        Ok(Self {
+            encrypt: config.encrypt,
+            keys: Arc::new(Mutex::new(NCKeyRing::new(config)?)),
+            #[cfg(feature = "net")]
+            codec: config.codecs().first().copied().unwrap_or(NCCodec::None),
+            payload_warn_bytes: config.payload_warn_bytes,
+            log_payload_hashes: config.log_payload_hashes,
+            job_id: config.job_id.clone(),
+            type_check: config.type_check,
+            compression_min_size: config.compression_min_size,
+            max_decompressed_bytes: config.max_decompressed_bytes,
+            #[cfg(feature = "net")]
+            max_frame_bytes: config.max_frame_bytes,
+            uncompressed_frames: Arc::new(AtomicU64::new(0)),
+            #[cfg(feature = "net")]
+            proxy: None,
+            dictionaries: Arc::new(Mutex::new(NCDictionaries::default())),
+        })
+    }
 
+    /// Creates a new communicator with the same settings that shares the encryption keys with this communicator.
+    #[cfg(feature = "net")]
+    pub(crate) fn share(&self) -> Self {
         Self {
-            compress: config.compress,
-            encrypt: config.encrypt,
-            cipher,
-            nonce: 0,
+            encrypt: self.encrypt,
+            keys: self.keys.clone(),
+            codec: self.codec,
+            payload_warn_bytes: self.payload_warn_bytes,
+            log_payload_hashes: self.log_payload_hashes,
+            job_id: self.job_id.clone(),
+            type_check: self.type_check,
+            compression_min_size: self.compression_min_size,
+            max_decompressed_bytes: self.max_decompressed_bytes,
+            max_frame_bytes: self.max_frame_bytes,
+            uncompressed_frames: self.uncompressed_frames.clone(),
+            proxy: self.proxy.clone(),
+            dictionaries: self.dictionaries.clone(),
+        }
+    }
+
+    /// Returns a [`NCError::InvalidKey`] error if the given key can not be used for encryption.
+    #[cfg(feature = "net")]
+    pub(crate) fn check_key(key: &str) -> Result<(), NCError> {
+        new_cipher(key).map(|_| ())
+    }
+
+    /// Use the given key for sending from now on, the current key is moved to the list of previous keys.
+    /// This affects all the communicators that share the keys.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::InvalidKey`] error if the key has the wrong length or invalid characters.
+    #[cfg(feature = "net")]
+    pub(crate) fn rotate_key(&self, key: &str) -> Result<(), NCError> {
+        debug!("NCCommunicator::rotate_key()");
+
+        self.keys.lock()?.rotate(key)
+    }
+
+    /// Returns the key that is currently used for sending.
+    #[cfg(feature = "net")]
+    pub(crate) fn current_key(&self) -> Result<String, NCError> {
+        Ok(self.keys.lock()?.current_key.clone())
+    }
+
+    /// Number of frames that have been sent uncompressed because they are smaller than compression_min_size in the NCConfiguration.
+    /// This includes the frames of all communicators that share the keys, see share().
+    #[cfg(feature = "net")]
+    pub(crate) fn uncompressed_frames(&self) -> u64 {
+        self.uncompressed_frames.load(Ordering::Relaxed)
+    }
+
+    /// Sets the codec that is used for encoding messages, usually after the codec has been negotiated with the server.
+    #[cfg(feature = "net")]
+    pub(crate) fn set_codec(&mut self, codec: NCCodec) {
+        self.codec = codec
+    }
+
+    /// Opens all new connections through the given proxy, this is only used for the nodes and clients.
+    /// The server connects to itself (heartbeat) and never uses a proxy.
+    #[cfg(feature = "net")]
+    pub(crate) fn set_proxy(&mut self, proxy: Option<NCProxyConfig>) {
+        self.proxy = proxy
+    }
+
+    /// Adds the given zstd dictionary for decompressing and returns its id, see the [`nc_dictionary`](crate::nc_dictionary) module.
+    /// This affects all the communicators that share the dictionaries.
+    #[cfg(feature = "net")]
+    pub(crate) fn add_dictionary(&self, dictionary: Vec<u8>) -> Result<u32, NCError> {
+        debug!("NCCommunicator::add_dictionary()");
+
+        Ok(self.dictionaries.lock()?.insert(dictionary))
+    }
+
+    /// Returns the zstd dictionary with the given id, [`None`] if it's not known.
+    pub(crate) fn dictionary(&self, id: u32) -> Result<Option<Arc<Vec<u8>>>, NCError> {
+        Ok(self.dictionaries.lock()?.get(id))
+    }
+
+    /// The id of the zstd dictionary that is used for sending, see use_dictionary().
+    #[cfg(feature = "net")]
+    pub(crate) fn current_dictionary(&self) -> Result<Option<u32>, NCError> {
+        Ok(self.dictionaries.lock()?.current().map(|(id, _)| id))
+    }
+
+    /// Compresses the frames with NCCodec::Zstd with the given known dictionary from now on, [`None`]: without a dictionary.
+    #[cfg(feature = "net")]
+    pub(crate) fn use_dictionary(&self, id: Option<u32>) -> Result<(), NCError> {
+        debug!("NCCommunicator::use_dictionary()");
+
+        self.dictionaries.lock()?.set_current(id);
+        Ok(())
+    }
+
+    /// Opens a connection to the given server, through the proxy if there is one (see set_proxy()).
+    /// If the server address has a shared memory directory the connection uses the shared memory transport instead (shmem feature).
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::SharedMemory`] error for a shared memory directory without the shmem feature.
+    #[cfg(feature = "net")]
+    pub(crate) fn connect(&self, server_addr: &NCServerAddr) -> Result<NCStream, NCError> {
+        if let Some(path) = &server_addr.shared_memory {
+            #[cfg(feature = "shmem")]
+            return Ok(Box::new(NCShmemStream::connect(path)?));
+            #[cfg(not(feature = "shmem"))]
+            return Err(NCError::SharedMemory(format!("{} needs the shmem feature", path.display())));
         }
+
+        Ok(Box::new(nc_proxy::connect_to(server_addr, self.proxy.as_ref())?))
     }
 
-    fn encrypt_data(&self, data: &[u8]) -> Result<Vec<u8>, NCError> {
-        let bytes: [u8; 8] = self.nonce.to_be_bytes();
-        let mut nonce_bytes: Vec<u8> = vec![0, 0, 0, 0];
-        nonce_bytes.extend_from_slice(&bytes);
-        let nonce = Nonce::from_slice(&nonce_bytes);
-        let encrypted_data = self.cipher.encrypt(nonce, data).map_err(|_| NCError::Encrypt)?;
+    /// Encrypts the data with the current key or with the given previous key.
+    fn encrypt_data(&self, data: &[u8], previous_key: Option<usize>) -> Result<Vec<u8>, NCError> {
+        let mut keys = self.keys.lock()?;
+        let bytes: [u8; 8] = keys.nonce.to_be_bytes();
+        keys.nonce += 1;
+        let mut nonce_bytes: [u8; 12] = [0; 12];
+        nonce_bytes[4..].copy_from_slice(&bytes);
+        let nonce = Nonce::from(nonce_bytes);
+        let cipher = match previous_key {
+            Some(index) => keys.previous.get(index).ok_or_else(|| NCError::InvalidKey(format!("there is no previous key {}", index)))?,
+            None => &keys.current,
+        };
+        let encrypted_data = cipher.encrypt(&nonce, data).map_err(|_| NCError::Encrypt)?;
         let mut full_data = Vec::with_capacity(nonce_bytes.len() + encrypted_data.len());
         full_data.extend_from_slice(&nonce_bytes);
         full_data.extend_from_slice(&encrypted_data);
         Ok(full_data)
     }
 
-    fn decrypt_data(&self, data: &[u8]) -> Result<Vec<u8>, NCError> {
+    /// Decrypts the data with the current key or one of the previous keys.
+    /// Also returns the index of the previous key if the current key didn't work.
+    fn decrypt_data(&self, data: &[u8]) -> Result<(Vec<u8>, Option<usize>), NCError> {
+        if data.len() < 12 {
+            return Err(NCError::Decrypt)
+        }
+
         let (nonce_bytes, encrypted_data) = data.split_at(12);
-        let nonce = Nonce::from_slice(nonce_bytes);
-        self.cipher.decrypt(nonce, encrypted_data).map_err(|_| NCError::Encrypt)
+        let nonce_bytes: [u8; 12] = nonce_bytes.try_into().map_err(|_| NCError::Decrypt)?;
+        let nonce = Nonce::from(nonce_bytes);
+        let keys = self.keys.lock()?;
+
+        if let Ok(decrypted_data) = keys.current.decrypt(&nonce, encrypted_data) {
+            return Ok((decrypted_data, None))
+        }
+
+        for (index, cipher) in keys.previous.iter().enumerate() {
+            if let Ok(decrypted_data) = cipher.decrypt(&nonce, encrypted_data) {
+                return Ok((decrypted_data, Some(index)))
+            }
+        }
+
+        Err(NCError::Decrypt)
     }
 
     /// Encodes the given data to a [`Vec<u8>`].
@@ -55,38 +756,285 @@ impl NCCommunicator {
     /// # Errors
     ///
     /// On failure it returns a [`NCError::Serialize`] error which contains the serde serialize error.
+    #[cfg(feature = "net")]
     pub(crate) fn nc_encode_data<S: Serialize>(&mut self, data: &S) -> Result<Vec<u8>, NCError> {
-        let mut data_out = serialize(data).map_err(|e| NCError::Serialize(e))?;
+        self.nc_encode_data_codec(data, self.codec)
+    }
+
+    /// Encodes the given data to a [`Vec<u8>`] using the given codec instead of the default one.
+    /// There is no length in front of the frame, so it can also be used with a custom transport, see nc_decode_data().
+    ///
+    /// # Errors
+    ///
+    /// On failure it returns a [`NCError::Serialize`] error which contains the serde serialize error.
+    pub fn nc_encode_data_codec<S: Serialize>(&mut self, data: &S, codec: NCCodec) -> Result<Vec<u8>, NCError> {
+        self.encode_data(data, codec, None, None)
+    }
+
+    /// Encodes the given data with the given codec and the current key or the given previous key.
+    /// If type_check is set and there is a payload type, its hash is put in front of the serialized data.
+    fn encode_data<S: Serialize>(&mut self, data: &S, codec: NCCodec, previous_key: Option<usize>, payload_type: Option<&'static str>) -> Result<Vec<u8>, NCError> {
+        let data_out = serialize(data).map_err(NCError::Serialize)?;
+        let serialized_size = data_out.len();
+        let frame = self.prepare_serialized(data_out, type_name::<S>(), codec, payload_type)?;
+        let codec = frame.codec;
+        let codec_byte = frame.codec_byte(0);
+        let dictionary = frame.dictionary.map(|(id, _)| id);
+        let data_out = self.seal(frame.data, codec_byte, dictionary, previous_key)?;
+
+        debug!("Encoded message: {}, serialized size: {} bytes, encoded size: {} bytes, codec: {:?}", type_name::<S>(), serialized_size, data_out.len(), codec);
+
+        Ok(data_out)
+    }
+
+    /// Compresses the given serialized data of the first part of a message, so that it can be sent several times with
+    /// nc_send_prepared(). The type name of the message is only used for the log messages. See [`NCPreparedFrame`].
+    #[cfg(feature = "net")]
+    pub(crate) fn prepare_frame(&self, serialized: Vec<u8>, message_type: &str, codec: NCCodec, payload_type: Option<&'static str>) -> Result<NCPreparedFrame, NCError> {
+        debug!("NCCommunicator::prepare_frame()");
+
+        self.prepare_serialized(serialized, message_type, codec, payload_type)
+    }
 
+    /// Logs the size and the hash of the serialized data (see payload_warn_bytes and log_payload_hashes in the NCConfiguration),
+    /// puts the type hash in front of it and compresses it with the given codec, unless it's smaller than compression_min_size.
+    /// With NCCodec::Zstd the current dictionary is used (see use_dictionary()), if there is one.
+    fn prepare_serialized(&self, mut data_out: Vec<u8>, message_type: &str, codec: NCCodec, payload_type: Option<&'static str>) -> Result<NCPreparedFrame, NCError> {
+        let serialized_size = data_out.len();
+
+        if self.payload_warn_bytes > 0 && serialized_size as u64 > self.payload_warn_bytes {
+            warn!("Large message: {}, serialized size: {} bytes, warn threshold: {} bytes", message_type, serialized_size, self.payload_warn_bytes);
+        }
+
+        if self.log_payload_hashes {
+            info!("Send message: {}, serialized size: {} bytes, hash: {}", message_type, serialized_size, payload_hash(&data_out));
+        }
+
+        let type_hash = payload_type.filter(|_| self.type_check).map(type_hash);
+
+        if let Some(type_hash) = type_hash {
+            data_out.splice(0..0, type_hash.to_le_bytes());
+        }
+
+        let codec = if codec != NCCodec::None && data_out.len() < self.compression_min_size {
+            self.uncompressed_frames.fetch_add(1, Ordering::Relaxed);
+            NCCodec::None
+        } else {
+            codec
+        };
+
+        let dictionary = match codec {
+            NCCodec::Zstd(_) => self.dictionaries.lock()?.current(),
+            _ => None,
+        };
+
+        let mut frame = NCPreparedFrame { codec, type_hash: type_hash.is_some(), data: Vec::new(), dictionary };
+        frame.data = frame.compress(&data_out)?;
+        Ok(frame)
+    }
+
+    /// Encrypts the compressed data if encrypt is set in the NCConfiguration and puts the codec byte, the dictionary id (if there is one)
+    /// and the job tag (if there is a job id) in front of it.
+    fn seal(&self, mut data_out: Vec<u8>, codec_byte: u8, dictionary: Option<u32>, previous_key: Option<usize>) -> Result<Vec<u8>, NCError> {
         if self.encrypt {
-            data_out = self.encrypt_data(&data_out)?;
-            self.nonce += 1;
+            data_out = self.encrypt_data(&data_out, previous_key)?;
         }
 
-        if self.compress {
-            data_out = compress_prepend_size(&data_out);
+        Ok(self.add_header(data_out, codec_byte, dictionary))
+    }
+
+    /// Puts the codec byte, the dictionary id (if there is one) and the job tag (if there is a job id) in front of the data.
+    fn add_header(&self, mut data_out: Vec<u8>, codec_byte: u8, dictionary: Option<u32>) -> Vec<u8> {
+        match dictionary {
+            Some(id) => { data_out.splice(0..0, std::iter::once(codec_byte).chain(id.to_le_bytes())); }
+            None => data_out.insert(0, codec_byte),
         }
 
-        Ok(data_out)
+        if let Some(job_id) = &self.job_id {
+            let job_id = &job_id.as_bytes()[..job_id.len().min(u8::MAX as usize)];
+            let mut tag = Vec::with_capacity(2 + job_id.len());
+            tag.push(JOB_TAG);
+            tag.push(job_id.len() as u8);
+            tag.extend_from_slice(job_id);
+            data_out.splice(0..0, tag);
+        }
+
+        data_out
+    }
+
+    /// Encodes a message from the prepared first part and the serialized rest, see [`NCPreparedFrame`].
+    /// The rest is compressed with the same codec and dictionary as the first part.
+    #[cfg(feature = "net")]
+    pub(crate) fn nc_encode_prepared(&self, frame: &NCPreparedFrame, rest: &[u8]) -> Result<Vec<u8>, NCError> {
+        let rest = frame.compress(rest)?;
+        let mut data_out = Vec::with_capacity(8 + frame.data.len() + rest.len());
+        data_out.extend_from_slice(&(frame.data.len() as u64).to_le_bytes());
+        data_out.extend_from_slice(&frame.data);
+        data_out.extend_from_slice(&rest);
+
+        self.seal(data_out, frame.codec_byte(SEGMENTS_FLAG), frame.dictionary.as_ref().map(|(id, _)| *id), None)
     }
 
-    /// Decode the given data from a `&[u8]` slice to the type `T`.
+    /// Sends a message from the prepared first part and the serialized rest to the given Writer, see nc_encode_prepared().
     ///
     /// # Errors
     ///
-    /// On failure it returns a [`NCError::Deserialize`] error which contains the serde deserialize error.
-    pub(crate) fn nc_decode_data<D: DeserializeOwned>(&self, data: &[u8]) -> Result<D, NCError> {
-        let mut data_out: Vec<u8> = data.to_vec();
+    /// On failure it returns a [`NCError`].
+    #[cfg(feature = "net")]
+    pub(crate) fn nc_send_prepared<W: Write>(&self, frame: &NCPreparedFrame, rest: &[u8], tcp_stream: &mut W) -> Result<(), NCError> {
+        let data = self.nc_encode_prepared(frame, rest)?;
+        self.write_frame(&data, tcp_stream)
+    }
 
-        if self.compress {
-            data_out = decompress_size_prepended(&data_out)?;
+    /// Decode the given data from a `&[u8]` slice to the type `T`, for example a frame from nc_encode_data_codec().
+    ///
+    /// # Errors
+    ///
+    /// On failure it returns a [`NCError::Deserialize`] error which contains the serde deserialize error,
+    /// or a [`NCError::DataTooShort`] error if the data ends before the value is complete.
+    pub fn nc_decode_data<D: DeserializeOwned>(&self, data: &[u8]) -> Result<D, NCError> {
+        self.nc_decode_data_key(data).map(|(result, _)| result)
+    }
+
+    /// Same as nc_decode_data() but also returns the index of the previous key if the data was not encrypted with the current key.
+    ///
+    /// # Errors
+    ///
+    /// On failure it returns a [`NCError::Deserialize`] error which contains the serde deserialize error,
+    /// or a [`NCError::DataTooShort`] error if the data ends before the value is complete.
+    pub(crate) fn nc_decode_data_key<D: DeserializeOwned>(&self, data: &[u8]) -> Result<(D, Option<usize>), NCError> {
+        let (data_out, _, previous_key) = self.decode_frame(data)?;
+
+        Ok((deserialize_limited(&data_out)?, previous_key))
+    }
+
+    /// Decode a message with user data, see [`NCTyped`].
+    ///
+    /// # Errors
+    ///
+    /// On failure it returns a [`NCError::Deserialize`] error which contains the serde deserialize error,
+    /// or a [`NCError::DataTooShort`] error if the data ends before the value is complete.
+    /// If type_check is set and the message contains user data of another type it returns a [`NCError::TypeMismatch`] error.
+    #[cfg(feature = "net")]
+    pub(crate) fn nc_decode_message<D: DeserializeOwned + NCTyped>(&self, data: &[u8]) -> Result<D, NCError> {
+        self.nc_decode_message_key(data).map(|(result, _)| result)
+    }
+
+    /// Same as nc_decode_message() but also returns the index of the previous key if the data was not encrypted with the current key.
+    ///
+    /// # Errors
+    ///
+    /// On failure it returns a [`NCError::Deserialize`] error which contains the serde deserialize error,
+    /// or a [`NCError::DataTooShort`] error if the data ends before the value is complete.
+    /// If type_check is set and the message contains user data of another type it returns a [`NCError::TypeMismatch`] error.
+    #[cfg(feature = "net")]
+    pub(crate) fn nc_decode_message_key<D: DeserializeOwned + NCTyped>(&self, data: &[u8]) -> Result<(D, Option<usize>), NCError> {
+        let (data_out, type_hash, previous_key) = self.decode_frame(data)?;
+        // Messages from a node or server without the type check are not checked
+        let type_hash = type_hash.filter(|_| self.type_check);
+
+        // Check before deserializing, since data of another type usually can't be deserialized
+        if let Some(type_hash) = type_hash {
+            let expected = D::payload_types();
+
+            if !expected.iter().any(|name| self::type_hash(name) == type_hash) {
+                return Err(type_mismatch(expected.join(" or "), type_hash))
+            }
+        }
+
+        let message: D = deserialize_limited(&data_out)?;
+
+        if let (Some(type_hash), Some(expected)) = (type_hash, message.payload_type()) {
+            if self::type_hash(expected) != type_hash {
+                return Err(type_mismatch(expected.to_string(), type_hash))
+            }
         }
 
+        Ok((message, previous_key))
+    }
+
+    /// Removes the job id, decompresses and decrypts the data.
+    /// Returns the serialized data, the hash of the type name of the user data (if there is one) and
+    /// the index of the previous key if the data was not encrypted with the current key.
+    /// A frame that has been compressed with an unknown dictionary gives a [`NCError::UnknownDictionary`] error.
+    fn decode_frame(&self, data: &[u8]) -> Result<DecodedFrame, NCError> {
+        let (_, data) = split_job_id(data)?;
+        let (codec_byte, data) = data.split_first().ok_or(NCError::UnknownCodec(None))?;
+        let codec_id = codec_byte & !(TYPE_HASH_FLAG | SEGMENTS_FLAG | PLAIN_FLAG | DICTIONARY_FLAG);
+
+        if codec_id > 2 || (codec_byte & DICTIONARY_FLAG != 0 && codec_id != NCCodec::Zstd(0).id()) {
+            return Err(NCError::UnknownCodec(Some(*codec_byte)))
+        }
+
+        let (dictionary, data) = if codec_byte & DICTIONARY_FLAG != 0 {
+            if data.len() < 4 {
+                return Err(NCError::UnexpectedEof("dictionary id", data.len() as u64, 4))
+            }
+
+            let (id, data) = data.split_at(4);
+            let id = u32::from_le_bytes([id[0], id[1], id[2], id[3]]);
+            (Some(self.dictionary(id)?.ok_or(NCError::UnknownDictionary(id))?), data)
+        } else {
+            (None, data)
+        };
+
+        let decompress = |data: &[u8], limit: u64| match &dictionary {
+            Some(dictionary) => nc_dictionary::decompress(data, dictionary, limit),
+            None => NCCodec::decompress(codec_id, data, limit),
+        };
+
+        let mut previous_key = None;
+        let mut decrypted = None;
+
         if self.encrypt {
-            data_out = self.decrypt_data(&data_out)?;
+            // Only a rejection comes without encryption, see decode_plain_rejection()
+            if codec_byte & PLAIN_FLAG != 0 {
+                return Err(NCError::Decrypt)
+            }
+
+            let (data_out, key) = self.decrypt_data(data)?;
+            decrypted = Some(data_out);
+            previous_key = key;
+        }
+
+        let data = decrypted.as_deref().unwrap_or(data);
+
+        let mut data_out = if codec_byte & SEGMENTS_FLAG != 0 {
+            if data.len() < 8 {
+                return Err(NCError::UnexpectedEof("segment length", data.len() as u64, 8))
+            }
+
+            let (first_len, data) = data.split_at(8);
+            let first_len = u64::from_le_bytes(first_len.try_into().map_err(|_| NCError::UnexpectedEof("segment length", 0, 8))?);
+
+            if (data.len() as u64) < first_len {
+                return Err(NCError::UnexpectedEof("first segment", data.len() as u64, first_len))
+            }
+
+            let (first, rest) = data.split_at(first_len as usize);
+            let mut data_out = decompress(first, self.max_decompressed_bytes)?;
+            data_out.extend(decompress(rest, self.max_decompressed_bytes.saturating_sub(data_out.len() as u64))?);
+            data_out
+        } else {
+            decompress(data, self.max_decompressed_bytes)?
+        };
+
+        let mut type_hash = None;
+
+        if codec_byte & TYPE_HASH_FLAG != 0 {
+            if data_out.len() < 4 {
+                return Err(NCError::UnexpectedEof("type hash", data_out.len() as u64, 4))
+            }
+
+            let hash: Vec<u8> = data_out.drain(..4).collect();
+            type_hash = Some(u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]]));
         }
 
-        deserialize(&data_out).map_err(|e| NCError::Deserialize(e))
+        if self.log_payload_hashes {
+            info!("Received message, serialized size: {} bytes, hash: {}", data_out.len(), payload_hash(&data_out));
+        }
+
+        Ok((data_out, type_hash, previous_key))
     }
 
     /// Open a tcp connection and sends the data using the nc_send_data2() function.
@@ -94,8 +1042,9 @@ impl NCCommunicator {
     /// # Errors
     ///
     /// On failure it returns a [`NCError`].
-    pub(crate) fn nc_send_data<S: Serialize, A: ToSocketAddrs>(&mut self, data: &S, socket_addr: &A) -> Result<(), NCError> {
-        let mut tcp_stream = TcpStream::connect(socket_addr)?;
+    #[cfg(feature = "net")]
+    pub(crate) fn nc_send_data<S: Serialize + NCTyped>(&mut self, data: &S, server_addr: &NCServerAddr) -> Result<(), NCError> {
+        let mut tcp_stream = self.connect(server_addr)?;
         self.nc_send_data2(data, &mut tcp_stream)
     }
 
@@ -104,13 +1053,44 @@ impl NCCommunicator {
     /// # Errors
     ///
     /// On failure it returns a [`NCError`].
-    pub(crate) fn nc_send_data2<S: Serialize, W: Write>(&mut self, data: &S, tcp_stream: &mut W) -> Result<(), NCError> {
-        let data = self.nc_encode_data(data)?;
-        let data_len = data.len() as u64; // u64 is platform independent, usize is platform dependent
+    #[cfg(feature = "net")]
+    pub(crate) fn nc_send_data2<S: Serialize + NCTyped, W: Write>(&mut self, data: &S, tcp_stream: &mut W) -> Result<(), NCError> {
+        let data = self.encode_data(data, self.codec, None, data.payload_type())?;
+        self.write_frame(&data, tcp_stream)
+    }
 
-        tcp_stream.write_all(&data_len.to_le_bytes())?;
-        tcp_stream.write_all(&data)?;
-        tcp_stream.flush().map_err(|e| e.into())
+    /// Same as nc_send_data2() but uses the given codec instead of the default one.
+    ///
+    /// # Errors
+    ///
+    /// On failure it returns a [`NCError`].
+    #[cfg(feature = "net")]
+    pub(crate) fn nc_send_data2_codec<S: Serialize + NCTyped, W: Write>(&mut self, data: &S, codec: NCCodec, tcp_stream: &mut W) -> Result<(), NCError> {
+        let data = self.encode_data(data, codec, None, data.payload_type())?;
+        self.write_frame(&data, tcp_stream)
+    }
+
+    /// Same as nc_send_data2() but encrypts the data with the given previous key.
+    /// This is only used to send the new key to a node that still uses an old key.
+    ///
+    /// # Errors
+    ///
+    /// On failure it returns a [`NCError`].
+    #[cfg(feature = "net")]
+    pub(crate) fn nc_send_data2_previous_key<S: Serialize + NCTyped, W: Write>(&mut self, data: &S, previous_key: usize, tcp_stream: &mut W) -> Result<(), NCError> {
+        let data = self.encode_data(data, NCCodec::None, Some(previous_key), data.payload_type())?;
+        self.write_frame(&data, tcp_stream)
+    }
+
+    /// Write the length of the encoded data and the data itself to the given Writer with one write call, see [`nc_frame_writer`].
+    /// The frame is flushed at the end since the other side waits for it.
+    /// If the other side has closed the connection NCError::PeerDisconnected is returned.
+    /// The data has been encoded completely before, so a connection that is closed while the frame is written (see
+    /// [`NCConnectionWatchdog::drain()`](crate::nc_watchdog::NCConnectionWatchdog)) never leaves a frame behind that looks complete:
+    /// read_frame() on the other side returns NCError::UnexpectedEof.
+    #[cfg(feature = "net")]
+    fn write_frame<W: Write>(&self, data: &[u8], tcp_stream: &mut W) -> Result<(), NCError> {
+        nc_frame_writer::write_frame(tcp_stream, data).map_err(NCError::from_write_error)
     }
 
     /// Read data from the given Reader (usually a tcp stream) and deserialize it.
@@ -119,16 +1099,67 @@ impl NCCommunicator {
     /// # Errors
     ///
     /// On failure it returns a [`NCError`].
-    pub(crate) fn nc_receive_data<D: DeserializeOwned, R: Read>(&self, tcp_stream: &mut R) -> Result<D, NCError> {
+    /// A rejection from the server without encryption (see nc_send_plain_rejection()) is returned as the error for its reason.
+    #[cfg(feature = "net")]
+    pub(crate) fn nc_receive_data<D: DeserializeOwned + NCTyped, R: Read>(&self, tcp_stream: &mut R) -> Result<D, NCError> {
+        let data = Self::read_frame(tcp_stream, self.max_frame_bytes)?;
+
+        match self.decode_plain_rejection(&data) {
+            Some(error) => Err(error),
+            None => self.nc_decode_message(&data),
+        }
+    }
+
+    /// Read one encoded message from the given Reader without decoding it, see nc_decode_message_key() and [`split_job_id()`].
+    /// The frame must not be longer than max_frame_bytes (see max_frame_bytes in the NCConfiguration).
+    ///
+    /// # Errors
+    ///
+    /// On failure it returns a [`NCError`].
+    #[cfg(feature = "net")]
+    pub(crate) fn nc_receive_frame<R: Read>(tcp_stream: &mut R, max_frame_bytes: u64) -> Result<Vec<u8>, NCError> {
+        Self::read_frame(tcp_stream, max_frame_bytes)
+    }
+
+    /// The maximum length of a received frame, see nc_receive_frame().
+    #[cfg(feature = "net")]
+    pub(crate) fn max_frame_bytes(&self) -> u64 {
+        self.max_frame_bytes
+    }
+
+    /// Read the length of the encoded data and then the data itself from the given Reader.
+    /// A length bigger than max_frame_bytes is rejected with a [`NCError::FrameTooLarge`] error before anything is allocated or read.
+    /// Short reads are continued until the whole frame has arrived. If the other side closes the connection in the middle of the frame
+    /// a [`NCError::UnexpectedEof`] error is returned, so a partial frame is never decoded.
+    #[cfg(feature = "net")]
+    fn read_frame<R: Read>(tcp_stream: &mut R, max_frame_bytes: u64) -> Result<Vec<u8>, NCError> {
         let mut data_len: [u8; 8] = [0; 8];
-        tcp_stream.read_exact(&mut data_len)?;
+        let bytes_read = read_full(tcp_stream, &mut data_len)?;
+
+        if bytes_read < data_len.len() {
+            return Err(NCError::UnexpectedEof("length", bytes_read as u64, data_len.len() as u64))
+        }
+
         let data_len = u64::from_le_bytes(data_len);  // u64 is platform independent, usize is platform dependent
 
-        let mut data: Vec<u8> = vec![0; data_len as usize];
-        tcp_stream.read_exact(&mut data)?;
+        if data_len > max_frame_bytes {
+            return Err(NCError::FrameTooLarge(data_len, max_frame_bytes))
+        }
+
+        let mut data: Vec<u8> = Vec::with_capacity(data_len.min(READ_CHUNK_SIZE) as usize);
+
+        while (data.len() as u64) < data_len {
+            let start = data.len();
+            let end = start + (data_len - start as u64).min(READ_CHUNK_SIZE) as usize;
+            data.resize(end, 0);
+            let bytes_read = read_full(tcp_stream, &mut data[start..end])?;
+
+            if bytes_read < end - start {
+                return Err(NCError::UnexpectedEof("data", (start + bytes_read) as u64, data_len))
+            }
+        }
 
-        let result = self.nc_decode_data(&data)?;
-        Ok(result)
+        Ok(data)
     }
 
     /// Open a tcp stream, send the data using the nc_send_data2() function and receive data using the nc_receive_data() function.
@@ -137,22 +1168,75 @@ impl NCCommunicator {
     /// # Errors
     ///
     /// On failure it returns a [`NCError`].
-    pub(crate) fn nc_send_receive_data<S: Serialize, D: DeserializeOwned, A: ToSocketAddrs>(&mut self, data: &S, socket_addr: &A) -> Result<D, NCError> {
-        let mut tcp_stream = TcpStream::connect(socket_addr)?;
+    #[cfg(feature = "net")]
+    pub(crate) fn nc_send_receive_data<S: Serialize + NCTyped, D: DeserializeOwned + NCTyped>(&mut self, data: &S, server_addr: &NCServerAddr) -> Result<D, NCError> {
+        let mut tcp_stream = self.connect(server_addr)?;
 
         self.nc_send_data2(data, &mut tcp_stream)?;
         self.nc_receive_data(&mut tcp_stream)
     }
+
+    /// Sends the NCServerMessage::Rejected message with the given reason and message without encryption and compression (PLAIN_FLAG),
+    /// this is the answer of the server if it can't decrypt or decode the message of the node. See the module documentation.
+    #[cfg(feature = "net")]
+    pub(crate) fn nc_send_plain_rejection<W: Write>(&self, reason: NCRejectReason, message: String, tcp_stream: &mut W) -> Result<(), NCError> {
+        debug!("NCCommunicator::nc_send_plain_rejection()");
+
+        let rejection: NCServerMessage<(), (), ()> = NCServerMessage::Rejected { reason, message, retry_after: None };
+        let data_out = serialize(&rejection).map_err(NCError::Serialize)?;
+        self.write_frame(&self.add_header(data_out, NCCodec::None.id() | PLAIN_FLAG, None), tcp_stream)
+    }
+
+    /// Returns the error for a rejection without encryption (see nc_send_plain_rejection()), [`None`] if the frame is something else.
+    /// Only NCRejectReason::WrongKey and VersionMismatch are accepted like this, they just make the node exit.
+    #[cfg(feature = "net")]
+    fn decode_plain_rejection(&self, data: &[u8]) -> Option<NCError> {
+        let (_, data) = split_job_id(data).ok()?;
+
+        match data.split_first() {
+            Some((&codec_byte, data)) if codec_byte == NCCodec::None.id() | PLAIN_FLAG => {
+                match deserialize_limited(data).ok()? {
+                    NCServerMessage::<(), (), ()>::Rejected { reason: reason @ (NCRejectReason::WrongKey | NCRejectReason::VersionMismatch), message, retry_after } =>
+                        Some(NCError::rejected(reason, message, retry_after, &[])),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[cfg(feature = "net")]
+    use crate::nc_message::{NCServerMessage, NCJobStatus, NCChunkInfo, NCRejectReason};
+
+    /// In these tests the whole value is the user data.
+    #[cfg(feature = "net")]
+    macro_rules! typed {
+        ($($t:ty),*) => {
+            $(impl NCTyped for $t {
+                fn payload_type(&self) -> Option<&'static str> {
+                    Some(type_name::<$t>())
+                }
+
+                fn payload_types() -> Vec<&'static str> {
+                    vec![type_name::<$t>()]
+                }
+            })*
+        };
+    }
+
+    #[cfg(feature = "net")]
+    typed!((String, u32, bool), Vec<u32>, Vec<u64>, Vec<u8>, ());
+
+    #[cfg(feature = "net")]
     #[test]
     fn test_encode_decode() {
         let config = NCConfiguration {compress: false, encrypt: false, ..Default::default()};
-        let mut nc_communicator = NCCommunicator::new(&config);
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
         let data1: (String, u32, bool) = ("Hello World!".to_string(), 123456, false);
 
         let data2 = nc_communicator.nc_encode_data(&data1).unwrap();
@@ -162,10 +1246,11 @@ mod tests {
         assert_eq!(data1, data3);
     }
 
+    #[cfg(feature = "net")]
     #[test]
     fn test_encode_decode_compress() {
         let config = NCConfiguration {compress: true, encrypt: false, ..Default::default()};
-        let mut nc_communicator = NCCommunicator::new(&config);
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
         let data1: (String, u32, bool) = ("Hello World!".to_string(), 123456, false);
 
         let data2 = nc_communicator.nc_encode_data(&data1).unwrap();
@@ -175,72 +1260,76 @@ mod tests {
         assert_eq!(data1, data3);
     }
 
+    #[cfg(feature = "net")]
     #[test]
     fn test_encode_decode_encrypt() {
         let config = NCConfiguration {compress: false, encrypt: true, key: "7Fv2YhMzwrQHoXRAirOkB0QQDOjS4qnZ".to_string(), ..Default::default()};
-        let mut nc_communicator = NCCommunicator::new(&config);
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
         let data1: (String, u32, bool) = ("Hello World!".to_string(), 123456, false);
 
-        assert_eq!(nc_communicator.nonce, 0);
+        assert_eq!(nc_communicator.keys.lock().unwrap().nonce, 0);
 
         let data2 = nc_communicator.nc_encode_data(&data1).unwrap();
 
-        assert_eq!(nc_communicator.nonce, 1);
+        assert_eq!(nc_communicator.keys.lock().unwrap().nonce, 1);
 
         let data3: (String, u32, bool) = nc_communicator.nc_decode_data(&data2).unwrap();
 
-        assert_eq!(nc_communicator.nonce, 1);
+        assert_eq!(nc_communicator.keys.lock().unwrap().nonce, 1);
 
         assert_eq!(data1, data3);
     }
 
+    #[cfg(feature = "net")]
     #[test]
     fn test_encode_decode_compress_encrypt() {
         let config = NCConfiguration {compress: true, encrypt: true, key: "VnlUYvqu5S4tNHty0ccA1LAlsgqIXhIs".to_string(), ..Default::default()};
-        let mut nc_communicator = NCCommunicator::new(&config);
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
         let data1: (String, u32, bool) = ("Hello World!".to_string(), 123456, false);
 
-        assert_eq!(nc_communicator.nonce, 0);
+        assert_eq!(nc_communicator.keys.lock().unwrap().nonce, 0);
 
         let data2 = nc_communicator.nc_encode_data(&data1).unwrap();
 
-        assert_eq!(nc_communicator.nonce, 1);
+        assert_eq!(nc_communicator.keys.lock().unwrap().nonce, 1);
 
         let data3: (String, u32, bool) = nc_communicator.nc_decode_data(&data2).unwrap();
 
-        assert_eq!(nc_communicator.nonce, 1);
+        assert_eq!(nc_communicator.keys.lock().unwrap().nonce, 1);
 
         assert_eq!(data1, data3);
     }
 
+    #[cfg(feature = "net")]
     #[test]
     fn test_send_data2() {
         use std::convert::TryInto;
 
-        let config = NCConfiguration {compress: false, encrypt: false, ..Default::default()};
-        let mut nc_communicator = NCCommunicator::new(&config);
+        let config = NCConfiguration {compress: false, encrypt: false, type_check: false, ..Default::default()};
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
         let data1: (String, u32, bool) = ("Test send_data2!".to_string(), 121212, false);
 
         let mut buffer: Vec<u8> = Vec::new();
 
         nc_communicator.nc_send_data2(&data1, &mut buffer).unwrap();
 
-        assert_eq!(buffer.len(), 37);
+        assert_eq!(buffer.len(), 38);
 
         let len_buffer: [u8; 8] = buffer[0..8].try_into().unwrap();
         let data_len = u64::from_le_bytes(len_buffer);
 
-        assert_eq!(data_len, 29);
+        assert_eq!(data_len, 30);
 
         let data2: (String, u32, bool) = nc_communicator.nc_decode_data(&buffer[8..]).unwrap();
 
         assert_eq!(data1, data2);
     }
 
+    #[cfg(feature = "net")]
     #[test]
     fn test_receive_data() {
         let config = NCConfiguration {compress: false, encrypt: false, ..Default::default()};
-        let mut nc_communicator = NCCommunicator::new(&config);
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
         let data1: (String, u32, bool) = ("Test receive_data!".to_string(), 998877, true);
         let mut data2 = nc_communicator.nc_encode_data(&data1).unwrap();
 
@@ -255,10 +1344,38 @@ mod tests {
         assert_eq!(data1, data3);
     }
 
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_plain_rejection() {
+        let server = NCCommunicator::new(&NCConfiguration { encrypt: true, key: "u8PqN2lO3ZJbm6DHkTnR0cyVaSx1FWgi".to_string(), ..Default::default() }).unwrap();
+        let node = NCCommunicator::new(&NCConfiguration { encrypt: true, key: "Hn3kqPZ0lXv8dWmYc1RtG5sJbQ2eA7fU".to_string(), ..Default::default() }).unwrap();
+        let plain = |reason| {
+            let mut buffer = Vec::new();
+            server.nc_send_plain_rejection(reason, "use the same key".to_string(), &mut buffer).unwrap();
+            buffer
+        };
+
+        // The node can read the rejection although it uses another key
+        let result = node.nc_receive_data::<NCServerMessage<(), (), ()>, _>(&mut plain(NCRejectReason::WrongKey).as_slice());
+        assert!(matches!(result, Err(NCError::WrongKey(message)) if message == "use the same key"));
+        let result = node.nc_receive_data::<NCServerMessage<(), (), ()>, _>(&mut plain(NCRejectReason::VersionMismatch).as_slice());
+        assert!(matches!(result, Err(NCError::VersionMismatch(_))));
+
+        // Other reasons must be encrypted
+        let result = node.nc_receive_data::<NCServerMessage<(), (), ()>, _>(&mut plain(NCRejectReason::Disabled).as_slice());
+        assert!(matches!(result, Err(NCError::Decrypt)));
+
+        // A plain frame is never accepted by a communicator with encryption
+        let frame = NCCommunicator::nc_receive_frame(&mut plain(NCRejectReason::WrongKey).as_slice(), u64::MAX).unwrap();
+        assert!(matches!(server.nc_decode_message::<NCServerMessage<(), (), ()>>(&frame), Err(NCError::Decrypt)));
+        assert!(matches!(node.nc_decode_message::<NCServerMessage<(), (), ()>>(&frame), Err(NCError::Decrypt)));
+    }
+
+    #[cfg(feature = "net")]
     #[test]
     fn test_send_and_receive() {
         let config = NCConfiguration {compress: false, encrypt: false, ..Default::default()};
-        let mut nc_communicator = NCCommunicator::new(&config);
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
         let data1: (String, u32, bool) = ("Test send and then receive data!".to_string(), 550055, true);
 
         let mut buffer: Vec<u8> = Vec::new();
@@ -269,4 +1386,665 @@ mod tests {
 
         assert_eq!(data1, data2);
     }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_encode_decode_zstd_encrypt() {
+        let config = NCConfiguration {encrypt: true, allowed_codecs: vec![NCCodec::Zstd(3)], key: "u8PqN2lO3ZJbm6DHkTnR0cyVaSx1FWgi".to_string(), ..Default::default()};
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
+        let data1: Vec<u32> = vec![42; 1000];
+
+        assert_eq!(nc_communicator.codec, NCCodec::Zstd(3));
+
+        let data2 = nc_communicator.nc_encode_data(&data1).unwrap();
+
+        assert_eq!(data2[0], 2);
+
+        let data3: Vec<u32> = nc_communicator.nc_decode_data(&data2).unwrap();
+
+        assert_eq!(data1, data3);
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_decode_mixed_codecs() {
+        let config = NCConfiguration {compress: false, encrypt: false, ..Default::default()};
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
+        let data1: (String, u32) = ("Mixed codecs".to_string(), 7);
+
+        assert_eq!(nc_communicator.codec, NCCodec::None);
+
+        for codec in [NCCodec::None, NCCodec::Lz4, NCCodec::Zstd(1), NCCodec::Zstd(19)] {
+            let data2 = nc_communicator.nc_encode_data_codec(&data1, codec).unwrap();
+            let data3: (String, u32) = nc_communicator.nc_decode_data(&data2).unwrap();
+
+            assert_eq!(data1, data3);
+        }
+    }
+
+    #[test]
+    fn test_decode_unknown_codec() {
+        let config = NCConfiguration::default();
+        let nc_communicator = NCCommunicator::new(&config).unwrap();
+
+        assert!(matches!(nc_communicator.nc_decode_data::<u32>(&[7, 0, 0, 0, 0]), Err(NCError::UnknownCodec(Some(7)))));
+        assert!(matches!(nc_communicator.nc_decode_data::<u32>(&[]), Err(NCError::UnknownCodec(None))));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_empty_payload() {
+        // () is serialized to zero bytes, an empty Vec<u8> to its length only
+        for codec in [NCCodec::None, NCCodec::Lz4, NCCodec::Zstd(3)] {
+            for encrypt in [false, true] {
+                let config = NCConfiguration { encrypt, key: "Qm3xVt7KpL0sNd9RgW2hYc5JfE8aZb1U".to_string(), compression_min_size: 0, type_check: true, ..Default::default() };
+                let mut nc_communicator = NCCommunicator::new(&config).unwrap();
+                nc_communicator.set_codec(codec);
+
+                let mut frame = Vec::new();
+                nc_communicator.nc_send_data2(&Vec::<u8>::new(), &mut frame).unwrap();
+                assert_eq!(frame[8] & !TYPE_HASH_FLAG, codec.id());
+                let received: Vec<u8> = nc_communicator.nc_receive_data(&mut frame.as_slice()).unwrap();
+                assert!(received.is_empty());
+
+                let mut frame = Vec::new();
+                nc_communicator.nc_send_data2(&(), &mut frame).unwrap();
+                let _: () = nc_communicator.nc_receive_data(&mut frame.as_slice()).unwrap();
+            }
+        }
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_empty_layers() {
+        let config = NCConfiguration { encrypt: true, key: "Qm3xVt7KpL0sNd9RgW2hYc5JfE8aZb1U".to_string(), ..Default::default() };
+        let nc_communicator = NCCommunicator::new(&config).unwrap();
+
+        // Encryption: nonce and tag only
+        let encrypted = nc_communicator.encrypt_data(&[], None).unwrap();
+        assert_eq!(encrypted.len(), 12 + 16);
+        assert_eq!(nc_communicator.decrypt_data(&encrypted).unwrap(), (Vec::new(), None));
+
+        // Compression
+        assert!(lz4_flex::decompress_size_prepended(&compress_prepend_size(&[])).unwrap().is_empty());
+        assert!(zstd::decode_all(zstd::encode_all(&[][..], 3).unwrap().as_slice()).unwrap().is_empty());
+
+        // A zero-length frame is read, but it can't be decoded since it has no codec byte
+        let mut frame = Vec::new();
+        nc_communicator.write_frame(&[], &mut frame).unwrap();
+        assert_eq!(frame, 0u64.to_le_bytes());
+        let data = NCCommunicator::nc_receive_frame(&mut frame.as_slice(), u64::MAX).unwrap();
+        assert!(data.is_empty());
+        assert!(matches!(nc_communicator.nc_decode_data::<()>(&data), Err(NCError::UnknownCodec(None))));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_empty_chunk_data() {
+        let mut nc_communicator = NCCommunicator::new(&NCConfiguration { type_check: false, ..Default::default() }).unwrap();
+
+        // An empty chunk is delivered as such and is not the end of the job
+        let message: NCServerMessage<(), Vec<u8>, ()> = NCServerMessage::JobStatus(NCJobStatus::Unfinished(Vec::new(), NCChunkInfo { chunk_id: 3, ..Default::default() }));
+        let data = nc_communicator.nc_encode_data(&message).unwrap();
+        let message: NCServerMessage<(), Vec<u8>, ()> = nc_communicator.nc_decode_message(&data).unwrap();
+        assert!(matches!(message, NCServerMessage::JobStatus(NCJobStatus::Unfinished(data, chunk_info)) if data.is_empty() && chunk_info.chunk_id == 3));
+
+        let finished: NCServerMessage<(), Vec<u8>, ()> = NCServerMessage::JobStatus(NCJobStatus::Finished);
+        assert_ne!(nc_communicator.nc_encode_data(&finished).unwrap(), data);
+
+        // Decoding empty data as another type names that type
+        let data = nc_communicator.nc_encode_data(&Vec::<u8>::new()).unwrap();
+        let result = nc_communicator.nc_decode_data::<(u64, u64)>(&data);
+        assert!(matches!(result, Err(NCError::DataTooShort(ref expected, 8)) if expected == "(u64, u64)"));
+        assert_eq!(result.unwrap_err().to_string(), "Could not deserialize (u64, u64): the data ends after 8 bytes");
+
+        assert!(matches!(nc_communicator.nc_decode_data::<u32>(&[0]), Err(NCError::DataTooShort(ref expected, 0)) if expected == "u32"));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_prepared_frame() {
+        for (codec, encrypt) in [(NCCodec::None, false), (NCCodec::Lz4, false), (NCCodec::Lz4, true), (NCCodec::Zstd(3), true)] {
+            let config = NCConfiguration { encrypt, key: "u8PqN2lO3ZJbm6DHkTnR0cyVaSx1FWgi".to_string(), type_check: true,
+                compression_min_size: 0, job_id: Some("job".to_string()), ..Default::default() };
+            let mut nc_communicator = NCCommunicator::new(&config).unwrap();
+            let message: NCServerMessage<(), Vec<u8>, ()> = NCServerMessage::JobStatus(NCJobStatus::Unfinished(vec![7; 1000], NCChunkInfo { chunk_id: 3, ..Default::default() }));
+            let chunk_info = bincode::serialize(&NCChunkInfo { chunk_id: 3, ..Default::default() }).unwrap();
+            let mut serialized = bincode::serialize(&message).unwrap();
+            serialized.truncate(serialized.len() - chunk_info.len());
+
+            let frame = nc_communicator.prepare_frame(serialized, "message", codec, message.payload_type()).unwrap();
+            let first = nc_communicator.nc_encode_prepared(&frame, &chunk_info).unwrap();
+            let second = nc_communicator.nc_encode_prepared(&frame, &chunk_info).unwrap();
+            assert_eq!(first == second, !encrypt);
+
+            // The node decodes it like any other message
+            for data in [first, second, nc_communicator.nc_encode_data_codec(&message, codec).unwrap()] {
+                match nc_communicator.nc_decode_data::<NCServerMessage<(), Vec<u8>, ()>>(&data).unwrap() {
+                    NCServerMessage::JobStatus(NCJobStatus::Unfinished(data, chunk_info)) => assert_eq!((data, chunk_info.chunk_id), (vec![7; 1000], 3)),
+                    _ => panic!("Unexpected message"),
+                }
+            }
+        }
+
+        // Compressed before it's encrypted: the repeated data shrinks also with encryption
+        let config = NCConfiguration { encrypt: true, key: "u8PqN2lO3ZJbm6DHkTnR0cyVaSx1FWgi".to_string(), ..Default::default() };
+        assert!(NCCommunicator::new(&config).unwrap().nc_encode_data_codec(&vec![7_u8; 10_000], NCCodec::Lz4).unwrap().len() < 1000);
+
+        // The first part is longer than the data
+        let nc_communicator = NCCommunicator::new(&NCConfiguration::default()).unwrap();
+        let mut data = vec![SEGMENTS_FLAG];
+        data.extend_from_slice(&100_u64.to_le_bytes());
+        data.extend_from_slice(&[1, 2, 3]);
+        assert!(matches!(nc_communicator.nc_decode_data::<Vec<u8>>(&data), Err(NCError::UnexpectedEof("first segment", 3, 100))));
+        assert!(matches!(nc_communicator.nc_decode_data::<Vec<u8>>(&[SEGMENTS_FLAG, 1]), Err(NCError::UnexpectedEof("segment length", 1, 8))));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_decode_huge_length() {
+        let config = NCConfiguration {compress: false, encrypt: false, ..Default::default()};
+        let nc_communicator = NCCommunicator::new(&config).unwrap();
+
+        // Codec None, a Vec<u64> that claims to have 2^40 elements but only one follows
+        let mut data = vec![0];
+        data.extend_from_slice(&(1u64 << 40).to_le_bytes());
+        data.extend_from_slice(&7u64.to_le_bytes());
+
+        assert!(matches!(nc_communicator.nc_decode_data::<Vec<u64>>(&data), Err(NCError::DataTooShort(_, 16))));
+        assert!(matches!(nc_communicator.nc_decode_message::<Vec<u64>>(&data), Err(NCError::DataTooShort(_, 16))));
+        // Also for a payload that is decoded on its own, like a signed result
+        assert!(matches!(deserialize_limited::<Vec<u64>>(&data[1..]), Err(NCError::DataTooShort(_, 16))));
+
+        // The same data with the right length still works
+        data[1..9].copy_from_slice(&1u64.to_le_bytes());
+        assert_eq!(nc_communicator.nc_decode_data::<Vec<u64>>(&data).unwrap(), vec![7]);
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_decompression_bomb() {
+        let config = NCConfiguration { compression_min_size: 0, ..Default::default() };
+        let mut sender = NCCommunicator::new(&config).unwrap();
+        let receiver = NCCommunicator::new(&NCConfiguration { max_decompressed_bytes: 1024 * 1024, ..config.clone() }).unwrap();
+        let zeros = vec![0_u8; 16 * 1024 * 1024];
+
+        // 16 MB of zeros compress to a few kB, the receiver stops at its limit
+        for codec in [NCCodec::Lz4, NCCodec::Zstd(3)] {
+            let data = sender.nc_encode_data_codec(&zeros, codec).unwrap();
+            assert!(data.len() < 128 * 1024, "{:?}: {}", codec, data.len());
+            assert!(matches!(receiver.nc_decode_data::<Vec<u8>>(&data), Err(NCError::DecompressionLimit(1048576))));
+            assert_eq!(sender.nc_decode_data::<Vec<u8>>(&data).unwrap().len(), zeros.len());
+        }
+
+        // An lz4 size prefix that is bigger than the limit or than the data can hold is not allocated
+        let mut data = vec![NCCodec::Lz4.id()];
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        data.extend_from_slice(&[0x1f, 0, 1, 0, 0]);
+        assert!(matches!(receiver.nc_decode_data::<Vec<u8>>(&data), Err(NCError::DecompressionLimit(1048576))));
+        data[1..5].copy_from_slice(&1_000_000_u32.to_le_bytes());
+        assert!(matches!(receiver.nc_decode_data::<Vec<u8>>(&data), Err(NCError::Decompress(_))));
+
+        // The limit counts for both parts of a prepared frame together
+        let receiver = NCCommunicator::new(&NCConfiguration { max_decompressed_bytes: 1500, ..config.clone() }).unwrap();
+        let frame = sender.prepare_frame(vec![1; 1000], "message", NCCodec::Zstd(3), None).unwrap();
+        assert!(matches!(receiver.nc_decode_data::<Vec<u8>>(&sender.nc_encode_prepared(&frame, &[2; 1000]).unwrap()), Err(NCError::DecompressionLimit(500))));
+
+        // zstd with a dictionary
+        let dictionary: Vec<u8> = (0..200).flat_map(|i| format!("{{\"chunk\":{}}}", i).into_bytes()).collect();
+        let compressed = nc_dictionary::compress(&zeros, 3, &dictionary).unwrap();
+        assert!(matches!(nc_dictionary::decompress(&compressed, &dictionary, 1024 * 1024), Err(NCError::DecompressionLimit(1048576))));
+        assert_eq!(nc_dictionary::decompress(&compressed, &dictionary, zeros.len() as u64).unwrap(), zeros);
+    }
+
+    #[test]
+    fn test_negotiate_codec() {
+        let server = [NCCodec::Zstd(5), NCCodec::Lz4, NCCodec::None];
+
+        assert_eq!(NCCodec::negotiate(&server, &[NCCodec::None, NCCodec::Lz4]), Some(NCCodec::Lz4));
+        assert_eq!(NCCodec::negotiate(&server, &[NCCodec::Zstd(1)]), Some(NCCodec::Zstd(5)));
+        assert_eq!(NCCodec::negotiate(&server, &[NCCodec::None]), Some(NCCodec::None));
+        assert_eq!(NCCodec::negotiate(&[NCCodec::Zstd(3)], &[NCCodec::Lz4, NCCodec::None]), None);
+    }
+
+    #[test]
+    fn test_encoded_size() {
+        let data1: Vec<u8> = vec![0; 10];
+        let data2: Vec<Vec<u8>> = vec![vec![0]; 10];
+
+        assert_eq!(nc_encoded_size(&42_u32), 4);
+        assert_eq!(nc_encoded_size(&data1), 8 + 10);
+        // Every inner vector stores its length
+        assert_eq!(nc_encoded_size(&data2), 8 + 10 * (8 + 1));
+    }
+
+    #[cfg(feature = "net")]
+    const KEY1: &str = "u8PqN2lO3ZJbm6DHkTnR0cyVaSx1FWgi";
+    #[cfg(feature = "net")]
+    const KEY2: &str = "Hn3kqPZ0lXv8dWmYc1RtG5sJbQ2eA7fU";
+    #[cfg(feature = "net")]
+    const KEY3: &str = "pL4xT9nVb0cK2wQzR8mY6hJ1sD3gF5aE";
+
+    #[cfg(feature = "net")]
+    fn communicator_with_keys(key: &str, previous_keys: &[&str]) -> NCCommunicator {
+        let config = NCConfiguration {
+            encrypt: true,
+            key: key.to_string(),
+            previous_keys: previous_keys.iter().map(|key| key.to_string()).collect(),
+            ..Default::default()
+        };
+
+        NCCommunicator::new(&config).unwrap()
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_decode_previous_key() {
+        let mut old_communicator = communicator_with_keys(KEY1, &[]);
+        let new_communicator = communicator_with_keys(KEY2, &[KEY1]);
+        let other_communicator = communicator_with_keys(KEY3, &[KEY2]);
+        let data1: Vec<u32> = vec![1, 2, 3];
+
+        let data2 = old_communicator.nc_encode_data(&data1).unwrap();
+        let (data3, previous_key): (Vec<u32>, _) = new_communicator.nc_decode_data_key(&data2).unwrap();
+
+        assert_eq!(data1, data3);
+        assert_eq!(previous_key, Some(0));
+        assert!(matches!(other_communicator.nc_decode_data::<Vec<u32>>(&data2), Err(NCError::Decrypt)));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_rotate_key() {
+        let mut node_communicator = communicator_with_keys(KEY1, &[]);
+        let mut shared_communicator = node_communicator.share();
+        let server_communicator = communicator_with_keys(KEY1, &[]);
+        let data1: u64 = 42;
+
+        let data_key1 = node_communicator.nc_encode_data(&data1).unwrap();
+
+        server_communicator.rotate_key(KEY2).unwrap();
+        assert_eq!(server_communicator.current_key().unwrap(), KEY2);
+        assert_eq!(server_communicator.nc_decode_data_key::<u64>(&data_key1).unwrap(), (42, Some(0)));
+
+        node_communicator.rotate_key(KEY2).unwrap();
+        // The shared communicator uses the new key, too
+        let data_key2 = shared_communicator.nc_encode_data(&data1).unwrap();
+        assert_eq!(server_communicator.nc_decode_data_key::<u64>(&data_key2).unwrap(), (42, None));
+
+        // Only one previous key is kept, so KEY1 has expired now
+        server_communicator.rotate_key(KEY3).unwrap();
+        assert_eq!(server_communicator.nc_decode_data_key::<u64>(&data_key2).unwrap(), (42, Some(0)));
+        assert!(matches!(server_communicator.nc_decode_data::<u64>(&data_key1), Err(NCError::Decrypt)));
+
+        assert!(matches!(server_communicator.rotate_key("too short"), Err(NCError::InvalidKey(_))));
+        assert_eq!(server_communicator.current_key().unwrap(), KEY3);
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_key_formats() {
+        let bytes: Vec<u8> = (0..32).collect();
+        let hex_key = "hex:000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1F";
+        let base64_key = "base64:AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+        assert_eq!(decode_key(KEY1).unwrap().to_vec(), KEY1.as_bytes());
+        assert_eq!(decode_key(hex_key).unwrap().to_vec(), bytes);
+        assert_eq!(decode_key(base64_key).unwrap().to_vec(), bytes);
+        assert_eq!(decode_key(base64_key.trim_end_matches('=')).unwrap().to_vec(), bytes);
+
+        // The same key in two formats
+        let mut hex_communicator = communicator_with_keys(hex_key, &[]);
+        let base64_communicator = communicator_with_keys(base64_key, &[]);
+        let data = hex_communicator.nc_encode_data(&42_u64).unwrap();
+        assert_eq!(base64_communicator.nc_decode_data::<u64>(&data).unwrap(), 42);
+
+        // The errors explain the format, but never contain the key
+        let problems = [
+            ("secret but short", "the key contains spaces"),
+            ("secret-and-short", "the key has 16 characters instead of 32"),
+            ("secret-with-umlaut-ä-1234567890", "the key contains spaces, control or non ASCII characters"),
+            ("hex:5ecre7", "the hex key contains characters that are not hex digits"),
+            ("hex:5ec7e7", "the hex key has 6 digits instead of 64"),
+            ("base64:c2VjcmV0", "the base64 key has 6 bytes instead of 32"),
+            ("base64:secret!", "the base64 key contains characters that are not base64 digits"),
+        ];
+
+        for (key, problem) in problems {
+            let message = decode_key(key).unwrap_err();
+            assert!(message.starts_with(problem), "{}", message);
+            assert!(message.contains("expected 32 printable ASCII characters"));
+            assert!(!message.contains(key.trim_start_matches("hex:").trim_start_matches("base64:")));
+        }
+
+        let config = NCConfiguration { previous_keys: vec![KEY1.to_string(), "secret".to_string()], ..Default::default() };
+        let message = config.check_keys().unwrap_err().to_string();
+        assert!(message.starts_with("Invalid encryption key: previous_keys[1]: the key has 6 characters instead of 32"), "{}", message);
+
+        // Generated keys
+        let key = NCConfiguration::generate_key();
+        assert_eq!(key.len(), 68);
+        assert!(decode_key(&key).is_ok());
+        assert_ne!(key, NCConfiguration::generate_key());
+    }
+
+    /// Delivers the data one byte at a time, every other read is interrupted.
+    #[cfg(feature = "net")]
+    struct TrickleReader {
+        data: Vec<u8>,
+        pos: usize,
+        interrupt: bool,
+    }
+
+    #[cfg(feature = "net")]
+    impl Read for TrickleReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+
+            if self.interrupt {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "signal"))
+            }
+
+            if self.pos == self.data.len() || buf.is_empty() {
+                return Ok(0)
+            }
+
+            buf[0] = self.data[self.pos];
+            self.pos += 1;
+            Ok(1)
+        }
+    }
+
+    #[cfg(feature = "net")]
+    fn encoded_frame(nc_communicator: &mut NCCommunicator, data: &Vec<u32>) -> Vec<u8> {
+        let mut frame = Vec::new();
+        nc_communicator.nc_send_data2(data, &mut frame).unwrap();
+        frame
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_receive_one_byte_reads() {
+        let mut nc_communicator = NCCommunicator::new(&NCConfiguration::default()).unwrap();
+        let data: Vec<u32> = (0..100).collect();
+        let mut reader = TrickleReader { data: encoded_frame(&mut nc_communicator, &data), pos: 0, interrupt: false };
+
+        let received: Vec<u32> = nc_communicator.nc_receive_data(&mut reader).unwrap();
+        assert_eq!(received, data);
+        assert_eq!(reader.pos, reader.data.len());
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_receive_closed_mid_frame() {
+        let mut nc_communicator = NCCommunicator::new(&NCConfiguration::default()).unwrap();
+        let frame = encoded_frame(&mut nc_communicator, &(0..100).collect());
+        let payload_len = (frame.len() - 8) as u64;
+
+        // Closed after half of the payload
+        let mut reader = TrickleReader { data: frame[..8 + (payload_len as usize) / 2].to_vec(), pos: 0, interrupt: false };
+        let result = nc_communicator.nc_receive_data::<Vec<u32>, _>(&mut reader);
+        assert!(matches!(result, Err(NCError::UnexpectedEof("data", read, expected)) if read == payload_len / 2 && expected == payload_len));
+
+        // Closed in the middle of the length
+        let result = nc_communicator.nc_receive_data::<Vec<u32>, _>(&mut &frame[..3]);
+        assert!(matches!(result, Err(NCError::UnexpectedEof("length", 3, 8))));
+
+        // A broken length within max_frame_bytes doesn't allocate everything at once
+        let mut broken = (1024 * 1024 * 1024_u64).to_le_bytes().to_vec();
+        broken.extend_from_slice(&[1, 2, 3]);
+        let result = nc_communicator.nc_receive_data::<Vec<u32>, _>(&mut broken.as_slice());
+        assert!(matches!(result, Err(NCError::UnexpectedEof("data", 3, 1073741824))));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_receive_frame_too_large() {
+        let nc_communicator = NCCommunicator::new(&NCConfiguration { max_frame_bytes: 1024, ..Default::default() }).unwrap();
+
+        // Rejected right after the length, the rest of the stream is not read
+        let mut header = u64::MAX.to_le_bytes().to_vec();
+        header.extend_from_slice(&[1, 2, 3]);
+        let mut reader = header.as_slice();
+        let result = nc_communicator.nc_receive_data::<Vec<u32>, _>(&mut reader);
+        assert!(matches!(result, Err(NCError::FrameTooLarge(u64::MAX, 1024))));
+        assert_eq!(reader, [1, 2, 3]);
+
+        let result = NCCommunicator::nc_receive_frame(&mut &1025_u64.to_le_bytes()[..], 1024);
+        assert!(matches!(result, Err(NCError::FrameTooLarge(1025, 1024))));
+
+        // Exactly the limit is fine
+        let mut frame = 1024_u64.to_le_bytes().to_vec();
+        frame.extend_from_slice(&[7; 1024]);
+        assert_eq!(NCCommunicator::nc_receive_frame(&mut frame.as_slice(), 1024).unwrap(), [7; 1024]);
+
+        let config = NCConfiguration { max_frame_bytes: 0, ..Default::default() };
+        assert!(config.check().unwrap_err().contains("max_frame_bytes"));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_receive_large_frame() {
+        let config = NCConfiguration { compress: false, ..Default::default() };
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
+        let data: Vec<u32> = (0..(READ_CHUNK_SIZE as u32)).collect();
+        let frame = encoded_frame(&mut nc_communicator, &data);
+
+        let received: Vec<u32> = nc_communicator.nc_receive_data(&mut frame.as_slice()).unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_job_id() {
+        let config = NCConfiguration { job_id: Some("mandel".to_string()), encrypt: true, compression_min_size: 0, ..Default::default() };
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
+        let data: Vec<u32> = (0..10).collect();
+
+        let encoded = nc_communicator.nc_encode_data(&data).unwrap();
+        let (job_id, rest) = split_job_id(&encoded).unwrap();
+        assert_eq!(job_id.as_deref(), Some("mandel"));
+        assert_eq!(rest[0], NCCodec::Lz4.id());
+
+        // The job id is ignored for decoding, also by a communicator without a job id
+        let other = NCCommunicator::new(&NCConfiguration { encrypt: true, ..Default::default() }).unwrap();
+        assert_eq!(other.nc_decode_data::<Vec<u32>>(&encoded).unwrap(), data);
+
+        let encoded = other.share().nc_encode_data(&data).unwrap();
+        assert_eq!(split_job_id(&encoded).unwrap().0, None);
+
+        assert!(matches!(split_job_id(&[JOB_TAG, 5, b'a']), Err(NCError::UnexpectedEof("job id", 1, 5))));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_type_check() {
+        let config = NCConfiguration { type_check: true, encrypt: true, compression_min_size: 0, ..Default::default() };
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
+        let data: Vec<u32> = (0..10).collect();
+        let frame = encoded_frame(&mut nc_communicator, &data);
+
+        assert_eq!(frame[8], NCCodec::Lz4.id() | TYPE_HASH_FLAG);
+        assert_eq!(nc_communicator.nc_receive_data::<Vec<u32>, _>(&mut frame.as_slice()).unwrap(), data);
+
+        let result = nc_communicator.nc_receive_data::<Vec<u64>, _>(&mut frame.as_slice());
+        assert!(matches!(result, Err(NCError::TypeMismatch { expected, got })
+            if expected == "alloc::vec::Vec<u64>" && got == "alloc::vec::Vec<u32>"));
+
+        // The untyped path ignores the hash
+        assert_eq!(nc_communicator.nc_decode_data::<Vec<u32>>(&frame[8..]).unwrap(), data);
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_type_check_payload() {
+        let mut nc_communicator = NCCommunicator::new(&NCConfiguration { type_check: true, ..Default::default() }).unwrap();
+        let mut frame = Vec::new();
+
+        // A message without user data has no hash
+        let message: NCServerMessage<(), u32, String> = NCServerMessage::ResultAck { job_finished: false };
+        nc_communicator.nc_send_data2(&message, &mut frame).unwrap();
+        assert_eq!(frame[8] & TYPE_HASH_FLAG, 0);
+
+        let message: NCServerMessage<(), u32, String> = NCServerMessage::JobStatus(NCJobStatus::Unfinished(7, NCChunkInfo::default()));
+        frame.clear();
+        nc_communicator.nc_send_data2(&message, &mut frame).unwrap();
+        let received: NCServerMessage<(), u32, String> = nc_communicator.nc_receive_data(&mut frame.as_slice()).unwrap();
+        assert!(matches!(received, NCServerMessage::JobStatus(NCJobStatus::Unfinished(7, _))));
+
+        // A string can be deserialized as a number, but the type of the custom message doesn't match
+        let message: NCServerMessage<(), u32, String> = NCServerMessage::CustomMessage("abc".to_string());
+        frame.clear();
+        nc_communicator.nc_send_data2(&message, &mut frame).unwrap();
+        let result = nc_communicator.nc_receive_data::<NCServerMessage<(), String, u32>, _>(&mut frame.as_slice());
+        assert!(matches!(result, Err(NCError::TypeMismatch { expected, got }) if expected == "u32" && got == "alloc::string::String"));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_type_check_mixed() {
+        let mut with_check = NCCommunicator::new(&NCConfiguration { type_check: true, ..Default::default() }).unwrap();
+        let mut without_check = NCCommunicator::new(&NCConfiguration { type_check: false, ..Default::default() }).unwrap();
+        let data: Vec<u32> = (0..10).collect();
+
+        let frame = encoded_frame(&mut without_check, &data);
+        assert_eq!(frame[8] & TYPE_HASH_FLAG, 0);
+        assert_eq!(with_check.nc_receive_data::<Vec<u32>, _>(&mut frame.as_slice()).unwrap(), data);
+
+        let frame = encoded_frame(&mut with_check, &data);
+        assert_eq!(without_check.nc_receive_data::<Vec<u32>, _>(&mut frame.as_slice()).unwrap(), data);
+
+        // Not checked since the check is disabled on this side
+        let result = without_check.nc_receive_data::<Vec<u64>, _>(&mut frame.as_slice());
+        assert!(!matches!(result, Err(NCError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn test_type_hash() {
+        assert_eq!(type_hash(""), 0x811c_9dc5);
+        assert_eq!(type_hash("a"), 0xe40c_292c);
+        assert_eq!(type_hash(type_name::<&Vec<u32>>()), type_hash(type_name::<Vec<u32>>()));
+        assert_ne!(type_hash(type_name::<Vec<u32>>()), type_hash(type_name::<Vec<u64>>()));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_compression_min_size() {
+        // Not encrypted, so the frame is only the serialized data: 8 bytes length + 100 bytes
+        let config = NCConfiguration { compress: true, encrypt: false, compression_min_size: 108, ..Default::default() };
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
+
+        let data = nc_communicator.nc_encode_data(&vec![0u8; 100]).unwrap();
+        assert_eq!(data[0], NCCodec::Lz4.id());
+        assert_eq!(nc_communicator.uncompressed_frames(), 0);
+
+        let data = nc_communicator.nc_encode_data(&vec![0u8; 99]).unwrap();
+        assert_eq!(data[0], NCCodec::None.id());
+        assert_eq!(nc_communicator.uncompressed_frames(), 1);
+        assert_eq!(nc_communicator.nc_decode_data::<Vec<u8>>(&data).unwrap(), vec![0u8; 99]);
+
+        // The counter is shared
+        assert_eq!(nc_communicator.share().uncompressed_frames(), 1);
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_mixed_compressed_frames() {
+        // Encrypted data can't be compressed, so the size of the large frame would not show if it has been compressed
+        let config = NCConfiguration { compress: true, encrypt: false, ..Default::default() };
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
+        let small: Vec<u32> = vec![1, 2, 3];
+        let large: Vec<u32> = vec![7; 4000];
+
+        let mut buffer = Vec::new();
+        buffer.extend(encoded_frame(&mut nc_communicator, &small));
+        buffer.extend(encoded_frame(&mut nc_communicator, &large));
+        buffer.extend(encoded_frame(&mut nc_communicator, &small));
+
+        assert_eq!(nc_communicator.uncompressed_frames(), 2);
+        assert!(buffer.len() < 4 * 4000);
+
+        let mut reader = buffer.as_slice();
+        assert_eq!(nc_communicator.nc_receive_data::<Vec<u32>, _>(&mut reader).unwrap(), small);
+        assert_eq!(nc_communicator.nc_receive_data::<Vec<u32>, _>(&mut reader).unwrap(), large);
+        assert_eq!(nc_communicator.nc_receive_data::<Vec<u32>, _>(&mut reader).unwrap(), small);
+        assert!(reader.is_empty());
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_dictionary_frames() {
+        let dictionary: Vec<u8> = (0..200).flat_map(|i| format!("{{\"chunk\":{},\"status\":\"done\",\"renderer\":\"path tracer\"}}", i).into_bytes()).collect();
+        let message = r#"{"chunk":7,"status":"done","renderer":"path tracer"}"#.to_string();
+
+        for encrypt in [false, true] {
+            let config = NCConfiguration { encrypt, key: "u8PqN2lO3ZJbm6DHkTnR0cyVaSx1FWgi".to_string(), allowed_codecs: vec![NCCodec::Zstd(3)],
+                compression_min_size: 0, ..Default::default() };
+            let mut node = NCCommunicator::new(&config).unwrap();
+            let server = NCCommunicator::new(&config).unwrap();
+            let id = node.add_dictionary(dictionary.clone()).unwrap();
+            assert_eq!(server.add_dictionary(dictionary.clone()).unwrap(), id);
+
+            let without = node.nc_encode_data(&message).unwrap();
+            node.use_dictionary(Some(id)).unwrap();
+            let with = node.nc_encode_data(&message).unwrap();
+            let lz4 = node.nc_encode_data_codec(&message, NCCodec::Lz4).unwrap();
+
+            assert_eq!(with[0], DICTIONARY_FLAG | NCCodec::Zstd(3).id());
+            assert_eq!(with[1..5], id.to_le_bytes());
+            assert_eq!(nc_proto::NCFrameHeader::parse(&with).unwrap().0.dictionary, Some(id));
+            assert!(with.len() < without.len(), "{} / {}", with.len(), without.len());
+            assert_eq!(lz4[0], NCCodec::Lz4.id());
+
+            // Frames with and without the dictionary are decoded by the same receiver
+            for data in [&with, &without, &lz4, &with] {
+                assert_eq!(server.nc_decode_data::<String>(data).unwrap(), message);
+            }
+
+            // Both parts of a prepared frame use the dictionary
+            let frame = node.prepare_frame(bincode::serialize(&message).unwrap(), "message", NCCodec::Zstd(3), None).unwrap();
+            let data = node.nc_encode_prepared(&frame, &bincode::serialize(&7_u32).unwrap()).unwrap();
+            assert_eq!(data[0], DICTIONARY_FLAG | SEGMENTS_FLAG | NCCodec::Zstd(3).id());
+            assert_eq!(server.nc_decode_data::<(String, u32)>(&data).unwrap(), (message.clone(), 7));
+
+            // A receiver without the dictionary knows which one is missing
+            let other = NCCommunicator::new(&config).unwrap();
+            assert!(matches!(other.nc_decode_data::<String>(&with), Err(NCError::UnknownDictionary(other_id)) if other_id == id));
+            assert_eq!(other.nc_decode_data::<String>(&without).unwrap(), message);
+
+            // The dictionaries are shared, an unknown one is not used
+            node.share().use_dictionary(Some(id ^ 1)).unwrap();
+            assert_eq!(node.current_dictionary().unwrap(), None);
+            assert_eq!(node.nc_encode_data(&message).unwrap()[0], NCCodec::Zstd(3).id());
+        }
+
+        // The dictionary flag only goes with zstd
+        let nc_communicator = NCCommunicator::new(&NCConfiguration::default()).unwrap();
+        assert!(matches!(nc_communicator.nc_decode_data::<u32>(&[DICTIONARY_FLAG | 1, 1, 2, 3, 4, 0]), Err(NCError::UnknownCodec(Some(0x11)))));
+        assert!(matches!(nc_communicator.nc_decode_data::<u32>(&[DICTIONARY_FLAG | 2, 1, 2]), Err(NCError::UnexpectedEof("dictionary id", 2, 4))));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_server_addr() {
+        assert_eq!(NCServerAddr::new("::1", 2020).to_string(), "[::1]:2020");
+        assert_eq!(NCServerAddr::from("127.0.0.1:2020".parse::<SocketAddr>().unwrap()), NCServerAddr::new("127.0.0.1", 2020));
+
+        // IP addresses are not sent to the resolver
+        assert_eq!(NCServerAddr::new("::1", 2020).resolve().unwrap(), vec!["[::1]:2020".parse().unwrap()]);
+        let addresses = NCServerAddr::new("localhost", 2020).resolve().unwrap();
+        assert!(!addresses.is_empty());
+        assert!(addresses.windows(2).all(|pair| pair[0].is_ipv6() || pair[1].is_ipv4()));
+        assert!(matches!(NCServerAddr::new("node-crunch.invalid", 2020).resolve(), Err(NCError::DnsResolve(ref host)) if host == "node-crunch.invalid"));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(NCServerAddr::new("127.0.0.1", port).connect().is_ok());
+
+        // Every address that has been tried is part of the error
+        drop(listener);
+        let error = NCServerAddr::new("127.0.0.1", port).connect().unwrap_err();
+        assert!(matches!(error, NCError::Connect(_, ref attempts) if attempts.len() == 1));
+        assert!(error.to_string().starts_with(&format!("Could not connect to 127.0.0.1:{}: 127.0.0.1:{} (", port, port)));
+    }
 }
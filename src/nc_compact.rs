@@ -0,0 +1,318 @@
+//! This module contains the compact encoding of an [`Array2D`]: all values in one byte slab instead of one by one.
+//! Use it for the fields of the user data with `#[serde(with = "node_crunch::nc_compact")]`:
+//!
+//! ```
+//! use serde::{Serialize, Deserialize};
+//! use node_crunch::Array2D;
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Tile {
+//!     x: u64,
+//!     y: u64,
+//!     #[serde(with = "node_crunch::nc_compact")]
+//!     pixels: Array2D<[f32; 3]>,
+//! }
+//! ```
+//!
+//! The compact encoding only exists for values that are plain numbers, that's what the [`PodElement`] trait says. It's sealed and
+//! implemented for the integer and float primitives and for arrays and small tuples (up to four) of them. Types whose size depends on the
+//! platform (usize, isize) and types with invalid bit patterns (bool, char) don't have it, the normal Array2D serialization works for them.
+//!
+//! The slab has a small header that is always little-endian: the byte order of the values, the size of one value in bytes, the width and
+//! the height. Then come the bytes of the values, row by row, every number in the byte order of the sender and without any padding
+//! (a tuple `(u8, f64)` takes 9 bytes). The receiver checks the header:
+//!
+//! - The same byte order: the values are read as they are.
+//! - The other byte order: every number is byte-swapped while it's read, so a little-endian server can talk to a big-endian node.
+//! - Another size of the values: this is not the same type on both sides, the data is rejected with [`NCError::Array2DLayout`]
+//!   instead of being misread.
+//!
+//! In the JSON messages of the text protocol (or any other human readable format) the array is written element-wise as usual.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::size_of;
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer, de::{self, Visitor}};
+
+use crate::array2d::Array2D;
+use crate::nc_error::NCError;
+
+/// The values in the slab are little-endian.
+const LITTLE_ENDIAN: u8 = 0;
+/// The values in the slab are big-endian.
+const BIG_ENDIAN: u8 = 1;
+/// The header: byte order (u8), size of one value (u32), width (u64) and height (u64).
+const HEADER_SIZE: usize = 1 + 4 + 8 + 8;
+
+/// A value of an [`Array2D`] that has the compact encoding, see the module documentation.
+/// This trait is sealed, it can't be implemented outside of node_crunch.
+pub trait PodElement: Copy + sealed::Sealed {}
+
+mod sealed {
+    /// How a [`PodElement`](super::PodElement) is written to the slab and read from it.
+    pub trait Sealed: Sized {
+        /// The number of bytes of one value in the slab.
+        const SIZE: usize;
+
+        /// Appends the bytes of the value in the given byte order.
+        fn write(&self, little_endian: bool, out: &mut Vec<u8>);
+
+        /// Reads the value from exactly SIZE bytes in the given byte order.
+        fn read(bytes: &[u8], little_endian: bool) -> Self;
+    }
+}
+
+use sealed::Sealed;
+
+macro_rules! impl_primitive {
+    ($($t:ty),*) => {
+        $(
+            impl Sealed for $t {
+                const SIZE: usize = size_of::<$t>();
+
+                fn write(&self, little_endian: bool, out: &mut Vec<u8>) {
+                    match little_endian {
+                        true => out.extend_from_slice(&self.to_le_bytes()),
+                        false => out.extend_from_slice(&self.to_be_bytes()),
+                    }
+                }
+
+                fn read(bytes: &[u8], little_endian: bool) -> Self {
+                    let bytes = bytes.try_into().expect("the slab has been checked");
+
+                    match little_endian {
+                        true => <$t>::from_le_bytes(bytes),
+                        false => <$t>::from_be_bytes(bytes),
+                    }
+                }
+            }
+
+            impl PodElement for $t {}
+        )*
+    }
+}
+
+impl_primitive!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+impl<T: PodElement, const N: usize> Sealed for [T; N] {
+    const SIZE: usize = T::SIZE * N;
+
+    fn write(&self, little_endian: bool, out: &mut Vec<u8>) {
+        for value in self {
+            value.write(little_endian, out);
+        }
+    }
+
+    fn read(bytes: &[u8], little_endian: bool) -> Self {
+        std::array::from_fn(|i| T::read(&bytes[i * T::SIZE..(i + 1) * T::SIZE], little_endian))
+    }
+}
+
+impl<T: PodElement, const N: usize> PodElement for [T; N] {}
+
+macro_rules! impl_tuple {
+    ($($t:ident . $i:tt),*) => {
+        impl<$($t: PodElement),*> Sealed for ($($t,)*) {
+            const SIZE: usize = 0 $(+ $t::SIZE)*;
+
+            fn write(&self, little_endian: bool, out: &mut Vec<u8>) {
+                $(self.$i.write(little_endian, out);)*
+            }
+
+            // The last value doesn't need the next start
+            #[allow(unused_assignments)]
+            fn read(bytes: &[u8], little_endian: bool) -> Self {
+                let mut start = 0;
+
+                ($({
+                    let value = $t::read(&bytes[start..start + $t::SIZE], little_endian);
+                    start += $t::SIZE;
+                    value
+                },)*)
+            }
+        }
+
+        impl<$($t: PodElement),*> PodElement for ($($t,)*) {}
+    }
+}
+
+impl_tuple!(A.0, B.1);
+impl_tuple!(A.0, B.1, C.2);
+impl_tuple!(A.0, B.1, C.2, D.3);
+
+/// Returns the array as one byte slab in the byte order of this machine, see the module documentation.
+pub fn to_bytes<T: PodElement>(array: &Array2D<T>) -> Vec<u8> {
+    encode(array, cfg!(target_endian = "little"))
+}
+
+/// Returns the array from a byte slab that has been created with to_bytes(), on this or on another machine.
+///
+/// # Errors
+///
+/// Returns a [`NCError::Array2DLayout`] error if the slab is broken or the values have another size than T.
+pub fn from_bytes<T: PodElement>(bytes: &[u8]) -> Result<Array2D<T>, NCError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(NCError::Array2DLayout(format!("the slab has only {} bytes, the header has {}", bytes.len(), HEADER_SIZE)))
+    }
+
+    let little_endian = match bytes[0] {
+        LITTLE_ENDIAN => true,
+        BIG_ENDIAN => false,
+        other => return Err(NCError::Array2DLayout(format!("unknown byte order {}", other))),
+    };
+    let element_size = u32::from_le_bytes(bytes[1..5].try_into().expect("header size")) as usize;
+    let width = u64::from_le_bytes(bytes[5..13].try_into().expect("header size"));
+    let height = u64::from_le_bytes(bytes[13..21].try_into().expect("header size"));
+    let values = &bytes[HEADER_SIZE..];
+
+    if element_size != T::SIZE {
+        return Err(NCError::Array2DLayout(format!("the values have {} bytes, expected {}", element_size, T::SIZE)))
+    }
+
+    if T::SIZE == 0 || width.checked_mul(height).and_then(|len| len.checked_mul(T::SIZE as u64)) != Some(values.len() as u64) {
+        return Err(NCError::Array2DLayout(format!("{} bytes don't fit {} x {} values with {} bytes", values.len(), width, height, T::SIZE)))
+    }
+
+    let data = values.chunks_exact(T::SIZE).map(|value| T::read(value, little_endian)).collect();
+    Array2D::from_vec(width, height, data)
+}
+
+/// Writes the header and the values in the given byte order, the tests use it for the byte order of another machine.
+fn encode<T: PodElement>(array: &Array2D<T>, little_endian: bool) -> Vec<u8> {
+    let (width, height) = array.dimensions();
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + array.as_slice().len() * T::SIZE);
+
+    bytes.push(if little_endian { LITTLE_ENDIAN } else { BIG_ENDIAN });
+    bytes.extend_from_slice(&(T::SIZE as u32).to_le_bytes());
+    bytes.extend_from_slice(&width.to_le_bytes());
+    bytes.extend_from_slice(&height.to_le_bytes());
+
+    for value in array.as_slice() {
+        value.write(little_endian, &mut bytes);
+    }
+
+    bytes
+}
+
+/// Serializes the array as a byte slab, use it with `#[serde(with = "node_crunch::nc_compact")]`.
+pub fn serialize<T: PodElement + Serialize, S: Serializer>(array: &Array2D<T>, serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        return array.serialize(serializer)
+    }
+
+    serializer.serialize_bytes(&to_bytes(array))
+}
+
+/// Deserializes the array from a byte slab, use it with `#[serde(with = "node_crunch::nc_compact")]`.
+pub fn deserialize<'de, T: PodElement + Deserialize<'de>, D: Deserializer<'de>>(deserializer: D) -> Result<Array2D<T>, D::Error> {
+    if deserializer.is_human_readable() {
+        return Array2D::deserialize(deserializer)
+    }
+
+    deserializer.deserialize_byte_buf(SlabVisitor(PhantomData))
+}
+
+/// Decodes the byte slab.
+struct SlabVisitor<T>(PhantomData<T>);
+
+impl<'de, T: PodElement> Visitor<'de> for SlabVisitor<T> {
+    type Value = Array2D<T>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an Array2D byte slab")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Array2D<T>, E> {
+        from_bytes(bytes).map_err(E::custom)
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Array2D<T>, E> {
+        self.visit_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Tile {
+        x: u64,
+        #[serde(with = "crate::nc_compact")]
+        pixels: Array2D<(u16, [f32; 2])>,
+    }
+
+    fn pixels() -> Array2D<(u16, [f32; 2])> {
+        Array2D::from_vec(3, 2, (0..6).map(|i| (i as u16 * 1000, [i as f32 * 0.5, -(i as f32)])).collect()).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let tile = Tile { x: 7, pixels: pixels() };
+        let bytes = bincode::serialize(&tile).unwrap();
+        assert_eq!(bincode::deserialize::<Tile>(&bytes).unwrap(), tile);
+
+        // No padding: 2 + 2 * 4 bytes per value
+        assert_eq!(to_bytes(&tile.pixels).len(), HEADER_SIZE + 6 * 10);
+
+        // JSON is element-wise, like the normal Array2D
+        let json = serde_json::to_string(&tile).unwrap();
+        assert!(json.contains(r#""width":3"#), "{}", json);
+        assert_eq!(serde_json::from_str::<Tile>(&json).unwrap(), tile);
+    }
+
+    #[test]
+    fn test_foreign_byte_order() {
+        let native = cfg!(target_endian = "little");
+        let foreign = encode(&pixels(), !native);
+
+        // The slab of the other machine has byte-swapped numbers but the same header
+        assert_eq!(foreign[0], if native { BIG_ENDIAN } else { LITTLE_ENDIAN });
+        assert_eq!(foreign[1..HEADER_SIZE], to_bytes(&pixels())[1..HEADER_SIZE]);
+        assert_eq!(foreign[HEADER_SIZE + 10..HEADER_SIZE + 12], if native { 1000u16.to_be_bytes() } else { 1000u16.to_le_bytes() });
+        assert_eq!(from_bytes::<(u16, [f32; 2])>(&foreign).unwrap(), pixels());
+
+        // A fixed slab from a big-endian machine, independent of the byte order of this one
+        let mut big_endian = vec![BIG_ENDIAN, 4, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+        big_endian.extend_from_slice(&[0x01, 0x02, 0x03, 0x04, 0xff, 0xff, 0xff, 0xfe]);
+        assert_eq!(from_bytes::<i32>(&big_endian).unwrap().as_slice(), &[0x0102_0304, -2]);
+    }
+
+    #[test]
+    fn test_layout_mismatch() {
+        let bytes = to_bytes(&Array2D::new(2, 2, 7u32));
+
+        // Another type of the same size is not detected, another size is
+        assert!(from_bytes::<f32>(&bytes).is_ok());
+        assert!(matches!(from_bytes::<u16>(&bytes), Err(NCError::Array2DLayout(_))));
+        assert!(matches!(from_bytes::<(u16, u8)>(&bytes), Err(NCError::Array2DLayout(_))));
+
+        // Broken slabs
+        assert!(from_bytes::<u32>(&bytes[..bytes.len() - 1]).is_err());
+        assert!(from_bytes::<u32>(&bytes[..HEADER_SIZE - 1]).is_err());
+        let mut unknown = bytes.clone();
+        unknown[0] = 9;
+        assert!(from_bytes::<u32>(&unknown).is_err());
+
+        // The error also comes through serde
+        let array: Array2D<u64> = Array2D::new(1, 1, 1);
+        let tile = bincode::serialize(&(7u64, serde_bytes(&to_bytes(&array)))).unwrap();
+        let error = bincode::deserialize::<Tile>(&tile).unwrap_err();
+        assert!(error.to_string().contains("expected 10"), "{}", error);
+    }
+
+    /// A byte string like serialize() writes it.
+    fn serde_bytes(bytes: &[u8]) -> impl Serialize + '_ {
+        struct Bytes<'a>(&'a [u8]);
+
+        impl Serialize for Bytes<'_> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_bytes(self.0)
+            }
+        }
+
+        Bytes(bytes)
+    }
+}
@@ -2,14 +2,142 @@
 //! Usually code for the server and the node is shared.
 
 use std::fmt::{self, Display, Formatter};
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[cfg(feature = "net")]
+use log::info;
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+
+use crate::nc_communicator::{NCCodec, HEX_KEY_PREFIX, decode_key};
+use crate::nc_error::NCError;
+use crate::nc_proxy::NCProxyConfig;
+
+/// With this address the node finds the server with mDNS, see [`NCConfiguration::mdns_announce`].
+#[cfg(feature = "net")]
+pub(crate) const MDNS_ADDRESS: &str = "mdns:";
+
+/// What the server does when the result queue is full, see [`NCConfiguration::result_queue_max_bytes`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NCResultQueueMode {
+    /// The server waits until there is enough space in the queue before it sends the ResultAck message back to the node.
+    /// So the nodes slow down and don't get new data until the server has caught up.
+    Backpressure,
+    /// The server writes the result to a file in [`NCConfiguration::spill_dir`] and reads it back in when it is processed.
+    SpillToDisk,
+}
+
+/// What the server does when the NCServer trait method process_data_from_node() returns an error
+/// (other than a `NCError::Job` error, that one is always sent to the node) or panics, see [`NCConfiguration::on_process_error`].
+/// If assign_chunk_with_context() panics there is no chunk to give to a node again or to drop, so only AbortJob makes a difference:
+/// otherwise the node gets a NCServerMessage::ServerFailed message and asks again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnProcessError {
+    /// Shut down the server, finish_job() is called with the results so far.
+    AbortJob,
+    /// The chunk is given to a node again, at most max_process_retries times. After that it's dropped.
+    RequeueChunk,
+    /// The chunk is marked as failed and the job continues without it.
+    DropChunk,
+}
+
+/// The addresses the server listens on, see [`NCConfiguration::bind_mode`] and the [`nc_listener`](crate::nc_listener) module.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BindMode {
+    /// All IPv4 addresses (0.0.0.0).
+    V4,
+    /// All IPv6 addresses (::), depending on the system IPv4 clients are accepted too (net.ipv6.bindv6only on Linux).
+    V6,
+    /// All IPv4 and all IPv6 addresses, with two sockets if the system needs them.
+    DualStack,
+}
+
+/// The byte channel between the node (or the client) and the server, see [`NCConfiguration::transport`] and the
+/// [`nc_transport`](crate::nc_transport) module.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NCTransportKind {
+    /// A tcp connection to the address and the port of the server.
+    Tcp,
+    /// A shared memory ring buffer in the given directory, only for a server and nodes on the same machine (shmem feature),
+    /// see the [`nc_shmem`](crate::nc_shmem) module. The server listens on its port too.
+    SharedMemory {
+        /// The directory with the shared memory segments, it should be on a memory file system like `/dev/shm`.
+        path: PathBuf,
+    },
+}
+
+/// How bad a difference between two configurations is, see [`NCConfiguration::diff()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ConfigDiffSeverity {
+    /// The setting can be different on every machine.
+    Info,
+    /// Server and node still work together, but maybe not as intended (for example another codec or type_check only on one side).
+    Warning,
+    /// Server and node can't work together, the server refuses to register the node.
+    Error,
+}
+
+/// One setting that is different in two configurations, see [`NCConfiguration::diff()`].
+/// The values are shown like in the key=value output of [`NCConfiguration::key_values()`], secrets are masked.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfigDiff {
+    /// The name of the setting, the same as the field in the NCConfiguration.
+    pub key: String,
+    /// The value in this configuration (the server during registration).
+    pub local: String,
+    /// The value in the other configuration (the node during registration).
+    pub remote: String,
+    /// How bad the difference is.
+    pub severity: ConfigDiffSeverity,
+}
+
+impl Display for ConfigDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}: {} != {}", self.severity, self.key, self.local, self.remote)
+    }
+}
+
+/// The settings of the node that must match the server, the node sends them with the NCNodeMessage::Register message
+/// and the server compares them with its own, see [`NCConfiguration::diff()`].
+#[cfg(feature = "net")]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct NCSharedSettings {
+    heartbeat: u64,
+    compress: bool,
+    allowed_codecs: Vec<NCCodec>,
+    compression_min_size: usize,
+    encrypt: bool,
+    type_check: bool,
+}
+
+#[cfg(feature = "net")]
+impl NCSharedSettings {
+    /// Returns the default configuration with these settings, so that only they show up in [`NCConfiguration::diff()`].
+    pub(crate) fn to_config(&self) -> NCConfiguration {
+        NCConfiguration {
+            heartbeat: self.heartbeat,
+            compress: self.compress,
+            allowed_codecs: self.allowed_codecs.clone(),
+            compression_min_size: self.compression_min_size,
+            encrypt: self.encrypt,
+            type_check: self.type_check,
+            ..Default::default()
+        }
+    }
+}
+
 /// This data structure contains the configuration for the server and the node.
-#[derive(Debug, Clone)]
+/// The Debug output masks the encryption keys and the admin key.
+#[derive(Clone)]
 pub struct NCConfiguration{
-    /// IP address of the server, default: 127.0.0.1
+    /// IP address or host name of the server, or "mdns:" to find the server with mDNS (mdns feature, the port comes from the service), default: 127.0.0.1
     pub address: String,
-    /// Port used by the server, default: 9000.
+    /// Port used by the server, default: 9000. With 0 the OS chooses a free port when the server starts, the port is logged and
+    /// can be read from the [`NCServerHandle`](crate::NCServerHandle). The nodes can't know it from a configuration file,
+    /// they need mDNS (mdns_announce) or [`nc_local::run()`](crate::nc_local::run), which passes the port to the nodes it starts.
     pub port: u16,
-    /// Nodes have to send a heartbeat every n seconds or they will be marked as offline.
+    /// Nodes have to send a heartbeat every n seconds or they will be marked as offline. Every node adds a random jitter of ± 20 % to each interval.
     /// (The method [`heartbeat_timeout(node_id)`](crate::nc_server::NCServer::heartbeat_timeout)
     /// with the corresponding node ID is called), default: 5.
     pub heartbeat: u64,
@@ -19,12 +147,261 @@ pub struct NCConfiguration{
     pub retry_counter: u64,
     /// The number of threads in the thread pool, default: 8.
     pub pool_size: u64,
-    /// Enable compression during communication
+    /// Enable compression during communication, if false only NCCodec::None is used.
     pub compress: bool,
+    /// The codecs (compression algorithms) that can be used for the communication between the server and a node,
+    /// the first one is the preferred one. The server chooses the first codec in its list that the node supports, default: Lz4, None.
+    pub allowed_codecs: Vec<NCCodec>,
+    /// Frames smaller than n bytes are sent uncompressed even if compress is set, because compressing tiny control messages
+    /// only costs CPU time and can even make them larger, default: 2048, 0 = compress every frame.
+    pub compression_min_size: usize,
+    /// A received frame that decompresses to more than n bytes is rejected with a [`NCError::DecompressionLimit`](crate::NCError::DecompressionLimit) error
+    /// before more memory is allocated, so that a small compressed frame can't exhaust the memory, default: 1 GB.
+    pub max_decompressed_bytes: u64,
+    /// A received frame whose length prefix is bigger than n bytes is rejected with a [`NCError::FrameTooLarge`](crate::NCError::FrameTooLarge) error
+    /// before anything is allocated for it, so that a broken or hostile length can't exhaust the memory, default: 1 GB.
+    pub max_frame_bytes: u64,
+    /// The server trains a zstd dictionary from the serialized data of the first n results and the nodes with NCCodec::Zstd compress
+    /// their results with it, so that many small results that look alike get much smaller. See the [`nc_dictionary`](crate::nc_dictionary) module.
+    /// Needs the zstd-dict feature, default: 0 = no dictionary.
+    pub result_dictionary_samples: usize,
+    /// Maximum size of the zstd dictionary in bytes, see result_dictionary_samples, default: 16384.
+    pub result_dictionary_size: usize,
     /// Enable encryption during communication
     pub encrypt: bool,
-    /// Encryption key, must be exactly 32 chars
+    /// Encryption key, this key is used for sending. It's either 32 printable ASCII characters without spaces,
+    /// "hex:" followed by 64 hex digits or "base64:" followed by 32 bytes in base64, see [`NCConfiguration::generate_key()`].
+    /// The starters check the keys before they do anything else and return a [`NCError::InvalidKey`](crate::NCError::InvalidKey) error.
     pub key: String,
+    /// Old encryption keys that are still accepted for receiving, the newest one first. Same format as the key, default: empty.
+    pub previous_keys: Vec<String>,
+    /// When the key is rotated (NCAdminCommand::RotateKey) the old key is kept in the list of previous keys,
+    /// but at most n previous keys are kept, default: 1.
+    pub max_previous_keys: usize,
+    /// Key for signing admin messages (abort, pause, resume, ...), default: empty.
+    /// If it is empty the server rejects all admin messages.
+    pub admin_key: String,
+    /// Signed admin messages that are older than n seconds (or n seconds ahead of the clock of the server) are rejected,
+    /// the nonces of the newer ones are kept so that a message can't be replayed. This is also the maximum clock difference
+    /// between the server and the admin client, default: 60.
+    pub admin_message_max_age: u64,
+    /// Abort the job after n chunks have failed permanently (NCJobError with retryable = false), default: 0 = never abort.
+    pub max_permanent_failures: u64,
+    /// Maximum number of bytes (serialized size) of all the results from the nodes that wait to be processed, default: 256 MB.
+    pub result_queue_max_bytes: u64,
+    /// What to do if the result queue is full, default: Backpressure.
+    pub result_queue_mode: NCResultQueueMode,
+    /// What to do if process_data_from_node() returns an error or panics, default: RequeueChunk.
+    pub on_process_error: OnProcessError,
+    /// How often a chunk is given to a node again if process_data_from_node() has returned an error for it, default: 3.
+    pub max_process_retries: u32,
+    /// How often a chunk is sent to a node before it's given up: after this many attempts without a result (errors from the nodes,
+    /// heartbeat timeouts, ...) the chunk moves to the dead-letter list and the NCServer trait method chunk_dead() is called,
+    /// default: 0 = no limit.
+    pub max_chunk_attempts: u32,
+    /// Folder for results that don't fit into the result queue if the mode is SpillToDisk, default: the temp folder of the OS.
+    pub spill_dir: PathBuf,
+    /// Log a warning if the serialized size of a message exceeds this number of bytes, default: 64 MB, 0 = never warn.
+    pub payload_warn_bytes: u64,
+    /// Log a short hash (sha256) of every message that is sent or received, so that the messages can be matched up in the logs
+    /// of the server and the nodes without logging their content, default: false.
+    pub log_payload_hashes: bool,
+    /// Call process_data_from_node() strictly in ascending chunk id order (starting with 0), default: false.
+    /// Results that arrive out of order wait in a reorder buffer.
+    pub ordered_results: bool,
+    /// If more results wait in the reorder buffer the nodes don't get new data until the missing chunk has arrived, default: 1000.
+    pub reorder_buffer_max_len: usize,
+    /// Wait n seconds for a missing chunk before chunk_gap() is called, default: 300.
+    pub max_reorder_wait: u64,
+    /// Give the results to the NCServer trait method process_result_batch() in batches of up to n results instead of calling
+    /// process_data_from_node() for every result, default: 0 = no batches. The nodes get the answer for their result after the batch
+    /// has been processed. See the [`nc_result_batch`](crate::nc_result_batch) module.
+    pub result_batch_size: usize,
+    /// A batch is processed as soon as its results have this many bytes (serialized size), default: 1 MB.
+    pub result_batch_bytes: u64,
+    /// A batch is processed at the latest n milliseconds after its first result has arrived, default: 20.
+    pub result_batch_ms: u64,
+    /// Keep the serialized data of the unfinished chunks, so that a chunk that has to be sent again (for example after a heartbeat timeout)
+    /// doesn't need to be prepared again by assign_chunk(), default: false. See the NCServer trait method reassign_chunk().
+    pub cache_chunk_payloads: bool,
+    /// Maximum number of bytes (serialized size) of all the cached chunk data, the least recently used data is dropped first, default: 256 MB.
+    pub chunk_cache_max_bytes: u64,
+    /// Keep the serialized and compressed chunk data of the unfinished chunks that the NCServer trait method cache_chunk() allows,
+    /// so that a chunk that is sent again (to another node or after a failure) is not serialized and compressed again, default: false.
+    /// Only the encryption is done for every frame, since every frame needs its own nonce.
+    pub cache_chunk_frames: bool,
+    /// Maximum number of bytes (compressed size) of all the cached chunk frames, the least recently used frame is dropped first, default: 64 MB.
+    pub frame_cache_max_bytes: u64,
+    /// Folder for the temporary files of the node, see [`ScratchDir`](crate::ScratchDir), default: None = the temp folder of the OS.
+    pub scratch_dir: Option<PathBuf>,
+    /// Don't delete the temporary files of a chunk if processing failed, for debugging, default: false.
+    pub keep_scratch_on_failure: bool,
+    /// The node doesn't ask for new data while there are less than n bytes free in the scratch folder, default: 0 = no check.
+    pub min_free_space: u64,
+    /// The server writes the result of the NCServer trait method checkpoint() to this file, default: None = no checkpoints.
+    /// See [`NCCheckpoint`](crate::NCCheckpoint).
+    pub checkpoint_file: Option<PathBuf>,
+    /// Write a checkpoint every n seconds, default: 300.
+    pub checkpoint_interval: u64,
+    /// The node asks for chunks of at most n bytes, see [`NCWorkHint`](crate::NCWorkHint), default: 0 = no hint.
+    pub work_hint_max_bytes: u64,
+    /// The node would like to work at most n seconds on one chunk, default: 0 = no hint.
+    pub work_hint_max_duration: u64,
+    /// The kind of work the node prefers, the meaning is up to the user code on the server, default: None.
+    pub work_hint_kind: Option<String>,
+    /// The node asks for the next chunk while it is still processing the current one and keeps the answer until it's done,
+    /// so that it doesn't have to wait for the server between two chunks. The node holds up to two chunks then, default: false.
+    pub prefetch: bool,
+    /// The node sends the result of a chunk from a separate thread and starts with the next chunk (fetched in advance, see prefetch)
+    /// while the result is uploaded, see the [`nc_upload`](crate::nc_upload) module. Without prefetch the node waits for the upload
+    /// before it asks for new data. Only results that are sent to the server directly are uploaded this way, default: false.
+    pub upload_in_background: bool,
+    /// The server gives the chunks of an offline batch to other nodes if its results have not been imported after n days,
+    /// see the [`nc_offline`](crate::nc_offline) module, default: 7.
+    pub offline_batch_timeout_days: u64,
+    /// A node that would get a NCJobStatus::Waiting waits at the server for up to n milliseconds until new chunks are enqueued,
+    /// only used if the NCServer trait method chunk_queue_waker() returns a waker (see [`ChunkQueueHandle`](crate::ChunkQueueHandle)),
+    /// default: 1000, 0 = answer right away.
+    pub long_poll_ms: u64,
+    /// Maximum size of the metadata of a chunk (keys and values) in bytes, larger metadata is not sent to the node
+    /// and not accepted from the node, see [`ChunkMeta`](crate::ChunkMeta), default: 4096.
+    pub max_chunk_meta_bytes: usize,
+    /// At the end of the job the server writes the [`NCJobSummary`](crate::NCJobSummary) as JSON next to the checkpoint file
+    /// (`job.checkpoint` -> `job.summary.json`), default: false. Needs checkpoint_file.
+    pub write_job_summary: bool,
+    /// The server appends every event of the job (node registered, chunk assigned or completed, ...) as one JSON line to this file,
+    /// see the [`nc_events`](crate::nc_events) module. Default: None = no event log.
+    pub event_log: Option<PathBuf>,
+    /// The event log is flushed to the disk every n milliseconds (and at the end of the job), default: 1000.
+    pub event_log_flush_ms: u64,
+    /// The server only sends this part of the chunks (between 0 and 1) to the nodes for a quick validation run, the other chunks
+    /// are left out (see the NCServer trait method chunk_left_out()), default: None = all chunks. See the nc_sample module.
+    pub sample_fraction: Option<f64>,
+    /// The seed that decides which chunks are part of the sample, the same seed gives the same chunks,
+    /// default: None = a random seed, it's logged and written to the job summary. Only used with sample_fraction.
+    pub sample_seed: Option<u64>,
+    /// The server listens on IPv4, IPv6 or both, default: V4.
+    pub bind_mode: BindMode,
+    /// With BindMode::DualStack the server doesn't start if one address family can't be bound, default: false = only a warning is logged.
+    pub dual_stack_required: bool,
+    /// The server handles at most n node connections at the same time, further connections are closed right away, default: 256, 0 = no limit.
+    pub max_connections: usize,
+    /// The server closes a node connection that is open for longer than n seconds, for example because the node doesn't send anything,
+    /// default: 300, 0 = no limit.
+    pub max_connection_lifetime: u64,
+    /// When the job is done the server keeps answering the nodes for n milliseconds and tells them that the job is finished,
+    /// so they exit right away instead of waiting until their retry counter is zero, default: 2000, 0 = exit right away.
+    pub finish_linger_ms: u64,
+    /// When the server exits it waits up to n milliseconds until the open node connections have finished their current message,
+    /// then the remaining connections are closed, default: 5000, 0 = close them right away.
+    pub shutdown_grace_ms: u64,
+    /// The job stops after this time: no new chunks are handed out, the nodes that ask for data get a NCJobStatus::Finished.
+    /// The chunks that are still being processed have job_drain_timeout seconds to come back, then the job ends with
+    /// NCJobEndReason::TimeBudgetExhausted and the results so far, default: None = no limit.
+    /// The nodes get the remaining time with every chunk, see [`NCProcessContext::job_time_remaining()`](crate::NCProcessContext::job_time_remaining).
+    pub max_job_duration: Option<Duration>,
+    /// After max_job_duration the server waits at most n seconds for the results of the chunks that are still being processed, default: 60.
+    pub job_drain_timeout: u64,
+    /// The server also accepts connections that send the messages as JSON, one per line, for debugging by hand with netcat,
+    /// see the [`nc_text_protocol`](crate::nc_text_protocol) module. Needs the debug-protocol feature, can't be used together with encrypt,
+    /// default: false.
+    pub text_protocol: bool,
+    /// Number of threads for the post processing of the results, see the NCServer trait method post_processor(), default: 2.
+    pub post_process_workers: usize,
+    /// Maximum number of results waiting for post processing, if the queue is full the result thread waits, default: 64.
+    pub post_process_queue_len: usize,
+    /// The build of the node binary that the server expects, nodes with a different build get a NCServerMessage::PleaseRestart
+    /// message after their current chunk, default: None = every build is accepted. Can be changed with the admin protocol.
+    pub required_node_build: Option<String>,
+    /// The build of this node, sent to the server during registration, default: the environment variable NC_NODE_BUILD
+    /// at run time or (if that is not set) at compile time.
+    pub node_build: Option<String>,
+    /// The job this node works for if several jobs share one port, see [`NCMultiServerStarter`](crate::NCMultiServerStarter).
+    /// It's sent in front of every message, at most 255 bytes, default: None = the server runs only one job.
+    pub job_id: Option<String>,
+    /// This node also works as an aggregator: it listens on this port for the results of other nodes, combines them with the
+    /// NCNode trait method reduce() and sends the combined result to the server, see the [`nc_aggregator`](crate::nc_aggregator) module.
+    /// Port 0 lets the OS choose a free port, default: None = no aggregator.
+    pub aggregator_port: Option<u16>,
+    /// Nodes only send their results to an aggregator with the same tag, default: None.
+    pub aggregator_tag: Option<String>,
+    /// The aggregator sends the combined result to the server every n seconds, default: 30.
+    pub aggregate_interval: u64,
+    /// The groups this node belongs to (for example "gpu"), sent to the server during registration. The server can send custom messages
+    /// to all nodes of a group and hand out chunks only to a group, default: empty = no group.
+    pub node_tags: Vec<String>,
+    /// The server loads the shard tags of the chunks from this file, so that a chunk only goes to a node with the tag of its shard,
+    /// see the [`nc_locality`](crate::nc_locality) module. Default: None = every node gets every chunk.
+    pub locality_manifest: Option<PathBuf>,
+    /// A chunk that has waited this many milliseconds for a node with the tag of its shard goes to any node, default: 30000.
+    pub locality_fallback_ms: u64,
+    /// Send a hash of the type name of the user data with every message and check it when a message is decoded, so that a node and a server
+    /// with different data types get a NCError::TypeMismatch error instead of garbage, default: true in debug builds.
+    /// Costs 4 bytes per message, nodes and servers with and without the check work together.
+    pub type_check: bool,
+    /// The server treats protocol anomalies (a duplicate or late result, a message that can't be decoded or doesn't fit,
+    /// a heartbeat from a node that has been declared offline) as errors: the first one aborts the job, [`NCServerStarter::start()`](crate::NCServerStarter::start)
+    /// returns a [`NCError::StrictViolation`](crate::NCError::StrictViolation) and the job summary lists them. Meant for CI,
+    /// [`nc_local::run()`](crate::nc_local::run) always uses it. Default: false = they are logged as warnings.
+    pub strict_mode: bool,
+    /// The server warns if the clock of a node is off by more than n milliseconds (measured during the registration,
+    /// see the [`nc_clock`](crate::nc_clock) module), default: 1000. The timeouts of the server don't depend on the clocks of the nodes.
+    pub max_clock_skew_ms: u64,
+    /// The node sends a [`NCResourceReport`](crate::NCResourceReport) (free memory, free disk space, load) with every heartbeat, default: false.
+    pub report_resources: bool,
+    /// Nodes with less free memory (in bytes, according to their last resource report) get a NCJobStatus::Waiting
+    /// instead of new data, default: 0 = no limit.
+    pub min_node_free_mem: u64,
+    /// Nodes with less free disk space (in bytes, according to their last resource report) get a NCJobStatus::Waiting
+    /// instead of new data, default: 0 = no limit.
+    pub min_node_free_disk: u64,
+    /// The node uses at most this percentage of the CPU (1 - 100): after every chunk and in NCProcessContext::throttle() it sleeps
+    /// so that it's busy at most this part of the time, see the [`nc_throttle`](crate::nc_throttle) module. Default: None = no limit.
+    pub cpu_limit_percent: Option<u8>,
+    /// The server stores the artifacts from the nodes in this folder, the nodes only send artifacts if it's set,
+    /// see the [`nc_artifacts`](crate::nc_artifacts) module. Default: None = no artifacts.
+    pub artifacts_dir: Option<PathBuf>,
+    /// Larger artifacts are dropped with a warning, default: 16 MB.
+    pub max_artifact_bytes: u64,
+    /// A chunk has at most n artifacts, further ones are dropped with a warning, default: 16.
+    pub max_artifacts_per_chunk: usize,
+    /// The node keeps the results of the last n chunks until the server has acknowledged them, so that they are not computed again
+    /// after a lost connection, see the [`nc_result_cache`](crate::nc_result_cache) module. Default: 0 = no cache.
+    pub result_cache_count: usize,
+    /// The cached results take at most n bytes (serialized), the oldest ones are dropped first, default: 64 MB.
+    pub result_cache_max_bytes: u64,
+    /// The node also writes the cached results to this folder, so that they survive a restart of the node.
+    /// Default: None = the results are only kept in memory.
+    pub result_cache_dir: Option<PathBuf>,
+    /// The node keeps its node id and its incarnation in this file, so that a new process on the same machine (for example a spot
+    /// instance that comes back) takes over the node id and the chunks of the old process are given to other nodes right away.
+    /// The file is locked while the node is running, see the [`nc_node_id_file`](crate::nc_node_id_file) module.
+    /// Default: None = the node gets a new node id every time it starts.
+    pub node_id_file: Option<PathBuf>,
+    /// The node signs its results with the secret key in this file, see [`nc_keys::generate()`](crate::nc_keys::generate).
+    /// Needs the ed25519 feature, default: None = results are not signed.
+    pub signing_key_file: Option<PathBuf>,
+    /// The server only accepts the public keys of the nodes in this file, see [`NCKeyRing`](crate::NCKeyRing).
+    /// Needs the ed25519 feature, default: None = every key that a node sends during registration is accepted.
+    pub node_keys_file: Option<PathBuf>,
+    /// The server rejects results that are not signed, needs the ed25519 feature, default: false.
+    pub require_signed_results: bool,
+    /// The server registers the mDNS service `_nodecrunch._tcp.local.` while it's running, nodes with the address "mdns:" find it,
+    /// see the [`nc_mdns`](crate::nc_mdns) module. Needs the mdns feature, default: false.
+    pub mdns_announce: bool,
+    /// The server sends the state of the chunks to a standby server on this host (warm standby), see the
+    /// [`nc_replication`](crate::nc_replication) module. Default: None = no replication.
+    pub replicate_to: Option<String>,
+    /// The standby server listens on this port for the updates from the server and for the admin command Promote, default: 9001.
+    pub replication_port: u16,
+    /// The server sends the changes to the standby every n milliseconds, default: 1000.
+    pub replication_interval_ms: u64,
+    /// The node (and the client) connects to the server through this SOCKS5 or HTTP CONNECT proxy, see the [`nc_proxy`](crate::nc_proxy) module.
+    /// The address of the server is resolved by the proxy. Default: None = direct connection.
+    pub proxy: Option<NCProxyConfig>,
+    /// The node (and the client) connects to the server with this transport, with NCTransportKind::SharedMemory the server accepts
+    /// connections in the given directory in addition to its port. Needs the shmem feature for shared memory, default: NCTransportKind::Tcp.
+    pub transport: NCTransportKind,
 }
 
 impl Default for NCConfiguration {
@@ -37,19 +414,678 @@ impl Default for NCConfiguration {
             retry_counter: 5,
             pool_size: 8,
             compress: true,
+            allowed_codecs: vec![NCCodec::Lz4, NCCodec::None],
+            compression_min_size: 2048,
+            max_decompressed_bytes: 1024 * 1024 * 1024,
+            max_frame_bytes: 1024 * 1024 * 1024,
+            result_dictionary_samples: 0,
+            result_dictionary_size: 16 * 1024,
             encrypt: false,
-            // Key must be exactly 32 chars long
+            // Key must be exactly 32 chars long, see decode_key()
             key: "XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX".to_string(),
+            previous_keys: Vec::new(),
+            max_previous_keys: 1,
+            admin_key: String::new(),
+            admin_message_max_age: 60,
+            max_permanent_failures: 0,
+            result_queue_max_bytes: 256 * 1024 * 1024,
+            result_queue_mode: NCResultQueueMode::Backpressure,
+            on_process_error: OnProcessError::RequeueChunk,
+            max_process_retries: 3,
+            max_chunk_attempts: 0,
+            spill_dir: std::env::temp_dir(),
+            payload_warn_bytes: 64 * 1024 * 1024,
+            log_payload_hashes: false,
+            ordered_results: false,
+            reorder_buffer_max_len: 1000,
+            max_reorder_wait: 300,
+            result_batch_size: 0,
+            result_batch_bytes: 1024 * 1024,
+            result_batch_ms: 20,
+            cache_chunk_payloads: false,
+            chunk_cache_max_bytes: 256 * 1024 * 1024,
+            cache_chunk_frames: false,
+            frame_cache_max_bytes: 64 * 1024 * 1024,
+            scratch_dir: None,
+            keep_scratch_on_failure: false,
+            min_free_space: 0,
+            checkpoint_file: None,
+            checkpoint_interval: 300,
+            work_hint_max_bytes: 0,
+            work_hint_max_duration: 0,
+            work_hint_kind: None,
+            prefetch: false,
+            upload_in_background: false,
+            offline_batch_timeout_days: 7,
+            long_poll_ms: 1000,
+            max_chunk_meta_bytes: 4096,
+            write_job_summary: false,
+            event_log: None,
+            event_log_flush_ms: 1000,
+            sample_fraction: None,
+            sample_seed: None,
+            max_connections: 256,
+            max_connection_lifetime: 300,
+            bind_mode: BindMode::V4,
+            dual_stack_required: false,
+            finish_linger_ms: 2000,
+            shutdown_grace_ms: 5000,
+            max_job_duration: None,
+            job_drain_timeout: 60,
+            text_protocol: false,
+            post_process_workers: 2,
+            post_process_queue_len: 64,
+            required_node_build: None,
+            node_build: std::env::var("NC_NODE_BUILD").ok().or_else(|| option_env!("NC_NODE_BUILD").map(String::from)),
+            job_id: None,
+            aggregator_port: None,
+            aggregator_tag: None,
+            aggregate_interval: 30,
+            node_tags: Vec::new(),
+            locality_manifest: None,
+            locality_fallback_ms: 30000,
+            type_check: cfg!(debug_assertions),
+            strict_mode: false,
+            max_clock_skew_ms: 1000,
+            report_resources: false,
+            min_node_free_mem: 0,
+            min_node_free_disk: 0,
+            cpu_limit_percent: None,
+            artifacts_dir: None,
+            max_artifact_bytes: 16 * 1024 * 1024,
+            max_artifacts_per_chunk: 16,
+            result_cache_count: 0,
+            result_cache_max_bytes: 64 * 1024 * 1024,
+            result_cache_dir: None,
+            node_id_file: None,
+            signing_key_file: None,
+            node_keys_file: None,
+            require_signed_results: false,
+            mdns_announce: false,
+            replicate_to: None,
+            replication_port: 9001,
+            replication_interval_ms: 1000,
+            proxy: None,
+            transport: NCTransportKind::Tcp,
         }
     }
 }
 
+impl NCConfiguration {
+    /// Returns a new random key with 32 bytes as 64 hex digits with the "hex:" prefix, for example for the key in the configuration.
+    pub fn generate_key() -> String {
+        let mut bytes = [0_u8; 32];
+        rand::thread_rng().fill(&mut bytes);
+
+        let digits: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{}{}", HEX_KEY_PREFIX, digits)
+    }
+
+    /// Checks the format of the encryption key and the previous keys, the error doesn't contain the keys.
+    pub(crate) fn check_keys(&self) -> Result<(), NCError> {
+        decode_key(&self.key).map_err(NCError::InvalidKey)?;
+
+        for (index, key) in self.previous_keys.iter().enumerate() {
+            decode_key(key).map_err(|problem| NCError::InvalidKey(format!("previous_keys[{}]: {}", index, problem)))?;
+        }
+
+        Ok(())
+    }
+
+    /// The codecs that are actually used, taking the compress flag into account.
+    pub(crate) fn codecs(&self) -> Vec<NCCodec> {
+        if self.compress {
+            self.allowed_codecs.clone()
+        } else {
+            vec![NCCodec::None]
+        }
+    }
+
+    /// Checks the settings that would make the server or the node fail later on, used for the dry run.
+    /// The encryption keys are checked separately.
+    #[cfg(feature = "net")]
+    pub(crate) fn check(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+
+        if self.heartbeat == 0 {
+            problems.push("heartbeat must be greater than 0")
+        }
+
+        if self.pool_size == 0 {
+            problems.push("pool_size must be greater than 0")
+        }
+
+        if self.codecs().is_empty() {
+            problems.push("allowed_codecs is empty")
+        }
+
+        if self.admin_message_max_age == 0 {
+            problems.push("admin_message_max_age must be greater than 0")
+        }
+
+        if self.max_decompressed_bytes == 0 {
+            problems.push("max_decompressed_bytes must be greater than 0")
+        }
+
+        if self.max_frame_bytes == 0 {
+            problems.push("max_frame_bytes must be greater than 0")
+        }
+
+        if self.ordered_results && self.reorder_buffer_max_len == 0 {
+            problems.push("reorder_buffer_max_len must be greater than 0 for ordered results")
+        }
+
+        if self.result_batch_size > 0 && self.ordered_results {
+            problems.push("result_batch_size can't be used together with ordered_results")
+        }
+
+        if self.cache_chunk_payloads && self.chunk_cache_max_bytes == 0 {
+            problems.push("chunk_cache_max_bytes must be greater than 0 for the chunk cache")
+        }
+
+        if self.cache_chunk_frames && self.frame_cache_max_bytes == 0 {
+            problems.push("frame_cache_max_bytes must be greater than 0 for the frame cache")
+        }
+
+        if self.sample_fraction.is_some_and(|fraction| !(fraction > 0.0 && fraction <= 1.0)) {
+            problems.push("sample_fraction must be greater than 0 and at most 1")
+        }
+
+        if self.post_process_workers == 0 {
+            problems.push("post_process_workers must be greater than 0")
+        }
+
+        if self.event_log.is_some() && self.event_log_flush_ms == 0 {
+            problems.push("event_log_flush_ms must be greater than 0 for the event log")
+        }
+
+        if self.job_id.as_ref().is_some_and(|job_id| job_id.is_empty() || job_id.len() > 255) {
+            problems.push("job_id must be between 1 and 255 bytes long")
+        }
+
+        if self.aggregator_port.is_some() && self.aggregate_interval == 0 {
+            problems.push("aggregate_interval must be greater than 0 for an aggregator")
+        }
+
+        if self.cpu_limit_percent.is_some_and(|percent| percent == 0 || percent > 100) {
+            problems.push("cpu_limit_percent must be between 1 and 100")
+        }
+
+        if let Err(problem) = self.check_text_protocol() {
+            problems.push(problem)
+        }
+
+        if let Err(problem) = self.check_signatures() {
+            problems.push(problem)
+        }
+
+        if let Err(problem) = self.check_mdns() {
+            problems.push(problem)
+        }
+
+        if let Err(problem) = self.check_result_dictionary() {
+            problems.push(problem)
+        }
+
+        if let Err(problem) = self.check_transport() {
+            problems.push(problem)
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join(", "))
+        }
+    }
+
+    /// The text protocol is only available with the debug-protocol feature and it's not encrypted, so it can't be used together with encrypt.
+    #[cfg(feature = "net")]
+    pub(crate) fn check_text_protocol(&self) -> Result<(), &'static str> {
+        if !self.text_protocol {
+            Ok(())
+        } else if !cfg!(feature = "debug-protocol") {
+            Err("text_protocol needs the debug-protocol feature")
+        } else if self.encrypt {
+            Err("text_protocol can't be used together with encrypt")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Signed results are only available with the ed25519 feature.
+    #[cfg(feature = "net")]
+    pub(crate) fn check_signatures(&self) -> Result<(), &'static str> {
+        if cfg!(feature = "ed25519") || (self.signing_key_file.is_none() && self.node_keys_file.is_none() && !self.require_signed_results) {
+            Ok(())
+        } else {
+            Err("signing_key_file, node_keys_file and require_signed_results need the ed25519 feature")
+        }
+    }
+
+    /// The node browses for the server if the address is "mdns:".
+    #[cfg(feature = "net")]
+    pub(crate) fn uses_mdns(&self) -> bool {
+        self.address.trim() == MDNS_ADDRESS
+    }
+
+    /// mdns_announce and the address "mdns:" are only available with the mdns feature.
+    #[cfg(feature = "net")]
+    pub(crate) fn check_mdns(&self) -> Result<(), &'static str> {
+        if cfg!(feature = "mdns") || (!self.mdns_announce && !self.uses_mdns()) {
+            Ok(())
+        } else {
+            Err("mdns_announce and the address \"mdns:\" need the mdns feature")
+        }
+    }
+
+    /// The zstd dictionary is only trained with the zstd-dict feature.
+    #[cfg(feature = "net")]
+    pub(crate) fn check_result_dictionary(&self) -> Result<(), &'static str> {
+        if self.result_dictionary_samples == 0 {
+            Ok(())
+        } else if !cfg!(feature = "zstd-dict") {
+            Err("result_dictionary_samples needs the zstd-dict feature")
+        } else if self.result_dictionary_size == 0 {
+            Err("result_dictionary_size must be greater than 0")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The shared memory transport is only available with the shmem feature.
+    #[cfg(feature = "net")]
+    pub(crate) fn check_transport(&self) -> Result<(), &'static str> {
+        match &self.transport {
+            NCTransportKind::Tcp => Ok(()),
+            NCTransportKind::SharedMemory { .. } if !cfg!(feature = "shmem") => Err("transport NCTransportKind::SharedMemory needs the shmem feature"),
+            NCTransportKind::SharedMemory { path } if path.as_os_str().is_empty() => Err("the path of NCTransportKind::SharedMemory must not be empty"),
+            NCTransportKind::SharedMemory { .. } => Ok(()),
+        }
+    }
+
+    /// Returns the settings that the node sends to the server when it registers.
+    #[cfg(feature = "net")]
+    pub(crate) fn shared_settings(&self) -> NCSharedSettings {
+        NCSharedSettings {
+            heartbeat: self.heartbeat,
+            compress: self.compress,
+            allowed_codecs: self.allowed_codecs.clone(),
+            compression_min_size: self.compression_min_size,
+            encrypt: self.encrypt,
+            type_check: self.type_check,
+        }
+    }
+
+    /// Returns every setting with its value in the order of the fields, the encryption keys and the admin key are masked like in the Debug output.
+    /// All values come from this NCConfiguration, there are no other sources (files or environment variables) for the settings.
+    pub fn key_values(&self) -> Vec<(&'static str, String)> {
+        self.entries(true)
+    }
+
+    /// Logs the settings as key=value pairs, the starters call this at the beginning, so that the logs of two machines can be compared.
+    #[cfg(feature = "net")]
+    pub(crate) fn log_settings(&self, side: &str) {
+        let settings: Vec<String> = self.key_values().into_iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        info!("Configuration of the {}: {}", side, settings.join(" "));
+    }
+
+    /// Compares every setting with the other configuration and returns the ones that are different.
+    /// The server compares its configuration with the settings of every new node (see [`ConfigDiffSeverity`]):
+    /// - Error: encrypt, the key if encrypt is set and allowed_codecs or compress if there is no codec in common.
+    /// - Warning: allowed_codecs or compress if there is a codec in common, heartbeat, type_check, job_id and port.
+    /// - Info: all other settings.
+    pub fn diff(&self, other: &NCConfiguration) -> Vec<ConfigDiff> {
+        let common_codec = NCCodec::negotiate(&self.codecs(), &other.codecs()).is_some();
+
+        self.entries(false).into_iter().zip(other.entries(false)).zip(self.entries(true).into_iter().zip(other.entries(true)))
+            .filter(|(((_, local), (_, remote)), _)| local != remote)
+            .map(|(_, ((key, local), (_, remote)))| {
+                let severity = match key {
+                    "encrypt" => ConfigDiffSeverity::Error,
+                    "key" if self.encrypt || other.encrypt => ConfigDiffSeverity::Error,
+                    "compress" | "allowed_codecs" if !common_codec => ConfigDiffSeverity::Error,
+                    "compress" | "allowed_codecs" | "heartbeat" | "type_check" | "job_id" | "port" => ConfigDiffSeverity::Warning,
+                    _ => ConfigDiffSeverity::Info,
+                };
+
+                ConfigDiff { key: key.to_string(), local, remote, severity }
+            })
+            .collect()
+    }
+
+    /// Returns every setting with its value (Debug format), see key_values(). If masked is false the secrets are shown.
+    fn entries(&self, masked: bool) -> Vec<(&'static str, String)> {
+        let secret = |secret: &str| if masked { format!("{:?}", mask(secret)) } else { format!("{:?}", secret) };
+
+        vec![
+            ("address", format!("{:?}", self.address)),
+            ("port", format!("{:?}", self.port)),
+            ("heartbeat", format!("{:?}", self.heartbeat)),
+            ("delay_request_data", format!("{:?}", self.delay_request_data)),
+            ("retry_counter", format!("{:?}", self.retry_counter)),
+            ("pool_size", format!("{:?}", self.pool_size)),
+            ("compress", format!("{:?}", self.compress)),
+            ("allowed_codecs", format!("{:?}", self.allowed_codecs)),
+            ("compression_min_size", format!("{:?}", self.compression_min_size)),
+            ("max_decompressed_bytes", format!("{:?}", self.max_decompressed_bytes)),
+            ("max_frame_bytes", format!("{:?}", self.max_frame_bytes)),
+            ("result_dictionary_samples", format!("{:?}", self.result_dictionary_samples)),
+            ("result_dictionary_size", format!("{:?}", self.result_dictionary_size)),
+            ("encrypt", format!("{:?}", self.encrypt)),
+            ("key", secret(&self.key)),
+            ("previous_keys", format!("[{}]", self.previous_keys.iter().map(|key| secret(key)).collect::<Vec<_>>().join(", "))),
+            ("max_previous_keys", format!("{:?}", self.max_previous_keys)),
+            ("admin_key", secret(&self.admin_key)),
+            ("admin_message_max_age", format!("{:?}", self.admin_message_max_age)),
+            ("max_permanent_failures", format!("{:?}", self.max_permanent_failures)),
+            ("result_queue_max_bytes", format!("{:?}", self.result_queue_max_bytes)),
+            ("result_queue_mode", format!("{:?}", self.result_queue_mode)),
+            ("on_process_error", format!("{:?}", self.on_process_error)),
+            ("max_process_retries", format!("{:?}", self.max_process_retries)),
+            ("max_chunk_attempts", format!("{:?}", self.max_chunk_attempts)),
+            ("spill_dir", format!("{:?}", self.spill_dir)),
+            ("payload_warn_bytes", format!("{:?}", self.payload_warn_bytes)),
+            ("log_payload_hashes", format!("{:?}", self.log_payload_hashes)),
+            ("ordered_results", format!("{:?}", self.ordered_results)),
+            ("reorder_buffer_max_len", format!("{:?}", self.reorder_buffer_max_len)),
+            ("max_reorder_wait", format!("{:?}", self.max_reorder_wait)),
+            ("result_batch_size", format!("{:?}", self.result_batch_size)),
+            ("result_batch_bytes", format!("{:?}", self.result_batch_bytes)),
+            ("result_batch_ms", format!("{:?}", self.result_batch_ms)),
+            ("cache_chunk_payloads", format!("{:?}", self.cache_chunk_payloads)),
+            ("chunk_cache_max_bytes", format!("{:?}", self.chunk_cache_max_bytes)),
+            ("cache_chunk_frames", format!("{:?}", self.cache_chunk_frames)),
+            ("frame_cache_max_bytes", format!("{:?}", self.frame_cache_max_bytes)),
+            ("scratch_dir", format!("{:?}", self.scratch_dir)),
+            ("keep_scratch_on_failure", format!("{:?}", self.keep_scratch_on_failure)),
+            ("min_free_space", format!("{:?}", self.min_free_space)),
+            ("checkpoint_file", format!("{:?}", self.checkpoint_file)),
+            ("checkpoint_interval", format!("{:?}", self.checkpoint_interval)),
+            ("work_hint_max_bytes", format!("{:?}", self.work_hint_max_bytes)),
+            ("work_hint_max_duration", format!("{:?}", self.work_hint_max_duration)),
+            ("work_hint_kind", format!("{:?}", self.work_hint_kind)),
+            ("prefetch", format!("{:?}", self.prefetch)),
+            ("upload_in_background", format!("{:?}", self.upload_in_background)),
+            ("offline_batch_timeout_days", format!("{:?}", self.offline_batch_timeout_days)),
+            ("long_poll_ms", format!("{:?}", self.long_poll_ms)),
+            ("max_chunk_meta_bytes", format!("{:?}", self.max_chunk_meta_bytes)),
+            ("write_job_summary", format!("{:?}", self.write_job_summary)),
+            ("event_log", format!("{:?}", self.event_log)),
+            ("event_log_flush_ms", format!("{:?}", self.event_log_flush_ms)),
+            ("sample_fraction", format!("{:?}", self.sample_fraction)),
+            ("sample_seed", format!("{:?}", self.sample_seed)),
+            ("max_connections", format!("{:?}", self.max_connections)),
+            ("max_connection_lifetime", format!("{:?}", self.max_connection_lifetime)),
+            ("bind_mode", format!("{:?}", self.bind_mode)),
+            ("dual_stack_required", format!("{:?}", self.dual_stack_required)),
+            ("finish_linger_ms", format!("{:?}", self.finish_linger_ms)),
+            ("shutdown_grace_ms", format!("{:?}", self.shutdown_grace_ms)),
+            ("max_job_duration", format!("{:?}", self.max_job_duration)),
+            ("job_drain_timeout", format!("{:?}", self.job_drain_timeout)),
+            ("text_protocol", format!("{:?}", self.text_protocol)),
+            ("post_process_workers", format!("{:?}", self.post_process_workers)),
+            ("post_process_queue_len", format!("{:?}", self.post_process_queue_len)),
+            ("required_node_build", format!("{:?}", self.required_node_build)),
+            ("node_build", format!("{:?}", self.node_build)),
+            ("job_id", format!("{:?}", self.job_id)),
+            ("aggregator_port", format!("{:?}", self.aggregator_port)),
+            ("aggregator_tag", format!("{:?}", self.aggregator_tag)),
+            ("aggregate_interval", format!("{:?}", self.aggregate_interval)),
+            ("node_tags", format!("{:?}", self.node_tags)),
+            ("locality_manifest", format!("{:?}", self.locality_manifest)),
+            ("locality_fallback_ms", format!("{:?}", self.locality_fallback_ms)),
+            ("type_check", format!("{:?}", self.type_check)),
+            ("strict_mode", format!("{:?}", self.strict_mode)),
+            ("max_clock_skew_ms", format!("{:?}", self.max_clock_skew_ms)),
+            ("report_resources", format!("{:?}", self.report_resources)),
+            ("min_node_free_mem", format!("{:?}", self.min_node_free_mem)),
+            ("min_node_free_disk", format!("{:?}", self.min_node_free_disk)),
+            ("cpu_limit_percent", format!("{:?}", self.cpu_limit_percent)),
+            ("artifacts_dir", format!("{:?}", self.artifacts_dir)),
+            ("max_artifact_bytes", format!("{:?}", self.max_artifact_bytes)),
+            ("max_artifacts_per_chunk", format!("{:?}", self.max_artifacts_per_chunk)),
+            ("result_cache_count", format!("{:?}", self.result_cache_count)),
+            ("result_cache_max_bytes", format!("{:?}", self.result_cache_max_bytes)),
+            ("result_cache_dir", format!("{:?}", self.result_cache_dir)),
+            ("node_id_file", format!("{:?}", self.node_id_file)),
+            ("signing_key_file", format!("{:?}", self.signing_key_file)),
+            ("node_keys_file", format!("{:?}", self.node_keys_file)),
+            ("require_signed_results", format!("{:?}", self.require_signed_results)),
+            ("mdns_announce", format!("{:?}", self.mdns_announce)),
+            ("replicate_to", format!("{:?}", self.replicate_to)),
+            ("replication_port", format!("{:?}", self.replication_port)),
+            ("replication_interval_ms", format!("{:?}", self.replication_interval_ms)),
+            // The Debug output of NCProxyConfig masks the password
+            ("proxy", if masked { format!("{:?}", self.proxy) } else { format!("{:?}", self.proxy.as_ref().map(|proxy| (proxy.kind, &proxy.addr, &proxy.auth))) }),
+            ("transport", format!("{:?}", self.transport)),
+        ]
+    }
+}
+
+/// Masks a secret for the Debug output, only shows if it's set at all.
+fn mask(secret: &str) -> &'static str {
+    if secret.is_empty() {
+        ""
+    } else {
+        "***"
+    }
+}
+
+/// Same as the derived Debug, but the encryption keys and the admin key are masked, so that the configuration can be logged.
+impl fmt::Debug for NCConfiguration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NCConfiguration")
+            .field("address", &self.address)
+            .field("port", &self.port)
+            .field("heartbeat", &self.heartbeat)
+            .field("delay_request_data", &self.delay_request_data)
+            .field("retry_counter", &self.retry_counter)
+            .field("pool_size", &self.pool_size)
+            .field("compress", &self.compress)
+            .field("allowed_codecs", &self.allowed_codecs)
+            .field("compression_min_size", &self.compression_min_size)
+            .field("max_decompressed_bytes", &self.max_decompressed_bytes)
+            .field("max_frame_bytes", &self.max_frame_bytes)
+            .field("result_dictionary_samples", &self.result_dictionary_samples)
+            .field("result_dictionary_size", &self.result_dictionary_size)
+            .field("encrypt", &self.encrypt)
+            .field("key", &mask(&self.key))
+            .field("previous_keys", &self.previous_keys.iter().map(|key| mask(key)).collect::<Vec<_>>())
+            .field("max_previous_keys", &self.max_previous_keys)
+            .field("admin_key", &mask(&self.admin_key))
+            .field("admin_message_max_age", &self.admin_message_max_age)
+            .field("max_permanent_failures", &self.max_permanent_failures)
+            .field("result_queue_max_bytes", &self.result_queue_max_bytes)
+            .field("result_queue_mode", &self.result_queue_mode)
+            .field("on_process_error", &self.on_process_error)
+            .field("max_process_retries", &self.max_process_retries)
+            .field("max_chunk_attempts", &self.max_chunk_attempts)
+            .field("spill_dir", &self.spill_dir)
+            .field("payload_warn_bytes", &self.payload_warn_bytes)
+            .field("log_payload_hashes", &self.log_payload_hashes)
+            .field("ordered_results", &self.ordered_results)
+            .field("reorder_buffer_max_len", &self.reorder_buffer_max_len)
+            .field("max_reorder_wait", &self.max_reorder_wait)
+            .field("result_batch_size", &self.result_batch_size)
+            .field("result_batch_bytes", &self.result_batch_bytes)
+            .field("result_batch_ms", &self.result_batch_ms)
+            .field("cache_chunk_payloads", &self.cache_chunk_payloads)
+            .field("chunk_cache_max_bytes", &self.chunk_cache_max_bytes)
+            .field("cache_chunk_frames", &self.cache_chunk_frames)
+            .field("frame_cache_max_bytes", &self.frame_cache_max_bytes)
+            .field("scratch_dir", &self.scratch_dir)
+            .field("keep_scratch_on_failure", &self.keep_scratch_on_failure)
+            .field("min_free_space", &self.min_free_space)
+            .field("checkpoint_file", &self.checkpoint_file)
+            .field("checkpoint_interval", &self.checkpoint_interval)
+            .field("work_hint_max_bytes", &self.work_hint_max_bytes)
+            .field("work_hint_max_duration", &self.work_hint_max_duration)
+            .field("work_hint_kind", &self.work_hint_kind)
+            .field("prefetch", &self.prefetch)
+            .field("upload_in_background", &self.upload_in_background)
+            .field("offline_batch_timeout_days", &self.offline_batch_timeout_days)
+            .field("long_poll_ms", &self.long_poll_ms)
+            .field("max_chunk_meta_bytes", &self.max_chunk_meta_bytes)
+            .field("write_job_summary", &self.write_job_summary)
+            .field("event_log", &self.event_log)
+            .field("event_log_flush_ms", &self.event_log_flush_ms)
+            .field("sample_fraction", &self.sample_fraction)
+            .field("sample_seed", &self.sample_seed)
+            .field("max_connections", &self.max_connections)
+            .field("max_connection_lifetime", &self.max_connection_lifetime)
+            .field("bind_mode", &self.bind_mode)
+            .field("dual_stack_required", &self.dual_stack_required)
+            .field("finish_linger_ms", &self.finish_linger_ms)
+            .field("shutdown_grace_ms", &self.shutdown_grace_ms)
+            .field("max_job_duration", &self.max_job_duration)
+            .field("job_drain_timeout", &self.job_drain_timeout)
+            .field("text_protocol", &self.text_protocol)
+            .field("post_process_workers", &self.post_process_workers)
+            .field("post_process_queue_len", &self.post_process_queue_len)
+            .field("required_node_build", &self.required_node_build)
+            .field("node_build", &self.node_build)
+            .field("job_id", &self.job_id)
+            .field("aggregator_port", &self.aggregator_port)
+            .field("aggregator_tag", &self.aggregator_tag)
+            .field("aggregate_interval", &self.aggregate_interval)
+            .field("node_tags", &self.node_tags)
+            .field("locality_manifest", &self.locality_manifest)
+            .field("locality_fallback_ms", &self.locality_fallback_ms)
+            .field("type_check", &self.type_check)
+            .field("strict_mode", &self.strict_mode)
+            .field("max_clock_skew_ms", &self.max_clock_skew_ms)
+            .field("report_resources", &self.report_resources)
+            .field("min_node_free_mem", &self.min_node_free_mem)
+            .field("min_node_free_disk", &self.min_node_free_disk)
+            .field("cpu_limit_percent", &self.cpu_limit_percent)
+            .field("artifacts_dir", &self.artifacts_dir)
+            .field("max_artifact_bytes", &self.max_artifact_bytes)
+            .field("max_artifacts_per_chunk", &self.max_artifacts_per_chunk)
+            .field("result_cache_count", &self.result_cache_count)
+            .field("result_cache_max_bytes", &self.result_cache_max_bytes)
+            .field("result_cache_dir", &self.result_cache_dir)
+            .field("node_id_file", &self.node_id_file)
+            .field("signing_key_file", &self.signing_key_file)
+            .field("node_keys_file", &self.node_keys_file)
+            .field("require_signed_results", &self.require_signed_results)
+            .field("mdns_announce", &self.mdns_announce)
+            .field("replicate_to", &self.replicate_to)
+            .field("replication_port", &self.replication_port)
+            .field("replication_interval_ms", &self.replication_interval_ms)
+            .field("proxy", &self.proxy)
+            .field("transport", &self.transport)
+            .finish()
+    }
+}
+
 impl Display for NCConfiguration {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "address: '{}', port: '{}', heartbeat: '{}'\n
                   delay request data: '{}', retry counter: '{}', pool size: '{}'\n
-                  compress: '{}', encrypt: '{}'",
+                  compress: '{}', allowed codecs: '{:?}', compression min size: '{}', max decompressed bytes: '{}', max frame bytes: '{}', encrypt: '{}'\n
+                  result dictionary samples: '{}', result dictionary size: '{}'\n
+                  result queue max bytes: '{}', result queue mode: '{:?}', spill dir: '{}'\n
+                  on process error: '{:?}', max process retries: '{}', max chunk attempts: '{}'\n
+                  payload warn bytes: '{}', log payload hashes: '{}', ordered results: '{}', reorder buffer max len: '{}', max reorder wait: '{}'\n
+                  result batch size: '{}', result batch bytes: '{}', result batch ms: '{}'\n
+                  cache chunk payloads: '{}', chunk cache max bytes: '{}', cache chunk frames: '{}', frame cache max bytes: '{}'\n
+                  scratch dir: '{:?}', keep scratch on failure: '{}', min free space: '{}'\n
+                  checkpoint file: '{:?}', checkpoint interval: '{}', write job summary: '{}', event log: '{:?}', event log flush ms: '{}', sample fraction: '{:?}', sample seed: '{:?}'\n
+                  work hint max bytes: '{}', work hint max duration: '{}', work hint kind: '{:?}', prefetch: '{}', upload in background: '{}', max chunk meta bytes: '{}'\n
+                  offline batch timeout days: '{}', long poll ms: '{}'\n
+                  max connections: '{}', max connection lifetime: '{}', bind mode: '{:?}', dual stack required: '{}', finish linger ms: '{}', shutdown grace ms: '{}', text protocol: '{}'\n
+                  max job duration: '{:?}', job drain timeout: '{}'\n
+                  post process workers: '{}', post process queue len: '{}'\n
+                  required node build: '{:?}', node build: '{:?}', job id: '{:?}'\n
+                  aggregator port: '{:?}', aggregator tag: '{:?}', aggregate interval: '{}', node tags: '{:?}', locality manifest: '{:?}', locality fallback ms: '{}'\n
+                  type check: '{}', strict mode: '{}', max clock skew ms: '{}', report resources: '{}', min node free mem: '{}', min node free disk: '{}', cpu limit percent: '{:?}'\n
+                  artifacts dir: '{:?}', max artifact bytes: '{}', max artifacts per chunk: '{}'\n
+                  result cache count: '{}', result cache max bytes: '{}', result cache dir: '{:?}', node id file: '{:?}'\n
+                  signing key file: '{:?}', node keys file: '{:?}', require signed results: '{}', mdns announce: '{}', admin message max age: '{}'\n
+                  replicate to: '{:?}', replication port: '{}', replication interval ms: '{}', proxy: '{:?}', transport: '{:?}'",
             self.address, self.port, self.heartbeat, self.delay_request_data,
-            self.retry_counter, self.pool_size, self.compress, self.encrypt)
+            self.retry_counter, self.pool_size, self.compress, self.allowed_codecs, self.compression_min_size, self.max_decompressed_bytes, self.max_frame_bytes, self.encrypt,
+            self.result_dictionary_samples, self.result_dictionary_size,
+            self.result_queue_max_bytes, self.result_queue_mode, self.spill_dir.display(),
+            self.on_process_error, self.max_process_retries, self.max_chunk_attempts,
+            self.payload_warn_bytes, self.log_payload_hashes, self.ordered_results, self.reorder_buffer_max_len, self.max_reorder_wait,
+            self.result_batch_size, self.result_batch_bytes, self.result_batch_ms,
+            self.cache_chunk_payloads, self.chunk_cache_max_bytes, self.cache_chunk_frames, self.frame_cache_max_bytes,
+            self.scratch_dir, self.keep_scratch_on_failure, self.min_free_space,
+            self.checkpoint_file, self.checkpoint_interval, self.write_job_summary, self.event_log, self.event_log_flush_ms, self.sample_fraction, self.sample_seed,
+            self.work_hint_max_bytes, self.work_hint_max_duration, self.work_hint_kind, self.prefetch, self.upload_in_background, self.max_chunk_meta_bytes,
+            self.offline_batch_timeout_days, self.long_poll_ms,
+            self.max_connections, self.max_connection_lifetime, self.bind_mode, self.dual_stack_required, self.finish_linger_ms, self.shutdown_grace_ms, self.text_protocol,
+            self.max_job_duration, self.job_drain_timeout,
+            self.post_process_workers, self.post_process_queue_len,
+            self.required_node_build, self.node_build, self.job_id,
+            self.aggregator_port, self.aggregator_tag, self.aggregate_interval, self.node_tags, self.locality_manifest, self.locality_fallback_ms,
+            self.type_check, self.strict_mode, self.max_clock_skew_ms, self.report_resources, self.min_node_free_mem, self.min_node_free_disk, self.cpu_limit_percent,
+            self.artifacts_dir, self.max_artifact_bytes, self.max_artifacts_per_chunk,
+            self.result_cache_count, self.result_cache_max_bytes, self.result_cache_dir, self.node_id_file,
+            self.signing_key_file, self.node_keys_file, self.require_signed_results, self.mdns_announce, self.admin_message_max_age,
+            self.replicate_to, self.replication_port, self.replication_interval_ms, self.proxy, self.transport)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_masks_keys() {
+        let config = NCConfiguration {
+            key: "u8PqN2lO3ZJbm6DHkTnR0cyVaSx1FWgi".to_string(),
+            previous_keys: vec!["Hn3kqPZ0lXv8dWmYc1RtG5sJbQ2eA7fU".to_string()],
+            admin_key: "ZTXbsBVhz9tDzDhklykVDUXznjonhGil".to_string(),
+            ..Default::default()
+        };
+        let debug = format!("{:?}", config);
+
+        assert!(debug.contains(r#"key: "***", previous_keys: ["***"]"#));
+        assert!(debug.contains(r#"admin_key: "***""#));
+        assert!(debug.contains("require_signed_results: false"));
+        assert!(!debug.contains("u8PqN2lO") && !debug.contains("Hn3kqPZ0") && !debug.contains("ZTXbsBVh"));
+
+        // An empty admin key is shown, since it disables the admin commands
+        assert!(format!("{:?}", NCConfiguration::default()).contains(r#"admin_key: """#));
+        assert!(!format!("{:#?}", config).contains("u8PqN2lO"));
+    }
+
+    #[test]
+    fn test_key_values() {
+        let config = NCConfiguration { key: "u8PqN2lO3ZJbm6DHkTnR0cyVaSx1FWgi".to_string(), ..Default::default() };
+        let key_values = config.key_values();
+
+        // Every field is there, in the order of the Debug output
+        assert_eq!(key_values.len(), format!("{:?}", config).matches(": ").count());
+        assert_eq!(key_values[0], ("address", "\"127.0.0.1\"".to_string()));
+        assert!(key_values.contains(&("key", "\"***\"".to_string())));
+        assert!(key_values.iter().all(|(_, value)| !value.contains("u8PqN2lO")));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_diff() {
+        let server = NCConfiguration { encrypt: true, ..Default::default() };
+        assert!(server.diff(&server.clone()).is_empty());
+
+        let node = NCConfiguration { heartbeat: 5, pool_size: 2, key: "u8PqN2lO3ZJbm6DHkTnR0cyVaSx1FWgi".to_string(), ..server.clone() };
+        let diffs = server.diff(&node);
+        let severities: Vec<(&str, ConfigDiffSeverity)> = diffs.iter().map(|diff| (diff.key.as_str(), diff.severity)).collect();
+        assert_eq!(severities, vec![("heartbeat", ConfigDiffSeverity::Warning), ("pool_size", ConfigDiffSeverity::Info), ("key", ConfigDiffSeverity::Error)]);
+
+        // The secret is compared but not shown
+        assert_eq!(diffs[2].to_string(), "Error: key: \"***\" != \"***\"");
+
+        // Codecs only matter if there is none in common
+        let server = NCConfiguration::default();
+        let node = NCConfiguration { allowed_codecs: vec![NCCodec::Lz4], pool_size: 2, ..Default::default() };
+        assert_eq!(server.diff(&node)[1].severity, ConfigDiffSeverity::Warning);
+        let server = NCConfiguration { compress: false, ..Default::default() };
+        assert!(server.diff(&node).iter().filter(|diff| diff.key != "pool_size").all(|diff| diff.severity == ConfigDiffSeverity::Error));
+
+        // Only the settings that the node sends during registration are compared
+        assert_eq!(server.shared_settings().to_config().diff(&node.shared_settings().to_config()).len(), 2);
     }
 }
@@ -0,0 +1,157 @@
+//! This module contains the context of the current call into the user code, for example to tag every log line and
+//! every output file with the job, the node and the chunk.
+//! Before the server calls a method of the NCServer trait and before the node calls a method of the NCNode trait the context is set
+//! for the calling thread, inside the method [`current()`] returns it:
+//!
+//! - job_id: the job_id of the NCConfiguration.
+//! - node_id and node_info: the node that the call is about (on the node: the node itself), [`None`] for calls that are about the whole job,
+//!   for example finish_job() or heartbeat_timeout() with several nodes.
+//! - chunk_id and attempt: the chunk that the call is about and how often the chunk has been sent to a node, counting the current
+//!   attempt (1 for the first one). The attempt goes up when the chunk is sent again after an error, a heartbeat timeout, ...
+//!   A call that is not about a chunk has no chunk_id and attempt 0.
+//!
+//! Outside of these calls (and in threads that have been started by the user code) current() returns the empty context,
+//! NCNode::reduce() on an aggregator doesn't have a context either.
+//! The node gets the attempt from the server with the chunk, see [`NCProcessContext::attempt()`](crate::NCProcessContext::attempt).
+
+use std::cell::RefCell;
+use std::fmt::{self, Display, Formatter};
+
+use crate::nc_node_info::NodeID;
+#[cfg(feature = "net")]
+use crate::nc_config::NCConfiguration;
+use crate::array2d::ChunkID;
+
+thread_local! {
+    /// The context of the call into the user code that is running in this thread, if any.
+    static CURRENT: RefCell<Option<NCCallContext>> = const { RefCell::new(None) };
+}
+
+/// What is known about the node of a call, see [`NCCallContext`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NCContextNodeInfo {
+    /// The build of the node binary, see node_build in the NCConfiguration.
+    pub build: Option<String>,
+    /// The groups of the node, see node_tags in the NCConfiguration.
+    pub tags: Vec<String>,
+}
+
+#[cfg(feature = "net")]
+impl NCContextNodeInfo {
+    /// The build and the groups of this node (node_build and node_tags in the NCConfiguration), for the calls on the node.
+    pub(crate) fn from_config(config: &NCConfiguration) -> Self {
+        NCContextNodeInfo { build: config.node_build.clone(), tags: config.node_tags.clone() }
+    }
+}
+
+/// The job, the node and the chunk of the current call into the user code, see the module documentation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NCCallContext {
+    /// The id of the job, [`None`] if no job_id is set in the NCConfiguration.
+    pub job_id: Option<String>,
+    /// The node that the call is about.
+    pub node_id: Option<NodeID>,
+    /// The build and the groups of that node.
+    pub node_info: Option<NCContextNodeInfo>,
+    /// The chunk that the call is about.
+    pub chunk_id: Option<ChunkID>,
+    /// The number of the attempt for the chunk, starting with 1. 0 if the call is not about a chunk.
+    pub attempt: u32,
+}
+
+#[cfg(feature = "net")]
+impl NCCallContext {
+    /// A context for the given job and node, without a chunk.
+    pub(crate) fn new(job_id: Option<String>, node_id: Option<NodeID>, node_info: Option<NCContextNodeInfo>) -> Self {
+        NCCallContext { job_id, node_id, node_info, chunk_id: None, attempt: 0 }
+    }
+
+    /// The same context for the given chunk and attempt.
+    pub(crate) fn with_chunk(self, chunk_id: ChunkID, attempt: u32) -> Self {
+        NCCallContext { chunk_id: Some(chunk_id), attempt, ..self }
+    }
+}
+
+impl Display for NCCallContext {
+    /// The parts that are set, for example "job mandel, node 42, chunk 7, attempt 2" for a log line.
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+
+        if let Some(job_id) = &self.job_id {
+            parts.push(format!("job {}", job_id));
+        }
+
+        if let Some(node_id) = self.node_id {
+            parts.push(format!("node {}", node_id));
+        }
+
+        if let Some(chunk_id) = self.chunk_id {
+            parts.push(format!("chunk {}, attempt {}", chunk_id, self.attempt));
+        }
+
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Returns the context of the current call into the user code, the empty context outside of such a call.
+pub fn current() -> NCCallContext {
+    CURRENT.with(|current| current.borrow().clone()).unwrap_or_default()
+}
+
+/// Restores the previous context of the thread when it's dropped, see enter().
+#[cfg(feature = "net")]
+#[must_use]
+pub(crate) struct NCContextGuard(Option<NCCallContext>);
+
+#[cfg(feature = "net")]
+impl Drop for NCContextGuard {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Sets the given context for this thread until the guard is dropped, also if the user code panics.
+/// Contexts can be nested: the server first sets the context of the node and then the one of the chunk.
+#[cfg(feature = "net")]
+pub(crate) fn enter(context: NCCallContext) -> NCContextGuard {
+    NCContextGuard(CURRENT.with(|current| current.borrow_mut().replace(context)))
+}
+
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::*;
+
+    use std::panic;
+
+    #[test]
+    fn test_enter() {
+        assert_eq!(current(), NCCallContext::default());
+
+        let node_id: NodeID = "42".parse().unwrap();
+        let node = NCCallContext::new(Some("mandel".to_string()), Some(node_id), None);
+
+        {
+            let _guard = enter(node.clone());
+            assert_eq!(current(), node);
+
+            {
+                let _guard = enter(node.clone().with_chunk(7, 2));
+                assert_eq!(current().to_string(), "job mandel, node 42, chunk 7, attempt 2");
+            }
+
+            assert_eq!(current(), node);
+
+            // A panic in the user code doesn't leave the context behind
+            let result = panic::catch_unwind(|| {
+                let _guard = enter(NCCallContext::default().with_chunk(1, 1));
+                panic!("user code");
+            });
+            assert!(result.is_err());
+            assert_eq!(current(), node);
+        }
+
+        assert_eq!(current(), NCCallContext::default());
+        assert_eq!(current().to_string(), "");
+    }
+}
@@ -0,0 +1,221 @@
+//! This module contains the zstd dictionaries for the results (result_dictionary_samples in the NCConfiguration).
+//! Small results that look alike (for example JSON like blobs with the same keys) don't compress well on their own,
+//! zstd has not much to learn from in a few hundred bytes. With a dictionary that has been trained on similar data they do.
+//!
+//! The server collects the serialized data of the first result_dictionary_samples results and trains a dictionary with at most
+//! result_dictionary_size bytes from it (needs the zstd-dict feature). The id of the dictionary is the FNV-1a hash of its bytes.
+//! From then on every chunk info (NCChunkInfo::result_dictionary) for a node that uses NCCodec::Zstd and sends its results to the
+//! server directly (not to an aggregator) has the id of the dictionary. A node that doesn't have the dictionary yet asks for it
+//! with the NCNodeMessage::GetDictionary message and compresses its messages with it from then on.
+//! The frames have the id of the dictionary in the header (DICTIONARY_FLAG, see the [`nc_proto`](crate::nc_proto) module),
+//! so frames with and without a dictionary can be mixed and every frame is decoded with the right one.
+//! A node that can't get the dictionary sends its results without and a node that is older than the dictionaries ignores the id.
+//!
+//! The lifecycle is simple: the server trains one dictionary per run and keeps it until the job is done, it's not part of the checkpoint.
+//! A node keeps the last MAX_DICTIONARIES dictionaries and uses the one from the last chunk info, after a move to another server
+//! (NCServerMessage::NewServer) it doesn't use any until a chunk info of the new server has one.
+
+use std::io::Write;
+use std::sync::Arc;
+
+#[cfg(feature = "net")]
+use log::debug;
+
+use crate::nc_communicator::read_limited;
+#[cfg(feature = "net")]
+use crate::nc_config::NCConfiguration;
+use crate::nc_error::NCError;
+#[cfg(feature = "net")]
+use crate::nc_proto::fnv1a;
+
+/// A communicator keeps at most this many dictionaries, the oldest one that is not used for sending is dropped first.
+#[cfg(feature = "net")]
+const MAX_DICTIONARIES: usize = 4;
+
+/// The id of the given dictionary, see the module documentation.
+#[cfg(feature = "net")]
+pub(crate) fn dictionary_id(dictionary: &[u8]) -> u32 {
+    fnv1a(dictionary)
+}
+
+/// The dictionaries of a communicator, shared with the communicators from NCCommunicator::share().
+#[derive(Debug, Default)]
+pub(crate) struct NCDictionaries {
+    /// The id and the bytes of every dictionary, the oldest one first.
+    entries: Vec<(u32, Arc<Vec<u8>>)>,
+    /// The frames with NCCodec::Zstd are compressed with this dictionary.
+    current: Option<u32>,
+}
+
+impl NCDictionaries {
+    /// Adds the given dictionary and returns its id.
+    #[cfg(feature = "net")]
+    pub(crate) fn insert(&mut self, dictionary: Vec<u8>) -> u32 {
+        debug!("NCDictionaries::insert()");
+
+        let id = dictionary_id(&dictionary);
+
+        if !self.contains(id) {
+            if self.entries.len() >= MAX_DICTIONARIES {
+                if let Some(index) = self.entries.iter().position(|(other, _)| Some(*other) != self.current) {
+                    self.entries.remove(index);
+                }
+            }
+
+            self.entries.push((id, Arc::new(dictionary)));
+        }
+
+        id
+    }
+
+    /// Returns true if the dictionary with the given id is known.
+    #[cfg(feature = "net")]
+    pub(crate) fn contains(&self, id: u32) -> bool {
+        self.entries.iter().any(|(other, _)| *other == id)
+    }
+
+    /// Returns the dictionary with the given id, [`None`] if it's not known.
+    pub(crate) fn get(&self, id: u32) -> Option<Arc<Vec<u8>>> {
+        self.entries.iter().find(|(other, _)| *other == id).map(|(_, dictionary)| dictionary.clone())
+    }
+
+    /// Compresses the frames with the given dictionary from now on, [`None`] or an unknown dictionary: without a dictionary.
+    #[cfg(feature = "net")]
+    pub(crate) fn set_current(&mut self, id: Option<u32>) {
+        self.current = id.filter(|id| self.contains(*id));
+    }
+
+    /// The id and the bytes of the dictionary that is used for compressing the frames with NCCodec::Zstd, if any.
+    pub(crate) fn current(&self) -> Option<(u32, Arc<Vec<u8>>)> {
+        self.current.and_then(|id| self.get(id).map(|dictionary| (id, dictionary)))
+    }
+}
+
+/// Compresses the data with zstd, the given level and dictionary.
+pub(crate) fn compress(data: &[u8], level: i32, dictionary: &[u8]) -> Result<Vec<u8>, NCError> {
+    let mut encoder = zstd::stream::Encoder::with_dictionary(Vec::new(), level, dictionary)?;
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Decompresses the data that has been compressed with compress() and the same dictionary, but not more than limit bytes.
+pub(crate) fn decompress(data: &[u8], dictionary: &[u8], limit: u64) -> Result<Vec<u8>, NCError> {
+    let decoder = zstd::stream::Decoder::with_dictionary(data, dictionary)?;
+    read_limited(decoder, limit)
+}
+
+/// Collects the serialized data of the first results on the server and trains the dictionary, see the module documentation.
+#[cfg(feature = "net")]
+pub(crate) struct NCDictionaryTrainer {
+    /// The serialized data of the results so far.
+    samples: Vec<Vec<u8>>,
+    /// The dictionary is trained when there are this many samples.
+    wanted: usize,
+    /// Maximum size of the dictionary in bytes.
+    max_size: usize,
+}
+
+#[cfg(feature = "net")]
+impl NCDictionaryTrainer {
+    /// The trainer for the settings in the given configuration, [`None`] if result_dictionary_samples is 0.
+    pub(crate) fn new(config: &NCConfiguration) -> Option<Self> {
+        debug!("NCDictionaryTrainer::new()");
+
+        (config.result_dictionary_samples > 0).then(|| NCDictionaryTrainer {
+            samples: Vec::with_capacity(config.result_dictionary_samples),
+            wanted: config.result_dictionary_samples,
+            max_size: config.result_dictionary_size,
+        })
+    }
+
+    /// Adds the serialized data of a result, returns true once there are enough samples for train().
+    pub(crate) fn add(&mut self, sample: Vec<u8>) -> bool {
+        debug!("NCDictionaryTrainer::add()");
+
+        self.samples.push(sample);
+        self.samples.len() >= self.wanted
+    }
+
+    /// Trains the dictionary from the samples, returns the reason if it could not be trained (for example too few or too small samples).
+    pub(crate) fn train(&self) -> Result<Vec<u8>, String> {
+        debug!("NCDictionaryTrainer::train()");
+
+        train(&self.samples, self.max_size)
+    }
+}
+
+/// Trains a dictionary with at most max_size bytes from the given samples.
+#[cfg(feature = "zstd-dict")]
+fn train(samples: &[Vec<u8>], max_size: usize) -> Result<Vec<u8>, String> {
+    zstd::dict::from_samples(samples, max_size).map_err(|e| e.to_string())
+}
+
+/// Without the zstd-dict feature there is no training, NCConfiguration::check() doesn't allow result_dictionary_samples then.
+#[cfg(feature = "net")]
+#[cfg(not(feature = "zstd-dict"))]
+fn train(_samples: &[Vec<u8>], _max_size: usize) -> Result<Vec<u8>, String> {
+    Err("result_dictionary_samples needs the zstd-dict feature".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Results that look alike, as the node serializes them.
+    pub(crate) fn samples(n: usize) -> Vec<Vec<u8>> {
+        (0..n).map(|i| format!(r#"{{"chunk":{},"status":"done","pixels":[{},{},{}],"renderer":"path tracer","samples":{}}}"#,
+            i, i % 7, i % 13, i % 17, 64 + i % 5).into_bytes()).collect()
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_dictionaries() {
+        let mut dictionaries = NCDictionaries::default();
+        let first = dictionaries.insert(vec![1; 100]);
+        assert_eq!(first, dictionary_id(&[1; 100]));
+        assert_eq!(dictionaries.insert(vec![1; 100]), first);
+
+        dictionaries.set_current(Some(7));
+        assert!(dictionaries.current().is_none());
+        dictionaries.set_current(Some(first));
+        assert_eq!(dictionaries.current().unwrap().0, first);
+
+        // The dictionary that is used for sending is kept, the oldest other one is dropped
+        let others: Vec<u32> = (2..=MAX_DICTIONARIES as u8 + 1).map(|byte| dictionaries.insert(vec![byte; 100])).collect();
+        assert!(dictionaries.contains(first));
+        assert!(!dictionaries.contains(others[0]));
+        assert!(others[1..].iter().all(|id| dictionaries.contains(*id)));
+    }
+
+    #[test]
+    fn test_compress() {
+        let dictionary = samples(3).concat();
+        let data = samples(1).remove(0);
+        let compressed = compress(&data, 3, &dictionary).unwrap();
+
+        assert_eq!(decompress(&compressed, &dictionary, u64::MAX).unwrap(), data);
+    }
+
+    #[cfg(feature = "zstd-dict")]
+    #[test]
+    fn test_train() {
+        let config = NCConfiguration { result_dictionary_samples: 200, result_dictionary_size: 4096, ..Default::default() };
+        let mut trainer = NCDictionaryTrainer::new(&config).unwrap();
+        let mut samples = samples(201);
+        let data = samples.pop().unwrap();
+
+        let last = samples.pop().unwrap();
+        assert!(samples.into_iter().all(|sample| !trainer.add(sample)));
+        assert!(trainer.add(last));
+        let dictionary = trainer.train().unwrap();
+        assert!(!dictionary.is_empty() && dictionary.len() <= 4096);
+
+        // Much smaller than without the dictionary
+        let with = compress(&data, 3, &dictionary).unwrap();
+        let without = zstd::encode_all(&data[..], 3).unwrap();
+        assert!(with.len() * 2 < without.len(), "{} / {}", with.len(), without.len());
+        assert_eq!(decompress(&with, &dictionary, u64::MAX).unwrap(), data);
+
+        assert!(NCDictionaryTrainer::new(&NCConfiguration::default()).is_none());
+    }
+}
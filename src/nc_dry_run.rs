@@ -0,0 +1,138 @@
+//! This module contains the report of a dry run, see [`NCServerStarter::dry_run()`](crate::NCServerStarter::dry_run)
+//! and [`NCNodeStarter::dry_run()`](crate::NCNodeStarter::dry_run).
+//! A dry run checks the configuration and calls the user code once with some sample data, so that problems show up
+//! before a long running job is started and not after the first few hours.
+
+use std::fmt::{self, Display, Formatter};
+
+#[cfg(feature = "net")]
+use log::debug;
+
+#[cfg(feature = "net")]
+use crate::nc_config::NCConfiguration;
+#[cfg(feature = "net")]
+use crate::nc_communicator::NCCommunicator;
+
+/// The result of one check of the dry run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DryRunCheck {
+    /// Short name of the check, for example "bind port".
+    pub name: String,
+    /// What went wrong, [`None`] if the check has passed.
+    pub problem: Option<String>,
+}
+
+/// All the checks of a dry run in the order they have been done.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DryRunReport {
+    checks: Vec<DryRunCheck>,
+}
+
+impl DryRunReport {
+    /// Creates a new empty report.
+    #[cfg(feature = "net")]
+    pub(crate) fn new() -> Self {
+        DryRunReport { checks: Vec::new() }
+    }
+
+    /// Adds a check with the given name, if the result is an error the check has failed.
+    /// Returns true if the check has passed.
+    #[cfg(feature = "net")]
+    pub(crate) fn check<E: Display>(&mut self, name: &str, result: Result<(), E>) -> bool {
+        let problem = result.err().map(|e| e.to_string());
+        let passed = problem.is_none();
+
+        debug!("Dry run check: {}, problem: {:?}", name, problem);
+        self.checks.push(DryRunCheck { name: name.to_string(), problem });
+        passed
+    }
+
+    /// All the checks, passed and failed.
+    pub fn checks(&self) -> &[DryRunCheck] {
+        &self.checks
+    }
+
+    /// Only the checks that have failed.
+    pub fn problems(&self) -> impl Iterator<Item=&DryRunCheck> {
+        self.checks.iter().filter(|check| check.problem.is_some())
+    }
+
+    /// Returns true if all the checks have passed.
+    pub fn is_ok(&self) -> bool {
+        self.problems().next().is_none()
+    }
+}
+
+impl Display for DryRunReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for check in self.checks.iter() {
+            match &check.problem {
+                Some(problem) => writeln!(f, "[failed] {}: {}", check.name, problem)?,
+                None => writeln!(f, "[ok] {}", check.name)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks that a sample payload can be encoded and decoded again with every codec from the configuration
+/// (and the encryption key if encryption is enabled).
+/// Returns the communicator for further checks or [`None`] if the keys are not valid.
+#[cfg(feature = "net")]
+pub(crate) fn check_round_trip(config: &NCConfiguration, report: &mut DryRunReport) -> Option<NCCommunicator> {
+    if !report.check("encryption keys", config.check_keys()) {
+        return None
+    }
+
+    let mut nc_communicator = NCCommunicator::new(config).ok()?;
+    let sample: Vec<u64> = (0..1024).collect();
+
+    for codec in config.codecs() {
+        let result = nc_communicator.nc_encode_data_codec(&sample, codec)
+            .and_then(|data| nc_communicator.nc_decode_data::<Vec<u64>>(&data))
+            .map_err(|e| e.to_string())
+            .and_then(|decoded| if decoded == sample { Ok(()) } else { Err("decoded data is different".to_string()) });
+
+        report.check(&format!("round trip {:?}", codec), result);
+    }
+
+    Some(nc_communicator)
+}
+
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::*;
+
+    use crate::nc_communicator::NCCodec;
+
+    #[test]
+    fn test_report() {
+        let mut report = DryRunReport::new();
+
+        assert!(report.is_ok());
+        assert!(report.check::<String>("first", Ok(())));
+        assert!(!report.check("second", Err("something is wrong")));
+
+        assert!(!report.is_ok());
+        assert_eq!(report.checks().len(), 2);
+        assert_eq!(report.problems().map(|check| check.name.as_str()).collect::<Vec<_>>(), vec!["second"]);
+        assert_eq!(report.to_string(), "[ok] first\n[failed] second: something is wrong\n");
+    }
+
+    #[test]
+    fn test_check_round_trip() {
+        let config = NCConfiguration { encrypt: true, allowed_codecs: vec![NCCodec::Zstd(3), NCCodec::Lz4, NCCodec::None], ..Default::default() };
+        let mut report = DryRunReport::new();
+
+        assert!(check_round_trip(&config, &mut report).is_some());
+        assert!(report.is_ok());
+        assert_eq!(report.checks().len(), 4);
+
+        let config = NCConfiguration { previous_keys: vec!["too short".to_string()], ..Default::default() };
+        let mut report = DryRunReport::new();
+
+        assert!(check_round_trip(&config, &mut report).is_none());
+        assert_eq!(report.problems().count(), 1);
+    }
+}
@@ -0,0 +1,362 @@
+//! This module contains the versioned envelope for the parts of the messages that belong to node_crunch itself:
+//! the chunk info that the server sends with the data (NCJobStatus::Unfinished) and the settings that the node sends
+//! when it registers (NCNodeMessage::Register). bincode has no schema, a field that is added to one of these structs would
+//! otherwise break every peer that runs an older build.
+//!
+//! Inside a bincode message such a struct is written as a byte string: one version byte and then the bincode body.
+//! Since the length of the byte string is known, a reader doesn't have to understand all of the body:
+//!
+//! - A body with a newer version is decoded as the current version and the rest of the body is ignored.
+//! - A body with an older version is converted with [`NCVersioned::from_previous()`].
+//!
+//! In the JSON messages of the text protocol the structs are written as they are, without an envelope.
+//! The user data (InitialDataT, NewDataT, ProcessedDataT, CustomMessageT) is not wrapped, it's up to these types how they change.
+//!
+//! When a struct changes, follow these rules:
+//!
+//! 1. New fields are only added at the end and the old peers must be able to work without them ("ignorable additions").
+//!    Fields are never removed, reordered or changed to another type, that needs a new message instead.
+//! 2. Increase [`NCVersioned::VERSION`] and keep the previous layout as its own struct (for example `NCChunkInfoV1`).
+//! 3. Decode that struct in from_previous() and convert it with an explicit from_v1() function that sets the new fields to their defaults.
+//!    The conversion stays for at least one release, so that a fleet can be updated one machine at a time.
+//! 4. Add a fixture with the bytes of the previous version to the tests and check that both directions still work.
+//!
+//! Peers that are older than the envelope itself can't talk to peers that use it.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer, de::{self, DeserializeOwned, Visitor}};
+
+#[cfg(feature = "net")]
+use crate::nc_config::NCSharedSettings;
+use crate::nc_message::NCChunkInfo;
+use crate::array2d::{ChunkID, ChunkMeta};
+
+/// A struct that is sent inside the versioned envelope, see the module documentation.
+pub(crate) trait NCVersioned: Serialize + DeserializeOwned {
+    /// The version of the current layout, it's written in front of the body. The first version is 1.
+    const VERSION: u8;
+
+    /// Decodes the body of an older version (1 up to VERSION - 1).
+    fn from_previous(version: u8, body: &[u8]) -> Result<Self, String>;
+}
+
+/// Version 3 of the chunk info, before the result dictionary.
+#[derive(Deserialize)]
+struct NCChunkInfoV3 {
+    chunk_id: ChunkID,
+    deadline: Option<Duration>,
+    average_chunk_time: Option<Duration>,
+    aggregator: Option<SocketAddr>,
+    metadata: Option<ChunkMeta>,
+    attempt: u32,
+    job_time_remaining: Option<Duration>,
+}
+
+/// Version 2 of the chunk info, before the remaining job time.
+#[derive(Deserialize)]
+struct NCChunkInfoV2 {
+    chunk_id: ChunkID,
+    deadline: Option<Duration>,
+    average_chunk_time: Option<Duration>,
+    aggregator: Option<SocketAddr>,
+    metadata: Option<ChunkMeta>,
+    attempt: u32,
+}
+
+/// Version 1 of the chunk info, before the attempt.
+#[derive(Deserialize)]
+struct NCChunkInfoV1 {
+    chunk_id: ChunkID,
+    deadline: Option<Duration>,
+    average_chunk_time: Option<Duration>,
+    aggregator: Option<SocketAddr>,
+    metadata: Option<ChunkMeta>,
+}
+
+impl NCChunkInfo {
+    /// The chunk info from a server that doesn't count the attempts.
+    fn from_v1(chunk_info: NCChunkInfoV1) -> Self {
+        NCChunkInfo {
+            chunk_id: chunk_info.chunk_id,
+            deadline: chunk_info.deadline,
+            average_chunk_time: chunk_info.average_chunk_time,
+            aggregator: chunk_info.aggregator,
+            metadata: chunk_info.metadata,
+            attempt: 0,
+            job_time_remaining: None,
+            result_dictionary: None,
+        }
+    }
+
+    /// The chunk info from a server that doesn't send the remaining job time.
+    fn from_v2(chunk_info: NCChunkInfoV2) -> Self {
+        NCChunkInfo {
+            chunk_id: chunk_info.chunk_id,
+            deadline: chunk_info.deadline,
+            average_chunk_time: chunk_info.average_chunk_time,
+            aggregator: chunk_info.aggregator,
+            metadata: chunk_info.metadata,
+            attempt: chunk_info.attempt,
+            job_time_remaining: None,
+            result_dictionary: None,
+        }
+    }
+
+    /// The chunk info from a server that doesn't have result dictionaries.
+    fn from_v3(chunk_info: NCChunkInfoV3) -> Self {
+        NCChunkInfo {
+            chunk_id: chunk_info.chunk_id,
+            deadline: chunk_info.deadline,
+            average_chunk_time: chunk_info.average_chunk_time,
+            aggregator: chunk_info.aggregator,
+            metadata: chunk_info.metadata,
+            attempt: chunk_info.attempt,
+            job_time_remaining: chunk_info.job_time_remaining,
+            result_dictionary: None,
+        }
+    }
+}
+
+impl NCVersioned for NCChunkInfo {
+    const VERSION: u8 = 4;
+
+    fn from_previous(version: u8, body: &[u8]) -> Result<Self, String> {
+        match version {
+            1 => bincode::deserialize(body).map(NCChunkInfo::from_v1).map_err(|e| e.to_string()),
+            2 => bincode::deserialize(body).map(NCChunkInfo::from_v2).map_err(|e| e.to_string()),
+            3 => bincode::deserialize(body).map(NCChunkInfo::from_v3).map_err(|e| e.to_string()),
+            _ => Err(format!("NCChunkInfo has no version {}", version)),
+        }
+    }
+}
+
+#[cfg(feature = "net")]
+impl NCVersioned for NCSharedSettings {
+    const VERSION: u8 = 1;
+
+    fn from_previous(version: u8, _body: &[u8]) -> Result<Self, String> {
+        Err(format!("NCSharedSettings has no version {}", version))
+    }
+}
+
+/// Writes the version byte and the body.
+pub(crate) fn to_bytes<T: NCVersioned>(value: &T) -> Result<Vec<u8>, bincode::Error> {
+    let mut bytes = vec![T::VERSION];
+    bincode::serialize_into(&mut bytes, value)?;
+    Ok(bytes)
+}
+
+/// Reads the version byte and decodes the body of the current, an older or a newer version.
+pub(crate) fn from_bytes<T: NCVersioned>(bytes: &[u8]) -> Result<T, String> {
+    match bytes.split_first() {
+        None | Some((0, _)) => Err("the versioned envelope is empty or has version 0".to_string()),
+        // The fields that have been added after this version are left at the end of the body
+        Some((&version, body)) if version >= T::VERSION => bincode::deserialize(body).map_err(|e| e.to_string()),
+        Some((&version, body)) => T::from_previous(version, body),
+    }
+}
+
+/// The value in the envelope as it's serialized inside a bincode message, used for the chunk info at the end of a cached frame.
+#[cfg(feature = "net")]
+pub(crate) fn serialize_versioned<T: NCVersioned>(value: &T) -> Result<Vec<u8>, bincode::Error> {
+    bincode::serialize(&Wrapped(value))
+}
+
+/// Serializes the value with serialize().
+#[cfg(feature = "net")]
+struct Wrapped<'a, T>(&'a T);
+
+#[cfg(feature = "net")]
+impl<T: NCVersioned> Serialize for Wrapped<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize(self.0, serializer)
+    }
+}
+
+/// Deserializes the value with deserialize().
+#[cfg(feature = "net")]
+struct Unwrapped<T>(T);
+
+#[cfg(feature = "net")]
+impl<'de, T: NCVersioned> Deserialize<'de> for Unwrapped<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize(deserializer).map(Unwrapped)
+    }
+}
+
+/// Serializes the value in the envelope, use it with `#[serde(with = "crate::nc_envelope")]`.
+pub(crate) fn serialize<T: NCVersioned, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        return value.serialize(serializer)
+    }
+
+    serializer.serialize_bytes(&to_bytes(value).map_err(serde::ser::Error::custom)?)
+}
+
+/// Deserializes the value from the envelope, use it with `#[serde(with = "crate::nc_envelope")]`.
+pub(crate) fn deserialize<'de, T: NCVersioned, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+    if deserializer.is_human_readable() {
+        return T::deserialize(deserializer)
+    }
+
+    deserializer.deserialize_byte_buf(EnvelopeVisitor(PhantomData))
+}
+
+/// Decodes the byte string of the envelope.
+struct EnvelopeVisitor<T>(PhantomData<T>);
+
+impl<'de, T: NCVersioned> Visitor<'de> for EnvelopeVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a versioned envelope")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<T, E> {
+        from_bytes(bytes).map_err(E::custom)
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<T, E> {
+        self.visit_bytes(&bytes)
+    }
+}
+
+/// The same for an optional value, use it with `#[serde(with = "crate::nc_envelope::option")]`.
+#[cfg(feature = "net")]
+pub(crate) mod option {
+    use super::*;
+
+    /// Serializes the optional value, the value itself is in the envelope.
+    pub(crate) fn serialize<T: NCVersioned, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+        value.as_ref().map(Wrapped).serialize(serializer)
+    }
+
+    /// Deserializes the optional value, the value itself is in the envelope.
+    pub(crate) fn deserialize<'de, T: NCVersioned, D: Deserializer<'de>>(deserializer: D) -> Result<Option<T>, D::Error> {
+        Ok(Option::<Unwrapped<T>>::deserialize(deserializer)?.map(|Unwrapped(value)| value))
+    }
+}
+
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::*;
+
+    /// The first version of a struct, as an old peer knows it.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct InfoV1 {
+        chunk_id: u64,
+        name: String,
+    }
+
+    impl NCVersioned for InfoV1 {
+        const VERSION: u8 = 1;
+
+        fn from_previous(version: u8, _body: &[u8]) -> Result<Self, String> {
+            Err(format!("no version {}", version))
+        }
+    }
+
+    /// The second version with a field at the end, as a new peer knows it.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct InfoV2 {
+        chunk_id: u64,
+        name: String,
+        retries: Option<u32>,
+    }
+
+    impl InfoV2 {
+        fn from_v1(info: InfoV1) -> Self {
+            InfoV2 { chunk_id: info.chunk_id, name: info.name, retries: None }
+        }
+    }
+
+    impl NCVersioned for InfoV2 {
+        const VERSION: u8 = 2;
+
+        fn from_previous(version: u8, body: &[u8]) -> Result<Self, String> {
+            match version {
+                1 => bincode::deserialize(body).map(InfoV2::from_v1).map_err(|e| e.to_string()),
+                _ => Err(format!("no version {}", version)),
+            }
+        }
+    }
+
+    /// A message with the struct inside, like NCJobStatus::Unfinished.
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Message<T: NCVersioned> {
+        Data(u32, #[serde(with = "crate::nc_envelope")] T),
+        Register(#[serde(with = "crate::nc_envelope::option")] Option<T>, bool),
+    }
+
+    /// `Message::Data(7, InfoV1 { chunk_id: 3, name: "a" })` from an old peer.
+    const V1_FIXTURE: [u8; 34] = [0, 0, 0, 0, 7, 0, 0, 0, 18, 0, 0, 0, 0, 0, 0, 0, 1, 3, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, b'a'];
+
+    #[test]
+    fn test_envelope_matrix() {
+        let v1 = InfoV1 { chunk_id: 3, name: "a".to_string() };
+        let v2 = InfoV2 { chunk_id: 3, name: "a".to_string(), retries: Some(5) };
+
+        // The layout of the first version doesn't change
+        let fixture = V1_FIXTURE.to_vec();
+        assert_eq!(bincode::serialize(&Message::Data(7, v1.clone())).unwrap(), fixture);
+
+        // v1 with v2 code: the new field gets its default
+        let message: Message<InfoV2> = bincode::deserialize(&fixture).unwrap();
+        assert_eq!(message, Message::Data(7, InfoV2 { retries: None, ..v2.clone() }));
+
+        // v2 with v1 code: the new field is ignored
+        let data = bincode::serialize(&Message::Data(7, v2.clone())).unwrap();
+        let message: Message<InfoV1> = bincode::deserialize(&data).unwrap();
+        assert_eq!(message, Message::Data(7, v1.clone()));
+
+        // The fields after the envelope are still found
+        let data = bincode::serialize(&Message::Register(Some(v2.clone()), true)).unwrap();
+        assert_eq!(bincode::deserialize::<Message<InfoV1>>(&data).unwrap(), Message::Register(Some(v1.clone()), true));
+        let data = bincode::serialize(&Message::<InfoV1>::Register(None, true)).unwrap();
+        assert_eq!(bincode::deserialize::<Message<InfoV2>>(&data).unwrap(), Message::Register(None, true));
+
+        // Unknown older versions and broken envelopes are errors
+        assert!(from_bytes::<InfoV2>(&[]).is_err());
+        assert!(from_bytes::<InfoV2>(&[0, 1, 2]).is_err());
+        assert!(from_bytes::<InfoV1>(&[1, 3]).is_err());
+
+        // JSON without envelope
+        let json = serde_json::to_string(&Message::Data(7, v1.clone())).unwrap();
+        assert_eq!(json, r#"{"Data":[7,{"chunk_id":3,"name":"a"}]}"#);
+        assert_eq!(serde_json::from_str::<Message<InfoV1>>(&json).unwrap(), Message::Data(7, v1));
+    }
+
+    #[test]
+    fn test_chunk_info_fixture() {
+        let chunk_info = NCChunkInfo { chunk_id: 3, deadline: Some(Duration::from_millis(1500)), attempt: 2, job_time_remaining: Some(Duration::from_secs(60)),
+            result_dictionary: Some(0x0102_0304), ..Default::default() };
+        let fixture = [47, 0, 0, 0, 0, 0, 0, 0, 4, 3, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 101, 205, 29, 0, 0, 0, 2, 0, 0, 0,
+            1, 60, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 4, 3, 2, 1];
+
+        assert_eq!(serialize_versioned(&chunk_info).unwrap(), fixture);
+
+        // As the end of a message
+        let message: Message<NCChunkInfo> = bincode::deserialize(&[&[0, 0, 0, 0, 7, 0, 0, 0][..], &fixture].concat()).unwrap();
+        assert_eq!(message, Message::Data(7, chunk_info.clone()));
+
+        // Version 1 from an older server, without the attempt
+        let fixture_v1 = [25, 0, 0, 0, 0, 0, 0, 0, 1, 3, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 101, 205, 29, 0, 0, 0];
+        let message: Message<NCChunkInfo> = bincode::deserialize(&[&[0, 0, 0, 0, 7, 0, 0, 0][..], &fixture_v1].concat()).unwrap();
+        assert_eq!(message, Message::Data(7, NCChunkInfo { attempt: 0, job_time_remaining: None, result_dictionary: None, ..chunk_info.clone() }));
+
+        // Version 2 without the remaining job time
+        let fixture_v2 = [29, 0, 0, 0, 0, 0, 0, 0, 2, 3, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 101, 205, 29, 0, 0, 0, 2, 0, 0, 0];
+        let message: Message<NCChunkInfo> = bincode::deserialize(&[&[0, 0, 0, 0, 7, 0, 0, 0][..], &fixture_v2].concat()).unwrap();
+        assert_eq!(message, Message::Data(7, NCChunkInfo { job_time_remaining: None, result_dictionary: None, ..chunk_info.clone() }));
+
+        // Version 3 without the result dictionary
+        let fixture_v3 = [42, 0, 0, 0, 0, 0, 0, 0, 3, 3, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 101, 205, 29, 0, 0, 0, 2, 0, 0, 0,
+            1, 60, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let message: Message<NCChunkInfo> = bincode::deserialize(&[&[0, 0, 0, 0, 7, 0, 0, 0][..], &fixture_v3].concat()).unwrap();
+        assert_eq!(message, Message::Data(7, NCChunkInfo { result_dictionary: None, ..chunk_info }));
+    }
+}
@@ -1,10 +1,29 @@
 //! This module contains the common error type for server and node.
 
 use std::{io, net, sync};
+use std::net::SocketAddr;
+use std::any::type_name;
+#[cfg(feature = "net")]
+use std::any::Any;
+use std::panic::Location;
+#[cfg(feature = "net")]
+use std::thread;
+use std::time::Duration;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
 
 use thiserror::Error;
+use serde::{Serialize, Deserialize};
+#[cfg(feature = "net")]
+use log::warn;
+#[cfg(feature = "net")]
+use rand::Rng;
 
 use crate::NodeID;
+use crate::nc_communicator::NCCodec;
+use crate::nc_config::ConfigDiff;
+#[cfg(feature = "net")]
+use crate::nc_message::NCRejectReason;
 
 /// This data structure contains all error codes for the server and the nodes.
 #[derive(Error, Debug)]
@@ -12,21 +31,97 @@ pub enum NCError {
     /// Parsing the IP address went wrong.
     #[error("IP address parse error: {0}")]
     IPAddrParse(#[from] net::AddrParseError),
+    /// The host name of the server could not be resolved, see [`NCServerAddr`](crate::NCServerAddr).
+    #[error("Could not resolve host name: '{0}'")]
+    DnsResolve(String),
+    /// No connection to the server could be opened. Contains the server and every address that has been tried with its error.
+    #[error("Could not connect to {0}: {}", format_attempts(.1))]
+    Connect(String, Vec<(SocketAddr, io::Error)>),
+    /// The proxy has refused the connection to the server (see proxy in the NCConfiguration).
+    /// Contains the proxy and its answer, for example the HTTP status line or the SOCKS5 reply.
+    #[error("Proxy handshake with {0} failed: {1}")]
+    Proxy(String, String),
+    /// The server could not register its mDNS service or a node could not find a server with mDNS, see the nc_mdns module (mdns feature).
+    #[error("mDNS error: {0}")]
+    Mdns(String),
+    /// More than one server matches the node, contains every candidate. Set job_id or the address of the server.
+    #[error("More than one server found with mDNS: {}", .0.join(", "))]
+    MdnsAmbiguous(Vec<String>),
+    /// The shared memory transport is not available or its directory can't be used, see transport in the NCConfiguration and the nc_shmem module (shmem feature).
+    #[error("Shared memory error: {0}")]
+    SharedMemory(String),
+    /// A NCServer trait method (user code) has panicked, contains the method and the panic message.
+    /// The panic is caught by the server, so that it can still serve the other nodes, see on_process_error in the NCConfiguration.
+    #[error("User code panicked in {0}")]
+    UserPanic(String),
     /// Common IO error, usually network related.
     #[error("IO error: {0}")]
     IOError(#[from] io::Error),
+    /// The other side has closed the connection in the middle of a message.
+    /// Contains the part of the message (length or data), the number of bytes read and the number of bytes expected.
+    #[error("Connection closed while reading the {0}, got {1} of {2} bytes")]
+    UnexpectedEof(&'static str, u64, u64),
+    /// The other side has closed the connection while a message was written (broken pipe, connection reset or aborted).
+    #[error("Connection closed by the other side: {0}")]
+    PeerDisconnected(io::Error),
     /// Data could not be serialized for sending over the network.
     #[error("Serialize bincode error: {0}")]
     Serialize(bincode::Error),
     /// Data coming from the network could not be deserialized.
     #[error("Deserialize bincode error: {0}")]
     Deserialize(bincode::Error),
+    /// The data from the network ends before a value of the expected type is complete, for example an empty buffer or an empty `Vec<u8>`
+    /// where the other side expects another type. Contains the expected type and the size of the data in bytes.
+    #[error("Could not deserialize {0}: the data ends after {1} bytes")]
+    DataTooShort(String, u64),
     /// The [`bincode`] crate has its own error.
     #[error("Bincode error: {0}")]
     Bincode(#[from] Box<bincode::ErrorKind>),
     /// Decompression error
     #[error("Decompression error")]
     Decompress(#[from] lz4_flex::block::DecompressError),
+    /// A received frame decompresses to more bytes than max_decompressed_bytes in the NCConfiguration allows, contains the limit.
+    /// The decompression stops at the limit, so a compression bomb can't exhaust the memory.
+    #[error("The decompressed data is bigger than {0} bytes")]
+    DecompressionLimit(u64),
+    /// The length prefix of a received frame is bigger than max_frame_bytes in the NCConfiguration allows, contains the length and the limit.
+    /// The frame is rejected before any memory is allocated for it.
+    #[error("The frame length {0} is bigger than {1} bytes")]
+    FrameTooLarge(u64, u64),
+    /// The message has been encoded with a codec (compression algorithm) that is not known.
+    #[error("Unknown codec: {0:?}")]
+    UnknownCodec(Option<u8>),
+    /// The user data in the message has another type than expected, see type_check in the NCConfiguration.
+    /// `got` is the type name if this program knows the type, otherwise its hash.
+    #[error("Type mismatch, expected: {expected}, got: {got}")]
+    TypeMismatch { expected: String, got: String },
+    /// The node and the server don't have a codec in common.
+    #[error("No common codec, server: {0:?}, node: {1:?}")]
+    NoCommonCodec(Vec<NCCodec>, Vec<NCCodec>),
+    /// The settings of the node don't match the settings of the server, contains every difference with the severity Error
+    /// (see [`NCConfiguration::diff()`](crate::NCConfiguration::diff)).
+    #[error("Settings of node and server don't match: {}", format_diffs(.0))]
+    ConfigMismatch(Vec<ConfigDiff>),
+    /// The server could not decrypt the message of the node (NCRejectReason::WrongKey), contains the message from the server.
+    #[error("Wrong encryption key: {0}")]
+    WrongKey(String),
+    /// The server could not decode the message of the node (NCRejectReason::VersionMismatch), contains the message from the server.
+    #[error("Version mismatch: {0}")]
+    VersionMismatch(String),
+    /// The server has too many connections (NCRejectReason::ServerBusy), contains the message from the server
+    /// and how long the node should wait before it tries again. See [`NCError::retry_after()`].
+    #[error("Server busy: {0}")]
+    ServerBusy(String, Option<Duration>),
+    /// The job is paused and the server doesn't register new nodes (NCRejectReason::Paused), contains the message from the server
+    /// and how long the node should wait before it tries again. See [`NCError::retry_after()`].
+    #[error("Job paused: {0}")]
+    Paused(String, Option<Duration>),
+    /// The node has been disabled by the admin (NCRejectReason::Disabled), contains the message from the server.
+    #[error("Node disabled: {0}")]
+    NodeDisabled(String),
+    /// The encryption key has the wrong length or invalid characters, the message contains the expected format but not the key.
+    #[error("Invalid encryption key: {0}")]
+    InvalidKey(String),
     /// Encrypt error
     #[error("Encrypt error")]
     Encrypt,
@@ -42,19 +137,666 @@ pub enum NCError {
     /// A different node id was expected. Expected first node id, found second node id.
     #[error("Node id mismatch error, expected: {0}, found: {1}")]
     NodeIDMismatch(NodeID, NodeID),
-    /// [`Mutex`](std::sync::Mutex) could not be locked or a thread did panic while holding the lock.
-    #[error("Mutex poisson error")]
-    MutexPoison,
+    /// A [`Mutex`](std::sync::Mutex) could not be locked because a thread did panic while holding the lock.
+    /// Contains the operation ("lock" for a lock with `?`), the source location of the call and the type of the locked data,
+    /// so the failing call can be found without the debug log.
+    #[error("Mutex poisoned in {context} at {location}, data: {data}")]
+    MutexPoison { context: &'static str, location: &'static Location<'static>, data: &'static str },
+    /// An internal channel or thread of the server or the node is gone, for example because the thread has panicked.
+    /// Contains the operation and the details, with the ids of the node and the chunk if there are any.
+    #[error("Internal error in {context}: {details}")]
+    Internal { context: &'static str, details: String },
     /// An error using the utility data structure [`Array2D`](crate::Array2D).
     #[error("Array2D dimension mismatch error, expected: {0:?}, got: {1:?}")]
     Array2DDimensionMismatch((u64, u64), (u64, u64)),
-    /// Custom user defined error. This needs to be replaced in the future with [`Box<dyn Error>`] or something similar.
+    /// The byte slab of an [`Array2D`](crate::Array2D) doesn't fit the type of its values, see the [`nc_compact`](crate::nc_compact) module.
+    #[error("Array2D byte slab doesn't match the element type: {0}")]
+    Array2DLayout(String),
+    /// The admin message was not signed with the correct admin key or it has been replayed.
+    #[error("Unauthorized admin message")]
+    Unauthorized,
+    /// The server doesn't run a job with this id (anymore), see [`NCMultiServerStarter`](crate::NCMultiServerStarter).
+    #[error("Unknown job: '{0}'")]
+    UnknownJob(String),
+    /// The job id can't be used for a NCMultiServerStarter: it's empty, longer than 255 bytes or has already been added.
+    #[error("Invalid job id: '{0}'")]
+    InvalidJobID(String),
+    /// An error from the user code that is sent to the other side (node -> server or server -> node), see [`NCJobError`].
+    #[error("Job error: {0}")]
+    Job(NCJobError),
+    /// A message of the text protocol could not be decoded or the text protocol can't be used with the configuration,
+    /// see the nc_text_protocol module (debug-protocol feature).
+    #[error("Text protocol error: {0}")]
+    TextProtocol(String),
+    /// The NCServer or NCNode trait method validate() has rejected the data.
+    #[error("Validation failed: {0}")]
+    ValidationFailed(String),
+    /// An offline archive could not be read or used, see the nc_offline module.
+    #[error("Offline archive error: {0}")]
+    Archive(String),
+    /// The sample_fraction in the NCConfiguration is not valid, or the checkpoint is from a sampled run and the server runs
+    /// without a sample or with another one (or the other way around), see the nc_sample module.
+    #[error("Sample error: {0}")]
+    Sample(String),
+    /// A signature of a result could not be created or checked, or the public key of the node is not known, see the nc_keys module (ed25519 feature).
+    #[error("Signature error: {0}")]
+    Signature(String),
+    /// A handle of the server has been used before start() or after the job is done (see [`NCBroadcastHandle`](crate::NCBroadcastHandle)),
+    /// or all the results of a [`NCResultStream`](crate::NCResultStream) have been received.
+    #[error("The server is not running")]
+    ServerNotRunning,
+    /// A chunk has been enqueued after the job has finished, see [`ChunkQueueHandle`](crate::ChunkQueueHandle).
+    #[error("The chunk queue is finished")]
+    ChunkQueueFinished,
+    /// strict_mode is set in the NCConfiguration and the server has seen a protocol anomaly, the job has been aborted.
+    #[error("Strict mode violation: {0}")]
+    StrictViolation(String),
+    /// A job definition file could not be read, see the nc_job_definition module.
+    #[error("Job definition error: {0}")]
+    JobDefinition(String),
+    /// Another node process uses the same node id file (node_id_file in the NCConfiguration), see the nc_node_id_file module.
+    #[error("The node id file is used by another process: {0}")]
+    NodeIdFileLocked(String),
+    /// The frame has been compressed with a zstd dictionary that this side doesn't have, see the nc_dictionary module.
+    /// The server answers with the NCServerMessage::UnknownDictionary message and the node sends the message again without a dictionary.
+    #[error("The zstd dictionary {0:#010x} is unknown")]
+    UnknownDictionary(u32),
+    /// The locality manifest (locality_manifest in the NCConfiguration) could not be read, see the nc_locality module.
+    #[error("Locality manifest error: {0}")]
+    LocalityManifest(String),
+    /// Custom user defined error. Use [`NCError::User`] for errors with a message.
     #[error("Custom user defined error: {0}")]
     Custom(u32),
+    /// An error from the user code, see [`NCError::custom()`], [`NCError::with_source()`] and the From implementations.
+    /// The message contains the whole chain of source errors.
+    #[error("{0}")]
+    User(NCUserError),
 }
 
-impl<T> From<sync::PoisonError<sync::MutexGuard<'_, T>>> for NCError {
+impl NCError {
+    /// Creates a user error with the given message.
+    pub fn custom<S: Into<String>>(message: S) -> Self {
+        NCError::User(NCUserError { message: Some(message.into()), error: None })
+    }
+
+    /// Creates a user error with the given message and the error that caused it.
+    pub fn with_source<S: Into<String>, E: Error + Send + Sync + 'static>(message: S, source: E) -> Self {
+        NCError::User(NCUserError { message: Some(message.into()), error: Some(Box::new(source)) })
+    }
+
+    /// Creates a NCError::MutexPoison error for the given operation and type of the locked data, at the location of the caller.
+    #[track_caller]
+    pub(crate) fn poisoned<T: ?Sized>(context: &'static str) -> Self {
+        NCError::MutexPoison { context, location: Location::caller(), data: type_name::<T>() }
+    }
+
+    /// Creates a NCError::Internal error for the given operation with the given details.
+    #[cfg(feature = "net")]
+    pub(crate) fn internal<S: Into<String>>(context: &'static str, details: S) -> Self {
+        NCError::Internal { context, details: details.into() }
+    }
+
+    /// Turns the NCServerMessage::Rejected message from the server into the error for its reason.
+    /// The codecs of the node are part of the NCError::NoCommonCodec error.
+    #[cfg(feature = "net")]
+    pub(crate) fn rejected(reason: NCRejectReason, message: String, retry_after: Option<Duration>, node_codecs: &[NCCodec]) -> Self {
+        match reason {
+            NCRejectReason::WrongKey => NCError::WrongKey(message),
+            NCRejectReason::VersionMismatch => NCError::VersionMismatch(message),
+            NCRejectReason::NoCommonCodec(server_codecs) => NCError::NoCommonCodec(server_codecs, node_codecs.to_vec()),
+            NCRejectReason::ConfigMismatch(diffs) => NCError::ConfigMismatch(diffs),
+            NCRejectReason::UnknownJob(job_id) => NCError::UnknownJob(job_id),
+            NCRejectReason::ServerBusy => NCError::ServerBusy(message, retry_after),
+            NCRejectReason::Paused => NCError::Paused(message, retry_after),
+            NCRejectReason::Disabled => NCError::NodeDisabled(message),
+            NCRejectReason::InvalidKey => NCError::InvalidKey(message),
+        }
+    }
+
+    /// Returns true if the server has rejected the node for a reason that goes away by waiting (NCError::ServerBusy and NCError::Paused).
+    /// The node waits for retry_after() (or delay_request_data in the NCConfiguration) and tries again while its retry counter is not zero.
+    pub fn is_retryable_rejection(&self) -> bool {
+        matches!(self, NCError::ServerBusy(_, _) | NCError::Paused(_, _))
+    }
+
+    /// Returns true if the server has rejected the node for a reason that doesn't go away by waiting: a wrong encryption key,
+    /// another version, no common codec, settings that don't match, an unknown job or a disabled node. The node exits right away.
+    pub fn is_fatal_rejection(&self) -> bool {
+        matches!(self, NCError::WrongKey(_) | NCError::VersionMismatch(_) | NCError::NoCommonCodec(_, _) | NCError::ConfigMismatch(_) |
+            NCError::UnknownJob(_) | NCError::NodeDisabled(_))
+    }
+
+    /// How long the node should wait before it tries again, sent by the server with a retryable rejection (see is_retryable_rejection()).
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            NCError::ServerBusy(_, retry_after) | NCError::Paused(_, retry_after) => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Returns true if the error is transient: the same message may work if it's sent again right away.
+    /// These are interrupted system calls, timeouts and sockets that would block (IOError) and connection attempts that all timed out (Connect).
+    /// All other errors are fatal for the connection: the server is not reachable (connection refused), the other side has disconnected,
+    /// the message could not be decoded or doesn't follow the protocol, or the error comes from the user code.
+    ///
+    /// ```
+    /// use std::io;
+    /// use node_crunch::NCError;
+    ///
+    /// assert!(NCError::IOError(io::Error::from(io::ErrorKind::TimedOut)).is_transient());
+    /// assert!(!NCError::ServerMsgMismatch.is_transient());
+    /// ```
+    pub fn is_transient(&self) -> bool {
+        match self {
+            NCError::IOError(e) => is_transient_kind(e.kind()),
+            NCError::Connect(_, attempts) => !attempts.is_empty() && attempts.iter().all(|(_, e)| is_transient_kind(e.kind())),
+            _ => false,
+        }
+    }
+
+    /// Returns true if the other side has sent something that doesn't follow the protocol: a message that can't be decoded
+    /// (broken frame, wrong codec or type) or that doesn't fit. These errors are the protocol anomalies of strict_mode in the NCConfiguration.
+    #[cfg(feature = "net")]
+    pub(crate) fn is_protocol_violation(&self) -> bool {
+        matches!(self, NCError::NodeMsgMismatch | NCError::ServerMsgMismatch | NCError::Deserialize(_) | NCError::Bincode(_) |
+            NCError::DataTooShort(_, _) | NCError::Decompress(_) | NCError::DecompressionLimit(_) | NCError::FrameTooLarge(_, _) | NCError::Decrypt | NCError::UnknownCodec(_) |
+            NCError::TypeMismatch { .. } | NCError::TextProtocol(_))
+    }
+
+    /// Turns an IO error from writing a message into NCError::PeerDisconnected if the other side has closed the connection,
+    /// all other errors become NCError::IOError.
+    /// Since Rust ignores the SIGPIPE signal a closed connection shows up as such an error and doesn't kill the process.
+    #[cfg(feature = "net")]
+    pub(crate) fn from_write_error(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => NCError::PeerDisconnected(e),
+            _ => NCError::IOError(e),
+        }
+    }
+}
+
+/// How often a transient error is retried in place, see [`retry_transient()`].
+#[cfg(feature = "net")]
+pub(crate) const TRANSIENT_RETRIES: u32 = 3;
+
+/// The IO errors that are transient, see [`NCError::is_transient()`].
+fn is_transient_kind(kind: io::ErrorKind) -> bool {
+    matches!(kind, io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// The pause before the given retry (1, 2, ...) of a transient error: 10 - 50 ms times the retry, so that nodes that had the same hiccup
+/// don't all try again at the same time.
+#[cfg(feature = "net")]
+pub(crate) fn transient_delay(retry: u32) -> Duration {
+    Duration::from_millis(rand::thread_rng().gen_range(10, 50) * retry as u64)
+}
+
+/// Calls f() and retries it in place at most TRANSIENT_RETRIES times with a short pause (see transient_delay()) as long as it returns a transient error.
+/// If resend is false f() is only called again if the connection could not be opened (NCError::Connect), since otherwise the other side
+/// may have got the message already. Fatal errors and the last transient error are returned.
+#[cfg(feature = "net")]
+pub(crate) fn retry_transient<R, F: FnMut() -> Result<R, NCError>>(what: &str, resend: bool, mut f: F) -> Result<R, NCError> {
+    let mut retry = 0;
+
+    loop {
+        match f() {
+            Err(e) if retry < TRANSIENT_RETRIES && e.is_transient() && (resend || matches!(e, NCError::Connect(_, _))) => {
+                retry += 1;
+                warn!("Transient error in {}: {}, retry {} of {}", what, e, retry, TRANSIENT_RETRIES);
+                thread::sleep(transient_delay(retry));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Lists the addresses and errors of all the connection attempts in one line.
+fn format_attempts(attempts: &[(SocketAddr, io::Error)]) -> String {
+    if attempts.is_empty() {
+        return "no address to try".to_string()
+    }
+
+    attempts.iter().map(|(address, e)| format!("{} ({})", address, e)).collect::<Vec<_>>().join(", ")
+}
+
+/// Formats the differences of the NCError::ConfigMismatch error.
+fn format_diffs(diffs: &[ConfigDiff]) -> String {
+    diffs.iter().map(|diff| format!("{}: {} (server) != {} (node)", diff.key, diff.local, diff.remote)).collect::<Vec<_>>().join(", ")
+}
+
+/// An error from the user code: a message and / or the error that caused it.
+/// It's shown as one line with all the source errors, separated by ": ".
+#[derive(Debug)]
+pub struct NCUserError {
+    /// Description of the error.
+    message: Option<String>,
+    /// The error that caused this error, with its own chain of source errors.
+    error: Option<Box<dyn Error + Send + Sync>>,
+}
+
+impl Display for NCUserError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut separator = "";
+
+        if let Some(message) = &self.message {
+            write!(f, "{}", message)?;
+            separator = ": ";
+        }
+
+        let mut source = self.error.as_deref().map(|error| error as &(dyn Error + 'static));
+
+        while let Some(error) = source {
+            write!(f, "{}{}", separator, error)?;
+            separator = ": ";
+            source = error.source();
+        }
+
+        Ok(())
+    }
+}
+
+impl Error for NCUserError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        let error = self.error.as_deref().map(|error| error as &(dyn Error + 'static));
+
+        match self.message {
+            Some(_) => error,
+            None => error.and_then(|error| error.source()),
+        }
+    }
+}
+
+impl From<Box<dyn Error + Send + Sync>> for NCError {
+    fn from(error: Box<dyn Error + Send + Sync>) -> NCError {
+        NCError::User(NCUserError { message: None, error: Some(error) })
+    }
+}
+
+/// The error can't be kept since NCError must be Sync, but its message with the whole chain of source errors is kept.
+impl From<Box<dyn Error + Send>> for NCError {
+    fn from(error: Box<dyn Error + Send>) -> NCError {
+        let mut chain = vec![error.to_string()];
+        let mut source = error.source();
+
+        while let Some(error) = source {
+            chain.push(error.to_string());
+            source = error.source();
+        }
+
+        NCError::custom(chain.join(": "))
+    }
+}
+
+#[cfg(feature = "anyhow")]
+impl From<anyhow::Error> for NCError {
+    fn from(error: anyhow::Error) -> NCError {
+        NCError::from(Box::<dyn Error + Send + Sync>::from(error))
+    }
+}
+
+/// A user defined error that can be sent over the network.
+/// If the NCNode trait method process_data_from_server() returns `Err(NCError::Job(...))` the node sends it to the server
+/// and the NCServer trait method process_node_error() is called with it.
+/// If the NCServer trait methods assign_chunk() or process_data_from_node() return `Err(NCError::Job(...))` the server sends it
+/// to the node and the NCNode trait method process_server_error() is called with it.
+/// The error codes from `u32::MAX` downwards are reserved for node_crunch itself, see [`NCJobError::DEADLINE_EXCEEDED`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NCJobError {
+    /// User defined error code.
+    pub code: u32,
+    /// Human readable error message.
+    pub message: String,
+    /// If false the chunk should not be processed again and is marked as failed, see [`ChunkList::chunk_failed()`](crate::ChunkList::chunk_failed).
+    pub retryable: bool,
+}
+
+impl NCJobError {
+    /// The node didn't finish processing the data within the deadline for the chunk.
+    pub const DEADLINE_EXCEEDED: u32 = u32::MAX;
+    /// The server could not process the result of the node, see [`OnProcessError`](crate::OnProcessError).
+    pub const RESULT_REJECTED: u32 = u32::MAX - 1;
+    /// The NCNode trait method process_data_from_server() returned a [`NCError::User`] error.
+    pub const USER_ERROR: u32 = u32::MAX - 2;
+    /// The data or the result didn't pass the NCServer or NCNode trait method validate().
+    pub const VALIDATION_FAILED: u32 = u32::MAX - 3;
+    /// The result is not signed, the signature is wrong or the public key of the node is not known, see the nc_keys module.
+    pub const SIGNATURE_INVALID: u32 = u32::MAX - 4;
+    /// The standby server could not apply a replication update or can't be promoted yet, see the nc_replication module.
+    /// If retryable is true the server sends the whole state with the next update.
+    pub const REPLICATION: u32 = u32::MAX - 5;
+    /// The NCServer trait method assign_chunk_with_context() has panicked, the node should ask for data again.
+    pub const USER_PANIC: u32 = u32::MAX - 6;
+    /// The external program of a SubprocessNode could not be started, has failed or has written an invalid result,
+    /// see the nc_subprocess module.
+    pub const SUBPROCESS_FAILED: u32 = u32::MAX - 7;
+    /// The external program of a SubprocessNode has been killed after its timeout, see the nc_subprocess module.
+    pub const SUBPROCESS_TIMEOUT: u32 = u32::MAX - 8;
+    /// The external program of a SubprocessNode has exceeded its memory limit, its CPU time limit or max_output_bytes,
+    /// see the nc_subprocess module. Such an error is not retryable.
+    pub const SUBPROCESS_LIMIT: u32 = u32::MAX - 9;
+
+    /// Creates a new job error with the given code, message and retry flag.
+    pub fn new<S: Into<String>>(code: u32, message: S, retryable: bool) -> Self {
+        NCJobError { code, message: message.into(), retryable }
+    }
+
+    /// The error that the node sends to the server if the deadline for the chunk has been exceeded.
+    /// The chunk can be given to another node immediately.
+    pub fn deadline_exceeded() -> Self {
+        Self::new(Self::DEADLINE_EXCEEDED, "deadline exceeded", true)
+    }
+
+    /// The error that the node sends to the server if the user code returned a [`NCError::User`] error.
+    /// The message contains the whole chain of source errors, the chunk can be given to a node again.
+    pub fn user_error(error: &NCError) -> Self {
+        Self::new(Self::USER_ERROR, error.to_string(), true)
+    }
+
+    /// The data (on the node) or the result (on the server) has been rejected by the trait method validate().
+    /// If retryable is true the chunk will be given to a node again.
+    pub fn validation_failed(error: &NCError, retryable: bool) -> Self {
+        Self::new(Self::VALIDATION_FAILED, error.to_string(), retryable)
+    }
+
+    /// The server has rejected the signature of the result, the chunk will be given to a node again.
+    pub fn signature_invalid(error: &NCError) -> Self {
+        Self::new(Self::SIGNATURE_INVALID, error.to_string(), true)
+    }
+
+    /// The error that the server sends to the node if the user code has panicked while the data for the node was prepared.
+    pub fn user_panic(error: &NCError) -> Self {
+        Self::new(Self::USER_PANIC, error.to_string(), true)
+    }
+
+    /// The error that the server sends to the node if its result could not be processed.
+    /// If retryable is true the chunk will be given to a node again.
+    pub fn result_rejected<S: Into<String>>(message: S, retryable: bool) -> Self {
+        Self::new(Self::RESULT_REJECTED, message, retryable)
+    }
+}
+
+impl Display for NCJobError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "code: {}, message: '{}', retryable: {}", self.code, self.message, self.retryable)
+    }
+}
+
+impl From<NCJobError> for NCError {
+    fn from(job_error: NCJobError) -> NCError {
+        NCError::Job(job_error)
+    }
+}
+
+impl<T: ?Sized> From<sync::PoisonError<sync::MutexGuard<'_, T>>> for NCError {
+    /// The location is the `?` that has converted the error.
+    #[track_caller]
     fn from(_: sync::PoisonError<sync::MutexGuard<'_, T>>) -> NCError {
-        NCError::MutexPoison
+        NCError::poisoned::<T>("lock")
+    }
+}
+
+/// Returns the message of a thread that has panicked (the payload from JoinHandle::join()).
+#[cfg(feature = "net")]
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "net")]
+    use crate::nc_config::NCConfiguration;
+    #[cfg(feature = "net")]
+    use crate::nc_communicator::NCCommunicator;
+
+    /// An error of the user code with an optional cause.
+    #[derive(Debug)]
+    struct ReadError {
+        file: &'static str,
+        cause: Option<io::Error>,
+    }
+
+    impl Display for ReadError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "could not read {}", self.file)
+        }
+    }
+
+    impl Error for ReadError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            self.cause.as_ref().map(|cause| cause as &(dyn Error + 'static))
+        }
+    }
+
+    fn read_error() -> ReadError {
+        ReadError { file: "input.dat", cause: Some(io::Error::new(io::ErrorKind::NotFound, "no such file")) }
+    }
+
+    fn load_boxed() -> Result<(), Box<dyn Error + Send + Sync>> {
+        Err(Box::new(read_error()))
+    }
+
+    fn load_boxed_send() -> Result<(), Box<dyn Error + Send>> {
+        Err(Box::new(read_error()))
+    }
+
+    fn callback() -> Result<(), NCError> {
+        load_boxed()?;
+        Ok(())
+    }
+
+    fn callback_send() -> Result<(), NCError> {
+        load_boxed_send()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_error_chain() {
+        assert_eq!(NCError::custom("chunk is broken").to_string(), "chunk is broken");
+
+        let error = NCError::with_source("chunk 3 failed", read_error());
+        assert_eq!(error.to_string(), "chunk 3 failed: could not read input.dat: no such file");
+
+        match &error {
+            NCError::User(user_error) => assert_eq!(user_error.source().unwrap().to_string(), "could not read input.dat"),
+            _ => panic!("Expected a user error"),
+        }
+
+        let error = callback().unwrap_err();
+        assert_eq!(error.to_string(), "could not read input.dat: no such file");
+
+        match &error {
+            NCError::User(user_error) => assert_eq!(user_error.source().unwrap().to_string(), "no such file"),
+            _ => panic!("Expected a user error"),
+        }
+
+        assert_eq!(callback_send().unwrap_err().to_string(), "could not read input.dat: no such file");
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_user_error_on_the_wire() {
+        let mut nc_communicator = NCCommunicator::new(&NCConfiguration::default()).unwrap();
+        let job_error = NCJobError::user_error(&NCError::with_source("chunk 3 failed", read_error()));
+
+        let data = nc_communicator.nc_encode_data(&job_error).unwrap();
+        let job_error: NCJobError = nc_communicator.nc_decode_data(&data).unwrap();
+
+        assert_eq!(job_error, NCJobError::new(NCJobError::USER_ERROR, "chunk 3 failed: could not read input.dat: no such file", true));
+    }
+
+    #[cfg(feature = "anyhow")]
+    #[test]
+    fn test_anyhow() {
+        use anyhow::Context;
+
+        fn callback() -> Result<(), NCError> {
+            Err(read_error()).context("chunk 3 failed")?;
+            Ok(())
+        }
+
+        assert_eq!(callback().unwrap_err().to_string(), "chunk 3 failed: could not read input.dat: no such file");
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_mutex_poison() {
+        use std::sync::{Arc, Mutex};
+
+        const LOCK_LINE: u32 = line!() + 2;
+        fn lock(mutex: &Mutex<Vec<u32>>) -> Result<usize, NCError> {
+            Ok(mutex.lock()?.len())
+        }
+
+        let mutex = Arc::new(Mutex::new(vec![1]));
+        let poisoned = mutex.clone();
+        let _ = thread::spawn(move || {
+            let _guard = poisoned.lock().unwrap();
+            panic!("poison the mutex");
+        }).join();
+
+        // The `?` in lock() is the source location
+        match lock(&mutex).unwrap_err() {
+            NCError::MutexPoison { context, location, data } => {
+                assert_eq!(context, "lock");
+                assert_eq!(location.file(), file!());
+                assert_eq!(location.line(), LOCK_LINE);
+                assert_eq!(data, "alloc::vec::Vec<u32>");
+            }
+            e => panic!("Expected a MutexPoison error, got: {}", e),
+        }
+
+        let error = lock(&mutex).unwrap_err().to_string();
+        assert!(error.starts_with("Mutex poisoned in lock at src/nc_error.rs:") && error.ends_with(", data: alloc::vec::Vec<u32>"), "{}", error);
+
+        let error = NCError::internal("NodeProcess::finish_upload", "upload thread has stopped, chunk: 4");
+        assert_eq!(error.to_string(), "Internal error in NodeProcess::finish_upload: upload thread has stopped, chunk: 4");
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<NCError>();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_panic_message() {
+        let payload = std::thread::spawn(|| panic!("result thread broke")).join().unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "result thread broke");
+
+        let payload = std::thread::spawn(|| panic!("chunk {} broke", 4)).join().unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "chunk 4 broke");
+
+        let payload = std::thread::spawn(|| std::panic::panic_any(4)).join().unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "unknown panic payload");
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_rejected() {
+        use crate::nc_config::{ConfigDiff, ConfigDiffSeverity};
+
+        let diff = ConfigDiff { key: "encrypt".to_string(), local: "true".to_string(), remote: "false".to_string(), severity: ConfigDiffSeverity::Error };
+        let retry_after = Some(Duration::from_secs(5));
+        let rejected = |reason| NCError::rejected(reason, "message".to_string(), retry_after, &[NCCodec::None]);
+
+        let fatal = vec![
+            (rejected(NCRejectReason::WrongKey), "Wrong encryption key: message"),
+            (rejected(NCRejectReason::VersionMismatch), "Version mismatch: message"),
+            (rejected(NCRejectReason::NoCommonCodec(vec![NCCodec::Lz4])), "No common codec, server: [Lz4], node: [None]"),
+            (rejected(NCRejectReason::ConfigMismatch(vec![diff])), "Settings of node and server don't match: encrypt: true (server) != false (node)"),
+            (rejected(NCRejectReason::UnknownJob("a".to_string())), "Unknown job: 'a'"),
+            (rejected(NCRejectReason::Disabled), "Node disabled: message"),
+        ];
+
+        for (e, message) in &fatal {
+            assert!(e.is_fatal_rejection() && !e.is_retryable_rejection() && !e.is_transient(), "{}", e);
+            assert_eq!(e.retry_after(), None);
+            assert_eq!(&e.to_string(), message);
+        }
+
+        let retryable = vec![
+            (rejected(NCRejectReason::ServerBusy), "Server busy: message"),
+            (rejected(NCRejectReason::Paused), "Job paused: message"),
+        ];
+
+        for (e, message) in &retryable {
+            assert!(e.is_retryable_rejection() && !e.is_fatal_rejection(), "{}", e);
+            assert_eq!(e.retry_after(), retry_after);
+            assert_eq!(&e.to_string(), message);
+        }
+
+        // Only an answer to the admin client, the node is not affected
+        let invalid_key = rejected(NCRejectReason::InvalidKey);
+        assert!(!invalid_key.is_fatal_rejection() && !invalid_key.is_retryable_rejection());
+        assert_eq!(invalid_key.to_string(), "Invalid encryption key: message");
+
+        assert!(NCRejectReason::ServerBusy.is_retryable() && NCRejectReason::Paused.is_retryable());
+        assert!(!NCRejectReason::WrongKey.is_retryable());
+        assert!(!NCError::Decrypt.is_fatal_rejection() && !NCError::Decrypt.is_retryable_rejection());
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_is_transient() {
+        use std::cell::Cell;
+
+        let io_error = |kind| NCError::IOError(io::Error::from(kind));
+        let address: SocketAddr = "127.0.0.1:2020".parse().unwrap();
+        let connect = |kinds: &[io::ErrorKind]| NCError::Connect("localhost".to_string(), kinds.iter().map(|kind| (address, io::Error::from(*kind))).collect());
+
+        assert!(io_error(io::ErrorKind::Interrupted).is_transient());
+        assert!(io_error(io::ErrorKind::WouldBlock).is_transient());
+        assert!(io_error(io::ErrorKind::TimedOut).is_transient());
+        assert!(connect(&[io::ErrorKind::TimedOut, io::ErrorKind::TimedOut]).is_transient());
+
+        let fatal = vec![
+            io_error(io::ErrorKind::ConnectionRefused),
+            io_error(io::ErrorKind::InvalidData),
+            connect(&[io::ErrorKind::TimedOut, io::ErrorKind::ConnectionRefused]),
+            connect(&[]),
+            NCError::DnsResolve("localhost".to_string()),
+            NCError::Proxy("socks5://127.0.0.1:1080".to_string(), "connection refused (reply 5)".to_string()),
+            NCError::UnexpectedEof("data", 3, 8),
+            NCError::PeerDisconnected(io::Error::from(io::ErrorKind::ConnectionReset)),
+            NCError::Deserialize(Box::new(bincode::ErrorKind::SizeLimit)),
+            NCError::DataTooShort("u32".to_string(), 0),
+            NCError::UnknownCodec(Some(9)),
+            NCError::Decrypt,
+            NCError::ServerMsgMismatch,
+            NCError::NodeMsgMismatch,
+            NCError::poisoned::<u64>("test"),
+            NCError::internal("test", "channel closed"),
+            NCError::Unauthorized,
+            NCError::UnknownJob("a".to_string()),
+            NCError::Job(NCJobError::new(7, "broken", true)),
+            NCError::TextProtocol("not json".to_string()),
+            NCError::custom("user"),
+        ];
+        assert!(fatal.iter().all(|e| !e.is_transient()));
+
+        // Transient errors are retried a few times, fatal ones and messages that may have arrived are not
+        let calls = Cell::new(0);
+        let result: Result<(), NCError> = retry_transient("test", true, || { calls.set(calls.get() + 1); Err(io_error(io::ErrorKind::TimedOut)) });
+        assert!(result.is_err());
+        assert_eq!(calls.get(), TRANSIENT_RETRIES + 1);
+
+        calls.set(0);
+        assert!(retry_transient("test", true, || { calls.set(calls.get() + 1); if calls.get() < 3 { Err(io_error(io::ErrorKind::Interrupted)) } else { Ok(()) } }).is_ok());
+        assert_eq!(calls.get(), 3);
+
+        calls.set(0);
+        let _ = retry_transient::<(), _>("test", false, || { calls.set(calls.get() + 1); Err(io_error(io::ErrorKind::TimedOut)) });
+        let _ = retry_transient::<(), _>("test", false, || { calls.set(calls.get() + 1); Err(connect(&[io::ErrorKind::TimedOut])) });
+        let _ = retry_transient::<(), _>("test", true, || { calls.set(calls.get() + 1); Err(NCError::ServerMsgMismatch) });
+        assert_eq!(calls.get(), 1 + TRANSIENT_RETRIES + 1 + 1);
     }
 }
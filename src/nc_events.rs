@@ -0,0 +1,382 @@
+//! This module contains the event log of the server (event_log in the NCConfiguration).
+//! The server appends every event of the job to the file, one JSON object per line (NDJSON), so the run can be analyzed
+//! afterwards without parsing the log messages: when a node has registered or has gone offline, which chunk has been sent to which node,
+//! how long it took, which chunks have expired or have been put on the dead-letter list, the broadcasts and why the job has been aborted.
+//!
+//! Every line has the same fields: the version of the schema (`v`, see EVENT_LOG_VERSION), the sequence number of the event in this run
+//! (`seq`, starts with 0), the time in milliseconds since the UNIX epoch (`time_ms`), the type of the event (`event`) and the fields of
+//! the event, see [`NCEvent`]:
+//!
+//! ```text
+//! {"v":1,"seq":3,"time_ms":1760434800123,"event":"chunk_completed","node_id":2,"chunk_id":7,"duration_ms":1250}
+//! ```
+//!
+//! The first event of a run is job_started and the last one is job_finished. The file is opened in append mode, so a job that has been
+//! resumed from a checkpoint has all of its runs in one file, each one starts with seq 0 again.
+//! New fields and new events may be added to the schema without a new version, the reader ignores fields that it doesn't know
+//! and reads events that it doesn't know as [`NCEvent::Unknown`].
+//!
+//! The events are written by a separate thread, so a slow disk doesn't hold up the server. It buffers at most EVENT_BUFFER events,
+//! if it can't keep up the server drops the newest events and the writer notes the number of dropped events (events_dropped).
+//! The file is flushed every event_log_flush_ms milliseconds and at the end of the job.
+//! Only [`NCServerStarter::start()`](crate::NCServerStarter::start) writes the event log, the jobs of a NCMultiServerStarter don't.
+//!
+//! Use [`read()`] to read the events back.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, Receiver, RecvTimeoutError, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info, error};
+use serde::{Serialize, Deserialize};
+
+use crate::nc_config::NCConfiguration;
+use crate::nc_error::NCError;
+use crate::nc_job_summary::NCJobEndReason;
+use crate::nc_node_info::NodeID;
+use crate::nc_server::NCProgressEvent;
+use crate::array2d::ChunkID;
+
+/// The version of the schema, the `v` field of every line.
+pub const EVENT_LOG_VERSION: u32 = 1;
+
+/// The writer thread buffers at most this many events, see the module documentation.
+const EVENT_BUFFER: usize = 4096;
+
+/// One line of the event log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NCEventRecord {
+    /// The version of the schema, see EVENT_LOG_VERSION.
+    pub v: u32,
+    /// The sequence number of the event in this run, starts with 0 at job_started.
+    pub seq: u64,
+    /// The time of the event in milliseconds since the UNIX epoch.
+    pub time_ms: u64,
+    /// The event itself.
+    #[serde(flatten)]
+    pub event: NCEvent,
+}
+
+/// The events in the event log, the name of the event is the `event` field of the line.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum NCEvent {
+    /// The server is listening on this address, this is the first event of a run.
+    JobStarted { address: String },
+    /// A new node has registered with the server (or is back after the restart of the server).
+    NodeRegistered { node_id: NodeID },
+    /// The node has missed its heartbeat or has been disabled, its chunks are given to other nodes.
+    NodeDisconnected { node_id: NodeID },
+    /// The chunk has been sent to the node.
+    ChunkAssigned { node_id: NodeID, chunk_id: ChunkID },
+    /// The node has sent the result for its chunk (or an empty / skip message), the time since the chunk has been sent.
+    ChunkCompleted { node_id: NodeID, chunk_id: ChunkID, duration_ms: u64 },
+    /// The node could not process its chunk.
+    ChunkFailed { node_id: NodeID },
+    /// The node has missed its heartbeat while it was holding the chunk.
+    ChunkExpired { node_id: NodeID, chunk_id: ChunkID },
+    /// The node has dropped the revoked chunk or its result has been dropped.
+    ChunkRevoked { node_id: NodeID, chunk_id: ChunkID },
+    /// The chunk has run out of attempts and is on the dead-letter list now.
+    ChunkDeadLettered { chunk_id: ChunkID, attempts: u32, last_error: String },
+    /// A custom message has been broadcast to this group of nodes ([`None`] = all nodes).
+    Broadcast { group: Option<String>, nodes: usize },
+    /// The job has been aborted for this reason.
+    JobAborted { reason: String },
+    /// The server could not keep up and has dropped this many events before this one.
+    EventsDropped { count: u64 },
+    /// The job has ended for this reason, see [`NCJobEndReason`]. This is the last event of a run.
+    JobFinished { reason: String },
+    /// An event from a newer version of node_crunch that this version doesn't know.
+    #[serde(other)]
+    Unknown,
+}
+
+impl NCEvent {
+    /// The event for the given progress event, [`None`] if it's not part of the event log.
+    fn from_progress(event: &NCProgressEvent) -> Option<Self> {
+        let event = match event {
+            NCProgressEvent::Listening(address) => NCEvent::JobStarted { address: address.to_string() },
+            NCProgressEvent::NodeRegistered(node_id) => NCEvent::NodeRegistered { node_id: *node_id },
+            NCProgressEvent::NodeOffline(node_id) => NCEvent::NodeDisconnected { node_id: *node_id },
+            NCProgressEvent::ChunkSent(node_id, chunk_id) => NCEvent::ChunkAssigned { node_id: *node_id, chunk_id: *chunk_id },
+            NCProgressEvent::ChunkDone(node_id, chunk_id, chunk_time) =>
+                NCEvent::ChunkCompleted { node_id: *node_id, chunk_id: *chunk_id, duration_ms: chunk_time.as_millis() as u64 },
+            NCProgressEvent::ChunkFailed(node_id) => NCEvent::ChunkFailed { node_id: *node_id },
+            NCProgressEvent::ChunkExpired(node_id, chunk_id) => NCEvent::ChunkExpired { node_id: *node_id, chunk_id: *chunk_id },
+            NCProgressEvent::ChunkRevoked(node_id, chunk_id) => NCEvent::ChunkRevoked { node_id: *node_id, chunk_id: *chunk_id },
+            NCProgressEvent::ChunkDead(chunk_id, attempts, last_error) =>
+                NCEvent::ChunkDeadLettered { chunk_id: *chunk_id, attempts: *attempts, last_error: last_error.clone() },
+            NCProgressEvent::Broadcast(group, nodes) => NCEvent::Broadcast { group: group.clone(), nodes: *nodes },
+            NCProgressEvent::JobAborted(end_reason) => NCEvent::JobAborted { reason: end_reason.to_string() },
+            // job_finished is written by NCEventLog::finish() with the reason
+            NCProgressEvent::JobDone | NCProgressEvent::NodeTags(_, _) | NCProgressEvent::JobProgress(_, _) |
+            NCProgressEvent::TaskFailed(_, _) | NCProgressEvent::ArtifactStored(_, _, _) | NCProgressEvent::ClockSkew(_, _) => return None,
+        };
+
+        Some(event)
+    }
+}
+
+/// The current time in milliseconds since the UNIX epoch.
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis() as u64).unwrap_or(0)
+}
+
+/// Writes the events of the server to the event log, see the module documentation.
+pub(crate) struct NCEventLog {
+    /// The time stamp and the event for the writer thread.
+    sender: SyncSender<(u64, NCEvent)>,
+    /// Number of events that have been dropped because the buffer was full, the writer thread resets it.
+    dropped: Arc<AtomicU64>,
+    /// The writer thread, [`None`] after finish().
+    writer_thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl NCEventLog {
+    /// Opens the event log from the NCConfiguration and starts the writer thread, [`None`] if event_log is not set.
+    pub(crate) fn open(config: &NCConfiguration) -> Result<Option<Self>, NCError> {
+        debug!("NCEventLog::open()");
+
+        let path = match &config.event_log {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        info!("Write the events of the job to: {}", path.display());
+
+        let (sender, receiver) = mpsc::sync_channel(EVENT_BUFFER);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let flush_interval = Duration::from_millis(config.event_log_flush_ms);
+        let writer_dropped = dropped.clone();
+        let writer_thread = thread::spawn(move || write_events(BufWriter::new(file), receiver, &writer_dropped, flush_interval));
+
+        Ok(Some(NCEventLog { sender, dropped, writer_thread: Mutex::new(Some(writer_thread)) }))
+    }
+
+    /// Adds the given progress event to the event log if it's part of it. Never blocks, if the buffer is full the event is dropped.
+    pub(crate) fn record(&self, event: &NCProgressEvent) {
+        if let Some(event) = NCEvent::from_progress(event) {
+            if let Err(TrySendError::Full(_)) = self.sender.try_send((now_ms(), event)) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Writes the job_finished event with the given reason, waits until all events have been written and flushes the file.
+    pub(crate) fn finish(&self, end_reason: &NCJobEndReason) {
+        debug!("NCEventLog::finish()");
+
+        let writer_thread = match self.writer_thread.lock() {
+            Ok(mut writer_thread) => writer_thread.take(),
+            Err(_) => None,
+        };
+
+        if let Some(writer_thread) = writer_thread {
+            // The last event waits for space in the buffer, the writer thread exits after it
+            let _ = self.sender.send((now_ms(), NCEvent::JobFinished { reason: end_reason.to_string() }));
+
+            if writer_thread.join().is_err() {
+                error!("The writer thread of the event log has panicked");
+            }
+        }
+    }
+}
+
+/// The writer thread of the event log: writes the events until job_finished and flushes the file every flush_interval.
+fn write_events(mut writer: BufWriter<File>, receiver: Receiver<(u64, NCEvent)>, dropped: &AtomicU64, flush_interval: Duration) {
+    debug!("nc_events::write_events()");
+
+    let mut seq = 0;
+    let mut last_flush = Instant::now();
+    let mut failed = false;
+
+    loop {
+        let (time_ms, event) = match receiver.recv_timeout(flush_interval.saturating_sub(last_flush.elapsed())) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => {
+                failed |= report_error(writer.flush(), failed);
+                last_flush = Instant::now();
+                continue
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        let count = dropped.swap(0, Ordering::Relaxed);
+        let finished = matches!(event, NCEvent::JobFinished { .. });
+        let dropped_event = (count > 0).then_some(NCEvent::EventsDropped { count });
+
+        for event in dropped_event.into_iter().chain(Some(event)) {
+            let record = NCEventRecord { v: EVENT_LOG_VERSION, seq, time_ms, event };
+            seq += 1;
+            failed |= report_error(write_record(&mut writer, &record), failed);
+        }
+
+        if finished {
+            break
+        }
+
+        if last_flush.elapsed() >= flush_interval {
+            failed |= report_error(writer.flush(), failed);
+            last_flush = Instant::now();
+        }
+    }
+
+    report_error(writer.flush(), failed);
+}
+
+/// Writes one line to the event log.
+fn write_record(writer: &mut BufWriter<File>, record: &NCEventRecord) -> std::io::Result<()> {
+    serde_json::to_writer(&mut *writer, record)?;
+    writer.write_all(b"\n")
+}
+
+/// Logs the first error of the writer thread, returns true if there was an error. The job goes on without the event log.
+fn report_error(result: std::io::Result<()>, failed: bool) -> bool {
+    match result {
+        Ok(()) => false,
+        Err(e) => {
+            if !failed {
+                error!("Could not write the event log: {}", e);
+            }
+
+            true
+        }
+    }
+}
+
+/// Reads the events from the given event log, see [`read()`].
+pub struct NCEventReader {
+    /// The lines of the event log.
+    lines: std::io::Lines<BufReader<File>>,
+    /// The number of the last line that has been read, for the error messages.
+    line_number: usize,
+}
+
+impl Iterator for NCEventReader {
+    type Item = Result<NCEventRecord, NCError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            self.line_number += 1;
+
+            // An empty line may be left over from a server that has been killed
+            if line.trim().is_empty() {
+                continue
+            }
+
+            return Some(serde_json::from_str(&line)
+                .map_err(|e| NCError::custom(format!("Invalid event in line {} of the event log: {}", self.line_number, e))))
+        }
+    }
+}
+
+/// Reads the events from the given event log (event_log in the NCConfiguration), one after another in the order they have been written.
+///
+/// # Errors
+///
+/// Returns an error if the file could not be opened, the iterator returns an error for every line that is not a valid event.
+pub fn read<P: AsRef<Path>>(path: P) -> Result<NCEventReader, NCError> {
+    debug!("nc_events::read()");
+
+    let file = File::open(path)?;
+    Ok(NCEventReader { lines: BufReader::new(file).lines(), line_number: 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::nc_local;
+    use crate::nc_range::{RangeServer, RangeNode};
+
+    fn test_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("nc_events_{}_{}.ndjson", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_read() {
+        let path = test_file("read");
+        let node_id: NodeID = serde_json::from_str("2").unwrap();
+        let record = NCEventRecord { v: 1, seq: 3, time_ms: 1000, event: NCEvent::ChunkCompleted { node_id, chunk_id: 7, duration_ms: 1250 } };
+        let line = serde_json::to_string(&record).unwrap();
+        assert_eq!(line, r#"{"v":1,"seq":3,"time_ms":1000,"event":"chunk_completed","node_id":2,"chunk_id":7,"duration_ms":1250}"#);
+
+        // Unknown fields and events from a newer version are read, a broken line is an error
+        let newer = r#"{"v":1,"seq":4,"time_ms":1001,"event":"node_upgraded","node_id":2,"version":"9.9"}"#;
+        let extra = r#"{"v":1,"seq":5,"time_ms":1002,"event":"chunk_failed","node_id":2,"error":"bad input"}"#;
+        fs::write(&path, format!("{}\n{}\n\n{}\n{{\"v\":1,\n", line, newer, extra)).unwrap();
+
+        let events: Vec<Result<NCEventRecord, NCError>> = read(&path).unwrap().collect();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0].as_ref().unwrap(), &record);
+        assert_eq!(events[1].as_ref().unwrap().event, NCEvent::Unknown);
+        assert_eq!(events[2].as_ref().unwrap().event, NCEvent::ChunkFailed { node_id });
+        assert!(events[3].as_ref().unwrap_err().to_string().contains("line 5"));
+
+        assert!(read(test_file("missing")).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_run() {
+        type SumFold = fn(&mut u64, std::ops::Range<u64>, &[u64]);
+
+        let path = test_file("run");
+        let config = NCConfiguration { port: 0, delay_request_data: 1, heartbeat: 1, event_log: Some(path.clone()), event_log_flush_ms: 10, ..Default::default() };
+        let server: RangeServer<u64, u64, SumFold> = RangeServer::new(0..100, 7, 0, |sum, _range, values| *sum += values.iter().sum::<u64>());
+        let (_, job_summary) = nc_local::run(&config, server, |_| RangeNode::new(|i| i * i), 3).unwrap();
+
+        // Replay the recorded run and count the chunks from the events alone
+        let records: Vec<NCEventRecord> = read(&path).unwrap().map(Result::unwrap).collect();
+        assert!(records.iter().enumerate().all(|(seq, record)| record.seq == seq as u64 && record.v == EVENT_LOG_VERSION));
+        assert!(matches!(records[0].event, NCEvent::JobStarted { .. }));
+        assert_eq!(records.last().unwrap().event, NCEvent::JobFinished { reason: "finished".to_string() });
+
+        let mut nodes = HashSet::new();
+        let mut assigned = 0;
+        let mut completed = HashSet::new();
+
+        for record in records.iter() {
+            match &record.event {
+                NCEvent::NodeRegistered { node_id } => { nodes.insert(*node_id); }
+                NCEvent::ChunkAssigned { node_id, .. } => {
+                    assert!(nodes.contains(node_id));
+                    assigned += 1;
+                }
+                NCEvent::ChunkCompleted { chunk_id, .. } => { completed.insert(*chunk_id); }
+                _ => (),
+            }
+        }
+
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(assigned, job_summary.chunks_sent);
+        assert_eq!(Some(completed.len() as u64), job_summary.done_chunks);
+        assert_eq!(job_summary.total_chunks, Some(15));
+
+        // The next run is appended
+        let server: RangeServer<u64, u64, SumFold> = RangeServer::new(0..10, 5, 0, |sum, _range, values| *sum += values.iter().sum::<u64>());
+        nc_local::run(&config, server, |_| RangeNode::new(|i| i * i), 1).unwrap();
+        let starts = read(&path).unwrap().filter(|record| matches!(record.as_ref().unwrap(), NCEventRecord { seq: 0, event: NCEvent::JobStarted { .. }, .. })).count();
+        assert_eq!(starts, 2);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
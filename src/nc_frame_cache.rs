@@ -0,0 +1,147 @@
+//! This module contains the chunk frame cache for the server.
+//! If cache_chunk_frames is set in the NCConfiguration, the serialized and compressed chunk data (see [`NCPreparedFrame`]) of every chunk
+//! that has been sent to a node is kept until the chunk is done. If the same chunk is sent again (to another node or after a failure)
+//! only the chunk info is serialized and compressed, and the whole frame is encrypted with a new nonce.
+//! The cache is bounded by frame_cache_max_bytes, the least recently used frames are dropped first.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use log::debug;
+
+use crate::nc_config::NCConfiguration;
+use crate::nc_communicator::{NCCodec, NCPreparedFrame};
+use crate::array2d::ChunkID;
+
+/// Keeps the prepared frames of the unfinished chunks.
+pub(crate) struct NCFrameCache {
+    /// The prepared frame for each chunk and the codec it has been prepared for.
+    frames: HashMap<ChunkID, (NCCodec, Arc<NCPreparedFrame>)>,
+    /// The chunk ids in the order of use, the least recently used one first.
+    order: VecDeque<ChunkID>,
+    /// Total size of all the frames in bytes.
+    bytes: u64,
+    /// Maximum total size of all the frames in bytes.
+    max_bytes: u64,
+    /// Number of chunks that have been sent with a cached frame.
+    hits: u64,
+}
+
+impl NCFrameCache {
+    /// Creates a new empty cache with the settings from the given configuration.
+    pub(crate) fn new(config: &NCConfiguration) -> Self {
+        debug!("NCFrameCache::new()");
+
+        NCFrameCache {
+            frames: HashMap::new(),
+            order: VecDeque::new(),
+            bytes: 0,
+            max_bytes: config.frame_cache_max_bytes,
+            hits: 0,
+        }
+    }
+
+    /// Returns the frame of the given chunk if it has been prepared for the given codec.
+    pub(crate) fn get(&mut self, chunk_id: ChunkID, codec: NCCodec) -> Option<Arc<NCPreparedFrame>> {
+        let frame = match self.frames.get(&chunk_id) {
+            Some((frame_codec, frame)) if *frame_codec == codec => frame.clone(),
+            _ => return None,
+        };
+
+        self.hits += 1;
+        self.order.retain(|other| *other != chunk_id);
+        self.order.push_back(chunk_id);
+        Some(frame)
+    }
+
+    /// Stores the frame for the given chunk and codec. If the cache is full the least recently used frames are dropped.
+    /// Frames that are bigger than frame_cache_max_bytes are not stored at all.
+    pub(crate) fn insert(&mut self, chunk_id: ChunkID, codec: NCCodec, frame: Arc<NCPreparedFrame>) {
+        debug!("NCFrameCache::insert()");
+
+        self.remove(chunk_id);
+
+        let size = frame.len() as u64;
+
+        if size > self.max_bytes {
+            debug!("Frame for chunk {} is too big for the cache: {} bytes", chunk_id, size);
+            return
+        }
+
+        while self.bytes + size > self.max_bytes {
+            match self.order.front().copied() {
+                Some(old_chunk_id) => self.remove(old_chunk_id),
+                None => break,
+            }
+        }
+
+        self.bytes += size;
+        self.frames.insert(chunk_id, (codec, frame));
+        self.order.push_back(chunk_id);
+    }
+
+    /// Drops the frame of the given chunk, usually because the chunk is done.
+    pub(crate) fn remove(&mut self, chunk_id: ChunkID) {
+        if let Some((_, frame)) = self.frames.remove(&chunk_id) {
+            self.bytes -= frame.len() as u64;
+            self.order.retain(|other| *other != chunk_id);
+        }
+    }
+
+    /// Drops all the frames, the chunk ids of the next phase may have other data.
+    pub(crate) fn clear(&mut self) {
+        debug!("NCFrameCache::clear()");
+
+        self.frames.clear();
+        self.order.clear();
+        self.bytes = 0;
+    }
+
+    /// Number of chunks that have been sent with a cached frame.
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::nc_communicator::NCCommunicator;
+
+    fn frame_for_test(value: u8, size: usize) -> Arc<NCPreparedFrame> {
+        Arc::new(NCCommunicator::new(&NCConfiguration::default()).unwrap().prepare_frame(vec![value; size], "test", NCCodec::None, None).unwrap())
+    }
+
+    #[test]
+    fn test_frame_cache() {
+        let mut frame_cache = NCFrameCache::new(&NCConfiguration { frame_cache_max_bytes: 30, ..Default::default() });
+
+        frame_cache.insert(0, NCCodec::Lz4, frame_for_test(0, 10));
+        frame_cache.insert(1, NCCodec::Lz4, frame_for_test(1, 10));
+        frame_cache.insert(2, NCCodec::Lz4, frame_for_test(2, 10));
+
+        // Only for the same codec
+        assert_eq!(frame_cache.get(0, NCCodec::Lz4), Some(frame_for_test(0, 10)));
+        assert_eq!(frame_cache.get(0, NCCodec::None), None);
+        assert_eq!(frame_cache.hits(), 1);
+
+        // Chunk 0 has been used again, so chunk 1 is the least recently used one
+        frame_cache.insert(3, NCCodec::Lz4, frame_for_test(3, 10));
+        assert_eq!(frame_cache.get(1, NCCodec::Lz4), None);
+        assert_eq!(frame_cache.bytes, 30);
+
+        // Too big for the cache
+        frame_cache.insert(4, NCCodec::Lz4, frame_for_test(4, 31));
+        assert_eq!(frame_cache.get(4, NCCodec::Lz4), None);
+
+        // Chunk is done
+        frame_cache.remove(0);
+        assert_eq!(frame_cache.get(0, NCCodec::Lz4), None);
+        assert_eq!(frame_cache.bytes, 20);
+
+        frame_cache.clear();
+        assert_eq!(frame_cache.get(2, NCCodec::Lz4), None);
+        assert_eq!(frame_cache.bytes, 0);
+    }
+}
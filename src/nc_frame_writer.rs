@@ -0,0 +1,116 @@
+//! This module contains the frame writer that writes one frame with one write call.
+//! Every frame is the length of the data (u64, little endian) followed by the data itself, see [`NCCommunicator`](crate::nc_communicator::NCCommunicator).
+//! The length and the data are handed to the Writer together as a vectored write, so a tcp stream sends them
+//! with one syscall instead of two and the data doesn't have to be copied into a buffer first.
+//! Since every message uses its own tcp connection there is nothing to coalesce across frames.
+
+use std::io::{self, IoSlice, Write};
+
+/// Writes the length of the data and the data itself to the given Writer (usually a tcp stream) and flushes it.
+/// If the Writer doesn't accept everything at once the rest is written with more calls.
+pub(crate) fn write_frame<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    let data_len = (data.len() as u64).to_le_bytes(); // u64 is platform independent, usize is platform dependent
+    let mut slices = [IoSlice::new(&data_len), IoSlice::new(data)];
+    let mut slices = &mut slices[..];
+
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole frame")),
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts the write calls, like the syscalls on a tcp stream.
+    #[derive(Default)]
+    struct CountingWriter {
+        data: Vec<u8>,
+        writes: usize,
+        /// Accept at most this many bytes per call, 0 = no limit.
+        max_per_write: usize,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.writes += 1;
+            let mut written = 0;
+
+            for buf in bufs {
+                let n = if self.max_per_write == 0 { buf.len() } else { buf.len().min(self.max_per_write - written) };
+                self.data.extend_from_slice(&buf[..n]);
+                written += n;
+
+                if n < buf.len() {
+                    break
+                }
+            }
+
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frame(data: &[u8]) -> Vec<u8> {
+        let mut result = (data.len() as u64).to_le_bytes().to_vec();
+        result.extend_from_slice(data);
+        result
+    }
+
+    #[test]
+    fn test_one_write_per_frame() {
+        let mut stream = CountingWriter::default();
+
+        for _ in 0..10 {
+            write_frame(&mut stream, &[1, 2, 3]).unwrap();
+        }
+
+        write_frame(&mut stream, &[4; 100_000]).unwrap();
+
+        // Without the vectored write that would be 22 write calls (length + data)
+        assert_eq!(stream.writes, 11);
+        assert_eq!(stream.data, [frame(&[1, 2, 3]).repeat(10), frame(&[4; 100_000])].concat());
+    }
+
+    #[test]
+    fn test_partial_writes() {
+        let mut stream = CountingWriter { max_per_write: 5, ..Default::default() };
+
+        write_frame(&mut stream, &[1, 2, 3, 4, 5, 6, 7]).unwrap();
+
+        assert_eq!(stream.writes, 3);
+        assert_eq!(stream.data, frame(&[1, 2, 3, 4, 5, 6, 7]));
+    }
+
+    #[test]
+    fn test_write_zero() {
+        struct ZeroWriter;
+
+        impl Write for ZeroWriter {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Ok(0)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let error = write_frame(&mut ZeroWriter, &[1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WriteZero);
+    }
+}
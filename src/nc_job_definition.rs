@@ -0,0 +1,540 @@
+//! This module contains job definitions: a whole job in one file, so that it can be prepared on one machine and run later on another one.
+//! The file contains the name of the job, some settings (see [`NCJobSettings`]), free metadata and the inputs of all the chunks.
+//! The inputs are written one by one while they are created and read one by one when a chunk is handed out,
+//! so a job with millions of chunks doesn't have to fit into memory. Only the position of every input in the file is kept (8 bytes per chunk).
+//!
+//! ```no_run
+//! # #[cfg(feature = "net")] {
+//! use node_crunch::{NCConfiguration, NCServerStarter, NCNodeStarter, NCJobDefinition, NCDefinitionServer, NCDefinitionNode};
+//!
+//! // Prepare the job on the laptop, every number is one chunk
+//! NCJobDefinition::<u64, u64>::new("squares").save("squares.ncjob", 0..1_000_000).unwrap();
+//!
+//! // Run it later on the server
+//! NCServerStarter::<NCDefinitionServer<u64, u64, _>>::new(NCConfiguration::default()).start_from_definition("squares.ncjob", |chunk_id, square| {
+//!     println!("{}: {}", chunk_id, square);
+//!     Ok(())
+//! }).unwrap();
+//!
+//! // On every node
+//! NCNodeStarter::new(NCConfiguration::default()).start(NCDefinitionNode::new(|i: &u64| Ok(i * i))).unwrap();
+//! # }
+//! ```
+//!
+//! The file starts with the hash of the type names of the inputs and the results (the schema hash). Loading it with other types fails
+//! with a [`NCError::TypeMismatch`] error and [`NCServerStarter::start_from_definition()`](crate::NCServerStarter::start_from_definition)
+//! turns on type_check in the NCConfiguration, so that a node binary with other types fails with its first message.
+//!
+//! Layout of the file:
+//!
+//! - 8 bytes: the magic bytes `NCJOBDF1`
+//! - u64 (little endian): the length of the header, then the header (name, settings, metadata, schema hash and type names) as bincode
+//! - for every chunk: u64 (little endian): the length of the input, then the input as bincode
+
+use std::any::type_name;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+
+use log::{debug, info};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+use crate::nc_error::NCError;
+use crate::nc_config::NCConfiguration;
+use crate::nc_communicator::{NCCodec, fnv1a};
+use crate::array2d::ChunkID;
+
+/// Every job definition file starts with these bytes.
+const DEFINITION_MAGIC: &[u8; 8] = b"NCJOBDF1";
+
+/// The settings of the NCConfiguration that belong to the job and not to the machine that runs it.
+/// Only the settings that are set replace the ones from the NCConfiguration of the server.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NCJobSettings {
+    /// See job_id in the NCConfiguration.
+    pub job_id: Option<String>,
+    /// See heartbeat in the NCConfiguration.
+    pub heartbeat: Option<u64>,
+    /// See allowed_codecs in the NCConfiguration.
+    pub allowed_codecs: Option<Vec<NCCodec>>,
+    /// See ordered_results in the NCConfiguration.
+    pub ordered_results: Option<bool>,
+    /// See required_node_build in the NCConfiguration.
+    pub required_node_build: Option<String>,
+}
+
+impl NCJobSettings {
+    /// Takes all the settings from the given configuration.
+    pub fn from_config(config: &NCConfiguration) -> Self {
+        NCJobSettings {
+            job_id: config.job_id.clone(),
+            heartbeat: Some(config.heartbeat),
+            allowed_codecs: Some(config.allowed_codecs.clone()),
+            ordered_results: Some(config.ordered_results),
+            required_node_build: config.required_node_build.clone(),
+        }
+    }
+
+    /// Replaces the settings in the given configuration with the ones that are set here.
+    pub fn apply(&self, config: &mut NCConfiguration) {
+        if self.job_id.is_some() {
+            config.job_id = self.job_id.clone();
+        }
+
+        if let Some(heartbeat) = self.heartbeat {
+            config.heartbeat = heartbeat;
+        }
+
+        if let Some(allowed_codecs) = &self.allowed_codecs {
+            config.allowed_codecs = allowed_codecs.clone();
+        }
+
+        if let Some(ordered_results) = self.ordered_results {
+            config.ordered_results = ordered_results;
+        }
+
+        if self.required_node_build.is_some() {
+            config.required_node_build = self.required_node_build.clone();
+        }
+    }
+}
+
+/// The header of the file.
+#[derive(Debug, Serialize, Deserialize)]
+struct NCJobHeader {
+    name: String,
+    settings: NCJobSettings,
+    metadata: BTreeMap<String, String>,
+    schema_hash: u32,
+    schema: String,
+}
+
+/// A job with inputs of type I and results of type R, see the module documentation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NCJobDefinition<I, R> {
+    /// The name of the job, it's logged when the job is started.
+    pub name: String,
+    /// The settings that replace the ones from the NCConfiguration of the server.
+    pub settings: NCJobSettings,
+    /// Anything else that belongs to the job: who has prepared it, which data has been used, ...
+    pub metadata: BTreeMap<String, String>,
+    types: PhantomData<fn() -> (I, R)>,
+}
+
+impl<I, R> NCJobDefinition<I, R> {
+    /// Creates a new job definition with the given name, no settings and no metadata.
+    pub fn new(name: &str) -> Self {
+        NCJobDefinition { name: name.to_string(), settings: NCJobSettings::default(), metadata: BTreeMap::new(), types: PhantomData }
+    }
+
+    /// The type names of the inputs and the results.
+    pub fn schema() -> String {
+        format!("{} -> {}", type_name::<I>(), type_name::<R>())
+    }
+
+    /// The hash of schema(), it's the same for every build and platform.
+    pub fn schema_hash() -> u32 {
+        fnv1a(Self::schema().as_bytes())
+    }
+}
+
+impl<I: Serialize + DeserializeOwned, R> NCJobDefinition<I, R> {
+    /// Writes the job definition and the inputs to the given file, every input is one chunk. The inputs are written
+    /// while the iterator creates them, so they don't have to fit into memory. Returns the number of chunks.
+    /// The file gets its final name once it's complete, an existing file is replaced.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::IOError`] if the file could not be written and a [`NCError::Serialize`] error if an input could not be serialized.
+    pub fn save<P: AsRef<Path>, T: IntoIterator<Item = I>>(&self, path: P, inputs: T) -> Result<u64, NCError> {
+        debug!("NCJobDefinition::save()");
+
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp-definition");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let header = NCJobHeader { name: self.name.clone(), settings: self.settings.clone(), metadata: self.metadata.clone(),
+            schema_hash: Self::schema_hash(), schema: Self::schema() };
+
+        writer.write_all(DEFINITION_MAGIC)?;
+        write_entry(&mut writer, &header)?;
+        let mut count = 0;
+
+        for input in inputs {
+            write_entry(&mut writer, &input)?;
+            count += 1;
+        }
+
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+
+        Ok(count)
+    }
+
+    /// Opens the job definition in the given file. The inputs are not read yet, see [`NCJobFile::input()`].
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::TypeMismatch`] error if the file has been written for other types and a [`NCError::JobDefinition`] error
+    /// if it's not a job definition or is incomplete.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<NCJobFile<I, R>, NCError> {
+        debug!("NCJobDefinition::load()");
+
+        let path = path.as_ref();
+        let invalid = |problem: &str| NCError::JobDefinition(format!("{}: {}", path.display(), problem));
+        let mut reader = BufReader::new(File::open(path)?);
+        let file_len = reader.get_ref().metadata()?.len();
+
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic).map_err(|_| invalid("not a job definition"))?;
+
+        if &magic != DEFINITION_MAGIC {
+            return Err(invalid("not a job definition"))
+        }
+
+        let header_len = read_len(&mut reader, file_len).ok_or_else(|| invalid("the header is incomplete"))?;
+        let mut header = vec![0; header_len as usize];
+        reader.read_exact(&mut header)?;
+        let header: NCJobHeader = bincode::deserialize(&header).map_err(NCError::Deserialize)?;
+
+        if header.schema_hash != Self::schema_hash() {
+            return Err(NCError::TypeMismatch { expected: Self::schema(), got: header.schema })
+        }
+
+        // Only the positions of the inputs are kept
+        let mut offsets = Vec::new();
+        let mut offset = reader.stream_position()?;
+
+        while offset < file_len {
+            let len = read_len(&mut reader, file_len).ok_or_else(|| invalid(&format!("input {} is incomplete", offsets.len())))?;
+            offsets.push(offset);
+            offset = reader.seek(SeekFrom::Current(len as i64))?;
+        }
+
+        let definition = NCJobDefinition { name: header.name, settings: header.settings, metadata: header.metadata, types: PhantomData };
+        info!("Job definition '{}' loaded from {}: {} chunks", definition.name, path.display(), offsets.len());
+
+        Ok(NCJobFile { definition, reader, offsets })
+    }
+}
+
+/// Writes the length and the serialized value.
+fn write_entry<W: Write, S: Serialize>(writer: &mut W, value: &S) -> Result<(), NCError> {
+    let data = bincode::serialize(value).map_err(NCError::Serialize)?;
+    writer.write_all(&(data.len() as u64).to_le_bytes())?;
+    writer.write_all(&data)?;
+    Ok(())
+}
+
+/// Reads the length of the next entry, [`None`] if the length or the entry itself goes beyond the end of the file.
+fn read_len<T: Read + Seek>(reader: &mut T, file_len: u64) -> Option<u64> {
+    let mut len = [0; 8];
+    reader.read_exact(&mut len).ok()?;
+    let len = u64::from_le_bytes(len);
+    let start = reader.stream_position().ok()?;
+
+    start.checked_add(len).filter(|end| *end <= file_len).map(|_| len)
+}
+
+/// An opened job definition file, see [`NCJobDefinition::load()`].
+#[derive(Debug)]
+pub struct NCJobFile<I, R> {
+    definition: NCJobDefinition<I, R>,
+    reader: BufReader<File>,
+    offsets: Vec<u64>,
+}
+
+impl<I: DeserializeOwned, R> NCJobFile<I, R> {
+    /// The name, the settings and the metadata of the job.
+    pub fn definition(&self) -> &NCJobDefinition<I, R> {
+        &self.definition
+    }
+
+    /// The number of chunks.
+    pub fn len(&self) -> u64 {
+        self.offsets.len() as u64
+    }
+
+    /// Returns true if the job has no chunks.
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Reads the input of the given chunk from the file.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::JobDefinition`] error if there is no such chunk and a [`NCError::Deserialize`] error if the input could not be decoded.
+    pub fn input(&mut self, chunk_id: ChunkID) -> Result<I, NCError> {
+        let offset = *self.offsets.get(chunk_id as usize).ok_or_else(|| NCError::JobDefinition(format!("unknown chunk: {}", chunk_id)))?;
+        self.reader.seek(SeekFrom::Start(offset))?;
+
+        let mut len = [0; 8];
+        self.reader.read_exact(&mut len)?;
+        let mut data = vec![0; u64::from_le_bytes(len) as usize];
+        self.reader.read_exact(&mut data)?;
+
+        bincode::deserialize(&data).map_err(NCError::Deserialize)
+    }
+
+    /// Reads all the inputs one by one.
+    pub fn inputs(&mut self) -> impl Iterator<Item = Result<I, NCError>> + '_ {
+        (0..self.len()).map(move |chunk_id| self.input(chunk_id))
+    }
+}
+
+/// The input of one chunk together with its id, this is sent from the [`NCDefinitionServer`](crate::NCDefinitionServer) to the node.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NCJobInput<I> {
+    /// The id of the chunk.
+    pub chunk_id: ChunkID,
+    /// The input from the job definition.
+    pub input: I,
+}
+
+/// The result of one chunk together with its id, this is sent from the node back to the [`NCDefinitionServer`](crate::NCDefinitionServer).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NCJobOutput<R> {
+    /// The id of the chunk.
+    pub chunk_id: ChunkID,
+    /// The result for the input.
+    pub output: R,
+}
+
+#[cfg(feature = "net")]
+pub use self::net::{NCDefinitionServer, NCDefinitionNode};
+
+#[cfg(feature = "net")]
+mod net {
+    use super::*;
+
+    use crate::nc_error::NCJobError;
+    use crate::nc_node_info::NodeID;
+    use crate::nc_server::{NCServer, ChunkAssignment};
+    use crate::nc_node::{NCNode, NodeResult};
+    use crate::array2d::LazyChunkList;
+
+    /// A server that hands out the inputs of a job definition and calls a closure for every result, see
+    /// [`NCServerStarter::start_from_definition()`](crate::NCServerStarter::start_from_definition).
+    /// The inputs stay in the file, the chunks are kept in a [`LazyChunkList`].
+    pub struct NCDefinitionServer<I, R, F> {
+        file: NCJobFile<I, R>,
+        chunk_list: LazyChunkList<(), fn(ChunkID)>,
+        process_fn: F,
+    }
+
+    impl<I, R, F> NCDefinitionServer<I, R, F> where I: DeserializeOwned, F: FnMut(ChunkID, &R) -> Result<(), NCError> {
+        /// process_fn() is called with the id of the chunk and its result, in the order the results arrive
+        /// (unless ordered_results is set in the NCConfiguration).
+        pub fn new(file: NCJobFile<I, R>, process_fn: F) -> Self {
+            debug!("NCDefinitionServer::new()");
+
+            let chunk_list = LazyChunkList::new(file.len(), (|_| ()) as fn(ChunkID));
+            NCDefinitionServer { file, chunk_list, process_fn }
+        }
+
+        /// Returns the chunks that have failed permanently (a node has sent a non retryable error for them).
+        pub fn failed_chunks(&self) -> Vec<ChunkID> {
+            self.chunk_list.failed_chunks().collect()
+        }
+    }
+
+    impl<I, R, F> NCServer for NCDefinitionServer<I, R, F> where I: Serialize + DeserializeOwned, R: Serialize + DeserializeOwned + Send + 'static,
+        F: FnMut(ChunkID, &R) -> Result<(), NCError> {
+        type InitialDataT = ();
+        type NewDataT = NCJobInput<I>;
+        type ProcessedDataT = NCJobOutput<R>;
+        type CustomMessageT = ();
+
+        fn assign_chunk(&mut self, node_id: NodeID) -> Result<ChunkAssignment<NCJobInput<I>>, NCError> {
+            if let Some((chunk_id, ())) = self.chunk_list.assign_next_chunk(node_id) {
+                return match self.file.input(chunk_id) {
+                    Ok(input) => Ok(ChunkAssignment::Assigned(chunk_id, NCJobInput { chunk_id, input })),
+                    Err(e) => {
+                        self.chunk_list.chunk_send_failed(chunk_id);
+                        Err(e)
+                    }
+                }
+            }
+
+            let (done, total) = self.chunk_list.progress();
+
+            if done == total {
+                Ok(ChunkAssignment::Finished)
+            } else {
+                Ok(ChunkAssignment::Waiting)
+            }
+        }
+
+        fn chunk_sent(&mut self, chunk_id: ChunkID) {
+            self.chunk_list.chunk_sent(chunk_id)
+        }
+
+        fn chunk_send_failed(&mut self, chunk_id: ChunkID) {
+            self.chunk_list.chunk_send_failed(chunk_id)
+        }
+
+        fn chunk_empty(&mut self, chunk_id: ChunkID) {
+            self.chunk_list.chunk_empty(chunk_id)
+        }
+
+        fn chunk_skipped(&mut self, chunk_id: ChunkID, _reason: &str) {
+            self.chunk_list.chunk_skipped(chunk_id)
+        }
+
+        fn chunk_left_out(&mut self, chunk_id: ChunkID) {
+            self.chunk_list.chunk_left_out(chunk_id)
+        }
+
+        fn chunk_rejected(&mut self, chunk_id: ChunkID, requeue: bool) {
+            self.chunk_list.chunk_rejected(chunk_id, requeue)
+        }
+
+        fn validate(&self, data: &NCJobOutput<R>) -> Result<(), String> {
+            if data.chunk_id < self.file.len() {
+                Ok(())
+            } else {
+                Err(format!("unknown chunk: {}", data.chunk_id))
+            }
+        }
+
+        fn process_data_from_node(&mut self, node_id: NodeID, data: &NCJobOutput<R>) -> Result<(), NCError> {
+            debug!("NCDefinitionServer::process_data_from_node()");
+
+            // A late result for a chunk that has been given to another node in the meantime is dropped
+            if self.chunk_list.is_processing(data.chunk_id, node_id) {
+                self.chunk_list.set_finished(data.chunk_id);
+                (self.process_fn)(data.chunk_id, &data.output)?;
+            }
+
+            Ok(())
+        }
+
+        fn process_node_error(&mut self, node_id: NodeID, error: &NCJobError) {
+            self.chunk_list.chunk_failed(node_id, error.retryable)
+        }
+
+        fn job_progress(&self) -> Option<(u64, u64)> {
+            Some(self.chunk_list.progress())
+        }
+
+        fn job_finished(&self) -> bool {
+            let (done, total) = self.chunk_list.progress();
+            done == total
+        }
+
+        fn heartbeat_timeout(&mut self, nodes: Vec<NodeID>) {
+            self.chunk_list.heartbeat_timeout(&nodes)
+        }
+
+        fn finish_job(&mut self) {
+            debug!("NCDefinitionServer::finish_job()");
+        }
+    }
+
+    /// A node that calls the user function for the input of every chunk it gets from the [`NCDefinitionServer`].
+    pub struct NCDefinitionNode<I, R, F> {
+        function: F,
+        types: PhantomData<fn() -> (I, R)>,
+    }
+
+    impl<I, R, F> NCDefinitionNode<I, R, F> where F: FnMut(&I) -> Result<R, NCError> {
+        /// The function is called for every input, its result is sent back to the server.
+        pub fn new(function: F) -> Self {
+            NCDefinitionNode { function, types: PhantomData }
+        }
+    }
+
+    impl<I, R, F> NCNode for NCDefinitionNode<I, R, F> where I: Serialize + DeserializeOwned, R: Serialize + DeserializeOwned,
+        F: FnMut(&I) -> Result<R, NCError> {
+        type InitialDataT = ();
+        type NewDataT = NCJobInput<I>;
+        type ProcessedDataT = NCJobOutput<R>;
+        type CustomMessageT = ();
+
+        fn process_data_from_server(&mut self, data: &NCJobInput<I>) -> Result<NodeResult<NCJobOutput<R>>, NCError> {
+            debug!("NCDefinitionNode::process_data_from_server()");
+
+            let output = (self.function)(&data.input)?;
+            Ok(NodeResult::Data(NCJobOutput { chunk_id: data.chunk_id, output }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    fn test_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("nc_job_definition_{}_{}.ncjob", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = test_path("save");
+        let mut definition = NCJobDefinition::<(u32, String), f64>::new("labels");
+        definition.settings = NCJobSettings { job_id: Some("labels".to_string()), heartbeat: Some(5), ..Default::default() };
+        definition.metadata.insert("prepared by".to_string(), "laptop".to_string());
+
+        let count = definition.save(&path, (0..1000).map(|i| (i, format!("#{}", i)))).unwrap();
+        assert_eq!(count, 1000);
+
+        let mut file = NCJobDefinition::<(u32, String), f64>::load(&path).unwrap();
+        assert_eq!(file.definition(), &definition);
+        assert_eq!(file.len(), 1000);
+        assert_eq!(file.input(999).unwrap(), (999, "#999".to_string()));
+        assert_eq!(file.input(7).unwrap(), (7, "#7".to_string()));
+        assert!(matches!(file.input(1000), Err(NCError::JobDefinition(_))));
+        assert_eq!(file.inputs().map(|input| input.unwrap().0).sum::<u32>(), (0..1000_u32).sum::<u32>());
+
+        // The settings replace the ones of the server
+        let mut config = NCConfiguration::default();
+        file.definition().settings.apply(&mut config);
+        assert_eq!((config.job_id.as_deref(), config.heartbeat, config.ordered_results), (Some("labels"), 5, false));
+
+        // Other types
+        let error = NCJobDefinition::<(u32, String), f32>::load(&path).unwrap_err();
+        assert!(matches!(error, NCError::TypeMismatch { got, .. } if got.ends_with("-> f64")));
+
+        // Incomplete file
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 3]).unwrap();
+        assert!(matches!(NCJobDefinition::<(u32, String), f64>::load(&path), Err(NCError::JobDefinition(problem)) if problem.contains("input 999")));
+        fs::write(&path, b"NCOFFLN1").unwrap();
+        assert!(matches!(NCJobDefinition::<(u32, String), f64>::load(&path), Err(NCError::JobDefinition(_))));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_run_definition() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::nc_local;
+
+        let path = test_path("run");
+        NCJobDefinition::<u64, u64>::new("squares").save(&path, 0..500).unwrap();
+        let file = NCJobDefinition::<u64, u64>::load(&path).unwrap();
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let server_results = results.clone();
+
+        let server = NCDefinitionServer::new(file, move |chunk_id, square: &u64| {
+            server_results.lock()?.push((chunk_id, *square));
+            Ok(())
+        });
+        let config = NCConfiguration { port: 0, delay_request_data: 1, heartbeat: 1, ..Default::default() };
+        let (server, job_summary) = nc_local::run(&config, server, |_| NCDefinitionNode::new(|i: &u64| Ok(i * i)), 3).unwrap();
+
+        let mut results = results.lock().unwrap().clone();
+        results.sort();
+        assert_eq!(results, (0..500).map(|i| (i, i * i)).collect::<Vec<_>>());
+        assert_eq!(job_summary.chunks_sent, 500);
+        assert!(server.failed_chunks().is_empty());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,738 @@
+//! This module contains the summary of a job that the server collects while it's running.
+//! When the job ends (because it's done or because it has been aborted) the summary is logged at info level,
+//! returned by [`NCServerStarter::start()`](crate::NCServerStarter::start) and optionally written as JSON next to the checkpoint file
+//! (write_job_summary in the NCConfiguration).
+//!
+//! The server also keeps a log of the attempts for every chunk (which node, when, how it has ended), so that it's possible to find out
+//! where and why a chunk has failed before it has finally been done. Only the chunks that needed more than one attempt keep their log
+//! once they are done, it's part of the summary (chunk_history) and can be queried with the NCAdminCommand::ChunkHistory command.
+//!
+//! The summary of a sampled run (sample_fraction in the NCConfiguration) contains the sample with its seed, so the same chunks can be
+//! processed again, see the nc_sample module.
+//!
+//! With max_chunk_attempts in the NCConfiguration a chunk that has been sent that many times without a result is not sent again,
+//! it moves to the dead-letter list (dead_chunks) with its number of attempts and the last error. The list is part of the summary
+//! and of the checkpoint, so a resumed job doesn't retry these chunks until they are reset with the NCAdminCommand::ResetDeadChunk command.
+
+use std::collections::BTreeMap;
+#[cfg(feature = "net")]
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display, Formatter};
+#[cfg(feature = "net")]
+use std::fs;
+#[cfg(feature = "net")]
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+#[cfg(feature = "net")]
+use std::time::Instant;
+
+#[cfg(feature = "net")]
+use log::debug;
+use serde::{Serialize, Deserialize};
+
+#[cfg(feature = "net")]
+use crate::nc_error::{NCError, NCJobError};
+use crate::nc_node_info::NodeID;
+use crate::nc_sample::NCSample;
+use crate::array2d::ChunkID;
+
+/// The maximum number of attempts in the log of a chunk, the oldest attempts are dropped.
+#[cfg(feature = "net")]
+const MAX_CHUNK_ATTEMPTS: usize = 16;
+
+/// Why the job has ended.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum NCJobEndReason {
+    /// The NCServer trait method assign_chunk() has returned ChunkAssignment::Finished.
+    Finished,
+    /// The job has been aborted via the admin protocol with the given reason.
+    Aborted(String),
+    /// The nodes have reported too many errors that are not retryable, see max_permanent_failures in the NCConfiguration.
+    TooManyFailures(u64),
+    /// process_data_from_node() has returned the given error and on_process_error is OnProcessError::AbortJob.
+    ProcessError(String),
+    /// The server has been stopped from outside, for example with Ctrl-C (see [`NCServerStarter::stop_on_ctrl_c()`](crate::NCServerStarter))
+    /// or because a node of [`nc_local::run()`](crate::nc_local::run) has failed.
+    Stopped,
+    /// The job has run for max_job_duration (see the NCConfiguration), the chunks that are not finished can be processed
+    /// in a later run from the last checkpoint.
+    TimeBudgetExhausted,
+    /// strict_mode is set in the NCConfiguration and the server has seen this protocol anomaly.
+    StrictViolation(String),
+    /// The given chunk has run out of attempts (max_chunk_attempts in the NCConfiguration) and the NCServer trait method
+    /// chunk_dead() has returned NCDeadChunkAction::AbortJob.
+    ChunkDead(ChunkID),
+}
+
+impl Display for NCJobEndReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NCJobEndReason::Finished => write!(f, "finished"),
+            NCJobEndReason::Aborted(reason) => write!(f, "aborted: {}", reason),
+            NCJobEndReason::TooManyFailures(failures) => write!(f, "too many permanent failures: {}", failures),
+            NCJobEndReason::ProcessError(error) => write!(f, "could not process result: {}", error),
+            NCJobEndReason::Stopped => write!(f, "stopped"),
+            NCJobEndReason::TimeBudgetExhausted => write!(f, "time budget exhausted"),
+            NCJobEndReason::StrictViolation(description) => write!(f, "strict mode violation: {}", description),
+            NCJobEndReason::ChunkDead(chunk_id) => write!(f, "chunk {} has run out of attempts", chunk_id),
+        }
+    }
+}
+
+impl NCJobEndReason {
+    /// Returns true if the job has been aborted, the nodes get a NCServerMessage::JobAborted then instead of NCJobStatus::Finished.
+    pub fn is_abort(&self) -> bool {
+        matches!(self, NCJobEndReason::Aborted(_) | NCJobEndReason::TooManyFailures(_) | NCJobEndReason::ProcessError(_) |
+            NCJobEndReason::StrictViolation(_) | NCJobEndReason::ChunkDead(_))
+    }
+}
+
+/// What a single node has contributed to the job.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NCNodeContribution {
+    /// The id of the node.
+    pub node_id: NodeID,
+    /// Number of results the node has sent, combined results from an aggregator count once.
+    pub results: u64,
+    /// Size of these results in bytes (serialized, uncompressed).
+    pub result_bytes: u64,
+    /// The time from sending the chunks to the node until their results arrived.
+    pub busy_time: Duration,
+}
+
+/// How an attempt to process a chunk has ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NCAttemptOutcome {
+    /// The node is still processing the chunk, or the result has arrived through an aggregator.
+    Running,
+    /// The node has sent a result.
+    Success,
+    /// The node has skipped the chunk.
+    Skipped,
+    /// The node has failed with the given error code, see [`NCJobError`]. A result that the server could not process
+    /// has the code NCJobError::RESULT_REJECTED.
+    Failed(u32),
+    /// The heartbeat of the node has timed out.
+    Timeout,
+    /// The chunk has been taken from the node without a result: the node has been disabled, has given back the chunk
+    /// that it had fetched in advance or has failed with its other chunk.
+    Released,
+    /// The chunk has been revoked with [`NCServerHandle::cancel_chunk()`](crate::NCServerHandle::cancel_chunk).
+    Revoked,
+}
+
+impl Display for NCAttemptOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NCAttemptOutcome::Running => write!(f, "running"),
+            NCAttemptOutcome::Success => write!(f, "success"),
+            NCAttemptOutcome::Skipped => write!(f, "skipped"),
+            NCAttemptOutcome::Failed(code) => write!(f, "failed: {}", code),
+            NCAttemptOutcome::Timeout => write!(f, "timeout"),
+            NCAttemptOutcome::Released => write!(f, "released"),
+            NCAttemptOutcome::Revoked => write!(f, "revoked"),
+        }
+    }
+}
+
+/// One attempt to process a chunk.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NCChunkAttempt {
+    /// The node that got the chunk.
+    pub node_id: NodeID,
+    /// When the chunk has been sent to the node.
+    pub assigned_at: SystemTime,
+    /// How the attempt has ended.
+    pub outcome: NCAttemptOutcome,
+    /// The time from sending the chunk until the outcome, zero while the attempt is running.
+    pub duration: Duration,
+}
+
+/// A chunk that has run out of attempts, see max_chunk_attempts in the NCConfiguration.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NCDeadChunk {
+    /// The id of the chunk.
+    pub chunk_id: ChunkID,
+    /// How often the chunk has been sent to a node.
+    pub attempts: u32,
+    /// How the last attempt has ended, for example the error from the node.
+    pub last_error: String,
+}
+
+/// The summary of a job, see the module documentation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NCJobSummary {
+    /// Why the job has ended.
+    pub end_reason: NCJobEndReason,
+    /// The total number of chunks from the NCServer trait method job_progress(), [`None`] if it isn't implemented.
+    pub total_chunks: Option<u64>,
+    /// The number of done chunks from job_progress(), for a job that has been aborted this is less than total_chunks.
+    pub done_chunks: Option<u64>,
+    /// Number of different chunks that have been sent to the nodes.
+    pub chunks_sent: u64,
+    /// Number of times a chunk has been sent again, for example after a heartbeat timeout or a rejected result.
+    pub retries: u64,
+    /// Number of chunks that have failed permanently: errors from the nodes that are not retryable and dropped results.
+    pub failed_chunks: u64,
+    /// Number of results that have been dropped because the chunk had already been delivered or skipped (only with ordered_results).
+    pub duplicates_dropped: u64,
+    /// Number of chunks without any data.
+    pub empty_chunks: u64,
+    /// Number of chunks that the nodes have skipped.
+    pub skipped_chunks: u64,
+    /// The time from starting the server until the end of the job.
+    pub wall_time: Duration,
+    /// Size of all the results in bytes (serialized, uncompressed).
+    pub result_bytes: u64,
+    /// What every node has contributed, in the order the nodes have sent their first result.
+    pub nodes: Vec<NCNodeContribution>,
+    /// The protocol anomalies in strict_mode (see the NCConfiguration), the first one has aborted the job.
+    /// Anomalies from other threads may still arrive until the server has stopped.
+    #[serde(default)]
+    pub strict_violations: Vec<String>,
+    /// The attempts for every chunk of the last phase that needed more than one, at most the last 16 per chunk.
+    #[serde(default)]
+    pub chunk_history: BTreeMap<ChunkID, Vec<NCChunkAttempt>>,
+    /// The sample if this has been a sampled run, [`None`] if all the chunks have been processed.
+    #[serde(default)]
+    pub sample: Option<NCSample>,
+    /// Number of chunks that have been left out because they are not part of the sample.
+    #[serde(default)]
+    pub chunks_left_out: u64,
+    /// The chunks of the last phase that have run out of attempts (max_chunk_attempts in the NCConfiguration).
+    #[serde(default)]
+    pub dead_chunks: Vec<NCDeadChunk>,
+    /// Number of times a chunk has been sent to a node with its shard (locality_manifest in the NCConfiguration, see the nc_locality module).
+    #[serde(default)]
+    pub local_chunks: u64,
+    /// Number of times a chunk has been sent to a node without its shard, after it has waited for locality_fallback_ms.
+    #[serde(default)]
+    pub locality_fallbacks: u64,
+}
+
+#[cfg(feature = "net")]
+impl NCJobSummary {
+    /// Returns the file for the summary that belongs to the given checkpoint file: `job.checkpoint` -> `job.summary.json`.
+    pub(crate) fn file_for_checkpoint(checkpoint_file: &Path) -> PathBuf {
+        checkpoint_file.with_extension("summary.json")
+    }
+
+    /// Writes the summary as JSON into the given file.
+    pub(crate) fn save(&self, path: &Path) -> Result<(), NCError> {
+        debug!("NCJobSummary::save()");
+
+        let json = serde_json::to_string_pretty(self).map_err(|e| NCError::custom(format!("Could not serialize job summary: {}", e)))?;
+        fs::write(path, json)?;
+        Ok(())
+    }
+}
+
+impl Display for NCJobSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let chunks = match (self.done_chunks, self.total_chunks) {
+            (Some(done), Some(total)) => format!("{} / {}", done, total),
+            _ => "unknown".to_string(),
+        };
+
+        writeln!(f, "Job {}, wall time: {:.1} s", self.end_reason, self.wall_time.as_secs_f64())?;
+        writeln!(f, "Chunks done: {}, sent: {}, retries: {}, failed: {}, duplicates dropped: {}, empty: {}, skipped: {}",
+            chunks, self.chunks_sent, self.retries, self.failed_chunks, self.duplicates_dropped, self.empty_chunks, self.skipped_chunks)?;
+        write!(f, "Results: {} bytes", self.result_bytes)?;
+
+        if let Some(sample) = self.sample {
+            write!(f, "\nSampled run: {}, left out: {}", sample, self.chunks_left_out)?;
+        }
+
+        if self.local_chunks + self.locality_fallbacks > 0 {
+            write!(f, "\nLocality: {} chunks sent to a node with their shard, {} fallbacks", self.local_chunks, self.locality_fallbacks)?;
+        }
+
+        for violation in self.strict_violations.iter() {
+            write!(f, "\nStrict mode violation: {}", violation)?;
+        }
+
+        for node in self.nodes.iter() {
+            write!(f, "\nNode {}: {} results, {} bytes, busy: {:.1} s", node.node_id, node.results, node.result_bytes, node.busy_time.as_secs_f64())?;
+        }
+
+        for dead_chunk in self.dead_chunks.iter() {
+            write!(f, "\nDead chunk {}: {} attempts, last error: {}", dead_chunk.chunk_id, dead_chunk.attempts, dead_chunk.last_error)?;
+        }
+
+        for (chunk_id, attempts) in self.chunk_history.iter() {
+            let outcomes: Vec<String> = attempts.iter().map(|attempt| format!("{} ({})", attempt.outcome, attempt.node_id)).collect();
+            write!(f, "\nChunk {}: {}", chunk_id, outcomes.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Collects the numbers for the NCJobSummary while the server is running.
+#[cfg(feature = "net")]
+#[derive(Debug, Default)]
+pub(crate) struct NCJobStats {
+    /// The chunks that have been sent in the current phase.
+    sent_chunks: HashSet<ChunkID>,
+    /// Number of different chunks sent in all phases.
+    chunks_sent: u64,
+    /// See the fields of NCJobSummary with the same name.
+    retries: u64,
+    failed_chunks: u64,
+    result_bytes: u64,
+    nodes: Vec<NCNodeContribution>,
+    strict_violations: Vec<String>,
+    sample: Option<NCSample>,
+    chunks_left_out: u64,
+    local_chunks: u64,
+    locality_fallbacks: u64,
+    /// The attempts of the chunks that are not done yet or needed more than one attempt.
+    attempts: HashMap<ChunkID, VecDeque<NCChunkAttempt>>,
+    /// When the running attempts have started.
+    running: HashMap<(ChunkID, NodeID), Instant>,
+    /// How often the chunks of the current phase have been sent, unlike the log of the attempts this is not bounded.
+    attempt_counts: HashMap<ChunkID, u32>,
+    /// How the last attempt of a chunk has ended without a result.
+    last_errors: HashMap<ChunkID, String>,
+    /// The dead-letter list, see the module documentation.
+    dead_chunks: BTreeMap<ChunkID, NCDeadChunk>,
+}
+
+#[cfg(feature = "net")]
+impl NCJobStats {
+    /// Counts the given chunk as sent to the given node, the second time it's a retry. A new attempt starts.
+    /// Returns true if the chunk has been sent for the first time in this phase, see chunk_not_sent().
+    pub(crate) fn chunk_sent(&mut self, chunk_id: ChunkID, node_id: NodeID) -> bool {
+        let first = self.sent_chunks.insert(chunk_id);
+
+        if first {
+            self.chunks_sent += 1;
+        } else {
+            self.retries += 1;
+        }
+
+        let attempts = self.attempts.entry(chunk_id).or_default();
+        attempts.push_back(NCChunkAttempt { node_id, assigned_at: SystemTime::now(), outcome: NCAttemptOutcome::Running, duration: Duration::ZERO });
+
+        if attempts.len() > MAX_CHUNK_ATTEMPTS {
+            attempts.pop_front();
+        }
+
+        self.running.insert((chunk_id, node_id), Instant::now());
+        *self.attempt_counts.entry(chunk_id).or_default() += 1;
+        first
+    }
+
+    /// Takes back chunk_sent() for the given chunk and node, the chunk could not be sent after all.
+    /// first is the return value of chunk_sent().
+    pub(crate) fn chunk_not_sent(&mut self, chunk_id: ChunkID, node_id: NodeID, first: bool) {
+        if self.running.remove(&(chunk_id, node_id)).is_none() {
+            // The attempt has already ended (for example the node has failed in the meantime), so it counts
+            return
+        }
+
+        if first {
+            self.sent_chunks.remove(&chunk_id);
+            self.chunks_sent -= 1;
+        } else {
+            self.retries -= 1;
+        }
+
+        if let Some(attempts) = self.attempts.get_mut(&chunk_id) {
+            if let Some(index) = attempts.iter().rposition(|attempt| attempt.node_id == node_id && attempt.outcome == NCAttemptOutcome::Running) {
+                attempts.remove(index);
+            }
+
+            if attempts.is_empty() {
+                self.attempts.remove(&chunk_id);
+            }
+        }
+
+        if let Some(attempts) = self.attempt_counts.get_mut(&chunk_id) {
+            *attempts -= 1;
+
+            if *attempts == 0 {
+                self.attempt_counts.remove(&chunk_id);
+            }
+        }
+    }
+
+    /// Ends the running attempt of the given node for the given chunk, if any.
+    pub(crate) fn attempt_finished(&mut self, chunk_id: ChunkID, node_id: NodeID, outcome: NCAttemptOutcome) {
+        let started = match self.running.remove(&(chunk_id, node_id)) {
+            Some(started) => started,
+            None => return,
+        };
+
+        let attempt = self.attempts.get_mut(&chunk_id)
+            .and_then(|attempts| attempts.iter_mut().rev().find(|attempt| attempt.node_id == node_id && attempt.outcome == NCAttemptOutcome::Running));
+
+        if let Some(attempt) = attempt {
+            attempt.outcome = outcome;
+            attempt.duration = started.elapsed();
+        }
+
+        if !matches!(outcome, NCAttemptOutcome::Running | NCAttemptOutcome::Success) {
+            self.last_errors.insert(chunk_id, outcome.to_string());
+        }
+    }
+
+    /// The given node has failed with the given error, this ends the attempt for the chunk that it got first.
+    pub(crate) fn node_failed(&mut self, node_id: NodeID, job_error: &NCJobError) {
+        let chunk_id = self.running.iter()
+            .filter(|((_, other), _)| *other == node_id)
+            .min_by_key(|(_, started)| **started)
+            .map(|((chunk_id, _), _)| *chunk_id);
+
+        if let Some(chunk_id) = chunk_id {
+            self.attempt_finished(chunk_id, node_id, NCAttemptOutcome::Failed(job_error.code));
+            self.last_errors.insert(chunk_id, job_error.to_string());
+        }
+    }
+
+    /// Ends all running attempts of the given node with the given outcome, for example after a heartbeat timeout.
+    pub(crate) fn node_released(&mut self, node_id: NodeID, outcome: NCAttemptOutcome) {
+        let chunks: Vec<ChunkID> = self.running.keys().filter(|(_, other)| *other == node_id).map(|(chunk_id, _)| *chunk_id).collect();
+
+        for chunk_id in chunks {
+            self.attempt_finished(chunk_id, node_id, outcome);
+        }
+    }
+
+    /// Ends all running attempts for the given chunk with the given outcome, for example when an offline batch has expired.
+    pub(crate) fn chunk_released(&mut self, chunk_id: ChunkID, outcome: NCAttemptOutcome) {
+        let nodes: Vec<NodeID> = self.running.keys().filter(|(other, _)| *other == chunk_id).map(|(_, node_id)| *node_id).collect();
+
+        for node_id in nodes {
+            self.attempt_finished(chunk_id, node_id, outcome);
+        }
+    }
+
+    /// The server could not process the result of the given node for the given chunk, the successful attempt has failed after all.
+    pub(crate) fn result_rejected(&mut self, chunk_id: ChunkID, node_id: NodeID) {
+        let attempt = self.attempts.get_mut(&chunk_id)
+            .and_then(|attempts| attempts.iter_mut().rev().find(|attempt| attempt.node_id == node_id && attempt.outcome == NCAttemptOutcome::Success));
+
+        if let Some(attempt) = attempt {
+            attempt.outcome = NCAttemptOutcome::Failed(NCJobError::RESULT_REJECTED);
+            self.last_errors.insert(chunk_id, attempt.outcome.to_string());
+        }
+    }
+
+    /// How often the given chunk has been sent to a node, 0 if it has not been sent or is done already.
+    pub(crate) fn attempt(&self, chunk_id: ChunkID) -> u32 {
+        self.attempt_counts.get(&chunk_id).copied().unwrap_or_default()
+    }
+
+    /// Returns true if the given chunk is on the dead-letter list or has been sent max_attempts times (0 = no limit).
+    pub(crate) fn attempts_exhausted(&self, chunk_id: ChunkID, max_attempts: u32) -> bool {
+        self.dead_chunks.contains_key(&chunk_id) ||
+            (max_attempts > 0 && self.attempt_counts.get(&chunk_id).is_some_and(|attempts| *attempts >= max_attempts))
+    }
+
+    /// Moves the given chunk to the dead-letter list, if it's not there yet, and returns its entry.
+    pub(crate) fn chunk_dead(&mut self, chunk_id: ChunkID) -> NCDeadChunk {
+        let attempts = self.attempt_counts.get(&chunk_id).copied().unwrap_or_default();
+        let last_error = self.last_errors.get(&chunk_id).cloned().unwrap_or_else(|| "unknown".to_string());
+
+        self.dead_chunks.entry(chunk_id).or_insert(NCDeadChunk { chunk_id, attempts, last_error }).clone()
+    }
+
+    /// Takes the given chunk from the dead-letter list, it gets max_chunk_attempts new attempts.
+    /// Returns false if the chunk isn't on the list.
+    pub(crate) fn reset_dead_chunk(&mut self, chunk_id: ChunkID) -> bool {
+        self.attempt_counts.remove(&chunk_id);
+        self.last_errors.remove(&chunk_id);
+        self.dead_chunks.remove(&chunk_id).is_some()
+    }
+
+    /// The dead-letter list for the checkpoint.
+    pub(crate) fn dead_chunks(&self) -> Vec<NCDeadChunk> {
+        self.dead_chunks.values().cloned().collect()
+    }
+
+    /// The dead-letter list from the checkpoint of an earlier run, these chunks are not sent again.
+    pub(crate) fn restore_dead_chunks(&mut self, dead_chunks: Vec<NCDeadChunk>) {
+        self.dead_chunks.extend(dead_chunks.into_iter().map(|dead_chunk| (dead_chunk.chunk_id, dead_chunk)));
+    }
+
+    /// The given chunk is done, its attempts are only kept if there has been more than one.
+    pub(crate) fn chunk_done(&mut self, chunk_id: ChunkID) {
+        self.attempt_counts.remove(&chunk_id);
+        self.last_errors.remove(&chunk_id);
+
+        if self.attempts.get(&chunk_id).is_some_and(|attempts| attempts.len() <= 1) {
+            self.attempts.remove(&chunk_id);
+            self.running.retain(|(other, _), _| *other != chunk_id);
+        }
+    }
+
+    /// The attempts for the given chunk so far, empty if the chunk hasn't been sent yet or has been done with the first attempt.
+    pub(crate) fn chunk_history(&self, chunk_id: ChunkID) -> Vec<NCChunkAttempt> {
+        self.attempts.get(&chunk_id).map(|attempts| attempts.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// A new phase starts, the chunk ids of the last phase may be used again.
+    pub(crate) fn phase_finished(&mut self) {
+        self.sent_chunks.clear();
+        self.attempts.clear();
+        self.running.clear();
+        self.attempt_counts.clear();
+        self.last_errors.clear();
+        self.dead_chunks.clear();
+    }
+
+    /// Counts a result of the given size from the given node, chunk_time is [`None`] if the server doesn't know the chunk of the result.
+    pub(crate) fn result_received(&mut self, node_id: NodeID, result_bytes: u64, chunk_time: Option<Duration>) {
+        self.result_bytes += result_bytes;
+
+        let index = match self.nodes.iter().position(|node| node.node_id == node_id) {
+            Some(index) => index,
+            None => {
+                self.nodes.push(NCNodeContribution { node_id, results: 0, result_bytes: 0, busy_time: Duration::ZERO });
+                self.nodes.len() - 1
+            }
+        };
+
+        let node = &mut self.nodes[index];
+        node.results += 1;
+        node.result_bytes += result_bytes;
+        node.busy_time += chunk_time.unwrap_or_default();
+    }
+
+    /// What the nodes have contributed so far.
+    pub(crate) fn nodes(&self) -> &[NCNodeContribution] {
+        &self.nodes
+    }
+
+    /// A node from before a restart of the server is back, it keeps what it has contributed so far.
+    pub(crate) fn restore_node(&mut self, contribution: NCNodeContribution) {
+        if contribution.results > 0 && !self.nodes.iter().any(|node| node.node_id == contribution.node_id) {
+            self.result_bytes += contribution.result_bytes;
+            self.nodes.push(contribution);
+        }
+    }
+
+    /// Adds a protocol anomaly in strict_mode.
+    pub(crate) fn strict_violation(&mut self, description: String) {
+        self.strict_violations.push(description);
+    }
+
+    /// Sets the sample of a sampled run.
+    pub(crate) fn set_sample(&mut self, sample: Option<NCSample>) {
+        self.sample = sample;
+    }
+
+    /// Counts a chunk that has been left out because it's not part of the sample.
+    pub(crate) fn chunk_left_out(&mut self) {
+        self.chunks_left_out += 1;
+    }
+
+    /// Counts a chunk that has been sent to a node with its shard (local) or without it (fallback), see the nc_locality module.
+    pub(crate) fn chunk_locality(&mut self, local: bool) {
+        if local {
+            self.local_chunks += 1;
+        } else {
+            self.locality_fallbacks += 1;
+        }
+    }
+
+    /// The number of chunks that have been sent to a node with their shard and the number of fallbacks.
+    pub(crate) fn locality(&self) -> (u64, u64) {
+        (self.local_chunks, self.locality_fallbacks)
+    }
+
+    /// Counts a chunk that has failed permanently.
+    pub(crate) fn chunk_failed(&mut self) {
+        self.failed_chunks += 1;
+    }
+
+    /// Creates the summary with the given values that the server knows at the end of the job.
+    /// progress is the result of job_progress(), the chunks are counted as (duplicates dropped, empty, skipped).
+    pub(crate) fn summary(&self, end_reason: NCJobEndReason, progress: Option<(u64, u64)>, chunks: (u64, u64, u64), wall_time: Duration) -> NCJobSummary {
+        let (duplicates_dropped, empty_chunks, skipped_chunks) = chunks;
+
+        NCJobSummary {
+            end_reason,
+            total_chunks: progress.map(|(_, total)| total),
+            done_chunks: progress.map(|(done, _)| done),
+            chunks_sent: self.chunks_sent,
+            retries: self.retries,
+            failed_chunks: self.failed_chunks,
+            duplicates_dropped,
+            empty_chunks,
+            skipped_chunks,
+            wall_time,
+            result_bytes: self.result_bytes,
+            nodes: self.nodes.clone(),
+            strict_violations: self.strict_violations.clone(),
+            chunk_history: self.attempts.iter()
+                .filter(|(_, attempts)| attempts.len() > 1)
+                .map(|(chunk_id, attempts)| (*chunk_id, attempts.iter().cloned().collect()))
+                .collect(),
+            sample: self.sample,
+            chunks_left_out: self.chunks_left_out,
+            dead_chunks: self.dead_chunks(),
+            local_chunks: self.local_chunks,
+            locality_fallbacks: self.locality_fallbacks,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_stats() {
+        let mut job_stats = NCJobStats::default();
+        let node_id1 = NodeID::random();
+        let node_id2 = NodeID::random();
+
+        job_stats.chunk_sent(0, node_id1);
+        job_stats.chunk_sent(1, node_id2);
+        job_stats.chunk_sent(0, node_id2);
+        job_stats.result_received(node_id1, 100, Some(Duration::from_secs(2)));
+        job_stats.result_received(node_id2, 50, None);
+        job_stats.result_received(node_id1, 10, Some(Duration::from_secs(1)));
+
+        // Chunk ids can be used again in the next phase
+        job_stats.phase_finished();
+        job_stats.chunk_sent(0, node_id1);
+        job_stats.chunk_failed();
+        job_stats.strict_violation("duplicate result".to_string());
+
+        let summary = job_stats.summary(NCJobEndReason::Finished, Some((2, 3)), (2, 1, 0), Duration::from_secs(10));
+
+        assert_eq!(summary.chunks_sent, 3);
+        assert_eq!(summary.retries, 1);
+        assert_eq!(summary.failed_chunks, 1);
+        assert_eq!(summary.duplicates_dropped, 2);
+        assert_eq!(summary.result_bytes, 160);
+        assert_eq!(summary.total_chunks, Some(3));
+        assert_eq!(summary.done_chunks, Some(2));
+        assert_eq!(summary.strict_violations, vec!["duplicate result".to_string()]);
+        assert_eq!(summary.nodes, vec![
+            NCNodeContribution { node_id: node_id1, results: 2, result_bytes: 110, busy_time: Duration::from_secs(3) },
+            NCNodeContribution { node_id: node_id2, results: 1, result_bytes: 50, busy_time: Duration::ZERO },
+        ]);
+    }
+
+    #[test]
+    fn test_chunk_history() {
+        let mut job_stats = NCJobStats::default();
+        let node_id1 = NodeID::random();
+        let node_id2 = NodeID::random();
+        let node_id3 = NodeID::random();
+
+        // Chunk 0: timeout on node 1, error on node 2, done by node 3
+        job_stats.chunk_sent(0, node_id1);
+        job_stats.chunk_sent(1, node_id1);
+        job_stats.node_released(node_id1, NCAttemptOutcome::Timeout);
+        job_stats.chunk_sent(0, node_id2);
+        job_stats.node_failed(node_id2, &NCJobError::new(7, "broken", true));
+        job_stats.chunk_sent(0, node_id3);
+        job_stats.attempt_finished(0, node_id3, NCAttemptOutcome::Success);
+        job_stats.chunk_done(0);
+
+        let outcomes: Vec<(NodeID, NCAttemptOutcome)> = job_stats.chunk_history(0).iter().map(|attempt| (attempt.node_id, attempt.outcome)).collect();
+        assert_eq!(outcomes, vec![(node_id1, NCAttemptOutcome::Timeout), (node_id2, NCAttemptOutcome::Failed(7)), (node_id3, NCAttemptOutcome::Success)]);
+
+        // Chunk 2 is done with the first attempt, its history is dropped
+        job_stats.chunk_sent(2, node_id3);
+        assert_eq!(job_stats.chunk_history(2).len(), 1);
+        job_stats.attempt_finished(2, node_id3, NCAttemptOutcome::Success);
+        job_stats.chunk_done(2);
+        assert!(job_stats.chunk_history(2).is_empty());
+
+        // Chunk 1 has timed out with node 1 and the result from node 2 is rejected
+        job_stats.chunk_sent(1, node_id2);
+        job_stats.attempt_finished(1, node_id2, NCAttemptOutcome::Success);
+        job_stats.result_rejected(1, node_id2);
+        assert_eq!(job_stats.chunk_history(1)[1].outcome, NCAttemptOutcome::Failed(NCJobError::RESULT_REJECTED));
+
+        // The log of a chunk is bounded
+        for _ in 0..20 {
+            job_stats.chunk_sent(3, node_id1);
+            job_stats.node_released(node_id1, NCAttemptOutcome::Released);
+        }
+        assert_eq!(job_stats.chunk_history(3).len(), MAX_CHUNK_ATTEMPTS);
+
+        let summary = job_stats.summary(NCJobEndReason::Finished, None, (0, 0, 0), Duration::from_secs(1));
+        assert_eq!(summary.chunk_history.keys().copied().collect::<Vec<ChunkID>>(), vec![0, 1, 3]);
+        assert!(summary.to_string().contains(&format!("Chunk 0: timeout ({}), failed: 7 ({}), success ({})", node_id1, node_id2, node_id3)));
+    }
+
+    #[test]
+    fn test_dead_chunks() {
+        let mut job_stats = NCJobStats::default();
+        let node_id = NodeID::random();
+
+        // The attempts are counted beyond the bounded log
+        for _ in 0..20 {
+            job_stats.chunk_sent(0, node_id);
+            job_stats.node_released(node_id, NCAttemptOutcome::Timeout);
+        }
+        job_stats.chunk_sent(0, node_id);
+        job_stats.node_failed(node_id, &NCJobError::new(3, "degenerate geometry", true));
+        assert!(job_stats.attempts_exhausted(0, 21));
+        assert!(!job_stats.attempts_exhausted(0, 22));
+        assert!(!job_stats.attempts_exhausted(0, 0));
+
+        let dead_chunk = job_stats.chunk_dead(0);
+        assert_eq!(dead_chunk, NCDeadChunk { chunk_id: 0, attempts: 21, last_error: "code: 3, message: 'degenerate geometry', retryable: true".to_string() });
+        assert!(job_stats.attempts_exhausted(0, 0));
+
+        // A done chunk starts from zero
+        job_stats.chunk_sent(1, node_id);
+        job_stats.node_released(node_id, NCAttemptOutcome::Timeout);
+        job_stats.chunk_done(1);
+        assert!(!job_stats.attempts_exhausted(1, 1));
+
+        // A chunk that could not be sent doesn't count, unless the node has failed in the meantime
+        let first = job_stats.chunk_sent(2, node_id);
+        assert!(job_stats.attempts_exhausted(2, 1));
+        job_stats.chunk_not_sent(2, node_id, first);
+        assert!(!job_stats.attempts_exhausted(2, 1));
+        assert!(job_stats.chunk_history(2).is_empty());
+        let first = job_stats.chunk_sent(2, node_id);
+        assert!(first);
+        job_stats.node_failed(node_id, &NCJobError::new(3, "degenerate geometry", true));
+        job_stats.chunk_not_sent(2, node_id, first);
+        assert!(job_stats.attempts_exhausted(2, 1));
+        assert_eq!(job_stats.chunk_history(2)[0].outcome, NCAttemptOutcome::Failed(3));
+        job_stats.chunk_done(2);
+
+        let summary = job_stats.summary(NCJobEndReason::ChunkDead(0), None, (0, 0, 0), Duration::from_secs(1));
+        assert_eq!(summary.dead_chunks, vec![dead_chunk.clone()]);
+        assert!(summary.to_string().starts_with("Job chunk 0 has run out of attempts"));
+        assert!(summary.to_string().contains("Dead chunk 0: 21 attempts, last error: code: 3"));
+
+        // The list comes back from the checkpoint and a chunk can be reset
+        let mut restored = NCJobStats::default();
+        restored.restore_dead_chunks(job_stats.dead_chunks());
+        assert!(restored.attempts_exhausted(0, 0));
+        assert!(restored.reset_dead_chunk(0));
+        assert!(!restored.reset_dead_chunk(0));
+        assert!(!restored.attempts_exhausted(0, 0));
+    }
+
+    #[test]
+    fn test_save_summary() {
+        let checkpoint_file = std::env::temp_dir().join(format!("nc_test_summary_{}.checkpoint", std::process::id()));
+        let summary_file = NCJobSummary::file_for_checkpoint(&checkpoint_file);
+        assert_eq!(summary_file.file_name().unwrap().to_str().unwrap(), format!("nc_test_summary_{}.summary.json", std::process::id()));
+
+        let mut job_stats = NCJobStats::default();
+        job_stats.result_received(NodeID::random(), 100, None);
+        let summary = job_stats.summary(NCJobEndReason::Aborted("test".to_string()), None, (0, 0, 0), Duration::from_millis(1500));
+
+        summary.save(&summary_file).unwrap();
+        let json = fs::read_to_string(&summary_file).unwrap();
+        fs::remove_file(&summary_file).unwrap();
+
+        assert!(json.contains("\"Aborted\": \"test\""));
+        assert_eq!(serde_json::from_str::<NCJobSummary>(&json).unwrap(), summary);
+        assert!(summary.to_string().starts_with("Job aborted: test, wall time: 1.5 s"));
+    }
+}
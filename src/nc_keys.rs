@@ -0,0 +1,309 @@
+//! This module contains the Ed25519 keys for signed results, for example in volunteer computing where it must be possible to prove later
+//! which node has produced which result (ed25519 feature).
+//!
+//! A node with signing_key_file in the [`NCConfiguration`](crate::NCConfiguration) sends its public key to the server after registration
+//! and signs every result over the chunk id and the hash of the serialized data. The server checks the signature with the public key of the node
+//! and passes it to the NCServer trait method process_data_with_meta() as part of the metadata of the chunk
+//! (see [`SIGNATURE_META_KEY`] and [`PUBLIC_KEY_META_KEY`]), so that it can be stored together with the result.
+//! If node_keys_file is set only the public keys in that file are accepted, see [`NCKeyRing`].
+//!
+//! Results with a wrong signature are handled like a failed node: the chunk is given to another node.
+//! Results that go through an aggregator are combined with other results and can't be signed, signing nodes always send
+//! their results to the server directly.
+
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use log::debug;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use crate::nc_error::NCError;
+use crate::array2d::ChunkID;
+
+/// The key of the signature (hex) in the metadata of the chunk that is given to the NCServer trait method process_data_with_meta().
+pub const SIGNATURE_META_KEY: &str = "nc.signature";
+
+/// The key of the public key of the node (hex) in the metadata of the chunk.
+pub const PUBLIC_KEY_META_KEY: &str = "nc.public_key";
+
+/// The start of every signed message, so that a signature of a result can't be used for anything else.
+const SIGNATURE_CONTEXT: &[u8] = b"node_crunch result v1";
+
+/// The public key of a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NCPublicKey([u8; 32]);
+
+impl NCPublicKey {
+    /// Creates the public key from its 32 bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::Signature`] error if there are not exactly 32 bytes or they are not a valid Ed25519 public key.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, NCError> {
+        let bytes: [u8; 32] = bytes.try_into().map_err(|_| NCError::Signature(format!("public key must be 32 bytes, got {}", bytes.len())))?;
+        VerifyingKey::from_bytes(&bytes).map_err(|e| NCError::Signature(format!("invalid public key: {}", e)))?;
+
+        Ok(NCPublicKey(bytes))
+    }
+
+    /// Parses the public key from 64 hex digits, for example a line of the keys file.
+    pub fn from_hex(hex: &str) -> Result<Self, NCError> {
+        Self::from_bytes(&decode_hex(hex)?)
+    }
+
+    /// Returns the 32 bytes of the key.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Checks the signature of the node over the given chunk id and the serialized result.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::Signature`] error if the signature doesn't match, for example because the data has been changed.
+    pub fn verify(&self, chunk_id: ChunkID, payload: &[u8], signature: &[u8]) -> Result<(), NCError> {
+        let verifying_key = VerifyingKey::from_bytes(&self.0).map_err(|e| NCError::Signature(format!("invalid public key: {}", e)))?;
+        let signature = Signature::from_slice(signature).map_err(|e| NCError::Signature(format!("invalid signature: {}", e)))?;
+
+        verifying_key.verify_strict(&signed_message(chunk_id, payload), &signature)
+            .map_err(|_| NCError::Signature(format!("wrong signature for chunk {}", chunk_id)))
+    }
+}
+
+impl Display for NCPublicKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", encode_hex(&self.0))
+    }
+}
+
+/// The keypair of a node, it signs the results that are sent to the server.
+#[derive(Clone)]
+pub struct NCKeypair {
+    signing_key: SigningKey,
+}
+
+impl NCKeypair {
+    /// Returns the public key that the server uses to check the signatures.
+    pub fn public_key(&self) -> NCPublicKey {
+        NCPublicKey(self.signing_key.verifying_key().to_bytes())
+    }
+
+    /// Signs the given chunk id and the serialized result, see [`NCPublicKey::verify()`].
+    pub fn sign(&self, chunk_id: ChunkID, payload: &[u8]) -> Vec<u8> {
+        self.signing_key.sign(&signed_message(chunk_id, payload)).to_bytes().to_vec()
+    }
+}
+
+impl fmt::Debug for NCKeypair {
+    // The secret key is never logged.
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "NCKeypair({})", self.public_key())
+    }
+}
+
+/// The public keys that the server accepts, loaded from node_keys_file in the [`NCConfiguration`](crate::NCConfiguration).
+/// The file has one public key (64 hex digits) per line, the rest of the line (for example the name of the volunteer) is ignored.
+/// Empty lines and lines that start with `#` are ignored too.
+#[derive(Debug, Clone, Default)]
+pub struct NCKeyRing {
+    keys: HashSet<NCPublicKey>,
+}
+
+impl NCKeyRing {
+    /// Loads the public keys from the given file.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::IOError`] error if the file can't be read and a [`NCError::Signature`] error if a line doesn't start with a valid key.
+    pub fn load(path: &Path) -> Result<Self, NCError> {
+        debug!("NCKeyRing::load()");
+
+        let mut keys = HashSet::new();
+
+        for (index, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+
+            let hex = line.split_whitespace().next().unwrap_or_default();
+            let key = NCPublicKey::from_hex(hex).map_err(|e| match e {
+                NCError::Signature(message) => NCError::Signature(format!("{}, line {}: {}", path.display(), index + 1, message)),
+                e => e,
+            })?;
+            keys.insert(key);
+        }
+
+        Ok(NCKeyRing { keys })
+    }
+
+    /// Adds the given key.
+    pub fn insert(&mut self, key: NCPublicKey) {
+        self.keys.insert(key);
+    }
+
+    /// Returns true if the given key is in the key ring.
+    pub fn contains(&self, key: &NCPublicKey) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// Returns the number of keys.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Returns true if there are no keys.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// Creates a new keypair and writes the secret key (hex) to the given file and the public key to the same file with `.pub` appended,
+/// that line can be added to the keys file of the server. An existing file is not overwritten.
+///
+/// # Errors
+///
+/// Returns a [`NCError::IOError`] error if one of the files exists already or can't be written.
+pub fn generate(path: &Path) -> Result<NCKeypair, NCError> {
+    debug!("nc_keys::generate()");
+
+    let seed: [u8; 32] = rand::thread_rng().gen();
+    let keypair = NCKeypair { signing_key: SigningKey::from_bytes(&seed) };
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    writeln!(options.open(path)?, "{}", encode_hex(&seed))?;
+    writeln!(OpenOptions::new().write(true).create_new(true).open(public_key_path(path))?, "{}", keypair.public_key())?;
+
+    Ok(keypair)
+}
+
+/// Loads the keypair from the secret key file that has been written by [`generate()`].
+///
+/// # Errors
+///
+/// Returns a [`NCError::IOError`] error if the file can't be read and a [`NCError::Signature`] error if it doesn't contain a valid key.
+pub fn load(path: &Path) -> Result<NCKeypair, NCError> {
+    debug!("nc_keys::load()");
+
+    let seed: [u8; 32] = decode_hex(fs::read_to_string(path)?.trim())?.try_into()
+        .map_err(|_| NCError::Signature(format!("{}: secret key must be 32 bytes", path.display())))?;
+
+    Ok(NCKeypair { signing_key: SigningKey::from_bytes(&seed) })
+}
+
+/// Returns the file with the public key for the given secret key file, see [`generate()`].
+pub fn public_key_path(path: &Path) -> PathBuf {
+    let mut file_name = path.as_os_str().to_owned();
+    file_name.push(".pub");
+    PathBuf::from(file_name)
+}
+
+/// Returns the hex digits of the given bytes, for example for the signature in the metadata of the chunk.
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Returns the bytes of the given hex digits. The error doesn't contain the digits, since they may be a secret key.
+fn decode_hex(hex: &str) -> Result<Vec<u8>, NCError> {
+    let invalid_hex = || NCError::Signature(format!("invalid hex ({} chars)", hex.len()));
+
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(invalid_hex())
+    }
+
+    (0..hex.len()).step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).map_err(|_| invalid_hex()))
+        .collect()
+}
+
+/// The message that is actually signed: the context, the chunk id and the hash of the serialized result.
+fn signed_message(chunk_id: ChunkID, payload: &[u8]) -> Vec<u8> {
+    let mut message = SIGNATURE_CONTEXT.to_vec();
+    message.extend_from_slice(&chunk_id.to_le_bytes());
+    message.extend_from_slice(&Sha256::digest(payload));
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a path in the temp folder that doesn't exist yet.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("nc_keys_{}_{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(public_key_path(&path));
+        path
+    }
+
+    #[test]
+    fn test_generate_and_load() {
+        let path = temp_path("generate");
+        let keypair = generate(&path).unwrap();
+
+        assert!(matches!(generate(&path), Err(NCError::IOError(_))));
+        assert_eq!(load(&path).unwrap().public_key(), keypair.public_key());
+
+        let public_key = fs::read_to_string(public_key_path(&path)).unwrap();
+        assert_eq!(NCPublicKey::from_hex(public_key.trim()).unwrap(), keypair.public_key());
+        assert!(!format!("{:?}", keypair).contains(&fs::read_to_string(&path).unwrap().trim().to_string()));
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(public_key_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let path = temp_path("sign");
+        let keypair = generate(&path).unwrap();
+        let public_key = keypair.public_key();
+        let payload = bincode::serialize(&vec![1u32, 2, 3]).unwrap();
+        let signature = keypair.sign(7, &payload);
+
+        assert!(public_key.verify(7, &payload, &signature).is_ok());
+
+        // Tampered payload, other chunk, broken signature
+        let mut tampered = payload.clone();
+        tampered[8] ^= 1;
+        assert!(matches!(public_key.verify(7, &tampered, &signature), Err(NCError::Signature(_))));
+        assert!(matches!(public_key.verify(8, &payload, &signature), Err(NCError::Signature(_))));
+        assert!(matches!(public_key.verify(7, &payload, &signature[1..]), Err(NCError::Signature(_))));
+
+        // Another key
+        let other_path = temp_path("sign_other");
+        let other_key = generate(&other_path).unwrap().public_key();
+        assert!(matches!(other_key.verify(7, &payload, &signature), Err(NCError::Signature(_))));
+
+        let keys_path = temp_path("keys");
+        fs::write(&keys_path, format!("# volunteers\n\n{} alice\n", public_key)).unwrap();
+        let key_ring = NCKeyRing::load(&keys_path).unwrap();
+        assert_eq!(key_ring.len(), 1);
+        assert!(key_ring.contains(&public_key));
+        assert!(!key_ring.contains(&other_key));
+
+        fs::write(&keys_path, "1234 bob\n").unwrap();
+        assert!(matches!(NCKeyRing::load(&keys_path), Err(NCError::Signature(e)) if e.ends_with("line 1: public key must be 32 bytes, got 2")));
+
+        for path in [path, other_path] {
+            fs::remove_file(public_key_path(&path)).unwrap();
+            fs::remove_file(path).unwrap();
+        }
+
+        fs::remove_file(keys_path).unwrap();
+    }
+}
@@ -0,0 +1,356 @@
+//! This module contains the listener of the server. Depending on bind_mode in the NCConfiguration the server listens on all IPv4 addresses
+//! (BindMode::V4), on all IPv6 addresses (BindMode::V6) or on both (BindMode::DualStack).
+//!
+//! With BindMode::DualStack the IPv6 socket is bound first. If the system accepts IPv4 clients on IPv6 sockets
+//! (Linux with `net.ipv6.bindv6only=0`) the IPv4 socket can't be bound to the same port and isn't needed either, so the server runs
+//! with the IPv6 socket alone. Otherwise the server has two sockets: each one gets its own accept thread and the connections of both
+//! are handled by the same main loop of the server.
+//! If only one family can be bound a warning is logged, with dual_stack_required the server doesn't start.
+//!
+//! With NCTransportKind::SharedMemory in the NCConfiguration (shmem feature) the server also accepts shared memory connections
+//! in the given directory, they get their own accept thread too. The port is still used for the heartbeat and by nodes that use tcp.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use log::{debug, info, warn};
+
+use crate::nc_config::{NCConfiguration, BindMode, NCTransportKind};
+use crate::nc_error::NCError;
+use crate::nc_transport::NCStream;
+#[cfg(feature = "shmem")]
+use crate::nc_shmem::NCShmemListener;
+
+/// An accepted connection or the error from accept().
+type Accepted = io::Result<(NCStream, SocketAddr)>;
+
+/// One or more sockets of the server on the same port.
+pub(crate) struct NCListener {
+    /// The sockets, there is at least one.
+    listeners: Vec<TcpListener>,
+    /// The directory for the shared memory connections (shmem feature), see with_transport().
+    #[cfg(feature = "shmem")]
+    shmem: Option<Arc<NCShmemListener>>,
+    /// The connections of all the sockets if there is more than one, the accept threads send them here.
+    accepted: Option<Mutex<mpsc::Receiver<Accepted>>>,
+    /// For the accept thread of the shared memory connections, see with_transport().
+    #[cfg(feature = "shmem")]
+    sender: Option<mpsc::Sender<Accepted>>,
+    /// Tells the accept threads to exit, see drop().
+    stop: Arc<AtomicBool>,
+}
+
+/// Takes the connections from accept() until the listener is dropped, see NCListener::from_listeners().
+fn spawn_accept_thread<F: FnMut() -> Accepted + Send + 'static>(mut accept: F, sender: mpsc::Sender<Accepted>, stop: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        loop {
+            let accepted = accept();
+
+            if stop.load(Ordering::Relaxed) || sender.send(accepted).is_err() {
+                break
+            }
+        }
+    });
+}
+
+/// Accepts the next tcp connection, the stream blocks again also on systems where it inherits the non blocking mode of the listener.
+fn accept_tcp(listener: &TcpListener) -> Accepted {
+    let (stream, addr) = listener.accept()?;
+    stream.set_nonblocking(false)?;
+    Ok((Box::new(stream), addr))
+}
+
+impl NCListener {
+    /// Binds the sockets for the bind_mode and the port in the given configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::IOError`] if no socket could be bound, or with BindMode::DualStack and dual_stack_required if one of them
+    /// could not be bound.
+    pub(crate) fn bind(config: &NCConfiguration) -> Result<Self, NCError> {
+        debug!("NCListener::bind()");
+
+        match config.bind_mode {
+            BindMode::V4 => Ok(TcpListener::bind((Ipv4Addr::UNSPECIFIED, config.port))?.into()),
+            BindMode::V6 => Ok(TcpListener::bind((Ipv6Addr::UNSPECIFIED, config.port))?.into()),
+            BindMode::DualStack => Self::bind_dual_stack(config.port, config.dual_stack_required),
+        }
+    }
+
+    /// Binds the IPv6 socket and then the IPv4 socket on the same port (also if the port is 0).
+    fn bind_dual_stack(port: u16, required: bool) -> Result<Self, NCError> {
+        let v6 = TcpListener::bind((Ipv6Addr::UNSPECIFIED, port));
+        let v4_port = v6.as_ref().ok().and_then(|v6| v6.local_addr().ok()).map_or(port, |addr| addr.port());
+        let v4 = TcpListener::bind((Ipv4Addr::UNSPECIFIED, v4_port));
+
+        let (listener, family, e) = match (v6, v4) {
+            (Ok(v6), Ok(v4)) => return Self::from_listeners(vec![v6, v4]),
+            (Ok(v6), Err(e)) if e.kind() == io::ErrorKind::AddrInUse => {
+                info!("The IPv6 socket on port {} accepts IPv4 clients too", v4_port);
+                return Ok(v6.into())
+            }
+            (Ok(v6), Err(e)) => (v6, "IPv4", e),
+            (Err(e), Ok(v4)) => (v4, "IPv6", e),
+            (Err(e), Err(_)) => return Err(e.into()),
+        };
+
+        if required {
+            return Err(io::Error::new(e.kind(), format!("could not bind the {} socket on port {}: {}", family, v4_port, e)).into())
+        }
+
+        warn!("Could not bind the {} socket on port {}, only {} is used: {}", family, v4_port, listener.local_addr()?, e);
+        Ok(listener.into())
+    }
+
+    /// Uses the given sockets, starts an accept thread for each one if there is more than one.
+    pub(crate) fn from_listeners(listeners: Vec<TcpListener>) -> Result<Self, NCError> {
+        debug!("NCListener::from_listeners()");
+
+        let stop = Arc::new(AtomicBool::new(false));
+
+        if listeners.len() < 2 {
+            return Ok(NCListener::single(listeners, stop))
+        }
+
+        let (sender, receiver) = mpsc::channel();
+
+        for listener in &listeners {
+            let listener = listener.try_clone()?;
+            spawn_accept_thread(move || accept_tcp(&listener), sender.clone(), stop.clone());
+        }
+
+        info!("Listening on {:?}", listeners.iter().filter_map(|listener| listener.local_addr().ok()).collect::<Vec<_>>());
+
+        Ok(NCListener {
+            listeners,
+            #[cfg(feature = "shmem")]
+            shmem: None,
+            accepted: Some(Mutex::new(receiver)),
+            #[cfg(feature = "shmem")]
+            sender: Some(sender),
+            stop,
+        })
+    }
+
+    /// The sockets without accept threads.
+    fn single(listeners: Vec<TcpListener>, stop: Arc<AtomicBool>) -> Self {
+        NCListener {
+            listeners,
+            #[cfg(feature = "shmem")]
+            shmem: None,
+            accepted: None,
+            #[cfg(feature = "shmem")]
+            sender: None,
+            stop,
+        }
+    }
+
+    /// Also accepts the shared memory connections in the directory of NCTransportKind::SharedMemory (shmem feature), see the nc_shmem module.
+    /// With NCTransportKind::Tcp or if the listener accepts shared memory connections already it's returned as is.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::SharedMemory`] error without the shmem feature or if another server uses the directory.
+    pub(crate) fn with_transport(self, transport: &NCTransportKind) -> Result<Self, NCError> {
+        debug!("NCListener::with_transport()");
+
+        match transport {
+            NCTransportKind::Tcp => Ok(self),
+            #[cfg(feature = "shmem")]
+            NCTransportKind::SharedMemory { .. } if self.shmem.is_some() => Ok(self),
+            #[cfg(feature = "shmem")]
+            NCTransportKind::SharedMemory { path } => {
+                let mut listener = self;
+                let shmem = Arc::new(NCShmemListener::bind(path)?);
+
+                // The tcp socket needs an accept thread now too
+                if listener.accepted.is_none() {
+                    let (sender, receiver) = mpsc::channel();
+                    let tcp_listener = listener.listeners[0].try_clone()?;
+                    spawn_accept_thread(move || accept_tcp(&tcp_listener), sender.clone(), listener.stop.clone());
+                    listener.accepted = Some(Mutex::new(receiver));
+                    listener.sender = Some(sender);
+                }
+
+                if let Some(sender) = &listener.sender {
+                    let shmem = shmem.clone();
+                    spawn_accept_thread(move || shmem.accept().map(|stream| (Box::new(stream) as NCStream, NCShmemListener::peer_addr())),
+                        sender.clone(), listener.stop.clone());
+                }
+
+                info!("Listening for shared memory connections in {}", path.display());
+                listener.shmem = Some(shmem);
+                Ok(listener)
+            }
+            #[cfg(not(feature = "shmem"))]
+            NCTransportKind::SharedMemory { .. } => Err(NCError::SharedMemory("transport NCTransportKind::SharedMemory needs the shmem feature".to_string())),
+        }
+    }
+
+    /// Locks the connections from the accept threads, only the main loop of the server uses them.
+    fn lock(accepted: &Mutex<mpsc::Receiver<Accepted>>) -> io::Result<MutexGuard<'_, mpsc::Receiver<Accepted>>> {
+        accepted.lock().map_err(|_| io::Error::other("the listener lock is poisoned"))
+    }
+
+    /// Waits for the next connection on any of the sockets.
+    pub(crate) fn accept(&self) -> Accepted {
+        match &self.accepted {
+            None => accept_tcp(&self.listeners[0]),
+            Some(accepted) => Self::lock(accepted)?.recv().map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "the accept threads have exited"))?,
+        }
+    }
+
+    /// Returns the next connection if there is one, without waiting. Call set_nonblocking() before.
+    pub(crate) fn try_accept(&self) -> io::Result<Option<(NCStream, SocketAddr)>> {
+        let accepted = match &self.accepted {
+            None => accept_tcp(&self.listeners[0]),
+            Some(accepted) => match Self::lock(accepted)?.try_recv() {
+                Ok(accepted) => accepted,
+                Err(mpsc::TryRecvError::Empty) => return Ok(None),
+                Err(mpsc::TryRecvError::Disconnected) => return Err(io::Error::new(io::ErrorKind::NotConnected, "the accept threads have exited")),
+            },
+        };
+
+        match accepted {
+            Ok(accepted) => Ok(Some(accepted)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Lets try_accept() return right away. With more than one socket the accept threads keep waiting for connections.
+    pub(crate) fn set_nonblocking(&self) -> io::Result<()> {
+        match self.accepted {
+            None => self.listeners[0].set_nonblocking(true),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// The port of the sockets, it's the same for all of them.
+    pub(crate) fn port(&self) -> io::Result<u16> {
+        Ok(self.local_addr()?.port())
+    }
+
+    /// The address of the first socket, with BindMode::DualStack and two sockets that's the IPv6 one.
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+}
+
+impl From<TcpListener> for NCListener {
+    fn from(listener: TcpListener) -> Self {
+        NCListener::single(vec![listener], Arc::new(AtomicBool::new(false)))
+    }
+}
+
+impl Drop for NCListener {
+    /// Stops the accept threads: every thread is woken up with a connection to its socket and then exits, which closes the socket.
+    /// The accept thread of the shared memory connections is woken up by closing its listener.
+    fn drop(&mut self) {
+        if self.accepted.is_none() {
+            return
+        }
+
+        self.stop.store(true, Ordering::Relaxed);
+
+        #[cfg(feature = "shmem")]
+        if let Some(shmem) = &self.shmem {
+            shmem.close();
+        }
+
+        for listener in &self.listeners {
+            if let Ok(addr) = listener.local_addr() {
+                let ip = match addr.ip() {
+                    ip if !ip.is_unspecified() => ip,
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                };
+
+                let _ = TcpStream::connect((ip, addr.port()));
+            }
+        }
+    }
+}
+
+/// The address that the server uses to connect to itself (heartbeat, waking up the main loop).
+pub(crate) fn loopback(config: &NCConfiguration) -> IpAddr {
+    match config.bind_mode {
+        BindMode::V6 => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        BindMode::V4 | BindMode::DualStack => IpAddr::V4(Ipv4Addr::LOCALHOST),
+    }
+}
+
+/// The address family of a client, IPv4 addresses that arrive on an IPv6 socket (`::ffff:a.b.c.d`) count as IPv4.
+pub(crate) fn is_ipv4(addr: &SocketAddr) -> bool {
+    match addr.ip() {
+        IpAddr::V4(_) => true,
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().is_some(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_accept_multiplexing() {
+        let v4 = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = v4.local_addr().unwrap().port();
+        let v6 = TcpListener::bind((Ipv6Addr::LOCALHOST, port)).unwrap();
+        let listener = NCListener::from_listeners(vec![v4, v6]).unwrap();
+        assert_eq!(listener.port().unwrap(), port);
+
+        let _client4 = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        let _client6 = TcpStream::connect((Ipv6Addr::LOCALHOST, port)).unwrap();
+        let mut families: Vec<bool> = (0..2).map(|_| is_ipv4(&listener.accept().unwrap().1)).collect();
+        families.sort_unstable();
+        assert_eq!(families, vec![false, true]);
+
+        // Nothing left
+        assert!(listener.try_accept().unwrap().is_none());
+        let _client4 = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        let start = Instant::now();
+
+        loop {
+            if let Some((_, addr)) = listener.try_accept().unwrap() {
+                assert!(is_ipv4(&addr));
+                break
+            }
+
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // The accept threads close the sockets
+        drop(listener);
+        let start = Instant::now();
+
+        while TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_ok() || TcpStream::connect((Ipv6Addr::LOCALHOST, port)).is_ok() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_bind_dual_stack() {
+        let config = NCConfiguration { port: 0, bind_mode: BindMode::DualStack, dual_stack_required: true, ..Default::default() };
+        let listener = NCListener::bind(&config).unwrap();
+        let port = listener.port().unwrap();
+
+        // One dual stack socket or two sockets, both families are accepted
+        let _client6 = TcpStream::connect((Ipv6Addr::LOCALHOST, port)).unwrap();
+        assert!(!is_ipv4(&listener.accept().unwrap().1));
+        let _client4 = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        assert!(is_ipv4(&listener.accept().unwrap().1));
+
+        // The port is taken
+        let config = NCConfiguration { port, ..config };
+        assert!(NCListener::bind(&config).is_err());
+        assert!(NCListener::bind(&NCConfiguration { bind_mode: BindMode::V4, ..config }).is_err());
+    }
+}
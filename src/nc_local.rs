@@ -0,0 +1,770 @@
+//! This module contains a runner that starts the server and several nodes inside one process, for quick experiments, benchmarks and tests.
+//! The server and every node run in their own thread and talk to each other over the loopback interface (or the shared memory transport,
+//! see transport in the NCConfiguration) with the real protocol, so the whole configuration (compression, encryption, heartbeat,
+//! retry counter, ...) is used like in a distributed job.
+//! See [`run()`].
+
+use std::any::Any;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+use log::{error, info, debug};
+
+use crate::nc_error::NCError;
+use crate::nc_config::NCConfiguration;
+use crate::nc_server::{NCServer, NCServerStarter};
+use crate::nc_node::{NCNode, NCNodeStarter, NodeExit};
+use crate::nc_job_summary::NCJobSummary;
+use crate::nc_listener::NCListener;
+
+/// A participant has exited.
+enum LocalEvent<S> {
+    /// The server has exited and returned the user data structure and the job summary (boxed, the summary is large).
+    Server(Box<Result<(S, NCJobSummary), NCError>>),
+    /// The node with the given index has exited.
+    Node(usize, Result<NodeExit, NCError>),
+}
+
+/// Turns the payload of a panic into an error.
+fn panic_error(who: &str, payload: Box<dyn Any + Send>) -> NCError {
+    let message = payload.downcast_ref::<&str>().map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown reason".to_string());
+
+    NCError::custom(format!("{} panicked: {}", who, message))
+}
+
+/// Runs the server and num_nodes nodes in this process until the job is done and returns the server data structure
+/// (so that the results can be checked) and the job summary.
+/// The nodes are created with node_factory(index), index goes from 0 to num_nodes - 1.
+///
+/// The server listens on 127.0.0.1 and the port from the configuration, port 0 lets the OS choose a free port.
+/// Once the server is done the nodes are stopped right away (instead of waiting for their retry counter).
+/// Keep delay_request_data small, since the last results are only noticed when a node asks for new data again
+/// (unless the NCServer trait method job_finished() is implemented).
+/// The server always runs with strict_mode (see the NCConfiguration), so that a protocol anomaly fails the run instead of being tolerated.
+/// All the nodes use the same configuration, so with node_id_file only one of them can run (see the nc_node_id_file module).
+///
+/// # Errors
+///
+/// Returns the first error of the server or one of the nodes. If a node fails (returns an error or panics) or all nodes have
+/// exited before the job is done, the job is aborted: the server processes the remaining results, calls finish_job() and exits,
+/// the other nodes are stopped.
+pub fn run<S, N, F>(config: &NCConfiguration, nc_server: S, mut node_factory: F, num_nodes: usize) -> Result<(S, NCJobSummary), NCError>
+    where S: NCServer + Send + 'static, N: NCNode + Send + 'static, N::ProcessedDataT: Send + 'static, F: FnMut(usize) -> N {
+    debug!("nc_local::run()");
+
+    if num_nodes == 0 {
+        return Err(NCError::custom("At least one node is needed"))
+    }
+
+    // The listener is opened first, so the nodes can connect right away.
+    let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), config.port))?;
+    let port = listener.local_addr()?.port();
+    let listener = NCListener::from(listener).with_transport(&config.transport)?;
+    let config = NCConfiguration { address: Ipv4Addr::LOCALHOST.to_string(), port, strict_mode: true, ..config.clone() };
+    let (sender, receiver) = mpsc::channel();
+
+    let mut server_starter = NCServerStarter::new(config.clone());
+    let job_done = server_starter.abort_handle();
+    let server_sender = sender.clone();
+
+    thread::spawn(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| server_starter.run(nc_server, listener)))
+            .unwrap_or_else(|payload| Err(panic_error("Server", payload)));
+        let _ = server_sender.send(LocalEvent::Server(Box::new(result)));
+    });
+
+    let mut node_stops = Vec::with_capacity(num_nodes);
+
+    for index in 0..num_nodes {
+        let nc_node = node_factory(index);
+        let mut node_starter = NCNodeStarter::new(config.clone());
+        let node_sender = sender.clone();
+        node_stops.push(node_starter.stop_handle());
+
+        thread::spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| node_starter.start(nc_node)))
+                .unwrap_or_else(|payload| Err(panic_error(&format!("Node {}", index), payload)));
+            let _ = node_sender.send(LocalEvent::Node(index, result));
+        });
+    }
+
+    // The loop below ends when all threads have sent their result.
+    drop(sender);
+
+    let abort = || {
+        job_done.store(true, Ordering::Relaxed);
+        // Wake up the main loop of the server, it checks the flag after every connection.
+        let _ = TcpStream::connect(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port));
+    };
+
+    let mut server_result = None;
+    let mut server_done = false;
+    let mut first_error = None;
+    let mut running_nodes = num_nodes;
+
+    for event in receiver {
+        match event {
+            LocalEvent::Server(result) => {
+                info!("Server has exited");
+                server_done = true;
+                node_stops.iter().for_each(|stop| stop.store(true, Ordering::Relaxed));
+
+                match *result {
+                    Ok(server_data) => server_result = Some(server_data),
+                    Err(e) => {
+                        error!("Server failed: {}", e);
+                        first_error.get_or_insert(e);
+                    }
+                }
+            }
+            LocalEvent::Node(index, result) => {
+                running_nodes -= 1;
+
+                if let Err(e) = result {
+                    error!("Node {} failed: {}, abort job", index, e);
+                    first_error.get_or_insert(e);
+                    abort();
+                } else if running_nodes == 0 && !server_done && !job_done.load(Ordering::Relaxed) {
+                    // Once the job is done the nodes may exit before the server, see NCServerStarter::linger().
+                    error!("All nodes have exited, abort job");
+                    first_error.get_or_insert(NCError::custom("All nodes have exited before the job was done"));
+                    abort();
+                }
+            }
+        }
+    }
+
+    match (first_error, server_result) {
+        (Some(e), _) => Err(e),
+        (None, Some(server_data)) => Ok(server_data),
+        (None, None) => Err(NCError::custom("Server has exited without a result")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::sync::atomic::{AtomicBool, AtomicU64};
+    use std::time::{Duration, Instant};
+
+    use crate::nc_node::NodeResult;
+    use crate::nc_node_info::NodeID;
+    use crate::nc_server::{ChunkAssignment, NCDeadChunkAction, NCServerMessage, NCJobStatus};
+    use crate::nc_job_summary::NCJobEndReason;
+    use crate::nc_error::NCJobError;
+    use crate::nc_communicator::NCCodec;
+    use crate::nc_checkpoint::NCCheckpoint;
+    use crate::nc_chunk_queue::ChunkQueueWaker;
+    use crate::array2d::{ChunkList, ChunkID};
+    use crate::nc_client::NCClient;
+    use crate::nc_node_id_file::NCNodeIdFile;
+
+    /// Sums up the squares of the numbers in the chunks.
+    struct SumServer {
+        chunk_list: ChunkList<u64>,
+        sum: u64,
+        /// The chunks in the order their results have been added to the sum.
+        processed: Vec<ChunkID>,
+        finished: bool,
+        /// When the last result has been added to the sum.
+        last_result: Option<Instant>,
+        /// Lets the nodes wait at the server instead of sleeping, see chunk_queue_waker().
+        waker: Option<ChunkQueueWaker>,
+        /// The chunks that have run out of attempts, see chunk_dead().
+        dead_chunks: Vec<ChunkID>,
+        /// Abort the job when a chunk has run out of attempts instead of continuing without it.
+        abort_dead_chunks: bool,
+    }
+
+    impl SumServer {
+        fn new(numbers: u64) -> Self {
+            let mut chunk_list = ChunkList::new();
+
+            for i in 0..numbers {
+                chunk_list.push(i);
+            }
+
+            SumServer { chunk_list, sum: 0, processed: Vec::new(), finished: false, last_result: None, waker: None, dead_chunks: Vec::new(),
+                abort_dead_chunks: false }
+        }
+    }
+
+    impl NCServer for SumServer {
+        type InitialDataT = ();
+        type NewDataT = (ChunkID, u64);
+        type ProcessedDataT = (ChunkID, u64);
+        type CustomMessageT = ();
+
+        fn assign_chunk(&mut self, node_id: NodeID) -> Result<ChunkAssignment<(ChunkID, u64)>, NCError> {
+            if let Some((chunk_id, chunk)) = self.chunk_list.assign_next_chunk(node_id) {
+                return Ok(ChunkAssignment::Assigned(chunk_id, (chunk_id, chunk.data)))
+            }
+
+            let (finished, total) = self.chunk_list.progress();
+
+            if finished == total {
+                Ok(ChunkAssignment::Finished)
+            } else {
+                Ok(ChunkAssignment::Waiting)
+            }
+        }
+
+        fn process_data_from_node(&mut self, node_id: NodeID, data: &(ChunkID, u64)) -> Result<(), NCError> {
+            let chunk = self.chunk_list.get(data.0 as usize);
+
+            if chunk.is_processing(node_id) {
+                chunk.set_finished();
+                self.sum += data.1;
+                self.processed.push(data.0);
+                self.last_result = Some(Instant::now());
+            }
+
+            Ok(())
+        }
+
+        fn job_finished(&self) -> bool {
+            let (finished, total) = self.chunk_list.progress();
+            finished == total
+        }
+
+        fn chunk_queue_waker(&mut self) -> Option<ChunkQueueWaker> {
+            self.waker.clone()
+        }
+
+        fn checkpoint(&mut self) -> Result<Option<NCCheckpoint>, NCError> {
+            NCCheckpoint::new(&self.chunk_list).map(Some)
+        }
+
+        fn heartbeat_timeout(&mut self, nodes: Vec<NodeID>) {
+            self.chunk_list.heartbeat_timeout(&nodes);
+        }
+
+        fn process_node_error(&mut self, node_id: NodeID, error: &NCJobError) {
+            self.chunk_list.chunk_failed(node_id, error.retryable)
+        }
+
+        fn chunk_rejected(&mut self, chunk_id: ChunkID, requeue: bool) {
+            self.chunk_list.chunk_rejected(chunk_id, requeue)
+        }
+
+        fn chunk_dead(&mut self, chunk_id: ChunkID, _attempts: u32, _last_error: &str) -> NCDeadChunkAction {
+            self.dead_chunks.push(chunk_id);
+
+            if self.abort_dead_chunks {
+                NCDeadChunkAction::AbortJob
+            } else {
+                NCDeadChunkAction::Continue
+            }
+        }
+
+        fn finish_job(&mut self) {
+            self.finished = true;
+        }
+    }
+
+    /// Squares the number, panics for the given number.
+    struct SquareNode {
+        panic_at: Option<u64>,
+    }
+
+    impl NCNode for SquareNode {
+        type InitialDataT = ();
+        type NewDataT = (ChunkID, u64);
+        type ProcessedDataT = (ChunkID, u64);
+        type CustomMessageT = ();
+
+        fn process_data_from_server(&mut self, data: &(ChunkID, u64)) -> Result<NodeResult<(ChunkID, u64)>, NCError> {
+            if self.panic_at == Some(data.1) {
+                panic!("bad number: {}", data.1)
+            }
+
+            Ok(NodeResult::Data((data.0, data.1 * data.1)))
+        }
+    }
+
+    /// Fails for the given number every time with a retryable error, squares the others.
+    struct FailingNode {
+        fail_at: u64,
+    }
+
+    impl NCNode for FailingNode {
+        type InitialDataT = ();
+        type NewDataT = (ChunkID, u64);
+        type ProcessedDataT = (ChunkID, u64);
+        type CustomMessageT = ();
+
+        fn process_data_from_server(&mut self, data: &(ChunkID, u64)) -> Result<NodeResult<(ChunkID, u64)>, NCError> {
+            if data.1 == self.fail_at {
+                return Err(NCError::Job(NCJobError::new(NCJobError::USER_ERROR, "degenerate geometry", true)))
+            }
+
+            Ok(NodeResult::Data((data.0, data.1 * data.1)))
+        }
+    }
+
+    /// Squares the number like SquareNode, but leaves the job after the given number of chunks (it stops itself).
+    struct SpotNode {
+        chunks_left: u32,
+        stop: Arc<AtomicBool>,
+    }
+
+    impl NCNode for SpotNode {
+        type InitialDataT = ();
+        type NewDataT = (ChunkID, u64);
+        type ProcessedDataT = (ChunkID, u64);
+        type CustomMessageT = ();
+
+        fn process_data_from_server(&mut self, data: &(ChunkID, u64)) -> Result<NodeResult<(ChunkID, u64)>, NCError> {
+            self.chunks_left = self.chunks_left.saturating_sub(1);
+
+            if self.chunks_left == 0 {
+                self.stop.store(true, Ordering::Relaxed);
+            }
+
+            Ok(NodeResult::Data((data.0, data.1 * data.1)))
+        }
+    }
+
+    /// One machine with spot instances for test_churn(), all of them use the node id file of the machine.
+    /// In every round one process takes a chunk and is gone without a result, then the next one processes a few chunks and leaves.
+    fn spot_rounds(config: &NCConfiguration, rounds: u32) -> Result<(), NCError> {
+        for _ in 0..rounds {
+            let mut node_id_file = NCNodeIdFile::new(config.node_id_file.as_ref().unwrap());
+            let mut nc_client = NCClient::connect(config)?;
+            let incarnation = match node_id_file.open()? {
+                Some((node_id, incarnation)) => {
+                    nc_client.register_again::<()>(node_id, incarnation + 1)?;
+                    incarnation + 1
+                }
+                None => {
+                    nc_client.register::<()>()?;
+                    0
+                }
+            };
+            node_id_file.save(nc_client.node_id(), incarnation);
+            assert!(matches!(nc_client.request_data::<(ChunkID, u64), ()>()?, NCServerMessage::JobStatus(NCJobStatus::Unfinished(..))));
+            drop((nc_client, node_id_file));
+
+            let mut node_starter = NCNodeStarter::new(config.clone());
+            let stop = node_starter.stop_handle();
+            assert_eq!(node_starter.start(SpotNode { chunks_left: 3, stop })?, NodeExit::StoppedByUser);
+        }
+
+        Ok(())
+    }
+
+    /// Hands out byte chunks, some of them empty, and records the lengths that come back.
+    struct BytesServer {
+        chunk_list: ChunkList<Vec<u8>>,
+        lengths: Vec<u64>,
+    }
+
+    impl NCServer for BytesServer {
+        type InitialDataT = ();
+        type NewDataT = Vec<u8>;
+        type ProcessedDataT = u64;
+        type CustomMessageT = ();
+
+        fn assign_chunk(&mut self, node_id: NodeID) -> Result<ChunkAssignment<Vec<u8>>, NCError> {
+            if let Some((chunk_id, chunk)) = self.chunk_list.assign_next_chunk(node_id) {
+                return Ok(ChunkAssignment::Assigned(chunk_id, chunk.data.clone()))
+            }
+
+            let (finished, total) = self.chunk_list.progress();
+            Ok(if finished == total { ChunkAssignment::Finished } else { ChunkAssignment::Waiting })
+        }
+
+        fn process_data_from_node(&mut self, node_id: NodeID, length: &u64) -> Result<(), NCError> {
+            if let Some(chunk_id) = self.chunk_list.chunks().iter().position(|chunk| chunk.is_processing(node_id)) {
+                self.chunk_list.get(chunk_id).set_finished();
+                self.lengths.push(*length);
+            }
+
+            Ok(())
+        }
+
+        fn heartbeat_timeout(&mut self, nodes: Vec<NodeID>) {
+            self.chunk_list.heartbeat_timeout(&nodes);
+        }
+
+        fn finish_job(&mut self) {}
+    }
+
+    struct LengthNode;
+
+    impl NCNode for LengthNode {
+        type InitialDataT = ();
+        type NewDataT = Vec<u8>;
+        type ProcessedDataT = u64;
+        type CustomMessageT = ();
+
+        fn process_data_from_server(&mut self, data: &Vec<u8>) -> Result<NodeResult<u64>, NCError> {
+            Ok(NodeResult::Data(data.len() as u64))
+        }
+    }
+
+    fn test_config() -> NCConfiguration {
+        NCConfiguration { port: 0, delay_request_data: 1, heartbeat: 1, ..Default::default() }
+    }
+
+    #[test]
+    fn test_empty_chunks() {
+        let config = NCConfiguration {
+            encrypt: true,
+            key: "u8PqN2lO3ZJbm6DHkTnR0cyVaSx1FWgi".to_string(),
+            allowed_codecs: vec![NCCodec::Lz4],
+            compression_min_size: 0,
+            type_check: true,
+            ..test_config()
+        };
+        let mut chunk_list = ChunkList::new();
+
+        for data in [vec![], vec![1, 2, 3], vec![], vec![4]] {
+            chunk_list.push(data);
+        }
+
+        // The empty chunks are processed like the others, the job only ends when all of them are done
+        let (nc_server, job_summary) = run(&config, BytesServer { chunk_list, lengths: Vec::new() }, |_| LengthNode, 2).unwrap();
+        assert_eq!(job_summary.end_reason, NCJobEndReason::Finished);
+        assert_eq!(nc_server.chunk_list.progress(), (4, 4));
+
+        let mut lengths = nc_server.lengths;
+        lengths.sort_unstable();
+        assert_eq!(lengths, vec![0, 0, 1, 3]);
+    }
+
+    #[test]
+    fn test_run() {
+        let config = NCConfiguration {
+            encrypt: true,
+            key: "u8PqN2lO3ZJbm6DHkTnR0cyVaSx1FWgi".to_string(),
+            allowed_codecs: vec![NCCodec::Zstd(3)],
+            ..test_config()
+        };
+
+        let (nc_server, job_summary) = run(&config, SumServer::new(50), |_| SquareNode { panic_at: None }, 3).unwrap();
+
+        assert!(nc_server.finished);
+        assert_eq!(nc_server.sum, (0..50).map(|i| i * i).sum::<u64>());
+        assert_eq!(job_summary.end_reason, NCJobEndReason::Finished);
+        assert_eq!(job_summary.chunks_sent, 50);
+        assert_eq!(job_summary.nodes.iter().map(|node| node.results).sum::<u64>(), 50);
+        assert_eq!(job_summary.result_bytes, 50 * 16);
+    }
+
+    #[test]
+    fn test_node_panic() {
+        let result = run(&test_config(), SumServer::new(50), |index| SquareNode { panic_at: if index == 1 { Some(10) } else { None } }, 2);
+
+        // Only node 1 panics if it gets chunk 10, if node 0 got it the job is done.
+        match result {
+            Err(e) => assert_eq!(e.to_string(), "Node 1 panicked: bad number: 10"),
+            Ok((nc_server, _)) => assert_eq!(nc_server.sum, (0..50).map(|i| i * i).sum::<u64>()),
+        }
+
+        let result = run(&test_config(), SumServer::new(5), |_| SquareNode { panic_at: Some(3) }, 2);
+        assert!(matches!(result, Err(e) if e.to_string().ends_with("panicked: bad number: 3")));
+    }
+
+    #[test]
+    fn test_no_nodes() {
+        assert!(run(&test_config(), SumServer::new(5), |_| SquareNode { panic_at: None }, 0).is_err());
+    }
+
+    #[test]
+    fn test_nodes_exit_when_job_finished() {
+        // Without the NCJobStatus::Finished the nodes would only exit after 100 retries, one every second.
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = NCConfiguration { address: Ipv4Addr::LOCALHOST.to_string(), port, retry_counter: 100, finish_linger_ms: 3000, ..test_config() };
+        let mut server_starter = NCServerStarter::new(config.clone());
+        let server_thread = thread::spawn(move || server_starter.run(SumServer::new(20), listener));
+        let (sender, receiver) = mpsc::channel();
+
+        for _ in 0..3 {
+            let config = config.clone();
+            let sender = sender.clone();
+
+            thread::spawn(move || {
+                let node_exit = NCNodeStarter::new(config).start(SquareNode { panic_at: None }).map_err(|e| e.to_string());
+                let _ = sender.send((node_exit, Instant::now()));
+            });
+        }
+
+        let (nc_server, _) = server_thread.join().unwrap().unwrap();
+        let server_exit = Instant::now();
+        assert!(nc_server.finished);
+
+        // The waiting nodes ask again after delay_request_data, that's within the linger time of the server.
+        for _ in 0..3 {
+            let (node_exit, exit_time) = receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert_eq!(node_exit, Ok(NodeExit::JobFinished));
+            assert!(exit_time <= server_exit);
+        }
+    }
+
+    #[test]
+    fn test_shutdown_latency() {
+        // The nodes poll every 3 seconds, but they all exit right after the last result: the node that has sent it
+        // learns it from the ResultAck message and the other nodes wait at the server (long poll) until the job ends.
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = NCConfiguration { address: Ipv4Addr::LOCALHOST.to_string(), port, delay_request_data: 3, long_poll_ms: 5000, ..test_config() };
+        let poll_interval = Duration::from_secs(config.delay_request_data);
+        let mut server_starter = NCServerStarter::new(config.clone());
+        let nc_server = SumServer { waker: Some(ChunkQueueWaker::new()), ..SumServer::new(20) };
+        let server_thread = thread::spawn(move || server_starter.run(nc_server, listener));
+        let (sender, receiver) = mpsc::channel();
+
+        for _ in 0..3 {
+            let config = config.clone();
+            let sender = sender.clone();
+
+            thread::spawn(move || {
+                let node_exit = NCNodeStarter::new(config).start(SquareNode { panic_at: None }).map_err(|e| e.to_string());
+                let _ = sender.send((node_exit, Instant::now()));
+            });
+        }
+
+        let (nc_server, job_summary) = server_thread.join().unwrap().unwrap();
+        assert_eq!(job_summary.end_reason, NCJobEndReason::Finished);
+        let last_result = nc_server.last_result.unwrap();
+
+        for _ in 0..3 {
+            let (node_exit, exit_time) = receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert_eq!(node_exit, Ok(NodeExit::JobFinished));
+            let latency = exit_time.saturating_duration_since(last_result);
+            assert!(latency < poll_interval, "latency: {:?}", latency);
+        }
+    }
+
+    #[test]
+    fn test_dead_chunk() {
+        let config = NCConfiguration { max_chunk_attempts: 3, ..test_config() };
+
+        // The chunk that fails on every node is given up after three attempts, the job ends without it
+        let (nc_server, job_summary) = run(&config, SumServer::new(10), |_| FailingNode { fail_at: 4 }, 2).unwrap();
+        assert_eq!(job_summary.end_reason, NCJobEndReason::Finished);
+        assert_eq!(nc_server.dead_chunks, vec![4]);
+        assert_eq!(nc_server.chunk_list.failed_chunks().collect::<Vec<ChunkID>>(), vec![4]);
+        assert_eq!(nc_server.sum, (0..10).filter(|i| *i != 4).map(|i| i * i).sum::<u64>());
+        assert_eq!(job_summary.failed_chunks, 1);
+        assert_eq!(job_summary.dead_chunks.len(), 1);
+        assert_eq!(job_summary.dead_chunks[0].attempts, 3);
+        assert!(job_summary.dead_chunks[0].last_error.contains("degenerate geometry"));
+
+        // Or the job is aborted
+        let nc_server = SumServer { abort_dead_chunks: true, ..SumServer::new(10) };
+        let (nc_server, job_summary) = run(&config, nc_server, |_| FailingNode { fail_at: 4 }, 2).unwrap();
+        assert_eq!(job_summary.end_reason, NCJobEndReason::ChunkDead(4));
+        assert_eq!(nc_server.dead_chunks, vec![4]);
+    }
+
+    #[test]
+    fn test_restart_from_checkpoint() {
+        let checkpoint_file = std::env::temp_dir().join(format!("nc_local_restart_{}.checkpoint", std::process::id()));
+        let config = NCConfiguration {
+            checkpoint_file: Some(checkpoint_file.clone()),
+            max_job_duration: Some(Duration::from_millis(200)),
+            job_drain_timeout: 0,
+            finish_linger_ms: 100,
+            ..test_config()
+        };
+
+        // The time is up while the nodes are still busy, the chunks they are processing are in the checkpoint
+        let (first_run, job_summary) = run(&config, SumServer::new(5000), |_| SquareNode { panic_at: None }, 4).unwrap();
+        assert_eq!(job_summary.end_reason, NCJobEndReason::TimeBudgetExhausted);
+        assert!(first_run.processed.len() < 5000);
+
+        let checkpoint = NCCheckpoint::load(&checkpoint_file).unwrap();
+        assert_eq!(checkpoint.chunk_list().stats().2 as usize, first_run.processed.len());
+        assert_eq!(checkpoint.nodes().len(), 4);
+
+        let second_run = SumServer { chunk_list: checkpoint.restore().unwrap(), ..first_run };
+        let config = NCConfiguration { max_job_duration: None, ..config };
+        let (second_run, job_summary) = run(&config, second_run, |_| SquareNode { panic_at: None }, 4).unwrap();
+        fs::remove_file(&checkpoint_file).unwrap();
+
+        // No chunk is lost or processed twice
+        assert_eq!(job_summary.end_reason, NCJobEndReason::Finished);
+        let mut processed = second_run.processed.clone();
+        processed.sort_unstable();
+        assert_eq!(processed, (0..5000).collect::<Vec<ChunkID>>());
+        assert_eq!(second_run.sum, (0..5000).map(|i| i * i).sum::<u64>());
+    }
+
+    #[test]
+    fn test_churn() {
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // The heartbeat timeout (73 seconds) never comes, the chunks of the vanished processes must be given out again when the next one joins
+        let config = NCConfiguration { address: Ipv4Addr::LOCALHOST.to_string(), port, heartbeat: 60, strict_mode: true, finish_linger_ms: 500, ..test_config() };
+        let start = Instant::now();
+        let mut server_starter = NCServerStarter::new(config.clone());
+        let server_thread = thread::spawn(move || server_starter.run(SumServer::new(80), listener));
+
+        // Four machines with spot instances, one node that leaves early and one that joins late.
+        // The rounds and the early node process at most 4 * 3 * 3 + 2 chunks, so the job can't be done before the machines stay for the rest of it.
+        let stay = Arc::new(Barrier::new(5));
+        let mut machines = Vec::new();
+
+        for machine in 0..4 {
+            let node_id_file = std::env::temp_dir().join(format!("nc_local_churn_{}_{}.id", std::process::id(), machine));
+            let _ = fs::remove_file(&node_id_file);
+            let config = NCConfiguration { node_id_file: Some(node_id_file), ..config.clone() };
+            let stay = stay.clone();
+
+            machines.push(thread::spawn(move || {
+                let rounds = spot_rounds(&config, 3);
+                stay.wait();
+                rounds?;
+                let node_exit = NCNodeStarter::new(config.clone()).start(SquareNode { panic_at: None });
+                let node_id_file = config.node_id_file.as_ref().unwrap();
+                fs::remove_file(node_id_file)?;
+                fs::remove_file(format!("{}.lock", node_id_file.display()))?;
+                node_exit
+            }));
+        }
+
+        let early_config = config.clone();
+        machines.push(thread::spawn(move || {
+            let mut node_starter = NCNodeStarter::new(early_config);
+            let stop = node_starter.stop_handle();
+            node_starter.start(SpotNode { chunks_left: 2, stop })
+        }));
+
+        machines.push(thread::spawn(move || {
+            stay.wait();
+            NCNodeStarter::new(config).start(SquareNode { panic_at: None })
+        }));
+
+        let node_exits: Vec<NodeExit> = machines.into_iter().map(|machine| machine.join().unwrap().unwrap()).collect();
+        let (nc_server, job_summary) = server_thread.join().unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(30), "{:?}", start.elapsed());
+        assert_eq!(node_exits[4], NodeExit::StoppedByUser);
+        assert_eq!(node_exits.iter().filter(|node_exit| **node_exit == NodeExit::JobFinished).count(), 5);
+
+        // Every chunk has been processed exactly once
+        assert_eq!(job_summary.end_reason, NCJobEndReason::Finished);
+        let mut processed = nc_server.processed.clone();
+        processed.sort_unstable();
+        assert_eq!(processed, (0..80).collect::<Vec<ChunkID>>());
+        assert_eq!(nc_server.sum, (0..80).map(|i| i * i).sum::<u64>());
+        assert_eq!(job_summary.nodes.iter().map(|node| node.results).sum::<u64>(), 80);
+
+        // All the processes of a machine have used the same node id
+        assert!(job_summary.nodes.len() <= 6, "{:?}", job_summary.nodes);
+    }
+
+    #[test]
+    fn test_node_id_file() {
+        let node_id_file = std::env::temp_dir().join(format!("nc_local_node_id_{}.id", std::process::id()));
+        let _ = fs::remove_file(&node_id_file);
+        let config = NCConfiguration { node_id_file: Some(node_id_file.clone()), ..test_config() };
+
+        // The node keeps its node id across restarts, also with a new server
+        let (_, first) = run(&config, SumServer::new(5), |_| SquareNode { panic_at: None }, 1).unwrap();
+        let (_, second) = run(&config, SumServer::new(5), |_| SquareNode { panic_at: None }, 1).unwrap();
+        assert_eq!(first.nodes.len(), 1);
+        assert_eq!(second.nodes[0].node_id, first.nodes[0].node_id);
+        assert_eq!(fs::read_to_string(&node_id_file).unwrap(), format!("{} 1\n", first.nodes[0].node_id));
+
+        // Two nodes can't share the file
+        let result = run(&config, SumServer::new(5), |_| SquareNode { panic_at: None }, 2);
+        assert!(matches!(result, Err(NCError::NodeIdFileLocked(_))), "{:?}", result.map(|(_, job_summary)| job_summary.end_reason));
+
+        fs::remove_file(&node_id_file).unwrap();
+        fs::remove_file(format!("{}.lock", node_id_file.display())).unwrap();
+    }
+
+    /// A result that looks like the others, for test_result_dictionary().
+    #[cfg(feature = "zstd-dict")]
+    fn report(i: u64) -> String {
+        format!(r#"{{"chunk":{},"status":"done","pixels":[{},{},{}],"renderer":"path tracer"}}"#, i, i % 7, i % 13, i % 17)
+    }
+
+    #[cfg(feature = "zstd-dict")]
+    #[test]
+    fn test_result_dictionary() {
+        use std::ops::Range;
+        use crate::nc_range::{RangeServer, RangeNode};
+
+        type Reports = RangeServer<String, Vec<String>, fn(&mut Vec<String>, Range<u64>, &[String])>;
+
+        // The first results come without the dictionary, the others with it once it has been trained
+        let config = NCConfiguration { allowed_codecs: vec![NCCodec::Zstd(3)], compression_min_size: 0, result_dictionary_samples: 100, ..test_config() };
+        let server: Reports = RangeServer::new(0..400, 1, Vec::new(), |reports, _range, values| reports.extend_from_slice(values));
+        let reports = server.accumulator();
+
+        let (_, job_summary) = run(&config, server, |_| RangeNode::new(report), 3).unwrap();
+        assert_eq!(job_summary.end_reason, NCJobEndReason::Finished);
+
+        let mut reports = reports.lock().unwrap().clone();
+        reports.sort();
+        let mut expected: Vec<String> = (0..400).map(report).collect();
+        expected.sort();
+        assert_eq!(reports, expected);
+    }
+
+    const LOG_TEST_KEY: &str = "Yt3pWq8ZkLm2Nc7VbX0sRd4Hf9Ga1JeU";
+    const LOG_TEST_ADMIN_KEY: &str = "q2WmE8rT4yUi0OpA6sDf1GhJ3kLz7XcV";
+
+    /// Counts the log lines of all tests (the logger is global) and remembers the ones that contain the keys of test_no_key_material_in_logs().
+    struct SecretLogger {
+        hashes: AtomicU64,
+        leaks: Mutex<Vec<String>>,
+    }
+
+    impl log::Log for SecretLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let line = record.args().to_string();
+
+            if line.contains(", hash: ") {
+                self.hashes.fetch_add(1, Ordering::Relaxed);
+            }
+
+            if line.contains(LOG_TEST_KEY) || line.contains(LOG_TEST_ADMIN_KEY) {
+                self.leaks.lock().unwrap().push(line);
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static SECRET_LOGGER: SecretLogger = SecretLogger { hashes: AtomicU64::new(0), leaks: Mutex::new(Vec::new()) };
+
+    #[test]
+    fn test_no_key_material_in_logs() {
+        log::set_logger(&SECRET_LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let config = NCConfiguration {
+            encrypt: true,
+            key: LOG_TEST_KEY.to_string(),
+            previous_keys: vec![LOG_TEST_ADMIN_KEY.to_string()],
+            admin_key: LOG_TEST_ADMIN_KEY.to_string(),
+            log_payload_hashes: true,
+            ..test_config()
+        };
+        info!("Configuration: {:?}", config);
+
+        let (nc_server, _) = run(&config, SumServer::new(10), |_| SquareNode { panic_at: None }, 2).unwrap();
+        assert_eq!(nc_server.sum, (0..10).map(|i| i * i).sum::<u64>());
+
+        // Every message is logged twice (sent and received) with its hash
+        assert!(SECRET_LOGGER.hashes.load(Ordering::Relaxed) >= 40);
+        assert_eq!(*SECRET_LOGGER.leaks.lock().unwrap(), Vec::<String>::new());
+    }
+}
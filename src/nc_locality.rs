@@ -0,0 +1,242 @@
+//! This module contains the locality map, it routes the chunks to the nodes that already hold their input files.
+//! When the input is sharded across the local drives of the nodes, a chunk should go to a node that has its shard instead of sending
+//! the data over the network. The nodes advertise their shards as tags (node_tags in the NCConfiguration, for example "shard-a")
+//! and the manifest tells the server the shard tags of every chunk (locality_manifest in the NCConfiguration).
+//!
+//! The manifest is a text file with one line per chunk or range of chunks (inclusive), followed by the tags of the nodes that have
+//! the input for these chunks. A node needs one of the tags. Empty lines and lines starting with `#` are ignored,
+//! chunks that are not in the manifest go to any node:
+//!
+//! ```text
+//! # chunks  shard tags
+//! 0-99      shard-a
+//! 100-199   shard-b shard-b-replica
+//! 200       shard-c
+//! ```
+//!
+//! [`ChunkList::assign_next_chunk_with_locality()`](crate::ChunkList::assign_next_chunk_with_locality) gives a node the first free chunk
+//! whose shard it has. A chunk that no node with its shard has taken for locality_fallback_ms milliseconds (counted from the first time
+//! a node without the shard has asked for it) goes to the next node that asks. That node doesn't have the input,
+//! so the NCServer trait method assign_chunk_with_context() has to send the data inline then,
+//! [`NCAssignContext::is_local()`](crate::NCAssignContext::is_local) tells which case it is.
+//! The server counts the local and the fallback chunks for the statistics and the job summary.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::nc_error::NCError;
+use crate::array2d::ChunkID;
+
+/// The default for the time that a chunk waits for a node with its shard, same as locality_fallback_ms in the NCConfiguration.
+const DEFAULT_FALLBACK: Duration = Duration::from_secs(30);
+
+/// The shard tags of the chunks, see the module documentation.
+#[derive(Debug)]
+pub struct LocalityMap {
+    /// The ranges of chunk ids (first and last chunk id) sorted by the first chunk id, together with their shard tags.
+    shards: Vec<(ChunkID, ChunkID, Vec<String>)>,
+    /// A chunk goes to any node after it has waited this long for a node with its shard.
+    fallback: Duration,
+    /// Since when the chunks have been waiting for a node with their shard.
+    waiting: Mutex<HashMap<ChunkID, Instant>>,
+}
+
+impl LocalityMap {
+    /// Loads the map from the given manifest file, see the module documentation for the format.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::LocalityManifest`] error if the file could not be read or a line is invalid or overlaps another one.
+    pub fn from_manifest<P: AsRef<Path>>(path: P) -> Result<Self, NCError> {
+        debug!("LocalityMap::from_manifest()");
+
+        let path = path.as_ref();
+        let manifest = fs::read_to_string(path).map_err(|e| NCError::LocalityManifest(format!("{}: {}", path.display(), e)))?;
+
+        LocalityMap::parse(&manifest).map_err(|e| match e {
+            NCError::LocalityManifest(problem) => NCError::LocalityManifest(format!("{}: {}", path.display(), problem)),
+            e => e,
+        })
+    }
+
+    /// Creates the map from the content of a manifest file, see [`from_manifest()`](LocalityMap::from_manifest).
+    pub fn parse(manifest: &str) -> Result<Self, NCError> {
+        debug!("LocalityMap::parse()");
+
+        let mut shards = Vec::new();
+
+        for (number, line) in manifest.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+
+            let invalid = |problem: &str| NCError::LocalityManifest(format!("line {}: {}", number + 1, problem));
+            let mut fields = line.split_whitespace();
+            let chunks = fields.next().unwrap_or_default();
+            let tags: Vec<String> = fields.map(str::to_string).collect();
+
+            let (first, last) = chunks.split_once('-').unwrap_or((chunks, chunks));
+            let first: ChunkID = first.parse().map_err(|_| invalid("invalid chunk id"))?;
+            let last: ChunkID = last.parse().map_err(|_| invalid("invalid chunk id"))?;
+
+            if last < first {
+                return Err(invalid("the range of chunks is empty"))
+            }
+
+            if tags.is_empty() {
+                return Err(invalid("no shard tag"))
+            }
+
+            shards.push((first, last, tags));
+        }
+
+        shards.sort_by_key(|(first, _, _)| *first);
+
+        if let Some(window) = shards.windows(2).find(|window| window[1].0 <= window[0].1) {
+            return Err(NCError::LocalityManifest(format!("chunk {} has more than one line", window[1].0)))
+        }
+
+        Ok(LocalityMap { shards, fallback: DEFAULT_FALLBACK, waiting: Mutex::new(HashMap::new()) })
+    }
+
+    /// Sets the time that a chunk waits for a node with its shard before it goes to any node, default: 30 seconds.
+    pub fn with_fallback(mut self, fallback: Duration) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    /// The time that a chunk waits for a node with its shard before it goes to any node.
+    pub fn fallback(&self) -> Duration {
+        self.fallback
+    }
+
+    /// The shard tags of the given chunk, empty if the chunk is not in the manifest and can go to any node.
+    pub fn required_tags(&self, chunk_id: ChunkID) -> &[String] {
+        let index = self.shards.partition_point(|(first, _, _)| *first <= chunk_id);
+
+        match index.checked_sub(1).map(|index| &self.shards[index]) {
+            Some((_, last, tags)) if chunk_id <= *last => tags,
+            _ => &[],
+        }
+    }
+
+    /// Returns true if a node with the given tags has the shard of the given chunk (or the chunk can go to any node).
+    pub fn is_local(&self, chunk_id: ChunkID, tags: &[String]) -> bool {
+        let required = self.required_tags(chunk_id);
+        required.is_empty() || required.iter().any(|tag| tags.contains(tag))
+    }
+
+    /// Returns the first of the given chunks that has waited for at least the fallback time.
+    /// The chunks that have not waited before start to wait now.
+    pub(crate) fn first_fallback<I: Iterator<Item = ChunkID>>(&self, chunk_ids: I) -> Option<ChunkID> {
+        let mut waiting = match self.waiting.lock() {
+            Ok(waiting) => waiting,
+            Err(_) => return None,
+        };
+
+        let now = Instant::now();
+        let mut first = None;
+
+        for chunk_id in chunk_ids {
+            let since = *waiting.entry(chunk_id).or_insert(now);
+
+            if first.is_none() && now.duration_since(since) >= self.fallback {
+                first = Some(chunk_id);
+            }
+        }
+
+        first
+    }
+
+    /// The given chunk has been assigned to a node, it doesn't wait anymore.
+    pub(crate) fn chunk_assigned(&self, chunk_id: ChunkID) {
+        if let Ok(mut waiting) = self.waiting.lock() {
+            waiting.remove(&chunk_id);
+        }
+    }
+}
+
+impl PartialEq for LocalityMap {
+    /// The chunks that are waiting right now are not compared.
+    fn eq(&self, other: &Self) -> bool {
+        self.shards == other.shards && self.fallback == other.fallback
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use crate::array2d::ChunkList;
+    use crate::nc_node_info::NodeID;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn test_manifest() {
+        let locality = LocalityMap::parse("# chunks  shard tags\n100-199 shard-b shard-b2\n\n0-99 shard-a\n200 shard-c\n").unwrap();
+
+        assert_eq!(locality.required_tags(0), tags(&["shard-a"]));
+        assert_eq!(locality.required_tags(99), tags(&["shard-a"]));
+        assert_eq!(locality.required_tags(150), tags(&["shard-b", "shard-b2"]));
+        assert_eq!(locality.required_tags(200), tags(&["shard-c"]));
+        assert!(locality.required_tags(201).is_empty());
+
+        assert!(locality.is_local(120, &tags(&["gpu", "shard-b2"])));
+        assert!(!locality.is_local(120, &tags(&["shard-a"])));
+        assert!(locality.is_local(500, &[]));
+
+        let error = |manifest: &str| match LocalityMap::parse(manifest) {
+            Err(NCError::LocalityManifest(problem)) => problem,
+            result => panic!("Expected an error, got: {:?}", result),
+        };
+        assert_eq!(error("0-9 shard-a\nten shard-b"), "line 2: invalid chunk id");
+        assert_eq!(error("9-0 shard-a"), "line 1: the range of chunks is empty");
+        assert_eq!(error("0-9"), "line 1: no shard tag");
+        assert_eq!(error("0-9 shard-a\n9-12 shard-b"), "chunk 9 has more than one line");
+
+        let path = std::env::temp_dir().join(format!("nc_locality_{}.txt", std::process::id()));
+        fs::write(&path, "0-99 shard-a\n").unwrap();
+        assert_eq!(LocalityMap::from_manifest(&path).unwrap(), LocalityMap::parse("0-99 shard-a").unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(LocalityMap::from_manifest(&path), Err(NCError::LocalityManifest(_))));
+    }
+
+    #[test]
+    fn test_assign() {
+        let locality = LocalityMap::parse("0-1 shard-a\n2 shard-b\n3 shard-c").unwrap().with_fallback(Duration::from_millis(100));
+        let mut chunk_list = ChunkList::new();
+        (0..5).for_each(|i| chunk_list.push(i));
+        let node_id = NodeID::random();
+        let shard_a = tags(&["shard-a"]);
+        let mut assign = |tags: &[String]| chunk_list.assign_next_chunk_with_locality(node_id, tags, Some(&locality)).map(|(chunk_id, _)| chunk_id);
+
+        // The chunks of the shard first, then the chunks for any node
+        assert_eq!(assign(&shard_a), Some(0));
+        assert_eq!(assign(&shard_a), Some(1));
+        assert_eq!(assign(&shard_a), Some(4));
+        assert_eq!(assign(&shard_a), None);
+        assert_eq!(assign(&tags(&["shard-b"])), Some(2));
+
+        // Chunk 3 has waited long enough for a node with shard-c
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(assign(&shard_a), Some(3));
+        assert!(locality.waiting.lock().unwrap().is_empty());
+
+        // Without a locality map only the groups count
+        let mut chunk_list = ChunkList::new();
+        chunk_list.push(0);
+        assert_eq!(chunk_list.assign_next_chunk_with_locality(node_id, &[], None).map(|(chunk_id, _)| chunk_id), Some(0));
+    }
+}
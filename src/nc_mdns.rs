@@ -0,0 +1,697 @@
+//! This module contains the mDNS (zeroconf) support (mdns feature). With mdns_announce in the NCConfiguration the server registers
+//! the service `_nodecrunch._tcp.local.` while it's running. Its TXT records contain the job name (job_id), the protocol version
+//! (the version of node_crunch) and whether encryption is required. Nodes with the address `"mdns:"` browse for these services and
+//! connect to the one that matches their configuration:
+//!
+//! ```no_run
+//! use node_crunch::{NCConfiguration, nc_mdns};
+//!
+//! // On the server
+//! let server_config = NCConfiguration { mdns_announce: true, job_id: Some("mandel".to_string()), ..Default::default() };
+//!
+//! // On every node, the port comes from the service
+//! let node_config = NCConfiguration { address: "mdns:".to_string(), job_id: Some("mandel".to_string()), ..Default::default() };
+//!
+//! // Or just list the servers in the local network
+//! for server in nc_mdns::browse().unwrap() {
+//!     println!("{}", server);
+//! }
+//! ```
+//!
+//! Only servers with the same protocol version and the same encrypt setting are candidates. If more than one is left the ones
+//! with the job name of the node are preferred, if that's still more than one the node gets a [`NCError::MdnsAmbiguous`] error
+//! that lists them. The node connects to the address that the answer came from.
+//!
+//! This is a small implementation of multicast DNS on top of std::net, it only knows the records that node_crunch needs (PTR, SRV, TXT and A).
+//! The server needs the UDP port 5353, if another mDNS responder (avahi, Bonjour) already uses it the server doesn't start.
+//! Nodes don't need that port, they ask for a unicast answer. On a clean shutdown the server sends a goodbye, so the service disappears right away.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use crate::nc_config::NCConfiguration;
+use crate::nc_error::NCError;
+
+/// The service type of node_crunch servers.
+pub const MDNS_SERVICE_TYPE: &str = "_nodecrunch._tcp.local.";
+
+/// Nodes and servers with a different protocol version can't talk to each other.
+pub(crate) const PROTOCOL_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The multicast group of mDNS.
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+
+/// The port of mDNS.
+const MDNS_PORT: u16 = 5353;
+
+/// The time to live of the records in seconds.
+const RECORD_TTL: u32 = 120;
+
+/// The time to live for answers to legacy unicast queries, see RFC 6762 section 6.7.
+const LEGACY_TTL: u32 = 10;
+
+/// How long browse() collects the answers.
+const BROWSE_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// browse() sends its query again after this time, in case a packet got lost.
+const QUERY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The responder checks its stop flag at least this often.
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(200);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// The top bit of the class: a question asks for a unicast answer, a record replaces all others with the same name.
+const CLASS_FLAG: u16 = 0x8000;
+/// The flags of an authoritative answer.
+const FLAGS_RESPONSE: u16 = 0x8400;
+
+/// The data of one resource record.
+#[derive(Debug, Clone, PartialEq)]
+enum RecordData {
+    Ptr(String),
+    Srv { port: u16, target: String },
+    Txt(Vec<String>),
+    A(Ipv4Addr),
+    /// Any other type, the data is ignored.
+    Other(u16),
+}
+
+/// One resource record.
+#[derive(Debug, Clone, PartialEq)]
+struct Record {
+    name: String,
+    ttl: u32,
+    data: RecordData,
+}
+
+/// The question of a query: name, type and if a unicast answer is wanted.
+#[derive(Debug, Clone, PartialEq)]
+struct Question {
+    name: String,
+    qtype: u16,
+    unicast: bool,
+}
+
+/// A DNS message, the records of all sections are put together.
+#[derive(Debug, Clone, PartialEq)]
+struct Packet {
+    id: u16,
+    response: bool,
+    questions: Vec<Question>,
+    records: Vec<Record>,
+}
+
+/// DNS names are compared without case.
+fn same_name(name1: &str, name2: &str) -> bool {
+    name1.trim_end_matches('.').eq_ignore_ascii_case(name2.trim_end_matches('.'))
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<u16, String> {
+    data.get(pos..pos + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]])).ok_or_else(|| format!("packet too short at {}", pos))
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32, String> {
+    Ok(((read_u16(data, pos)? as u32) << 16) | read_u16(data, pos + 2)? as u32)
+}
+
+/// Returns the name at the given position and the position after it, follows compression pointers.
+fn decode_name(data: &[u8], mut pos: usize) -> Result<(String, usize), String> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *data.get(pos).ok_or_else(|| format!("name out of bounds at {}", pos))? as usize;
+
+        if len == 0 {
+            pos += 1;
+            break
+        }
+
+        if len & 0xC0 == 0xC0 {
+            jumps += 1;
+
+            if jumps > 16 {
+                return Err("too many compression pointers".to_string())
+            }
+
+            let target = (read_u16(data, pos)? & 0x3FFF) as usize;
+            end.get_or_insert(pos + 2);
+            pos = target;
+            continue
+        }
+
+        let label = data.get(pos + 1..pos + 1 + len).ok_or_else(|| format!("label out of bounds at {}", pos))?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+
+    Ok((labels.join(".") + ".", end.unwrap_or(pos)))
+}
+
+/// Appends the name without compression, labels are cut to 63 bytes.
+fn encode_name(name: &str, out: &mut Vec<u8>) {
+    for label in name.trim_end_matches('.').split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+
+    out.push(0);
+}
+
+impl Record {
+    fn decode(data: &[u8], pos: usize) -> Result<(Record, usize), String> {
+        let (name, pos) = decode_name(data, pos)?;
+        let rtype = read_u16(data, pos)?;
+        let ttl = read_u32(data, pos + 4)?;
+        let len = read_u16(data, pos + 8)? as usize;
+        let start = pos + 10;
+        let rdata = data.get(start..start + len).ok_or_else(|| format!("record data out of bounds at {}", start))?;
+
+        let data = match rtype {
+            TYPE_PTR => RecordData::Ptr(decode_name(data, start)?.0),
+            TYPE_SRV => RecordData::Srv { port: read_u16(rdata, 4)?, target: decode_name(data, start + 6)?.0 },
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                let mut rest = rdata;
+
+                while let Some((&len, tail)) = rest.split_first() {
+                    let string = tail.get(..len as usize).ok_or("TXT string out of bounds")?;
+                    strings.push(String::from_utf8_lossy(string).into_owned());
+                    rest = &tail[len as usize..];
+                }
+
+                RecordData::Txt(strings)
+            }
+            TYPE_A if len == 4 => RecordData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+            _ => RecordData::Other(rtype),
+        };
+
+        Ok((Record { name, ttl, data }, start + len))
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut rdata = Vec::new();
+
+        let rtype = match &self.data {
+            RecordData::Ptr(name) => {
+                encode_name(name, &mut rdata);
+                TYPE_PTR
+            }
+            RecordData::Srv { port, target } => {
+                rdata.extend_from_slice(&[0, 0, 0, 0]);
+                rdata.extend_from_slice(&port.to_be_bytes());
+                encode_name(target, &mut rdata);
+                TYPE_SRV
+            }
+            RecordData::Txt(strings) => {
+                for string in strings {
+                    let string = &string.as_bytes()[..string.len().min(255)];
+                    rdata.push(string.len() as u8);
+                    rdata.extend_from_slice(string);
+                }
+                TYPE_TXT
+            }
+            RecordData::A(addr) => {
+                rdata.extend_from_slice(&addr.octets());
+                TYPE_A
+            }
+            RecordData::Other(rtype) => *rtype,
+        };
+
+        encode_name(&self.name, out);
+        out.extend_from_slice(&rtype.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        out.extend_from_slice(&self.ttl.to_be_bytes());
+        out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        out.extend_from_slice(&rdata);
+    }
+}
+
+impl Packet {
+    /// A query for all node_crunch services that asks for a unicast answer.
+    fn query(id: u16) -> Self {
+        Packet { id, response: false, questions: vec![Question { name: MDNS_SERVICE_TYPE.to_string(), qtype: TYPE_PTR, unicast: true }], records: Vec::new() }
+    }
+
+    fn decode(data: &[u8]) -> Result<Packet, String> {
+        let id = read_u16(data, 0)?;
+        let response = read_u16(data, 2)? & 0x8000 != 0;
+        let num_of_questions = read_u16(data, 4)?;
+        let num_of_records: u32 = (read_u16(data, 6)? as u32) + (read_u16(data, 8)? as u32) + (read_u16(data, 10)? as u32);
+        let mut pos = 12;
+        let mut questions = Vec::new();
+        let mut records = Vec::new();
+
+        for _ in 0..num_of_questions {
+            let (name, next) = decode_name(data, pos)?;
+            questions.push(Question { name, qtype: read_u16(data, next)?, unicast: read_u16(data, next + 2)? & CLASS_FLAG != 0 });
+            pos = next + 4;
+        }
+
+        for _ in 0..num_of_records {
+            let (record, next) = Record::decode(data, pos)?;
+            records.push(record);
+            pos = next;
+        }
+
+        Ok(Packet { id, response, questions, records })
+    }
+
+    /// All records go into the answer section.
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        out.extend_from_slice(&self.id.to_be_bytes());
+        out.extend_from_slice(&(if self.response { FLAGS_RESPONSE } else { 0 }).to_be_bytes());
+        out.extend_from_slice(&(self.questions.len() as u16).to_be_bytes());
+        out.extend_from_slice(&(self.records.len() as u16).to_be_bytes());
+        out.extend_from_slice(&[0, 0, 0, 0]);
+
+        for question in &self.questions {
+            encode_name(&question.name, &mut out);
+            out.extend_from_slice(&question.qtype.to_be_bytes());
+            out.extend_from_slice(&(if question.unicast { CLASS_IN | CLASS_FLAG } else { CLASS_IN }).to_be_bytes());
+        }
+
+        for record in &self.records {
+            record.encode(&mut out);
+        }
+
+        out
+    }
+}
+
+/// The service that the server registers.
+#[derive(Debug, Clone)]
+struct ServiceInfo {
+    /// The full name of the service instance, e.g. `node_crunch-2020-1a2b3c4d._nodecrunch._tcp.local.`.
+    instance: String,
+    /// The host name in the SRV record.
+    host: String,
+    /// The tcp port of the server.
+    port: u16,
+    /// job, proto and encrypt.
+    txt: Vec<String>,
+    /// The address for the A record.
+    addr: Option<Ipv4Addr>,
+}
+
+impl ServiceInfo {
+    fn new(config: &NCConfiguration, port: u16) -> Self {
+        let id: u32 = rand::random();
+
+        ServiceInfo {
+            instance: format!("node_crunch-{}-{:08x}.{}", port, id, MDNS_SERVICE_TYPE),
+            host: format!("nc-{:08x}.local.", id),
+            port,
+            txt: vec![
+                format!("job={}", config.job_id.as_deref().unwrap_or_default()),
+                format!("proto={}", PROTOCOL_VERSION),
+                format!("encrypt={}", if config.encrypt { 1 } else { 0 }),
+            ],
+            addr: local_ipv4(),
+        }
+    }
+
+    /// The PTR, SRV, TXT and A records, ttl 0 is a goodbye.
+    fn records(&self, ttl: u32) -> Vec<Record> {
+        let mut records = vec![
+            Record { name: MDNS_SERVICE_TYPE.to_string(), ttl, data: RecordData::Ptr(self.instance.clone()) },
+            Record { name: self.instance.clone(), ttl, data: RecordData::Srv { port: self.port, target: self.host.clone() } },
+            Record { name: self.instance.clone(), ttl, data: RecordData::Txt(self.txt.clone()) },
+        ];
+
+        if let Some(addr) = self.addr {
+            records.push(Record { name: self.host.clone(), ttl, data: RecordData::A(addr) });
+        }
+
+        records
+    }
+
+    /// Returns the answer to the given query and if it must be sent back to the source (unicast) instead of the group.
+    fn answer(&self, query: &Packet, source: SocketAddr) -> Option<(Packet, bool)> {
+        let asked = query.questions.iter().any(|question| {
+            (same_name(&question.name, MDNS_SERVICE_TYPE) && (question.qtype == TYPE_PTR || question.qtype == TYPE_ANY)) ||
+            (same_name(&question.name, &self.instance) && matches!(question.qtype, TYPE_SRV | TYPE_TXT | TYPE_ANY))
+        });
+
+        if query.response || !asked {
+            return None
+        }
+
+        // A query that doesn't come from the mDNS port is a legacy unicast query, the answer looks like a normal DNS answer.
+        if source.port() != MDNS_PORT {
+            return Some((Packet { id: query.id, response: true, questions: query.questions.clone(), records: self.records(LEGACY_TTL) }, true))
+        }
+
+        let unicast = query.questions.iter().any(|question| question.unicast);
+        Some((Packet { id: 0, response: true, questions: Vec::new(), records: self.records(RECORD_TTL) }, unicast))
+    }
+}
+
+/// The local address that is used for multicast, no packet is sent.
+fn local_ipv4() -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((MDNS_GROUP, MDNS_PORT)).ok()?;
+
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(addr) if !addr.is_unspecified() => Some(addr),
+        _ => None,
+    }
+}
+
+/// The registered service of a running server. It's answering queries in its own thread until it's dropped, then it sends a goodbye.
+pub(crate) struct NCMdnsService {
+    info: ServiceInfo,
+    socket: UdpSocket,
+    /// Where the announcement and the goodbye go to, the mDNS group.
+    group: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NCMdnsService {
+    /// Registers the service for the server with the given tcp port.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::Mdns`] error if the mDNS port can't be used.
+    pub(crate) fn register(config: &NCConfiguration, port: u16) -> Result<Self, NCError> {
+        debug!("NCMdnsService::register()");
+
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, MDNS_PORT))
+            .map_err(|e| NCError::Mdns(format!("could not bind UDP port {}, is another mDNS responder running? {}", MDNS_PORT, e)))?;
+        socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED).map_err(|e| NCError::Mdns(format!("could not join the mDNS group: {}", e)))?;
+        socket.set_multicast_ttl_v4(255)?;
+
+        Self::with_socket(ServiceInfo::new(config, port), socket, SocketAddr::from((MDNS_GROUP, MDNS_PORT)))
+    }
+
+    /// Announces the service to the group and starts the thread that answers the queries.
+    fn with_socket(info: ServiceInfo, socket: UdpSocket, group: SocketAddr) -> Result<Self, NCError> {
+        socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+
+        let announcement = Packet { id: 0, response: true, questions: Vec::new(), records: info.records(RECORD_TTL) };
+        socket.send_to(&announcement.encode(), group)?;
+        info!("Registered the mDNS service '{}' for port {}", info.instance, info.port);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread_socket = socket.try_clone()?;
+        let thread_info = info.clone();
+
+        let thread = thread::spawn(move || {
+            let mut buffer = [0; 9000];
+
+            while !thread_stop.load(Ordering::Relaxed) {
+                let (len, source) = match thread_socket.recv_from(&mut buffer) {
+                    Ok(received) => received,
+                    Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                    Err(e) => {
+                        warn!("mDNS responder could not receive: {}", e);
+                        continue
+                    }
+                };
+
+                let query = match Packet::decode(&buffer[..len]) {
+                    Ok(query) => query,
+                    Err(e) => {
+                        debug!("Invalid mDNS packet from {}: {}", source, e);
+                        continue
+                    }
+                };
+
+                if let Some((answer, unicast)) = thread_info.answer(&query, source) {
+                    let destination = if unicast { source } else { group };
+
+                    if let Err(e) = thread_socket.send_to(&answer.encode(), destination) {
+                        warn!("mDNS responder could not answer {}: {}", destination, e);
+                    }
+                }
+            }
+        });
+
+        Ok(NCMdnsService { info, socket, group, stop, thread: Some(thread) })
+    }
+}
+
+impl Drop for NCMdnsService {
+    fn drop(&mut self) {
+        debug!("NCMdnsService::drop()");
+
+        self.stop.store(true, Ordering::Relaxed);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        let goodbye = Packet { id: 0, response: true, questions: Vec::new(), records: self.info.records(0) };
+
+        match self.socket.send_to(&goodbye.encode(), self.group) {
+            Ok(_) => info!("Deregistered the mDNS service '{}'", self.info.instance),
+            Err(e) => warn!("Could not deregister the mDNS service '{}': {}", self.info.instance, e),
+        }
+    }
+}
+
+/// A node_crunch server that has been found with [`browse()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NCMdnsServer {
+    /// The name of the service instance (without the service type).
+    pub name: String,
+    /// The address of the server, the ip address is the one the answer came from.
+    pub address: SocketAddr,
+    /// The job name (job_id of the server), None if the server doesn't have one.
+    pub job: Option<String>,
+    /// The protocol version of the server (the version of node_crunch).
+    pub protocol: String,
+    /// The server requires encryption.
+    pub encrypt: bool,
+}
+
+impl Display for NCMdnsServer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' at {} (job: '{}', protocol: {}, encrypt: {})", self.name, self.address, self.job.as_deref().unwrap_or_default(),
+            self.protocol, self.encrypt)
+    }
+}
+
+/// Adds the servers in the answer to the list (or removes them for a goodbye).
+fn collect_servers(packet: &Packet, source: IpAddr, servers: &mut BTreeMap<String, NCMdnsServer>) {
+    if !packet.response {
+        return
+    }
+
+    for record in &packet.records {
+        let instance = match &record.data {
+            RecordData::Ptr(instance) if same_name(&record.name, MDNS_SERVICE_TYPE) => instance,
+            _ => continue,
+        };
+
+        if record.ttl == 0 {
+            servers.remove(&instance.to_ascii_lowercase());
+            continue
+        }
+
+        let port = packet.records.iter().find_map(|record| match &record.data {
+            RecordData::Srv { port, .. } if same_name(&record.name, instance) => Some(*port),
+            _ => None,
+        });
+
+        let txt: BTreeMap<&str, &str> = packet.records.iter().filter(|record| same_name(&record.name, instance))
+            .filter_map(|record| match &record.data { RecordData::Txt(strings) => Some(strings), _ => None })
+            .flatten()
+            .filter_map(|string| string.split_once('='))
+            .collect();
+
+        if let Some(port) = port {
+            servers.insert(instance.to_ascii_lowercase(), NCMdnsServer {
+                name: instance.split('.').next().unwrap_or_default().to_string(),
+                address: SocketAddr::new(source, port),
+                job: txt.get("job").filter(|job| !job.is_empty()).map(|job| job.to_string()),
+                protocol: txt.get("proto").unwrap_or(&"").to_string(),
+                encrypt: txt.get("encrypt") == Some(&"1"),
+            });
+        }
+    }
+}
+
+/// Sends the query to the given address and collects the answers until the timeout.
+fn browse_at(target: SocketAddr, timeout: Duration) -> Result<Vec<NCMdnsServer>, NCError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_multicast_ttl_v4(255)?;
+
+    let query = Packet::query(rand::random()).encode();
+    let end = Instant::now() + timeout;
+    let mut next_query = Instant::now();
+    let mut servers = BTreeMap::new();
+    let mut buffer = [0; 9000];
+
+    loop {
+        let now = Instant::now();
+
+        if now >= end {
+            break
+        }
+
+        if now >= next_query {
+            socket.send_to(&query, target)?;
+            next_query += QUERY_INTERVAL;
+        }
+
+        socket.set_read_timeout(Some((end.min(next_query) - now).max(Duration::from_millis(1))))?;
+
+        match socket.recv_from(&mut buffer) {
+            Ok((len, source)) => match Packet::decode(&buffer[..len]) {
+                Ok(packet) => collect_servers(&packet, source.ip(), &mut servers),
+                Err(e) => debug!("Invalid mDNS packet from {}: {}", source, e),
+            },
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(servers.into_values().collect())
+}
+
+/// Returns all node_crunch servers in the local network that answer within 1.5 seconds.
+pub fn browse() -> Result<Vec<NCMdnsServer>, NCError> {
+    debug!("nc_mdns::browse()");
+
+    browse_at(SocketAddr::from((MDNS_GROUP, MDNS_PORT)), BROWSE_TIMEOUT)
+}
+
+/// Picks the server for a node with the given configuration, see the module documentation.
+fn select_server(servers: Vec<NCMdnsServer>, config: &NCConfiguration) -> Result<NCMdnsServer, NCError> {
+    let found = servers.len();
+    let compatible: Vec<NCMdnsServer> = servers.into_iter().filter(|server| server.protocol == PROTOCOL_VERSION && server.encrypt == config.encrypt).collect();
+
+    if compatible.is_empty() {
+        return Err(NCError::Mdns(format!("no matching server found ({} servers with another protocol version or encrypt setting)", found)))
+    }
+
+    let matching: Vec<NCMdnsServer> = compatible.iter().filter(|server| server.job == config.job_id).cloned().collect();
+    let mut candidates = if matching.is_empty() { compatible } else { matching };
+
+    if candidates.len() == 1 {
+        Ok(candidates.remove(0))
+    } else {
+        Err(NCError::MdnsAmbiguous(candidates.iter().map(|server| server.to_string()).collect()))
+    }
+}
+
+/// Browses for the server of the node, used for the address "mdns:".
+pub(crate) fn find_server(config: &NCConfiguration) -> Result<SocketAddr, NCError> {
+    debug!("nc_mdns::find_server()");
+
+    let server = select_server(browse()?, config)?;
+    info!("Found the server {} with mDNS", server);
+
+    Ok(server.address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_info(config: &NCConfiguration) -> ServiceInfo {
+        ServiceInfo { addr: Some(Ipv4Addr::new(192, 168, 0, 10)), ..ServiceInfo::new(config, 2020) }
+    }
+
+    fn test_server(name: &str, job: Option<&str>, protocol: &str, encrypt: bool) -> NCMdnsServer {
+        NCMdnsServer { name: name.to_string(), address: "192.168.0.10:2020".parse().unwrap(), job: job.map(String::from),
+            protocol: protocol.to_string(), encrypt }
+    }
+
+    #[test]
+    fn test_packet() {
+        let info = test_info(&NCConfiguration { job_id: Some("mandel".to_string()), ..Default::default() });
+        let packet = Packet { id: 7, response: true, questions: Packet::query(7).questions, records: info.records(RECORD_TTL) };
+        assert_eq!(Packet::decode(&packet.encode()).unwrap(), packet);
+
+        let mut servers = BTreeMap::new();
+        collect_servers(&packet, IpAddr::from([10, 0, 0, 1]), &mut servers);
+        let server = servers.values().next().unwrap();
+        assert_eq!(server.address, "10.0.0.1:2020".parse().unwrap());
+        assert_eq!(server.job.as_deref(), Some("mandel"));
+        assert_eq!(server.protocol, PROTOCOL_VERSION);
+        assert!(!server.encrypt);
+
+        // A goodbye removes the server again
+        collect_servers(&Packet { records: info.records(0), ..packet }, IpAddr::from([10, 0, 0, 1]), &mut servers);
+        assert!(servers.is_empty());
+
+        // A compressed name: "b" followed by a pointer to "a.local"
+        let mut data = vec![0; 12];
+        data.extend_from_slice(b"\x01a\x05local\x00\x01b\xc0\x0c");
+        assert_eq!(decode_name(&data, 21).unwrap(), ("b.a.local.".to_string(), 25));
+        assert!(decode_name(b"\xc0\x00", 0).is_err());
+        assert!(Packet::decode(&[0, 1, 0]).is_err());
+    }
+
+    #[test]
+    fn test_responder() {
+        let group = UdpSocket::bind("127.0.0.1:0").unwrap();
+        group.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let responder = socket.local_addr().unwrap();
+        let info = test_info(&NCConfiguration { encrypt: true, ..Default::default() });
+        let service = NCMdnsService::with_socket(info.clone(), socket, group.local_addr().unwrap()).unwrap();
+
+        let mut buffer = [0; 9000];
+        let (len, _) = group.recv_from(&mut buffer).unwrap();
+        assert_eq!(Packet::decode(&buffer[..len]).unwrap().records, info.records(RECORD_TTL));
+
+        // The query doesn't come from port 5353, so the answer goes back to the node
+        let servers = browse_at(responder, Duration::from_millis(300)).unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].address, SocketAddr::new(responder.ip(), 2020));
+        assert_eq!(servers[0].job, None);
+        assert!(servers[0].encrypt);
+
+        drop(service);
+        let (len, _) = group.recv_from(&mut buffer).unwrap();
+        assert!(Packet::decode(&buffer[..len]).unwrap().records.iter().all(|record| record.ttl == 0));
+    }
+
+    #[test]
+    fn test_select_server() {
+        let config = NCConfiguration { job_id: Some("mandel".to_string()), ..Default::default() };
+        let servers = vec![
+            test_server("a", Some("mandel"), PROTOCOL_VERSION, false),
+            test_server("b", None, PROTOCOL_VERSION, false),
+            test_server("c", Some("mandel"), "0.1.0", false),
+            test_server("d", Some("mandel"), PROTOCOL_VERSION, true),
+        ];
+        assert_eq!(select_server(servers.clone(), &config).unwrap().name, "a");
+        assert_eq!(select_server(servers.clone(), &NCConfiguration::default()).unwrap().name, "b");
+
+        // Without a matching job every compatible server is a candidate
+        let config = NCConfiguration { job_id: Some("fractal".to_string()), ..Default::default() };
+        match select_server(servers.clone(), &config) {
+            Err(NCError::MdnsAmbiguous(candidates)) => {
+                assert_eq!(candidates.len(), 2);
+                assert!(candidates[0].starts_with("'a' at 192.168.0.10:2020 (job: 'mandel'"));
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        assert!(matches!(select_server(servers[2..3].to_vec(), &config), Err(NCError::Mdns(_))));
+        assert!(matches!(select_server(Vec::new(), &config), Err(NCError::Mdns(_))));
+    }
+}
@@ -0,0 +1,615 @@
+//! This module contains the messages that are sent between the server and the nodes and the data that they carry.
+//! They don't depend on the network code, so they are available without the net feature, for example
+//! for a custom transport or for tools that only decode recorded messages.
+//! The server and node modules re-export them, so the paths crate::nc_server::NCServerMessage and
+//! crate::nc_node::NCNodeMessage still work.
+
+#[cfg(feature = "net")]
+use std::any::type_name;
+use std::fmt::{self, Debug};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+
+use crate::nc_error::NCJobError;
+use crate::nc_config::ConfigDiff;
+#[cfg(feature = "net")]
+use crate::nc_config::{NCConfiguration, NCSharedSettings};
+use crate::nc_node_info::NodeID;
+#[cfg(feature = "net")]
+use crate::nc_admin::NCAdminMessage;
+use crate::nc_communicator::NCCodec;
+#[cfg(feature = "net")]
+use crate::nc_communicator::NCTyped;
+#[cfg(feature = "net")]
+use crate::nc_resources::NCResourceReport;
+use crate::array2d::{ChunkID, ChunkMeta};
+#[cfg(feature = "net")]
+use crate::nc_replication::NCReplicationUpdate;
+use crate::nc_clock::NCClockSkew;
+use crate::nc_job_summary::NCChunkAttempt;
+
+/// This message is send from the server to each node. The Debug output masks the new key of RotateKey.
+#[derive(Serialize, Deserialize)]
+pub enum NCServerMessage<InitialDataT, NewDataT, CustomMessageT> {
+    /// When the node registers for the first time with the NCNodeMessage::Register message the server assigns a new node id
+    /// and sends some optional initial data to the node together with the codec that has been chosen for this node.
+    /// This is also the answer to the NCNodeMessage::Rejoin message, with the node id that the node has to use from now on.
+    InitialData(NodeID, Option<InitialDataT>, NCCodec),
+    /// The server doesn't support any of the codecs from the node. Contains the codecs of the server.
+    NoCommonCodec(Vec<NCCodec>),
+    /// When the node requests new data to process with the NCNodeMessage::NeedsData message, the current job status is sent to
+    /// the node: unfinished, waiting or finished.
+    JobStatus(NCJobStatus<NewDataT>),
+    /// Send some statistics about the server to the node.
+    Statistics(NCServerStatistics),
+    /// Move all nodes to a new server.
+    NewServer(String, u16),
+    /// Send a custom message to one or all nodes.
+    CustomMessage(CustomMessageT),
+    /// The answer to the NCAdminCommand::QueryStatus message. Since no user code is called for this
+    /// the status is either `Unfinished((), NCChunkInfo::default())` or `Finished`.
+    Status(NCJobStatus<()>),
+    /// The admin command has been executed.
+    AdminAck,
+    /// The admin message was not signed with the admin key or it has been replayed.
+    Unauthorized,
+    /// The result from the node has been put into the result queue. If job_finished is set the job is done
+    /// (this may have been the last result, see [`NCServer::job_finished()`](crate::NCServer::job_finished)), the node exits without asking for new data.
+    /// All the other messages that just need an acknowledgement get this too, with job_finished set if the job is done already.
+    ResultAck { job_finished: bool },
+    /// The server could not process the last result from the node (see [`OnProcessError`](crate::OnProcessError)).
+    /// This is sent the next time the node needs data, because the result is processed after the ResultAck message.
+    ResultRejected(NCJobError),
+    /// The user code on the server returned a NCError::Job error for this node.
+    ServerFailed(NCJobError),
+    /// The node has used an old encryption key, it has to use this new key from now on and send its message again.
+    /// This message is encrypted with the old key.
+    RotateKey(String),
+    /// The node doesn't run the required build (see required_node_build in the NCConfiguration) and should exit,
+    /// so that it can be restarted with the new build. This is sent instead of new data, after the node has sent its last result.
+    PleaseRestart { reason: String },
+    /// The [`NCMultiServerStarter`](crate::NCMultiServerStarter) doesn't run a job with the job id of the message (anymore).
+    /// Contains the job id, it's empty if the message didn't have one.
+    UnknownJob(String),
+    /// The settings of the node in the NCNodeMessage::Register message don't match the settings of the server, the node is not registered.
+    /// Contains every difference with the severity Error, see [`NCConfiguration::diff()`](crate::NCConfiguration::diff).
+    ConfigMismatch(Vec<ConfigDiff>),
+    /// The answer to the NCNodeMessage::ClockProbe message: the time of the server in microseconds since the unix epoch.
+    ClockTime(u64),
+    /// The answer to the NCAdminCommand::ChunkHistory message: the attempts for the chunk, oldest first.
+    /// Empty if the chunk hasn't been sent yet or has been done with the first attempt.
+    ChunkHistory(Vec<NCChunkAttempt>),
+    /// The answer to the NCNodeMessage::PollHeartBeat message: the chunks of the node (the current one and the one fetched in advance)
+    /// that have been revoked, the node should stop processing them.
+    ChunksRevoked(Vec<ChunkID>),
+    /// The server has rejected the message of the node, for example the registration: the reason, a message for the log of the node
+    /// and how long the node should wait before it tries again (only for the reasons that can be retried, see [`NCRejectReason::is_retryable()`]).
+    /// The node turns this into an error, see [`NCError::is_fatal_rejection()`](crate::NCError::is_fatal_rejection).
+    /// NoCommonCodec, UnknownJob and ConfigMismatch are only sent by older servers, the node still understands them.
+    Rejected { reason: NCRejectReason, message: String, retry_after: Option<Duration> },
+    /// The job has been aborted (for example via the admin protocol or because of too many failures) with the given reason.
+    /// This is sent instead of NCJobStatus::Finished, the node exits with NodeExit::JobAborted.
+    JobAborted(String),
+    /// The answer to the NCNodeMessage::GetDictionary message: the id and the bytes of the zstd dictionary for the results,
+    /// see the [`nc_dictionary`](crate::nc_dictionary) module.
+    Dictionary(u32, Vec<u8>),
+    /// The server doesn't know the zstd dictionary with the given id: the answer to a message that has been compressed with it
+    /// and to the NCNodeMessage::GetDictionary message. The node stops using the dictionary and sends its message again.
+    UnknownDictionary(u32),
+}
+
+/// Why the server has rejected a node, see NCServerMessage::Rejected.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum NCRejectReason {
+    /// The server could not decrypt the message, the node uses another encryption key (or doesn't encrypt).
+    /// This rejection is sent without encryption, see the [`nc_communicator`](crate::nc_communicator) module.
+    WrongKey,
+    /// The server could not decode the message: the node runs another version of node_crunch or of the job (other data types).
+    /// This rejection is sent without encryption like WrongKey.
+    VersionMismatch,
+    /// The server doesn't support any of the codecs of the node. Contains the codecs of the server.
+    NoCommonCodec(Vec<NCCodec>),
+    /// The settings of the node don't match the settings of the server, contains every difference with the severity Error,
+    /// see [`NCConfiguration::diff()`](crate::NCConfiguration::diff).
+    ConfigMismatch(Vec<ConfigDiff>),
+    /// The [`NCMultiServerStarter`](crate::NCMultiServerStarter) doesn't run a job with the job id of the message (anymore).
+    /// Contains the job id, it's empty if the message didn't have one.
+    UnknownJob(String),
+    /// The server handles max_connections connections already (see the NCConfiguration), the node should try again later.
+    ServerBusy,
+    /// The job has been paused with NCAdminCommand::PauseJob, the server doesn't register new nodes until it's resumed.
+    Paused,
+    /// The node has been disabled with NCAdminCommand::DisableNode, the server doesn't send it any more data.
+    Disabled,
+    /// The new key of NCAdminCommand::RotateKey can't be used for encryption, the message says what's wrong with it.
+    /// The server keeps its current key.
+    InvalidKey,
+}
+
+impl NCRejectReason {
+    /// Returns true if the node should try again after retry_after (ServerBusy and Paused), the other reasons don't go away by waiting
+    /// and the node exits right away.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, NCRejectReason::ServerBusy | NCRejectReason::Paused)
+    }
+}
+
+impl<InitialDataT: Debug, NewDataT: Debug, CustomMessageT: Debug> Debug for NCServerMessage<InitialDataT, NewDataT, CustomMessageT> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NCServerMessage::InitialData(node_id, initial_data, codec) => f.debug_tuple("InitialData").field(node_id).field(initial_data).field(codec).finish(),
+            NCServerMessage::NoCommonCodec(codecs) => f.debug_tuple("NoCommonCodec").field(codecs).finish(),
+            NCServerMessage::JobStatus(job_status) => f.debug_tuple("JobStatus").field(job_status).finish(),
+            NCServerMessage::Statistics(statistics) => f.debug_tuple("Statistics").field(statistics).finish(),
+            NCServerMessage::NewServer(address, port) => f.debug_tuple("NewServer").field(address).field(port).finish(),
+            NCServerMessage::CustomMessage(message) => f.debug_tuple("CustomMessage").field(message).finish(),
+            NCServerMessage::Status(job_status) => f.debug_tuple("Status").field(job_status).finish(),
+            NCServerMessage::AdminAck => f.write_str("AdminAck"),
+            NCServerMessage::Unauthorized => f.write_str("Unauthorized"),
+            NCServerMessage::ResultAck { job_finished } => f.debug_struct("ResultAck").field("job_finished", job_finished).finish(),
+            NCServerMessage::ResultRejected(job_error) => f.debug_tuple("ResultRejected").field(job_error).finish(),
+            NCServerMessage::ServerFailed(job_error) => f.debug_tuple("ServerFailed").field(job_error).finish(),
+            NCServerMessage::RotateKey(_) => f.debug_tuple("RotateKey").field(&"***").finish(),
+            NCServerMessage::PleaseRestart { reason } => f.debug_struct("PleaseRestart").field("reason", reason).finish(),
+            NCServerMessage::UnknownJob(job_id) => f.debug_tuple("UnknownJob").field(job_id).finish(),
+            NCServerMessage::ConfigMismatch(diffs) => f.debug_tuple("ConfigMismatch").field(diffs).finish(),
+            NCServerMessage::ClockTime(server_time) => f.debug_tuple("ClockTime").field(server_time).finish(),
+            NCServerMessage::ChunkHistory(attempts) => f.debug_tuple("ChunkHistory").field(attempts).finish(),
+            NCServerMessage::ChunksRevoked(chunks) => f.debug_tuple("ChunksRevoked").field(chunks).finish(),
+            NCServerMessage::Rejected { reason, message, retry_after } =>
+                f.debug_struct("Rejected").field("reason", reason).field("message", message).field("retry_after", retry_after).finish(),
+            NCServerMessage::JobAborted(reason) => f.debug_tuple("JobAborted").field(reason).finish(),
+            NCServerMessage::Dictionary(id, dictionary) => f.debug_tuple("Dictionary").field(id).field(&format!("{} bytes", dictionary.len())).finish(),
+            NCServerMessage::UnknownDictionary(id) => f.debug_tuple("UnknownDictionary").field(id).finish(),
+        }
+    }
+}
+
+#[cfg(feature = "net")]
+impl<InitialDataT, NewDataT, CustomMessageT> NCTyped for NCServerMessage<InitialDataT, NewDataT, CustomMessageT> {
+    fn payload_type(&self) -> Option<&'static str> {
+        match self {
+            NCServerMessage::InitialData(_, Some(_), _) => Some(type_name::<InitialDataT>()),
+            NCServerMessage::JobStatus(NCJobStatus::Unfinished(_, _)) => Some(type_name::<NewDataT>()),
+            NCServerMessage::CustomMessage(_) => Some(type_name::<CustomMessageT>()),
+            _ => None,
+        }
+    }
+
+    fn payload_types() -> Vec<&'static str> {
+        vec![type_name::<InitialDataT>(), type_name::<NewDataT>(), type_name::<CustomMessageT>()]
+    }
+}
+
+/// The job status tells the node what to do next: process the new data, wait for other nodes to finish or exit. This is the answer from the server when
+/// a node request new data via the NCNodeMessage::NeedsData message.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub enum NCJobStatus<NewDataT> {
+    /// The job is not done yet and the node has to process the data the server sends to it.
+    /// Some information about the chunk is sent along with the data, see [`NCChunkInfo`].
+    /// The chunk info is in the versioned envelope, see the [`nc_envelope`](crate::nc_envelope) module.
+    Unfinished(NewDataT, #[serde(with = "crate::nc_envelope")] NCChunkInfo),
+    /// The server is still waiting for other nodes to finish the job. This means that all the work has already been distributed to all the nodes
+    /// and the server sends this message to the remaining nodes. It does this because some of the processing nodes can still crash, so that its work
+    /// has to be done by a waiting node.
+    Waiting,
+    /// Now all nodes are finished and the job is done. The server sends this message to all the nodes that request new data.
+    Finished,
+}
+
+/// The information about a chunk that the server sends to the node together with the data.
+/// The node can access it via the [`NCProcessContext`](crate::NCProcessContext).
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct NCChunkInfo {
+    /// The id of the chunk.
+    pub chunk_id: ChunkID,
+    /// The time the node has for processing the data, see [`NCServer::chunk_deadline()`](crate::NCServer::chunk_deadline).
+    pub deadline: Option<Duration>,
+    /// The average time from sending a chunk to a node until its result arrived, measured by the server.
+    /// This is just a hint, it's [`None`] until the first result has arrived.
+    pub average_chunk_time: Option<Duration>,
+    /// The aggregator the node sends the result to, see the [`nc_aggregator`](crate::nc_aggregator) module.
+    /// [`None`] if the result is sent to the server directly.
+    pub aggregator: Option<SocketAddr>,
+    /// The metadata of the chunk, see [`NCServer::chunk_meta()`](crate::NCServer::chunk_meta). [`None`] if there is none, so it doesn't need any space.
+    pub metadata: Option<ChunkMeta>,
+    /// How often the chunk has been sent to a node, including this time, see [`NCCallContext`](crate::NCCallContext).
+    /// 0 if the server doesn't count the attempts (version 1 of the envelope or an offline batch).
+    pub attempt: u32,
+    /// The time until the job stops handing out chunks (max_job_duration in the NCConfiguration) when the server has sent the chunk.
+    /// [`None`] if the job has no time limit (or the server is older than version 3 of the envelope).
+    pub job_time_remaining: Option<Duration>,
+    /// The id of the zstd dictionary that the node compresses its result with, see the [`nc_dictionary`](crate::nc_dictionary) module.
+    /// [`None`] if there is no dictionary (or the server is older than version 4 of the envelope).
+    pub result_dictionary: Option<u32>,
+}
+
+/// Some statistics about the server.
+/// This is the data that will be send when a
+/// NCNodeMessage::GetStatistics message arrived.
+/// More items may be added in the future.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NCServerStatistics {
+    /// Total number of nodes, includes inactive nodes
+    pub(crate) num_of_nodes: usize,
+    /// total time from start of server as secs
+    pub(crate) time_taken: f64,
+    /// Node ids and time since last heartbeat as secs
+    pub(crate) hb_time_stamps: Vec<(NodeID, f64)>,
+    /// Number of results waiting to be processed, in the result queue and in the current batch (see result_batch_size)
+    pub(crate) result_queue_len: u64,
+    /// Number of bytes of the results waiting in memory
+    pub(crate) result_queue_bytes: u64,
+    /// Number of results waiting on disk
+    pub(crate) result_queue_spilled: u64,
+    /// Number of chunks without any data
+    pub(crate) empty_chunks: u64,
+    /// Number of skipped chunks for each reason
+    pub(crate) skipped_chunks: Vec<(String, u64)>,
+    /// Number of chunks that have been sent again with the cached data
+    pub(crate) chunk_cache_hits: u64,
+    /// Number of chunks that had to be prepared again because the data was not in the cache
+    pub(crate) chunk_cache_misses: u64,
+    /// Number of chunks that have been sent with the serialized and compressed data from the frame cache
+    pub(crate) frame_cache_hits: u64,
+    /// Number of node connections that are currently handled
+    pub(crate) connections_in_flight: u64,
+    /// Number of node connections that have been closed right away because there were too many
+    pub(crate) rejected_connections: u64,
+    /// Number of node connections that have been closed because they were open for too long
+    pub(crate) killed_connections: u64,
+    /// Number of results for which the post processor has returned an error
+    pub(crate) post_process_failures: u64,
+    /// Number of nodes that have been asked to restart because they run an outdated build
+    pub(crate) restart_requests: u64,
+    /// Number of frames that have been sent uncompressed because they are smaller than compression_min_size
+    pub(crate) uncompressed_frames: u64,
+    /// Every group (node tag) and its number of nodes, includes inactive nodes
+    pub(crate) group_sizes: Vec<(String, u64)>,
+    /// Duration of the last heartbeat check in microseconds
+    pub(crate) heartbeat_sweep_micros: u64,
+    /// Duration of the slowest heartbeat check in microseconds
+    pub(crate) max_heartbeat_sweep_micros: u64,
+    /// Number of node connections from IPv4 addresses
+    pub(crate) ipv4_connections: u64,
+    /// Number of node connections from IPv6 addresses
+    pub(crate) ipv6_connections: u64,
+    /// Number of panics in the NCServer trait methods that have been caught
+    pub(crate) user_panics: u64,
+    /// Node ids and their clock skew, only the nodes that have sent a NCNodeMessage::ClockReport message
+    pub(crate) clock_skews: Vec<(NodeID, NCClockSkew)>,
+    /// Number of chunks that have been sent to a node with their shard, see locality_manifest
+    pub(crate) local_chunks: u64,
+    /// Number of chunks that have been sent to a node without their shard after waiting for locality_fallback_ms
+    pub(crate) locality_fallbacks: u64,
+}
+
+impl NCServerStatistics {
+    /// Total number of nodes, includes inactive nodes
+    pub fn num_of_nodes(&self) -> usize {
+        self.num_of_nodes
+    }
+
+    /// Total time from start of server as secs
+    pub fn time_taken(&self) -> f64 {
+        self.time_taken
+    }
+
+    /// Node ids and time since last heartbeat as secs
+    pub fn hb_time_stamps(&self) -> &[(NodeID, f64)] {
+        &self.hb_time_stamps
+    }
+
+    /// Number of results waiting to be processed
+    pub fn result_queue_len(&self) -> u64 {
+        self.result_queue_len
+    }
+
+    /// Number of bytes of the results waiting in memory
+    pub fn result_queue_bytes(&self) -> u64 {
+        self.result_queue_bytes
+    }
+
+    /// Number of results waiting on disk
+    pub fn result_queue_spilled(&self) -> u64 {
+        self.result_queue_spilled
+    }
+
+    /// Number of chunks without any data
+    pub fn empty_chunks(&self) -> u64 {
+        self.empty_chunks
+    }
+
+    /// Number of skipped chunks for each reason
+    pub fn skipped_chunks(&self) -> &[(String, u64)] {
+        &self.skipped_chunks
+    }
+
+    /// Number of chunks that have been sent again with the cached data
+    pub fn chunk_cache_hits(&self) -> u64 {
+        self.chunk_cache_hits
+    }
+
+    /// Number of chunks that had to be prepared again because the data was not in the cache
+    pub fn chunk_cache_misses(&self) -> u64 {
+        self.chunk_cache_misses
+    }
+
+    /// Number of chunks that have been sent with the serialized and compressed data from the frame cache (see cache_chunk_frames in the NCConfiguration)
+    pub fn frame_cache_hits(&self) -> u64 {
+        self.frame_cache_hits
+    }
+
+    /// Number of node connections that are currently handled
+    pub fn connections_in_flight(&self) -> u64 {
+        self.connections_in_flight
+    }
+
+    /// Number of node connections that have been closed right away because there were too many
+    pub fn rejected_connections(&self) -> u64 {
+        self.rejected_connections
+    }
+
+    /// Number of node connections that have been closed because they were open for too long
+    pub fn killed_connections(&self) -> u64 {
+        self.killed_connections
+    }
+
+    /// Number of results for which the post processor has returned an error
+    pub fn post_process_failures(&self) -> u64 {
+        self.post_process_failures
+    }
+
+    /// Number of nodes that have been asked to restart because they run an outdated build
+    pub fn restart_requests(&self) -> u64 {
+        self.restart_requests
+    }
+
+    /// Number of frames that have been sent uncompressed because they are smaller than compression_min_size (see NCConfiguration)
+    pub fn uncompressed_frames(&self) -> u64 {
+        self.uncompressed_frames
+    }
+
+    /// Every group (node tag) and its number of nodes sorted by tag, includes inactive nodes
+    pub fn group_sizes(&self) -> &[(String, u64)] {
+        &self.group_sizes
+    }
+
+    /// Duration of the last heartbeat check in microseconds, the server checks the heartbeats of the nodes every 2 * heartbeat seconds
+    pub fn heartbeat_sweep_micros(&self) -> u64 {
+        self.heartbeat_sweep_micros
+    }
+
+    /// Duration of the slowest heartbeat check in microseconds
+    pub fn max_heartbeat_sweep_micros(&self) -> u64 {
+        self.max_heartbeat_sweep_micros
+    }
+
+    /// Number of node connections from IPv4 addresses, IPv4 clients on an IPv6 socket (see BindMode) count as IPv4
+    pub fn ipv4_connections(&self) -> u64 {
+        self.ipv4_connections
+    }
+
+    /// Number of node connections from IPv6 addresses
+    pub fn ipv6_connections(&self) -> u64 {
+        self.ipv6_connections
+    }
+
+    /// Number of panics in the NCServer trait methods assign_chunk_with_context(), phase_finished() and process_data_with_meta() that have been caught
+    pub fn user_panics(&self) -> u64 {
+        self.user_panics
+    }
+
+    /// Node ids and their clock skew measured during the registration (see the [`nc_clock`](crate::nc_clock) module), includes inactive nodes
+    pub fn clock_skews(&self) -> &[(NodeID, NCClockSkew)] {
+        &self.clock_skews
+    }
+
+    /// Number of chunks that have been sent to a node with their shard (see the [`nc_locality`](crate::nc_locality) module),
+    /// chunks that are not in the locality manifest count too
+    pub fn local_chunks(&self) -> u64 {
+        self.local_chunks
+    }
+
+    /// Number of chunks that have been sent to a node without their shard after they have waited for locality_fallback_ms (see NCConfiguration)
+    pub fn locality_fallbacks(&self) -> u64 {
+        self.locality_fallbacks
+    }
+}
+
+/// This message is sent from the node to the server in order to register, receive new data and send processed data.
+#[cfg(feature = "net")]
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum NCNodeMessage<ProcessedDataT, CustomMessageT> {
+    /// Register this node with the server. The server will assign a new node id to this node and answers with a NCServerMessage::InitialData message.
+    /// The node sends the list of codecs it can use, the server chooses one of them for this node.
+    /// The node also sends its build (see node_build in the NCConfiguration) and if it is an aggregator the port it listens on
+    /// and its aggregator tag (see aggregator_port and aggregator_tag in the NCConfiguration), the groups it belongs to (node_tags)
+    /// and the settings that must match the server. Without these settings (text protocol: `null`) they are not compared.
+    /// The settings are in the versioned envelope, see the [`nc_envelope`](crate::nc_envelope) module.
+    /// This is the first thing every node has to do!
+    Register(Vec<NCCodec>, Option<String>, Option<u16>, Option<String>, Vec<String>, #[serde(with = "crate::nc_envelope::option")] Option<NCSharedSettings>),
+    /// This node needs new data to process. The server answers with a JobStatus message.
+    NeedsData(NodeID),
+    /// This node has finished processing the data and sends it to the server. The server answers with a ResultAck message.
+    HasData(NodeID, ProcessedDataT),
+    /// The chunk of this node didn't produce any data, the server calls chunk_empty() and answers with a ResultAck message.
+    Empty(NodeID),
+    /// This node has skipped the chunk for the given reason, the server calls chunk_skipped() and answers with a ResultAck message.
+    Skip(NodeID, String),
+    /// This node could not process the data, the user code returned a NCError::Job error. No answer from the server.
+    NodeFailed(NodeID, NCJobError),
+    /// This node sends a heartbeat message every n seconds. The time span between two heartbeats is set in the configuration NCConfiguration.
+    /// If report_resources is set in the configuration the node also sends its current resources.
+    HeartBeat(NodeID, Option<NCResourceReport>),
+    /// This is a message that the server sends to itself to break out from blocking on node connection via accept() and
+    /// start checking the heartbeat time stamps of all nodes.
+    CheckHeartbeat,
+    /// Get some statistics from the server:
+    /// - number of active nodes (node ids)
+    /// - other items
+    GetStatistics,
+    /// Tell the server to shut down. A server with an admin key answers with a NCServerMessage::Unauthorized message,
+    /// it only accepts the signed NCAdminCommand::ShutDown command.
+    ShutDown,
+    /// Move all the nodes to a new server given by address and port, like ShutDown only without an admin key on the server
+    /// (NCAdminCommand::NewServer otherwise).
+    NewServer(String, u16),
+    /// Register migrated node to new server
+    NodeMigrated(NodeID),
+    /// Send a custom message to one or all nodes
+    CustomMessage(CustomMessageT, Option<NodeID>),
+    /// A command signed with the admin key. The server answers with a NCServerMessage::Unauthorized message
+    /// if the signature is not valid.
+    Admin(NCAdminMessage),
+    /// This node sends its result for the given chunk to its aggregator instead of the server. The aggregator answers with
+    /// a ResultAck message once the server has handed the chunk over to it, see the [`nc_aggregator`](crate::nc_aggregator) module.
+    Aggregate(NodeID, ChunkID, ProcessedDataT),
+    /// The aggregator (first node id) takes over the chunk of the given node (second node id). The server calls chunk_delegated()
+    /// and answers with a ResultAck message, or a ResultRejected message if the chunk can't be delegated.
+    Delegated(NodeID, NodeID, ChunkID),
+    /// The aggregator sends the combined result of the given chunks, the server calls process_aggregate() and answers with a
+    /// ResultAck message. The result is [`None`] if reduce() has failed, then the chunks are given to a node again.
+    Aggregated(NodeID, Vec<ChunkID>, Option<ProcessedDataT>),
+    /// Same as NeedsData but with a hint how much work this node would like to get, see [`NCWorkHint`].
+    /// This is a separate message so that nodes without a hint send the same NeedsData message as older nodes.
+    NeedsDataWithHint(NodeID, NCWorkHint),
+    /// Same as HasData but with the metadata of the chunk that the server has sent together with the data (see [`ChunkMeta`]).
+    /// This is a separate message so that chunks without metadata send the same HasData message as older nodes.
+    HasDataWithMeta(NodeID, ChunkMeta, ProcessedDataT),
+    /// Same as NeedsData (with an optional work hint), but the node is still processing its current chunk and asks for the next one
+    /// in advance, see prefetch in the NCConfiguration. The server then keeps track of both chunks.
+    NeedsDataPrefetch(NodeID, Option<NCWorkHint>),
+    /// The node gives back the chunk that it has fetched in advance without processing it, for example because its current chunk
+    /// has failed or the node has been stopped. The server calls chunk_send_failed() and answers with a ResultAck message.
+    ReleaseChunk(NodeID, ChunkID),
+    /// The node sends its public key (32 bytes) right after the registration, see the nc_keys module (ed25519 feature).
+    /// The server answers with a ResultAck message or with Unauthorized if it doesn't accept the key.
+    RegisterKey(NodeID, Vec<u8>),
+    /// Same as HasDataWithMeta but signed by the node: the chunk id, the optional metadata, the signature and the serialized result.
+    /// The signature is over the chunk id and the hash of the serialized result, so that the server can check exactly the bytes that the node has signed.
+    HasSignedData(NodeID, ChunkID, Option<ChunkMeta>, Vec<u8>, Vec<u8>),
+    /// The node belongs to these groups from now on, it leaves all the other groups.
+    SetTags(NodeID, Vec<String>),
+    /// Send a custom message to all nodes of the group with the given tag.
+    GroupMessage(CustomMessageT, String),
+    /// The server sends the state of the chunks to its standby server, see the [`nc_replication`](crate::nc_replication) module.
+    /// The standby answers with a ResultAck message or with a ServerFailed message if it could not apply the update.
+    Replicate(NCReplicationUpdate),
+    /// An artifact (name and data) of the given chunk that the node sends after the result, see the [`nc_artifacts`](crate::nc_artifacts) module.
+    /// The server answers with a ResultAck message, also if it has dropped the artifact.
+    Artifact(NodeID, ChunkID, String, Vec<u8>),
+    /// The node sends its time in microseconds since the unix epoch right after the registration, the server answers with a
+    /// ClockTime message, see the [`nc_clock`](crate::nc_clock) module.
+    ClockProbe(NodeID, u64),
+    /// The time stamps of the clock measurement: the time of the node when it sent the ClockProbe message, the time of the server
+    /// from the ClockTime message and the time of the node when that answer arrived. No answer from the server.
+    ClockReport(NodeID, u64, u64, u64),
+    /// The node offers the result of the given chunk that it has cached because the server has not acknowledged it,
+    /// see the [`nc_result_cache`](crate::nc_result_cache) module. The server answers with a ResultAck message if it takes the result
+    /// and with a ResultRejected message if it has the result already or the chunk belongs to another node.
+    HasCachedData(NodeID, ChunkID, Option<ChunkMeta>, ProcessedDataT),
+    /// Same as HeartBeat, but the server answers with a ChunksRevoked message: the chunks of the node that have been revoked
+    /// with [`NCServerHandle::cancel_chunk()`](crate::NCServerHandle::cancel_chunk).
+    PollHeartBeat(NodeID, Option<NCResourceReport>),
+    /// The node has dropped the given chunk without a result because it has been revoked. The server answers with a ResultAck message.
+    ChunkRevoked(NodeID, ChunkID),
+    /// Same as Register, but for a node that has been registered before with the given node id and incarnation (see node_id_file
+    /// in the NCConfiguration): a new process takes over the node id. If the server knows the node id with a lower incarnation,
+    /// the old process is handled like a node with a heartbeat timeout and the new one keeps the node id. Otherwise (the same or a lower
+    /// incarnation, for example a copy of the file) it gets a new node id. The server answers with a NCServerMessage::InitialData message.
+    Rejoin(NodeID, u32, Vec<NCCodec>, Option<String>, Option<u16>, Option<String>, Vec<String>, #[serde(with = "crate::nc_envelope::option")] Option<NCSharedSettings>),
+    /// The node asks for the zstd dictionary with the given id from the chunk info, see the [`nc_dictionary`](crate::nc_dictionary) module.
+    /// The server answers with a NCServerMessage::Dictionary message or with UnknownDictionary.
+    GetDictionary(NodeID, u32),
+    // More items may be added in the future
+}
+
+#[cfg(feature = "net")]
+impl<ProcessedDataT, CustomMessageT> NCNodeMessage<ProcessedDataT, CustomMessageT> {
+    /// The id of the node that has sent the message, [`None`] for messages without one (for example Register or GetStatistics).
+    pub(crate) fn node_id(&self) -> Option<NodeID> {
+        match self {
+            NCNodeMessage::NeedsData(node_id) | NCNodeMessage::HasData(node_id, _) | NCNodeMessage::Empty(node_id) |
+            NCNodeMessage::Skip(node_id, _) | NCNodeMessage::NodeFailed(node_id, _) | NCNodeMessage::HeartBeat(node_id, _) |
+            NCNodeMessage::Aggregate(node_id, _, _) | NCNodeMessage::Aggregated(node_id, _, _) | NCNodeMessage::NeedsDataWithHint(node_id, _) |
+            NCNodeMessage::HasDataWithMeta(node_id, _, _) | NCNodeMessage::NeedsDataPrefetch(node_id, _) | NCNodeMessage::ReleaseChunk(node_id, _) |
+            NCNodeMessage::RegisterKey(node_id, _) | NCNodeMessage::HasSignedData(node_id, _, _, _, _) | NCNodeMessage::SetTags(node_id, _) |
+            NCNodeMessage::Artifact(node_id, _, _, _) | NCNodeMessage::ClockProbe(node_id, _) |
+            NCNodeMessage::ClockReport(node_id, _, _, _) | NCNodeMessage::HasCachedData(node_id, _, _, _) |
+            NCNodeMessage::PollHeartBeat(node_id, _) | NCNodeMessage::ChunkRevoked(node_id, _) |
+            NCNodeMessage::Rejoin(node_id, _, _, _, _, _, _, _) | NCNodeMessage::GetDictionary(node_id, _) => Some(*node_id),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the server answers this message.
+    pub(crate) fn needs_answer(&self) -> bool {
+        matches!(self, NCNodeMessage::Register(_, _, _, _, _, _) | NCNodeMessage::NeedsData(_) | NCNodeMessage::HasData(_, _) |
+            NCNodeMessage::Empty(_) | NCNodeMessage::Skip(_, _) | NCNodeMessage::GetStatistics | NCNodeMessage::Admin(_) |
+            NCNodeMessage::Aggregate(_, _, _) | NCNodeMessage::Delegated(_, _, _) | NCNodeMessage::Aggregated(_, _, _) |
+            NCNodeMessage::NeedsDataWithHint(_, _) | NCNodeMessage::HasDataWithMeta(_, _, _) | NCNodeMessage::NeedsDataPrefetch(_, _) |
+            NCNodeMessage::ReleaseChunk(_, _) | NCNodeMessage::RegisterKey(_, _) | NCNodeMessage::HasSignedData(_, _, _, _, _) |
+            NCNodeMessage::Replicate(_) | NCNodeMessage::Artifact(_, _, _, _) | NCNodeMessage::ClockProbe(_, _) |
+            NCNodeMessage::HasCachedData(_, _, _, _) | NCNodeMessage::PollHeartBeat(_, _) | NCNodeMessage::ChunkRevoked(_, _) |
+            NCNodeMessage::Rejoin(_, _, _, _, _, _, _, _) | NCNodeMessage::GetDictionary(_, _))
+    }
+
+    /// Returns true if the message can be sent again after a transient error, even if the server may have got it already.
+    /// These are control messages that don't change anything when they arrive twice, see [`NCError::is_transient()`](crate::NCError::is_transient).
+    pub(crate) fn can_resend(&self) -> bool {
+        matches!(self, NCNodeMessage::HeartBeat(_, _) | NCNodeMessage::PollHeartBeat(_, _) | NCNodeMessage::CheckHeartbeat | NCNodeMessage::GetStatistics |
+            NCNodeMessage::SetTags(_, _) | NCNodeMessage::RegisterKey(_, _) | NCNodeMessage::ClockProbe(_, _) |
+            NCNodeMessage::ClockReport(_, _, _, _) | NCNodeMessage::GetDictionary(_, _))
+    }
+}
+
+#[cfg(feature = "net")]
+impl<ProcessedDataT, CustomMessageT> NCTyped for NCNodeMessage<ProcessedDataT, CustomMessageT> {
+    fn payload_type(&self) -> Option<&'static str> {
+        match self {
+            NCNodeMessage::HasData(_, _) | NCNodeMessage::Aggregate(_, _, _) | NCNodeMessage::Aggregated(_, _, Some(_)) |
+            NCNodeMessage::HasDataWithMeta(_, _, _) | NCNodeMessage::HasSignedData(_, _, _, _, _) | NCNodeMessage::HasCachedData(_, _, _, _) =>
+                Some(type_name::<ProcessedDataT>()),
+            NCNodeMessage::CustomMessage(_, _) | NCNodeMessage::GroupMessage(_, _) => Some(type_name::<CustomMessageT>()),
+            _ => None,
+        }
+    }
+
+    fn payload_types() -> Vec<&'static str> {
+        vec![type_name::<ProcessedDataT>(), type_name::<CustomMessageT>()]
+    }
+}
+
+/// How much work a node would like to get, this is sent to the server when the node needs new data.
+/// It's just a hint: the server passes it to the NCServer trait method
+/// [`assign_chunk_with_context()`](crate::NCServer::assign_chunk_with_context), the user code there decides what to do with it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NCWorkHint {
+    /// The largest chunk in bytes (serialized) that the node would like to get.
+    pub max_bytes: Option<u64>,
+    /// How long the node would like to work on one chunk.
+    pub max_duration_hint: Option<Duration>,
+    /// The kind of work the node prefers, for example "gpu". The meaning is up to the user code.
+    pub preferred_kind: Option<String>,
+}
+
+#[cfg(feature = "net")]
+impl NCWorkHint {
+    /// Creates the hint from the given configuration, [`None`] if nothing is set there.
+    pub(crate) fn from_config(config: &NCConfiguration) -> Option<Self> {
+        let work_hint = NCWorkHint {
+            max_bytes: if config.work_hint_max_bytes > 0 { Some(config.work_hint_max_bytes) } else { None },
+            max_duration_hint: if config.work_hint_max_duration > 0 { Some(Duration::from_secs(config.work_hint_max_duration)) } else { None },
+            preferred_kind: config.work_hint_kind.clone(),
+        };
+
+        if work_hint == NCWorkHint::default() {
+            None
+        } else {
+            Some(work_hint)
+        }
+    }
+}
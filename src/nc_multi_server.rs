@@ -0,0 +1,441 @@
+//! This module contains the NCMultiServerStarter that runs several jobs on the same port.
+//! Sometimes only one port is open in the firewall, but several jobs should run at the same time. Every node sets job_id
+//! in its NCConfiguration, the job id is sent in front of every message (see the [`nc_communicator`](crate::nc_communicator) module),
+//! so the message can be routed to the right job without decoding it.
+//! Each job has its own server process with its own node list, chunks, result queue, statistics, heartbeat thread and finished state.
+//! When one job is done its finish_job() is called and its nodes are rejected with NCRejectReason::UnknownJob from then on,
+//! the other jobs keep running. The server exits when all jobs are done.
+//! All jobs use the same NCConfiguration (port, keys, codecs, ...).
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{error, warn, info, debug};
+use threadpool::ThreadPool;
+
+use crate::nc_error::NCError;
+use crate::nc_config::NCConfiguration;
+use crate::nc_communicator::{NCCommunicator, split_job_id};
+use crate::nc_server::{NCServer, NCServerMessage, NCServerProcess, NCServerHeartbeat, NCServerHandle};
+use crate::nc_message::NCRejectReason;
+use crate::nc_watchdog::NCConnectionWatchdog;
+use crate::nc_listener::{NCListener, loopback};
+use crate::nc_transport::NCStream;
+
+/// One job of the NCMultiServerStarter, this is implemented by the server process of every NCServer.
+pub(crate) trait NCJob: Send + Sync {
+    /// Decodes and handles one message from a node, the message has already been read from the stream.
+    fn handle_frame(&self, data: &[u8], stream: NCStream) -> Result<(), NCError>;
+    /// Returns true if the job is finished.
+    fn is_job_done(&self) -> bool;
+    /// Processes the results until the job is done and then calls the NCServer trait method finish_job().
+    fn run(self: Arc<Self>) -> Result<(), NCError>;
+}
+
+/// The jobs that are still running, messages for other job ids are rejected with NCRejectReason::UnknownJob.
+struct NCJobRouter {
+    /// The running jobs by job id.
+    jobs: Mutex<HashMap<String, Arc<dyn NCJob>>>,
+    /// Only used for the NCRejectReason::UnknownJob rejection.
+    nc_communicator: Mutex<NCCommunicator>,
+    /// Frames from the nodes must not be longer, see max_frame_bytes in the NCConfiguration.
+    max_frame_bytes: u64,
+}
+
+impl NCJobRouter {
+    /// Creates a new router for the given jobs.
+    fn new(config: &NCConfiguration, jobs: HashMap<String, Arc<dyn NCJob>>) -> Result<Self, NCError> {
+        debug!("NCJobRouter::new()");
+
+        Ok(NCJobRouter {
+            jobs: Mutex::new(jobs),
+            nc_communicator: Mutex::new(NCCommunicator::new(config)?),
+            max_frame_bytes: config.max_frame_bytes,
+        })
+    }
+
+    /// The job is done, messages for it are not routed anymore.
+    fn remove(&self, job_id: &str) -> Result<(), NCError> {
+        self.jobs.lock()?.remove(job_id);
+        Ok(())
+    }
+
+    /// Returns true if all jobs are done.
+    fn is_empty(&self) -> Result<bool, NCError> {
+        Ok(self.jobs.lock()?.is_empty())
+    }
+
+    /// Reads one message from the stream and hands it to the job with the job id of the message.
+    /// A connection that is closed before anything has been sent is ignored, this is used to wake up the main loop.
+    fn handle_connection(&self, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("NCJobRouter::handle_connection()");
+
+        let data = match NCCommunicator::nc_receive_frame(&mut stream, self.max_frame_bytes) {
+            Ok(data) => data,
+            Err(NCError::UnexpectedEof("length", 0, _)) => {
+                debug!("Connection closed without a message");
+                return Ok(())
+            }
+            Err(e) => return Err(e)
+        };
+
+        let job_id = split_job_id(&data)?.0.unwrap_or_default();
+        let job = self.jobs.lock()?.get(&job_id).cloned();
+
+        match job {
+            Some(job) => job.handle_frame(&data, stream),
+            None => {
+                info!("Message for unknown job: '{}', from: {}", job_id, stream.peer_addr()?);
+                self.send_unknown_job_message(job_id, stream)
+            }
+        }
+    }
+
+    /// Rejects the node with NCRejectReason::UnknownJob and the given job id.
+    fn send_unknown_job_message(&self, job_id: String, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("NCJobRouter::send_unknown_job_message()");
+        let message = format!("the server doesn't run the job '{}' (anymore), job_id of the node must be one of the running jobs", job_id);
+        let message: NCServerMessage<(), (), ()> = NCServerMessage::Rejected { reason: NCRejectReason::UnknownJob(job_id), message, retry_after: None };
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+}
+
+/// Main data structure for managing and starting several jobs on one port.
+pub struct NCMultiServerStarter {
+    /// Configuration for the server and all the jobs.
+    config: NCConfiguration,
+    /// The jobs that will be started by start().
+    jobs: HashMap<String, Arc<dyn NCJob>>,
+    /// Knows the address of the server once it's listening, see server_handle().
+    server_handle: NCServerHandle,
+}
+
+impl NCMultiServerStarter {
+    /// Create a new NCMultiServerStarter using the given configuration, jobs are added with add_job().
+    pub fn new(config: NCConfiguration) -> Self {
+        debug!("NCMultiServerStarter::new()");
+
+        NCMultiServerStarter{ config, jobs: HashMap::new(), server_handle: NCServerHandle::default() }
+    }
+
+    /// Returns a handle with the address that the server is listening on once start() has opened the socket,
+    /// this is useful with port 0 in the NCConfiguration.
+    pub fn server_handle(&self) -> NCServerHandle {
+        debug!("NCMultiServerStarter::server_handle()");
+
+        self.server_handle.clone()
+    }
+
+    /// Adds a job that is started together with the other jobs in start(). The nodes for this job must use the same job_id in their NCConfiguration.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::InvalidJobID`] error if the job id is empty, longer than 255 bytes or has already been added
+    /// and a [`NCError::InvalidKey`] error if the encryption keys in the NCConfiguration have the wrong format.
+    pub fn add_job<T: NCServer + Send + 'static>(&mut self, job_id: &str, nc_server: T) -> Result<(), NCError> {
+        debug!("NCMultiServerStarter::add_job()");
+
+        if job_id.is_empty() || job_id.len() > 255 || self.jobs.contains_key(job_id) {
+            return Err(NCError::InvalidJobID(job_id.to_string()))
+        }
+
+        self.config.check_keys()?;
+
+        let server_process = NCServerProcess::new(&self.config, nc_server)?;
+        self.jobs.insert(job_id.to_string(), Arc::new(server_process));
+        Ok(())
+    }
+
+    /// This is the main method that you call when you start the server, after all the jobs have been added.
+    /// It returns when all jobs are done.
+    pub fn start(&mut self) -> Result<(), NCError> {
+        debug!("NCMultiServerStarter::start()");
+
+        self.config.check_keys()?;
+        let jobs = std::mem::take(&mut self.jobs);
+        info!("Starting jobs: {:?}", jobs.keys().collect::<Vec<_>>());
+
+        let listener = NCListener::bind(&self.config)?.with_transport(&self.config.transport)?;
+        let port = listener.port()?;
+        self.server_handle.set_local_addr(listener.local_addr()?)?;
+        let router = Arc::new(NCJobRouter::new(&self.config, jobs.clone())?);
+        let watchdog = Arc::new(NCConnectionWatchdog::new(&self.config));
+        let thread_pool = ThreadPool::new(self.config.pool_size as usize);
+
+        let job_threads = jobs.into_iter().map(|(job_id, job)| {
+            self.start_heartbeat_thread(&job_id, job.clone(), port)?;
+            Ok(self.start_job_thread(job_id, job, router.clone(), port))
+        }).collect::<Result<Vec<JoinHandle<()>>, NCError>>()?;
+
+        let watchdog_thread = self.start_watchdog_thread(watchdog.clone(), router.clone());
+        self.accept_connections(&listener, &thread_pool, &router, &watchdog);
+
+        for job_thread in job_threads {
+            if job_thread.join().is_err() {
+                error!("Job thread panicked");
+            }
+        }
+
+        if watchdog_thread.join().is_err() {
+            error!("Watchdog thread panicked");
+        }
+
+        let closed = watchdog.drain(Duration::from_millis(self.config.shutdown_grace_ms))?;
+
+        if closed > 0 {
+            warn!("{} node connections have been closed at shutdown", closed);
+        }
+
+        thread_pool.join();
+
+        Ok(())
+    }
+
+    /// Sends the NCNodeMessage::CheckHeartbeat message to the given job on the given port, like NCServerStarter does it for its only job.
+    /// The thread exits when the job is done.
+    fn start_heartbeat_thread(&self, job_id: &str, job: Arc<dyn NCJob>, port: u16) -> Result<(), NCError> {
+        debug!("NCMultiServerStarter::start_heartbeat_thread()");
+
+        let config = NCConfiguration { job_id: Some(job_id.to_string()), port, ..self.config.clone() };
+        let server_heartbeat = NCServerHeartbeat::new(&config)?;
+
+        thread::spawn(move || {
+            loop {
+                server_heartbeat.sleep();
+
+                if job.is_job_done() {
+                    break
+                }
+
+                if let Err(e) = server_heartbeat.send_check_heartbeat_message() {
+                    error!("Error in start_heartbeat_thread(), couldn't send CheckHeartbeat message: {}", e);
+                    break
+                }
+            }
+            debug!("Exit start_heartbeat_thread() main loop");
+        });
+
+        Ok(())
+    }
+
+    /// Runs the given job until it's done, then its messages are not routed anymore.
+    /// Afterwards the main loop is woken up on the given port, so that it can exit if this was the last job.
+    fn start_job_thread(&self, job_id: String, job: Arc<dyn NCJob>, router: Arc<NCJobRouter>, port: u16) -> JoinHandle<()> {
+        debug!("NCMultiServerStarter::start_job_thread()");
+
+        let wake_addr = SocketAddr::new(loopback(&self.config), port);
+
+        thread::spawn(move || {
+            if let Err(e) = job.run() {
+                error!("Error in job '{}': {}", job_id, e);
+            }
+
+            info!("Job '{}' is done", job_id);
+
+            if let Err(e) = router.remove(&job_id) {
+                error!("Could not remove job '{}': {}", job_id, e);
+            }
+
+            if let Err(e) = TcpStream::connect(wake_addr) {
+                debug!("Could not wake up main loop: {}", e);
+            }
+        })
+    }
+
+    /// The watchdog thread closes the node connections of all jobs that are open for too long, see [`NCConnectionWatchdog`].
+    /// It exits when all jobs are done.
+    fn start_watchdog_thread(&self, watchdog: Arc<NCConnectionWatchdog>, router: Arc<NCJobRouter>) -> JoinHandle<()> {
+        debug!("NCMultiServerStarter::start_watchdog_thread()");
+
+        thread::spawn(move || {
+            while !router.is_empty().unwrap_or(true) {
+                thread::sleep(watchdog.interval());
+
+                if let Err(e) = watchdog.check() {
+                    error!("Error in start_watchdog_thread(): {}", e);
+                }
+            }
+            debug!("Exit start_watchdog_thread() main loop");
+        })
+    }
+
+    /// Accepts the node connections for all jobs until all jobs are done.
+    /// Every connection is handled in the thread pool, see NCJobRouter::handle_connection().
+    fn accept_connections(&self, listener: &NCListener, thread_pool: &ThreadPool, router: &Arc<NCJobRouter>, watchdog: &Arc<NCConnectionWatchdog>) {
+        debug!("NCMultiServerStarter::accept_connections()");
+
+        loop {
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    debug!("Connection from node: {}", addr);
+
+                    match NCConnectionWatchdog::register(watchdog, &stream) {
+                        Ok(Some(guard)) => {
+                            let router = router.clone();
+
+                            thread_pool.execute(move || {
+                                match router.handle_connection(stream) {
+                                    Ok(()) => (),
+                                    Err(e @ NCError::PeerDisconnected(_)) => warn!("Node has disconnected in handle_connection(): {}", e),
+                                    Err(e) => error!("Error in handle_connection(): {}", e),
+                                }
+
+                                drop(guard);
+                            });
+                        }
+                        Ok(None) => (), // Too many connections, the stream is closed here
+                        Err(e) => error!("Could not register connection: {}", e),
+                    }
+                }
+                Err(e) => {
+                    error!("IO error while accepting node connections: {}", e);
+                }
+            }
+
+            if router.is_empty().unwrap_or(true) {
+                break
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::nc_client::NCClient;
+    use crate::nc_node_info::NodeID;
+    use crate::nc_server::{ChunkAssignment, NCJobStatus};
+    use crate::array2d::ChunkID;
+
+    /// Hands out the numbers 0..chunks, then the job is finished.
+    struct CountServer {
+        chunks: u32,
+        next: u32,
+        finished: Arc<AtomicBool>,
+    }
+
+    impl NCServer for CountServer {
+        type InitialDataT = ();
+        type NewDataT = u32;
+        type ProcessedDataT = u32;
+        type CustomMessageT = ();
+
+        fn assign_chunk(&mut self, _node_id: NodeID) -> Result<ChunkAssignment<u32>, NCError> {
+            if self.next == self.chunks {
+                return Ok(ChunkAssignment::Finished)
+            }
+
+            self.next += 1;
+            Ok(ChunkAssignment::Assigned(self.next as ChunkID - 1, self.next - 1))
+        }
+
+        fn process_data_from_node(&mut self, _node_id: NodeID, _data: &u32) -> Result<(), NCError> {
+            Ok(())
+        }
+
+        fn heartbeat_timeout(&mut self, _nodes: Vec<NodeID>) {}
+
+        fn finish_job(&mut self) {
+            self.finished.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Routes all connections to the listener with the given router, returns the port.
+    fn serve(router: Arc<NCJobRouter>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let router = router.clone();
+                thread::spawn(move || router.handle_connection(Box::new(stream.unwrap())));
+            }
+        });
+
+        port
+    }
+
+    fn client_for(port: u16, job_id: Option<&str>) -> NCClient {
+        let config = NCConfiguration { port, job_id: job_id.map(String::from), ..Default::default() };
+        NCClient::connect(&config).unwrap()
+    }
+
+    fn next_chunk(client: &mut NCClient) -> Option<u32> {
+        match client.request_data::<u32, ()>().unwrap() {
+            NCServerMessage::JobStatus(NCJobStatus::Unfinished(data, _)) => Some(data),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_add_job() {
+        let mut starter = NCMultiServerStarter::new(NCConfiguration::default());
+        let server = || CountServer { chunks: 1, next: 0, finished: Arc::new(AtomicBool::new(false)) };
+
+        starter.add_job("a", server()).unwrap();
+        assert!(matches!(starter.add_job("a", server()), Err(NCError::InvalidJobID(_))));
+        assert!(matches!(starter.add_job("", server()), Err(NCError::InvalidJobID(_))));
+        assert!(matches!(starter.add_job(&"x".repeat(256), server()), Err(NCError::InvalidJobID(_))));
+        assert_eq!(starter.jobs.len(), 1);
+    }
+
+    #[test]
+    fn test_route_jobs() {
+        let finished_a = Arc::new(AtomicBool::new(false));
+        let finished_b = Arc::new(AtomicBool::new(false));
+        let mut starter = NCMultiServerStarter::new(NCConfiguration::default());
+        starter.add_job("a", CountServer { chunks: 1, next: 0, finished: finished_a.clone() }).unwrap();
+        starter.add_job("b", CountServer { chunks: 3, next: 0, finished: finished_b.clone() }).unwrap();
+
+        let job_a = starter.jobs["a"].clone();
+        let job_b = starter.jobs["b"].clone();
+        let router = Arc::new(NCJobRouter::new(&starter.config, starter.jobs.clone()).unwrap());
+        let port = serve(router.clone());
+
+        let mut client_a = client_for(port, Some("a"));
+        let mut client_b = client_for(port, Some("b"));
+        let mut client_b2 = client_for(port, Some("b"));
+        client_a.register::<()>().unwrap();
+        client_b.register::<()>().unwrap();
+        client_b2.register::<()>().unwrap();
+
+        // Every job has its own nodes and chunks
+        assert_eq!(client_a.get_statistics().unwrap().num_of_nodes(), 1);
+        assert_eq!(client_b.get_statistics().unwrap().num_of_nodes(), 2);
+        assert_eq!(next_chunk(&mut client_a), Some(0));
+        assert_eq!(next_chunk(&mut client_b), Some(0));
+        assert_eq!(next_chunk(&mut client_b2), Some(1));
+
+        // Unknown jobs and nodes without a job id
+        assert!(matches!(client_for(port, Some("c")).register::<()>(), Err(NCError::UnknownJob(job_id)) if job_id == "c"));
+        assert!(matches!(client_for(port, None).register::<()>(), Err(NCError::UnknownJob(job_id)) if job_id.is_empty()));
+
+        // Job a is finished, the node is told to exit
+        assert!(matches!(client_a.request_data::<u32, ()>(), Ok(NCServerMessage::JobStatus(NCJobStatus::Finished))));
+        assert!(job_a.is_job_done());
+        assert!(!job_b.is_job_done());
+
+        job_a.run().unwrap();
+        router.remove("a").unwrap();
+        assert!(finished_a.load(Ordering::Relaxed));
+        assert!(!router.is_empty().unwrap());
+
+        // Job b is not disturbed
+        assert!(matches!(client_a.request_data::<u32, ()>(), Err(NCError::UnknownJob(job_id)) if job_id == "a"));
+        assert_eq!(next_chunk(&mut client_b), Some(2));
+        assert!(!finished_b.load(Ordering::Relaxed));
+
+        // Connections without a message are ignored
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        drop(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        assert!(router.handle_connection(Box::new(listener.accept().unwrap().0)).is_ok());
+    }
+}
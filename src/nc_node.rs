@@ -2,49 +2,39 @@
 //! To use the node you have to implement the NCNode trait that has two methods:
 //! set_initial_data() and process_data_from_server()
 
-use std::net::{IpAddr, SocketAddr};
-use std::{time::Duration};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use std::thread::{self, spawn, JoinHandle};
-use std::sync::{Arc, Mutex};
-
-use log::{error, info, debug};
-use serde::{Serialize, Deserialize, de::DeserializeOwned};
-
-use crate::nc_error::NCError;
-use crate::nc_server::{NCServerMessage, NCJobStatus};
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::HashSet;
+
+use log::{error, info, debug, warn};
+use rand::Rng;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::nc_error::{NCError, NCJobError, TRANSIENT_RETRIES, transient_delay};
+use crate::nc_server::{NCServerMessage, NCJobStatus, NCChunkInfo};
+pub(crate) use crate::nc_message::NCNodeMessage;
+pub use crate::nc_message::NCWorkHint;
 use crate::nc_config::NCConfiguration;
-use crate::nc_node_info::NodeID;
-use crate::nc_communicator::{NCCommunicator};
-
-/// This message is sent from the node to the server in order to register, receive new data and send processed data.
-#[derive(Debug, Serialize, Deserialize)]
-pub(crate) enum NCNodeMessage<ProcessedDataT, CustomMessageT> {
-    /// Register this node with the server. The server will assign a new node id to this node and answers with a NCServerMessage::InitialData message.
-    /// This is the first thing every node has to do!
-    Register,
-    /// This node needs new data to process. The server answers with a JobStatus message.
-    NeedsData(NodeID),
-    /// This node has finished processing the data and sends it to the server. No answer from the server.
-    HasData(NodeID, ProcessedDataT),
-    /// This node sends a heartbeat message every n seconds. The time span between two heartbeats is set in the configuration NCConfiguration.
-    HeartBeat(NodeID),
-    /// This is a message that the server sends to itself to break out from blocking on node connection via accept() and
-    /// start checking the heartbeat time stamps of all nodes.
-    CheckHeartbeat,
-    /// Get some statistics from the server:
-    /// - number of active nodes (node ids)
-    /// - other items
-    GetStatistics,
-    /// Tell the server to shut down
-    ShutDown,
-    /// Move all the nodes to a new server given by address and port
-    NewServer(String, u16),
-    /// Register migrated node to new server
-    NodeMigrated(NodeID),
-    /// Send a custom message to one or all nodes
-    CustomMessage(CustomMessageT, Option<NodeID>),
-    // More items may be added in the future
-}
+use crate::nc_node_info::{NodeID, HEARTBEAT_JITTER};
+use crate::nc_client::NCClient;
+use crate::nc_scratch_dir::ScratchDir;
+use crate::nc_node_id_file::NCNodeIdFile;
+use crate::nc_throttle::NCThrottle;
+use crate::nc_artifacts::{NCArtifacts, Artifact};
+use crate::nc_result_cache::{NCResultCache, NCCachedResult, data_hash};
+use crate::nc_upload::NCUploader;
+use crate::nc_dry_run::{DryRunReport, check_round_trip};
+use crate::nc_aggregator::NCAggregator;
+use crate::nc_resources::NCResourceReport;
+use crate::nc_communicator::{NCCommunicator, NCServerAddr};
+use crate::nc_proxy;
+use crate::nc_offline::{NCChunkArchive, NCResultArchive, NCOfflineResult, NCArchiveKind, write_archive, read_archive};
+use crate::nc_context::{self, NCCallContext, NCContextNodeInfo};
+use crate::array2d::{ChunkID, ChunkMeta};
 
 /// This trait has to be implemented for the code that runs on all the nodes.
 pub trait NCNode {
@@ -71,19 +61,239 @@ pub trait NCNode {
     /// Here you put your code that does the main number crunching on every node.
     /// Note that you have to use the nc_decode_data() or nc_decode_data2() helper methods from the nc_utils module in order to
     /// deserialize the data.
-    fn process_data_from_server(&mut self, data: &Self::NewDataT) -> Result<Self::ProcessedDataT, NCError>;
+    /// If the chunk turns out to be empty return `NodeResult::Empty` instead of an empty data structure,
+    /// if the chunk can't be processed on this node return `NodeResult::Skip(reason)`, see [`NodeResult`].
+    fn process_data_from_server(&mut self, data: &Self::NewDataT) -> Result<NodeResult<Self::ProcessedDataT>, NCError>;
+
+    /// This method is called instead of process_data_from_server() and gets some more information about the current chunk of data,
+    /// for example the remaining time if the server has set a deadline for it.
+    /// Long running computations should check [`NCProcessContext::is_cancelled()`] from time to time and return early.
+    /// By default it just calls process_data_from_server().
+    fn process_data_with_context(&mut self, data: &Self::NewDataT, _context: &NCProcessContext) -> Result<NodeResult<Self::ProcessedDataT>, NCError> {
+        self.process_data_from_server(data)
+    }
+
+    /// This method is called with the data from the server before process_data_with_context().
+    /// Use it to sanity check the data, for example that the width * height of an Array2D matches the length of its buffer.
+    /// If it returns an error the data is not processed and the server gets a NCJobError::VALIDATION_FAILED, the chunk will not be
+    /// given to a node again.
+    /// The default accepts everything.
+    fn validate(&self, _data: &Self::NewDataT) -> Result<(), String> {
+        Ok(())
+    }
 
     /// The server has send a special user defined custom message to the node.
     /// Usually this is not needed, only for debug purposes or if s.th. special has happened (user interaction for example)
     fn process_custom_message(&mut self, _custom_message: &Self::CustomMessageT) {
         debug!("Got a custom message from server");
     }
+
+    /// The user code on the server has returned a [`NCError::Job`] error that concerns this node,
+    /// for example the result could not be used.
+    fn process_server_error(&mut self, _error: &NCJobError) {
+        debug!("Got an error from server");
+    }
+
+    /// The server could not process the last result of this node, see [`OnProcessError`](crate::OnProcessError).
+    /// If the error is retryable the chunk will be given to a node again.
+    fn process_result_rejected(&mut self, _error: &NCJobError) {
+        debug!("Server has rejected the result");
+    }
+
+    /// Combines two results into one, this is called on the aggregator nodes, see aggregator_port in the NCConfiguration
+    /// and the [`nc_aggregator`](crate::nc_aggregator) module.
+    /// The results of the nodes arrive in any order, so reduce() must be associative and commutative (like adding up counts).
+    /// If it returns an error all the chunks of the combined result are given to a node again.
+    /// By default it returns an error, so this must be implemented if the node runs as an aggregator.
+    fn reduce(_aggregate: Self::ProcessedDataT, _data: Self::ProcessedDataT) -> Result<Self::ProcessedDataT, NCError> {
+        Err(NCError::custom("reduce() is not implemented"))
+    }
+
+    /// This method is called every time before the node asks the server for new data. The hint is sent to the server together
+    /// with the request, see [`NCWorkHint`]. The given hint comes from the NCConfiguration (work_hint_max_bytes, work_hint_max_duration
+    /// and work_hint_kind), [`None`] if nothing is set there.
+    /// Override it to measure the node at runtime, for example the free memory. By default it returns the configured hint.
+    fn work_hint(&mut self, configured: Option<NCWorkHint>) -> Option<NCWorkHint> {
+        configured
+    }
+}
+
+/// The result of processing one chunk of data on the node.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeResult<ProcessedDataT> {
+    /// The processed data, this is sent to the server and process_data_from_node() is called with it.
+    Data(ProcessedDataT),
+    /// The chunk doesn't contain anything, for example a tile that is outside of the scene.
+    /// Only a small marker message is sent to the server and chunk_empty() is called.
+    Empty,
+    /// The chunk has not been processed for the given reason and should be given to another node.
+    /// The server calls chunk_skipped() and counts the reason in the statistics.
+    Skip(String),
+}
+
+/// Events about the progress of the node, see [`NCNodeStarter::progress_events()`].
+/// The [`nc_progress`](crate::nc_progress) module (progress feature) shows them as a spinner.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NCNodeProgressEvent {
+    /// The node has registered with the server and got this node id.
+    Registered(NodeID),
+    /// The node has started processing the chunk.
+    ChunkStarted(ChunkID),
+    /// The result of the chunk has been sent to the server, together with the processing time.
+    ChunkDone(ChunkID, Duration),
+    /// Processing the chunk has failed, exceeded the deadline or the result could not be sent.
+    ChunkFailed(ChunkID),
+    /// The server has revoked the chunk (see [`NCServerHandle::cancel_chunk()`](crate::NCServerHandle::cancel_chunk)), it has been dropped.
+    ChunkRevoked(ChunkID),
+    /// All chunks have been handed out, the node waits for the other nodes.
+    Waiting,
+    /// The node exits, this is the last event.
+    Exit(NodeExit),
+}
+
+/// How the node has exited, this is returned by NCNodeStarter::start(). Errors are returned as `Err(NCError)` instead.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeExit {
+    /// The job is done (or the server could not be reached anymore and the retry counter is zero).
+    JobFinished,
+    /// The server runs with a different required_node_build (see NCConfiguration) and has asked this node to exit,
+    /// so that a wrapper script or supervisor can restart it with the new build.
+    RestartRequested,
+    /// The admin of the server has disabled this node (NCRejectReason::Disabled), it should not be started again.
+    Disabled { reason: String },
+    /// The node has been stopped with the stop handle, see NCNodeStarter::stop_handle().
+    StoppedByUser,
+    /// The server has aborted the job before it was done (NCServerMessage::JobAborted), for example via the admin protocol.
+    JobAborted { reason: String },
+}
+
+/// Information about the chunk of data that is currently processed by the node.
+#[derive(Debug, Clone)]
+pub struct NCProcessContext {
+    /// Time when the node has started processing the data.
+    time_start: Instant,
+    /// Chunk id, optional deadline and average chunk time from the server.
+    chunk_info: NCChunkInfo,
+    /// Is set when the deadline has been exceeded or the server has revoked the chunk.
+    cancelled: Arc<AtomicBool>,
+    /// Empty folder for temporary files of this chunk.
+    scratch_dir: PathBuf,
+    /// Keeps the CPU usage below cpu_limit_percent, shared with the node.
+    throttle: NCThrottle,
+    /// The artifacts that are sent to the server after the result or the failure report.
+    artifacts: NCArtifacts,
+}
+
+impl NCProcessContext {
+    /// Creates a new context with the given chunk info, scratch folder, throttle and artifacts, starting now.
+    fn new(chunk_info: NCChunkInfo, scratch_dir: PathBuf, throttle: NCThrottle, artifacts: NCArtifacts) -> Self {
+        NCProcessContext {
+            time_start: Instant::now(),
+            chunk_info,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            scratch_dir,
+            throttle,
+            artifacts,
+        }
+    }
+
+    /// An empty folder for temporary files that is only used for this chunk.
+    /// It is deleted automatically after the server has acknowledged the result, see [`ScratchDir`].
+    pub fn scratch_dir(&self) -> &Path {
+        &self.scratch_dir
+    }
+
+    /// The id of the chunk that is currently processed.
+    pub fn chunk_id(&self) -> ChunkID {
+        self.chunk_info.chunk_id
+    }
+
+    /// Time since the node has started processing the data.
+    pub fn elapsed(&self) -> Duration {
+        self.time_start.elapsed()
+    }
+
+    /// The deadline that the server has set for this chunk of data, if any.
+    pub fn deadline(&self) -> Option<Duration> {
+        self.chunk_info.deadline
+    }
+
+    /// The remaining time until the deadline is reached, [`None`] if there is no deadline.
+    pub fn deadline_remaining(&self) -> Option<Duration> {
+        self.chunk_info.deadline.map(|deadline| deadline.saturating_sub(self.elapsed()))
+    }
+
+    /// The metadata that the server has set for this chunk (see [`ChunkMeta`]), [`None`] if there is none.
+    /// It's sent back to the server together with the result.
+    pub fn metadata(&self) -> Option<&ChunkMeta> {
+        self.chunk_info.metadata.as_ref()
+    }
+
+    /// How often the server has sent this chunk to a node, including this time (1 for the first attempt).
+    /// 0 if the server doesn't count the attempts, for example in an offline batch. See also [`nc_context::current()`].
+    pub fn attempt(&self) -> u32 {
+        self.chunk_info.attempt
+    }
+
+    /// The average time the nodes have needed for one chunk so far, measured by the server from sending the data
+    /// until the result arrived (so it includes the network transfer). [`None`] if there is no result yet.
+    /// Can be used to budget the work, for example the number of refinement passes.
+    pub fn average_chunk_time(&self) -> Option<Duration> {
+        self.chunk_info.average_chunk_time
+    }
+
+    /// The remaining time until the job stops handing out chunks (max_job_duration in the NCConfiguration), [`None`] if the job
+    /// has no time limit. The server still accepts the result after that time, as long as the job drain timeout (job_drain_timeout)
+    /// hasn't run out, then the job ends without it. An anytime algorithm (progressive rendering, iterative optimization) can use this
+    /// to return a result with a lower quality when the time is short, see also average_chunk_time().
+    pub fn job_time_remaining(&self) -> Option<Duration> {
+        self.chunk_info.job_time_remaining.map(|remaining| remaining.saturating_sub(self.elapsed()))
+    }
+
+    /// Returns true if the deadline has been exceeded or the server has revoked the chunk
+    /// (see [`NCServerHandle::cancel_chunk()`](crate::NCServerHandle::cancel_chunk)). The result will not be sent to the server anymore.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// The flag that is set when the deadline has been exceeded or the chunk has been revoked, can be shared with other threads.
+    pub fn cancellation_token(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+
+    /// Sleeps if the node has been busy for more than cpu_limit_percent of the time, see the [`nc_throttle`](crate::nc_throttle) module.
+    /// The node calls this after every chunk, call it in between (for example once per row) if a chunk takes longer than a second.
+    /// Returns right away if there is no limit.
+    pub fn throttle(&self) {
+        self.throttle.throttle()
+    }
+
+    /// The number of threads that the user code should use for this chunk: the number of CPUs, reduced proportionally to cpu_limit_percent.
+    /// This keeps the node roughly below the limit even if throttle() is not called.
+    pub fn max_threads(&self) -> usize {
+        self.throttle.max_threads()
+    }
+
+    /// Attaches an auxiliary file (a debug image, a log, ...) to this chunk, see the [`nc_artifacts`](crate::nc_artifacts) module.
+    /// It's sent to the server after the result has been acknowledged and stored there in `artifacts_dir/chunk_id/name`.
+    /// Returns false if the artifact has been dropped: artifacts_dir is not set, the name is not a plain file name,
+    /// or max_artifact_bytes or max_artifacts_per_chunk has been exceeded. The chunk itself never fails because of an artifact.
+    pub fn attach_artifact(&self, name: &str, data: Vec<u8>) -> bool {
+        self.artifacts.attach(name, data)
+    }
 }
 
+/// How often a sleeping node checks if it has been stopped.
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
 /// Main data structure for managing and starting the computation on the nodes.
 pub struct NCNodeStarter {
     /// Configuration for the server and the node.
     config: NCConfiguration,
+    /// Receives the progress events, if progress_events() has been called.
+    progress_sender: Option<mpsc::Sender<NCNodeProgressEvent>>,
+    /// Setting this flag stops the node, if stop_handle() has been called.
+    stop: Option<Arc<AtomicBool>>,
 }
 
 impl NCNodeStarter {
@@ -91,7 +301,28 @@ impl NCNodeStarter {
     pub fn new(config: NCConfiguration) -> Self {
         debug!("NCNodeStarter::new()");
 
-        NCNodeStarter{ config }
+        NCNodeStarter{ config, progress_sender: None, stop: None }
+    }
+
+    /// Returns the receiving end for the progress events of the next start(), see [`NCNodeProgressEvent`].
+    /// The events can be shown with [`NCNodeProgress`](crate::nc_progress::NCNodeProgress) (progress feature).
+    pub fn progress_events(&mut self) -> mpsc::Receiver<NCNodeProgressEvent> {
+        debug!("NCNodeStarter::progress_events()");
+
+        let (sender, receiver) = mpsc::channel();
+        self.progress_sender = Some(sender);
+        receiver
+    }
+
+    /// Returns a flag that stops the next start(): once it's set the node doesn't ask for new data anymore and start() returns
+    /// NodeExit::StoppedByUser, instead of waiting until the retry counter is zero after the server has exited.
+    /// Used by [`nc_local::run()`](crate::nc_local::run) and by applications that embed the node, for example a GUI.
+    pub fn stop_handle(&mut self) -> Arc<AtomicBool> {
+        debug!("NCNodeStarter::stop_handle()");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        self.stop = Some(stop.clone());
+        stop
     }
 
     /// The main entry point for the code that runs on all nodes.
@@ -99,36 +330,193 @@ impl NCNodeStarter {
     /// Everything else is done automatically for you.
     /// The NCNode trait method set_initial_data() is called here once in order to set the node id and some optional data that is
     /// send to all nodes at the beginning.
-    pub fn start<T: NCNode>(&mut self, nc_node: T) -> Result<(), NCError> {
+    /// If aggregator_port is set in the NCConfiguration this node also combines the results of other nodes,
+    /// see the [`nc_aggregator`](crate::nc_aggregator) module.
+    /// Returns how the node has exited (see [`NodeExit`]), so that a supervisor script can decide what to do next:
+    /// for example NodeExit::RestartRequested if the server has asked this node to restart because it runs an outdated build.
+    pub fn start<T: NCNode>(&mut self, nc_node: T) -> Result<NodeExit, NCError> where T::ProcessedDataT: Send + 'static {
         debug!("NCNodeStarter::start()");
 
-        let ip_addr: IpAddr = self.config.address.parse()?;
-        let server_addr = SocketAddr::new(ip_addr, self.config.port);
-        let server_addr = Arc::new(Mutex::new(server_addr));
+        self.config.check_keys()?;
+        let mut config = self.config.clone();
+
+        // The listener is opened first, so that the actual port is sent to the server during registration.
+        let listener = match config.aggregator_port {
+            Some(port) => {
+                let listener = NCAggregator::bind(port)?;
+                config.aggregator_port = Some(listener.local_addr()?.port());
+                Some(listener)
+            }
+            None => None
+        };
+
+        config.check_signatures().map_err(|problem| NCError::Signature(problem.to_string()))?;
+        config.log_settings("node");
+        let nc_client = NCClient::connect(&config)?;
+        let server_addr = nc_client.shared_server_addr();
+
+        let mut node_process = NodeProcess::new(server_addr, nc_node, &config)?;
+        node_process.progress_sender = self.progress_sender.take();
+
+        #[cfg(feature = "ed25519")]
+        if let Some(path) = &config.signing_key_file {
+            node_process.nc_client.set_keypair(crate::nc_keys::load(path)?);
+        }
+
+        if let Some(stop) = self.stop.take() {
+            node_process.stopped = stop;
+        }
 
-        let mut node_process = NodeProcess::new(server_addr.clone(), nc_node, &self.config);
         node_process.get_initial_data()?;
+        let progress_sender = node_process.progress_sender.clone();
+
+        // The heartbeat client shares the server address and the encryption keys with the main loop.
+        let node_heartbeat = NodeHeartbeat::new(node_process.nc_client.share(), node_process.revoked.clone(), &config);
+
+        let aggregator = match listener {
+            Some(listener) => Some(NCAggregator::start(listener, T::reduce, node_process.nc_client.share(), &config)?),
+            None => None
+        };
+
+        let (stop_heartbeat, stop_receiver) = mpsc::channel();
+        let thread_handle = self.start_heartbeat_thread(node_heartbeat, stop_receiver);
+        let node_exit = self.start_main_loop(node_process);
+
+        if let Some(aggregator) = aggregator {
+            aggregator.stop();
+        }
 
-        let node_heartbeat = NodeHeartbeat::new(server_addr, node_process.node_id, &self.config);
+        // The server may still be running (restart requested, stopped or job finished), so the heartbeat thread would not run into an error.
+        let _ = stop_heartbeat.send(());
 
-        let thread_handle = self.start_heartbeat_thread(node_heartbeat);
-        self.start_main_loop(node_process);
         thread_handle.join().unwrap();
+        let node_exit = node_exit?;
+
+        match &node_exit {
+            NodeExit::JobFinished => info!("Job done, exit now"),
+            NodeExit::RestartRequested => info!("Restart requested by server, exit now"),
+            NodeExit::Disabled { reason } => info!("Disabled by server, exit now: {}", reason),
+            NodeExit::StoppedByUser => info!("Node has been stopped, exit now"),
+            NodeExit::JobAborted { reason } => info!("Job aborted by server, exit now: {}", reason),
+        }
 
-        info!("Job done, exit now");
-        Ok(())
+        if let Some(progress_sender) = progress_sender {
+            let _ = progress_sender.send(NCNodeProgressEvent::Exit(node_exit.clone()));
+        }
+
+        Ok(node_exit)
+    }
+
+    /// Checks the configuration and the user code without registering with the server, for example before a job that runs for days:
+    /// - the configuration and the encryption keys are valid
+    /// - the server address can be resolved and a tcp connection can be opened, it's closed right away without sending anything
+    /// - a sample payload can be encoded and decoded again with every codec
+    /// - process_data_with_context() works with the given sample data and the result can be encoded and decoded again
+    pub fn dry_run<T: NCNode>(&self, nc_node: &mut T, sample_data: &T::NewDataT) -> DryRunReport {
+        debug!("NCNodeStarter::dry_run()");
+
+        let mut report = DryRunReport::new();
+
+        report.check("configuration", self.config.check());
+
+        let result = NCServerAddr::from_config(&self.config).and_then(|server_addr| nc_proxy::connect_to(&server_addr, self.config.proxy.as_ref())).map(|_| ());
+        report.check("connect to server", result);
+
+        let mut nc_communicator = match check_round_trip(&self.config, &mut report) {
+            Some(nc_communicator) => nc_communicator,
+            None => return report
+        };
+
+        let mut scratch_dir = ScratchDir::new(&self.config);
+
+        let result = scratch_dir.create_chunk_dir().and_then(|chunk_dir| {
+            let context = NCProcessContext::new(NCChunkInfo::default(), chunk_dir.clone(), NCThrottle::new(&self.config), NCArtifacts::disabled());
+            let result = nc_node.process_data_with_context(sample_data, &context).and_then(|node_result| {
+                match node_result {
+                    NodeResult::Data(data) => {
+                        let message: NCNodeMessage<T::ProcessedDataT, T::CustomMessageT> = NCNodeMessage::HasData(NodeID::random(), data);
+                        let data = nc_communicator.nc_encode_data(&message)?;
+                        nc_communicator.nc_decode_data::<NCNodeMessage<T::ProcessedDataT, T::CustomMessageT>>(&data).map(|_| ())
+                    }
+                    NodeResult::Empty | NodeResult::Skip(_) => Ok(()),
+                }
+            });
+
+            scratch_dir.remove_chunk_dir(&chunk_dir, result.is_ok());
+            result
+        });
+        report.check("process data", result);
+
+        report
+    }
+
+    /// Processes the chunks of an offline batch without any network connection (air-gapped node), see the
+    /// [`nc_offline`](crate::nc_offline) module. The chunks are read from in_path (written by
+    /// [`NCOfflineHandle::export_chunks()`](crate::NCOfflineHandle::export_chunks)), set_initial_data() is called once and
+    /// every chunk is given to process_data_with_context(). The results are written to out_path, which is read by
+    /// [`NCOfflineHandle::import_results()`](crate::NCOfflineHandle::import_results) on the server.
+    /// An error of the user code only fails the chunk, a retryable error gives the chunk to another node.
+    /// The progress events ChunkStarted, ChunkDone and ChunkFailed are sent. Returns the number of processed chunks.
+    pub fn process_archive<T: NCNode, P: AsRef<Path>, Q: AsRef<Path>>(&mut self, mut nc_node: T, in_path: P, out_path: Q) -> Result<usize, NCError> {
+        debug!("NCNodeStarter::process_archive()");
+
+        let mut nc_communicator = NCCommunicator::new(&self.config)?;
+        let archive: NCChunkArchive<T::InitialDataT, T::NewDataT> = read_archive(in_path.as_ref(), NCArchiveKind::Chunks, &nc_communicator)?;
+        info!("Process {} chunks of offline batch {}", archive.chunks.len(), archive.batch_id);
+
+        let node_context = NCCallContext::new(self.config.job_id.clone(), Some(archive.node_id), Some(NCContextNodeInfo::from_config(&self.config)));
+        let _context = nc_context::enter(node_context.clone());
+        nc_node.set_initial_data(archive.node_id, archive.initial_data)?;
+        let mut scratch_dir = ScratchDir::new(&self.config);
+        let throttle = NCThrottle::new(&self.config);
+        let mut results = Vec::with_capacity(archive.chunks.len());
+
+        for (chunk_info, data) in archive.chunks {
+            let chunk_id = chunk_info.chunk_id;
+            self.report_progress(NCNodeProgressEvent::ChunkStarted(chunk_id));
+            let _chunk_context = nc_context::enter(node_context.clone().with_chunk(chunk_id, chunk_info.attempt));
+            let (result, elapsed) = process_offline_chunk(&mut nc_node, &mut scratch_dir, &throttle, chunk_info, &data)?;
+
+            match &result {
+                NCOfflineResult::Failed(job_error) => {
+                    error!("Could not process chunk {}: {}", chunk_id, job_error);
+                    self.report_progress(NCNodeProgressEvent::ChunkFailed(chunk_id));
+                }
+                _ => self.report_progress(NCNodeProgressEvent::ChunkDone(chunk_id, elapsed)),
+            }
+
+            results.push((chunk_id, result));
+        }
+
+        let count = results.len();
+        let archive: NCResultArchive<T::ProcessedDataT> = NCResultArchive { batch_id: archive.batch_id, node_id: archive.node_id, results };
+        write_archive(out_path.as_ref(), NCArchiveKind::Results, &archive, &mut nc_communicator)?;
+        info!("Offline batch {} done, results written to {}", archive.batch_id, out_path.as_ref().display());
+
+        Ok(count)
+    }
+
+    /// Sends the given event to the progress receiver, if progress_events() has been called.
+    fn report_progress(&self, event: NCNodeProgressEvent) {
+        if let Some(progress_sender) = &self.progress_sender {
+            let _ = progress_sender.send(event);
+        }
     }
 
     /// The heartbeat thread that runs in the background and sends heartbeat messages to the server is started here.
     /// It does this every n seconds which can be configured in the NCConfiguration data structure.
     /// If the server doesn't receive the heartbeat within the valid time span, the server marks the node internally as offline
     /// and gives another node the same data chunk to process.
-    fn start_heartbeat_thread(&self, mut node_heartbeat: NodeHeartbeat) -> JoinHandle<()> {
+    /// The thread also exits when something is sent to the stop channel.
+    fn start_heartbeat_thread(&self, mut node_heartbeat: NodeHeartbeat, stop: mpsc::Receiver<()>) -> JoinHandle<()> {
         debug!("NCNodeStarter::start_heartbeat_thread()");
 
         spawn(move || {
             loop {
-                node_heartbeat.sleep();
+                if node_heartbeat.sleep(&stop) {
+                    debug!("Heartbeat thread stopped");
+                    break
+                }
 
                 if let Err(e) = node_heartbeat.send_heartbeat_message() {
                     error!("Error in send_heartbeat(): {}, retry_counter: {}", e, node_heartbeat.get_counter());
@@ -149,19 +537,56 @@ impl NCNodeStarter {
     }
 
     /// Here is main loop for this node. It keeps requesting and processing data until the server
-    /// is finished. There will be no finish message from the server and the node will just run into
-    /// a timeout and exit.
+    /// is finished. If the server sends a NCJobStatus::Finished the loop exits right away with NodeExit::JobFinished,
+    /// if the server has exited already the node will just run into a timeout and exit.
     /// If there is an error this node will wait n seconds before it tries to reconnect to the server.
     /// The delay time can be configured in the NCConfiguration data structure.
     /// With every error the retry counter is decremented. If it reaches zero the node will give up and exit.
     /// The counter can be configured in the NCConfiguration.
-    fn start_main_loop<T: NCNode>(&self, mut node_process: NodeProcess<T>) {
+    /// If the server sends a NCServerMessage::PleaseRestart message the loop exits right away with NodeExit::RestartRequested.
+    /// If the server sends a NCServerMessage::JobAborted message the loop exits with NodeExit::JobAborted.
+    /// If the node has been stopped (see stop_handle()) the loop exits with NodeExit::StoppedByUser.
+    /// If the node has been disabled the loop exits right away with NodeExit::Disabled.
+    /// If the server has rejected the node for good for another reason (see NCError::is_fatal_rejection()) the loop exits right away with that error,
+    /// if the server is busy or the job is paused the node waits for the time given by the server instead of delay_request_data.
+    fn start_main_loop<T: NCNode>(&self, mut node_process: NodeProcess<T>) -> Result<NodeExit, NCError> where T::ProcessedDataT: Send + 'static {
         debug!("NCNodeStarter::start_main_loop()");
 
+        let mut transient_retries = 0;
+        let mut rejection = None;
+        let mut node_exit = NodeExit::JobFinished;
+
         loop {
+            if node_process.is_stopped() {
+                debug!("Node has been stopped");
+                node_exit = NodeExit::StoppedByUser;
+                break
+            }
+
             debug!("Ask server for new data");
 
             if let Err(e) = node_process.get_and_process_data() {
+                // A hiccup (timeout, interrupted system call) is retried right away a few times, without the delay and the retry counter.
+                if e.is_transient() && transient_retries < TRANSIENT_RETRIES {
+                    transient_retries += 1;
+                    warn!("Transient error in get_and_process_data(): {}, retry {} of {}", e, transient_retries, TRANSIENT_RETRIES);
+                    thread::sleep(transient_delay(transient_retries));
+                    continue
+                }
+
+                if let NCError::NodeDisabled(reason) = e {
+                    warn!("The server has disabled this node, will exit now: {}", reason);
+                    node_exit = NodeExit::Disabled { reason };
+                    break
+                }
+
+                if e.is_fatal_rejection() {
+                    error!("The server has rejected this node, will exit now: {}", e);
+                    rejection = Some(e);
+                    break
+                }
+
+                transient_retries = 0;
                 error!("Error in get_and_process_data(): {}, retry counter: {:?}", e, node_process.get_counter());
 
                 if node_process.dec_and_check_counter() {
@@ -169,60 +594,130 @@ impl NCNodeStarter {
                     break
                 }
 
-                debug!("Will wait before retry (delay_request_data: {} sec)", node_process.get_delay());
-                node_process.sleep();
+                match e.retry_after() {
+                    Some(retry_after) => {
+                        debug!("Will wait before retry ({} sec, given by the server)", retry_after.as_secs());
+                        node_process.sleep_for(retry_after);
+                    }
+                    None => {
+                        debug!("Will wait before retry (delay_request_data: {} sec)", node_process.get_delay());
+                        node_process.sleep();
+                    }
+                }
+            } else if node_process.restart_requested {
+                debug!("Main loop finished, restart requested");
+                return Ok(NodeExit::RestartRequested)
+            } else if let Some(reason) = node_process.job_aborted.take() {
+                debug!("Main loop finished, job has been aborted");
+                node_exit = NodeExit::JobAborted { reason };
+                break
+            } else if node_process.check_job_finished() {
+                debug!("Main loop finished, job is done");
+                break
             } else {
                 // Reset the counter if message was sent successfully
+                transient_retries = 0;
                 node_process.reset_counter()
             }
         }
 
-        debug!("Main loop finished")
+        if let Err(e) = node_process.finish_upload() {
+            error!("Could not send the last result: {}", e);
+        }
+
+        // A chunk fetched in advance will not be processed anymore.
+        node_process.release_prefetched();
+
+        let cached_results = node_process.result_cache.len();
+
+        if cached_results > 0 {
+            info!("{} results ({} bytes) have not been taken by the server and are still in the result cache", cached_results, node_process.result_cache.bytes());
+        }
+
+        debug!("Main loop finished");
+
+        match rejection {
+            Some(e) => Err(e),
+            None => Ok(node_exit),
+        }
     }
 }
 
+/// Validates and processes one chunk of an offline batch, see NCNodeStarter::process_archive().
+/// Returns the result for the server and the processing time.
+fn process_offline_chunk<T: NCNode>(nc_node: &mut T, scratch_dir: &mut ScratchDir, throttle: &NCThrottle, chunk_info: NCChunkInfo, data: &T::NewDataT)
+    -> Result<(NCOfflineResult<T::ProcessedDataT>, Duration), NCError> {
+    if let Err(message) = nc_node.validate(data) {
+        let error = NCError::ValidationFailed(message);
+        return Ok((NCOfflineResult::Failed(NCJobError::validation_failed(&error, false)), Duration::ZERO))
+    }
+
+    let context = NCProcessContext::new(chunk_info, scratch_dir.create_chunk_dir()?, throttle.clone(), NCArtifacts::disabled());
+    throttle.resume();
+    let result = nc_node.process_data_with_context(data, &context);
+    throttle.throttle();
+
+    let result = match result {
+        Ok(NodeResult::Data(data)) => NCOfflineResult::Data(context.metadata().cloned(), data),
+        Ok(NodeResult::Empty) => NCOfflineResult::Empty,
+        Ok(NodeResult::Skip(reason)) => NCOfflineResult::Skip(reason),
+        Err(NCError::Job(job_error)) => NCOfflineResult::Failed(job_error),
+        // There is no server to retry with, so every other error only fails this chunk.
+        Err(error) => NCOfflineResult::Failed(NCJobError::user_error(&error)),
+    };
+
+    scratch_dir.remove_chunk_dir(context.scratch_dir(), !matches!(result, NCOfflineResult::Failed(_)));
+    Ok((result, context.elapsed()))
+}
+
 /// Manages and sends heartbeat messages to the server.
 struct NodeHeartbeat {
-    /// IP address and port of the server.
-    server_addr: Arc<Mutex<SocketAddr>>,
-    /// The node id for this node,
-    node_id: NodeID,
+    /// Connection to the server, shares the server address with the main loop.
+    nc_client: NCClient,
     /// How often should the heartbeat thread try to contact the server before giving up.
     retry_counter: RetryCounter,
-    /// Send every heartbeat_duration seconds the xxx message to the server.
+    /// Send every heartbeat_duration seconds (± HEARTBEAT_JITTER) the xxx message to the server.
     heartbeat_duration: Duration,
-    /// Handles all the communication
-    nc_communicator: NCCommunicator,
+    /// The folder for the free disk space in the resource report, [`None`] if no report is sent.
+    resource_path: Option<PathBuf>,
+    /// The chunks that the server has revoked, shared with the main loop.
+    revoked: RevokedChunks,
 }
 
 impl NodeHeartbeat {
     /// Creates a new NodeHeartbeat with the given arguments.
-    fn new(server_addr: Arc<Mutex<SocketAddr>>, node_id: NodeID, config: &NCConfiguration) -> Self {
+    fn new(nc_client: NCClient, revoked: RevokedChunks, config: &NCConfiguration) -> Self {
         debug!("NodeHeartbeat::new()");
 
         NodeHeartbeat {
-            server_addr,
-            node_id,
+            nc_client,
+            revoked,
             retry_counter: RetryCounter::new(config.retry_counter),
             heartbeat_duration: Duration::from_secs(config.heartbeat),
-            nc_communicator: NCCommunicator::new(config),
+            resource_path: if config.report_resources { Some(config.scratch_dir.clone().unwrap_or_else(std::env::temp_dir)) } else { None },
         }
     }
 
-    /// The heartbeat thread will sleep for the given duration from the configuration.
-    fn sleep(&self) {
+    /// The heartbeat thread will sleep for the given duration from the configuration, randomly up to HEARTBEAT_JITTER longer or shorter.
+    /// So the heartbeats of many nodes that have been started at the same time don't all arrive at the server at the same time.
+    /// Returns true if the thread has been stopped in the meantime or the sender is gone (for example after a panic in the main loop).
+    fn sleep(&self, stop: &mpsc::Receiver<()>) -> bool {
         debug!("NodeHeartbeat::sleep()");
 
-        thread::sleep(self.heartbeat_duration);
+        let jitter = rand::thread_rng().gen_range(1.0 - HEARTBEAT_JITTER, 1.0 + HEARTBEAT_JITTER);
+        !matches!(stop.recv_timeout(self.heartbeat_duration.mul_f64(jitter)), Err(mpsc::RecvTimeoutError::Timeout))
     }
 
-    /// Send the NCNodeMessage::HeartBeat message to the server.
+    /// Send the NCNodeMessage::PollHeartBeat message to the server, with the resource report if report_resources is set in the NCConfiguration.
+    /// The chunks in the answer have been revoked by the server, see RevokedChunks.
     fn send_heartbeat_message(&mut self) -> Result<(), NCError> {
         debug!("NodeHeartbeat::send_heartbeat_message()");
-        let message: NCNodeMessage<(), ()> = NCNodeMessage::HeartBeat(self.node_id);
-        let server_addr = *self.server_addr.lock()?;
 
-        self.nc_communicator.nc_send_data(&message, &server_addr)
+        let resources = self.resource_path.as_ref().map(|path| NCResourceReport::gather(path));
+        let revoked = self.nc_client.poll_heartbeat(resources)?;
+        self.revoked.revoke(revoked);
+
+        Ok(())
     }
 
     /// Returns the current value of the retry counter.
@@ -248,86 +743,311 @@ impl NodeHeartbeat {
     }
 }
 
+/// The chunk that is being processed and its cancellation token, see RevokedChunks.
+type CurrentChunk = Option<(ChunkID, Arc<AtomicBool>)>;
+
+/// The chunks that the server has revoked (see NCServerHandle::cancel_chunk()), shared between the heartbeat thread and the main loop.
+/// The heartbeat thread gets them with every heartbeat and sets the cancellation token of the chunk that is being processed.
+/// The main loop drops a revoked chunk without a result and tells the server with the NCNodeMessage::ChunkRevoked message.
+#[derive(Debug, Clone, Default)]
+struct RevokedChunks {
+    /// The revoked chunks from the last heartbeat, the server sends all of them every time.
+    revoked: Arc<Mutex<HashSet<ChunkID>>>,
+    /// The chunk that is being processed and its cancellation token.
+    current: Arc<Mutex<CurrentChunk>>,
+}
+
+impl RevokedChunks {
+    /// Replaces the revoked chunks with the given ones and cancels the current chunk if it's one of them.
+    fn revoke(&self, chunks: Vec<ChunkID>) {
+        if let Ok(mut revoked) = self.revoked.lock() {
+            *revoked = chunks.into_iter().collect();
+
+            if let Ok(current) = self.current.lock() {
+                if let Some((chunk_id, cancelled)) = current.as_ref().filter(|(chunk_id, _)| revoked.contains(chunk_id)) {
+                    info!("Chunk {} has been revoked by the server, cancel it", chunk_id);
+                    cancelled.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Sets the chunk that is being processed, [`None`] when the processing is done.
+    fn set_current(&self, current: CurrentChunk) {
+        if let Ok(revoked) = self.revoked.lock() {
+            if let Some((chunk_id, cancelled)) = &current {
+                if revoked.contains(chunk_id) {
+                    cancelled.store(true, Ordering::Relaxed);
+                }
+            }
+
+            // Locked in the same order as in revoke()
+            if let Ok(mut old) = self.current.lock() {
+                *old = current;
+            }
+        }
+    }
+
+    /// Returns true if the given chunk has been revoked, it's removed from the revoked chunks.
+    fn take(&self, chunk_id: ChunkID) -> bool {
+        self.revoked.lock().is_ok_and(|mut revoked| revoked.remove(&chunk_id))
+    }
+}
+
+/// The answer to the request for the next chunk in advance and the work hint that has been sent, see NodeProcess::start_prefetch().
+type PrefetchRequest = (mpsc::Receiver<Result<Vec<u8>, NCError>>, Option<NCWorkHint>);
+
+/// A result that is sent by the upload thread, see NodeProcess::submit_result().
+/// The scratch folder and the artifacts of the chunk are kept until the server has acknowledged the result.
+struct PendingUpload {
+    /// The chunk of the result.
+    chunk_id: ChunkID,
+    /// The scratch folder of the chunk.
+    chunk_dir: PathBuf,
+    /// The artifacts that are sent after the result.
+    artifacts: Vec<Artifact>,
+    /// The time it took to process the chunk.
+    elapsed: Duration,
+    /// Gets the outcome of the upload.
+    uploaded: mpsc::Receiver<Result<(), NCError>>,
+}
+
 /// Communication with the server and processing of data.
-struct NodeProcess<T> {
-    /// IP address and port of the server.
-    server_addr: Arc<Mutex<SocketAddr>>,
+struct NodeProcess<T: NCNode> {
+    /// Connection to the server, shares the server address with the heartbeat thread.
+    nc_client: NCClient,
     /// The suer defined data structure that implements the NCNode trait.
     nc_node: T,
-    /// The node id for this node,
-    node_id: NodeID,
     /// How often should the main processing loop try to contact the server before giving up.
     retry_counter: RetryCounter,
     /// In case of IO error wait delay_duration seconds before trying to contact the server again.
     delay_duration: Duration,
-    /// Handles all the communication
-    nc_communicator: NCCommunicator,
+    /// Temporary folders for the chunks.
+    scratch_dir: ScratchDir,
+    /// Keeps the CPU usage below cpu_limit_percent, see the nc_throttle module.
+    throttle: NCThrottle,
+    /// The limits for the artifacts of each chunk, see the nc_artifacts module.
+    artifacts: NCArtifacts,
+    /// The server has sent a NCServerMessage::PleaseRestart message.
+    restart_requested: bool,
+    /// The server has sent a NCJobStatus::Finished, the job is done.
+    job_finished: bool,
+    /// The server has sent a NCServerMessage::JobAborted message with this reason.
+    job_aborted: Option<String>,
+    /// Gets the progress events, only used if NCNodeStarter::progress_events() has been called.
+    progress_sender: Option<mpsc::Sender<NCNodeProgressEvent>>,
+    /// The node has been stopped, see NCNodeStarter::stop_handle().
+    stopped: Arc<AtomicBool>,
+    /// The work hint from the NCConfiguration, this is given to the NCNode trait method work_hint().
+    work_hint: Option<NCWorkHint>,
+    /// Ask for the next chunk while the current one is processed, see prefetch in the NCConfiguration.
+    prefetch: bool,
+    /// The answer of the server to the last request in advance, it's handled instead of asking the server again.
+    prefetched: Option<NCServerMessage<(), T::NewDataT, T::CustomMessageT>>,
+    /// The results that the server has not acknowledged yet, see the nc_result_cache module.
+    result_cache: NCResultCache,
+    /// Sends the results while the next chunk is processed, see upload_in_background in the NCConfiguration.
+    uploader: Option<NCUploader>,
+    /// The result that is being sent by the uploader.
+    pending_upload: Option<PendingUpload>,
+    /// The chunks that the server has revoked, shared with the heartbeat thread.
+    revoked: RevokedChunks,
+    /// The job id and the build and groups of this node for the context of the calls into the user code, see the nc_context module.
+    job_id: Option<String>,
+    node_info: NCContextNodeInfo,
+    /// Keeps the node id for the next process on this machine, see node_id_file in the NCConfiguration.
+    node_id_file: Option<NCNodeIdFile>,
 }
 
-impl<T: NCNode> NodeProcess<T> {
+impl<T: NCNode> NodeProcess<T> where T::ProcessedDataT: Send + 'static {
     /// Creates a new NodeProcess with the given arguments.
-    fn new(server_addr: Arc<Mutex<SocketAddr>>, nc_node: T, config: &NCConfiguration) -> Self {
+    fn new(server_addr: Arc<Mutex<NCServerAddr>>, nc_node: T, config: &NCConfiguration) -> Result<Self, NCError> {
         debug!("NodeProcess::new()");
 
-        NodeProcess{
-            server_addr,
+        Ok(NodeProcess{
+            // The node id will be set in the method get_initial_data()
+            nc_client: NCClient::with_server_addr(server_addr, config)?,
             nc_node,
-            // This will be set in the method get_initial_data()
-            node_id: NodeID::unset(),
             retry_counter: RetryCounter::new(config.retry_counter),
             delay_duration: Duration::from_secs(config.delay_request_data),
-            nc_communicator: NCCommunicator::new(config),
-        }
+            scratch_dir: ScratchDir::new(config),
+            throttle: NCThrottle::new(config),
+            artifacts: NCArtifacts::new(config),
+            restart_requested: false,
+            job_finished: false,
+            job_aborted: None,
+            progress_sender: None,
+            stopped: Arc::new(AtomicBool::new(false)),
+            work_hint: NCWorkHint::from_config(config),
+            prefetch: config.prefetch,
+            prefetched: None,
+            result_cache: NCResultCache::new(config),
+            uploader: config.upload_in_background.then(NCUploader::start),
+            pending_upload: None,
+            revoked: RevokedChunks::default(),
+            job_id: config.job_id.clone(),
+            node_info: NCContextNodeInfo::from_config(config),
+            node_id_file: config.node_id_file.as_deref().map(NCNodeIdFile::new),
+        })
+    }
+
+    /// The context for the calls into the user code, without a chunk.
+    fn call_context(&self) -> NCCallContext {
+        NCCallContext::new(self.job_id.clone(), Some(self.node_id()), Some(self.node_info.clone()))
+    }
+
+    /// Returns true if the node has been stopped, see NCNodeStarter::stop_handle().
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the node id that has been assigned by the server.
+    fn node_id(&self) -> NodeID {
+        self.nc_client.node_id()
     }
 
     /// This is called once at the beginning of NCNodeStarter::start().
     /// It sends a NCNodeMessage::Register message to the server and expects a NCServerMessage::InitialData message from the server.
     /// On success it sets the new assigned node id for this node and calls the NCNode trait method set_initial_data().
     /// If the server doesn't respond with a NCServerMessage::InitialData message a NCError::ServerMsgMismatch error is returned.
+    /// If the server rejects the node because it's busy or the job is paused (see NCError::is_retryable_rejection()) the node
+    /// waits for the time given by the server and tries again until its retry counter is zero, all other rejections are returned right away.
+    /// If the node id file from the NCConfiguration has the node id of an earlier process, the node sends a NCNodeMessage::Rejoin message
+    /// with the next incarnation instead and takes over that node id, see the nc_node_id_file module. If another process uses the node id file
+    /// a NCError::NodeIdFileLocked error is returned.
     fn get_initial_data(&mut self) -> Result<(), NCError> {
         debug!("NodeProcess::get_initial_data()");
 
-        let initial_data: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = self.send_register_message()?;
+        let previous = match &mut self.node_id_file {
+            Some(node_id_file) => node_id_file.open()?,
+            None => None,
+        };
+        let incarnation = previous.map_or(0, |(_, incarnation)| incarnation.saturating_add(1));
+
+        let initial_data = loop {
+            let registered = match previous {
+                Some((node_id, _)) => self.nc_client.register_again::<T::InitialDataT>(node_id, incarnation),
+                None => self.nc_client.register::<T::InitialDataT>(),
+            };
+
+            match registered {
+                Ok(initial_data) => break initial_data,
+                Err(e) if e.is_retryable_rejection() => {
+                    warn!("Could not register: {}, retry counter: {}", e, self.get_counter());
+
+                    if self.dec_and_check_counter() || self.is_stopped() {
+                        return Err(e)
+                    }
 
-        match initial_data {
-            NCServerMessage::InitialData(node_id, initial_data) => {
-                info!("Got node_id: {} and initial data from server", node_id);
-                self.node_id = node_id;
-                self.nc_node.set_initial_data(node_id, initial_data)
-            }
-            _msg => {
-                error!("Error in get_initial_data(), NCServerMessage mismatch, expected: InitialData");
-                Err(NCError::ServerMsgMismatch)
+                    self.sleep_for(e.retry_after().unwrap_or(self.delay_duration));
+                }
+                Err(e) => return Err(e),
             }
+        };
+
+        self.reset_counter();
+        let node_id = self.node_id();
+
+        if let Some(node_id_file) = &self.node_id_file {
+            node_id_file.save(node_id, incarnation);
+        }
+
+        #[cfg(feature = "ed25519")]
+        if self.nc_client.has_keypair() {
+            self.nc_client.register_key()?;
         }
+
+        // Older servers don't know the clock measurement, the node still works without it
+        match self.nc_client.measure_clock() {
+            Ok(clock_skew) => info!("Clock skew to the server: {}", clock_skew),
+            Err(e) => warn!("Could not measure the clock skew to the server: {}", e),
+        }
+
+        info!("Got node_id: {} and initial data from server", node_id);
+        self.report_progress(NCNodeProgressEvent::Registered(node_id));
+        let _context = nc_context::enter(self.call_context());
+        self.nc_node.set_initial_data(node_id, initial_data)
     }
 
-    /// Send the NCNodeMessage::Register message to the server.
-    fn send_register_message(&mut self) -> Result<NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT>, NCError> {
-        debug!("NodeProcess::send_register_message()");
-        let message: NCNodeMessage<T::ProcessedDataT, T::CustomMessageT> = NCNodeMessage::Register;
-        let server_addr = *self.server_addr.lock()?;
+    /// Sends the given event to the receiver from NCNodeStarter::progress_events(), if any.
+    fn report_progress(&self, event: NCNodeProgressEvent) {
+        if let Some(progress_sender) = &self.progress_sender {
+            // The receiver may be gone already, the progress is not important for the job.
+            let _ = progress_sender.send(event);
+        }
+    }
+
+    /// Returns true if the job is finished: the server has either answered NCNodeMessage::NeedsData with NCJobStatus::Finished
+    /// or a result with NCServerMessage::ResultAck and job_finished set (see NCClient::job_finished()), then the node doesn't ask for new data again.
+    fn check_job_finished(&mut self) -> bool {
+        if !self.job_finished && self.nc_client.job_finished() {
+            info!("Server has finished the job with the last result");
+            self.job_finished = true;
+        }
 
-        self.nc_communicator.nc_send_receive_data(&message, &server_addr)
+        self.job_finished
     }
 
     /// This method sends a NCNodeMessage::NeedsData message to the server and reacts accordingly to the server response:
     /// Only one message is expected as a response from the server: NCServerMessage::JobStatus. This status can have two values
     /// 1. NCJobStatus::Unfinished: This means that the job is note done and there is still some more data to be processed.
-    ///      This node will then process the data calling the process_data_from_server() method and sends the data back to the
-    ///      server using the NCNodeMessage::HasData message.
+    ///    This node will then process the data calling the process_data_from_server() method and sends the data back to the
+    ///    server using the NCNodeMessage::HasData message.
     /// 2. NCJobStatus::Waiting: This means that not all nodes are done and the server is still waiting for all nodes to finish.
+    /// 3. NCJobStatus::Finished: The job is done, job_finished is set and the main loop exits.
+    ///
+    /// If the server has answered the last result with a ResultAck message that says the job is finished, the node doesn't ask at all.
+    ///
+    /// If the server sends a NCServerMessage::PleaseRestart message restart_requested is set and the main loop exits.
+    /// If the server sends a NCServerMessage::JobAborted message job_aborted is set and the main loop exits.
+    /// If the server rejects the node (NCServerMessage::Rejected) the error for the reason is returned, see NCError::rejected().
     /// If the server sends a different message this method will return a NCError::ServerMsgMismatch error.
+    /// If the node has already asked for new data while processing the last chunk (prefetch), that answer is handled instead of
+    /// asking again. A NCJobStatus::Waiting from back then is not waited for, the server is asked again right away.
+    /// Results that the server has not acknowledged yet are offered before the node asks for new data, see offer_cached_results().
+    /// A result that is still being uploaded (see submit_result()) is waited for before the node asks the server for new data,
+    /// a chunk that has been fetched in advance is processed right away.
     fn get_and_process_data(&mut self) -> Result<(), NCError> {
         debug!("NodeProcess::get_and_process_data()");
 
-        let new_data: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = self.send_needs_data_message()?;
+        let _context = nc_context::enter(self.call_context());
+
+        // The result that is being uploaded is in the cache too, it's offered later if the upload fails.
+        if self.pending_upload.is_none() {
+            self.offer_cached_results()?;
+        }
+
+        if !self.scratch_dir.has_free_space()? {
+            // The node is temporarily unavailable, it keeps sending heartbeat messages but doesn't ask for new data.
+            warn!("Not enough free space in scratch folder: {}, wait (delay_request_data: {} sec)", self.scratch_dir.path().display(), self.get_delay());
+            self.sleep();
+            return Ok(())
+        }
+
+        let (new_data, prefetched): (NCServerMessage<(), T::NewDataT, T::CustomMessageT>, bool) = match self.prefetched.take() {
+            Some(new_data) => (new_data, true),
+            None => {
+                self.finish_upload()?;
+
+                if self.check_job_finished() {
+                    return Ok(())
+                }
+
+                let work_hint = self.nc_node.work_hint(self.work_hint.clone());
+                (self.nc_client.request_data_with_hint(work_hint)?, false)
+            }
+        };
 
         match new_data {
             NCServerMessage::JobStatus(job_status) => {
                 match job_status {
-                    NCJobStatus::Unfinished(data) => {
-                        self.process_data_and_send_has_data_message(&data)
+                    NCJobStatus::Unfinished(data, chunk_info) => {
+                        self.process_data_and_send_has_data_message(&data, chunk_info)
+                    }
+                    NCJobStatus::Waiting if prefetched => {
+                        // The answer is from the time when this node was still busy, ask again right away.
+                        debug!("Server has answered the request in advance with waiting, ask again");
+                        Ok(())
                     }
                     NCJobStatus::Waiting => {
                         // The node will not exit here since the job is not 100% done.
@@ -337,13 +1057,14 @@ impl<T: NCNode> NodeProcess<T> {
                         // from time to time (delay_request_data).
 
                         debug!("Waiting for other nodes to finish (delay_request_data: {} sec)...", self.get_delay());
+                        self.report_progress(NCNodeProgressEvent::Waiting);
                         self.sleep();
                         Ok(())
                     }
-                    _ => {
-                        // The server does not bother sending the node a NCJobStatus::Finished message.
-                        error!("Error: unexpected message from server");
-                        Err(NCError::ServerMsgMismatch)
+                    NCJobStatus::Finished => {
+                        info!("Server has finished the job");
+                        self.job_finished = true;
+                        Ok(())
                     }
                 }
             }
@@ -352,9 +1073,35 @@ impl<T: NCNode> NodeProcess<T> {
                 self.nc_node.process_custom_message(&message);
                 Ok(())
             }
+            NCServerMessage::ServerFailed(job_error) => {
+                // Forward error to user code
+                info!("Got error from server: {}", job_error);
+                self.nc_node.process_server_error(&job_error);
+                Ok(())
+            }
+            NCServerMessage::ResultRejected(job_error) => {
+                warn!("Server has rejected the last result: {}", job_error);
+                self.nc_node.process_result_rejected(&job_error);
+                Ok(())
+            }
             NCServerMessage::NewServer(server, port) => {
-                self.new_server(server, port)?;
-                self.send_node_migrated()
+                self.nc_client.set_server(&server, port)?;
+                self.nc_client.node_migrated()
+            }
+            NCServerMessage::PleaseRestart { reason } => {
+                info!("Server has asked this node to restart: {}", reason);
+                self.restart_requested = true;
+                Ok(())
+            }
+            NCServerMessage::JobAborted(reason) => {
+                warn!("Server has aborted the job: {}", reason);
+                self.job_aborted = Some(reason);
+                Ok(())
+            }
+            NCServerMessage::UnknownJob(job_id) => {
+                // The job is done, like a server that doesn't accept connections anymore.
+                warn!("Server doesn't run the job '{}' (anymore)", job_id);
+                Err(NCError::UnknownJob(job_id))
             }
             _ => {
                 error!("Error in process_data_and_send_has_data_message(), NCServerMessage mismatch");
@@ -363,43 +1110,358 @@ impl<T: NCNode> NodeProcess<T> {
         }
     }
 
-    /// Send the NCNodeMessage::NeedsData message to the server.
-    fn send_needs_data_message(&mut self) -> Result<NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT>, NCError> {
-        debug!("NodeProcess::send_needs_data_message()");
-        let message: NCNodeMessage<T::ProcessedDataT, T::CustomMessageT> = NCNodeMessage::NeedsData(self.node_id);
-        let server_addr = *self.server_addr.lock()?;
+    /// Process the new data from the server and sends the result back to the server using
+    /// the NCNodeMessage::HasData message (or the Empty / Skip message, see [`NodeResult`]).
+    /// If the user code returns a NCError::Job error it is sent to the server using the NCNodeMessage::NodeFailed message.
+    /// A NCError::User error is sent the same way as a retryable NCJobError::USER_ERROR with the whole error chain as message.
+    /// If there is a deadline a timer is started, see DeadlineTimer. When the deadline is exceeded the result is discarded.
+    /// With prefetch the next chunk is requested while the data is processed, see start_prefetch(). If the chunk fails the
+    /// prefetched chunk is given back to the server before the failure is reported, see release_prefetched().
+    /// If the result cache has a result for the same chunk and data, that result is sent without processing the data again.
+    /// With upload_in_background the result is sent by the upload thread, the last result is waited for before anything else
+    /// is sent to the server, see submit_result() and finish_upload_before().
+    /// If the server revokes the chunk before or while it's processed the cancellation token is set and the chunk is dropped
+    /// without a result, see RevokedChunks and drop_revoked_chunk().
+    fn process_data_and_send_has_data_message(&mut self, data: &T::NewDataT, chunk_info: NCChunkInfo) -> Result<(), NCError> {
+        debug!("NodeProcess::process_data_and_send_has_data_message()");
 
-        self.nc_communicator.nc_send_receive_data(&message, &server_addr)
+        let _context = nc_context::enter(self.call_context().with_chunk(chunk_info.chunk_id, chunk_info.attempt));
+
+        if self.revoked.take(chunk_info.chunk_id) {
+            return self.drop_revoked_chunk(chunk_info.chunk_id)
+        }
+
+        self.use_result_dictionary(chunk_info.result_dictionary);
+
+        if let Err(message) = self.nc_node.validate(data) {
+            let error = NCError::ValidationFailed(message);
+            error!("Data from server is not valid: {}", error);
+            self.finish_upload_before(chunk_info.chunk_id)?;
+            self.report_progress(NCNodeProgressEvent::ChunkFailed(chunk_info.chunk_id));
+            return self.nc_client.report_failure(NCJobError::validation_failed(&error, false))
+        }
+
+        let data_hash = match self.result_cache.is_enabled() {
+            true => Some(data_hash(data)?),
+            false => None,
+        };
+
+        if let Some(cached) = data_hash.and_then(|data_hash| self.result_cache.get(chunk_info.chunk_id, data_hash)) {
+            info!("Chunk {} has been processed before, send the cached result", chunk_info.chunk_id);
+            let result: T::ProcessedDataT = bincode::deserialize(&cached.payload).map_err(NCError::Deserialize)?;
+            self.finish_upload_before(chunk_info.chunk_id)?;
+            self.nc_client.submit_result_with_meta(result, chunk_info.metadata)?;
+            self.result_cache.remove(chunk_info.chunk_id);
+            self.report_progress(NCNodeProgressEvent::ChunkDone(chunk_info.chunk_id, Duration::ZERO));
+            return Ok(())
+        }
+
+        let chunk_dir = self.scratch_dir.create_chunk_dir()?;
+        let chunk_id = chunk_info.chunk_id;
+        let deadline = chunk_info.deadline;
+        let aggregator = chunk_info.aggregator;
+        let metadata = chunk_info.metadata.clone();
+        self.report_progress(NCNodeProgressEvent::ChunkStarted(chunk_id));
+        let context = NCProcessContext::new(chunk_info, chunk_dir, self.throttle.clone(), self.artifacts.for_next_chunk());
+        let timer = deadline.map(|deadline| {
+            DeadlineTimer::start(deadline, context.cancellation_token(), self.nc_client.share())
+        });
+
+        let prefetch = self.start_prefetch();
+        self.revoked.set_current(Some((chunk_id, context.cancellation_token())));
+        self.throttle.resume();
+        let result = self.nc_node.process_data_with_context(data, &context);
+        // Only the processing counts as busy, waiting for the server doesn't
+        self.throttle.throttle();
+        self.revoked.set_current(None);
+
+        if let Some(timer) = timer {
+            timer.stop();
+        }
+
+        if let Some(prefetch) = prefetch {
+            self.finish_prefetch(prefetch);
+        }
+
+        if self.revoked.take(chunk_id) {
+            self.scratch_dir.remove_chunk_dir(context.scratch_dir(), false);
+            return self.drop_revoked_chunk(chunk_id)
+        }
+
+        if context.is_cancelled() || result.is_err() {
+            self.release_prefetched();
+        }
+
+        if context.is_cancelled() {
+            info!("Deadline exceeded ({:?}), discard result", deadline);
+            self.report_progress(NCNodeProgressEvent::ChunkFailed(chunk_id));
+            self.scratch_dir.remove_chunk_dir(context.scratch_dir(), false);
+            return Ok(())
+        }
+
+        if let Err(e) = self.finish_upload_before(chunk_id) {
+            self.report_progress(NCNodeProgressEvent::ChunkFailed(chunk_id));
+            self.scratch_dir.remove_chunk_dir(context.scratch_dir(), false);
+            return Err(e)
+        }
+
+        let (sent, success) = match result {
+            Ok(NodeResult::Data(result)) => match self.submit_result(aggregator, chunk_id, metadata, data_hash, result) {
+                Ok(Some(uploaded)) => {
+                    let chunk_dir = context.scratch_dir().to_path_buf();
+                    self.pending_upload = Some(PendingUpload { chunk_id, chunk_dir, artifacts: context.artifacts.take(), elapsed: context.elapsed(), uploaded });
+                    return Ok(())
+                }
+                sent => (sent.map(|_| ()), true)
+            }
+            Ok(NodeResult::Empty) => (self.nc_client.submit_empty(), true),
+            Ok(NodeResult::Skip(reason)) => {
+                info!("Skip chunk: {}", reason);
+                (self.nc_client.submit_skip(reason), true)
+            }
+            Err(NCError::Job(job_error)) => {
+                error!("Could not process data: {}", job_error);
+                (self.nc_client.report_failure(job_error), false)
+            }
+            Err(error @ NCError::User(_)) => {
+                error!("Could not process data: {}", error);
+                (self.nc_client.report_failure(NCJobError::user_error(&error)), false)
+            }
+            Err(e) => (Err(e), false)
+        };
+
+        self.scratch_dir.remove_chunk_dir(context.scratch_dir(), success && sent.is_ok());
+
+        if sent.is_err() {
+            self.release_prefetched();
+        }
+
+        // The artifacts of a failed chunk are sent too, they may tell why it has failed
+        if sent.is_ok() {
+            self.send_artifacts(chunk_id, context.artifacts.take());
+        }
+
+        if success && sent.is_ok() {
+            self.report_progress(NCNodeProgressEvent::ChunkDone(chunk_id, context.elapsed()));
+        } else {
+            self.report_progress(NCNodeProgressEvent::ChunkFailed(chunk_id));
+        }
+
+        sent
     }
 
-    /// Process the new data from the server and sends the result back to the server using
-    /// the NCNodeMessage::HasData message.
-    fn process_data_and_send_has_data_message(&mut self, data: &T::NewDataT) -> Result<(), NCError> {
-        debug!("NodeProcess::process_data_and_send_has_data_message()");
-        let result = self.nc_node.process_data_from_server(data)?;
-        let message: NCNodeMessage<T::ProcessedDataT, T::CustomMessageT> = NCNodeMessage::HasData(self.node_id, result);
-        let server_addr = *self.server_addr.lock()?;
+    /// Compresses the result with the zstd dictionary from the chunk info, see the nc_dictionary module.
+    /// If the node can't get the dictionary from the server the result is sent without one, that's just a warning.
+    fn use_result_dictionary(&mut self, id: Option<u32>) {
+        if let Err(e) = self.nc_client.use_dictionary(id) {
+            warn!("Could not use the zstd dictionary {:#010x}: {}, the result is sent without it", id.unwrap_or_default(), e);
+        }
+    }
 
-        self.nc_communicator.nc_send_data(&message, &server_addr)
+    /// Drops the given chunk that the server has revoked and tells the server with the NCNodeMessage::ChunkRevoked message.
+    /// This is not a failure, the chunk fetched in advance (if any) is kept.
+    fn drop_revoked_chunk(&mut self, chunk_id: ChunkID) -> Result<(), NCError> {
+        debug!("NodeProcess::drop_revoked_chunk()");
+
+        info!("Chunk {} has been revoked by the server, drop it", chunk_id);
+        self.finish_upload_before(chunk_id)?;
+        self.report_progress(NCNodeProgressEvent::ChunkRevoked(chunk_id));
+        self.nc_client.chunk_revoked(chunk_id)
     }
 
-    /// Change settings for a new server
-    fn new_server(&mut self, server: String, port: u16) -> Result<(), NCError>{
-        debug!("NodeProcess::new_server()");
-        let ip_addr: IpAddr = server.parse()?;
-        let mut server_addr = self.server_addr.lock()?;
-        *server_addr = SocketAddr::new(ip_addr, port);
-        Ok(())
+    /// Sends the artifacts of the chunk after the result, one message per artifact.
+    /// An artifact that could not be sent is only logged, the result has already been acknowledged.
+    fn send_artifacts(&mut self, chunk_id: ChunkID, artifacts: Vec<Artifact>) {
+        for (name, data) in artifacts {
+            debug!("NodeProcess::send_artifacts(), artifact: '{}', {} bytes", name, data.len());
+
+            if let Err(e) = self.nc_client.submit_artifact(chunk_id, name.clone(), data) {
+                warn!("Could not send artifact '{}' of chunk {}: {}", name, chunk_id, e);
+            }
+        }
+    }
+
+    /// If prefetch is set in the NCConfiguration the next chunk is requested from a separate thread, so that the server
+    /// prepares it while this node is still busy. The thread only returns the encoded answer, see finish_prefetch().
+    /// Nothing is requested if the node has been stopped or still holds an answer.
+    /// With upload_in_background the request is sent by the upload thread after the result that is being uploaded,
+    /// so that the server gets them in the right order.
+    fn start_prefetch(&mut self) -> Option<PrefetchRequest> {
+        if !self.prefetch || self.is_stopped() || self.prefetched.is_some() {
+            return None
+        }
+
+        debug!("NodeProcess::start_prefetch()");
+
+        let work_hint = self.nc_node.work_hint(self.work_hint.clone());
+        let mut nc_client = self.nc_client.share();
+        let request_hint = work_hint.clone();
+        let request = move || nc_client.request_data_prefetch(request_hint);
+
+        let answer = match &self.uploader {
+            Some(uploader) => uploader.request(self.pending_upload.is_some(), request),
+            None => {
+                let (sender, answer) = mpsc::channel();
+                spawn(move || sender.send(request()));
+                answer
+            }
+        };
+
+        Some((answer, work_hint))
     }
 
-    /// Send message to new server that the node has migrated
-    fn send_node_migrated(&mut self) -> Result<(), NCError> {
-        debug!("NodeProcess::send_node_migrated()");
+    /// Waits for the answer from start_prefetch() and keeps it for the next call of get_and_process_data().
+    /// If the request has failed (or has not been sent since the upload before it has failed) the node just asks again the normal way.
+    fn finish_prefetch(&mut self, (answer, work_hint): PrefetchRequest) {
+        debug!("NodeProcess::finish_prefetch()");
 
-        let message: NCNodeMessage<T::ProcessedDataT, T::CustomMessageT> = NCNodeMessage::NodeMigrated(self.node_id);
-        let server_addr = *self.server_addr.lock()?;
+        let node_id = self.node_id();
+        let answer = answer.recv()
+            .unwrap_or_else(|_| Err(NCError::internal("NodeProcess::finish_prefetch", format!("the request in advance of node {} has not been sent", node_id))))
+            .and_then(|data| self.nc_client.decode_prefetched(&data, work_hint));
 
-        self.nc_communicator.nc_send_data(&message, &server_addr)
+        match answer {
+            Ok(answer) => self.prefetched = Some(answer),
+            Err(e) => warn!("Could not get the next chunk in advance: {}", e),
+        }
+    }
+
+    /// Gives the chunk that has been fetched in advance back to the server, if there is one, so that another node can process it.
+    /// Other answers (for example a custom message) are kept.
+    fn release_prefetched(&mut self) {
+        if let Some(NCServerMessage::JobStatus(NCJobStatus::Unfinished(_, chunk_info))) = &self.prefetched {
+            debug!("NodeProcess::release_prefetched()");
+
+            let chunk_id = chunk_info.chunk_id;
+            self.prefetched = None;
+            info!("Give back chunk {} that has been fetched in advance", chunk_id);
+
+            if let Err(e) = self.nc_client.release_chunk(chunk_id) {
+                // The server gives the chunk to another node after the heartbeat timeout.
+                error!("Could not give back chunk {}: {}", chunk_id, e);
+            }
+        }
+    }
+
+    /// Sends the result to the aggregator that the server has chosen for this chunk, if any.
+    /// If there is no aggregator or it can't be reached the result is sent to the server directly, together with the metadata of the chunk.
+    /// The aggregator combines the results, so the metadata is not sent along in that case.
+    /// Signed results (see the nc_keys module) are always sent to the server directly.
+    /// A result that is sent to the server directly and isn't signed stays in the result cache until the server has acknowledged it,
+    /// if data_hash is set.
+    /// With upload_in_background a result that is sent to the server directly is given to the upload thread (see the nc_upload module)
+    /// and the receiver for the outcome is returned.
+    fn submit_result(&mut self, aggregator: Option<SocketAddr>, chunk_id: ChunkID, metadata: Option<ChunkMeta>, data_hash: Option<u64>, result: T::ProcessedDataT)
+        -> Result<Option<mpsc::Receiver<Result<(), NCError>>>, NCError> {
+        debug!("NodeProcess::submit_result()");
+
+        // An aggregator combines the results, so they can't be signed.
+        #[cfg(feature = "ed25519")]
+        if self.nc_client.has_keypair() {
+            return self.nc_client.submit_signed_result(chunk_id, &result, metadata).map(|_| None)
+        }
+
+        if let Some(aggregator) = aggregator {
+            match self.nc_client.submit_to_aggregator(aggregator, chunk_id, &result) {
+                Ok(()) => return Ok(None),
+                Err(e) => warn!("Could not send result to aggregator {}: {}, send it to the server", aggregator, e),
+            }
+        }
+
+        if let Some(data_hash) = data_hash {
+            let payload = bincode::serialize(&result).map_err(NCError::Serialize)?;
+            self.result_cache.insert(NCCachedResult::new(chunk_id, data_hash, metadata.clone(), payload));
+        }
+
+        if let Some(uploader) = &self.uploader {
+            // The node goes on with the next chunk, the outcome is handled by finish_upload()
+            let mut nc_client = self.nc_client.share();
+            return Ok(Some(uploader.upload(move || nc_client.submit_result_with_meta(result, metadata))))
+        }
+
+        self.nc_client.submit_result_with_meta(result, metadata)?;
+        self.result_cache.remove(chunk_id);
+        Ok(None)
+    }
+
+    /// Waits until the result that has been given to the upload thread (see submit_result()) has been acknowledged by the server, if there is one.
+    /// Then the result is removed from the result cache, the artifacts of the chunk are sent and its scratch folder is deleted.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the upload. The result stays in the result cache (if enabled) and is offered again later.
+    fn finish_upload(&mut self) -> Result<(), NCError> {
+        let pending_upload = match self.pending_upload.take() {
+            Some(pending_upload) => pending_upload,
+            None => return Ok(()),
+        };
+
+        let chunk_id = pending_upload.chunk_id;
+        debug!("NodeProcess::finish_upload(), chunk: {}", chunk_id);
+
+        let uploaded = pending_upload.uploaded.recv()
+            .unwrap_or_else(|_| Err(NCError::internal("NodeProcess::finish_upload", format!("the upload thread has stopped before chunk {} was sent", chunk_id))));
+        self.scratch_dir.remove_chunk_dir(&pending_upload.chunk_dir, uploaded.is_ok());
+
+        match uploaded {
+            Ok(()) => {
+                self.result_cache.remove(chunk_id);
+                self.send_artifacts(chunk_id, pending_upload.artifacts);
+                self.report_progress(NCNodeProgressEvent::ChunkDone(chunk_id, pending_upload.elapsed));
+                Ok(())
+            }
+            Err(e) => {
+                error!("Could not send result of chunk {}: {}", chunk_id, e);
+                self.report_progress(NCNodeProgressEvent::ChunkFailed(chunk_id));
+                Err(e)
+            }
+        }
+    }
+
+    /// Same as finish_upload(), this is called before anything is sent for the given chunk.
+    /// The chunk has been fetched in advance while the last result was uploaded. If that upload has failed
+    /// the server still counts the chunk as fetched in advance, so it's given back and the error is returned.
+    fn finish_upload_before(&mut self, chunk_id: ChunkID) -> Result<(), NCError> {
+        if self.pending_upload.is_none() {
+            return Ok(())
+        }
+
+        self.finish_upload().inspect_err(|_| {
+            info!("Give back chunk {}, the result before it could not be sent", chunk_id);
+
+            if let Err(e) = self.nc_client.release_chunk(chunk_id) {
+                error!("Could not give back chunk {}: {}", chunk_id, e);
+            }
+        })
+    }
+
+    /// Offers the results that the server has not acknowledged yet (see the nc_result_cache module), the oldest one first.
+    /// A result that the server takes is removed from the cache, the others are kept in case the same chunk comes back.
+    fn offer_cached_results(&mut self) -> Result<(), NCError> {
+        while let Some(cached) = self.result_cache.next_to_offer() {
+            let chunk_id = cached.chunk_id;
+            debug!("NodeProcess::offer_cached_results(), chunk: {}", chunk_id);
+
+            let result: T::ProcessedDataT = match bincode::deserialize(&cached.payload) {
+                Ok(result) => result,
+                Err(e) => {
+                    // For example from an older build of the node in result_cache_dir
+                    warn!("Cached result of chunk {} can't be decoded: {}, drop it", chunk_id, e);
+                    self.result_cache.remove(chunk_id);
+                    continue
+                }
+            };
+
+            if self.nc_client.offer_cached_result(chunk_id, cached.meta.clone(), result)? {
+                info!("Server has taken the cached result of chunk {}", chunk_id);
+                self.result_cache.remove(chunk_id);
+                self.report_progress(NCNodeProgressEvent::ChunkDone(chunk_id, Duration::ZERO));
+            } else {
+                self.result_cache.set_offered(chunk_id);
+            }
+        }
+
+        Ok(())
     }
 
     /// Returns the current value of the retry counter.
@@ -425,10 +1487,22 @@ impl<T: NCNode> NodeProcess<T> {
     }
 
     /// The current thread in the main loop sleeps for the given delay from the configuration file.
+    /// It wakes up early if the node has been stopped.
     fn sleep(&self) {
         debug!("NodeProcess::sleep()");
 
-        thread::sleep(self.delay_duration);
+        self.sleep_for(self.delay_duration)
+    }
+
+    /// Like sleep(), but for the given duration, for example the time that the server has given in a rejection.
+    fn sleep_for(&self, duration: Duration) {
+        debug!("NodeProcess::sleep_for()");
+
+        let time_start = Instant::now();
+
+        while !self.is_stopped() && time_start.elapsed() < duration {
+            thread::sleep(duration.saturating_sub(time_start.elapsed()).min(STOP_CHECK_INTERVAL));
+        }
     }
 
     /// Resets the retry counter to the initial value when there was no error.
@@ -439,6 +1513,49 @@ impl<T: NCNode> NodeProcess<T> {
     }
 }
 
+/// Runs in a separate thread while the node is processing data with a deadline.
+/// If the deadline is exceeded, the cancellation token is set and a NCNodeMessage::NodeFailed message
+/// with NCJobError::DEADLINE_EXCEEDED is sent to the server right away, so that the chunk can be given to another node.
+struct DeadlineTimer {
+    /// Tells the timer thread that the processing is done.
+    sender: mpsc::Sender<()>,
+    /// Handle of the timer thread.
+    thread_handle: JoinHandle<()>,
+}
+
+impl DeadlineTimer {
+    /// Starts the timer thread with the given deadline.
+    fn start(deadline: Duration, cancelled: Arc<AtomicBool>, mut nc_client: NCClient) -> Self {
+        debug!("DeadlineTimer::start()");
+
+        let (sender, receiver) = mpsc::channel();
+
+        let thread_handle = spawn(move || {
+            if let Err(mpsc::RecvTimeoutError::Timeout) = receiver.recv_timeout(deadline) {
+                cancelled.store(true, Ordering::Relaxed);
+
+                if let Err(e) = nc_client.report_failure(NCJobError::deadline_exceeded()) {
+                    error!("Error in DeadlineTimer, could not send NodeFailed message: {}", e);
+                }
+            }
+        });
+
+        DeadlineTimer { sender, thread_handle }
+    }
+
+    /// Stops the timer and waits for the thread to finish.
+    fn stop(self) {
+        debug!("DeadlineTimer::stop()");
+
+        // If the deadline has already been exceeded the receiver is gone, that's fine.
+        let _ = self.sender.send(());
+
+        if self.thread_handle.join().is_err() {
+            error!("DeadlineTimer thread panicked");
+        }
+    }
+}
+
 /// Counter for nc_node if connection to server is not possible.
 /// The counter will be decreased every time there is an IO error and if it is zero the method dec_and_check
 /// returns true, otherwise false.
@@ -485,31 +1602,142 @@ impl RetryCounter {
 mod tests {
     use super::*;
 
-    use std::net::{IpAddr, Ipv4Addr};
+    use std::net::TcpListener;
+
+    use crate::nc_communicator::{NCCommunicator, NCCodec};
+    use crate::nc_message::NCRejectReason;
 
     struct TestNode;
 
+    /// Sleeps for the given number of milliseconds, unless the deadline has been exceeded. 3 is not valid.
+    struct SlowNode;
+
+    impl NCNode for SlowNode {
+        type InitialDataT = ();
+        type NewDataT = u64;
+        type ProcessedDataT = u64;
+        type CustomMessageT = ();
+
+        fn process_data_from_server(&mut self, data: &u64) -> Result<NodeResult<u64>, NCError> {
+            Ok(NodeResult::Data(*data))
+        }
+
+        fn process_data_with_context(&mut self, data: &u64, context: &NCProcessContext) -> Result<NodeResult<u64>, NCError> {
+            match *data {
+                0 => return Ok(NodeResult::Empty),
+                1 => return Ok(NodeResult::Skip("too small".to_string())),
+                2 => {
+                    let error = std::io::Error::other("disk full");
+                    return Err(NCError::with_source("could not write chunk", error))
+                }
+                _ => {}
+            }
+
+            for _ in 0..*data {
+                if context.is_cancelled() {
+                    break
+                }
+
+                thread::sleep(Duration::from_millis(1));
+            }
+
+            Ok(NodeResult::Data(*data))
+        }
+
+        fn validate(&self, data: &u64) -> Result<(), String> {
+            if *data == 3 {
+                Err("3 is not allowed".to_string())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    /// Accepts one connection and returns the message from the node, answers HasData, HasDataWithMeta, Empty, Skip, Aggregate and ChunkRevoked with ResultAck.
+    fn fake_server(listener: TcpListener) -> JoinHandle<NCNodeMessage<u64, ()>> {
+        spawn(move || {
+            let mut nc_communicator = NCCommunicator::new(&NCConfiguration::default()).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            let message: NCNodeMessage<u64, ()> = nc_communicator.nc_receive_data(&mut stream).unwrap();
+
+            if let NCNodeMessage::HasData(_, _) | NCNodeMessage::Empty(_) | NCNodeMessage::Skip(_, _) | NCNodeMessage::Aggregate(_, _, _) |
+                NCNodeMessage::HasDataWithMeta(_, _, _) | NCNodeMessage::ChunkRevoked(_, _) = message {
+                let answer: NCServerMessage<(), (), ()> = NCServerMessage::ResultAck { job_finished: false };
+                nc_communicator.nc_send_data2(&answer, &mut stream).unwrap();
+            }
+
+            message
+        })
+    }
+
+    /// Accepts the given number of connections, answers the requests for new data with the given answers (in this order)
+    /// and everything else that needs an answer with ResultAck. Returns the messages from the node.
+    fn prefetch_server(listener: TcpListener, connections: usize, answers: Vec<NCServerMessage<(), u64, ()>>) -> JoinHandle<Vec<NCNodeMessage<u64, ()>>> {
+        spawn(move || {
+            let mut nc_communicator = NCCommunicator::new(&NCConfiguration::default()).unwrap();
+            let mut answers = answers.into_iter();
+
+            (0..connections).map(|_| {
+                let (mut stream, _) = listener.accept().unwrap();
+                let message: NCNodeMessage<u64, ()> = nc_communicator.nc_receive_data(&mut stream).unwrap();
+
+                if let NCNodeMessage::NeedsData(_) | NCNodeMessage::NeedsDataPrefetch(_, _) = message {
+                    nc_communicator.nc_send_data2(&answers.next().unwrap(), &mut stream).unwrap();
+                } else if message.needs_answer() {
+                    let answer: NCServerMessage<(), (), ()> = NCServerMessage::ResultAck { job_finished: false };
+                    nc_communicator.nc_send_data2(&answer, &mut stream).unwrap();
+                }
+
+                message
+            }).collect()
+        })
+    }
+
+    /// Records the context of every call of process_data_from_server().
+    #[derive(Default)]
+    struct ContextNode {
+        contexts: Vec<NCCallContext>,
+    }
+
+    impl NCNode for ContextNode {
+        type InitialDataT = ();
+        type NewDataT = u64;
+        type ProcessedDataT = u64;
+        type CustomMessageT = ();
+
+        fn process_data_from_server(&mut self, data: &u64) -> Result<NodeResult<u64>, NCError> {
+            self.contexts.push(nc_context::current());
+            Ok(NodeResult::Data(*data))
+        }
+    }
+
+    fn slow_node_process(listener: &TcpListener) -> NodeProcess<SlowNode> {
+        let server_addr = Arc::new(Mutex::new(NCServerAddr::from(listener.local_addr().unwrap())));
+        let mut node_process = NodeProcess::new(server_addr, SlowNode, &NCConfiguration::default()).unwrap();
+        node_process.nc_client.set_node_id(NodeID::random());
+        node_process
+    }
+
     impl NCNode for TestNode {
         type InitialDataT = ();
         type NewDataT = ();
         type ProcessedDataT = ();
         type CustomMessageT = ();
 
-        fn process_data_from_server(&mut self, _data: &()) -> Result<(), NCError> {
-            Ok(())
+        fn process_data_from_server(&mut self, _data: &()) -> Result<NodeResult<()>, NCError> {
+            Ok(NodeResult::Data(()))
         }
     }
 
-    fn server_addr_for_test() -> Arc<Mutex<SocketAddr>> {
-        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-        Arc::new(Mutex::new(server_addr))
+    fn server_addr_for_test() -> Arc<Mutex<NCServerAddr>> {
+        Arc::new(Mutex::new(NCServerAddr::new("127.0.0.1", 8080)))
     }
 
     #[test]
     fn test_nhb_dec_and_check_counter1() {
         let config = NCConfiguration::default();
-        let server_addr = server_addr_for_test();
-        let mut nhb = NodeHeartbeat::new(server_addr, NodeID::unset(), &config);
+        let nc_client = NCClient::with_server_addr(server_addr_for_test(), &config).unwrap();
+        let mut nhb = NodeHeartbeat::new(nc_client, RevokedChunks::default(), &config);
 
         assert_eq!(nhb.get_counter(), 5);
         assert!(!nhb.dec_and_check_counter());
@@ -529,8 +1757,8 @@ mod tests {
     #[test]
     fn test_nhb_reset_counter() {
         let config = NCConfiguration::default();
-        let server_addr = server_addr_for_test();
-        let mut nhb = NodeHeartbeat::new(server_addr, NodeID::unset(), &config);
+        let nc_client = NCClient::with_server_addr(server_addr_for_test(), &config).unwrap();
+        let mut nhb = NodeHeartbeat::new(nc_client, RevokedChunks::default(), &config);
 
         assert_eq!(nhb.get_counter(), 5);
         assert!(!nhb.dec_and_check_counter());
@@ -548,7 +1776,7 @@ mod tests {
         let nc_node = TestNode{};
         let config = NCConfiguration::default();
         let server_addr = server_addr_for_test();
-        let mut np = NodeProcess::new(server_addr, nc_node, &config);
+        let mut np = NodeProcess::new(server_addr, nc_node, &config).unwrap();
 
         assert_eq!(np.get_counter(), 5);
         assert!(!np.dec_and_check_counter());
@@ -570,7 +1798,7 @@ mod tests {
         let nc_node = TestNode{};
         let config = NCConfiguration::default();
         let server_addr = server_addr_for_test();
-        let mut np = NodeProcess::new(server_addr, nc_node, &config);
+        let mut np = NodeProcess::new(server_addr, nc_node, &config).unwrap();
 
         assert_eq!(np.get_counter(), 5);
         assert!(!np.dec_and_check_counter());
@@ -582,4 +1810,567 @@ mod tests {
         assert!(!np.dec_and_check_counter());
         assert_eq!(np.get_counter(), 4);
     }
+
+    #[test]
+    fn test_node_failed_message_round_trip() {
+        let config = NCConfiguration::default();
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
+        let node_id = NodeID::random();
+        let job_error = NCJobError::new(7, "division by zero", false);
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::NodeFailed(node_id, job_error.clone());
+        let data = nc_communicator.nc_encode_data(&message).unwrap();
+        let message: NCNodeMessage<(), ()> = nc_communicator.nc_decode_data(&data).unwrap();
+
+        match message {
+            NCNodeMessage::NodeFailed(id, error) => {
+                assert_eq!(id, node_id);
+                assert_eq!(error, job_error);
+            }
+            _ => panic!("Expected a NodeFailed message"),
+        }
+    }
+
+    #[test]
+    fn test_process_context_deadline_remaining() {
+        let chunk_info = NCChunkInfo { chunk_id: 7, deadline: Some(Duration::from_secs(10)), average_chunk_time: Some(Duration::from_secs(2)), aggregator: None, metadata: None, attempt: 1,
+            job_time_remaining: Some(Duration::from_secs(30)), result_dictionary: None };
+        let context = NCProcessContext::new(chunk_info, PathBuf::new(), NCThrottle::new(&NCConfiguration::default()), NCArtifacts::disabled());
+
+        assert!(context.deadline_remaining().unwrap() <= Duration::from_secs(10));
+        assert!(context.deadline_remaining().unwrap() > Duration::from_secs(9));
+        assert!(context.elapsed() < Duration::from_secs(1));
+        assert_eq!(context.chunk_id(), 7);
+        assert_eq!(context.attempt(), 1);
+        assert_eq!(context.average_chunk_time(), Some(Duration::from_secs(2)));
+        assert!(context.job_time_remaining().unwrap() <= Duration::from_secs(30));
+        assert!(context.job_time_remaining().unwrap() > Duration::from_secs(29));
+        assert!(!context.is_cancelled());
+
+        let context = NCProcessContext::new(NCChunkInfo::default(), PathBuf::new(), NCThrottle::new(&NCConfiguration::default()), NCArtifacts::disabled());
+
+        assert_eq!(context.deadline_remaining(), None);
+        assert_eq!(context.average_chunk_time(), None);
+        assert_eq!(context.job_time_remaining(), None);
+        assert_eq!(context.metadata(), None);
+    }
+
+    #[test]
+    fn test_call_context() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = NCConfiguration { job_id: Some("tenant-a".to_string()), node_tags: vec!["gpu".to_string()], ..Default::default() };
+        let server_addr = Arc::new(Mutex::new(NCServerAddr::from(listener.local_addr().unwrap())));
+        let mut node_process = NodeProcess::new(server_addr, ContextNode::default(), &config).unwrap();
+        let node_id = NodeID::random();
+        node_process.nc_client.set_node_id(node_id);
+        let server = fake_server(listener);
+
+        node_process.process_data_and_send_has_data_message(&5, NCChunkInfo { chunk_id: 4, attempt: 2, ..Default::default() }).unwrap();
+        assert!(matches!(server.join().unwrap(), NCNodeMessage::HasData(_, 5)));
+
+        let node_info = NCContextNodeInfo { build: config.node_build.clone(), tags: vec!["gpu".to_string()] };
+        let expected = NCCallContext { job_id: Some("tenant-a".to_string()), node_id: Some(node_id), node_info: Some(node_info), chunk_id: Some(4), attempt: 2 };
+        assert_eq!(node_process.nc_node.contexts, vec![expected]);
+
+        // Outside of the calls into the user code there is no context
+        assert_eq!(nc_context::current(), NCCallContext::default());
+    }
+
+    #[test]
+    fn test_result_with_meta() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut node_process = slow_node_process(&listener);
+        let server = fake_server(listener);
+        let meta: ChunkMeta = vec![("tile".to_string(), "3/4".to_string())].into_iter().collect();
+
+        node_process.process_data_and_send_has_data_message(&10, NCChunkInfo { metadata: Some(meta.clone()), ..Default::default() }).unwrap();
+
+        // The metadata is sent back with the result
+        match server.join().unwrap() {
+            NCNodeMessage::HasDataWithMeta(node_id, result_meta, data) => {
+                assert_eq!(node_id, node_process.node_id());
+                assert_eq!(result_meta, meta);
+                assert_eq!(data, 10);
+            }
+            _ => panic!("Expected a HasDataWithMeta message"),
+        }
+    }
+
+    #[test]
+    fn test_job_finished_with_last_result() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut node_process = slow_node_process(&listener);
+
+        // Only one connection: the node must not ask for new data after the last result
+        let server = spawn(move || {
+            let mut nc_communicator = NCCommunicator::new(&NCConfiguration::default()).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            let message: NCNodeMessage<u64, ()> = nc_communicator.nc_receive_data(&mut stream).unwrap();
+            let answer: NCServerMessage<(), (), ()> = NCServerMessage::ResultAck { job_finished: true };
+            nc_communicator.nc_send_data2(&answer, &mut stream).unwrap();
+            message
+        });
+
+        node_process.process_data_and_send_has_data_message(&10, NCChunkInfo::default()).unwrap();
+        assert!(matches!(server.join().unwrap(), NCNodeMessage::HasData(_, 10)));
+        assert!(!node_process.job_finished);
+
+        node_process.get_and_process_data().unwrap();
+        assert!(node_process.job_finished);
+        assert!(node_process.nc_client.job_finished());
+    }
+
+    #[test]
+    fn test_validate() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut node_process = slow_node_process(&listener);
+        let server = fake_server(listener);
+
+        node_process.process_data_and_send_has_data_message(&3, NCChunkInfo::default()).unwrap();
+
+        // The data is not processed, the server gets a permanent failure
+        match server.join().unwrap() {
+            NCNodeMessage::NodeFailed(node_id, job_error) => {
+                assert_eq!(node_id, node_process.node_id());
+                assert_eq!(job_error.code, NCJobError::VALIDATION_FAILED);
+                assert_eq!(job_error.message, "Validation failed: 3 is not allowed");
+                assert!(!job_error.retryable);
+            }
+            _ => panic!("Expected a NodeFailed message"),
+        }
+    }
+
+    #[test]
+    fn test_deadline_exceeded() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut node_process = slow_node_process(&listener);
+        let server = fake_server(listener);
+        let time_start = Instant::now();
+
+        node_process.process_data_and_send_has_data_message(&5000, NCChunkInfo { deadline: Some(Duration::from_millis(100)), ..Default::default() }).unwrap();
+
+        // The slow computation has been cancelled
+        assert!(time_start.elapsed() < Duration::from_secs(4));
+
+        match server.join().unwrap() {
+            NCNodeMessage::NodeFailed(node_id, job_error) => {
+                assert_eq!(node_id, node_process.node_id());
+                assert_eq!(job_error.code, NCJobError::DEADLINE_EXCEEDED);
+                assert!(job_error.retryable);
+            }
+            _ => panic!("Expected a NodeFailed message"),
+        }
+    }
+
+    #[test]
+    fn test_revoked_chunk() {
+        // Revoked before the node has started the chunk
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut node_process = slow_node_process(&listener);
+        let server = fake_server(listener.try_clone().unwrap());
+
+        node_process.revoked.revoke(vec![4]);
+        node_process.process_data_and_send_has_data_message(&10, NCChunkInfo { chunk_id: 4, ..Default::default() }).unwrap();
+
+        match server.join().unwrap() {
+            NCNodeMessage::ChunkRevoked(node_id, chunk_id) => {
+                assert_eq!(node_id, node_process.node_id());
+                assert_eq!(chunk_id, 4);
+            }
+            _ => panic!("Expected a ChunkRevoked message"),
+        }
+
+        // Revoked while the node is processing it, other chunks are not affected
+        let server = fake_server(listener);
+        let revoked = node_process.revoked.clone();
+        let heartbeat = spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            revoked.revoke(vec![3, 5]);
+        });
+        let time_start = Instant::now();
+
+        node_process.process_data_and_send_has_data_message(&5000, NCChunkInfo { chunk_id: 5, ..Default::default() }).unwrap();
+        heartbeat.join().unwrap();
+
+        assert!(time_start.elapsed() < Duration::from_secs(4));
+        assert!(matches!(server.join().unwrap(), NCNodeMessage::ChunkRevoked(_, 5)));
+        assert!(!node_process.revoked.take(5));
+        assert!(node_process.revoked.take(3));
+    }
+
+    #[test]
+    fn test_deadline_not_exceeded() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut node_process = slow_node_process(&listener);
+        let server = fake_server(listener);
+
+        node_process.process_data_and_send_has_data_message(&10, NCChunkInfo { deadline: Some(Duration::from_secs(5)), ..Default::default() }).unwrap();
+
+        match server.join().unwrap() {
+            NCNodeMessage::HasData(node_id, data) => {
+                assert_eq!(node_id, node_process.node_id());
+                assert_eq!(data, 10);
+            }
+            _ => panic!("Expected a HasData message"),
+        }
+    }
+
+    #[test]
+    fn test_empty_and_skip_result() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut node_process = slow_node_process(&listener);
+        let server = fake_server(listener.try_clone().unwrap());
+
+        node_process.process_data_and_send_has_data_message(&0, NCChunkInfo::default()).unwrap();
+
+        match server.join().unwrap() {
+            NCNodeMessage::Empty(node_id) => assert_eq!(node_id, node_process.node_id()),
+            _ => panic!("Expected an Empty message"),
+        }
+
+        let server = fake_server(listener);
+
+        node_process.process_data_and_send_has_data_message(&1, NCChunkInfo::default()).unwrap();
+
+        match server.join().unwrap() {
+            NCNodeMessage::Skip(node_id, reason) => {
+                assert_eq!(node_id, node_process.node_id());
+                assert_eq!(reason, "too small");
+            }
+            _ => panic!("Expected a Skip message"),
+        }
+    }
+
+    #[test]
+    fn test_aggregator_fallback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut node_process = slow_node_process(&listener);
+
+        // The result goes to the aggregator
+        let aggregator = TcpListener::bind("127.0.0.1:0").unwrap();
+        let chunk_info = NCChunkInfo { chunk_id: 3, aggregator: Some(aggregator.local_addr().unwrap()), ..Default::default() };
+        let fake_aggregator = fake_server(aggregator);
+        node_process.process_data_and_send_has_data_message(&5, chunk_info.clone()).unwrap();
+
+        match fake_aggregator.join().unwrap() {
+            NCNodeMessage::Aggregate(node_id, chunk_id, data) => {
+                assert_eq!(node_id, node_process.node_id());
+                assert_eq!((chunk_id, data), (3, 5));
+            }
+            _ => panic!("Expected an Aggregate message"),
+        }
+
+        // The aggregator is gone, the result goes to the server
+        let server = fake_server(listener);
+        node_process.process_data_and_send_has_data_message(&5, chunk_info).unwrap();
+        assert!(matches!(server.join().unwrap(), NCNodeMessage::HasData(_, 5)));
+    }
+
+    #[test]
+    fn test_user_error_result() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut node_process = slow_node_process(&listener);
+        let server = fake_server(listener);
+
+        assert!(node_process.process_data_and_send_has_data_message(&2, NCChunkInfo::default()).is_ok());
+
+        match server.join().unwrap() {
+            NCNodeMessage::NodeFailed(node_id, job_error) => {
+                assert_eq!(node_id, node_process.node_id());
+                assert_eq!(job_error, NCJobError::new(NCJobError::USER_ERROR, "could not write chunk: disk full", true));
+            }
+            _ => panic!("Expected a NodeFailed message"),
+        }
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = NCConfiguration { port: listener.local_addr().unwrap().port(), ..Default::default() };
+
+        let report = NCNodeStarter::new(config.clone()).dry_run(&mut SlowNode, &5);
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.checks().last().unwrap().name, "process data");
+
+        // Nobody is listening anymore
+        drop(listener);
+        let report = NCNodeStarter::new(config).dry_run(&mut SlowNode, &0);
+        assert_eq!(report.problems().map(|check| check.name.as_str()).collect::<Vec<_>>(), vec!["connect to server"]);
+    }
+
+    #[test]
+    fn test_please_restart() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let node_process = slow_node_process(&listener);
+
+        let server = spawn(move || {
+            let mut nc_communicator = NCCommunicator::new(&NCConfiguration::default()).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            let message: NCNodeMessage<u64, ()> = nc_communicator.nc_receive_data(&mut stream).unwrap();
+            let answer: NCServerMessage<(), (), ()> = NCServerMessage::PleaseRestart { reason: "new build".to_string() };
+            nc_communicator.nc_send_data2(&answer, &mut stream).unwrap();
+            message
+        });
+
+        let node_exit = NCNodeStarter::new(NCConfiguration::default()).start_main_loop(node_process).unwrap();
+
+        assert_eq!(node_exit, NodeExit::RestartRequested);
+        assert!(matches!(server.join().unwrap(), NCNodeMessage::NeedsData(_)));
+    }
+
+    #[test]
+    fn test_rejected_by_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut node_process = slow_node_process(&listener);
+
+        // The first registration is rejected because the server is busy, the node is disabled when it asks for data
+        let server = spawn(move || {
+            let mut nc_communicator = NCCommunicator::new(&NCConfiguration::default()).unwrap();
+            let mut registrations = 0;
+
+            loop {
+                let (mut stream, _) = listener.accept().unwrap();
+                let message: NCNodeMessage<u64, ()> = nc_communicator.nc_receive_data(&mut stream).unwrap();
+
+                let (reason, retry_after) = match message {
+                    NCNodeMessage::Register(..) if registrations == 0 => (NCRejectReason::ServerBusy, Some(Duration::from_millis(10))),
+                    NCNodeMessage::Register(..) => {
+                        let answer: NCServerMessage<(), (), ()> = NCServerMessage::InitialData(NodeID::random(), None, NCCodec::None);
+                        nc_communicator.nc_send_data2(&answer, &mut stream).unwrap();
+                        registrations += 1;
+                        continue
+                    }
+                    NCNodeMessage::NeedsData(_) => (NCRejectReason::Disabled, None),
+                    _ => continue, // The clock measurement
+                };
+
+                registrations += 1;
+                let answer: NCServerMessage<(), (), ()> = NCServerMessage::Rejected { reason: reason.clone(), message: "test".to_string(), retry_after };
+                nc_communicator.nc_send_data2(&answer, &mut stream).unwrap();
+
+                if reason == NCRejectReason::Disabled {
+                    return registrations
+                }
+            }
+        });
+
+        node_process.get_initial_data().unwrap();
+        let result = NCNodeStarter::new(NCConfiguration::default()).start_main_loop(node_process);
+
+        // The node exits right away, without waiting for the retry counter
+        assert_eq!(result.unwrap(), NodeExit::Disabled { reason: "test".to_string() });
+        assert_eq!(server.join().unwrap(), 3);
+    }
+
+    /// Runs the main loop against a server that answers the first request for data with the given message.
+    fn exit_after(answer: NCServerMessage<(), (), ()>) -> Result<NodeExit, NCError> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let node_process = slow_node_process(&listener);
+
+        let server = spawn(move || {
+            let mut nc_communicator = NCCommunicator::new(&NCConfiguration::default()).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            let _: NCNodeMessage<u64, ()> = nc_communicator.nc_receive_data(&mut stream).unwrap();
+            nc_communicator.nc_send_data2(&answer, &mut stream).unwrap();
+        });
+
+        let node_exit = NCNodeStarter::new(NCConfiguration::default()).start_main_loop(node_process);
+        server.join().unwrap();
+        node_exit
+    }
+
+    #[test]
+    fn test_node_exit() {
+        assert_eq!(exit_after(NCServerMessage::JobStatus(NCJobStatus::Finished)).unwrap(), NodeExit::JobFinished);
+        assert_eq!(exit_after(NCServerMessage::PleaseRestart { reason: "new build".to_string() }).unwrap(), NodeExit::RestartRequested);
+        assert_eq!(exit_after(NCServerMessage::JobAborted("aborted: test".to_string())).unwrap(), NodeExit::JobAborted { reason: "aborted: test".to_string() });
+
+        let disabled = NCServerMessage::Rejected { reason: NCRejectReason::Disabled, message: "by the admin".to_string(), retry_after: None };
+        assert_eq!(exit_after(disabled).unwrap(), NodeExit::Disabled { reason: "by the admin".to_string() });
+
+        // Fatal rejections are errors
+        let wrong_version = NCServerMessage::Rejected { reason: NCRejectReason::VersionMismatch, message: "test".to_string(), retry_after: None };
+        assert!(matches!(exit_after(wrong_version), Err(NCError::VersionMismatch(_))));
+
+        // The stop handle, the server is not asked at all
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut node_starter = NCNodeStarter::new(NCConfiguration::default());
+        let mut node_process = slow_node_process(&listener);
+        node_process.stopped = node_starter.stop_handle();
+        node_process.stopped.store(true, Ordering::Relaxed);
+        assert_eq!(node_starter.start_main_loop(node_process).unwrap(), NodeExit::StoppedByUser);
+    }
+
+    #[test]
+    fn test_work_hint_from_config() {
+        assert_eq!(NCWorkHint::from_config(&NCConfiguration::default()), None);
+
+        let config = NCConfiguration { work_hint_max_bytes: 1000, work_hint_max_duration: 60, ..Default::default() };
+        let work_hint = NCWorkHint::from_config(&config).unwrap();
+
+        assert_eq!(work_hint.max_bytes, Some(1000));
+        assert_eq!(work_hint.max_duration_hint, Some(Duration::from_secs(60)));
+        assert_eq!(work_hint.preferred_kind, None);
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut node_process = slow_node_process(&listener);
+        node_process.prefetch = true;
+        let chunk_info = |chunk_id| NCChunkInfo { chunk_id, ..Default::default() };
+        let server = prefetch_server(listener, 4, vec![
+            NCServerMessage::JobStatus(NCJobStatus::Unfinished(7, chunk_info(1))),
+            NCServerMessage::JobStatus(NCJobStatus::Finished),
+        ]);
+
+        // The next chunk is requested while the current one is processed
+        node_process.process_data_and_send_has_data_message(&5, chunk_info(0)).unwrap();
+        assert!(matches!(node_process.prefetched, Some(NCServerMessage::JobStatus(NCJobStatus::Unfinished(7, _)))));
+
+        // The prefetched chunk is processed without asking the server again
+        node_process.get_and_process_data().unwrap();
+        node_process.get_and_process_data().unwrap();
+        assert!(node_process.job_finished);
+
+        let messages = server.join().unwrap();
+        assert!(matches!(messages[0], NCNodeMessage::NeedsDataPrefetch(_, None)));
+        assert!(matches!(messages[1], NCNodeMessage::HasData(_, 5)));
+        assert!(matches!(messages[2], NCNodeMessage::NeedsDataPrefetch(_, None)));
+        assert!(matches!(messages[3], NCNodeMessage::HasData(_, 7)));
+    }
+
+    #[test]
+    fn test_upload_in_background() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = Arc::new(Mutex::new(NCServerAddr::from(listener.local_addr().unwrap())));
+        let config = NCConfiguration { prefetch: true, upload_in_background: true, ..Default::default() };
+        let mut node_process = NodeProcess::new(server_addr, SlowNode, &config).unwrap();
+        node_process.nc_client.set_node_id(NodeID::random());
+        let chunk_info = |chunk_id| NCChunkInfo { chunk_id, ..Default::default() };
+        let mut answers = vec![
+            NCServerMessage::<(), u64, ()>::JobStatus(NCJobStatus::Unfinished(200, chunk_info(1))),
+            NCServerMessage::JobStatus(NCJobStatus::Unfinished(200, chunk_info(2))),
+            NCServerMessage::JobStatus(NCJobStatus::Finished),
+        ].into_iter();
+
+        // A slow upload: the server needs 200 ms for each result
+        let server = spawn(move || {
+            let mut nc_communicator = NCCommunicator::new(&NCConfiguration::default()).unwrap();
+
+            (0..6).map(|_| {
+                let (mut stream, _) = listener.accept().unwrap();
+                let message: NCNodeMessage<u64, ()> = nc_communicator.nc_receive_data(&mut stream).unwrap();
+
+                if let NCNodeMessage::NeedsDataPrefetch(_, _) = message {
+                    nc_communicator.nc_send_data2(&answers.next().unwrap(), &mut stream).unwrap();
+                } else {
+                    thread::sleep(Duration::from_millis(200));
+                    let answer: NCServerMessage<(), (), ()> = NCServerMessage::ResultAck { job_finished: false };
+                    nc_communicator.nc_send_data2(&answer, &mut stream).unwrap();
+                }
+
+                message
+            }).collect::<Vec<_>>()
+        });
+
+        // Three chunks of 200 ms each, the next chunk is processed while the last result is uploaded
+        let start = Instant::now();
+        node_process.process_data_and_send_has_data_message(&200, chunk_info(0)).unwrap();
+        assert!(node_process.pending_upload.is_some());
+        node_process.get_and_process_data().unwrap();
+        node_process.get_and_process_data().unwrap();
+        node_process.get_and_process_data().unwrap();
+        assert!(node_process.job_finished);
+        node_process.finish_upload().unwrap();
+        assert!(node_process.pending_upload.is_none());
+
+        // 1200 ms one after the other
+        let elapsed = start.elapsed();
+        assert!(elapsed < Duration::from_millis(1050), "elapsed: {:?}", elapsed);
+
+        // The request in advance is sent after the result before it has been acknowledged
+        let messages = server.join().unwrap();
+        assert!(matches!(messages[0], NCNodeMessage::NeedsDataPrefetch(_, None)));
+        assert!(matches!(messages[1], NCNodeMessage::HasData(_, 200)));
+        assert!(matches!(messages[2], NCNodeMessage::NeedsDataPrefetch(_, None)));
+        assert!(matches!(messages[3], NCNodeMessage::HasData(_, 200)));
+        assert!(matches!(messages[4], NCNodeMessage::NeedsDataPrefetch(_, None)));
+        assert!(matches!(messages[5], NCNodeMessage::HasData(_, 200)));
+    }
+
+    #[test]
+    fn test_result_cache() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = Arc::new(Mutex::new(NCServerAddr::from(listener.local_addr().unwrap())));
+        let mut node_process = NodeProcess::new(server_addr, SlowNode, &NCConfiguration { result_cache_count: 4, ..Default::default() }).unwrap();
+        node_process.nc_client.set_node_id(NodeID::random());
+        let chunk_info = |chunk_id| NCChunkInfo { chunk_id, ..Default::default() };
+
+        // None: the connection breaks before the answer
+        let answers: Vec<Option<NCServerMessage<(), u64, ()>>> = vec![
+            None,
+            Some(NCServerMessage::ResultRejected(NCJobError::result_rejected("not the current chunk", false))),
+            Some(NCServerMessage::JobStatus(NCJobStatus::Unfinished(5, chunk_info(4)))),
+            Some(NCServerMessage::ResultAck { job_finished: false }),
+            None,
+            Some(NCServerMessage::ResultAck { job_finished: false }),
+            Some(NCServerMessage::JobStatus(NCJobStatus::Finished)),
+        ];
+        let server = spawn(move || {
+            let mut nc_communicator = NCCommunicator::new(&NCConfiguration::default()).unwrap();
+
+            answers.into_iter().map(|answer| {
+                let (mut stream, _) = listener.accept().unwrap();
+                let message: NCNodeMessage<u64, ()> = nc_communicator.nc_receive_data(&mut stream).unwrap();
+
+                if let Some(answer) = answer {
+                    nc_communicator.nc_send_data2(&answer, &mut stream).unwrap();
+                }
+
+                message
+            }).collect::<Vec<_>>()
+        });
+
+        // The result is kept until the server has acknowledged it
+        assert!(node_process.process_data_and_send_has_data_message(&5, chunk_info(4)).is_err());
+        assert_eq!(node_process.result_cache.len(), 1);
+
+        // The server doesn't take it, but then gives the same chunk to the node again: the cached result is sent
+        node_process.get_and_process_data().unwrap();
+        assert_eq!(node_process.result_cache.len(), 0);
+
+        // The server takes the cached result
+        assert!(node_process.process_data_and_send_has_data_message(&6, chunk_info(6)).is_err());
+        node_process.get_and_process_data().unwrap();
+        assert_eq!(node_process.result_cache.len(), 0);
+        assert!(node_process.job_finished);
+
+        let messages = server.join().unwrap();
+        assert!(matches!(messages[0], NCNodeMessage::HasData(_, 5)));
+        assert!(matches!(messages[1], NCNodeMessage::HasCachedData(_, 4, None, 5)));
+        assert!(matches!(messages[2], NCNodeMessage::NeedsData(_)));
+        assert!(matches!(messages[3], NCNodeMessage::HasData(_, 5)));
+        assert!(matches!(messages[4], NCNodeMessage::HasData(_, 6)));
+        assert!(matches!(messages[5], NCNodeMessage::HasCachedData(_, 6, None, 6)));
+        assert!(matches!(messages[6], NCNodeMessage::NeedsData(_)));
+    }
+
+    #[test]
+    fn test_prefetch_released() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut node_process = slow_node_process(&listener);
+        node_process.prefetch = true;
+        let chunk_info = NCChunkInfo { chunk_id: 9, ..Default::default() };
+        let server = prefetch_server(listener, 3, vec![NCServerMessage::JobStatus(NCJobStatus::Unfinished(7, chunk_info))]);
+
+        // The current chunk fails, the prefetched chunk is given back before the failure is reported
+        node_process.process_data_and_send_has_data_message(&2, NCChunkInfo::default()).unwrap();
+        assert!(node_process.prefetched.is_none());
+
+        let messages = server.join().unwrap();
+        assert!(matches!(messages[0], NCNodeMessage::NeedsDataPrefetch(_, None)));
+        assert!(matches!(messages[1], NCNodeMessage::ReleaseChunk(_, 9)));
+        assert!(matches!(&messages[2], NCNodeMessage::NodeFailed(_, job_error) if job_error.code == NCJobError::USER_ERROR));
+    }
 }
@@ -0,0 +1,192 @@
+//! This module contains the node id file for the node (node_id_file in the NCConfiguration).
+//! Without it every start of the node gets a new node id from the server, so the statistics of the server are split up by process
+//! and the chunks of the old process are only given to other nodes after its heartbeat timeout. With the node id file the node keeps
+//! its node id: the first start writes the node id that the server has assigned, every later start sends the NCNodeMessage::Rejoin
+//! message with the node id and the next incarnation. The server gives the chunks of the old process to other nodes right away and the
+//! new process starts without them.
+//!
+//! The file contains one line with the node id and the incarnation, separated by a space. It's written after every registration,
+//! first to a temporary file that is then renamed, so that a crash doesn't leave half a line behind.
+//! A file that is broken or can't be read is ignored with a warning, then the node gets a new node id.
+//!
+//! Two processes must not use the same file at the same time: the second one would take over the node id of the first one, which
+//! keeps sending results for chunks that are not its own anymore. So the node locks the file `<node_id_file>.lock` while it's running
+//! (an advisory lock of the operating system, it's released when the process exits, also after a crash) and doesn't start if another
+//! process holds the lock.
+
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+
+use fs2::FileExt;
+use log::{debug, warn};
+
+use crate::nc_error::NCError;
+use crate::nc_node_info::NodeID;
+
+/// The node id file of a node, see the module documentation.
+#[derive(Debug)]
+pub(crate) struct NCNodeIdFile {
+    /// Where the node id and the incarnation are stored.
+    path: PathBuf,
+    /// The locked lock file, while this process uses the node id file.
+    lock: Option<File>,
+}
+
+impl NCNodeIdFile {
+    /// The node id file with the given path, nothing is read or written here.
+    pub(crate) fn new(path: &Path) -> Self {
+        debug!("NCNodeIdFile::new()");
+
+        NCNodeIdFile { path: path.to_path_buf(), lock: None }
+    }
+
+    /// Locks the file for this process and returns the node id and the incarnation of the last process, [`None`] if there is no file yet
+    /// or it can't be used. The lock is held until this NCNodeIdFile is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::NodeIdFileLocked`] error if another process uses the same file.
+    pub(crate) fn open(&mut self) -> Result<Option<(NodeID, u32)>, NCError> {
+        debug!("NCNodeIdFile::open()");
+
+        if self.lock.is_none() {
+            let mut lock_path = self.path.clone().into_os_string();
+            lock_path.push(".lock");
+
+            let lock = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)
+                .and_then(|file| file.try_lock_exclusive().map(|_| file));
+
+            match lock {
+                Ok(lock) => self.lock = Some(lock),
+                Err(e) if e.kind() == fs2::lock_contended_error().kind() => return Err(NCError::NodeIdFileLocked(self.path.display().to_string())),
+                Err(e) => {
+                    warn!("Could not lock node id file {}: {}, the node registers with a new node id", self.path.display(), e);
+                    return Ok(None)
+                }
+            }
+        }
+
+        Ok(self.load())
+    }
+
+    /// Returns the node id and the incarnation from the file, see open().
+    fn load(&self) -> Option<(NodeID, u32)> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Could not read node id file {}: {}, the node registers with a new node id", self.path.display(), e);
+                return None
+            }
+        };
+
+        let mut fields = content.split_whitespace();
+
+        match (fields.next().map(str::parse), fields.next().map(str::parse), fields.next()) {
+            (Some(Ok(node_id)), Some(Ok(incarnation)), None) => Some((node_id, incarnation)),
+            _ => {
+                warn!("Node id file {} is invalid, the node registers with a new node id", self.path.display());
+                None
+            }
+        }
+    }
+
+    /// Writes the node id and the incarnation of this process. If that fails the node keeps running, only the next process gets
+    /// a new node id, so this is just a warning. Nothing is written if the file could not be locked.
+    pub(crate) fn save(&self, node_id: NodeID, incarnation: u32) {
+        debug!("NCNodeIdFile::save()");
+
+        if self.lock.is_none() {
+            return
+        }
+
+        // Appended to the whole file name, so that node.id and node.cfg don't share the same temporary file
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        let result = fs::write(&temp_path, format!("{} {}\n", node_id, incarnation)).and_then(|_| fs::rename(&temp_path, &self.path));
+
+        if let Err(e) = result {
+            warn!("Could not write node id file {}: {}, the next process gets a new node id", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process;
+
+    fn remove(path: &Path) {
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(format!("{}.lock", path.display()));
+    }
+
+    #[test]
+    fn test_reuse() {
+        let path = std::env::temp_dir().join(format!("nc_node_id_{}.txt", process::id()));
+        remove(&path);
+        let mut node_id_file = NCNodeIdFile::new(&path);
+
+        assert_eq!(node_id_file.open().unwrap(), None);
+
+        // A file with the same stem is left alone
+        let sibling = path.with_extension("tmp");
+        fs::write(&sibling, "other").unwrap();
+
+        let node_id = NodeID::random();
+        node_id_file.save(node_id, 3);
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{} 3\n", node_id));
+        assert_eq!(fs::read_to_string(&sibling).unwrap(), "other");
+        assert!(!Path::new(&format!("{}.tmp", path.display())).exists());
+        fs::remove_file(&sibling).unwrap();
+        drop(node_id_file);
+
+        // The next process gets the node id and the incarnation
+        let mut node_id_file = NCNodeIdFile::new(&path);
+        assert_eq!(node_id_file.open().unwrap(), Some((node_id, 3)));
+        drop(node_id_file);
+        remove(&path);
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let path = std::env::temp_dir().join(format!("nc_node_id_corrupt_{}.txt", process::id()));
+        remove(&path);
+
+        // A broken file is not an error, the node just gets a new node id
+        for content in ["42", "42 x", "42 1 7", "\u{0}\u{1}"] {
+            fs::write(&path, content).unwrap();
+            assert_eq!(NCNodeIdFile::new(&path).open().unwrap(), None);
+        }
+
+        remove(&path);
+
+        // Neither is a file that can't be read
+        fs::create_dir_all(&path).unwrap();
+        assert_eq!(NCNodeIdFile::new(&path).open().unwrap(), None);
+        fs::remove_dir(&path).unwrap();
+        remove(&path);
+    }
+
+    #[test]
+    fn test_shared_file() {
+        let path = std::env::temp_dir().join(format!("nc_node_id_shared_{}.txt", process::id()));
+        remove(&path);
+        let mut first = NCNodeIdFile::new(&path);
+        assert_eq!(first.open().unwrap(), None);
+
+        // The second process doesn't start while the first one is running
+        let mut second = NCNodeIdFile::new(&path);
+        assert!(matches!(second.open(), Err(NCError::NodeIdFileLocked(_))));
+        second.save(NodeID::random(), 1);
+        assert!(!path.exists());
+
+        drop(first);
+        assert_eq!(second.open().unwrap(), None);
+        drop(second);
+        remove(&path);
+    }
+}
@@ -1,20 +1,72 @@
 //! This module contains the node id and node info data structure.
 //! NodeID is just a new type pattern for a integer number.
-//! NCNodeInfo holds the node id and a time stamp for the heartbeat.
+//! NCNodeInfo holds the node id and the state of the node on the server, NCHeartbeats the time stamps for the heartbeat.
+//! All the time stamps and timeouts here use the monotonic clock of the server, the clock of a node (see the
+//! [`nc_clock`](crate::nc_clock) module) is never used for them.
+//!
+//! Every node sends a heartbeat every few seconds, with thousands of nodes that's the most frequent message. So the heartbeats
+//! (and the resources that come with them) are not kept in the node list but in the NCHeartbeatTable, which is split up by node id
+//! into HEARTBEAT_SHARDS independently locked parts. The server handles a heartbeat with the lock of one shard and doesn't wait
+//! for the lock of the node list, which is held for assigning chunks, registering nodes and so on.
+//! Locking rules for the table:
+//! - The lock of a shard is only held inside the methods of NCHeartbeatTable, it's never returned or kept across calls.
+//! - At most one shard is locked at a time, check() goes through the shards one after another.
+//! - A shard may be locked while the node list is locked (the node list uses the table), but never the other way round:
+//!   nothing in the table calls back into the node list or the server.
 
-use std::collections::VecDeque;
-use std::time::Instant;
+#[cfg(feature = "net")]
+use std::cmp::Reverse;
+#[cfg(feature = "net")]
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, VecDeque};
+#[cfg(feature = "net")]
+use std::net::SocketAddr;
+#[cfg(feature = "net")]
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+#[cfg(feature = "net")]
+use std::time::{Duration, Instant};
 use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::num::ParseIntError;
 
 use rand::random;
 use serde::{Serialize, Deserialize};
 
+#[cfg(feature = "net")]
+use crate::nc_error::NCJobError;
+#[cfg(feature = "net")]
+use crate::nc_communicator::NCCodec;
+#[cfg(feature = "net")]
+use crate::array2d::ChunkID;
+#[cfg(feature = "net")]
+use crate::nc_resources::NCResourceReport;
+#[cfg(feature = "net")]
+use crate::nc_clock::NCClockSkew;
+#[cfg(feature = "net")]
+use crate::nc_context::NCContextNodeInfo;
+
+/// The nodes send their heartbeats at random intervals of heartbeat ± this fraction (see NCConfiguration), so that nodes that have been
+/// started at the same time don't all send their heartbeats at the same time.
+#[cfg(feature = "net")]
+pub(crate) const HEARTBEAT_JITTER: f64 = 0.2;
+
+/// Returns how long the server waits for a heartbeat before the node is marked as offline. This is the longest interval of a node
+/// (see HEARTBEAT_JITTER) plus one second for the network.
+#[cfg(feature = "net")]
+pub(crate) fn heartbeat_limit(heartbeat: u64) -> Duration {
+    Duration::from_secs_f64(heartbeat as f64 * (1.0 + HEARTBEAT_JITTER)) + Duration::from_secs(1)
+}
+
+/// Number of independently locked parts of the NCHeartbeatTable.
+#[cfg(feature = "net")]
+pub(crate) const HEARTBEAT_SHARDS: usize = 16;
+
 /// New type pattern for the node id.
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct NodeID(u64);
 
 impl NodeID {
     /// Create a new temporary node id that will be set later
+    #[cfg(feature = "net")]
     pub(crate) fn unset() -> Self {
         NodeID(0)
     }
@@ -31,38 +83,74 @@ impl Display for NodeID {
     }
 }
 
-/// This data structure contains the node id and the time stamps for the heartbeat.
+impl FromStr for NodeID {
+    type Err = ParseIntError;
+
+    /// Parses the node id from the same format that is used by Display.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(NodeID)
+    }
+}
+
+/// This data structure contains the node id and the state of the node on the server.
+#[cfg(feature = "net")]
 #[derive(Debug, PartialEq)]
 pub(crate) struct NCNodeInfo<U> {
     /// The id of the node.
     node_id: NodeID,
-    /// Time stamp for the node id since the last valid heartbeat.
-    instant: Instant,
     /// Message queue (FIFO) for messages that will be send to the node.
     message_queue: VecDeque<U>,
+    /// The node has been disabled via the admin protocol and will not get any more data.
+    disabled: bool,
+    /// Errors from the server that will be sent to the node.
+    job_errors: VecDeque<NCJobError>,
+    /// The codec that has been negotiated with the node during registration.
+    codec: NCCodec,
+    /// The chunk that has been sent to the node last and when it has been sent.
+    current_chunk: Option<(ChunkID, Instant)>,
+    /// The chunk that the node has fetched in advance while it was still processing the current chunk, see prefetch in the NCConfiguration.
+    prefetched_chunk: Option<(ChunkID, Instant)>,
+    /// The build of the node binary, sent by the node during registration.
+    build: Option<String>,
+    /// The address where this node accepts the results of other nodes, if it is an aggregator.
+    aggregator_addr: Option<SocketAddr>,
+    /// The aggregator tag of this node, see aggregator_tag in the NCConfiguration.
+    aggregator_tag: Option<String>,
+    /// The aggregator this node sends its results to.
+    aggregator_id: Option<NodeID>,
+    /// The chunks of other nodes that this aggregator has taken over and not sent to the server yet.
+    delegated_chunks: Vec<ChunkID>,
+    /// The groups this node belongs to, see node_tags in the NCConfiguration.
+    tags: Vec<String>,
+    /// The clock skew that the node has measured right after the registration.
+    clock_skew: Option<NCClockSkew>,
+    /// How often a new process has taken over this node id, see NCNodeMessage::Rejoin. 0 for a node that has registered normally.
+    incarnation: u32,
 }
 
+#[cfg(feature = "net")]
 impl<U> NCNodeInfo<U> {
-    /// Create a new node info with the given node id and the current time stamp.
+    /// Create a new node info with the given node id.
     fn new(node_id: NodeID) -> Self {
         NCNodeInfo {
             node_id,
-            instant: Instant::now(),
             message_queue: VecDeque::new(),
+            disabled: false,
+            job_errors: VecDeque::new(),
+            codec: NCCodec::None,
+            current_chunk: None,
+            prefetched_chunk: None,
+            build: None,
+            aggregator_addr: None,
+            aggregator_tag: None,
+            aggregator_id: None,
+            delegated_chunks: Vec::new(),
+            tags: Vec::new(),
+            clock_skew: None,
+            incarnation: 0,
         }
     }
 
-    /// When a node sends a valid heartbeat update the time stamp for that node.
-    fn update_heartbeat(&mut self) {
-        self.instant = Instant::now();
-    }
-
-    /// Check if the heartbeat for the node is invalid using the given time range.
-    fn heartbeat_invalid(&self, limit: u64) -> bool {
-        let diff = Instant::now() - self.instant;
-        diff.as_secs() > limit
-    }
-
     /// Add a new message to the message queue
     fn add_message(&mut self, message: U) {
         self.message_queue.push_back(message);
@@ -79,24 +167,183 @@ impl<U> NCNodeInfo<U> {
     }
 }
 
+/// The time stamps of the last heartbeat of all nodes. The time stamps are also kept in a min heap, so that a check only touches
+/// the nodes whose heartbeat is overdue instead of scanning all the nodes. A new heartbeat leaves the old entry in the heap,
+/// it's dropped when it comes up.
+#[cfg(feature = "net")]
+#[derive(Debug, Default)]
+pub(crate) struct NCHeartbeats {
+    /// The last heartbeat of every node.
+    latest: HashMap<NodeID, Instant>,
+    /// The heartbeats ordered by time, the oldest one comes first.
+    queue: BinaryHeap<Reverse<(Instant, NodeID)>>,
+    /// The nodes that have missed their heartbeat, they are reported by every check until they send a heartbeat again.
+    expired: BTreeSet<NodeID>,
+    /// The resources that the nodes have sent with their last heartbeat.
+    resources: HashMap<NodeID, NCResourceReport>,
+}
+
+#[cfg(feature = "net")]
+impl NCHeartbeats {
+    /// Adds the node with a heartbeat at the given time, this also counts as a heartbeat for a node that is already there.
+    fn insert(&mut self, node_id: NodeID, now: Instant) {
+        self.latest.insert(node_id, now);
+        self.queue.push(Reverse((now, node_id)));
+        self.expired.remove(&node_id);
+    }
+
+    /// A heartbeat from the given node at the given time, nodes that are not there are ignored.
+    /// Returns true if the node had missed its heartbeat before (see expire()).
+    fn update(&mut self, node_id: NodeID, now: Instant) -> bool {
+        let expired = self.expired.contains(&node_id);
+
+        if self.latest.contains_key(&node_id) {
+            self.insert(node_id, now);
+        }
+
+        expired
+    }
+
+    /// Removes the node, its entries in the heap are dropped later.
+    fn remove(&mut self, node_id: NodeID) {
+        self.latest.remove(&node_id);
+        self.expired.remove(&node_id);
+        self.resources.remove(&node_id);
+    }
+
+    /// Returns the time since the last heartbeat of the given node.
+    fn elapsed(&self, node_id: NodeID, now: Instant) -> Option<Duration> {
+        self.latest.get(&node_id).map(|instant| now.saturating_duration_since(*instant))
+    }
+
+    /// Returns true if the heartbeat of the given node is overdue (see heartbeat_limit()).
+    fn is_expired(&self, node_id: NodeID, now: Instant, heartbeat: u64) -> bool {
+        self.elapsed(node_id, now).is_none_or(|elapsed| elapsed >= heartbeat_limit(heartbeat))
+    }
+
+    /// Returns all the nodes whose heartbeat is overdue at the given time (see heartbeat_limit()).
+    /// Only the entries in the heap that are overdue are taken out, the others are not touched.
+    fn expire(&mut self, now: Instant, heartbeat: u64) -> Vec<NodeID> {
+        let limit = heartbeat_limit(heartbeat);
+
+        while let Some(Reverse((instant, node_id))) = self.queue.peek().copied() {
+            if now.saturating_duration_since(instant) < limit {
+                break
+            }
+
+            self.queue.pop();
+
+            // Otherwise the node has sent another heartbeat since then or it's gone
+            if self.latest.get(&node_id) == Some(&instant) {
+                self.expired.insert(node_id);
+            }
+        }
+
+        self.expired.iter().copied().collect()
+    }
+}
+
+/// The heartbeats of all the nodes, split up by node id into HEARTBEAT_SHARDS parts with their own lock, see the module documentation.
+/// The node list and the server share the table.
+#[cfg(feature = "net")]
+#[derive(Debug)]
+pub(crate) struct NCHeartbeatTable {
+    shards: Vec<Mutex<NCHeartbeats>>,
+}
+
+#[cfg(feature = "net")]
+impl NCHeartbeatTable {
+    /// Creates a new empty table.
+    pub(crate) fn new() -> Self {
+        NCHeartbeatTable { shards: (0..HEARTBEAT_SHARDS).map(|_| Mutex::new(NCHeartbeats::default())).collect() }
+    }
+
+    /// Locks the shard of the given node. A thread that has panicked while it held the lock can at most have left an outdated entry
+    /// in the heap, which expire() drops anyway, so a poisoned shard is used as it is.
+    fn shard(&self, node_id: NodeID) -> MutexGuard<'_, NCHeartbeats> {
+        // Node ids are random numbers, so the lower bits are good enough as a hash
+        self.shards[(node_id.0 % HEARTBEAT_SHARDS as u64) as usize].lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Adds the node with a heartbeat right now.
+    fn insert(&self, node_id: NodeID) {
+        self.shard(node_id).insert(node_id, Instant::now())
+    }
+
+    /// Removes the node with its resources.
+    fn remove(&self, node_id: NodeID) {
+        self.shard(node_id).remove(node_id)
+    }
+
+    /// A heartbeat from the given node right now, nodes that are not registered are ignored.
+    /// This happens when the heartbeat thread in the [`nc_node`](crate::nc_node) module
+    /// has send the [`NCNodeMessage::HeartBeat`](crate::nc_node::NCNodeMessage) message to the server.
+    /// Returns true if the node had missed its heartbeat before, so it has been declared offline.
+    pub(crate) fn update(&self, node_id: NodeID) -> bool {
+        self.shard(node_id).update(node_id, Instant::now())
+    }
+
+    /// All the registered nodes whose heartbeat is overdue are returned here, sorted by node id (see heartbeat_limit() and heartbeat in
+    /// [`NCConfiguration`](crate::nc_config::NCConfiguration)). Then the NCServer trait method
+    /// [`heartbeat_timeout()`](crate::nc_server::NCServer::heartbeat_timeout) is called where the node should be marked as offline.
+    /// A node is returned by every check until it sends a heartbeat again.
+    pub(crate) fn check(&self, heartbeat: u64) -> Vec<NodeID> {
+        let now = Instant::now();
+        let mut expired: Vec<NodeID> = self.shards.iter()
+            .flat_map(|shard| shard.lock().unwrap_or_else(PoisonError::into_inner).expire(now, heartbeat))
+            .collect();
+
+        expired.sort_unstable();
+        expired
+    }
+
+    /// Returns the time since the last heartbeat of the given node.
+    fn elapsed(&self, node_id: NodeID) -> Option<Duration> {
+        self.shard(node_id).elapsed(node_id, Instant::now())
+    }
+
+    /// Returns true if the heartbeat of the given node is overdue (see heartbeat_limit()).
+    fn is_expired(&self, node_id: NodeID, heartbeat: u64) -> bool {
+        self.shard(node_id).is_expired(node_id, Instant::now(), heartbeat)
+    }
+
+    /// Set the resources that the given node has sent with its heartbeat, nodes that are not registered are ignored.
+    pub(crate) fn set_resources(&self, resources: NCResourceReport, node_id: NodeID) {
+        let mut shard = self.shard(node_id);
+
+        if shard.latest.contains_key(&node_id) {
+            shard.resources.insert(node_id, resources);
+        }
+    }
+
+    /// Get the latest resources of the given node, [`None`] if the node hasn't sent any or is unknown.
+    pub(crate) fn get_resources(&self, node_id: NodeID) -> Option<NCResourceReport> {
+        self.shard(node_id).resources.get(&node_id).cloned()
+    }
+}
+
+#[cfg(feature = "net")]
 pub(crate) struct NCNodeList<U> {
     // TODO: Maybe use a hashmap instead of a vec ?
     /// List of all nodes that have been registered.
     nodes: Vec<NCNodeInfo<U>>,
+    /// The nodes of every group, this is kept in sync with the tags of the nodes.
+    groups: BTreeMap<String, Vec<NodeID>>,
+    /// The heartbeats of all the nodes in the list, shared with the server.
+    heartbeats: Arc<NCHeartbeatTable>,
 }
 
+#[cfg(feature = "net")]
 impl<U: Clone> NCNodeList<U> {
     /// Creates a new empty node list
     pub(crate) fn new() -> Self {
-        NCNodeList { nodes: Vec::new() }
+        NCNodeList { nodes: Vec::new(), groups: BTreeMap::new(), heartbeats: Arc::new(NCHeartbeatTable::new()) }
     }
 
-    /// All the registered nodes are checked here. If the heartbeat time stamp
-    /// is too old (> 2 * heartbeat in [`NCConfiguration`](crate::nc_config::NCConfiguration)) then
-    /// the NCServer trait method [`heartbeat_timeout()`](crate::nc_server::NCServer::heartbeat_timeout)
-    /// is called where the node should be marked as offline.
-    pub(crate) fn check_heartbeat(&self, heartbeat_duration: u64) -> impl Iterator<Item=NodeID> + '_ {
-        self.nodes.iter().filter(move |node| node.heartbeat_invalid(heartbeat_duration)).map(|node| node.node_id)
+    /// The heartbeats of the nodes in the list. A node is added to the table when it's registered and removed with remove_node(),
+    /// the heartbeats themselves and the resources of the nodes go directly to the table.
+    pub(crate) fn heartbeats(&self) -> Arc<NCHeartbeatTable> {
+        self.heartbeats.clone()
     }
 
     /// This method generates a new and unique node id for a new node that has just registered with the server.
@@ -117,20 +364,56 @@ impl<U: Clone> NCNodeList<U> {
         }
 
         self.nodes.push(NCNodeInfo::new(new_id));
+        self.heartbeats.insert(new_id);
 
         new_id
     }
 
-    /// Update the heartbeat timestamp for the given node.
-    /// This happens when the heartbeat thread in the [`nc_node`](crate::nc_node) module
-    /// has send the [`NCNodeMessage::HeartBeat`](crate::nc_node::NCNodeMessage) message to the server.
-    pub(crate) fn update_heartbeat(&mut self, node_id: NodeID) {
-        for node in self.nodes.iter_mut() {
-            if node.node_id == node_id {
-                node.update_heartbeat();
-                break
-            }
+    /// Registers a node with the given id again, for example a node from before a restart of the server.
+    /// Returns false if the node is already registered.
+    pub(crate) fn register_node(&mut self, node_id: NodeID) -> bool {
+        if self.contains(node_id) {
+            return false
+        }
+
+        self.nodes.push(NCNodeInfo::new(node_id));
+        self.heartbeats.insert(node_id);
+        true
+    }
+
+    /// Returns the incarnation of the given node, [`None`] if the node is unknown.
+    pub(crate) fn incarnation(&self, node_id: NodeID) -> Option<u32> {
+        self.nodes.iter().find(|node| node.node_id == node_id).map(|node| node.incarnation)
+    }
+
+    /// A new process has taken over the given node id with the given incarnation (see NCNodeMessage::Rejoin). An unknown node is registered,
+    /// for a known node everything that the old process has left behind is dropped: its chunks, the errors for it, its groups and its clock skew.
+    /// The chunks must have been given to other nodes before. Whether the node is disabled depends on the node id, so that stays.
+    pub(crate) fn rejoin_node(&mut self, incarnation: u32, node_id: NodeID) {
+        self.register_node(node_id);
+        self.set_tags(Vec::new(), node_id);
+
+        if let Some(node) = self.nodes.iter_mut().find(|node| node.node_id == node_id) {
+            node.incarnation = incarnation;
+            node.current_chunk = None;
+            node.prefetched_chunk = None;
+            node.delegated_chunks.clear();
+            node.job_errors.clear();
+            node.clock_skew = None;
         }
+
+        // The old process may have been declared offline already
+        self.heartbeats.insert(node_id);
+    }
+
+    /// Returns true if the node with the given id is registered.
+    pub(crate) fn contains(&self, node_id: NodeID) -> bool {
+        self.nodes.iter().any(|node| node.node_id == node_id)
+    }
+
+    /// Returns the ids of all registered nodes.
+    pub(crate) fn node_ids(&self) -> impl Iterator<Item = NodeID> + '_ {
+        self.nodes.iter().map(|node| node.node_id)
     }
 
     /// Return the number of nodes that have registered since the start of the server.
@@ -143,7 +426,7 @@ impl<U: Clone> NCNodeList<U> {
     /// Vec<(NodeID, f64)>
     pub(crate) fn get_time_stamps(& self) -> Vec<(NodeID, f64)> {
         self.nodes.iter().map(|node_info| {
-            (node_info.node_id, node_info.instant.elapsed().as_secs_f64())
+            (node_info.node_id, self.heartbeats.elapsed(node_info.node_id).unwrap_or_default().as_secs_f64())
         }).collect()
     }
 
@@ -172,19 +455,321 @@ impl<U: Clone> NCNodeList<U> {
             }
         }
 
-        return None
+        None
+    }
+
+    /// Add a new message for all nodes of the given group and return the number of these nodes.
+    pub(crate) fn add_message_group(&mut self, message: U, tag: &str) -> usize {
+        let members = match self.groups.get(tag) {
+            Some(members) => members,
+            None => return 0
+        };
+
+        for node in self.nodes.iter_mut().filter(|node| members.contains(&node.node_id)) {
+            node.add_message(message.clone());
+        }
+
+        members.len()
+    }
+
+    /// Sets the groups of the given node, it leaves all the groups that are not in tags. Empty and duplicate tags are ignored.
+    pub(crate) fn set_tags(&mut self, mut tags: Vec<String>, node_id: NodeID) {
+        let node = match self.nodes.iter_mut().find(|node| node.node_id == node_id) {
+            Some(node) => node,
+            None => return
+        };
+
+        tags.retain(|tag| !tag.is_empty());
+        tags.sort();
+        tags.dedup();
+        let old_tags = std::mem::replace(&mut node.tags, tags.clone());
+
+        self.leave_groups(&old_tags, node_id);
+
+        for tag in tags {
+            self.groups.entry(tag).or_default().push(node_id);
+        }
+    }
+
+    /// Returns the build and the groups of the given node for the context of a call into the user code, [`None`] for an unknown node.
+    pub(crate) fn context_info(&self, node_id: NodeID) -> Option<NCContextNodeInfo> {
+        self.nodes.iter().find(|node| node.node_id == node_id).map(|node| NCContextNodeInfo { build: node.build.clone(), tags: node.tags.clone() })
+    }
+
+    /// Returns the groups of the given node.
+    pub(crate) fn get_tags(&self, node_id: NodeID) -> Vec<String> {
+        self.nodes.iter().find(|node| node.node_id == node_id).map(|node| node.tags.clone()).unwrap_or_default()
+    }
+
+    /// Returns every group and its number of nodes, sorted by tag. Like len() this includes inactive nodes.
+    pub(crate) fn group_sizes(&self) -> Vec<(String, u64)> {
+        self.groups.iter().map(|(tag, members)| (tag.clone(), members.len() as u64)).collect()
+    }
+
+    /// Removes the given node from the index of the given groups, empty groups are removed.
+    fn leave_groups(&mut self, tags: &[String], node_id: NodeID) {
+        for tag in tags {
+            if let Some(members) = self.groups.get_mut(tag) {
+                members.retain(|member| *member != node_id);
+
+                if members.is_empty() {
+                    self.groups.remove(tag);
+                }
+            }
+        }
+    }
+
+    /// Disable the given node, it will not get any more data.
+    /// Returns false if the node is unknown.
+    pub(crate) fn disable_node(&mut self, node_id: NodeID) -> bool {
+        match self.nodes.iter_mut().find(|node| node.node_id == node_id) {
+            Some(node) => {
+                node.disabled = true;
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Add a new error for the given node, it will be sent the next time the node needs data.
+    pub(crate) fn add_job_error(&mut self, job_error: NCJobError, node_id: NodeID) {
+        if let Some(node) = self.nodes.iter_mut().find(|node| node.node_id == node_id) {
+            node.job_errors.push_back(job_error);
+        }
+    }
+
+    /// Get first error for the given node id, if any.
+    pub(crate) fn get_job_error(&mut self, node_id: NodeID) -> Option<NCJobError> {
+        self.nodes.iter_mut().find(|node| node.node_id == node_id).and_then(|node| node.job_errors.pop_front())
+    }
+
+    /// Set the codec that has been negotiated with the given node.
+    pub(crate) fn set_codec(&mut self, codec: NCCodec, node_id: NodeID) {
+        if let Some(node) = self.nodes.iter_mut().find(|node| node.node_id == node_id) {
+            node.codec = codec;
+        }
+    }
+
+    /// Get the codec for the given node, NCCodec::None if the node is unknown.
+    pub(crate) fn get_codec(&self, node_id: NodeID) -> NCCodec {
+        self.nodes.iter().find(|node| node.node_id == node_id).map_or(NCCodec::None, |node| node.codec)
+    }
+
+    /// Set the clock skew that has been measured for the given node.
+    pub(crate) fn set_clock_skew(&mut self, clock_skew: NCClockSkew, node_id: NodeID) {
+        if let Some(node) = self.nodes.iter_mut().find(|node| node.node_id == node_id) {
+            node.clock_skew = Some(clock_skew);
+        }
+    }
+
+    /// All the nodes and their clock skew, the ones without measurement are left out.
+    pub(crate) fn clock_skews(&self) -> Vec<(NodeID, NCClockSkew)> {
+        self.nodes.iter().filter_map(|node| node.clock_skew.map(|clock_skew| (node.node_id, clock_skew))).collect()
+    }
+
+    /// Set the build of the node binary that the given node has sent during registration.
+    pub(crate) fn set_build(&mut self, build: Option<String>, node_id: NodeID) {
+        if let Some(node) = self.nodes.iter_mut().find(|node| node.node_id == node_id) {
+            node.build = build;
+        }
+    }
+
+    /// Returns true if the given node runs a different build than the required one.
+    /// Nodes that don't send a build are outdated too. Unknown nodes are never outdated.
+    pub(crate) fn is_outdated(&self, required_build: &str, node_id: NodeID) -> bool {
+        self.nodes.iter().any(|node| node.node_id == node_id && node.build.as_deref() != Some(required_build))
+    }
+
+    /// Set the chunk that has just been sent to the given node. A chunk that the node may have fetched in advance is forgotten,
+    /// since the node only asks this way when it doesn't hold any chunk.
+    pub(crate) fn set_current_chunk(&mut self, chunk_id: ChunkID, node_id: NodeID) {
+        if let Some(node) = self.nodes.iter_mut().find(|node| node.node_id == node_id) {
+            node.current_chunk = Some((chunk_id, Instant::now()));
+            node.prefetched_chunk = None;
+        }
+    }
+
+    /// Set the chunk that has just been sent in advance to the given node while it's still processing its current chunk.
+    /// If the node doesn't have a current chunk anymore (its result has already arrived) the chunk becomes the current chunk.
+    pub(crate) fn set_prefetched_chunk(&mut self, chunk_id: ChunkID, node_id: NodeID) {
+        if let Some(node) = self.nodes.iter_mut().find(|node| node.node_id == node_id) {
+            if node.current_chunk.is_some() {
+                node.prefetched_chunk = Some((chunk_id, Instant::now()));
+            } else {
+                node.current_chunk = Some((chunk_id, Instant::now()));
+            }
+        }
+    }
+
+    /// Returns the chunk that has been sent to the given node last together with the time since it has been sent
+    /// and removes it, if any.
+    /// The chunk that the node has fetched in advance becomes the current chunk, its time starts now since the node
+    /// starts processing it now.
+    pub(crate) fn take_current_chunk(&mut self, node_id: NodeID) -> Option<(ChunkID, Duration)> {
+        self.nodes.iter_mut().find(|node| node.node_id == node_id)
+            .and_then(|node| {
+                let current_chunk = node.current_chunk.take();
+                node.current_chunk = node.prefetched_chunk.take().map(|(chunk_id, _)| (chunk_id, Instant::now()));
+                current_chunk
+            })
+            .map(|(chunk_id, instant)| (chunk_id, instant.elapsed()))
+    }
+
+    /// Removes the given chunk from the chunks of the given node, returns false if the node doesn't have it (anymore).
+    pub(crate) fn release_chunk(&mut self, chunk_id: ChunkID, node_id: NodeID) -> bool {
+        match self.nodes.iter_mut().find(|node| node.node_id == node_id) {
+            Some(node) if node.prefetched_chunk.is_some_and(|(other, _)| other == chunk_id) => {
+                node.prefetched_chunk = None;
+                true
+            }
+            Some(node) if node.current_chunk.is_some_and(|(other, _)| other == chunk_id) => {
+                node.current_chunk = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Removes the chunk that the given node has fetched in advance, if any.
+    pub(crate) fn take_prefetched_chunk(&mut self, node_id: NodeID) -> Option<ChunkID> {
+        self.nodes.iter_mut().find(|node| node.node_id == node_id)
+            .and_then(|node| node.prefetched_chunk.take())
+            .map(|(chunk_id, _)| chunk_id)
+    }
+
+    /// Returns true if the given node has a chunk for which no result has arrived yet.
+    pub(crate) fn holds_chunk(&self, node_id: NodeID) -> bool {
+        self.nodes.iter().any(|node| node.node_id == node_id && node.current_chunk.is_some())
+    }
+
+    /// Returns the number of chunks that have been sent to the nodes (or delegated to an aggregator) and have no result yet.
+    pub(crate) fn chunks_in_flight(&self) -> usize {
+        self.nodes.iter().map(|node| node.current_chunk.iter().count() + node.prefetched_chunk.iter().count() + node.delegated_chunks.len()).sum()
+    }
+
+    /// Set the aggregator address and tag that the given node has sent during registration.
+    pub(crate) fn set_aggregator(&mut self, aggregator_addr: Option<SocketAddr>, aggregator_tag: Option<String>, node_id: NodeID) {
+        if let Some(node) = self.nodes.iter_mut().find(|node| node.node_id == node_id) {
+            node.aggregator_addr = aggregator_addr;
+            node.aggregator_tag = aggregator_tag;
+        }
+    }
+
+    /// Returns the address of the aggregator the given node should send its result to, [`None`] if there is no aggregator
+    /// with the same tag or if the node is an aggregator itself.
+    /// A node keeps its aggregator as long as that one is not disabled and its heartbeat is valid (using the given heartbeat, see heartbeat_limit()),
+    /// otherwise it gets the aggregator with the fewest nodes.
+    pub(crate) fn aggregator_for(&mut self, node_id: NodeID, heartbeat: u64) -> Option<SocketAddr> {
+        let node = self.nodes.iter().find(|node| node.node_id == node_id)?;
+
+        if node.aggregator_addr.is_some() {
+            return None
+        }
+
+        let tag = node.aggregator_tag.clone();
+        let current = node.aggregator_id;
+        let heartbeats = &self.heartbeats;
+        let available = |other: &NCNodeInfo<U>| other.aggregator_addr.is_some() && other.aggregator_tag == tag &&
+            !other.disabled && !heartbeats.is_expired(other.node_id, heartbeat);
+
+        let aggregator = match self.nodes.iter().find(|other| Some(other.node_id) == current && available(other)) {
+            Some(aggregator) => aggregator,
+            None => self.nodes.iter().filter(|other| available(other))
+                .min_by_key(|other| self.nodes.iter().filter(|worker| worker.aggregator_id == Some(other.node_id)).count())?
+        };
+
+        let (aggregator_id, aggregator_addr) = (aggregator.node_id, aggregator.aggregator_addr);
+
+        if let Some(node) = self.nodes.iter_mut().find(|node| node.node_id == node_id) {
+            node.aggregator_id = Some(aggregator_id);
+        }
+
+        aggregator_addr
+    }
+
+    /// Returns the chunk that has been sent to the given node last and has no result yet.
+    pub(crate) fn current_chunk(&self, node_id: NodeID) -> Option<ChunkID> {
+        self.nodes.iter().find(|node| node.node_id == node_id).and_then(|node| node.current_chunk.map(|(chunk_id, _)| chunk_id))
+    }
+
+    /// Returns the node that holds the given chunk as its current chunk or the one it has fetched in advance, if any.
+    pub(crate) fn node_with_chunk(&self, chunk_id: ChunkID) -> Option<NodeID> {
+        self.nodes.iter()
+            .find(|node| node.current_chunk.is_some_and(|(other, _)| other == chunk_id) || node.prefetched_chunk.is_some_and(|(other, _)| other == chunk_id))
+            .map(|node| node.node_id)
+    }
+
+    /// Returns the current chunk of the given node and the one it has fetched in advance.
+    pub(crate) fn chunks_of_node(&self, node_id: NodeID) -> Vec<ChunkID> {
+        self.nodes.iter().find(|node| node.node_id == node_id)
+            .map(|node| node.current_chunk.iter().chain(node.prefetched_chunk.iter()).map(|(chunk_id, _)| *chunk_id).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns true if the given chunk has been sent to the given node last and no result has arrived yet.
+    pub(crate) fn has_current_chunk(&self, chunk_id: ChunkID, node_id: NodeID) -> bool {
+        self.nodes.iter().any(|node| node.node_id == node_id && node.current_chunk.is_some_and(|(other, _)| other == chunk_id))
+    }
+
+    /// The aggregator (to) takes over the current chunk of the node (from).
+    /// Returns the time since the chunk has been sent to the node or [`None`] if the node doesn't have this chunk or
+    /// the aggregator is unknown.
+    pub(crate) fn delegate_chunk(&mut self, chunk_id: ChunkID, from: NodeID, to: NodeID) -> Option<Duration> {
+        if !self.has_current_chunk(chunk_id, from) || !self.nodes.iter().any(|node| node.node_id == to) {
+            return None
+        }
+
+        let (_, chunk_time) = self.take_current_chunk(from)?;
+
+        if let Some(aggregator) = self.nodes.iter_mut().find(|node| node.node_id == to) {
+            aggregator.delegated_chunks.push(chunk_id);
+        }
+
+        Some(chunk_time)
+    }
+
+    /// Removes the given chunks from the delegated chunks of the given aggregator and returns the ones that have been there.
+    pub(crate) fn remove_delegated_chunks(&mut self, chunks: &[ChunkID], node_id: NodeID) -> Vec<ChunkID> {
+        match self.nodes.iter_mut().find(|node| node.node_id == node_id) {
+            Some(node) => {
+                let (removed, kept) = node.delegated_chunks.iter().partition(|chunk_id| chunks.contains(chunk_id));
+                node.delegated_chunks = kept;
+                removed
+            }
+            None => Vec::new()
+        }
+    }
+
+    /// Returns all the delegated chunks of the given aggregator and removes them.
+    pub(crate) fn take_delegated_chunks(&mut self, node_id: NodeID) -> Vec<ChunkID> {
+        self.nodes.iter_mut().find(|node| node.node_id == node_id)
+            .map(|node| std::mem::take(&mut node.delegated_chunks))
+            .unwrap_or_default()
+    }
+
+    /// Returns true if the given aggregator has delegated chunks that have not been sent to the server yet.
+    pub(crate) fn has_delegated_chunks(&self, node_id: NodeID) -> bool {
+        self.nodes.iter().any(|node| node.node_id == node_id && !node.delegated_chunks.is_empty())
+    }
+
+    /// Returns true if the given node has been disabled.
+    pub(crate) fn is_disabled(&self, node_id: NodeID) -> bool {
+        self.nodes.iter().any(|node| node.node_id == node_id && node.disabled)
     }
 
     /// Remove a node from the current (old) server because it will
     /// migrate to a new server.
     pub(crate) fn remove_node(&mut self, node_id: NodeID) {
         let i = self.nodes.iter().position(|node| node.node_id == node_id).unwrap();
-        self.nodes.swap_remove(i);
+        let node = self.nodes.swap_remove(i);
+        self.leave_groups(&node.tags, node_id);
+        self.heartbeats.remove(node_id);
     }
 
     /// Migrate node to new server -> register a new node id.
     pub(crate) fn migrate_node(&mut self, node_id: NodeID) {
-        self.nodes.push(NCNodeInfo::new(node_id))
+        self.nodes.push(NCNodeInfo::new(node_id));
+        self.heartbeats.insert(node_id);
     }
 }
 
@@ -192,35 +777,87 @@ impl<U: Clone> NCNodeList<U> {
 mod tests {
     use super::*;
 
+    #[cfg(feature = "net")]
     use std::thread;
+    #[cfg(feature = "net")]
     use std::time::Duration;
 
+    #[cfg(feature = "net")]
     #[test]
     fn test_heartbeat_invalid() {
-        let node_info: NCNodeInfo<()> = NCNodeInfo::new(NodeID::unset());
+        let mut heartbeats = NCHeartbeats::default();
+        let start = Instant::now();
+        let node_id = NodeID::random();
+        heartbeats.insert(node_id, start);
 
-        thread::sleep(Duration::from_secs(3));
+        // The limit leaves room for the jitter of the node
+        assert_eq!(heartbeat_limit(5), Duration::from_secs(7));
+        assert!(!heartbeats.is_expired(node_id, start + Duration::from_secs(6), 5));
+        assert!(heartbeats.is_expired(node_id, start + Duration::from_secs(7), 5));
+        assert!(heartbeats.is_expired(NodeID::unset(), start, 5));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_update_heartbeat() {
+        let mut heartbeats = NCHeartbeats::default();
+        let start = Instant::now();
+        let node_id = NodeID::random();
+        heartbeats.insert(node_id, start);
 
-        assert!(!node_info.heartbeat_invalid(5));
+        assert_eq!(heartbeats.expire(start + Duration::from_secs(5), 3), vec![node_id]);
 
-        thread::sleep(Duration::from_secs(3));
+        heartbeats.update(node_id, start + Duration::from_secs(5));
 
-        assert!(node_info.heartbeat_invalid(5));
+        assert!(heartbeats.expire(start + Duration::from_secs(6), 3).is_empty());
+        assert!(!heartbeats.is_expired(node_id, start + Duration::from_secs(6), 3));
+
+        // Unknown nodes don't get a heartbeat
+        assert!(!heartbeats.update(NodeID::unset(), start));
+        assert!(heartbeats.is_expired(NodeID::unset(), start, 3));
     }
 
+    #[cfg(feature = "net")]
     #[test]
-    fn test_update_heartbeat() {
-        let mut node_info: NCNodeInfo<()> = NCNodeInfo::new(NodeID::unset());
+    fn test_heartbeat_sweep() {
+        let mut heartbeats = NCHeartbeats::default();
+        let start = Instant::now();
+        let node_ids = (0..10_000).map(|_| NodeID::random()).collect::<Vec<NodeID>>();
+        let (silent, active) = node_ids.split_at(5);
 
-        thread::sleep(Duration::from_secs(5));
+        for node_id in node_ids.iter() {
+            heartbeats.insert(*node_id, start);
+        }
 
-        assert!(node_info.heartbeat_invalid(3));
+        for node_id in active {
+            heartbeats.update(*node_id, start + Duration::from_secs(1));
+        }
 
-        node_info.update_heartbeat();
+        // Nothing is due yet, the sweep only looks at the oldest entry
+        assert!(heartbeats.expire(start + Duration::from_secs(12), 10).is_empty());
+        assert_eq!(heartbeats.queue.len(), 10_000 + active.len());
 
-        assert!(!node_info.heartbeat_invalid(3));
+        // The first heartbeats are due: only those are taken out, the silent nodes have expired
+        let mut expected = silent.to_vec();
+        expected.sort_unstable();
+        assert_eq!(heartbeats.expire(start + Duration::from_secs(13), 10), expected);
+        assert_eq!(heartbeats.queue.len(), active.len());
+
+        // The expired nodes are reported again but the heap is not touched, until a node is back
+        assert_eq!(heartbeats.expire(start + Duration::from_millis(13_500), 10), expected);
+        assert_eq!(heartbeats.queue.len(), active.len());
+
+        assert!(heartbeats.update(silent[0], start + Duration::from_millis(13_500)));
+        heartbeats.remove(silent[1]);
+        assert_eq!(heartbeats.expire(start + Duration::from_millis(13_600), 10).len(), 3);
+        assert_eq!(heartbeats.queue.len(), active.len() + 1);
+
+        // Now all the other nodes are overdue as well
+        assert_eq!(heartbeats.expire(start + Duration::from_secs(14), 10).len(), active.len() + 3);
+        assert_eq!(heartbeats.queue.len(), 1);
     }
 
+    #[cfg(feature = "net")]
     #[test]
     fn test_register_new_node() {
         let mut node_list: NCNodeList<()> = NCNodeList::new();
@@ -239,6 +876,7 @@ mod tests {
         assert_eq!(node_list.nodes[1].node_id, node);
     }
 
+    #[cfg(feature = "net")]
     #[test]
     fn test_node_list_check_heartbeat() {
         let mut node_list: NCNodeList<()> = NCNodeList::new();
@@ -248,19 +886,57 @@ mod tests {
         let _ = node_list.register_new_node();
         let _ = node_list.register_new_node();
 
-        let result = node_list.check_heartbeat(5);
-        let result = result.collect::<Vec<NodeID>>();
+        let result = node_list.heartbeats().check(5);
 
         assert_eq!(result.len(), 0);
 
         thread::sleep(Duration::from_secs(5));
 
-        let result = node_list.check_heartbeat(3);
-        let result = result.collect::<Vec<NodeID>>();
+        let result = node_list.heartbeats().check(3);
 
         assert_eq!(result.len(), 4);
     }
 
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_node_list_disable_node() {
+        let mut node_list: NCNodeList<()> = NCNodeList::new();
+
+        let node_id1 = node_list.register_new_node();
+        let node_id2 = node_list.register_new_node();
+
+        assert!(node_list.disable_node(node_id1));
+        assert!(node_list.is_disabled(node_id1));
+        assert!(!node_list.is_disabled(node_id2));
+        assert!(!node_list.disable_node(NodeID::unset()));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_node_list_job_error() {
+        let mut node_list: NCNodeList<()> = NCNodeList::new();
+
+        let node_id1 = node_list.register_new_node();
+        let node_id2 = node_list.register_new_node();
+
+        node_list.add_job_error(NCJobError::new(1, "first", true), node_id1);
+        node_list.add_job_error(NCJobError::new(2, "second", false), node_id1);
+
+        assert_eq!(node_list.get_job_error(node_id2), None);
+        assert_eq!(node_list.get_job_error(node_id1), Some(NCJobError::new(1, "first", true)));
+        assert_eq!(node_list.get_job_error(node_id1), Some(NCJobError::new(2, "second", false)));
+        assert_eq!(node_list.get_job_error(node_id1), None);
+    }
+
+    #[test]
+    fn test_node_id_from_str() {
+        let node_id = NodeID::random();
+
+        assert_eq!(node_id.to_string().parse::<NodeID>().unwrap(), node_id);
+        assert!("abc".parse::<NodeID>().is_err());
+    }
+
+    #[cfg(feature = "net")]
     #[test]
     fn test_node_list_update_heartbeat() {
         let mut node_list: NCNodeList<()> = NCNodeList::new();
@@ -272,10 +948,9 @@ mod tests {
 
         thread::sleep(Duration::from_secs(5));
 
-        node_list.update_heartbeat(node_id);
+        node_list.heartbeats().update(node_id);
 
-        let result = node_list.check_heartbeat(3);
-        let result = result.collect::<Vec<NodeID>>();
+        let result = node_list.heartbeats().check(3);
 
         assert_eq!(result.len(), 3);
 
@@ -283,4 +958,220 @@ mod tests {
             assert_ne!(other_id, node_id);
         }
     }
+
+    #[cfg(feature = "net")]
+    fn aggregator_addr(port: u16) -> Option<SocketAddr> {
+        Some(SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_node_list_aggregator_for() {
+        let mut node_list: NCNodeList<()> = NCNodeList::new();
+
+        let worker1 = node_list.register_new_node();
+        let worker2 = node_list.register_new_node();
+        let worker3 = node_list.register_new_node();
+        assert_eq!(node_list.aggregator_for(worker1, 5), None);
+
+        let aggregator1 = node_list.register_new_node();
+        let aggregator2 = node_list.register_new_node();
+        let aggregator3 = node_list.register_new_node();
+        node_list.set_aggregator(aggregator_addr(1), None, aggregator1);
+        node_list.set_aggregator(aggregator_addr(2), None, aggregator2);
+        node_list.set_aggregator(aggregator_addr(3), Some("other".to_string()), aggregator3);
+        node_list.set_aggregator(None, Some("other".to_string()), worker3);
+
+        // The nodes are spread over the aggregators and keep them
+        let addr1 = node_list.aggregator_for(worker1, 5);
+        let addr2 = node_list.aggregator_for(worker2, 5);
+        assert!(addr1.is_some() && addr2.is_some());
+        assert_ne!(addr1, addr2);
+        assert_eq!(node_list.aggregator_for(worker1, 5), addr1);
+
+        // Same tag only, aggregators send their results to the server
+        assert_eq!(node_list.aggregator_for(worker3, 5), aggregator_addr(3));
+        assert_eq!(node_list.aggregator_for(aggregator1, 5), None);
+
+        // Disabled aggregators are not used anymore
+        let disabled = if addr1 == aggregator_addr(1) { aggregator1 } else { aggregator2 };
+        assert!(node_list.disable_node(disabled));
+        assert_eq!(node_list.aggregator_for(worker1, 5), addr2);
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_node_list_delegate_chunk() {
+        let mut node_list: NCNodeList<()> = NCNodeList::new();
+
+        let worker = node_list.register_new_node();
+        let aggregator = node_list.register_new_node();
+
+        node_list.set_current_chunk(7, worker);
+        assert!(node_list.has_current_chunk(7, worker));
+        assert_eq!(node_list.delegate_chunk(8, worker, aggregator), None);
+        assert_eq!(node_list.delegate_chunk(7, worker, NodeID::unset()), None);
+        assert!(node_list.delegate_chunk(7, worker, aggregator).is_some());
+        assert!(!node_list.has_current_chunk(7, worker));
+        assert!(node_list.has_delegated_chunks(aggregator));
+
+        node_list.set_current_chunk(9, worker);
+        assert!(node_list.delegate_chunk(9, worker, aggregator).is_some());
+        assert_eq!(node_list.remove_delegated_chunks(&[9, 10], aggregator), vec![9]);
+        assert_eq!(node_list.take_delegated_chunks(aggregator), vec![7]);
+        assert!(!node_list.has_delegated_chunks(aggregator));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_node_list_prefetched_chunk() {
+        let mut node_list: NCNodeList<()> = NCNodeList::new();
+
+        let node_id = node_list.register_new_node();
+        assert!(!node_list.holds_chunk(node_id));
+
+        // Without a current chunk the prefetched chunk is the current one
+        node_list.set_prefetched_chunk(1, node_id);
+        assert!(node_list.has_current_chunk(1, node_id));
+
+        // The results arrive in the order the chunks have been sent
+        node_list.set_prefetched_chunk(2, node_id);
+        assert!(node_list.holds_chunk(node_id));
+        assert_eq!(node_list.take_current_chunk(node_id).map(|(chunk_id, _)| chunk_id), Some(1));
+        assert!(node_list.has_current_chunk(2, node_id));
+        assert_eq!(node_list.take_current_chunk(node_id).map(|(chunk_id, _)| chunk_id), Some(2));
+        assert!(!node_list.holds_chunk(node_id));
+
+        // A normal request means the node doesn't hold any chunk anymore
+        node_list.set_current_chunk(3, node_id);
+        node_list.set_prefetched_chunk(4, node_id);
+        node_list.set_current_chunk(5, node_id);
+        assert_eq!(node_list.take_current_chunk(node_id).map(|(chunk_id, _)| chunk_id), Some(5));
+        assert_eq!(node_list.take_current_chunk(node_id), None);
+
+        // Released chunks are gone, unknown chunks can't be released
+        node_list.set_current_chunk(6, node_id);
+        node_list.set_prefetched_chunk(7, node_id);
+        assert!(!node_list.release_chunk(8, node_id));
+        assert!(node_list.release_chunk(7, node_id));
+        assert!(!node_list.release_chunk(7, node_id));
+        assert_eq!(node_list.take_prefetched_chunk(node_id), None);
+        assert!(node_list.has_current_chunk(6, node_id));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_node_list_resources() {
+        let mut node_list: NCNodeList<()> = NCNodeList::new();
+
+        let node_id1 = node_list.register_new_node();
+        let node_id2 = node_list.register_new_node();
+        let resources = NCResourceReport { free_mem_bytes: Some(1000), free_disk_bytes: None, load_avg: Some(0.5) };
+
+        let heartbeats = node_list.heartbeats();
+
+        let unknown = NodeID::random();
+
+        heartbeats.set_resources(resources.clone(), node_id1);
+        heartbeats.set_resources(resources.clone(), unknown);
+
+        assert_eq!(heartbeats.get_resources(node_id1), Some(resources));
+        assert_eq!(heartbeats.get_resources(node_id2), None);
+        assert_eq!(heartbeats.get_resources(unknown), None);
+
+        // The resources are gone with the node
+        node_list.remove_node(node_id1);
+        assert_eq!(heartbeats.get_resources(node_id1), None);
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_node_list_groups() {
+        let mut node_list: NCNodeList<u32> = NCNodeList::new();
+
+        let node_id1 = node_list.register_new_node();
+        let node_id2 = node_list.register_new_node();
+        let node_id3 = node_list.register_new_node();
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<String>>();
+
+        node_list.set_tags(tags(&["gpu", "cpu", "gpu", ""]), node_id1);
+        node_list.set_tags(tags(&["cpu"]), node_id2);
+        node_list.set_tags(tags(&["gpu"]), NodeID::random());
+
+        assert_eq!(node_list.get_tags(node_id1), tags(&["cpu", "gpu"]));
+        assert_eq!(node_list.group_sizes(), vec![("cpu".to_string(), 2), ("gpu".to_string(), 1)]);
+
+        assert_eq!(node_list.add_message_group(7, "gpu"), 1);
+        assert_eq!(node_list.add_message_group(8, "fpga"), 0);
+        assert_eq!(node_list.get_message(node_id1), Some(7));
+        assert_eq!(node_list.get_message(node_id1), None);
+        assert_eq!(node_list.get_message(node_id2), None);
+        assert_eq!(node_list.get_message(node_id3), None);
+
+        // Changing the tags moves the node to the new groups, empty groups are gone
+        node_list.set_tags(tags(&["fpga"]), node_id1);
+        assert_eq!(node_list.group_sizes(), vec![("cpu".to_string(), 1), ("fpga".to_string(), 1)]);
+        assert_eq!(node_list.add_message_group(9, "gpu"), 0);
+
+        node_list.remove_node(node_id2);
+        assert_eq!(node_list.group_sizes(), vec![("fpga".to_string(), 1)]);
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_node_list_clock_skew() {
+        let mut node_list: NCNodeList<()> = NCNodeList::new();
+
+        let behind = node_list.register_new_node();
+        let ahead = node_list.register_new_node();
+        let unknown = node_list.register_new_node();
+        let hour = 3_600_000_000;
+
+        node_list.set_clock_skew(NCClockSkew { offset_micros: hour, rtt_micros: 100 }, behind);
+        node_list.set_clock_skew(NCClockSkew { offset_micros: -hour, rtt_micros: 100 }, ahead);
+        assert_eq!(node_list.clock_skews().len(), 2);
+
+        assert!(node_list.clock_skews().iter().all(|(node_id, _)| *node_id != unknown));
+
+        // The heartbeats only use the clock of the server, the skew doesn't expire a node early or late
+        assert!(node_list.heartbeats().check(1).is_empty());
+        node_list.heartbeats().update(behind);
+        node_list.heartbeats().update(ahead);
+        thread::sleep(heartbeat_limit(1));
+        let mut expired = node_list.heartbeats().check(1);
+        expired.sort();
+        let mut all = vec![behind, ahead, unknown];
+        all.sort();
+        assert_eq!(expired, all);
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_node_list_rejoin() {
+        let mut node_list: NCNodeList<()> = NCNodeList::new();
+        let node_id = node_list.register_new_node();
+        assert_eq!(node_list.incarnation(node_id), Some(0));
+
+        node_list.set_current_chunk(3, node_id);
+        node_list.set_prefetched_chunk(4, node_id);
+        node_list.set_tags(vec!["gpu".to_string()], node_id);
+        node_list.add_job_error(NCJobError::new(1, "old process", true), node_id);
+        node_list.disable_node(node_id);
+
+        // The new process starts without the chunks and the errors of the old one
+        node_list.rejoin_node(1, node_id);
+        assert_eq!(node_list.incarnation(node_id), Some(1));
+        assert!(node_list.chunks_of_node(node_id).is_empty());
+        assert!(node_list.get_job_error(node_id).is_none());
+        assert!(node_list.group_sizes().is_empty());
+        assert!(node_list.is_disabled(node_id));
+        assert_eq!(node_list.len(), 1);
+
+        // An unknown node id is registered
+        let other = NodeID::random();
+        assert_eq!(node_list.incarnation(other), None);
+        node_list.rejoin_node(5, other);
+        assert_eq!(node_list.incarnation(other), Some(5));
+        assert_eq!(node_list.len(), 2);
+    }
 }
@@ -0,0 +1,413 @@
+//! This module contains the archives for nodes without network access (air-gapped nodes).
+//! The server writes a batch of chunks to a file with [`NCOfflineHandle::export_chunks()`], the file is carried to the node
+//! and processed there with [`NCNodeStarter::process_archive()`](crate::NCNodeStarter::process_archive), which writes the results
+//! to another file. The server reads that file with [`NCOfflineHandle::import_results()`] and handles the results like the ones from a regular node.
+//!
+//! Both files are encoded like the messages (same codec and encryption) and signed with HMAC-SHA256 using the key from the NCConfiguration,
+//! so server and node must have the same key. If the results of a batch don't arrive within offline_batch_timeout_days (see NCConfiguration)
+//! the remaining chunks are given to the regular nodes again and their late results are dropped.
+//! The batches that wait for results are part of the checkpoint (see [`NCCheckpoint`](crate::NCCheckpoint)): their chunks stay assigned
+//! when the chunk list is restored and the server takes the batches over from the checkpoint file when it's started again,
+//! so their results can still be imported after a restart.
+
+#[cfg(feature = "net")]
+use std::collections::HashMap;
+#[cfg(feature = "net")]
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+#[cfg(feature = "net")]
+use std::time::Instant;
+
+#[cfg(feature = "net")]
+use hmac::{Hmac, Mac};
+#[cfg(feature = "net")]
+use sha2::Sha256;
+use serde::{Serialize, Deserialize};
+#[cfg(feature = "net")]
+use serde::de::DeserializeOwned;
+use log::debug;
+
+use crate::nc_error::NCError;
+#[cfg(feature = "net")]
+use crate::nc_error::NCJobError;
+#[cfg(feature = "net")]
+use crate::nc_communicator::NCCommunicator;
+#[cfg(feature = "net")]
+use crate::nc_config::NCConfiguration;
+#[cfg(feature = "net")]
+use crate::nc_message::NCChunkInfo;
+use crate::nc_node_info::NodeID;
+use crate::array2d::ChunkID;
+#[cfg(feature = "net")]
+use crate::array2d::ChunkMeta;
+
+#[cfg(feature = "net")]
+type HmacSha256 = Hmac<Sha256>;
+
+/// Every archive starts with these bytes.
+#[cfg(feature = "net")]
+const ARCHIVE_MAGIC: &[u8; 8] = b"NCOFFLN1";
+
+/// Length of the HMAC-SHA256 at the end of every archive.
+#[cfg(feature = "net")]
+const MAC_LEN: usize = 32;
+
+/// What an archive contains, so that a chunk archive can't be imported as results by mistake.
+#[cfg(feature = "net")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum NCArchiveKind {
+    /// The chunks from the server, see [`NCChunkArchive`].
+    Chunks = 1,
+    /// The results from the node, see [`NCResultArchive`].
+    Results = 2,
+}
+
+/// The chunks of one offline batch together with the initial data for the node.
+#[cfg(feature = "net")]
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct NCChunkArchive<InitialDataT, NewDataT> {
+    /// Identifies the batch on the server.
+    pub(crate) batch_id: u64,
+    /// The node id that the server has used for the batch, it's given to set_initial_data().
+    pub(crate) node_id: NodeID,
+    /// The data from the NCServer trait method initial_data().
+    pub(crate) initial_data: Option<InitialDataT>,
+    /// The chunks in the order they have been assigned.
+    pub(crate) chunks: Vec<(NCChunkInfo, NewDataT)>,
+}
+
+/// What the node has done with one chunk of an offline batch.
+#[cfg(feature = "net")]
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum NCOfflineResult<ProcessedDataT> {
+    /// The processed data and the metadata of the chunk, see NodeResult::Data.
+    Data(Option<ChunkMeta>, ProcessedDataT),
+    /// See NodeResult::Empty.
+    Empty,
+    /// See NodeResult::Skip.
+    Skip(String),
+    /// The node could not process the chunk.
+    Failed(NCJobError),
+}
+
+/// The results of one offline batch.
+#[cfg(feature = "net")]
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct NCResultArchive<ProcessedDataT> {
+    /// The batch id from the chunk archive.
+    pub(crate) batch_id: u64,
+    /// The node id from the chunk archive.
+    pub(crate) node_id: NodeID,
+    /// One result for every chunk of the batch.
+    pub(crate) results: Vec<(ChunkID, NCOfflineResult<ProcessedDataT>)>,
+}
+
+/// Calculates the HMAC for the given part of the archive.
+#[cfg(feature = "net")]
+fn calc_mac(key: &str, data: &[u8]) -> Result<HmacSha256, NCError> {
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).map_err(|_| NCError::InvalidKey("the key can't be used for the HMAC".to_string()))?;
+    mac.update(data);
+    Ok(mac)
+}
+
+/// Encodes the data with the communicator, signs it with its current key and writes it to the given file.
+/// A temporary file is written first and then renamed, like the checkpoint.
+#[cfg(feature = "net")]
+pub(crate) fn write_archive<S: Serialize>(path: &Path, kind: NCArchiveKind, data: &S, nc_communicator: &mut NCCommunicator) -> Result<(), NCError> {
+    debug!("nc_offline::write_archive()");
+
+    let mut archive = ARCHIVE_MAGIC.to_vec();
+    archive.push(kind as u8);
+    archive.extend(nc_communicator.nc_encode_data(data)?);
+    let mac = calc_mac(&nc_communicator.current_key()?, &archive)?.finalize().into_bytes();
+    archive.extend_from_slice(&mac);
+
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, archive)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Reads the given file, checks the signature with the current key of the communicator and decodes the data.
+///
+/// # Errors
+///
+/// Returns a [`NCError::Archive`] error if the file is not an archive of the given kind or has been changed.
+#[cfg(feature = "net")]
+pub(crate) fn read_archive<D: DeserializeOwned>(path: &Path, kind: NCArchiveKind, nc_communicator: &NCCommunicator) -> Result<D, NCError> {
+    debug!("nc_offline::read_archive()");
+
+    let archive = fs::read(path)?;
+    let header_len = ARCHIVE_MAGIC.len() + 1;
+
+    if archive.len() < header_len + MAC_LEN || !archive.starts_with(ARCHIVE_MAGIC) {
+        return Err(NCError::Archive(format!("{} is not an offline archive", path.display())))
+    }
+
+    if archive[ARCHIVE_MAGIC.len()] != kind as u8 {
+        return Err(NCError::Archive(format!("{} doesn't contain {:?}", path.display(), kind)))
+    }
+
+    let (data, mac) = archive.split_at(archive.len() - MAC_LEN);
+    calc_mac(&nc_communicator.current_key()?, data)?.verify_slice(mac)
+        .map_err(|_| NCError::Archive(format!("wrong signature for {}, the file has been changed or another key has been used", path.display())))?;
+
+    nc_communicator.nc_decode_data(&data[header_len..])
+}
+
+/// One batch that has been exported and waits for its results.
+#[cfg(feature = "net")]
+#[derive(Debug)]
+struct NCOfflineBatch {
+    /// The node id that the server has used for assigning the chunks.
+    node_id: NodeID,
+    /// The chunks of the batch that have no result yet.
+    chunks: Vec<ChunkID>,
+    /// When the batch has been exported.
+    exported_at: Instant,
+}
+
+/// A batch that waits for its results, as it's stored in the checkpoint.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct NCSavedOfflineBatch {
+    pub(crate) batch_id: u64,
+    pub(crate) node_id: NodeID,
+    pub(crate) chunks: Vec<ChunkID>,
+    /// The time since the batch has been exported, when the checkpoint was written.
+    pub(crate) age: Duration,
+}
+
+/// Keeps track of the exported batches on the server.
+#[cfg(feature = "net")]
+#[derive(Debug)]
+pub(crate) struct NCOfflineBatches {
+    /// All the batches that have chunks without a result.
+    batches: HashMap<u64, NCOfflineBatch>,
+    /// The remaining chunks of a batch are given to other nodes after this time.
+    timeout: Duration,
+}
+
+#[cfg(feature = "net")]
+impl NCOfflineBatches {
+    /// Creates an empty list of batches with the timeout from the given configuration.
+    pub(crate) fn new(config: &NCConfiguration) -> Self {
+        NCOfflineBatches { batches: HashMap::new(), timeout: Duration::from_secs(config.offline_batch_timeout_days.saturating_mul(24 * 60 * 60)) }
+    }
+
+    /// Adds a new batch with the given chunks.
+    pub(crate) fn add(&mut self, batch_id: u64, node_id: NodeID, chunks: Vec<ChunkID>) {
+        debug!("NCOfflineBatches::add()");
+
+        self.batches.insert(batch_id, NCOfflineBatch { node_id, chunks, exported_at: Instant::now() });
+    }
+
+    /// Removes the given chunk from its batch and returns true if it was still waiting for its result.
+    /// A batch without any chunks left is removed.
+    pub(crate) fn take_chunk(&mut self, batch_id: u64, node_id: NodeID, chunk_id: ChunkID) -> bool {
+        debug!("NCOfflineBatches::take_chunk()");
+
+        let batch = match self.batches.get_mut(&batch_id) {
+            Some(batch) if batch.node_id == node_id => batch,
+            _ => return false
+        };
+
+        let found = match batch.chunks.iter().position(|other| *other == chunk_id) {
+            Some(index) => {
+                batch.chunks.swap_remove(index);
+                true
+            }
+            None => false
+        };
+
+        if batch.chunks.is_empty() {
+            self.batches.remove(&batch_id);
+        }
+
+        found
+    }
+
+    /// Removes all the batches that are older than the timeout and returns their remaining chunks.
+    pub(crate) fn take_expired(&mut self) -> Vec<ChunkID> {
+        let timeout = self.timeout;
+        let expired: Vec<u64> = self.batches.iter()
+            .filter(|(_, batch)| batch.exported_at.elapsed() >= timeout)
+            .map(|(batch_id, _)| *batch_id)
+            .collect();
+
+        expired.iter().filter_map(|batch_id| self.batches.remove(batch_id)).flat_map(|batch| batch.chunks).collect()
+    }
+
+    /// Number of batches that wait for results.
+    pub(crate) fn len(&self) -> usize {
+        self.batches.len()
+    }
+
+    /// Returns all batches for the checkpoint.
+    pub(crate) fn saved(&self) -> Vec<NCSavedOfflineBatch> {
+        self.batches.iter().map(|(batch_id, batch)| NCSavedOfflineBatch {
+            batch_id: *batch_id,
+            node_id: batch.node_id,
+            chunks: batch.chunks.clone(),
+            age: batch.exported_at.elapsed(),
+        }).collect()
+    }
+
+    /// Takes the batches over from a checkpoint that has been written the given time ago, so that their timeout keeps running.
+    pub(crate) fn restore(&mut self, batches: Vec<NCSavedOfflineBatch>, since_checkpoint: Duration) {
+        debug!("NCOfflineBatches::restore()");
+
+        for batch in batches {
+            let exported_at = Instant::now().checked_sub(batch.age + since_checkpoint).unwrap_or_else(Instant::now);
+            self.batches.insert(batch.batch_id, NCOfflineBatch { node_id: batch.node_id, chunks: batch.chunks, exported_at });
+        }
+    }
+}
+
+/// The part of the server that can export chunks and import results, implemented by the server process.
+pub(crate) trait NCOfflineJob: Send + Sync {
+    /// See [`NCOfflineHandle::export_chunks()`].
+    fn export_chunks(&self, path: &Path, count: usize) -> Result<usize, NCError>;
+    /// See [`NCOfflineHandle::import_results()`].
+    fn import_results(&self, path: &Path) -> Result<usize, NCError>;
+}
+
+/// Exports chunks for nodes without network access and imports their results while the server is running,
+/// see [`NCServerStarter::offline_handle()`](crate::NCServerStarter::offline_handle). The handle can be cloned and used from other threads.
+#[derive(Clone, Default)]
+pub struct NCOfflineHandle {
+    /// The running job, [`None`] before start() and after the job is done.
+    job: Arc<Mutex<Option<Arc<dyn NCOfflineJob>>>>,
+}
+
+impl NCOfflineHandle {
+    /// Sets (or clears) the running job.
+    #[cfg(feature = "net")]
+    pub(crate) fn set_job(&self, job: Option<Arc<dyn NCOfflineJob>>) -> Result<(), NCError> {
+        *self.job.lock()? = job;
+        Ok(())
+    }
+
+    /// Calls the given function with the running job. The lock is held until the function returns,
+    /// so that the server waits for an export or import before it finishes.
+    fn with_job<F: FnOnce(&dyn NCOfflineJob) -> Result<usize, NCError>>(&self, f: F) -> Result<usize, NCError> {
+        match self.job.lock()?.as_deref() {
+            Some(job) => f(job),
+            None => Err(NCError::Archive("the server is not running".to_string())),
+        }
+    }
+
+    /// Assigns up to count chunks like for a regular node and writes them to the given file, which can be processed
+    /// with [`NCNodeStarter::process_archive()`](crate::NCNodeStarter::process_archive).
+    /// Returns the number of chunks in the file, nothing is written if there is no chunk to hand out right now.
+    /// If the file can't be written the chunks are given back with the NCServer trait method chunk_send_failed().
+    pub fn export_chunks<P: AsRef<Path>>(&self, path: P, count: usize) -> Result<usize, NCError> {
+        debug!("NCOfflineHandle::export_chunks()");
+
+        self.with_job(|job| job.export_chunks(path.as_ref(), count))
+    }
+
+    /// Reads the results of an offline batch from the given file and handles them like the results of a regular node
+    /// (validate(), process_data_from_node(), chunk_empty(), ...). Results for chunks that are not part of the batch anymore
+    /// (imported before or expired) are dropped. Returns the number of results that have been accepted.
+    pub fn import_results<P: AsRef<Path>>(&self, path: P) -> Result<usize, NCError> {
+        debug!("NCOfflineHandle::import_results()");
+
+        self.with_job(|job| job.import_results(path.as_ref()))
+    }
+}
+
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    fn archive_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("nc_offline_{}_{}.bin", name, std::process::id()))
+    }
+
+    fn sample_archive() -> NCResultArchive<Vec<u32>> {
+        NCResultArchive {
+            batch_id: 7,
+            node_id: NodeID::random(),
+            results: vec![(1, NCOfflineResult::Data(None, vec![1, 2, 3])), (2, NCOfflineResult::Empty), (3, NCOfflineResult::Skip("small".to_string()))],
+        }
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let config = NCConfiguration { encrypt: true, compress: true, ..Default::default() };
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
+        let path = archive_path("round_trip");
+
+        write_archive(&path, NCArchiveKind::Results, &sample_archive(), &mut nc_communicator).unwrap();
+
+        // The node has its own communicator with the same configuration
+        let archive: NCResultArchive<Vec<u32>> = read_archive(&path, NCArchiveKind::Results, &NCCommunicator::new(&config).unwrap()).unwrap();
+        assert_eq!(archive.batch_id, 7);
+        assert_eq!(archive.results.len(), 3);
+        assert!(matches!(&archive.results[0], (1, NCOfflineResult::Data(None, data)) if *data == vec![1, 2, 3]));
+
+        // A chunk archive is not accepted as results
+        assert!(matches!(read_archive::<NCResultArchive<Vec<u32>>>(&path, NCArchiveKind::Chunks, &nc_communicator), Err(NCError::Archive(_))));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_archive_signature() {
+        let config = NCConfiguration::default();
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
+        let path = archive_path("signature");
+
+        write_archive(&path, NCArchiveKind::Results, &sample_archive(), &mut nc_communicator).unwrap();
+
+        let other_config = NCConfiguration { key: "Hn3kqPZ0lXv8dWmYc1RtG5sJbQ2eA7fU".to_string(), ..Default::default() };
+        assert!(matches!(read_archive::<NCResultArchive<Vec<u32>>>(&path, NCArchiveKind::Results, &NCCommunicator::new(&other_config).unwrap()), Err(NCError::Archive(_))));
+
+        let mut data = fs::read(&path).unwrap();
+        let index = data.len() / 2;
+        data[index] ^= 1;
+        fs::write(&path, data).unwrap();
+        assert!(matches!(read_archive::<NCResultArchive<Vec<u32>>>(&path, NCArchiveKind::Results, &nc_communicator), Err(NCError::Archive(_))));
+
+        fs::write(&path, b"NCOFF").unwrap();
+        assert!(matches!(read_archive::<NCResultArchive<Vec<u32>>>(&path, NCArchiveKind::Results, &nc_communicator), Err(NCError::Archive(_))));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_offline_batches() {
+        let mut batches = NCOfflineBatches::new(&NCConfiguration::default());
+        let node_id = NodeID::random();
+
+        batches.add(1, node_id, vec![4, 5]);
+        batches.add(2, NodeID::random(), vec![6]);
+        assert_eq!(batches.len(), 2);
+
+        // Wrong node, unknown chunk, unknown batch
+        assert!(!batches.take_chunk(1, NodeID::random(), 4));
+        assert!(!batches.take_chunk(1, node_id, 6));
+        assert!(!batches.take_chunk(3, node_id, 4));
+
+        assert!(batches.take_chunk(1, node_id, 4));
+        assert!(!batches.take_chunk(1, node_id, 4));
+        assert!(batches.take_chunk(1, node_id, 5));
+        assert_eq!(batches.len(), 1);
+        assert!(batches.take_expired().is_empty());
+
+        batches.timeout = Duration::from_millis(10);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(batches.take_expired(), vec![6]);
+        assert_eq!(batches.len(), 0);
+    }
+
+    #[test]
+    fn test_batch_timeout_saturates() {
+        let batches = NCOfflineBatches::new(&NCConfiguration { offline_batch_timeout_days: u64::MAX, ..Default::default() });
+        assert_eq!(batches.timeout, Duration::from_secs(u64::MAX));
+    }
+}
@@ -0,0 +1,226 @@
+//! This module contains the post processing workers for the results on the server.
+//! process_data_from_node() should only do the quick work (checking the result, book keeping), everything that takes longer
+//! (for example writing an image tile to disk) can be done by a [`NCPostProcessor`], see the NCServer trait method post_processor().
+//! The results are put into a bounded queue (post_process_queue_len in the NCConfiguration) and handled by post_process_workers threads,
+//! so the nc_server lock is not held while the slow work is done. All remaining results are processed before the server exits.
+
+#[cfg(feature = "net")]
+use std::sync::{Arc, Mutex, mpsc};
+#[cfg(feature = "net")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "net")]
+use std::thread::{self, JoinHandle};
+
+#[cfg(feature = "net")]
+use log::{debug, error};
+
+#[cfg(feature = "net")]
+use crate::nc_config::NCConfiguration;
+use crate::nc_error::NCError;
+#[cfg(feature = "net")]
+use crate::nc_error::panic_message;
+use crate::array2d::ChunkID;
+
+/// The user code that runs on the post processing workers.
+pub trait NCPostProcessor<ProcessedDataT>: Send + Sync {
+    /// This is called with every result after process_data_from_node() has accepted it.
+    /// The chunk id is [`None`] if the server doesn't know which chunk the result belongs to.
+    /// If this returns an error the NCServer trait method post_process_failed() is called, the node is not informed.
+    fn post_process(&self, chunk_id: Option<ChunkID>, data: ProcessedDataT) -> Result<(), NCError>;
+}
+
+/// A result and the chunk it belongs to, waiting for post processing.
+#[cfg(feature = "net")]
+type PostProcessItem<P> = (Option<ChunkID>, P);
+
+/// An error from the post processor and the chunk it belongs to.
+#[cfg(feature = "net")]
+type PostProcessFailure = (Option<ChunkID>, NCError);
+
+/// The queue and the worker threads for the post processing.
+#[cfg(feature = "net")]
+pub(crate) struct NCPostProcessQueue<P> {
+    /// Sends the results to the workers, [`None`] after close().
+    sender: Mutex<Option<mpsc::SyncSender<PostProcessItem<P>>>>,
+    /// The worker threads.
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    /// The errors from the post processor that have not been reported yet.
+    failures: Arc<Mutex<Vec<PostProcessFailure>>>,
+    /// Total number of errors from the post processor.
+    failure_count: Arc<AtomicU64>,
+}
+
+#[cfg(feature = "net")]
+impl<P: Send + 'static> NCPostProcessQueue<P> {
+    /// Starts the worker threads for the given post processor with the settings from the given configuration.
+    pub(crate) fn new(processor: Arc<dyn NCPostProcessor<P>>, config: &NCConfiguration) -> Self {
+        debug!("NCPostProcessQueue::new()");
+
+        let (sender, receiver) = mpsc::sync_channel::<PostProcessItem<P>>(config.post_process_queue_len);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let failure_count = Arc::new(AtomicU64::new(0));
+
+        let workers = (0..config.post_process_workers.max(1)).map(|_| {
+            let receiver = receiver.clone();
+            let processor = processor.clone();
+            let failures = failures.clone();
+            let failure_count = failure_count.clone();
+
+            thread::spawn(move || {
+                loop {
+                    // Only hold the lock while waiting for the next result, not while processing it.
+                    let item = match receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => break,
+                    };
+
+                    let (chunk_id, data) = match item {
+                        Ok(item) => item,
+                        Err(_) => break, // Queue has been closed and is empty
+                    };
+
+                    if let Err(e) = processor.post_process(chunk_id, data) {
+                        error!("Post processing failed for chunk {:?}: {}", chunk_id, e);
+                        failure_count.fetch_add(1, Ordering::Relaxed);
+
+                        if let Ok(mut failures) = failures.lock() {
+                            failures.push((chunk_id, e));
+                        }
+                    }
+                }
+                debug!("Exit post processing worker");
+            })
+        }).collect();
+
+        NCPostProcessQueue {
+            sender: Mutex::new(Some(sender)),
+            workers: Mutex::new(workers),
+            failures,
+            failure_count,
+        }
+    }
+
+    /// Puts the result into the queue, blocks while the queue is full.
+    /// After close() the result is dropped.
+    pub(crate) fn push(&self, chunk_id: Option<ChunkID>, data: P) -> Result<(), NCError> {
+        let sender = self.sender.lock()?.clone();
+
+        match sender {
+            Some(sender) => {
+                if sender.send((chunk_id, data)).is_err() {
+                    error!("Post processing workers have exited, drop result for chunk {:?}", chunk_id);
+                }
+            }
+            None => error!("Post processing queue is closed, drop result for chunk {:?}", chunk_id),
+        }
+
+        Ok(())
+    }
+
+    /// Returns the errors from the post processor since the last call.
+    pub(crate) fn take_failures(&self) -> Result<Vec<PostProcessFailure>, NCError> {
+        Ok(std::mem::take(&mut *self.failures.lock()?))
+    }
+
+    /// Total number of errors from the post processor.
+    pub(crate) fn failure_count(&self) -> u64 {
+        self.failure_count.load(Ordering::Relaxed)
+    }
+
+    /// No more results are accepted, waits until the workers have processed all the results in the queue.
+    /// Returns the panic messages of the workers that have panicked.
+    pub(crate) fn close(&self) -> Result<Vec<String>, NCError> {
+        debug!("NCPostProcessQueue::close()");
+
+        self.sender.lock()?.take();
+        let mut panics = Vec::new();
+
+        for worker in self.workers.lock()?.drain(..) {
+            if let Err(payload) = worker.join() {
+                let message = panic_message(payload.as_ref());
+                error!("Post processing worker panicked: {}", message);
+                panics.push(message);
+            }
+        }
+
+        Ok(panics)
+    }
+}
+
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    /// Collects the results slowly, fails for odd numbers.
+    struct SlowProcessor {
+        done: Mutex<Vec<u32>>,
+    }
+
+    impl NCPostProcessor<u32> for SlowProcessor {
+        fn post_process(&self, _chunk_id: Option<ChunkID>, data: u32) -> Result<(), NCError> {
+            thread::sleep(Duration::from_millis(10));
+            self.done.lock()?.push(data);
+
+            if data % 2 == 1 {
+                Err(NCError::Custom(data))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn test_close_drains_queue() {
+        let config = NCConfiguration { post_process_workers: 3, post_process_queue_len: 2, ..Default::default() };
+        let processor = Arc::new(SlowProcessor { done: Mutex::new(Vec::new()) });
+        let queue = NCPostProcessQueue::new(processor.clone(), &config);
+
+        for i in 0..20 {
+            queue.push(Some(i as ChunkID), i).unwrap();
+        }
+
+        queue.close().unwrap();
+
+        let mut done = processor.done.lock().unwrap().clone();
+        done.sort_unstable();
+        assert_eq!(done, (0..20).collect::<Vec<_>>());
+
+        let mut failures: Vec<Option<ChunkID>> = queue.take_failures().unwrap().into_iter().map(|(chunk_id, _)| chunk_id).collect();
+        failures.sort_unstable();
+        assert_eq!(failures, (0..20).filter(|i| i % 2 == 1).map(Some).collect::<Vec<_>>());
+        assert_eq!(queue.failure_count(), 10);
+        assert!(queue.take_failures().unwrap().is_empty());
+
+        // Closed, the result is dropped
+        queue.push(Some(20), 20).unwrap();
+        assert_eq!(processor.done.lock().unwrap().len(), 20);
+    }
+
+    /// Panics for 3, so that one worker is gone.
+    struct PanicProcessor;
+
+    impl NCPostProcessor<u32> for PanicProcessor {
+        fn post_process(&self, _chunk_id: Option<ChunkID>, data: u32) -> Result<(), NCError> {
+            if data == 3 {
+                panic!("can't handle 3")
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_close_reports_panics() {
+        let config = NCConfiguration { post_process_workers: 2, ..Default::default() };
+        let queue = NCPostProcessQueue::new(Arc::new(PanicProcessor), &config);
+
+        for i in 0..5 {
+            queue.push(Some(i as ChunkID), i).unwrap();
+        }
+
+        assert_eq!(queue.close().unwrap(), vec!["can't handle 3".to_string()]);
+    }
+}
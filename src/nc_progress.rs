@@ -0,0 +1,510 @@
+//! This module contains progress displays for the server and the node, it's only available with the progress feature.
+//! [`NCServerProgress`] shows the progress events of the server (see NCServerStarter::progress_events()) as an overall
+//! progress bar and one line per node with its current state and the time of its last chunk.
+//! [`NCNodeProgress`] shows the chunk that is currently processed on the node with a spinner and the elapsed time
+//! (see NCNodeStarter::progress_events()).
+//! If stderr is not a terminal (for example in a batch job) both fall back to a log line every log_interval.
+
+use std::collections::BTreeMap;
+use std::io::{stderr, IsTerminal};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{debug, error, info};
+
+use crate::nc_server::NCProgressEvent;
+use crate::nc_node::{NCNodeProgressEvent, NodeExit};
+use crate::nc_node_info::NodeID;
+use crate::array2d::ChunkID;
+
+/// The progress bars are redrawn this often.
+const TICK: Duration = Duration::from_millis(200);
+
+/// Default time between two log lines if stderr is not a terminal.
+const LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Formats the duration as seconds with one decimal place.
+fn secs(duration: Duration) -> String {
+    format!("{:.1}s", duration.as_secs_f64())
+}
+
+/// Creates a progress bar style from the given template, falls back to the default style if the template is invalid.
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template).unwrap_or_else(|_| ProgressStyle::default_bar())
+}
+
+/// What a node is doing right now, as far as the server knows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NodeState {
+    /// Registered or done with its last chunk.
+    Idle,
+    /// Processing the given chunk since the given time.
+    Processing(ChunkID, Instant),
+    /// The node could not process its last chunk.
+    Failed,
+    /// The node has missed its heartbeat or has been disabled.
+    Offline,
+}
+
+/// One line of the server display.
+#[derive(Debug)]
+struct NodeEntry {
+    /// The id of the node.
+    node_id: NodeID,
+    /// What the node is doing right now.
+    state: NodeState,
+    /// Number of chunks the node has finished.
+    chunks: u64,
+    /// The time the node needed for its last chunk.
+    last_chunk_time: Option<Duration>,
+    /// The groups of the node.
+    tags: Vec<String>,
+}
+
+impl NodeEntry {
+    /// The text that is shown for this node.
+    fn message(&self) -> String {
+        let state = match self.state {
+            NodeState::Idle => "idle".to_string(),
+            NodeState::Processing(chunk_id, since) => format!("chunk {} ({})", chunk_id, secs(since.elapsed())),
+            NodeState::Failed => "failed".to_string(),
+            NodeState::Offline => "offline".to_string(),
+        };
+        let last_chunk_time = self.last_chunk_time.map_or("-".to_string(), secs);
+
+        let tags = if self.tags.is_empty() { String::new() } else { format!(" [{}]", self.tags.join(", ")) };
+
+        format!("node {}{}: {}, chunks: {}, last chunk: {}", self.node_id, tags, state, self.chunks, last_chunk_time)
+    }
+}
+
+/// The state of the whole job, updated with the progress events from the server.
+#[derive(Debug, Default)]
+struct ServerProgressState {
+    /// Number of finished chunks, from the NCServer trait method job_progress().
+    finished: u64,
+    /// Total number of chunks, 0 if unknown.
+    total: u64,
+    /// All the nodes in the order they have been seen.
+    nodes: Vec<NodeEntry>,
+    /// The job is done.
+    done: bool,
+}
+
+impl ServerProgressState {
+    /// Returns the entry for the given node, a new one is added if the node is unknown.
+    fn node_mut(&mut self, node_id: NodeID) -> &mut NodeEntry {
+        let index = match self.nodes.iter().position(|node| node.node_id == node_id) {
+            Some(index) => index,
+            None => {
+                self.nodes.push(NodeEntry { node_id, state: NodeState::Idle, chunks: 0, last_chunk_time: None, tags: Vec::new() });
+                self.nodes.len() - 1
+            }
+        };
+
+        &mut self.nodes[index]
+    }
+
+    /// Updates the state with the given event.
+    fn update(&mut self, event: NCProgressEvent) {
+        match event {
+            NCProgressEvent::NodeRegistered(node_id) => {
+                self.node_mut(node_id).state = NodeState::Idle;
+            }
+            NCProgressEvent::ChunkSent(node_id, chunk_id) => {
+                self.node_mut(node_id).state = NodeState::Processing(chunk_id, Instant::now());
+            }
+            NCProgressEvent::ChunkDone(node_id, _, chunk_time) => {
+                let node = self.node_mut(node_id);
+                node.state = NodeState::Idle;
+                node.chunks += 1;
+                node.last_chunk_time = Some(chunk_time);
+            }
+            NCProgressEvent::ChunkFailed(node_id) => {
+                self.node_mut(node_id).state = NodeState::Failed;
+            }
+            NCProgressEvent::ChunkRevoked(node_id, _) => {
+                self.node_mut(node_id).state = NodeState::Idle;
+            }
+            NCProgressEvent::NodeOffline(node_id) => {
+                self.node_mut(node_id).state = NodeState::Offline;
+            }
+            NCProgressEvent::NodeTags(node_id, tags) => {
+                self.node_mut(node_id).tags = tags;
+            }
+            NCProgressEvent::JobProgress(finished, total) => {
+                self.finished = finished;
+                self.total = total;
+            }
+            NCProgressEvent::JobDone => {
+                self.done = true;
+            }
+            // Already logged by the server.
+            NCProgressEvent::TaskFailed(_, _) | NCProgressEvent::Listening(_) | NCProgressEvent::ArtifactStored(_, _, _) |
+            NCProgressEvent::ClockSkew(_, _) | NCProgressEvent::ChunkExpired(_, _) | NCProgressEvent::ChunkDead(_, _, _) |
+            NCProgressEvent::Broadcast(_, _) | NCProgressEvent::JobAborted(_) => (),
+        }
+    }
+
+    /// One line with the overall progress, used for the log.
+    fn summary(&self) -> String {
+        let busy = self.nodes.iter().filter(|node| matches!(node.state, NodeState::Processing(_, _))).count();
+        let offline = self.nodes.iter().filter(|node| node.state == NodeState::Offline).count();
+        let idle = self.nodes.len() - busy - offline;
+        let percent = if self.total == 0 { 0.0 } else { (self.finished as f64) * 100.0 / (self.total as f64) };
+
+        let groups = self.groups();
+        let groups = if groups.is_empty() { groups } else { format!(", {}", groups) };
+
+        format!("{}/{} chunks ({:.1}%), nodes: {} busy, {} idle, {} offline{}", self.finished, self.total, percent, busy, idle, offline, groups)
+    }
+
+    /// The number of nodes in every group like "groups: cpu: 2, gpu: 1", empty if no node has tags.
+    fn groups(&self) -> String {
+        let mut groups: BTreeMap<&str, u64> = BTreeMap::new();
+
+        for tag in self.nodes.iter().flat_map(|node| node.tags.iter()) {
+            *groups.entry(tag).or_default() += 1;
+        }
+
+        if groups.is_empty() {
+            return String::new()
+        }
+
+        let groups: Vec<String> = groups.iter().map(|(tag, count)| format!("{}: {}", tag, count)).collect();
+        format!("groups: {}", groups.join(", "))
+    }
+}
+
+/// The progress bars of the server display.
+struct ServerBars {
+    /// Keeps all the bars together.
+    multi: MultiProgress,
+    /// The overall progress of the job.
+    overall: ProgressBar,
+    /// One line for every node, in the same order as in the ServerProgressState.
+    nodes: Vec<ProgressBar>,
+}
+
+impl ServerBars {
+    /// Creates the overall progress bar.
+    fn new() -> Self {
+        let multi = MultiProgress::new();
+        let overall = multi.add(ProgressBar::new(0));
+        overall.set_style(style("{bar:40} {pos}/{len} chunks, {elapsed_precise} (eta {eta}) {msg}"));
+
+        ServerBars { multi, overall, nodes: Vec::new() }
+    }
+
+    /// Shows the given state, a new line is added for every new node.
+    fn draw(&mut self, state: &ServerProgressState) {
+        self.overall.set_length(state.total);
+        self.overall.set_position(state.finished);
+        self.overall.set_message(state.groups());
+
+        for (index, node) in state.nodes.iter().enumerate() {
+            if index == self.nodes.len() {
+                let bar = self.multi.add(ProgressBar::new_spinner());
+                bar.set_style(style("{spinner} {msg}"));
+                self.nodes.push(bar);
+            }
+
+            self.nodes[index].set_message(node.message());
+            self.nodes[index].tick();
+        }
+    }
+
+    /// Leaves the final state on the screen.
+    fn finish(&self) {
+        for bar in self.nodes.iter() {
+            bar.finish();
+        }
+
+        self.overall.finish_with_message("job done");
+    }
+}
+
+/// Shows the progress events of the server in a separate thread.
+pub struct NCServerProgress {
+    /// Handle of the display thread.
+    thread_handle: JoinHandle<()>,
+}
+
+impl NCServerProgress {
+    /// Starts a thread that shows the given progress events from NCServerStarter::progress_events() until the job is done.
+    pub fn start(events: Receiver<NCProgressEvent>) -> Self {
+        Self::with_log_interval(events, LOG_INTERVAL)
+    }
+
+    /// Same as start(), but if stderr is not a terminal a log line is written every log_interval instead of the default 30 seconds.
+    pub fn with_log_interval(events: Receiver<NCProgressEvent>, log_interval: Duration) -> Self {
+        debug!("NCServerProgress::with_log_interval()");
+
+        let thread_handle = thread::spawn(move || {
+            let mut state = ServerProgressState::default();
+            let mut bars = if stderr().is_terminal() { Some(ServerBars::new()) } else { None };
+            let mut last_log = Instant::now();
+
+            while !state.done {
+                match events.recv_timeout(TICK) {
+                    Ok(event) => {
+                        state.update(event);
+
+                        // Take all the events that have arrived in the meantime before drawing.
+                        while let Ok(event) = events.try_recv() {
+                            state.update(event);
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    // The server is gone, without a JobDone event.
+                    Err(RecvTimeoutError::Disconnected) => state.done = true,
+                }
+
+                match &mut bars {
+                    Some(bars) => bars.draw(&state),
+                    None => {
+                        if state.done || last_log.elapsed() >= log_interval {
+                            info!("Progress: {}", state.summary());
+                            last_log = Instant::now();
+                        }
+                    }
+                }
+            }
+
+            if let Some(bars) = bars {
+                bars.finish();
+            }
+
+            debug!("Exit server progress thread");
+        });
+
+        NCServerProgress { thread_handle }
+    }
+
+    /// Waits until the job is done and the display is finished.
+    pub fn finish(self) {
+        debug!("NCServerProgress::finish()");
+
+        if self.thread_handle.join().is_err() {
+            error!("Server progress thread panicked");
+        }
+    }
+}
+
+/// The state of the node, updated with the progress events from the node.
+#[derive(Debug, Default)]
+struct NodeProgressState {
+    /// The node id from the server, [`None`] until the node has registered.
+    node_id: Option<NodeID>,
+    /// The chunk that is processed right now and when processing has started.
+    current_chunk: Option<(ChunkID, Instant)>,
+    /// All the chunks have been handed out, the node waits for the other nodes.
+    waiting: bool,
+    /// Number of chunks that are done.
+    chunks: u64,
+    /// Number of chunks that have failed.
+    failed: u64,
+    /// The processing time of the last chunk.
+    last_chunk_time: Option<Duration>,
+    /// How the node has exited, [`None`] while it's running.
+    exit: Option<NodeExit>,
+}
+
+impl NodeProgressState {
+    /// Updates the state with the given event.
+    fn update(&mut self, event: NCNodeProgressEvent) {
+        match event {
+            NCNodeProgressEvent::Registered(node_id) => {
+                self.node_id = Some(node_id);
+            }
+            NCNodeProgressEvent::ChunkStarted(chunk_id) => {
+                self.current_chunk = Some((chunk_id, Instant::now()));
+                self.waiting = false;
+            }
+            NCNodeProgressEvent::ChunkDone(_, chunk_time) => {
+                self.current_chunk = None;
+                self.chunks += 1;
+                self.last_chunk_time = Some(chunk_time);
+            }
+            NCNodeProgressEvent::ChunkFailed(_) => {
+                self.current_chunk = None;
+                self.failed += 1;
+            }
+            NCNodeProgressEvent::ChunkRevoked(_) => {
+                self.current_chunk = None;
+            }
+            NCNodeProgressEvent::Waiting => {
+                self.waiting = true;
+            }
+            NCNodeProgressEvent::Exit(node_exit) => {
+                self.current_chunk = None;
+                self.exit = Some(node_exit);
+            }
+        }
+    }
+
+    /// One line with the current state of the node.
+    fn summary(&self) -> String {
+        let state = match (&self.exit, self.current_chunk) {
+            (Some(NodeExit::JobFinished), _) => "finished".to_string(),
+            (Some(NodeExit::RestartRequested), _) => "restart requested".to_string(),
+            (Some(NodeExit::Disabled { reason }), _) => format!("disabled: {}", reason),
+            (Some(NodeExit::StoppedByUser), _) => "stopped".to_string(),
+            (Some(NodeExit::JobAborted { reason }), _) => format!("job aborted: {}", reason),
+            (None, Some((chunk_id, since))) => format!("chunk {} ({})", chunk_id, secs(since.elapsed())),
+            (None, None) if self.waiting => "waiting for other nodes".to_string(),
+            (None, None) => "idle".to_string(),
+        };
+        let node_id = self.node_id.map_or("-".to_string(), |node_id| node_id.to_string());
+        let last_chunk_time = self.last_chunk_time.map_or("-".to_string(), secs);
+
+        format!("node {}: {}, chunks: {}, failed: {}, last chunk: {}", node_id, state, self.chunks, self.failed, last_chunk_time)
+    }
+}
+
+/// Shows the progress events of the node in a separate thread.
+pub struct NCNodeProgress {
+    /// Handle of the display thread.
+    thread_handle: JoinHandle<()>,
+}
+
+impl NCNodeProgress {
+    /// Starts a thread that shows the given progress events from NCNodeStarter::progress_events() until the node exits.
+    pub fn start(events: Receiver<NCNodeProgressEvent>) -> Self {
+        Self::with_log_interval(events, LOG_INTERVAL)
+    }
+
+    /// Same as start(), but if stderr is not a terminal a log line is written every log_interval instead of the default 30 seconds.
+    pub fn with_log_interval(events: Receiver<NCNodeProgressEvent>, log_interval: Duration) -> Self {
+        debug!("NCNodeProgress::with_log_interval()");
+
+        let thread_handle = thread::spawn(move || {
+            let mut state = NodeProgressState::default();
+            let spinner = if stderr().is_terminal() { Some(ProgressBar::new_spinner()) } else { None };
+            let mut last_log = Instant::now();
+            let mut running = true;
+
+            if let Some(spinner) = &spinner {
+                spinner.set_style(style("{spinner} {msg}"));
+            }
+
+            while running {
+                match events.recv_timeout(TICK) {
+                    Ok(event) => state.update(event),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    // The node is gone, without an Exit event.
+                    Err(RecvTimeoutError::Disconnected) => running = false,
+                }
+
+                running = running && state.exit.is_none();
+
+                match &spinner {
+                    Some(spinner) => {
+                        spinner.set_message(state.summary());
+                        spinner.tick();
+                    }
+                    None => {
+                        if !running || last_log.elapsed() >= log_interval {
+                            info!("Progress: {}", state.summary());
+                            last_log = Instant::now();
+                        }
+                    }
+                }
+            }
+
+            if let Some(spinner) = spinner {
+                spinner.finish();
+            }
+
+            debug!("Exit node progress thread");
+        });
+
+        NCNodeProgress { thread_handle }
+    }
+
+    /// Waits until the node has exited and the display is finished.
+    pub fn finish(self) {
+        debug!("NCNodeProgress::finish()");
+
+        if self.thread_handle.join().is_err() {
+            error!("Node progress thread panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_server_progress_state() {
+        let node_id1 = NodeID::from_str("1").unwrap();
+        let node_id2 = NodeID::from_str("2").unwrap();
+        let mut state = ServerProgressState::default();
+
+        state.update(NCProgressEvent::JobProgress(0, 4));
+        state.update(NCProgressEvent::NodeRegistered(node_id1));
+        state.update(NCProgressEvent::NodeRegistered(node_id2));
+        state.update(NCProgressEvent::ChunkSent(node_id1, 0));
+        state.update(NCProgressEvent::ChunkSent(node_id2, 1));
+        state.update(NCProgressEvent::ChunkDone(node_id1, 0, Duration::from_millis(1500)));
+        state.update(NCProgressEvent::JobProgress(1, 4));
+
+        assert_eq!(state.summary(), "1/4 chunks (25.0%), nodes: 1 busy, 1 idle, 0 offline");
+        assert_eq!(state.nodes[0].message(), "node 1: idle, chunks: 1, last chunk: 1.5s");
+        assert!(state.nodes[1].message().starts_with("node 2: chunk 1 ("));
+
+        state.update(NCProgressEvent::NodeOffline(node_id2));
+        assert_eq!(state.summary(), "1/4 chunks (25.0%), nodes: 0 busy, 1 idle, 1 offline");
+        assert!(!state.done);
+
+        state.update(NCProgressEvent::NodeTags(node_id1, vec!["cpu".to_string(), "gpu".to_string()]));
+        state.update(NCProgressEvent::NodeTags(node_id2, vec!["gpu".to_string()]));
+        assert_eq!(state.summary(), "1/4 chunks (25.0%), nodes: 0 busy, 1 idle, 1 offline, groups: cpu: 1, gpu: 2");
+        assert_eq!(state.nodes[0].message(), "node 1 [cpu, gpu]: idle, chunks: 1, last chunk: 1.5s");
+
+        state.update(NCProgressEvent::JobDone);
+        assert!(state.done);
+    }
+
+    #[test]
+    fn test_node_progress_state() {
+        let mut state = NodeProgressState::default();
+
+        assert_eq!(state.summary(), "node -: idle, chunks: 0, failed: 0, last chunk: -");
+
+        state.update(NCNodeProgressEvent::Registered(NodeID::from_str("7").unwrap()));
+        state.update(NCNodeProgressEvent::ChunkStarted(3));
+        assert!(state.summary().starts_with("node 7: chunk 3 ("));
+
+        state.update(NCNodeProgressEvent::ChunkDone(3, Duration::from_secs(2)));
+        state.update(NCNodeProgressEvent::ChunkStarted(4));
+        state.update(NCNodeProgressEvent::ChunkFailed(4));
+        state.update(NCNodeProgressEvent::Waiting);
+        assert_eq!(state.summary(), "node 7: waiting for other nodes, chunks: 1, failed: 1, last chunk: 2.0s");
+
+        state.update(NCNodeProgressEvent::Exit(NodeExit::RestartRequested));
+        assert_eq!(state.summary(), "node 7: restart requested, chunks: 1, failed: 1, last chunk: 2.0s");
+    }
+
+    #[test]
+    fn test_progress_threads_exit() {
+        let (sender, receiver) = mpsc::channel();
+        let server_progress = NCServerProgress::with_log_interval(receiver, Duration::from_millis(10));
+        sender.send(NCProgressEvent::JobProgress(1, 1)).unwrap();
+        sender.send(NCProgressEvent::JobDone).unwrap();
+        server_progress.finish();
+
+        // The node is gone without an Exit event
+        let (sender, receiver) = mpsc::channel();
+        let node_progress = NCNodeProgress::with_log_interval(receiver, Duration::from_millis(10));
+        sender.send(NCNodeProgressEvent::ChunkStarted(0)).unwrap();
+        drop(sender);
+        node_progress.finish();
+    }
+}
@@ -0,0 +1,415 @@
+//! This module contains the wire types of the messages that a small node needs and the encoding of the frame header.
+//! It only uses core and alloc, so with `default-features = false` and the no-std-proto feature the crate is no_std and
+//! can be used by the firmware of a microcontroller (for example an ESP32) that brings its own transport.
+//!
+//! The types have the same layout as the ones in the [`nc_message`](crate::nc_message) module: bincode writes the index of an enum variant,
+//! so the enums here contain the variants of the full enums in the same order, up to the last one a small node needs.
+//! The data is encoded like bincode 1 with its default options (fixed size integers, little endian), for example with bincode 2,
+//! `serde` and `bincode::config::legacy()`.
+//!
+//! A frame on the wire looks like this (see the [`nc_communicator`](crate::nc_communicator) module):
+//!
+//! - the length of the rest of the frame (u64, little endian)
+//! - the header, see [`NCFrameHeader`]: the optional job tag (JOB_TAG, the length of the job id and the job id), the codec byte and
+//!   the optional id of the zstd dictionary (DICTIONARY_FLAG)
+//! - the data: if encrypt is set the nonce (NONCE_LEN bytes) and the ChaCha20-Poly1305 cipher text, inside that the data
+//!   compressed with the codec, inside that the optional type hash (4 bytes, little endian) and the serialized message
+//!
+//! Compression and encryption are up to the firmware, a node that only offers NCCodec::None never gets compressed data.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Display, Formatter};
+use core::net::SocketAddr;
+use core::time::Duration;
+
+use serde::{Serialize, Deserialize};
+
+/// Marks a message that starts with a job id, this is never used as the id of a codec.
+pub const JOB_TAG: u8 = 0xFF;
+
+/// Set in the codec byte if the data starts with the hash of the type name of the user data.
+pub const TYPE_HASH_FLAG: u8 = 0x80;
+
+/// Set in the codec byte if the data consists of two separately compressed parts: the length of the first part (u64, little endian),
+/// the first part and the second part.
+pub const SEGMENTS_FLAG: u8 = 0x40;
+
+/// Set in the codec byte if the data is not encrypted although encrypt is set. The server only sends a rejection like this
+/// (NCRejectReason::WrongKey or VersionMismatch), since it could not decode the message of the node.
+pub const PLAIN_FLAG: u8 = 0x20;
+
+/// Set in the codec byte if the data has been compressed with zstd and a dictionary, the id of the dictionary (u32, little endian)
+/// follows the codec byte. See the [`nc_dictionary`](crate::nc_dictionary) module.
+pub const DICTIONARY_FLAG: u8 = 0x10;
+
+/// The codec id of uncompressed data.
+pub const CODEC_NONE: u8 = 0;
+
+/// The codec id of data compressed with lz4 (the size is prepended, u32 little endian).
+pub const CODEC_LZ4: u8 = 1;
+
+/// The codec id of data compressed with zstd.
+pub const CODEC_ZSTD: u8 = 2;
+
+/// The size of the nonce in front of encrypted data.
+pub const NONCE_LEN: usize = 12;
+
+/// The size of the length in front of every frame.
+pub const LENGTH_LEN: usize = 8;
+
+/// The FNV-1a hash of the given bytes.
+pub fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5_u32, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193))
+}
+
+/// The hash of the type name of the user data that is sent if type_check is set in the NCConfiguration.
+/// The name is the one from `core::any::type_name()` on the server, references are ignored.
+pub fn type_hash(type_name: &str) -> u32 {
+    fnv1a(type_name.trim_start_matches('&').as_bytes())
+}
+
+/// A frame that could not be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NCProtoError {
+    /// The frame ends too early, with the name of the missing part.
+    Truncated(&'static str),
+    /// The frame has no codec byte or an unknown one.
+    UnknownCodec(Option<u8>),
+    /// The job id is not valid UTF-8.
+    InvalidJobId,
+}
+
+impl Display for NCProtoError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            NCProtoError::Truncated(part) => write!(f, "frame ends before the {}", part),
+            NCProtoError::UnknownCodec(Some(codec_byte)) => write!(f, "unknown codec: {:#04x}", codec_byte),
+            NCProtoError::UnknownCodec(None) => f.write_str("frame has no codec byte"),
+            NCProtoError::InvalidJobId => f.write_str("job id is not valid UTF-8"),
+        }
+    }
+}
+
+/// The header of a frame: the optional job id and the codec byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NCFrameHeader<'a> {
+    /// The job id (job_id in the NCConfiguration), only the first 255 bytes are written.
+    pub job_id: Option<&'a str>,
+    /// The codec of the data: CODEC_NONE, CODEC_LZ4 or CODEC_ZSTD.
+    pub codec: u8,
+    /// The serialized data starts with the type hash, see type_hash().
+    pub type_hash: bool,
+    /// The data consists of two separately compressed parts, see SEGMENTS_FLAG.
+    pub segments: bool,
+    /// The data is not encrypted, see PLAIN_FLAG.
+    pub plain: bool,
+    /// The id of the zstd dictionary that the data has been compressed with, see DICTIONARY_FLAG.
+    pub dictionary: Option<u32>,
+}
+
+impl<'a> NCFrameHeader<'a> {
+    /// A header with the given codec, without job id and flags.
+    pub fn new(codec: u8) -> Self {
+        NCFrameHeader { job_id: None, codec, type_hash: false, segments: false, plain: false, dictionary: None }
+    }
+
+    /// The codec byte with the flags.
+    pub fn codec_byte(&self) -> u8 {
+        self.codec | if self.type_hash { TYPE_HASH_FLAG } else { 0 } | if self.segments { SEGMENTS_FLAG } else { 0 } | if self.plain { PLAIN_FLAG } else { 0 } |
+            if self.dictionary.is_some() { DICTIONARY_FLAG } else { 0 }
+    }
+
+    /// The job id as it's written, at most 255 bytes.
+    fn job_id_bytes(&self) -> Option<&'a [u8]> {
+        self.job_id.map(|job_id| &job_id.as_bytes()[..job_id.len().min(u8::MAX as usize)])
+    }
+
+    /// The number of bytes of the header.
+    pub fn encoded_len(&self) -> usize {
+        self.job_id_bytes().map_or(0, |job_id| 2 + job_id.len()) + 1 + if self.dictionary.is_some() { 4 } else { 0 }
+    }
+
+    /// Appends the header to the given buffer.
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        if let Some(job_id) = self.job_id_bytes() {
+            out.push(JOB_TAG);
+            out.push(job_id.len() as u8);
+            out.extend_from_slice(job_id);
+        }
+
+        out.push(self.codec_byte());
+
+        if let Some(dictionary) = self.dictionary {
+            out.extend_from_slice(&dictionary.to_le_bytes());
+        }
+    }
+
+    /// Reads the header from the front of the frame (without the length) and returns it together with the data.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCProtoError::Truncated`] error if the job id or the dictionary id is cut off, a [`NCProtoError::UnknownCodec`] error if the codec byte is missing
+    /// or unknown and a [`NCProtoError::InvalidJobId`] error if the job id is not valid UTF-8.
+    pub fn parse(frame: &'a [u8]) -> Result<(Self, &'a [u8]), NCProtoError> {
+        let (job_id, rest) = match frame {
+            [JOB_TAG, len, rest @ ..] => {
+                let len = *len as usize;
+
+                if rest.len() < len {
+                    return Err(NCProtoError::Truncated("job id"))
+                }
+
+                let (job_id, rest) = rest.split_at(len);
+                (Some(core::str::from_utf8(job_id).map_err(|_| NCProtoError::InvalidJobId)?), rest)
+            }
+            [JOB_TAG] => return Err(NCProtoError::Truncated("job id")),
+            _ => (None, frame),
+        };
+
+        let (codec_byte, data) = rest.split_first().ok_or(NCProtoError::UnknownCodec(None))?;
+        let codec = codec_byte & !(TYPE_HASH_FLAG | SEGMENTS_FLAG | PLAIN_FLAG | DICTIONARY_FLAG);
+
+        // Only zstd has dictionaries
+        if codec > CODEC_ZSTD || (codec_byte & DICTIONARY_FLAG != 0 && codec != CODEC_ZSTD) {
+            return Err(NCProtoError::UnknownCodec(Some(*codec_byte)))
+        }
+
+        let (dictionary, data) = if codec_byte & DICTIONARY_FLAG != 0 {
+            match data {
+                [a, b, c, d, data @ ..] => (Some(u32::from_le_bytes([*a, *b, *c, *d])), data),
+                _ => return Err(NCProtoError::Truncated("dictionary id")),
+            }
+        } else {
+            (None, data)
+        };
+
+        let header = NCFrameHeader { job_id, codec, type_hash: codec_byte & TYPE_HASH_FLAG != 0, segments: codec_byte & SEGMENTS_FLAG != 0,
+            plain: codec_byte & PLAIN_FLAG != 0, dictionary };
+        Ok((header, data))
+    }
+}
+
+/// Encodes a whole frame: the length, the header and the given data (already compressed and encrypted, if needed).
+pub fn encode_frame(header: &NCFrameHeader, data: &[u8]) -> Vec<u8> {
+    let len = header.encoded_len() + data.len();
+    let mut frame = Vec::with_capacity(LENGTH_LEN + len);
+    frame.extend_from_slice(&(len as u64).to_le_bytes());
+    header.write_to(&mut frame);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Reads the length of the rest of the frame from the first LENGTH_LEN bytes.
+///
+/// # Errors
+///
+/// Returns a [`NCProtoError::Truncated`] error if there are less than LENGTH_LEN bytes.
+pub fn frame_len(bytes: &[u8]) -> Result<u64, NCProtoError> {
+    let mut len = [0; LENGTH_LEN];
+    len.copy_from_slice(bytes.get(..LENGTH_LEN).ok_or(NCProtoError::Truncated("length"))?);
+    Ok(u64::from_le_bytes(len))
+}
+
+/// The node id, see [`NodeID`](crate::NodeID).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct NodeID(pub u64);
+
+/// The chunk id, see [`ChunkID`](crate::ChunkID).
+pub type ChunkID = u64;
+
+/// The metadata of a chunk, see [`ChunkMeta`](crate::ChunkMeta).
+pub type ChunkMeta = BTreeMap<String, String>;
+
+/// The codecs, see [`NCCodec`](crate::NCCodec).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NCCodec {
+    None,
+    Lz4,
+    Zstd(i32),
+}
+
+/// An error of a chunk, see [`NCJobError`](crate::NCJobError).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NCJobError {
+    pub code: u32,
+    pub message: String,
+    pub retryable: bool,
+}
+
+/// The resources of the node in the heartbeat, see [`NCResourceReport`](crate::NCResourceReport).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NCResourceReport {
+    pub free_mem_bytes: Option<u64>,
+    pub free_disk_bytes: Option<u64>,
+    pub load_avg: Option<f64>,
+}
+
+/// A struct in the versioned envelope (see the [`nc_envelope`](crate::nc_envelope) module): the version byte and the bincode body.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NCEnvelope(pub Vec<u8>);
+
+impl NCEnvelope {
+    /// The version of the body, [`None`] if the envelope is empty.
+    pub fn version(&self) -> Option<u8> {
+        self.0.first().copied()
+    }
+
+    /// The body, decode it as [`NCChunkInfo`] for example. A newer version only adds fields at the end, they can be ignored.
+    pub fn body(&self) -> &[u8] {
+        self.0.get(1..).unwrap_or_default()
+    }
+}
+
+/// The chunk info in the envelope of NCJobStatus::Unfinished (version 1), see [`NCChunkInfo`](crate::NCChunkInfo).
+/// It's also the start of every newer version, version 2 adds the attempt (u32) at the end and version 3 the remaining job time
+/// (Option<Duration>) after that, version 4 the id of the result dictionary (Option<u32>).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NCChunkInfo {
+    pub chunk_id: ChunkID,
+    pub deadline: Option<Duration>,
+    pub average_chunk_time: Option<Duration>,
+    pub aggregator: Option<SocketAddr>,
+    pub metadata: Option<ChunkMeta>,
+}
+
+/// The job status, see [`NCJobStatus`](crate::NCJobStatus). The chunk info is in the envelope.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum NCJobStatus<NewDataT> {
+    Unfinished(NewDataT, NCEnvelope),
+    Waiting,
+    Finished,
+}
+
+/// The messages from the node to the server up to CustomMessage, see the messages in the [`nc_message`](crate::nc_message) module.
+/// A small node registers without settings (the last field of Register), so the server doesn't check them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum NCNodeMessage<ProcessedDataT, CustomMessageT> {
+    /// The codecs, the build, the aggregator port, the aggregator tag, the groups and the settings in the envelope.
+    Register(Vec<NCCodec>, Option<String>, Option<u16>, Option<String>, Vec<String>, Option<NCEnvelope>),
+    NeedsData(NodeID),
+    HasData(NodeID, ProcessedDataT),
+    Empty(NodeID),
+    Skip(NodeID, String),
+    NodeFailed(NodeID, NCJobError),
+    HeartBeat(NodeID, Option<NCResourceReport>),
+    CheckHeartbeat,
+    GetStatistics,
+    ShutDown,
+    NewServer(String, u16),
+    NodeMigrated(NodeID),
+    CustomMessage(CustomMessageT, Option<NodeID>),
+}
+
+/// The messages from the server to the node up to UnknownJob, see [`NCServerMessage`](crate::NCServerMessage).
+/// The later ones are not sent to a node that registers without settings and doesn't measure its clock.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum NCServerMessage<InitialDataT, NewDataT, CustomMessageT> {
+    InitialData(NodeID, Option<InitialDataT>, NCCodec),
+    NoCommonCodec(Vec<NCCodec>),
+    JobStatus(NCJobStatus<NewDataT>),
+    /// Only sent to admin tools, the statistics are not decoded.
+    Statistics,
+    NewServer(String, u16),
+    CustomMessage(CustomMessageT),
+    Status(NCJobStatus<()>),
+    AdminAck,
+    Unauthorized,
+    ResultAck { job_finished: bool },
+    ResultRejected(NCJobError),
+    ServerFailed(NCJobError),
+    RotateKey(String),
+    PleaseRestart { reason: String },
+    UnknownJob(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::vec;
+
+    #[test]
+    fn test_frame_header() {
+        let header = NCFrameHeader { job_id: Some("render"), codec: CODEC_LZ4, type_hash: true, segments: false, plain: false, dictionary: None };
+        let frame = encode_frame(&header, &[1, 2, 3]);
+        assert_eq!(frame, vec![12, 0, 0, 0, 0, 0, 0, 0, JOB_TAG, 6, b'r', b'e', b'n', b'd', b'e', b'r', 0x81, 1, 2, 3]);
+        assert_eq!(frame_len(&frame).unwrap(), 12);
+        assert_eq!(NCFrameHeader::parse(&frame[LENGTH_LEN..]).unwrap(), (header, &[1_u8, 2, 3][..]));
+
+        let header = NCFrameHeader::new(CODEC_NONE);
+        assert_eq!(encode_frame(&header, &[]), vec![1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(NCFrameHeader::parse(&[SEGMENTS_FLAG | CODEC_ZSTD, 9]).unwrap().0.segments);
+        assert!(NCFrameHeader::parse(&[PLAIN_FLAG, 9]).unwrap().0.plain);
+
+        let header = NCFrameHeader { dictionary: Some(0x0102_0304), ..NCFrameHeader::new(CODEC_ZSTD) };
+        let frame = encode_frame(&header, &[9]);
+        assert_eq!(frame, vec![6, 0, 0, 0, 0, 0, 0, 0, DICTIONARY_FLAG | CODEC_ZSTD, 4, 3, 2, 1, 9]);
+        assert_eq!(NCFrameHeader::parse(&frame[LENGTH_LEN..]).unwrap(), (header, &[9_u8][..]));
+        assert_eq!(NCFrameHeader::parse(&[DICTIONARY_FLAG | CODEC_ZSTD, 4, 3]), Err(NCProtoError::Truncated("dictionary id")));
+        assert_eq!(NCFrameHeader::parse(&[DICTIONARY_FLAG | CODEC_LZ4, 4, 3, 2, 1]), Err(NCProtoError::UnknownCodec(Some(DICTIONARY_FLAG | CODEC_LZ4))));
+
+        assert_eq!(frame_len(&[1, 2]), Err(NCProtoError::Truncated("length")));
+        assert_eq!(NCFrameHeader::parse(&[]), Err(NCProtoError::UnknownCodec(None)));
+        assert_eq!(NCFrameHeader::parse(&[3]), Err(NCProtoError::UnknownCodec(Some(3))));
+        assert_eq!(NCFrameHeader::parse(&[JOB_TAG, 4, b'a']), Err(NCProtoError::Truncated("job id")));
+        assert_eq!(NCFrameHeader::parse(&[JOB_TAG, 1, 0xC3, 0]), Err(NCProtoError::InvalidJobId));
+        assert_eq!(type_hash("&u64"), fnv1a(b"u64"));
+    }
+
+    #[cfg(feature = "net")]
+    #[cfg(feature = "core")]
+    #[test]
+    fn test_same_layout() {
+        use crate::nc_communicator::NCCommunicator;
+        use crate::nc_config::NCConfiguration;
+        use crate::nc_message::{self, NCNodeMessage as FullNodeMessage};
+        use crate::nc_node_info::NodeID as FullNodeID;
+
+        // Encoded by the node code: the header and the message can be read here
+        let config = NCConfiguration { encrypt: false, compress: false, job_id: Some("render".to_string()), type_check: true, ..Default::default() };
+        let mut nc_communicator = NCCommunicator::new(&config).unwrap();
+        let node_id = FullNodeID::random();
+        let mut frame = Vec::new();
+        nc_communicator.nc_send_data2(&FullNodeMessage::<u32, ()>::HasData(node_id, 7), &mut frame).unwrap();
+
+        let (header, data) = NCFrameHeader::parse(&frame[LENGTH_LEN..]).unwrap();
+        assert_eq!(frame_len(&frame).unwrap() as usize, frame.len() - LENGTH_LEN);
+        assert_eq!((header.job_id, header.codec, header.type_hash), (Some("render"), CODEC_NONE, true));
+        assert_eq!(data[..4], type_hash(core::any::type_name::<u32>()).to_le_bytes());
+        let message: NCNodeMessage<u32, ()> = bincode::deserialize(&data[4..]).unwrap();
+        assert!(matches!(message, NCNodeMessage::HasData(_, 7)));
+        assert_eq!(bincode::serialize(&message).unwrap(), data[4..]);
+
+        // Encoded here: the server code decodes it
+        let message: NCNodeMessage<u32, ()> = NCNodeMessage::Register(vec![NCCodec::None], Some("esp32".to_string()), None, None, Vec::new(), None);
+        let frame = encode_frame(&NCFrameHeader::new(CODEC_NONE), &bincode::serialize(&message).unwrap());
+        let decoded: FullNodeMessage<u32, ()> = nc_communicator.nc_decode_message(&frame[LENGTH_LEN..]).unwrap();
+        assert!(matches!(decoded, FullNodeMessage::Register(codecs, Some(build), None, None, _, None) if codecs == [crate::NCCodec::None] && build == "esp32"));
+
+        let message: NCNodeMessage<(), ()> = NCNodeMessage::HeartBeat(NodeID(5), Some(NCResourceReport { free_mem_bytes: Some(1), ..Default::default() }));
+        let decoded: FullNodeMessage<(), ()> = bincode::deserialize(&bincode::serialize(&message).unwrap()).unwrap();
+        assert!(matches!(decoded, FullNodeMessage::HeartBeat(_, Some(report)) if report.free_mem_bytes == Some(1)));
+
+        // A chunk from the server, the chunk info is in the envelope
+        let chunk_info = nc_message::NCChunkInfo { chunk_id: 9, deadline: Some(Duration::from_secs(3)), aggregator: Some("127.0.0.1:9000".parse().unwrap()), ..Default::default() };
+        let answer: nc_message::NCServerMessage<(), Vec<u8>, ()> = nc_message::NCServerMessage::JobStatus(nc_message::NCJobStatus::Unfinished(vec![1, 2], chunk_info));
+        let decoded: NCServerMessage<(), Vec<u8>, ()> = bincode::deserialize(&bincode::serialize(&answer).unwrap()).unwrap();
+        let envelope = match decoded {
+            NCServerMessage::JobStatus(NCJobStatus::Unfinished(data, envelope)) if data == [1, 2] => envelope,
+            message => panic!("unexpected message: {:?}", message),
+        };
+        assert_eq!(envelope.version(), Some(4));
+        let chunk_info: NCChunkInfo = bincode::deserialize(envelope.body()).unwrap();
+        assert_eq!((chunk_info.chunk_id, chunk_info.deadline, chunk_info.aggregator), (9, Some(Duration::from_secs(3)), Some("127.0.0.1:9000".parse().unwrap())));
+
+        let answer: nc_message::NCServerMessage<(), (), ()> = nc_message::NCServerMessage::UnknownJob("other".to_string());
+        let decoded: NCServerMessage<(), (), ()> = bincode::deserialize(&bincode::serialize(&answer).unwrap()).unwrap();
+        assert_eq!(decoded, NCServerMessage::UnknownJob("other".to_string()));
+
+        let answer: nc_message::NCServerMessage<(), (), ()> = nc_message::NCServerMessage::ResultAck { job_finished: true };
+        let decoded: NCServerMessage<(), (), ()> = bincode::deserialize(&bincode::serialize(&answer).unwrap()).unwrap();
+        assert_eq!(decoded, NCServerMessage::ResultAck { job_finished: true });
+    }
+}
@@ -0,0 +1,260 @@
+//! This module contains a ready made server and node for jobs of the form "for i in 0..n compute f(i)".
+//! The range is split up into batches, every batch is one chunk in a [`ChunkList`]. The node calls the user function for every number
+//! of its batch and sends all the results back, the server folds them into an accumulator with a user closure:
+//!
+//! ```no_run
+//! use node_crunch::{NCConfiguration, NCServerStarter, NCNodeStarter, RangeServer, RangeNode};
+//!
+//! // On the server: sum up the squares
+//! let server = RangeServer::new(0..10_000_000, 10_000, 0u64, |sum: &mut u64, _range, squares: &[u64]| *sum += squares.iter().sum::<u64>());
+//! let sum = server.accumulator();
+//! NCServerStarter::new(NCConfiguration::default()).start(server).unwrap();
+//! println!("Sum: {}", sum.lock().unwrap());
+//!
+//! // On every node
+//! NCNodeStarter::new(NCConfiguration::default()).start(RangeNode::new(|i| i * i)).unwrap();
+//! ```
+//!
+//! This is the easiest way to use node_crunch. If the data has to be split up in another way, the results are big or
+//! the nodes need initial data, implement the [`NCServer`] and [`NCNode`] traits yourself.
+
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+use crate::nc_error::{NCError, NCJobError};
+use crate::nc_node_info::NodeID;
+use crate::nc_server::{NCServer, ChunkAssignment};
+use crate::nc_node::{NCNode, NodeResult};
+use crate::array2d::{ChunkList, ChunkID};
+
+/// The numbers of one batch, this is sent from the [`RangeServer`] to the [`RangeNode`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RangeBatch {
+    /// The id of the chunk.
+    pub chunk_id: ChunkID,
+    /// The numbers to process.
+    pub range: Range<u64>,
+}
+
+/// The results of one batch, `values[n]` is the result for `range.start + n`. This is sent from the [`RangeNode`] back to the [`RangeServer`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RangeResults<R> {
+    /// The id of the chunk.
+    pub chunk_id: ChunkID,
+    /// One result for every number of the batch.
+    pub values: Vec<R>,
+}
+
+/// A server that splits a range of numbers into batches and folds the results of every batch into an accumulator.
+/// The accumulator is shared (see [`accumulator()`](RangeServer::accumulator)), so that it can still be read after the server has been moved
+/// into [`NCServerStarter::start()`](crate::NCServerStarter::start).
+pub struct RangeServer<R, A, F> {
+    chunk_list: ChunkList<Range<u64>>,
+    accumulator: Arc<Mutex<A>>,
+    fold: F,
+    results: PhantomData<fn() -> R>,
+}
+
+impl<R, A, F> RangeServer<R, A, F> where F: FnMut(&mut A, Range<u64>, &[R]) {
+    /// Splits the range into batches of batch_size numbers (the last one may be smaller). For every batch that a node has processed
+    /// fold() is called with the accumulator, the numbers of the batch and their results. The batches are folded in the order the results arrive,
+    /// unless ordered_results is set in the NCConfiguration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if batch_size is 0.
+    pub fn new(range: Range<u64>, batch_size: u64, initial: A, fold: F) -> Self {
+        debug!("RangeServer::new()");
+
+        assert!(batch_size > 0, "batch_size must be greater than 0");
+
+        let mut chunk_list = ChunkList::new();
+        let mut start = range.start;
+
+        while start < range.end {
+            let end = range.end.min(start.saturating_add(batch_size));
+            chunk_list.push(start..end);
+            start = end;
+        }
+
+        RangeServer { chunk_list, accumulator: Arc::new(Mutex::new(initial)), fold, results: PhantomData }
+    }
+
+    /// Returns the accumulator, it contains the final result after the job is finished.
+    pub fn accumulator(&self) -> Arc<Mutex<A>> {
+        self.accumulator.clone()
+    }
+
+    /// Returns the batches that have failed permanently (a node has sent a non retryable error for them), they are not part of the accumulator.
+    pub fn failed_batches(&self) -> Vec<Range<u64>> {
+        self.chunk_list.failed_chunks().map(|chunk_id| self.chunk_list.chunks()[chunk_id as usize].data.clone()).collect()
+    }
+}
+
+impl<R, A, F> NCServer for RangeServer<R, A, F> where R: Serialize + DeserializeOwned + Send + 'static, F: FnMut(&mut A, Range<u64>, &[R]) {
+    type InitialDataT = ();
+    type NewDataT = RangeBatch;
+    type ProcessedDataT = RangeResults<R>;
+    type CustomMessageT = ();
+
+    fn assign_chunk(&mut self, node_id: NodeID) -> Result<ChunkAssignment<RangeBatch>, NCError> {
+        if let Some((chunk_id, chunk)) = self.chunk_list.assign_next_chunk(node_id) {
+            return Ok(ChunkAssignment::Assigned(chunk_id, RangeBatch { chunk_id, range: chunk.data.clone() }))
+        }
+
+        let (done, total) = self.chunk_list.progress();
+
+        if done == total {
+            Ok(ChunkAssignment::Finished)
+        } else {
+            Ok(ChunkAssignment::Waiting)
+        }
+    }
+
+    fn chunk_sent(&mut self, chunk_id: ChunkID) {
+        self.chunk_list.chunk_sent(chunk_id)
+    }
+
+    fn chunk_send_failed(&mut self, chunk_id: ChunkID) {
+        self.chunk_list.chunk_send_failed(chunk_id)
+    }
+
+    fn chunk_rejected(&mut self, chunk_id: ChunkID, requeue: bool) {
+        self.chunk_list.chunk_rejected(chunk_id, requeue)
+    }
+
+    fn chunk_left_out(&mut self, chunk_id: ChunkID) {
+        self.chunk_list.chunk_left_out(chunk_id)
+    }
+
+    /// Every number of the batch must have a result.
+    fn validate(&self, data: &RangeResults<R>) -> Result<(), String> {
+        match self.chunk_list.chunks().get(data.chunk_id as usize) {
+            Some(chunk) if (chunk.data.end - chunk.data.start) as usize == data.values.len() => Ok(()),
+            Some(chunk) => Err(format!("expected {} results for chunk {}, got {}", chunk.data.end - chunk.data.start, data.chunk_id, data.values.len())),
+            None => Err(format!("unknown chunk: {}", data.chunk_id)),
+        }
+    }
+
+    fn process_data_from_node(&mut self, node_id: NodeID, data: &RangeResults<R>) -> Result<(), NCError> {
+        debug!("RangeServer::process_data_from_node()");
+
+        let chunk = self.chunk_list.get(data.chunk_id as usize);
+
+        // A late result for a chunk that has been given to another node in the meantime is dropped
+        if chunk.is_processing(node_id) {
+            chunk.set_finished();
+            let range = chunk.data.clone();
+            (self.fold)(&mut *self.accumulator.lock()?, range, &data.values);
+        }
+
+        Ok(())
+    }
+
+    fn process_node_error(&mut self, node_id: NodeID, error: &NCJobError) {
+        self.chunk_list.chunk_failed(node_id, error.retryable)
+    }
+
+    fn job_progress(&self) -> Option<(u64, u64)> {
+        Some(self.chunk_list.progress())
+    }
+
+    fn job_finished(&self) -> bool {
+        let (done, total) = self.chunk_list.progress();
+        done == total
+    }
+
+    fn heartbeat_timeout(&mut self, nodes: Vec<NodeID>) {
+        self.chunk_list.heartbeat_timeout(&nodes)
+    }
+
+    fn finish_job(&mut self) {
+        debug!("RangeServer::finish_job()");
+    }
+}
+
+/// A node that calls the user function for every number of the batch it gets from the [`RangeServer`].
+pub struct RangeNode<R, F> {
+    function: F,
+    results: PhantomData<fn() -> R>,
+}
+
+impl<R, F> RangeNode<R, F> where F: FnMut(u64) -> R {
+    /// The function is called for every number, its results are sent back to the server.
+    pub fn new(function: F) -> Self {
+        RangeNode { function, results: PhantomData }
+    }
+}
+
+impl<R, F> NCNode for RangeNode<R, F> where R: Serialize + DeserializeOwned, F: FnMut(u64) -> R {
+    type InitialDataT = ();
+    type NewDataT = RangeBatch;
+    type ProcessedDataT = RangeResults<R>;
+    type CustomMessageT = ();
+
+    fn process_data_from_server(&mut self, data: &RangeBatch) -> Result<NodeResult<RangeResults<R>>, NCError> {
+        debug!("RangeNode::process_data_from_server()");
+
+        let values = data.range.clone().map(&mut self.function).collect();
+        Ok(NodeResult::Data(RangeResults { chunk_id: data.chunk_id, values }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::nc_config::NCConfiguration;
+    use crate::nc_local;
+
+    fn test_config() -> NCConfiguration {
+        NCConfiguration { port: 0, delay_request_data: 1, heartbeat: 1, ..Default::default() }
+    }
+
+    type SumFold = fn(&mut u64, Range<u64>, &[u64]);
+
+    fn sum_server(range: Range<u64>, batch_size: u64) -> RangeServer<u64, u64, SumFold> {
+        RangeServer::new(range, batch_size, 0, |sum, _range, values| *sum += values.iter().sum::<u64>())
+    }
+
+    #[test]
+    fn test_batches() {
+        let server = sum_server(3..13, 4);
+        let batches: Vec<Range<u64>> = server.chunk_list.chunks().iter().map(|chunk| chunk.data.clone()).collect();
+        assert_eq!(batches, vec![3..7, 7..11, 11..13]);
+
+        assert!(sum_server(5..5, 4).chunk_list.chunks().is_empty());
+        assert_eq!(sum_server(u64::MAX - 3..u64::MAX, 2).chunk_list.chunks().len(), 2);
+
+        // Results must match the size of the batch
+        assert_eq!(server.validate(&RangeResults { chunk_id: 2, values: vec![1, 2] }), Ok(()));
+        assert!(server.validate(&RangeResults { chunk_id: 2, values: vec![1] }).is_err());
+        assert!(server.validate(&RangeResults { chunk_id: 3, values: Vec::new() }).is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let server = sum_server(0..10_000, 64);
+        let sum = server.accumulator();
+
+        let (server, job_summary) = nc_local::run(&test_config(), server, |_| RangeNode::new(|i| i * i), 3).unwrap();
+
+        assert_eq!(*sum.lock().unwrap(), (0..10_000).map(|i| i * i).sum::<u64>());
+        assert_eq!(job_summary.chunks_sent, 157);
+        assert!(server.failed_batches().is_empty());
+
+        // The results of every number arrive in order, whatever order the batches are folded in
+        let server = RangeServer::new(0..100, 7, Vec::new(), |values: &mut Vec<(u64, String)>, range: Range<u64>, strings: &[String]| {
+            values.extend(range.zip(strings.iter().cloned()))
+        });
+        let (server, _) = nc_local::run(&test_config(), server, |_| RangeNode::new(|i| format!("#{}", i)), 2).unwrap();
+
+        let mut values = server.accumulator().lock().unwrap().clone();
+        values.sort();
+        assert_eq!(values, (0..100).map(|i| (i, format!("#{}", i))).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,227 @@
+//! This module contains the reorder buffer for the server.
+//! If ordered_results is set in the NCConfiguration, the results from the nodes are not put into the result queue
+//! right away. Instead they wait in this buffer until all the results for the chunks with a lower chunk id have arrived,
+//! so that the NCServer trait method process_data_from_node() is called strictly in ascending chunk id order (starting with 0).
+//! If a chunk is missing for too long (max_reorder_wait in the NCConfiguration) the NCServer trait method chunk_gap()
+//! decides if the server should wait longer or skip that chunk.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::nc_config::NCConfiguration;
+use crate::nc_node_info::NodeID;
+use crate::array2d::ChunkID;
+
+/// Buffers results that arrive out of order.
+pub(crate) struct NCReorderBuffer<P> {
+    /// The chunk id that has to be delivered next.
+    next_chunk: ChunkID,
+    /// Results that are waiting for the next chunk. [`None`] means the chunk didn't produce any data.
+    pending: BTreeMap<ChunkID, Option<(NodeID, P)>>,
+    /// If there are more results waiting, the nodes don't get any new data.
+    max_len: usize,
+    /// How long to wait for a missing chunk before chunk_gap() is called.
+    max_wait: Duration,
+    /// Since when the server has been waiting for the next chunk.
+    gap_since: Option<Instant>,
+    /// Number of results that have been dropped because their chunk had already been delivered or skipped.
+    dropped: u64,
+}
+
+impl<P> NCReorderBuffer<P> {
+    /// Creates a new empty reorder buffer with the settings from the given configuration.
+    pub(crate) fn new(config: &NCConfiguration) -> Self {
+        debug!("NCReorderBuffer::new()");
+
+        NCReorderBuffer {
+            next_chunk: 0,
+            pending: BTreeMap::new(),
+            max_len: config.reorder_buffer_max_len,
+            max_wait: Duration::from_secs(config.max_reorder_wait),
+            gap_since: None,
+            dropped: 0,
+        }
+    }
+
+    /// Adds the result for the given chunk and returns all the results that can be delivered now, in chunk order.
+    /// Results for chunks that have already been delivered or skipped are dropped.
+    pub(crate) fn insert(&mut self, chunk_id: ChunkID, result: Option<(NodeID, P)>) -> Vec<(NodeID, P)> {
+        debug!("NCReorderBuffer::insert()");
+
+        if chunk_id < self.next_chunk || self.pending.contains_key(&chunk_id) {
+            warn!("Result for chunk {} has already been delivered or skipped, will be dropped", chunk_id);
+            self.dropped += 1;
+            return Vec::new()
+        }
+
+        self.pending.insert(chunk_id, result);
+        self.drain()
+    }
+
+    /// Returns the number of results that have been dropped by insert().
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Takes all the results out that are in order and updates the time stamp for the next gap.
+    fn drain(&mut self) -> Vec<(NodeID, P)> {
+        let mut ready = Vec::new();
+        let next_chunk = self.next_chunk;
+
+        while let Some(result) = self.pending.remove(&self.next_chunk) {
+            ready.extend(result);
+            self.next_chunk += 1;
+        }
+
+        if self.pending.is_empty() {
+            self.gap_since = None
+        } else if self.gap_since.is_none() || self.next_chunk != next_chunk {
+            self.gap_since = Some(Instant::now())
+        }
+
+        ready
+    }
+
+    /// Returns true if there are too many results waiting, the nodes should not get any new data.
+    pub(crate) fn is_full(&self) -> bool {
+        self.pending.len() >= self.max_len
+    }
+
+    /// Returns the chunk id that is missing if the server has been waiting longer than max_reorder_wait for it.
+    pub(crate) fn gap(&self) -> Option<ChunkID> {
+        match self.gap_since {
+            Some(instant) if instant.elapsed() >= self.max_wait => Some(self.next_chunk),
+            _ => None,
+        }
+    }
+
+    /// Wait another max_reorder_wait for the missing chunk.
+    pub(crate) fn wait_longer(&mut self) {
+        if self.gap_since.is_some() {
+            self.gap_since = Some(Instant::now())
+        }
+    }
+
+    /// Gives up on the missing chunk and returns all the results that can be delivered now.
+    pub(crate) fn skip_gap(&mut self) -> Vec<(NodeID, P)> {
+        warn!("Skip missing chunk: {}", self.next_chunk);
+
+        self.next_chunk += 1;
+        self.drain()
+    }
+
+    /// Returns all the remaining results in chunk order, the missing chunks are skipped.
+    /// This is used when the job is done.
+    pub(crate) fn flush(&mut self) -> Vec<(NodeID, P)> {
+        debug!("NCReorderBuffer::flush()");
+
+        if let Some(chunk_id) = self.pending.keys().next_back() {
+            self.next_chunk = chunk_id + 1;
+        }
+
+        self.gap_since = None;
+        std::mem::take(&mut self.pending).into_values().flatten().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    fn reorder_buffer_for_test(max_len: usize, max_reorder_wait: u64) -> NCReorderBuffer<u32> {
+        let config = NCConfiguration { reorder_buffer_max_len: max_len, max_reorder_wait, ..Default::default() };
+        NCReorderBuffer::new(&config)
+    }
+
+    fn values(results: Vec<(NodeID, u32)>) -> Vec<u32> {
+        results.into_iter().map(|(_, value)| value).collect()
+    }
+
+    #[test]
+    fn test_insert_out_of_order() {
+        let mut reorder_buffer = reorder_buffer_for_test(10, 60);
+        let node_id = NodeID::random();
+
+        assert!(reorder_buffer.insert(2, Some((node_id, 20))).is_empty());
+        assert!(reorder_buffer.insert(1, Some((node_id, 10))).is_empty());
+        assert_eq!(values(reorder_buffer.insert(0, Some((node_id, 0)))), vec![0, 10, 20]);
+        // Chunk 3 is empty
+        assert!(reorder_buffer.insert(4, Some((node_id, 40))).is_empty());
+        assert_eq!(values(reorder_buffer.insert(3, None)), vec![40]);
+        assert_eq!(reorder_buffer.next_chunk, 5);
+        assert!(reorder_buffer.gap_since.is_none());
+    }
+
+    #[test]
+    fn test_duplicate_result() {
+        let mut reorder_buffer = reorder_buffer_for_test(10, 60);
+        let node_id = NodeID::random();
+
+        assert_eq!(values(reorder_buffer.insert(0, Some((node_id, 0)))), vec![0]);
+        assert!(reorder_buffer.insert(0, Some((node_id, 1))).is_empty());
+        assert!(reorder_buffer.insert(2, Some((node_id, 20))).is_empty());
+        assert!(reorder_buffer.insert(2, Some((node_id, 21))).is_empty());
+        assert_eq!(values(reorder_buffer.insert(1, Some((node_id, 10)))), vec![10, 20]);
+        assert_eq!(reorder_buffer.dropped(), 2);
+    }
+
+    #[test]
+    fn test_is_full() {
+        let mut reorder_buffer = reorder_buffer_for_test(2, 60);
+        let node_id = NodeID::random();
+
+        reorder_buffer.insert(1, Some((node_id, 10)));
+        assert!(!reorder_buffer.is_full());
+        reorder_buffer.insert(2, Some((node_id, 20)));
+        assert!(reorder_buffer.is_full());
+        reorder_buffer.insert(0, Some((node_id, 0)));
+        assert!(!reorder_buffer.is_full());
+    }
+
+    #[test]
+    fn test_gap() {
+        let mut reorder_buffer = reorder_buffer_for_test(10, 0);
+        let node_id = NodeID::random();
+
+        assert_eq!(reorder_buffer.gap(), None);
+        reorder_buffer.insert(1, Some((node_id, 10)));
+        reorder_buffer.insert(3, Some((node_id, 30)));
+        assert_eq!(reorder_buffer.gap(), Some(0));
+        assert_eq!(values(reorder_buffer.skip_gap()), vec![10]);
+        assert_eq!(reorder_buffer.gap(), Some(2));
+        assert_eq!(values(reorder_buffer.skip_gap()), vec![30]);
+        assert_eq!(reorder_buffer.gap(), None);
+        // Late result for a skipped chunk
+        assert!(reorder_buffer.insert(0, Some((node_id, 0))).is_empty());
+    }
+
+    #[test]
+    fn test_wait_longer() {
+        let mut reorder_buffer = reorder_buffer_for_test(10, 1);
+        let node_id = NodeID::random();
+
+        reorder_buffer.insert(1, Some((node_id, 10)));
+        assert_eq!(reorder_buffer.gap(), None);
+        thread::sleep(Duration::from_millis(1100));
+        assert_eq!(reorder_buffer.gap(), Some(0));
+        reorder_buffer.wait_longer();
+        assert_eq!(reorder_buffer.gap(), None);
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut reorder_buffer = reorder_buffer_for_test(10, 60);
+        let node_id = NodeID::random();
+
+        reorder_buffer.insert(5, Some((node_id, 50)));
+        reorder_buffer.insert(2, Some((node_id, 20)));
+        reorder_buffer.insert(3, None);
+        assert_eq!(values(reorder_buffer.flush()), vec![20, 50]);
+        assert!(reorder_buffer.pending.is_empty());
+        assert_eq!(reorder_buffer.next_chunk, 6);
+    }
+}
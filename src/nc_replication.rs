@@ -0,0 +1,550 @@
+//! This module contains the warm standby: a second server process that keeps a copy of the state of the chunks, so that it can
+//! continue the job if the server dies. If replicate_to is set in the NCConfiguration the server calls the NCServer trait method
+//! checkpoint() every replication_interval_ms milliseconds (see the [`nc_checkpoint`](crate::nc_checkpoint) module) and sends the changes
+//! since the last update to the standby on replication_port, over the same framed and encrypted protocol as the nodes.
+//! The first update (and the first one after an error) is the whole checkpoint, after that only the chunks whose state has changed
+//! are sent, their data only if it has changed too. The metadata of the results that have been accepted is sent as well
+//! (see [`NCAcceptedResult`]), the results themselves are not.
+//!
+//! The standby only listens on replication_port and answers none of the node messages, so it can't hand out chunks while the server is
+//! still running. It starts serving when it's promoted with the admin command Promote ([`NCClient::promote()`](crate::NCClient::promote)):
+//!
+//! ```no_run
+//! # #[cfg(feature = "net")] {
+//! use node_crunch::{NCConfiguration, NCStandby, ChunkList};
+//!
+//! let config = NCConfiguration { checkpoint_file: Some("job.checkpoint".into()), ..Default::default() };
+//!
+//! // Waits for the updates from the server until an admin sends Promote to replication_port
+//! let checkpoint = NCStandby::new(config.clone()).unwrap().wait_for_promotion().unwrap();
+//! let chunk_list: ChunkList<Vec<u64>> = checkpoint.restore().unwrap();
+//! // Create the server with the chunk list and start it on this machine: NCServerStarter::new(config).start(my_server)
+//! # }
+//! ```
+//!
+//! On promotion the standby writes the replicated state to the checkpoint_file, so the server started with the same configuration
+//! takes over the nodes and the offline batches like after a restart. The nodes find it with the multi-address failover
+//! (see [`NCServerAddr`](crate::NCServerAddr)): if the address of the server is a host name that resolves to both machines,
+//! the nodes connect to the one that accepts connections.
+
+#[cfg(feature = "net")]
+use std::mem;
+#[cfg(feature = "net")]
+use std::time::SystemTime;
+#[cfg(feature = "net")]
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+#[cfg(feature = "net")]
+use std::sync::{Mutex, atomic::{AtomicBool, Ordering}};
+#[cfg(feature = "net")]
+use std::time::Duration;
+
+#[cfg(feature = "net")]
+use log::{error, warn, info, debug};
+use serde::{Serialize, Deserialize};
+
+#[cfg(feature = "net")]
+use crate::nc_error::NCJobError;
+#[cfg(feature = "net")]
+use crate::nc_error::NCError;
+#[cfg(feature = "net")]
+use crate::nc_client::NCClient;
+#[cfg(feature = "net")]
+use crate::nc_communicator::NCCommunicator;
+#[cfg(feature = "net")]
+use crate::nc_config::NCConfiguration;
+#[cfg(feature = "net")]
+use crate::nc_admin::{NCAdminCommand, NCReplayGuard};
+#[cfg(feature = "net")]
+use crate::nc_message::{NCServerMessage, NCJobStatus, NCNodeMessage};
+#[cfg(feature = "net")]
+use crate::nc_checkpoint::NCCheckpoint;
+#[cfg(feature = "net")]
+use crate::nc_job_summary::{NCNodeContribution, NCDeadChunk};
+#[cfg(feature = "net")]
+use crate::nc_offline::NCSavedOfflineBatch;
+use crate::nc_node_info::NodeID;
+use crate::array2d::{ChunkID, ChunkMeta};
+#[cfg(feature = "net")]
+use crate::array2d::{Chunk, ChunkList};
+
+/// A result that the server has accepted (process_data_from_node() returned Ok), as it's sent to the standby.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NCAcceptedResult {
+    /// The node that has sent the result.
+    pub node_id: NodeID,
+    /// The chunk of the result, [`None`] if the server doesn't know it.
+    pub chunk_id: Option<ChunkID>,
+    /// The metadata of the chunk that came back with the result.
+    pub meta: Option<ChunkMeta>,
+}
+
+/// The chunks that have changed since the last update and the current state of the server.
+#[cfg(feature = "net")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct NCChunkChanges {
+    /// When the server has created the checkpoint for this update.
+    saved_at: SystemTime,
+    /// The chunks with a new state, the data is [`None`] if it hasn't changed.
+    chunks: Vec<(ChunkID, Chunk<Option<Vec<u8>>>)>,
+    /// See [`ChunkList::current_phase()`].
+    current_phase: u32,
+    /// The nodes, the offline batches and the dead-letter list, like in the checkpoint.
+    nodes: Vec<NCNodeContribution>,
+    offline_batches: Vec<NCSavedOfflineBatch>,
+    dead_chunks: Vec<NCDeadChunk>,
+}
+
+#[cfg(feature = "net")]
+impl NCChunkChanges {
+    /// Compares the given checkpoint with the chunks that the standby already has.
+    fn new(previous: &ChunkList<Vec<u8>>, checkpoint: &NCCheckpoint) -> Self {
+        let chunks = checkpoint.chunk_list().chunks().iter().zip(previous.chunks()).enumerate()
+            .filter(|(_, (chunk, old))| chunk != old)
+            .map(|(chunk_id, (chunk, old))| {
+                let data = if chunk.data != old.data { Some(chunk.data.clone()) } else { None };
+                (chunk_id as ChunkID, chunk.with_data(data))
+            })
+            .collect();
+
+        NCChunkChanges {
+            saved_at: checkpoint.saved_at(),
+            chunks,
+            current_phase: checkpoint.chunk_list().current_phase(),
+            nodes: checkpoint.nodes().to_vec(),
+            offline_batches: checkpoint.offline_batches().to_vec(),
+            dead_chunks: checkpoint.dead_chunks().to_vec(),
+        }
+    }
+
+    /// Applies the changes to the checkpoint of the standby.
+    fn apply(self, checkpoint: &mut NCCheckpoint) -> Result<(), NCJobError> {
+        let chunk_list = checkpoint.chunk_list_mut();
+        let num_of_chunks = chunk_list.chunks().len();
+
+        if let Some((chunk_id, _)) = self.chunks.iter().find(|(chunk_id, _)| *chunk_id as usize >= num_of_chunks) {
+            return Err(NCJobError::new(NCJobError::REPLICATION, format!("Unknown chunk {}, the standby server has {} chunks", chunk_id, num_of_chunks), true))
+        }
+
+        for (chunk_id, mut chunk) in self.chunks {
+            let target = chunk_list.get(chunk_id as usize);
+            let data = chunk.data.take().unwrap_or_else(|| mem::take(&mut target.data));
+            *target = chunk.with_data(data);
+        }
+
+        chunk_list.set_current_phase(self.current_phase);
+        checkpoint.set_server_state(self.nodes, self.offline_batches);
+        checkpoint.set_dead_chunks(self.dead_chunks);
+        checkpoint.set_saved_at(self.saved_at);
+        Ok(())
+    }
+}
+
+/// The message from the server to the standby, both variants carry the results accepted since the last update.
+#[cfg(feature = "net")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum NCReplicationUpdate {
+    /// The whole state, the standby replaces its own with it.
+    Snapshot(NCCheckpoint, Vec<NCAcceptedResult>),
+    /// Only the changes since the last update that the standby has acknowledged.
+    Changes(NCChunkChanges, Vec<NCAcceptedResult>),
+}
+
+/// The part of the server that sends the updates to the standby, see replicate_to in the NCConfiguration.
+#[cfg(feature = "net")]
+pub(crate) struct NCReplicator {
+    /// Sends the updates to the standby.
+    nc_client: Mutex<NCClient>,
+    /// The chunks as the standby has them, [`None`] if the next update must be the whole checkpoint.
+    replicated: Mutex<Option<ChunkList<Vec<u8>>>>,
+    /// The results that have been accepted since the last update.
+    accepted: Mutex<Vec<NCAcceptedResult>>,
+    /// The missing checkpoint() has been logged already.
+    no_checkpoint: AtomicBool,
+    /// Time between two updates.
+    interval: Duration,
+}
+
+#[cfg(feature = "net")]
+impl NCReplicator {
+    /// Creates the replicator if replicate_to is set in the configuration.
+    pub(crate) fn new(config: &NCConfiguration) -> Result<Option<Self>, NCError> {
+        debug!("NCReplicator::new()");
+
+        let standby = match &config.replicate_to {
+            Some(standby) => standby,
+            None => return Ok(None),
+        };
+
+        let standby_config = NCConfiguration { address: standby.clone(), port: config.replication_port, ..config.clone() };
+        info!("Replicate the state of the chunks to the standby server {}:{}", standby, config.replication_port);
+
+        Ok(Some(NCReplicator {
+            nc_client: Mutex::new(NCClient::connect(&standby_config)?),
+            replicated: Mutex::new(None),
+            accepted: Mutex::new(Vec::new()),
+            no_checkpoint: AtomicBool::new(false),
+            interval: Duration::from_millis(config.replication_interval_ms),
+        }))
+    }
+
+    /// Time between two updates, see replication_interval_ms in the NCConfiguration.
+    pub(crate) fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// The result will be sent with the next update.
+    pub(crate) fn result_accepted(&self, result: NCAcceptedResult) -> Result<(), NCError> {
+        self.accepted.lock()?.push(result);
+        Ok(())
+    }
+
+    /// Sends the changes between the given checkpoint and the last update to the standby, [`None`] if the server doesn't implement checkpoint().
+    /// If that fails the results are sent again with the next update and the next update is the whole checkpoint.
+    pub(crate) fn replicate(&self, checkpoint: Option<NCCheckpoint>) -> Result<(), NCError> {
+        let checkpoint = match checkpoint {
+            Some(checkpoint) => checkpoint,
+            None => {
+                if !self.no_checkpoint.swap(true, Ordering::Relaxed) {
+                    error!("Replication needs the NCServer trait method checkpoint(), nothing is sent to the standby server");
+                }
+                return Ok(())
+            }
+        };
+
+        debug!("NCReplicator::replicate()");
+
+        let accepted = mem::take(&mut *self.accepted.lock()?);
+        let mut replicated = self.replicated.lock()?;
+
+        let update = match replicated.take() {
+            Some(previous) if previous.chunks().len() == checkpoint.chunk_list().chunks().len() =>
+                NCReplicationUpdate::Changes(NCChunkChanges::new(&previous, &checkpoint), accepted.clone()),
+            _ => NCReplicationUpdate::Snapshot(checkpoint.clone(), accepted.clone()),
+        };
+
+        match self.nc_client.lock()?.replicate(update) {
+            Ok(()) => {
+                *replicated = Some(checkpoint.chunk_list().clone());
+                Ok(())
+            }
+            Err(e) => {
+                self.accepted.lock()?.splice(0..0, accepted);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// The standby server, it takes the updates from the server until it's promoted, see the module documentation.
+#[cfg(feature = "net")]
+pub struct NCStandby {
+    config: NCConfiguration,
+    /// Handles the communication with the server and the admin client.
+    nc_communicator: NCCommunicator,
+    /// Rejects replayed admin messages.
+    replay_guard: NCReplayGuard,
+    /// The replicated state, [`None`] until the first update has arrived.
+    checkpoint: Option<NCCheckpoint>,
+    /// The results that the server has accepted.
+    accepted: Vec<NCAcceptedResult>,
+}
+
+#[cfg(feature = "net")]
+impl NCStandby {
+    /// Creates a new standby server with the given configuration, the key and the admin key must be the same as for the server.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::InvalidKey`] error if the encryption key or one of the previous keys is not valid.
+    pub fn new(config: NCConfiguration) -> Result<Self, NCError> {
+        debug!("NCStandby::new()");
+
+        Ok(NCStandby {
+            nc_communicator: NCCommunicator::new(&config)?,
+            replay_guard: NCReplayGuard::new(&config),
+            checkpoint: None,
+            accepted: Vec::new(),
+            config,
+        })
+    }
+
+    /// Listens on replication_port and applies the updates from the server until the standby is promoted with the admin command Promote.
+    /// Returns the replicated state, it's also written to the checkpoint_file if one is set in the NCConfiguration.
+    /// Promote is only accepted once the first update has arrived.
+    pub fn wait_for_promotion(&mut self) -> Result<NCCheckpoint, NCError> {
+        debug!("NCStandby::wait_for_promotion()");
+
+        let socket_addr = SocketAddr::new(IpAddr::from([0, 0, 0, 0]), self.config.replication_port);
+        let listener = TcpListener::bind(socket_addr)?;
+
+        self.run(listener)
+    }
+
+    /// Applies the updates that arrive on the given listener until the standby is promoted, see wait_for_promotion().
+    pub(crate) fn run(&mut self, listener: TcpListener) -> Result<NCCheckpoint, NCError> {
+        debug!("NCStandby::run()");
+        info!("Standby server waits for updates on port {}", listener.local_addr()?.port());
+
+        for stream in listener.incoming() {
+            match stream.map_err(NCError::from).and_then(|stream| self.handle_connection(stream)) {
+                Ok(Some(checkpoint)) => {
+                    if let Some(checkpoint_file) = &self.config.checkpoint_file {
+                        checkpoint.save(checkpoint_file)?;
+                        info!("Replicated state written: {}", checkpoint_file.display());
+                    }
+
+                    return Ok(checkpoint)
+                }
+                Ok(None) => (),
+                Err(e) => error!("Error in NCStandby::run(): {}", e),
+            }
+        }
+
+        Err(NCError::custom("The standby server has stopped listening"))
+    }
+
+    /// The replicated state, [`None`] until the first update has arrived.
+    pub fn checkpoint(&self) -> Option<&NCCheckpoint> {
+        self.checkpoint.as_ref()
+    }
+
+    /// The results that the server has accepted, in the order they have arrived.
+    /// After a failed update the server sends the results of that update again, so a result may appear twice.
+    pub fn accepted_results(&self) -> &[NCAcceptedResult] {
+        &self.accepted
+    }
+
+    /// Handles one message: an update from the server or an admin command. Returns the replicated state if the standby has been promoted.
+    /// Node messages are not answered, the connection is just closed.
+    fn handle_connection(&mut self, mut stream: TcpStream) -> Result<Option<NCCheckpoint>, NCError> {
+        debug!("NCStandby::handle_connection()");
+
+        let data = NCCommunicator::nc_receive_frame(&mut stream, self.config.max_frame_bytes)?;
+        let message: NCNodeMessage<(), ()> = self.nc_communicator.nc_decode_message(&data)?;
+
+        let answer = match message {
+            NCNodeMessage::Replicate(update) => match self.apply(update) {
+                Ok(()) => NCServerMessage::ResultAck { job_finished: false },
+                Err(job_error) => {
+                    warn!("Could not apply the update from the server: {}", job_error);
+                    NCServerMessage::ServerFailed(job_error)
+                }
+            },
+            NCNodeMessage::Admin(message) => {
+                let command = message.verify(&self.config.admin_key).and_then(|command| self.replay_guard.check(&message).map(|_| command));
+
+                match command {
+                    Ok(NCAdminCommand::Promote) => match &self.checkpoint {
+                        Some(checkpoint) => {
+                            info!("Standby server promoted, {} chunks, {} accepted results", checkpoint.chunk_list().chunks().len(), self.accepted.len());
+                            let answer: NCServerMessage<(), (), ()> = NCServerMessage::AdminAck;
+                            self.nc_communicator.nc_send_data2(&answer, &mut stream)?;
+                            return Ok(Some(checkpoint.clone()))
+                        }
+                        None => NCServerMessage::ServerFailed(NCJobError::new(NCJobError::REPLICATION, "The standby server has no state from the server yet", false)),
+                    },
+                    // Nodes are not served yet
+                    Ok(NCAdminCommand::QueryStatus) => NCServerMessage::Status(NCJobStatus::Waiting),
+                    Ok(command) => NCServerMessage::ServerFailed(NCJobError::new(NCJobError::REPLICATION,
+                        format!("The standby server only accepts QueryStatus and Promote, not {:?}", command), false)),
+                    Err(e) => {
+                        error!("Rejected admin message from {}: {}", stream.peer_addr()?, e);
+                        NCServerMessage::Unauthorized
+                    }
+                }
+            }
+            _ => {
+                warn!("The standby server doesn't serve nodes until it's promoted, message from {} ignored", stream.peer_addr()?);
+                return Ok(None)
+            }
+        };
+
+        let answer: NCServerMessage<(), (), ()> = answer;
+        self.nc_communicator.nc_send_data2(&answer, &mut stream)?;
+        Ok(None)
+    }
+
+    /// Applies the update from the server to the replicated state.
+    fn apply(&mut self, update: NCReplicationUpdate) -> Result<(), NCJobError> {
+        debug!("NCStandby::apply()");
+
+        match update {
+            NCReplicationUpdate::Snapshot(checkpoint, accepted) => {
+                info!("Standby server got the whole state, {} chunks", checkpoint.chunk_list().chunks().len());
+                self.checkpoint = Some(checkpoint);
+                self.accepted.extend(accepted);
+            }
+            NCReplicationUpdate::Changes(changes, accepted) => {
+                let checkpoint = self.checkpoint.as_mut()
+                    .ok_or_else(|| NCJobError::new(NCJobError::REPLICATION, "The standby server has no state yet, send the whole state", true))?;
+                changes.apply(checkpoint)?;
+                self.accepted.extend(accepted);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use crate::nc_server::{NCServer, ChunkAssignment};
+    use crate::nc_node::{NCNode, NodeResult};
+    use crate::nc_client::NCClient;
+    use crate::nc_job_summary::NCJobEndReason;
+    use crate::nc_local;
+
+    const ADMIN_KEY: &str = "ZTXbsBVhz9tDzDhklykVDUXznjonhGil";
+
+    /// Adds up the numbers of the chunks, the node doubles them.
+    struct SumServer {
+        chunk_list: ChunkList<u64>,
+        sum: u64,
+        processed: Vec<ChunkID>,
+    }
+
+    impl NCServer for SumServer {
+        type InitialDataT = ();
+        type NewDataT = (ChunkID, u64);
+        type ProcessedDataT = (ChunkID, u64);
+        type CustomMessageT = ();
+
+        fn assign_chunk(&mut self, node_id: NodeID) -> Result<ChunkAssignment<(ChunkID, u64)>, NCError> {
+            if let Some((chunk_id, chunk)) = self.chunk_list.assign_next_chunk(node_id) {
+                return Ok(ChunkAssignment::Assigned(chunk_id, (chunk_id, chunk.data)))
+            }
+
+            let (finished, total) = self.chunk_list.progress();
+            Ok(if finished == total { ChunkAssignment::Finished } else { ChunkAssignment::Waiting })
+        }
+
+        fn process_data_from_node(&mut self, node_id: NodeID, data: &(ChunkID, u64)) -> Result<(), NCError> {
+            let chunk = self.chunk_list.get(data.0 as usize);
+
+            if chunk.is_processing(node_id) {
+                chunk.set_finished();
+                self.sum += data.1;
+                self.processed.push(data.0);
+            }
+
+            Ok(())
+        }
+
+        fn checkpoint(&mut self) -> Result<Option<NCCheckpoint>, NCError> {
+            NCCheckpoint::new(&self.chunk_list).map(Some)
+        }
+
+        fn heartbeat_timeout(&mut self, nodes: Vec<NodeID>) {
+            self.chunk_list.heartbeat_timeout(&nodes);
+        }
+
+        fn finish_job(&mut self) {}
+    }
+
+    struct DoubleNode;
+
+    impl NCNode for DoubleNode {
+        type InitialDataT = ();
+        type NewDataT = (ChunkID, u64);
+        type ProcessedDataT = (ChunkID, u64);
+        type CustomMessageT = ();
+
+        fn process_data_from_server(&mut self, data: &(ChunkID, u64)) -> Result<NodeResult<(ChunkID, u64)>, NCError> {
+            Ok(NodeResult::Data((data.0, data.1 * 2)))
+        }
+    }
+
+    fn chunk_list(numbers: u64) -> ChunkList<u64> {
+        let mut chunk_list = ChunkList::new();
+
+        for i in 0..numbers {
+            chunk_list.push(i);
+        }
+
+        chunk_list
+    }
+
+    #[test]
+    fn test_chunk_changes() {
+        let mut chunk_list = chunk_list(4);
+        let mut standby = NCCheckpoint::new(&chunk_list).unwrap();
+        let previous = standby.chunk_list().clone();
+
+        chunk_list.assign_next_chunk(NodeID::random());
+        chunk_list.get(0).set_finished();
+        chunk_list.get(3).data = 30;
+        let checkpoint = NCCheckpoint::new(&chunk_list).unwrap();
+
+        // Only the changed chunks are sent, the data only if it has changed
+        let changes = NCChunkChanges::new(&previous, &checkpoint);
+        assert_eq!(changes.chunks.iter().map(|(chunk_id, chunk)| (*chunk_id, chunk.data.is_some())).collect::<Vec<_>>(), vec![(0, false), (3, true)]);
+
+        changes.apply(&mut standby).unwrap();
+        assert_eq!(standby.chunk_list(), checkpoint.chunk_list());
+        assert_eq!(standby.restore::<u64>().unwrap().chunks()[3].data, 30);
+
+        // A standby with another chunk list needs the whole state
+        let mut small = NCCheckpoint::new(&ChunkList::<u64>::new()).unwrap();
+        let error = NCChunkChanges::new(&previous, &checkpoint).apply(&mut small).unwrap_err();
+        assert_eq!(error.code, NCJobError::REPLICATION);
+        assert!(error.retryable);
+    }
+
+    #[test]
+    fn test_standby_promotion() {
+        let config = NCConfiguration { port: 0, delay_request_data: 1, heartbeat: 1, admin_key: ADMIN_KEY.to_string(), ..Default::default() };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let replication_port = listener.local_addr().unwrap().port();
+        let standby_config = NCConfiguration { replication_port, ..config.clone() };
+
+        let standby = thread::spawn(move || {
+            let mut standby = NCStandby::new(standby_config).unwrap();
+            let checkpoint = standby.run(listener);
+            (standby, checkpoint)
+        });
+
+        // The standby doesn't serve nodes and can't be promoted without state
+        let client_config = NCConfiguration { port: replication_port, ..config.clone() };
+        assert!(NCClient::connect(&client_config).unwrap().register::<()>().is_err());
+        let mut admin_client = NCClient::connect(&client_config).unwrap();
+        assert!(matches!(admin_client.query_status(), Ok(NCJobStatus::Waiting)));
+        assert!(matches!(admin_client.promote(), Err(NCError::Job(job_error)) if job_error.code == NCJobError::REPLICATION));
+
+        // The server stops in the middle of the job
+        let primary_config = NCConfiguration {
+            replicate_to: Some("127.0.0.1".to_string()),
+            replication_port,
+            replication_interval_ms: 20,
+            max_job_duration: Some(Duration::from_millis(200)),
+            job_drain_timeout: 0,
+            finish_linger_ms: 100,
+            ..config.clone()
+        };
+        let (first_run, job_summary) = nc_local::run(&primary_config, SumServer { chunk_list: chunk_list(5000), sum: 0, processed: Vec::new() }, |_| DoubleNode, 4).unwrap();
+        assert_eq!(job_summary.end_reason, NCJobEndReason::TimeBudgetExhausted);
+        assert!(first_run.processed.len() < 5000);
+
+        admin_client.promote().unwrap();
+        let (standby, checkpoint) = standby.join().unwrap();
+        let checkpoint = checkpoint.unwrap();
+
+        // The standby has the state of the server when it stopped
+        assert_eq!(checkpoint.chunk_list().stats().2 as usize, first_run.processed.len());
+        assert_eq!(checkpoint.nodes().len(), 4);
+        assert_eq!(standby.accepted_results().len(), first_run.processed.len());
+        assert!(standby.accepted_results().iter().filter_map(|result| result.chunk_id).all(|chunk_id| first_run.processed.contains(&chunk_id)));
+
+        // The promoted server finishes the job, no chunk is lost or processed twice
+        let second_run = SumServer { chunk_list: checkpoint.restore().unwrap(), ..first_run };
+        let (second_run, job_summary) = nc_local::run(&config, second_run, |_| DoubleNode, 4).unwrap();
+        assert_eq!(job_summary.end_reason, NCJobEndReason::Finished);
+        let mut processed = second_run.processed.clone();
+        processed.sort_unstable();
+        assert_eq!(processed, (0..5000).collect::<Vec<ChunkID>>());
+        assert_eq!(second_run.sum, (0..5000).map(|i| i * 2).sum::<u64>());
+    }
+}
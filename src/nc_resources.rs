@@ -0,0 +1,101 @@
+//! This module contains the resource report that a node sends with its heartbeat if report_resources is set in the NCConfiguration.
+//! Nodes on shared machines may not be able to take another big chunk because other programs use the memory or the disk.
+//! The server keeps the latest report of every node and passes it to the NCServer trait method assign_chunk_with_context(),
+//! so the user code can size or skip the chunk. If the free memory or disk space is below min_node_free_mem / min_node_free_disk
+//! the server doesn't call the user code at all and the node gets a NCJobStatus::Waiting.
+//! The free disk space is always measured (in the scratch folder of the node), the free memory and the load average
+//! need the resources feature (using the sysinfo crate).
+
+use std::fmt::{self, Display, Formatter};
+#[cfg(feature = "net")]
+use std::path::Path;
+
+#[cfg(feature = "net")]
+use log::debug;
+use serde::{Serialize, Deserialize};
+
+/// The resources of a node at the time of its last heartbeat. A value is [`None`] if it could not be measured.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NCResourceReport {
+    /// Free memory in bytes, only with the resources feature.
+    pub free_mem_bytes: Option<u64>,
+    /// Free space in the scratch folder in bytes.
+    pub free_disk_bytes: Option<u64>,
+    /// The load average of the last minute, only with the resources feature on unix like systems.
+    pub load_avg: Option<f64>,
+}
+
+impl NCResourceReport {
+    /// Measures the resources of this machine, the free disk space is measured for the given folder.
+    #[cfg(feature = "net")]
+    pub(crate) fn gather(path: &Path) -> Self {
+        debug!("NCResourceReport::gather()");
+
+        let (free_mem_bytes, load_avg) = Self::memory_and_load();
+
+        NCResourceReport {
+            free_mem_bytes,
+            free_disk_bytes: fs2::available_space(path).ok(),
+            load_avg,
+        }
+    }
+
+    #[cfg(feature = "resources")]
+    fn memory_and_load() -> (Option<u64>, Option<f64>) {
+        let mut system = sysinfo::System::new();
+        system.refresh_memory();
+
+        let load_avg = sysinfo::System::load_average().one;
+
+        (Some(system.available_memory()), if cfg!(unix) { Some(load_avg) } else { None })
+    }
+
+    #[cfg(feature = "net")]
+    #[cfg(not(feature = "resources"))]
+    fn memory_and_load() -> (Option<u64>, Option<f64>) {
+        (None, None)
+    }
+
+    /// Returns the reason if the free memory or disk space is below the given limits, 0 = no limit.
+    /// Values that have not been measured are not checked.
+    #[cfg(feature = "net")]
+    pub(crate) fn below_floor(&self, min_free_mem: u64, min_free_disk: u64) -> Option<String> {
+        match (self.free_mem_bytes, self.free_disk_bytes) {
+            (Some(free_mem), _) if free_mem < min_free_mem => Some(format!("free memory: {} bytes, minimum: {} bytes", free_mem, min_free_mem)),
+            (_, Some(free_disk)) if free_disk < min_free_disk => Some(format!("free disk space: {} bytes, minimum: {} bytes", free_disk, min_free_disk)),
+            _ => None,
+        }
+    }
+}
+
+impl Display for NCResourceReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "free memory: {:?} bytes, free disk space: {:?} bytes, load average: {:?}", self.free_mem_bytes, self.free_disk_bytes, self.load_avg)
+    }
+}
+
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gather() {
+        let report = NCResourceReport::gather(&std::env::temp_dir());
+
+        assert!(report.free_disk_bytes.is_some());
+        assert_eq!(report.free_mem_bytes.is_some(), cfg!(feature = "resources"));
+    }
+
+    #[test]
+    fn test_below_floor() {
+        let report = NCResourceReport { free_mem_bytes: Some(1000), free_disk_bytes: Some(5000), load_avg: None };
+
+        assert_eq!(report.below_floor(0, 0), None);
+        assert_eq!(report.below_floor(1000, 5000), None);
+        assert!(report.below_floor(1001, 0).unwrap().starts_with("free memory: 1000 bytes"));
+        assert!(report.below_floor(0, 5001).unwrap().starts_with("free disk space: 5000 bytes"));
+
+        // Values that have not been measured are not checked
+        assert_eq!(NCResourceReport::default().below_floor(1000, 1000), None);
+    }
+}
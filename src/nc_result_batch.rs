@@ -0,0 +1,214 @@
+//! This module contains the result batcher for the server.
+//! If result_batch_size is set in the NCConfiguration, the results from the nodes don't go into the result queue one by one.
+//! Instead they are collected into batches and the NCServer trait method process_result_batch() is called once per batch.
+//! A batch is processed when it has result_batch_size results, when its results have at least result_batch_bytes bytes or
+//! when its first result has waited for result_batch_ms milliseconds, whatever comes first.
+//!
+//! The nodes get the NCServerMessage::ResultAck message for their results only after process_result_batch() has returned,
+//! so a result that has been acknowledged has also been stored. A result store that writes to a file can sync it once per batch
+//! instead of once per result, this is where most of the time goes for jobs with very many tiny results.
+//! The server itself doesn't keep a write-ahead log for the results, its own state is saved with the checkpoint (see checkpoint_interval).
+
+#[cfg(feature = "net")]
+use std::sync::{Mutex, Condvar};
+#[cfg(feature = "net")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "net")]
+use log::{debug, info};
+
+#[cfg(feature = "net")]
+use crate::nc_error::NCError;
+#[cfg(feature = "net")]
+use crate::nc_config::NCConfiguration;
+use crate::nc_node_info::NodeID;
+use crate::array2d::{ChunkID, ChunkMeta};
+
+/// One result in a batch for the NCServer trait method process_result_batch().
+#[derive(Debug, Clone, PartialEq)]
+pub struct NCBatchedResult<P> {
+    /// The node that has sent the result.
+    pub node_id: NodeID,
+    /// The chunk of the result.
+    pub chunk_id: ChunkID,
+    /// The metadata of the chunk, if any.
+    pub meta: Option<ChunkMeta>,
+    /// The result itself.
+    pub data: P,
+}
+
+/// The part of the batcher that is protected by the mutex.
+#[cfg(feature = "net")]
+struct ResultBatchInner<E> {
+    /// The results of the current batch in the order they have arrived.
+    entries: Vec<E>,
+    /// Number of bytes of all the results in the current batch.
+    bytes: u64,
+    /// When the first result of the current batch has arrived.
+    since: Option<Instant>,
+    /// No more results will arrive, the last batch will be processed.
+    closed: bool,
+}
+
+/// Collects the results from the nodes into batches.
+#[cfg(feature = "net")]
+pub(crate) struct NCResultBatcher<E> {
+    /// The current batch.
+    inner: Mutex<ResultBatchInner<E>>,
+    /// Signals the consumer that a result has arrived or that the batcher has been closed.
+    not_empty: Condvar,
+    /// Maximum number of results in a batch.
+    max_len: usize,
+    /// Maximum number of bytes of the results in a batch.
+    max_bytes: u64,
+    /// How long the first result of a batch waits for more results.
+    window: Duration,
+}
+
+#[cfg(feature = "net")]
+impl<E> NCResultBatcher<E> {
+    /// Creates a new empty batcher with the settings from the given configuration.
+    pub(crate) fn new(config: &NCConfiguration) -> Self {
+        debug!("NCResultBatcher::new()");
+
+        NCResultBatcher {
+            inner: Mutex::new(ResultBatchInner {
+                entries: Vec::new(),
+                bytes: 0,
+                since: None,
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            max_len: config.result_batch_size,
+            max_bytes: config.result_batch_bytes,
+            window: Duration::from_millis(config.result_batch_ms),
+        }
+    }
+
+    /// Adds a result with the given size in bytes to the current batch.
+    pub(crate) fn push(&self, entry: E, size: u64) -> Result<(), NCError> {
+        debug!("NCResultBatcher::push()");
+
+        let mut inner = self.inner.lock()?;
+
+        if inner.entries.is_empty() {
+            inner.since = Some(Instant::now());
+        }
+
+        inner.bytes += size;
+        inner.entries.push(entry);
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Takes the next batch out of the batcher, blocks until the batch is full or its first result has waited long enough.
+    /// Returns [`None`] if the batcher has been closed and all results have been taken out.
+    pub(crate) fn pop(&self) -> Option<Result<Vec<E>, NCError>> {
+        debug!("NCResultBatcher::pop()");
+
+        let mut inner = match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(e) => return Some(Err(e.into())),
+        };
+
+        loop {
+            let waited = inner.since.map(|since| since.elapsed()).unwrap_or_default();
+            let full = inner.entries.len() >= self.max_len || inner.bytes >= self.max_bytes;
+
+            if !inner.entries.is_empty() && (full || waited >= self.window || inner.closed) {
+                inner.bytes = 0;
+                inner.since = None;
+                return Some(Ok(inner.entries.drain(..).collect()))
+            }
+
+            if inner.closed {
+                return None
+            }
+
+            let next = if inner.entries.is_empty() {
+                self.not_empty.wait(inner).map_err(|_| ())
+            } else {
+                self.not_empty.wait_timeout(inner, self.window - waited).map(|(inner, _)| inner).map_err(|_| ())
+            };
+
+            inner = match next {
+                Ok(inner) => inner,
+                Err(_) => return Some(Err(NCError::poisoned::<ResultBatchInner<E>>("NCResultBatcher::pop"))),
+            };
+        }
+    }
+
+    /// No more results will be added, the consumer processes the last batch and then pop() returns [`None`].
+    pub(crate) fn close(&self) -> Result<(), NCError> {
+        info!("Close result batcher");
+
+        self.inner.lock()?.closed = true;
+        self.not_empty.notify_all();
+        Ok(())
+    }
+
+    /// Returns the number of results in the current batch.
+    pub(crate) fn len(&self) -> Result<usize, NCError> {
+        Ok(self.inner.lock()?.entries.len())
+    }
+}
+
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::thread;
+
+    fn config_for_test(size: usize, bytes: u64, ms: u64) -> NCConfiguration {
+        NCConfiguration {
+            result_batch_size: size,
+            result_batch_bytes: bytes,
+            result_batch_ms: ms,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_batch_full() {
+        let batcher: NCResultBatcher<u32> = NCResultBatcher::new(&config_for_test(3, 1024, 60_000));
+
+        for i in 0..7 {
+            batcher.push(i, 4).unwrap();
+        }
+
+        // A full batch is taken out right away, with all the results that have arrived so far
+        assert_eq!(batcher.pop().unwrap().unwrap(), vec![0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(batcher.len().unwrap(), 0);
+
+        let batcher: NCResultBatcher<u32> = NCResultBatcher::new(&config_for_test(1000, 10, 60_000));
+        batcher.push(1, 8).unwrap();
+        batcher.push(2, 8).unwrap();
+        assert_eq!(batcher.pop().unwrap().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_batch_window() {
+        let batcher: Arc<NCResultBatcher<u32>> = Arc::new(NCResultBatcher::new(&config_for_test(1000, 1024, 50)));
+        let consumer = { let batcher = batcher.clone(); thread::spawn(move || { let start = Instant::now(); (batcher.pop(), start.elapsed()) }) };
+
+        thread::sleep(Duration::from_millis(20));
+        batcher.push(1, 4).unwrap();
+        batcher.push(2, 4).unwrap();
+
+        let (batch, waited) = consumer.join().unwrap();
+        assert_eq!(batch.unwrap().unwrap(), vec![1, 2]);
+        assert!(waited >= Duration::from_millis(70), "{:?}", waited);
+    }
+
+    #[test]
+    fn test_close() {
+        let batcher: NCResultBatcher<u32> = NCResultBatcher::new(&config_for_test(1000, 1024, 60_000));
+        batcher.push(1, 4).unwrap();
+        batcher.close().unwrap();
+
+        // The last batch is processed right away
+        assert_eq!(batcher.pop().unwrap().unwrap(), vec![1]);
+        assert!(batcher.pop().is_none());
+    }
+}
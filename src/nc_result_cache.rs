@@ -0,0 +1,358 @@
+//! This module contains the result cache of the node (result_cache_count in the NCConfiguration).
+//! The node puts every result into the cache before it sends it to the server and removes it again when the server has acknowledged it.
+//! If the connection breaks in between (for example a flaky wifi right after the chunk has been processed), the node first offers the
+//! cached results with the NCNodeMessage::HasCachedData message before it asks for new data. The server only takes a result if the chunk
+//! still belongs to this node, otherwise it has the result already (only the acknowledgement was lost) or the chunk has been given to
+//! another node in the meantime.
+//!
+//! Results that the server doesn't take stay in the cache. Every result is stored with the id and a hash of the data of its chunk
+//! (see [`data_hash()`]), so if the server gives the same chunk with the same data to this node again, the cached result is sent
+//! right away instead of computing it again.
+//!
+//! The cache keeps at most result_cache_count results with at most result_cache_max_bytes bytes, the oldest ones are dropped first.
+//! With result_cache_dir every result is also written to its own file in that folder, so that the cache survives a restart of the node.
+//! Results that are sent to an aggregator and signed results are not cached. HasCachedData is a new message, so the server must be
+//! at least as new as the node.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+use crate::nc_config::NCConfiguration;
+use crate::nc_error::NCError;
+use crate::array2d::{ChunkID, ChunkMeta};
+
+/// The file extension of a cached result in result_cache_dir.
+const CACHE_FILE_EXTENSION: &str = "ncresult";
+
+/// Returns a hash of the serialized data of a chunk: the first 8 bytes of its sha256 hash.
+pub(crate) fn data_hash<D: Serialize>(data: &D) -> Result<u64, NCError> {
+    let mut hasher = Sha256::new();
+    bincode::serialize_into(&mut hasher, data).map_err(NCError::Serialize)?;
+
+    let mut hash = [0; 8];
+    hash.copy_from_slice(&hasher.finalize()[..8]);
+    Ok(u64::from_le_bytes(hash))
+}
+
+/// The result of one chunk in the cache.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct NCCachedResult {
+    /// The id of the chunk.
+    pub(crate) chunk_id: ChunkID,
+    /// The hash of the data of the chunk, see [`data_hash()`].
+    pub(crate) data_hash: u64,
+    /// The metadata of the chunk that is sent back with the result.
+    pub(crate) meta: Option<ChunkMeta>,
+    /// The serialized result (bincode).
+    pub(crate) payload: Vec<u8>,
+    /// The result has already been offered to the server since it was put into the cache or loaded from the folder.
+    #[serde(skip)]
+    offered: bool,
+}
+
+impl NCCachedResult {
+    /// Creates a new cached result that hasn't been offered yet.
+    pub(crate) fn new(chunk_id: ChunkID, data_hash: u64, meta: Option<ChunkMeta>, payload: Vec<u8>) -> Self {
+        NCCachedResult { chunk_id, data_hash, meta, payload, offered: false }
+    }
+}
+
+/// The results of the last chunks that the server has not acknowledged yet, the oldest one first.
+#[derive(Debug)]
+pub(crate) struct NCResultCache {
+    /// Keep at most n results, 0 = the cache is disabled.
+    max_count: usize,
+    /// Keep at most n bytes (sum of the payloads).
+    max_bytes: u64,
+    /// The results are also written to this folder.
+    dir: Option<PathBuf>,
+    /// The sequence number (for the file name) and the result.
+    results: VecDeque<(u64, NCCachedResult)>,
+    /// The sum of the payloads in bytes.
+    bytes: u64,
+    /// The sequence number of the next result.
+    next_seq: u64,
+}
+
+impl NCResultCache {
+    /// Creates the cache with the limits from the given configuration and loads the results from result_cache_dir, if it's set.
+    /// A folder that can't be read only gives a warning, the cache then starts empty.
+    pub(crate) fn new(config: &NCConfiguration) -> Self {
+        debug!("NCResultCache::new()");
+
+        let mut cache = NCResultCache {
+            max_count: config.result_cache_count,
+            max_bytes: config.result_cache_max_bytes,
+            dir: config.result_cache_dir.clone().filter(|_| config.result_cache_count > 0),
+            results: VecDeque::new(),
+            bytes: 0,
+            next_seq: 0,
+        };
+
+        if let Some(dir) = cache.dir.clone() {
+            match cache.load(&dir) {
+                Ok(0) => (),
+                Ok(loaded) => info!("Loaded {} cached results from {}", loaded, dir.display()),
+                Err(e) => warn!("Could not load the cached results from {}: {}", dir.display(), e),
+            }
+        }
+
+        cache
+    }
+
+    /// Returns false if result_cache_count is 0.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.max_count > 0
+    }
+
+    /// The number of cached results.
+    pub(crate) fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    /// The sum of the payloads of all cached results in bytes.
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Puts the result into the cache, an older result for the same chunk is replaced. The oldest results are dropped until
+    /// the cache is within its limits again, a result that is bigger than result_cache_max_bytes is not cached at all.
+    /// A file that can't be written only gives a warning, the result is still kept in memory.
+    pub(crate) fn insert(&mut self, result: NCCachedResult) {
+        if !self.is_enabled() {
+            return
+        }
+
+        debug!("NCResultCache::insert(), chunk: {}, {} bytes", result.chunk_id, result.payload.len());
+
+        self.remove(result.chunk_id);
+
+        if result.payload.len() as u64 > self.max_bytes {
+            debug!("Result of chunk {} is bigger than result_cache_max_bytes, it's not cached", result.chunk_id);
+            return
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        if let Some(dir) = &self.dir {
+            if let Err(e) = write_file(dir, seq, &result) {
+                warn!("Could not write the cached result of chunk {} to {}: {}", result.chunk_id, dir.display(), e);
+            }
+        }
+
+        self.bytes += result.payload.len() as u64;
+        self.results.push_back((seq, result));
+        self.evict();
+    }
+
+    /// Returns the cached result of the given chunk if it has been computed from the same data.
+    pub(crate) fn get(&self, chunk_id: ChunkID, data_hash: u64) -> Option<&NCCachedResult> {
+        self.results.iter().map(|(_, result)| result).find(|result| result.chunk_id == chunk_id && result.data_hash == data_hash)
+    }
+
+    /// Removes the result of the given chunk (for example when the server has acknowledged it), returns false if it's not in the cache.
+    pub(crate) fn remove(&mut self, chunk_id: ChunkID) -> bool {
+        match self.results.iter().position(|(_, result)| result.chunk_id == chunk_id) {
+            Some(index) => {
+                if let Some((seq, result)) = self.results.remove(index) {
+                    self.drop_result(seq, &result);
+                }
+
+                true
+            }
+            None => false
+        }
+    }
+
+    /// The oldest result that hasn't been offered to the server yet.
+    pub(crate) fn next_to_offer(&self) -> Option<&NCCachedResult> {
+        self.results.iter().map(|(_, result)| result).find(|result| !result.offered)
+    }
+
+    /// The server didn't take the result of the given chunk, it's kept but not offered again.
+    pub(crate) fn set_offered(&mut self, chunk_id: ChunkID) {
+        if let Some((_, result)) = self.results.iter_mut().find(|(_, result)| result.chunk_id == chunk_id) {
+            result.offered = true;
+        }
+    }
+
+    /// Drops the oldest results until the cache is within its limits.
+    fn evict(&mut self) {
+        while self.results.len() > self.max_count || self.bytes > self.max_bytes {
+            match self.results.pop_front() {
+                Some((seq, result)) => {
+                    debug!("Cache is full, drop the result of chunk {}", result.chunk_id);
+                    self.drop_result(seq, &result);
+                }
+                None => break
+            }
+        }
+    }
+
+    /// Updates the size and removes the file of a result that has been taken out of the list.
+    fn drop_result(&mut self, seq: u64, result: &NCCachedResult) {
+        self.bytes -= result.payload.len() as u64;
+
+        if let Some(dir) = &self.dir {
+            let path = file_path(dir, seq, result.chunk_id);
+
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Could not remove cached result {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Loads all the results from the folder in the order they have been cached, files that can't be read are removed.
+    /// Returns the number of results.
+    fn load(&mut self, dir: &Path) -> Result<usize, NCError> {
+        fs::create_dir_all(dir)?;
+        let mut files = Vec::new();
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            if path.extension().is_some_and(|extension| extension == CACHE_FILE_EXTENSION) {
+                match read_file(&path) {
+                    Some(file) => files.push(file),
+                    None => {
+                        warn!("Cached result {} can't be read, remove it", path.display());
+                        let _ = fs::remove_file(&path);
+                    }
+                }
+            }
+        }
+
+        files.sort_by_key(|(seq, _)| *seq);
+        self.next_seq = files.last().map_or(0, |(seq, _)| seq + 1);
+        self.bytes = files.iter().map(|(_, result)| result.payload.len() as u64).sum();
+        self.results = files.into();
+        self.evict();
+
+        Ok(self.results.len())
+    }
+}
+
+/// The file of a cached result: the sequence number (so the files sort in the order of the cache) and the chunk id.
+fn file_path(dir: &Path, seq: u64, chunk_id: ChunkID) -> PathBuf {
+    dir.join(format!("{:020}-{}.{}", seq, chunk_id, CACHE_FILE_EXTENSION))
+}
+
+/// Writes the result to a temporary file first, so that a half written file never has the final name.
+fn write_file(dir: &Path, seq: u64, result: &NCCachedResult) -> Result<(), NCError> {
+    fs::create_dir_all(dir)?;
+    let path = file_path(dir, seq, result.chunk_id);
+    let tmp_path = path.with_extension("tmp-result");
+    fs::write(&tmp_path, bincode::serialize(result).map_err(NCError::Serialize)?)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Reads the sequence number from the file name and the result from the file.
+fn read_file(path: &Path) -> Option<(u64, NCCachedResult)> {
+    let name = path.file_name()?.to_str()?;
+    let seq = name.split('-').next()?.parse().ok()?;
+    let result: NCCachedResult = bincode::deserialize(&fs::read(path).ok()?).ok()?;
+
+    (file_path(path.parent()?, seq, result.chunk_id) == path).then_some((seq, result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(chunk_id: ChunkID, bytes: usize) -> NCCachedResult {
+        NCCachedResult::new(chunk_id, chunk_id * 10, None, vec![chunk_id as u8; bytes])
+    }
+
+    #[test]
+    fn test_result_cache() {
+        let mut cache = NCResultCache::new(&NCConfiguration { result_cache_count: 3, result_cache_max_bytes: 100, ..Default::default() });
+
+        for chunk_id in 0..3 {
+            cache.insert(result(chunk_id, 10));
+        }
+
+        // Eviction on ack
+        assert!(cache.remove(1));
+        assert!(!cache.remove(1));
+        assert_eq!((cache.len(), cache.bytes()), (2, 20));
+
+        // Eviction on overflow: count, then bytes
+        cache.insert(result(3, 10));
+        cache.insert(result(4, 10));
+        assert!(cache.get(0, 0).is_none());
+        assert_eq!(cache.get(2, 20), Some(&result(2, 10)));
+        cache.insert(result(5, 85));
+        assert_eq!(cache.results.iter().map(|(_, result)| result.chunk_id).collect::<Vec<_>>(), vec![4, 5]);
+        assert_eq!(cache.bytes(), 95);
+
+        // Too big for the cache
+        cache.insert(result(6, 101));
+        assert!(cache.get(6, 60).is_none());
+        assert_eq!(cache.len(), 2);
+
+        // Only the same data gives the cached result
+        assert!(cache.get(4, 41).is_none());
+
+        // Offered results are kept, but not offered again
+        assert_eq!(cache.next_to_offer().map(|result| result.chunk_id), Some(4));
+        cache.set_offered(4);
+        assert_eq!(cache.next_to_offer().map(|result| result.chunk_id), Some(5));
+        cache.set_offered(5);
+        assert!(cache.next_to_offer().is_none());
+        assert!(cache.get(4, 40).is_some());
+
+        // Disabled
+        let mut cache = NCResultCache::new(&NCConfiguration::default());
+        cache.insert(result(0, 10));
+        assert_eq!(cache.len(), 0);
+
+        assert_eq!(data_hash(&vec![1_u64, 2]).unwrap(), data_hash(&vec![1_u64, 2]).unwrap());
+        assert_ne!(data_hash(&vec![1_u64, 2]).unwrap(), data_hash(&vec![2_u64, 1]).unwrap());
+    }
+
+    #[test]
+    fn test_result_cache_dir() {
+        let dir = std::env::temp_dir().join(format!("nc_result_cache_{}", std::process::id()));
+        let config = NCConfiguration { result_cache_count: 3, result_cache_dir: Some(dir.clone()), ..Default::default() };
+
+        let mut cache = NCResultCache::new(&config);
+        assert_eq!(cache.len(), 0);
+
+        for chunk_id in [7, 3, 5, 8] {
+            cache.insert(result(chunk_id, 10));
+        }
+
+        cache.remove(5);
+        cache.set_offered(3);
+        fs::write(dir.join("broken.ncresult"), b"no result").unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+        // The node has been restarted, the cache is loaded in the same order and all the results are offered again
+        drop(cache);
+        let mut cache = NCResultCache::new(&config);
+        assert_eq!(cache.results.iter().map(|(_, result)| result.chunk_id).collect::<Vec<_>>(), vec![3, 8]);
+        assert_eq!(cache.next_to_offer(), Some(&result(3, 10)));
+        assert!(!dir.join("broken.ncresult").exists());
+
+        // New results come after the loaded ones
+        cache.insert(result(1, 10));
+        cache.insert(result(2, 10));
+        assert_eq!(cache.results.iter().map(|(_, result)| result.chunk_id).collect::<Vec<_>>(), vec![8, 1, 2]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+        // A smaller limit after a restart
+        drop(cache);
+        let cache = NCResultCache::new(&NCConfiguration { result_cache_count: 1, ..config });
+        assert_eq!(cache.results.iter().map(|(_, result)| result.chunk_id).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
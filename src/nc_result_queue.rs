@@ -0,0 +1,375 @@
+//! This module contains the result queue for the server.
+//! Results that arrive from the nodes are put into this queue and a separate thread takes them out
+//! and calls the NCServer trait method process_data_from_node().
+//! The queue has a byte budget (result_queue_max_bytes in the NCConfiguration). If the budget is exceeded the queue either
+//! blocks until there is enough space again (backpressure: the node has to wait for the ResultAck message) or writes the result
+//! to a temporary file which is read back in when the result is taken out of the queue (spill to disk).
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, Condvar};
+use std::path::PathBuf;
+use std::fs;
+use std::process;
+use std::time::Duration;
+
+use log::{debug, info};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::nc_error::NCError;
+use crate::nc_config::{NCConfiguration, NCResultQueueMode};
+use crate::nc_node_info::NodeID;
+
+/// One result in the queue, either in memory or written to disk.
+enum ResultEntry<P> {
+    /// The result is kept in memory, the size in bytes is used for the budget.
+    Memory(NodeID, P, u64),
+    /// The result has been written to the given file.
+    Disk(NodeID, PathBuf),
+}
+
+/// The part of the queue that is protected by the mutex.
+struct ResultQueueInner<P> {
+    /// All the results in the order they have arrived.
+    entries: VecDeque<ResultEntry<P>>,
+    /// Number of bytes of all the results kept in memory.
+    bytes: u64,
+    /// Number of results that are currently written to disk.
+    spilled: u64,
+    /// Used to create unique file names for spilled results.
+    spill_counter: u64,
+    /// No more results will arrive, the queue will be drained.
+    closed: bool,
+    /// Number of results that have been taken out of the queue but not processed yet, see processed().
+    in_progress: u64,
+}
+
+/// The current state of the result queue.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct NCResultQueueStats {
+    /// Number of results waiting to be processed.
+    pub(crate) len: u64,
+    /// Number of bytes of the results waiting in memory.
+    pub(crate) bytes: u64,
+    /// Number of results waiting on disk.
+    pub(crate) spilled: u64,
+}
+
+/// Bounded queue for the results from the nodes.
+pub(crate) struct NCResultQueue<P> {
+    /// The actual queue.
+    inner: Mutex<ResultQueueInner<P>>,
+    /// Signals the consumer that a new result has arrived or that the queue has been closed.
+    not_empty: Condvar,
+    /// Signals the producers that a result has been taken out of the queue.
+    not_full: Condvar,
+    /// Signals wait_drained() that all results have been processed.
+    drained: Condvar,
+    /// The byte budget for results kept in memory.
+    max_bytes: u64,
+    /// What to do if the budget is exceeded.
+    mode: NCResultQueueMode,
+    /// Folder for spilled results.
+    spill_dir: PathBuf,
+}
+
+impl<P: Serialize + DeserializeOwned> NCResultQueue<P> {
+    /// Creates a new empty result queue with the settings from the given configuration.
+    pub(crate) fn new(config: &NCConfiguration) -> Self {
+        debug!("NCResultQueue::new()");
+
+        NCResultQueue {
+            inner: Mutex::new(ResultQueueInner {
+                entries: VecDeque::new(),
+                bytes: 0,
+                spilled: 0,
+                spill_counter: 0,
+                closed: false,
+                in_progress: 0,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            drained: Condvar::new(),
+            max_bytes: config.result_queue_max_bytes,
+            mode: config.result_queue_mode,
+            spill_dir: config.spill_dir.clone(),
+        }
+    }
+
+    /// Adds a new result to the queue. If the byte budget is exceeded this either blocks until there is enough space
+    /// or writes the result to disk, depending on the configuration.
+    /// A single result that is bigger than the budget is always accepted if the queue is empty.
+    pub(crate) fn push(&self, node_id: NodeID, data: P) -> Result<(), NCError> {
+        debug!("NCResultQueue::push()");
+
+        let size = bincode::serialized_size(&data).map_err(NCError::Serialize)?;
+        let mut inner = self.inner.lock()?;
+
+        if inner.bytes + size > self.max_bytes && inner.bytes > 0 {
+            match self.mode {
+                NCResultQueueMode::Backpressure => {
+                    debug!("Result queue is full, wait for the consumer");
+
+                    while inner.bytes + size > self.max_bytes && inner.bytes > 0 && !inner.closed {
+                        inner = self.not_full.wait(inner)?;
+                    }
+                }
+                NCResultQueueMode::SpillToDisk => {
+                    let path = self.spill_dir.join(format!("nc_result_{}_{}.bin", process::id(), inner.spill_counter));
+                    debug!("Result queue is full, write result to disk: {}", path.display());
+
+                    fs::write(&path, bincode::serialize(&data).map_err(NCError::Serialize)?)?;
+                    inner.spill_counter += 1;
+                    inner.spilled += 1;
+                    inner.entries.push_back(ResultEntry::Disk(node_id, path));
+                    self.not_empty.notify_one();
+                    return Ok(())
+                }
+            }
+        }
+
+        inner.bytes += size;
+        inner.entries.push_back(ResultEntry::Memory(node_id, data, size));
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Takes the next result out of the queue, blocks until a result is available.
+    /// Returns [`None`] if the queue has been closed and all results have been taken out.
+    pub(crate) fn pop(&self) -> Option<Result<(NodeID, P), NCError>> {
+        debug!("NCResultQueue::pop()");
+
+        let mut inner = match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(e) => return Some(Err(e.into())),
+        };
+
+        loop {
+            if let Some(entry) = inner.entries.pop_front() {
+                let result = match entry {
+                    ResultEntry::Memory(node_id, data, size) => {
+                        inner.bytes -= size;
+                        Ok((node_id, data))
+                    }
+                    ResultEntry::Disk(node_id, path) => {
+                        inner.spilled -= 1;
+                        Self::read_spilled(&path).map(|data| (node_id, data))
+                    }
+                };
+
+                inner.in_progress += 1;
+                self.not_full.notify_all();
+                return Some(result)
+            }
+
+            if inner.closed {
+                return None
+            }
+
+            inner = match self.not_empty.wait(inner) {
+                Ok(inner) => inner,
+                Err(e) => return Some(Err(e.into())),
+            };
+        }
+    }
+
+    /// Reads a spilled result back in and deletes the file.
+    fn read_spilled(path: &PathBuf) -> Result<P, NCError> {
+        let data = fs::read(path)?;
+        fs::remove_file(path)?;
+        bincode::deserialize(&data).map_err(NCError::Deserialize)
+    }
+
+    /// No more results will be added, the consumer drains the queue and then pop() returns [`None`].
+    pub(crate) fn close(&self) -> Result<(), NCError> {
+        info!("Close result queue");
+
+        self.inner.lock()?.closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+        Ok(())
+    }
+
+    /// The consumer has processed the result it has taken out of the queue last.
+    pub(crate) fn processed(&self) -> Result<(), NCError> {
+        let mut inner = self.inner.lock()?;
+        inner.in_progress = inner.in_progress.saturating_sub(1);
+
+        if inner.entries.is_empty() && inner.in_progress == 0 {
+            self.drained.notify_all();
+        }
+
+        Ok(())
+    }
+
+    /// Blocks until all results in the queue have been taken out and processed (see processed()) or the timeout has passed.
+    /// Returns false if there are still results left after the timeout.
+    pub(crate) fn wait_drained(&self, timeout: Duration) -> Result<bool, NCError> {
+        debug!("NCResultQueue::wait_drained()");
+
+        let (inner, _) = self.drained.wait_timeout_while(self.inner.lock()?, timeout, |inner| !inner.entries.is_empty() || inner.in_progress > 0)
+            .map_err(|_| NCError::poisoned::<ResultQueueInner<P>>("NCResultQueue::wait_drained"))?;
+
+        Ok(inner.entries.is_empty() && inner.in_progress == 0)
+    }
+
+    /// Returns the number of results in the queue, the number of bytes in memory and the number of results on disk.
+    pub(crate) fn stats(&self) -> Result<NCResultQueueStats, NCError> {
+        let inner = self.inner.lock()?;
+
+        Ok(NCResultQueueStats {
+            len: inner.entries.len() as u64,
+            bytes: inner.bytes,
+            spilled: inner.spilled,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    fn config_for_test(mode: NCResultQueueMode, max_bytes: u64, spill_dir: PathBuf) -> NCConfiguration {
+        NCConfiguration {
+            result_queue_max_bytes: max_bytes,
+            result_queue_mode: mode,
+            spill_dir,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_push_pop_in_order() {
+        let config = config_for_test(NCResultQueueMode::Backpressure, 1024, std::env::temp_dir());
+        let queue: NCResultQueue<u32> = NCResultQueue::new(&config);
+        let node_id = NodeID::random();
+
+        queue.push(node_id, 1).unwrap();
+        queue.push(node_id, 2).unwrap();
+        assert_eq!(queue.stats().unwrap(), NCResultQueueStats { len: 2, bytes: 8, spilled: 0 });
+
+        queue.close().unwrap();
+
+        assert_eq!(queue.pop().unwrap().unwrap(), (node_id, 1));
+        assert_eq!(queue.pop().unwrap().unwrap(), (node_id, 2));
+        assert!(queue.pop().is_none());
+        assert_eq!(queue.stats().unwrap(), NCResultQueueStats { len: 0, bytes: 0, spilled: 0 });
+    }
+
+    #[test]
+    fn test_single_result_bigger_than_budget() {
+        let config = config_for_test(NCResultQueueMode::Backpressure, 4, std::env::temp_dir());
+        let queue: NCResultQueue<Vec<u8>> = NCResultQueue::new(&config);
+
+        queue.push(NodeID::random(), vec![0; 100]).unwrap();
+        assert_eq!(queue.stats().unwrap().len, 1);
+    }
+
+    #[test]
+    fn test_wait_drained() {
+        let config = config_for_test(NCResultQueueMode::Backpressure, 1024, std::env::temp_dir());
+        let queue: Arc<NCResultQueue<u32>> = Arc::new(NCResultQueue::new(&config));
+        assert!(queue.wait_drained(Duration::ZERO).unwrap());
+
+        queue.push(NodeID::random(), 1).unwrap();
+        assert!(!queue.wait_drained(Duration::from_millis(10)).unwrap());
+
+        // Taken out of the queue is not enough, the result has to be processed
+        let consumer = {
+            let queue = queue.clone();
+
+            thread::spawn(move || {
+                queue.pop().unwrap().unwrap();
+                thread::sleep(Duration::from_millis(100));
+                queue.processed().unwrap();
+            })
+        };
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(queue.stats().unwrap().len, 0);
+        assert!(!queue.wait_drained(Duration::from_millis(10)).unwrap());
+        assert!(queue.wait_drained(Duration::from_secs(5)).unwrap());
+        consumer.join().unwrap();
+    }
+
+    #[test]
+    fn test_backpressure_slow_consumer() {
+        // Each result is 8 + 100 bytes, so at most two results fit into the budget.
+        let config = config_for_test(NCResultQueueMode::Backpressure, 250, std::env::temp_dir());
+        let queue: Arc<NCResultQueue<Vec<u8>>> = Arc::new(NCResultQueue::new(&config));
+        let max_bytes_seen = Arc::new(AtomicU64::new(0));
+
+        let producers: Vec<_> = (0..8).map(|_| {
+            let queue = queue.clone();
+            let max_bytes_seen = max_bytes_seen.clone();
+
+            thread::spawn(move || {
+                for _ in 0..5 {
+                    queue.push(NodeID::random(), vec![7; 100]).unwrap();
+                    max_bytes_seen.fetch_max(queue.stats().unwrap().bytes, Ordering::Relaxed);
+                }
+            })
+        }).collect();
+
+        let consumer = {
+            let queue = queue.clone();
+
+            thread::spawn(move || {
+                let mut count = 0;
+
+                while let Some(result) = queue.pop() {
+                    assert_eq!(result.unwrap().1, vec![7; 100]);
+                    count += 1;
+                    // Slow consumer
+                    thread::sleep(Duration::from_millis(5));
+                }
+
+                count
+            })
+        };
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        queue.close().unwrap();
+
+        assert_eq!(consumer.join().unwrap(), 40);
+        assert!(max_bytes_seen.load(Ordering::Relaxed) <= 250);
+    }
+
+    #[test]
+    fn test_spill_to_disk() {
+        let spill_dir = std::env::temp_dir().join(format!("nc_test_spill_{}", process::id()));
+        fs::create_dir_all(&spill_dir).unwrap();
+
+        let config = config_for_test(NCResultQueueMode::SpillToDisk, 250, spill_dir.clone());
+        let queue: NCResultQueue<Vec<u8>> = NCResultQueue::new(&config);
+        let node_id = NodeID::random();
+
+        for i in 0..10 {
+            queue.push(node_id, vec![i; 100]).unwrap();
+        }
+
+        let stats = queue.stats().unwrap();
+        assert_eq!(stats.len, 10);
+        assert_eq!(stats.bytes, 216);
+        assert_eq!(stats.spilled, 8);
+        assert_eq!(fs::read_dir(&spill_dir).unwrap().count(), 8);
+
+        queue.close().unwrap();
+
+        for i in 0..10 {
+            assert_eq!(queue.pop().unwrap().unwrap(), (node_id, vec![i; 100]));
+        }
+
+        assert!(queue.pop().is_none());
+        assert_eq!(fs::read_dir(&spill_dir).unwrap().count(), 0);
+
+        fs::remove_dir(&spill_dir).unwrap();
+    }
+}
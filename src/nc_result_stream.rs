@@ -0,0 +1,166 @@
+//! This module contains the NCResultStream, it delivers the results of a job to the server program instead of (or in addition to)
+//! the NCServer trait method process_data_from_node(). Every result that process_data_from_node() has accepted is sent to the stream
+//! together with its chunk id, so the program can pull the results from another thread while the server is running:
+//!
+//! ```no_run
+//! use node_crunch::{NCConfiguration, NCServerStarter, NCResultStream, RangeServer, RangeResults};
+//!
+//! let mut server_starter = NCServerStarter::new(NCConfiguration::default());
+//! let results: NCResultStream<RangeResults<u64>> = server_starter.results(16);
+//!
+//! let writer = std::thread::spawn(move || {
+//!     // Ends when the job is done
+//!     for (chunk_id, result) in results {
+//!         println!("chunk {:?}: {} values", chunk_id, result.values.len());
+//!     }
+//! });
+//!
+//! server_starter.start(RangeServer::new(0..1000, 10, (), |_, _, _: &[u64]| ())).unwrap();
+//! writer.join().unwrap();
+//! ```
+//!
+//! The stream is bounded: if the program doesn't keep up, the result thread of the server waits, the result queue fills up
+//! and the nodes are slowed down (see result_queue_max_bytes in the NCConfiguration). If the stream is dropped the server
+//! keeps going without it. A result goes either to the post processor (see the NCServer trait method post_processor()) or to the stream,
+//! so the server refuses to start with both.
+
+use std::sync::{Mutex, mpsc};
+use std::time::Duration;
+
+use log::{debug, info};
+
+use crate::nc_error::NCError;
+use crate::array2d::ChunkID;
+
+/// A result and the chunk it belongs to, [`None`] if the server doesn't know the chunk.
+pub type NCResultItem<P> = (Option<ChunkID>, P);
+
+/// The results of the job, see [`NCServerStarter::results()`](crate::NCServerStarter::results). This is an iterator that ends when the job is done.
+pub struct NCResultStream<P> {
+    receiver: mpsc::Receiver<NCResultItem<P>>,
+}
+
+impl<P> NCResultStream<P> {
+    /// Waits at most for the given time for the next result. Returns `Ok(None)` if there is no result in time
+    /// and a [`NCError::ServerNotRunning`] error if the job is done and all the results have been received.
+    pub fn next_timeout(&self, timeout: Duration) -> Result<Option<NCResultItem<P>>, NCError> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(item) => Ok(Some(item)),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(NCError::ServerNotRunning),
+        }
+    }
+}
+
+impl<P> Iterator for NCResultStream<P> {
+    type Item = NCResultItem<P>;
+
+    /// Waits for the next result, returns [`None`] when the job is done.
+    fn next(&mut self) -> Option<NCResultItem<P>> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// The sending end of the NCResultStream, used by the server.
+pub(crate) struct NCResultSender<P> {
+    /// [`None`] after close() or if the stream has been dropped.
+    sender: Mutex<Option<mpsc::SyncSender<NCResultItem<P>>>>,
+}
+
+impl<P: Send> NCResultSender<P> {
+    /// Creates the stream and its sender, at most capacity results wait in the stream.
+    pub(crate) fn new(capacity: usize) -> (Self, NCResultStream<P>) {
+        debug!("NCResultSender::new()");
+
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        (NCResultSender { sender: Mutex::new(Some(sender)) }, NCResultStream { receiver })
+    }
+
+    /// Sends the result to the stream, waits if the stream is full. If the stream has been dropped the result is dropped too
+    /// and no more results are sent.
+    pub(crate) fn send(&self, chunk_id: Option<ChunkID>, data: P) -> Result<(), NCError> {
+        let mut sender = self.sender.lock()?;
+
+        if let Some(stream) = sender.as_ref() {
+            if stream.send((chunk_id, data)).is_err() {
+                info!("The result stream has been dropped, the results are not sent anymore");
+                *sender = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ends the stream, this is done when the job is finished.
+    pub(crate) fn close(&self) -> Result<(), NCError> {
+        debug!("NCResultSender::close()");
+
+        *self.sender.lock()? = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::thread;
+
+    use crate::nc_config::NCConfiguration;
+    use crate::nc_server::NCServerStarter;
+    use crate::nc_node::NCNodeStarter;
+    use crate::nc_range::{RangeServer, RangeNode, RangeResults};
+
+    #[test]
+    fn test_stream() {
+        let (sender, mut stream) = NCResultSender::new(2);
+
+        sender.send(Some(3), "a").unwrap();
+        sender.send(None, "b").unwrap();
+        assert_eq!(stream.next_timeout(Duration::from_millis(10)).unwrap(), Some((Some(3), "a")));
+
+        sender.close().unwrap();
+        assert_eq!(stream.next(), Some((None, "b")));
+        assert_eq!(stream.next(), None);
+        assert!(matches!(stream.next_timeout(Duration::from_millis(10)), Err(NCError::ServerNotRunning)));
+
+        // A dropped stream doesn't block the server, even when it would be full
+        let (sender, stream) = NCResultSender::new(1);
+        sender.send(Some(1), 1).unwrap();
+        drop(stream);
+
+        for chunk_id in 2..5 {
+            sender.send(Some(chunk_id), chunk_id).unwrap();
+        }
+        assert!(sender.sender.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_results() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = NCConfiguration { port: listener.local_addr().unwrap().port(), delay_request_data: 1, heartbeat: 1, ..Default::default() };
+
+        // Only one result fits into the stream, the server waits for the slow consumer
+        let mut server_starter = NCServerStarter::new(config.clone());
+        let results: NCResultStream<RangeResults<u64>> = server_starter.results(1);
+        let server = thread::spawn(move || server_starter.run(RangeServer::new(0..100, 10, (), |_, _, _: &[u64]| ()), listener));
+        let node = thread::spawn(move || NCNodeStarter::new(config).start(RangeNode::new(|i| i * 2)));
+
+        let mut values = Vec::new();
+
+        for (chunk_id, result) in results {
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(chunk_id, Some(result.chunk_id));
+            values.extend(result.values);
+        }
+
+        // The stream ends with the job
+        let (_, job_summary) = server.join().unwrap().unwrap();
+        node.join().unwrap().unwrap();
+        assert_eq!(job_summary.chunks_sent, 10);
+
+        values.sort_unstable();
+        assert_eq!(values, (0..100).map(|i| i * 2).collect::<Vec<u64>>());
+    }
+}
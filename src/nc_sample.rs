@@ -0,0 +1,162 @@
+//! This module contains the sample of the chunks for a quick validation run.
+//! With sample_fraction in the NCConfiguration the server only sends a random part of the chunks to the nodes, for example 1%
+//! with 0.01, so the quality of the output and the time per chunk can be checked before the full run. The other chunks are
+//! left out: the NCServer trait method chunk_left_out() is called for them instead, so the job finishes early.
+//!
+//! Whether a chunk is part of the sample only depends on the seed and the chunk id, not on the order in which the chunks are assigned.
+//! So the same seed (sample_seed in the NCConfiguration) gives the same chunks again. Without a seed the server chooses one and logs it,
+//! the job summary contains the sample with the seed that has been used.
+//!
+//! The checkpoint of a sampled run contains the sample. A run without a sample doesn't resume from it and a sampled run doesn't resume
+//! from the checkpoint of a full run or of another sample (NCError::Sample), so the results of both are not mixed up.
+//! A sampled run with the same fraction and without a seed takes the seed from the checkpoint.
+
+use std::fmt::{self, Display, Formatter};
+
+#[cfg(feature = "net")]
+use log::info;
+use serde::{Serialize, Deserialize};
+
+#[cfg(feature = "net")]
+use crate::nc_error::NCError;
+#[cfg(feature = "net")]
+use crate::nc_config::NCConfiguration;
+#[cfg(feature = "net")]
+use crate::nc_checkpoint::NCCheckpoint;
+use crate::array2d::ChunkID;
+
+/// The part of the chunks that is processed in a sampled run, see the module documentation.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct NCSample {
+    /// The part of the chunks between 0 and 1, see sample_fraction in the NCConfiguration.
+    pub fraction: f64,
+    /// The seed that decides which chunks are part of the sample.
+    pub seed: u64,
+}
+
+impl NCSample {
+    /// The sample from sample_fraction and sample_seed in the given configuration, [`None`] for a full run.
+    /// Without a seed a random one is chosen.
+    #[cfg(feature = "net")]
+    pub(crate) fn from_config(config: &NCConfiguration) -> Option<Self> {
+        config.sample_fraction.map(|fraction| NCSample { fraction, seed: config.sample_seed.unwrap_or_else(rand::random) })
+    }
+
+    /// Returns true if the given chunk is part of the sample.
+    pub fn contains(&self, chunk_id: ChunkID) -> bool {
+        // The top 53 bits of a SplitMix64 step give a number between 0 and 1 for every chunk
+        let mut z = self.seed.wrapping_add(chunk_id.wrapping_add(1).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+
+        ((z >> 11) as f64 / (1u64 << 53) as f64) < self.fraction
+    }
+}
+
+impl Display for NCSample {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}% of the chunks, seed: {}", self.fraction * 100.0, self.seed)
+    }
+}
+
+/// The sample for this run: the sample from the configuration (see NCSample::from_config()), checked against the sample of the checkpoint
+/// that the run resumes from. Without a checkpoint file (or if it can't be read) the sample from the configuration is used.
+///
+/// # Errors
+///
+/// Returns a [`NCError::Sample`] error if the fraction is not valid, if a full run would resume from a sampled run or the other way around,
+/// or if the fraction or the seed are not the ones in the checkpoint.
+#[cfg(feature = "net")]
+pub(crate) fn sample_for_run(config: &NCConfiguration) -> Result<Option<NCSample>, NCError> {
+    if let Some(fraction) = config.sample_fraction.filter(|fraction| !(*fraction > 0.0 && *fraction <= 1.0)) {
+        return Err(NCError::Sample(format!("sample_fraction must be greater than 0 and at most 1, got {}", fraction)))
+    }
+
+    let checkpoint = config.checkpoint_file.as_ref().filter(|checkpoint_file| checkpoint_file.exists())
+        .and_then(|checkpoint_file| NCCheckpoint::load(checkpoint_file).ok());
+
+    let checkpoint = match checkpoint {
+        Some(checkpoint) => checkpoint,
+        None => return Ok(NCSample::from_config(config)),
+    };
+
+    match (checkpoint.sample(), config.sample_fraction) {
+        (None, None) => Ok(None),
+        (None, Some(fraction)) => Err(NCError::Sample(format!("the checkpoint is from a full run, it can't be resumed with sample_fraction {}", fraction))),
+        (Some(sample), None) => Err(NCError::Sample(format!("the checkpoint is from a sampled run ({}), it can't be resumed by a full run", sample))),
+        (Some(sample), Some(fraction)) if fraction != sample.fraction || config.sample_seed.is_some_and(|seed| seed != sample.seed) =>
+            Err(NCError::Sample(format!("the checkpoint is from another sample ({})", sample))),
+        (Some(sample), Some(_)) => {
+            info!("Resume the sampled run from the checkpoint: {}", sample);
+            Ok(Some(sample))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "net")]
+    use std::fs;
+
+    #[cfg(feature = "net")]
+    use crate::array2d::ChunkList;
+
+    fn sampled(sample: &NCSample) -> Vec<ChunkID> {
+        (0..10_000).filter(|chunk_id| sample.contains(*chunk_id)).collect()
+    }
+
+    #[test]
+    fn test_same_seed_same_sample() {
+        let sample = NCSample { fraction: 0.01, seed: 42 };
+        let chunks = sampled(&sample);
+
+        assert_eq!(chunks, sampled(&NCSample { fraction: 0.01, seed: 42 }));
+        assert_ne!(chunks, sampled(&NCSample { fraction: 0.01, seed: 43 }));
+        // About 1 %
+        assert!((50..150).contains(&chunks.len()), "{} chunks", chunks.len());
+
+        // The order of the questions doesn't matter
+        assert!(chunks.iter().rev().all(|chunk_id| sample.contains(*chunk_id)));
+
+        assert!(sampled(&NCSample { fraction: 0.0, seed: 42 }).is_empty());
+        assert_eq!(sampled(&NCSample { fraction: 1.0, seed: 42 }).len(), 10_000);
+        assert_eq!(sample.to_string(), "1% of the chunks, seed: 42");
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_sample_for_run() {
+        let checkpoint_file = std::env::temp_dir().join("nc_test_sample.checkpoint");
+        let mut chunk_list = ChunkList::new();
+        chunk_list.push(1);
+
+        let config = |fraction, seed| NCConfiguration { checkpoint_file: Some(checkpoint_file.clone()), sample_fraction: fraction, sample_seed: seed, ..Default::default() };
+
+        // Without a checkpoint the configuration decides
+        let _ = fs::remove_file(&checkpoint_file);
+        assert_eq!(sample_for_run(&config(Some(0.5), Some(7))).unwrap(), Some(NCSample { fraction: 0.5, seed: 7 }));
+        assert_eq!(sample_for_run(&config(None, None)).unwrap(), None);
+        assert!(matches!(sample_for_run(&config(Some(0.0), None)), Err(NCError::Sample(_))));
+        assert!(matches!(sample_for_run(&config(Some(1.5), None)), Err(NCError::Sample(_))));
+
+        // A full run only resumes a full run
+        NCCheckpoint::new(&chunk_list).unwrap().save(&checkpoint_file).unwrap();
+        assert_eq!(sample_for_run(&config(None, None)).unwrap(), None);
+        assert!(matches!(sample_for_run(&config(Some(0.5), None)), Err(NCError::Sample(_))));
+
+        // A sampled run only resumes the same sample, the seed comes from the checkpoint
+        let mut checkpoint = NCCheckpoint::new(&chunk_list).unwrap();
+        checkpoint.set_sample(Some(NCSample { fraction: 0.5, seed: 7 }));
+        checkpoint.save(&checkpoint_file).unwrap();
+        assert_eq!(sample_for_run(&config(Some(0.5), None)).unwrap(), Some(NCSample { fraction: 0.5, seed: 7 }));
+        assert_eq!(sample_for_run(&config(Some(0.5), Some(7))).unwrap(), Some(NCSample { fraction: 0.5, seed: 7 }));
+        assert!(matches!(sample_for_run(&config(None, None)), Err(NCError::Sample(_))));
+        assert!(matches!(sample_for_run(&config(Some(0.1), None)), Err(NCError::Sample(_))));
+        assert!(matches!(sample_for_run(&config(Some(0.5), Some(8))), Err(NCError::Sample(_))));
+
+        fs::remove_file(&checkpoint_file).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+//! This module contains the scratch directory for the node.
+//! Every chunk gets its own empty folder for temporary files (see [`NCProcessContext::scratch_dir()`](crate::NCProcessContext::scratch_dir)).
+//! The folder is deleted automatically after the server has acknowledged the result. If processing the chunk failed
+//! the folder can be kept for debugging (keep_scratch_on_failure in the NCConfiguration).
+
+use std::path::{Path, PathBuf};
+use std::fs;
+#[cfg(feature = "net")]
+use std::process;
+#[cfg(feature = "net")]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "net")]
+use log::{debug, error, warn};
+
+#[cfg(feature = "net")]
+use crate::nc_error::NCError;
+#[cfg(feature = "net")]
+use crate::nc_config::NCConfiguration;
+
+/// Used to create unique folder names if there is more than one node in the same process.
+#[cfg(feature = "net")]
+static NODE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Manages the temporary folders for all the chunks of one node.
+#[derive(Debug)]
+pub struct ScratchDir {
+    /// The folder for this node, each chunk gets a sub folder.
+    path: PathBuf,
+    /// Used to create unique folder names for the chunks.
+    #[cfg(feature = "net")]
+    chunk_counter: u64,
+    /// Don't delete the folder of a chunk if processing failed.
+    #[cfg(feature = "net")]
+    keep_on_failure: bool,
+    /// Minimum free space in bytes that is needed to start a new chunk, 0 = no check.
+    #[cfg(feature = "net")]
+    min_free_space: u64,
+}
+
+impl ScratchDir {
+    /// Creates a new scratch directory with the settings from the given configuration.
+    /// Nothing is written to disk here, the folders are created for each chunk in create_chunk_dir().
+    #[cfg(feature = "net")]
+    pub(crate) fn new(config: &NCConfiguration) -> Self {
+        debug!("ScratchDir::new()");
+
+        let base = config.scratch_dir.clone().unwrap_or_else(std::env::temp_dir);
+
+        ScratchDir {
+            path: base.join(format!("nc_node_{}_{}", process::id(), NODE_COUNTER.fetch_add(1, Ordering::Relaxed))),
+            chunk_counter: 0,
+            keep_on_failure: config.keep_scratch_on_failure,
+            min_free_space: config.min_free_space,
+        }
+    }
+
+    /// The folder for this node.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Creates a new empty folder for the next chunk.
+    #[cfg(feature = "net")]
+    pub(crate) fn create_chunk_dir(&mut self) -> Result<PathBuf, NCError> {
+        debug!("ScratchDir::create_chunk_dir()");
+
+        let chunk_dir = self.path.join(format!("chunk_{}", self.chunk_counter));
+        self.chunk_counter += 1;
+
+        fs::create_dir_all(&chunk_dir)?;
+        Ok(chunk_dir)
+    }
+
+    /// Deletes the folder of the chunk, unless processing failed and keep_scratch_on_failure is set.
+    #[cfg(feature = "net")]
+    pub(crate) fn remove_chunk_dir(&self, chunk_dir: &Path, success: bool) {
+        debug!("ScratchDir::remove_chunk_dir()");
+
+        if !success && self.keep_on_failure {
+            warn!("Processing failed, keep scratch folder: {}", chunk_dir.display());
+            return
+        }
+
+        if let Err(e) = fs::remove_dir_all(chunk_dir) {
+            error!("Could not remove scratch folder: {}, {}", chunk_dir.display(), e);
+        }
+    }
+
+    /// Returns false if there is less free space than min_free_space in the NCConfiguration.
+    #[cfg(feature = "net")]
+    pub(crate) fn has_free_space(&self) -> Result<bool, NCError> {
+        if self.min_free_space == 0 {
+            return Ok(true)
+        }
+
+        fs::create_dir_all(&self.path)?;
+        let free_space = fs2::available_space(&self.path)?;
+        debug!("Free space in scratch folder: {} bytes", free_space);
+
+        Ok(free_space >= self.min_free_space)
+    }
+}
+
+impl Drop for ScratchDir {
+    /// Removes the folder of this node if it's empty. Folders that have been kept for debugging are not deleted.
+    fn drop(&mut self) {
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::*;
+
+    fn scratch_dir_for_test(name: &str, keep_scratch_on_failure: bool, min_free_space: u64) -> ScratchDir {
+        let config = NCConfiguration {
+            scratch_dir: Some(std::env::temp_dir().join(name)),
+            keep_scratch_on_failure,
+            min_free_space,
+            ..Default::default()
+        };
+
+        ScratchDir::new(&config)
+    }
+
+    #[test]
+    fn test_chunk_dir_removed() {
+        let mut scratch_dir = scratch_dir_for_test("nc_test_scratch_removed", false, 0);
+
+        let chunk_dir1 = scratch_dir.create_chunk_dir().unwrap();
+        let chunk_dir2 = scratch_dir.create_chunk_dir().unwrap();
+        assert_ne!(chunk_dir1, chunk_dir2);
+
+        fs::write(chunk_dir1.join("data.bin"), [1, 2, 3]).unwrap();
+        scratch_dir.remove_chunk_dir(&chunk_dir1, true);
+        scratch_dir.remove_chunk_dir(&chunk_dir2, false);
+        assert!(!chunk_dir1.exists());
+        assert!(!chunk_dir2.exists());
+
+        let path = scratch_dir.path().to_path_buf();
+        drop(scratch_dir);
+        assert!(!path.exists());
+
+        fs::remove_dir(std::env::temp_dir().join("nc_test_scratch_removed")).unwrap();
+    }
+
+    #[test]
+    fn test_keep_on_failure() {
+        let mut scratch_dir = scratch_dir_for_test("nc_test_scratch_keep", true, 0);
+
+        let chunk_dir1 = scratch_dir.create_chunk_dir().unwrap();
+        let chunk_dir2 = scratch_dir.create_chunk_dir().unwrap();
+        scratch_dir.remove_chunk_dir(&chunk_dir1, true);
+        scratch_dir.remove_chunk_dir(&chunk_dir2, false);
+        assert!(!chunk_dir1.exists());
+        assert!(chunk_dir2.exists());
+
+        fs::remove_dir_all(std::env::temp_dir().join("nc_test_scratch_keep")).unwrap();
+    }
+
+    #[test]
+    fn test_has_free_space() {
+        assert!(scratch_dir_for_test("nc_test_scratch_space", false, 0).has_free_space().unwrap());
+        assert!(scratch_dir_for_test("nc_test_scratch_space", false, 1).has_free_space().unwrap());
+        assert!(!scratch_dir_for_test("nc_test_scratch_space", false, u64::MAX).has_free_space().unwrap());
+
+        fs::remove_dir(std::env::temp_dir().join("nc_test_scratch_space")).unwrap();
+    }
+}
@@ -1,65 +1,298 @@
 //! This module contains the nc server message, trait and helper methods
 //! To use the server you have to implement the NCServer trait that has five methods:
 //! initial_data(): This method is called once for every node when the node registers with the server.
-//! prepare_data_for_node(): This method is called when the node needs new data to process.
+//! assign_chunk(): This method is called when the node needs new data to process.
+//!     Once the data has been sent to the node chunk_sent() is called, if sending failed chunk_send_failed() is called.
+//!     If all chunks of a phase are done, phase_finished() is called where the chunks for the next phase can be created.
 //! process_data_from_node(): This method is called when the node is done with processing the data and has sent the result back to the server.
+//!     The results are put into a bounded queue first and this method is called from a separate thread, see [`NCResultQueue`].
+//!     With result_batch_size in the NCConfiguration process_result_batch() gets many results at once instead,
+//!     see the [`nc_result_batch`](crate::nc_result_batch) module.
+//! process_node_error(): This method is called when the node could not process the data and has sent a NCJobError back to the server.
 //! heartbeat_timeout(): This method is called when the node has missed a heartbeat, usually the node is then marked as offline and the chunk
 //!     of data for that node is sent to another node.
 //! finish_job(): This method is called when the job is done and all the threads are finished. Usually you want to save the results to disk
 //!     in here.
+//! If some nodes run as aggregators (see the [`nc_aggregator`](crate::nc_aggregator) module), chunk_delegated() and process_aggregate()
+//! have to be implemented as well.
+//!
+//! Locking rules inside the server process: the connections of the nodes are handled by a thread pool and share the state behind mutexes.
+//! If more than one lock is needed they are taken in this order: nc_server, node_list, then job_stats and the other small ones,
+//! and the shards of the heartbeat table (see [`nc_node_info`](crate::nc_node_info)) always last. Heartbeats (NCNodeMessage::HeartBeat
+//! and PollHeartBeat) and the CheckHeartbeat sweep only lock the heartbeat table, so they aren't delayed by a thread that holds the
+//! node list. The chunk times for the average are atomic counters without a lock.
 
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::any::type_name;
+use std::fmt::{self, Debug};
+use std::panic::{self, AssertUnwindSafe};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::thread;
-use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
-use std::time::{Instant, Duration};
+use std::net::SocketAddr;
+#[cfg(feature = "ctrlc")]
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::io::Write;
+use std::time::{Instant, Duration, SystemTime};
 
-use log::{error, info, debug};
-use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use log::{error, warn, info, debug};
+use serde::{Serialize, de::DeserializeOwned};
 use threadpool::ThreadPool;
 
-use crate::nc_error::NCError;
-use crate::nc_node::NCNodeMessage;
-use crate::nc_config::NCConfiguration;
-use crate::nc_node_info::{NodeID, NCNodeList};
-use crate::nc_communicator::{NCCommunicator};
-
-/// This message is send from the server to each node.
-#[derive(Debug, Serialize, Deserialize)]
-pub(crate) enum NCServerMessage<InitialDataT, NewDataT, CustomMessageT> {
-    /// When the node registers for the first time with the NCNodeMessage::Register message the server assigns a new node id
-    /// and sends some optional initial data to the node.
-    InitialData(NodeID, Option<InitialDataT>),
-    /// When the node requests new data to process with the NCNodeMessage::NeedsData message, the current job status is sent to
-    /// the node: unfinished, waiting or finished.
-    JobStatus(NCJobStatus<NewDataT>),
-    /// Send some statistics about the server to the node.
-    Statistics(NCServerStatistics),
-    /// Move all nodes to a new server.
-    NewServer(String, u16),
-    /// Send a custom message to one or all nodes.
-    CustomMessage(CustomMessageT),
+use crate::nc_error::{NCError, NCJobError, panic_message, retry_transient};
+use crate::nc_node::{NCNode, NCNodeMessage, NCWorkHint};
+pub use crate::nc_message::{NCServerMessage, NCJobStatus, NCChunkInfo, NCServerStatistics};
+use crate::nc_message::NCRejectReason;
+use crate::nc_config::{NCConfiguration, NCSharedSettings, OnProcessError, ConfigDiff, ConfigDiffSeverity};
+use crate::nc_node_info::{NodeID, NCNodeList, NCHeartbeatTable};
+use crate::nc_communicator::{NCCommunicator, NCCodec, NCServerAddr, NCTyped, deserialize_limited};
+use crate::nc_admin::{NCAdminMessage, NCAdminCommand, NCReplayGuard};
+use crate::array2d::{ChunkID, ChunkMeta, chunk_meta_size};
+use crate::nc_result_queue::NCResultQueue;
+use crate::nc_reorder_buffer::NCReorderBuffer;
+use crate::nc_result_batch::{NCResultBatcher, NCBatchedResult};
+use crate::nc_chunk_cache::NCChunkCache;
+use crate::nc_frame_cache::NCFrameCache;
+use crate::nc_checkpoint::NCCheckpoint;
+use crate::nc_sample::{NCSample, sample_for_run};
+use crate::nc_context::{self, NCCallContext};
+use crate::nc_listener::{NCListener, loopback};
+use crate::nc_envelope;
+use crate::nc_watchdog::{NCConnectionWatchdog, NCConnectionGuard};
+use crate::nc_transport::NCStream;
+use crate::nc_post_process::{NCPostProcessor, NCPostProcessQueue};
+use crate::nc_chunk_queue::ChunkQueueWaker;
+use crate::nc_dictionary::NCDictionaryTrainer;
+use crate::nc_events::NCEventLog;
+use crate::nc_locality::LocalityMap;
+#[cfg(feature = "ed25519")]
+use crate::nc_keys::{NCPublicKey, NCKeyRing, SIGNATURE_META_KEY, PUBLIC_KEY_META_KEY, encode_hex};
+#[cfg(doc)]
+use crate::nc_chunk_queue::ChunkQueueHandle;
+use crate::nc_dry_run::{DryRunReport, check_round_trip};
+use crate::nc_multi_server::NCJob;
+use crate::nc_resources::NCResourceReport;
+use crate::nc_clock::{NCClockSkew, unix_micros};
+use crate::nc_job_summary::{NCJobSummary, NCJobEndReason, NCJobStats, NCNodeContribution, NCAttemptOutcome, NCChunkAttempt, NCDeadChunk};
+use crate::nc_broadcast::{NCBroadcastHandle, NCBroadcastJob};
+use crate::nc_result_stream::{NCResultStream, NCResultSender};
+use crate::nc_replication::{NCReplicator, NCAcceptedResult};
+#[cfg(feature = "mdns")]
+use crate::nc_mdns::NCMdnsService;
+use crate::nc_artifacts::NCArtifactStore;
+use crate::nc_offline::{NCOfflineHandle, NCOfflineJob, NCOfflineBatches, NCChunkArchive, NCResultArchive, NCOfflineResult, NCArchiveKind, write_archive, read_archive};
+use crate::nc_job_definition::{NCJobDefinition, NCDefinitionServer};
+#[cfg(feature = "debug-protocol")]
+use crate::nc_text_protocol;
+
+/// The maximum number of threads that reject a node with NCRejectReason::ServerBusy at the same time, see NCServerStarter::start_busy_thread().
+const MAX_BUSY_REJECTIONS: u64 = 16;
+
+/// Events about the progress of the job, see [`NCServerStarter::progress_events()`].
+/// The [`nc_progress`](crate::nc_progress) module (progress feature) shows them as progress bars.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NCProgressEvent {
+    /// A new node has registered with the server.
+    NodeRegistered(NodeID),
+    /// The chunk has been sent to the node.
+    ChunkSent(NodeID, ChunkID),
+    /// The node has sent the result for its chunk (or an empty / skip message), together with the time since the chunk has been sent.
+    ChunkDone(NodeID, ChunkID, Duration),
+    /// The node could not process its chunk.
+    ChunkFailed(NodeID),
+    /// The node has missed its heartbeat or has been disabled.
+    NodeOffline(NodeID),
+    /// The node belongs to these groups now (see node_tags in the NCConfiguration), it's sent after NodeRegistered if the node has tags.
+    NodeTags(NodeID, Vec<String>),
+    /// Number of finished chunks and total number of chunks, see the NCServer trait method job_progress().
+    JobProgress(u64, u64),
+    /// A background thread of the server (result thread, watchdog or post processing worker) has panicked:
+    /// the name of the thread and the panic message. It's reported when the thread is joined at the end of the job.
+    TaskFailed(String, String),
+    /// The server is listening on this address, this is the first event. With port 0 in the NCConfiguration it has the port
+    /// that the OS has chosen, see [`NCServerHandle`].
+    Listening(SocketAddr),
+    /// An artifact of the chunk from this node has been stored in this file, see the [`nc_artifacts`](crate::nc_artifacts) module.
+    ArtifactStored(NodeID, ChunkID, PathBuf),
+    /// The clock skew of the node has been measured, see the [`nc_clock`](crate::nc_clock) module. Time stamps that the node puts
+    /// into its results can be converted into server time with [`NCClockSkew::to_server_time()`].
+    ClockSkew(NodeID, NCClockSkew),
+    /// The node has dropped the revoked chunk (see [`NCServerHandle::cancel_chunk()`]) or its result has been dropped.
+    ChunkRevoked(NodeID, ChunkID),
+    /// The node has missed its heartbeat while it was holding the chunk, the chunk is given to another node.
+    /// It's sent before the NodeOffline event of the node.
+    ChunkExpired(NodeID, ChunkID),
+    /// The chunk has run out of attempts (max_chunk_attempts in the NCConfiguration) and is on the dead-letter list now:
+    /// the number of attempts and the last error.
+    ChunkDead(ChunkID, u32, String),
+    /// A custom message has been broadcast to this group of nodes ([`None`] = all nodes), the number of nodes that get it.
+    Broadcast(Option<String>, usize),
+    /// The job has been aborted for this reason, the JobDone event follows when all the results have been processed.
+    JobAborted(NCJobEndReason),
+    /// The job is done, this is the last event.
+    JobDone,
+}
+
+/// The part of the server that revokes chunks, implemented by the server process.
+pub(crate) trait NCChunkControl: Send + Sync {
+    /// See [`NCServerHandle::cancel_chunk()`].
+    fn cancel_chunk(&self, chunk_id: ChunkID) -> Result<bool, NCError>;
+}
+
+/// Gives access to the running server from another thread, see [`NCServerStarter::server_handle()`].
+/// With port 0 in the NCConfiguration the OS chooses a free port when the server starts, the handle tells which one.
+#[derive(Clone, Default)]
+pub struct NCServerHandle {
+    /// The address of the listening socket, [`None`] until the server has been started.
+    local_addr: Arc<(Mutex<Option<SocketAddr>>, Condvar)>,
+    /// The running job, [`None`] before start() and after the job is done.
+    job: Arc<Mutex<Option<Arc<dyn NCChunkControl>>>>,
+}
+
+impl Debug for NCServerHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NCServerHandle").field("local_addr", &self.local_addr()).finish()
+    }
+}
+
+impl NCServerHandle {
+    /// The address that the server is listening on, [`None`] if it hasn't been started yet.
+    /// With BindMode::DualStack and two sockets this is the address of the IPv6 socket, both have the same port.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr.0.lock().ok().and_then(|local_addr| *local_addr)
+    }
+
+    /// Same as local_addr() but waits up to the given time until the server has been started.
+    pub fn wait_local_addr(&self, timeout: Duration) -> Option<SocketAddr> {
+        debug!("NCServerHandle::wait_local_addr()");
+
+        let (local_addr, started) = &*self.local_addr;
+        let local_addr = local_addr.lock().ok()?;
+
+        started.wait_timeout_while(local_addr, timeout, |local_addr| local_addr.is_none()).ok().and_then(|(local_addr, _)| *local_addr)
+    }
+
+    /// Called by the server once the listening socket is open.
+    pub(crate) fn set_local_addr(&self, addr: SocketAddr) -> Result<(), NCError> {
+        let (local_addr, started) = &*self.local_addr;
+        *local_addr.lock()? = Some(addr);
+        started.notify_all();
+        Ok(())
+    }
+
+    /// Sets (or clears) the running job.
+    pub(crate) fn set_job(&self, job: Option<Arc<dyn NCChunkControl>>) -> Result<(), NCError> {
+        *self.job.lock()? = job;
+        Ok(())
+    }
+
+    /// Revokes the given chunk, for example because its parameters were wrong and the result would be discarded anyway.
+    /// The node that holds the chunk learns it with its next heartbeat (see the NCNodeMessage::PollHeartBeat message):
+    /// it stops processing the chunk (the cancellation token of the [`NCProcessContext`](crate::NCProcessContext) is set)
+    /// or doesn't start it at all if it has fetched it in advance. That is not counted as a failure, the NCServer trait
+    /// method chunk_revoked() is called instead. If the node sends the result anyway, the result is dropped.
+    /// Returns false if no node holds the chunk (it hasn't been sent yet or is done already), then nothing happens.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::ServerNotRunning`] error before start() and after the job is done.
+    pub fn cancel_chunk(&self, chunk_id: ChunkID) -> Result<bool, NCError> {
+        debug!("NCServerHandle::cancel_chunk()");
+
+        let job = self.job.lock()?.clone().ok_or(NCError::ServerNotRunning)?;
+        job.cancel_chunk(chunk_id)
+    }
+}
+
+/// What the server knows about a node that needs new data, see [`NCServer::assign_chunk_with_context()`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NCAssignContext {
+    /// The resources that the node has sent with its last heartbeat.
+    resources: Option<NCResourceReport>,
+    /// The work hint that the node has sent together with its request for new data.
+    work_hint: Option<NCWorkHint>,
+    /// The groups of the node.
+    tags: Vec<String>,
+    /// The shard tags of the chunks, see locality_manifest in the NCConfiguration.
+    locality: Option<Arc<LocalityMap>>,
+}
+
+impl NCAssignContext {
+    /// The resources that the node has sent with its last heartbeat,
+    /// [`None`] if report_resources is not set in the NCConfiguration of the node or no heartbeat has arrived yet.
+    pub fn resources(&self) -> Option<&NCResourceReport> {
+        self.resources.as_ref()
+    }
+
+    /// How much work the node would like to get, [`None`] if the node doesn't send a hint (for example an older node).
+    pub fn work_hint(&self) -> Option<&NCWorkHint> {
+        self.work_hint.as_ref()
+    }
+
+    /// The largest chunk (in bytes) that the node would like to get, see [`NCWorkHint::max_bytes`].
+    pub fn max_bytes(&self) -> Option<u64> {
+        self.work_hint.as_ref().and_then(|work_hint| work_hint.max_bytes)
+    }
+
+    /// The groups of the node (see node_tags in the NCConfiguration), for example for
+    /// [`ChunkList::assign_next_chunk_for_groups()`](crate::ChunkList::assign_next_chunk_for_groups).
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// The shard tags of the chunks from locality_manifest in the NCConfiguration, [`None`] if it isn't set. For example for
+    /// [`ChunkList::assign_next_chunk_with_locality()`](crate::ChunkList::assign_next_chunk_with_locality).
+    pub fn locality(&self) -> Option<&LocalityMap> {
+        self.locality.as_deref()
+    }
+
+    /// Returns true if the node has the shard of the given chunk (or there is no locality manifest).
+    /// If not, the chunk goes to the node after the fallback time and the data has to be sent inline, see the [`nc_locality`](crate::nc_locality) module.
+    pub fn is_local(&self, chunk_id: ChunkID) -> bool {
+        self.locality.as_ref().is_none_or(|locality| locality.is_local(chunk_id, &self.tags))
+    }
 }
 
-/// The job status tells the node what to do next: process the new data, wait for other nodes to finish or exit. This is the answer from the server when
-/// a node request new data via the NCNodeMessage::NeedsData message.
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-pub enum NCJobStatus<NewDataT> {
-    /// The job is not done yet and the node has to process the data the server sends to it.
-    Unfinished(NewDataT),
-    /// The server is still waiting for other nodes to finish the job. This means that all the work has already been distributed to all the nodes
-    /// and the server sends this message to the remaining nodes. It does this because some of the processing nodes can still crash, so that its work
-    /// has to be done by a waiting node.
+/// This is the answer from the user code when a node needs new data, see [`NCServer::assign_chunk()`].
+#[derive(Debug, PartialEq)]
+pub enum ChunkAssignment<NewDataT> {
+    /// The job is not done yet, send the data for the chunk with the given id to the node.
+    Assigned(ChunkID, NewDataT),
+    /// All the work has already been distributed, the node has to wait for other nodes to finish.
     Waiting,
-    /// Now all nodes are finished and the job is done. The server sends this message to all the nodes that request new data.
+    /// All nodes are finished and the job is done.
     Finished,
+    /// All chunks of the given phase are done, see [`ChunkList::finish_phase()`](crate::ChunkList::finish_phase).
+    /// The server calls [`NCServer::phase_finished()`] and then asks for a new chunk again.
+    PhaseFinished(u32),
+}
+
+/// This is the answer from the user code when a result is missing for too long and ordered_results is set
+/// in the NCConfiguration, see [`NCServer::chunk_gap()`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NCGapAction {
+    /// Wait another max_reorder_wait seconds for the missing chunk.
+    Wait,
+    /// Give up on the missing chunk and continue with the next one. A late result for that chunk is dropped.
+    Skip,
+}
+
+/// This is the answer from the user code when a chunk has run out of attempts (max_chunk_attempts in the NCConfiguration),
+/// see [`NCServer::chunk_dead()`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NCDeadChunkAction {
+    /// End the job with NCJobEndReason::ChunkDead, the chunk stays on the dead-letter list of the checkpoint.
+    AbortJob,
+    /// Continue the job without the chunk, chunk_rejected() is called for it and it's marked as failed.
+    Continue,
 }
 
 /// This is the trait that you have to implement in order to start the server.
 pub trait NCServer {
     type InitialDataT: Serialize + DeserializeOwned;
     type NewDataT: Serialize + DeserializeOwned;
-    type ProcessedDataT: Serialize + DeserializeOwned;
+    type ProcessedDataT: Serialize + DeserializeOwned + Send + 'static;
     type CustomMessageT: Serialize + DeserializeOwned + Send + Clone;
 
     /// This method is called once for every new node that registers with the server using the NCNodeMessage::Register message.
@@ -71,14 +304,192 @@ pub trait NCServer {
     /// It's the servers task to prepare the data for each node individually.
     /// For example a 2D array can be split up into smaller pieces that are processed by each node.
     /// Usually the server will have an internal data structure containing all the registered nodes.
-    /// According to the status of the job this method returns a NCJobStatus value:
-    /// Unfinished, Waiting or Finished.
-    fn prepare_data_for_node(&mut self, node_id: NodeID) -> Result<NCJobStatus<Self::NewDataT>, NCError>;
+    /// According to the status of the job this method returns a ChunkAssignment value:
+    /// Assigned, Waiting or Finished.
+    /// An assigned chunk should not be handed out again until either chunk_sent() or chunk_send_failed() has been called,
+    /// the [`ChunkList`](crate::ChunkList) does this book keeping for you.
+    /// If the job has several phases, return PhaseFinished when all chunks of the current phase are done.
+    fn assign_chunk(&mut self, node_id: NodeID) -> Result<ChunkAssignment<Self::NewDataT>, NCError>;
+    /// This method is called instead of assign_chunk() by the server, with the resources the node has sent with its last heartbeat
+    /// and the work hint from its request, see [`NCAssignContext`].
+    /// Override it to size the chunk to the free memory or disk space of the node, to choose the kind of work the node prefers
+    /// or to let a busy node wait. [`ChunkList::assign_next_chunk_fitting()`](crate::ChunkList::assign_next_chunk_fitting) uses the max_bytes hint.
+    /// The default calls assign_chunk().
+    fn assign_chunk_with_context(&mut self, node_id: NodeID, _context: &NCAssignContext) -> Result<ChunkAssignment<Self::NewDataT>, NCError> {
+        self.assign_chunk(node_id)
+    }
+    /// This method is called when assign_chunk() has returned ChunkAssignment::PhaseFinished. All the results of that phase have already been
+    /// processed, so here the chunks for the next phase can be created from them
+    /// (for example with [`ChunkList::push_phase()`](crate::ChunkList::push_phase)).
+    /// Nodes that need data while the current phase is not finished yet should get a ChunkAssignment::Waiting.
+    fn phase_finished(&mut self, _phase: u32) -> Result<(), NCError> {
+        Ok(())
+    }
+    /// This method is called after the data for the given chunk has been completely written to the node connection.
+    fn chunk_sent(&mut self, _chunk_id: ChunkID) {
+    }
+    /// This method is called before the data for the given chunk is sent to the node.
+    /// If it returns a duration the node has to finish processing the data within that time, otherwise the node sends a
+    /// NCJobError with the code [`NCJobError::DEADLINE_EXCEEDED`] back to the server and process_node_error() is called.
+    /// The chunk can then be given to another node right away.
+    fn chunk_deadline(&mut self, _chunk_id: ChunkID) -> Option<Duration> {
+        None
+    }
+    /// This method is called before the data for the given chunk is sent to the node, like chunk_deadline().
+    /// The metadata (for example tile coordinates) is sent together with the data, the node can access it via
+    /// [`NCProcessContext::metadata()`](crate::NCProcessContext::metadata) and sends it back with the result, see process_data_with_meta().
+    /// Usually `self.chunk_list.chunk_meta(chunk_id)`, see [`ChunkList::push_with_meta()`](crate::ChunkList::push_with_meta).
+    /// Metadata larger than max_chunk_meta_bytes in the NCConfiguration is not sent.
+    fn chunk_meta(&mut self, _chunk_id: ChunkID) -> Option<ChunkMeta> {
+        None
+    }
+    /// This method is called when the data for the given chunk could not be sent to the node.
+    /// The chunk should be returned to the pool of free chunks so that another node can process it.
+    fn chunk_send_failed(&mut self, _chunk_id: ChunkID) {
+    }
+    /// This method is called after chunk_send_failed() if the node has closed the connection while the data was sent,
+    /// usually because the node has been killed. The node is not removed, if it's really gone heartbeat_timeout() is called later.
+    fn node_disconnected(&mut self, _node_id: NodeID) {
+    }
+    /// This method is called after assign_chunk() has prepared the data for the given chunk and cache_chunk_payloads is set in the NCConfiguration,
+    /// and before the chunk is sent if cache_chunk_frames is set.
+    /// Return false if the data must not be sent to another node again, for example if it depends on the node it has been prepared for.
+    fn cache_chunk(&mut self, _chunk_id: ChunkID) -> bool {
+        true
+    }
+    /// This method is called when the given chunk has to be sent again (heartbeat timeout, send failure or a retryable node error)
+    /// and its data is still in the cache (cache_chunk_payloads in the NCConfiguration).
+    /// Return true if the chunk has been assigned to the given node, then the cached data is sent and assign_chunk() is not called.
+    /// The [`ChunkList::reassign_chunk()`](crate::ChunkList::reassign_chunk) method does this for you.
+    /// The default returns false, so assign_chunk() has to prepare the data again.
+    fn reassign_chunk(&mut self, _chunk_id: ChunkID, _node_id: NodeID) -> bool {
+        false
+    }
     /// When one node is done processing the data from the server it will send the result back to the server and then this method is called.
+    /// The results are processed one after another in a separate thread in the order they have arrived,
+    /// or in chunk id order if ordered_results is set in the NCConfiguration.
     /// For example a small piece of a 2D array may be returned by the node and the server puts the resulting data back into the big 2D array.
+    /// If this returns a `NCError::Job` error, it is sent to the node the next time it asks for new data.
     fn process_data_from_node(&mut self, node_id: NodeID, data: &Self::ProcessedDataT) -> Result<(), NCError>;
-    /// Every node has to send a heartbeat message to the server. If it doesn't arrive in time (2 * the heartbeat value in the NCConfiguration)
-    /// then this method is called with the corresponding node id and the node should be marked as offline in this method.
+    /// This method is called with every result right after it has been received, before the server acknowledges and queues it.
+    /// Use it to sanity check the data from the node, for example that the width * height of an Array2D matches the length of its buffer.
+    /// If it returns an error the result is dropped, the node counts as failed (see process_node_error()) and the chunk is given to
+    /// a node again. The node gets a NCServerMessage::ServerFailed the next time it needs data.
+    /// The default accepts everything.
+    fn validate(&self, _data: &Self::ProcessedDataT) -> Result<(), String> {
+        Ok(())
+    }
+    /// Same as process_data_from_node(), but also gets the metadata that the node has sent back together with the result (see chunk_meta()).
+    /// The metadata is [`None`] if the chunk didn't have any or if the result has been combined on an aggregator.
+    /// The default ignores the metadata and calls process_data_from_node().
+    fn process_data_with_meta(&mut self, node_id: NodeID, data: &Self::ProcessedDataT, _meta: Option<&ChunkMeta>) -> Result<(), NCError> {
+        self.process_data_from_node(node_id, data)
+    }
+    /// This method is called instead of process_data_with_meta() if result_batch_size is set in the NCConfiguration:
+    /// with many results at once, in the order they have arrived (see the [`nc_result_batch`](crate::nc_result_batch) module).
+    /// Jobs with very many tiny results can write them to the result store together, for example with one sync of the file per batch.
+    /// The nodes get the answer for their results after this method has returned.
+    /// If it returns an error, every result of the batch is handled as if process_data_from_node() had returned that error.
+    /// The default calls process_data_with_meta() for every result.
+    fn process_result_batch(&mut self, batch: Vec<NCBatchedResult<Self::ProcessedDataT>>) -> Result<(), NCError> {
+        for result in batch {
+            self.process_data_with_meta(result.node_id, &result.data, result.meta.as_ref())?;
+        }
+
+        Ok(())
+    }
+    /// This method is called once when the server starts. If it returns a post processor, every result that process_data_from_node()
+    /// has accepted is given to it afterwards. The post processor runs on post_process_workers threads (see the NCConfiguration)
+    /// without the server lock, so slow work like writing files to disk doesn't delay the other nodes.
+    /// All results are post processed before finish_job() is called.
+    fn post_processor(&mut self) -> Option<Arc<dyn NCPostProcessor<Self::ProcessedDataT>>> {
+        None
+    }
+    /// This method is called once when the server starts. If it returns a waker (see [`ChunkQueueHandle::waker()`]),
+    /// nodes that would get a NCJobStatus::Waiting wait at the server for up to long_poll_ms (see the NCConfiguration)
+    /// and assign_chunk() is called again as soon as the waker is woken up, for example when a new chunk is enqueued.
+    fn chunk_queue_waker(&mut self) -> Option<ChunkQueueWaker> {
+        None
+    }
+    /// The post processor has returned an error for the result of the given chunk. The node is not informed about this.
+    fn post_process_failed(&mut self, _chunk_id: Option<ChunkID>, _error: &NCError) {
+    }
+    /// The node didn't produce any data for the given chunk (see [`NodeResult::Empty`](crate::NodeResult::Empty)).
+    /// Usually the chunk should be marked as finished, the [`ChunkList::chunk_empty()`](crate::ChunkList::chunk_empty) method does this for you.
+    fn chunk_empty(&mut self, _chunk_id: ChunkID) {
+    }
+    /// The node has skipped the given chunk for the given reason (see [`NodeResult::Skip`](crate::NodeResult::Skip)).
+    /// Usually the chunk should be given to another node, the [`ChunkList::chunk_skipped()`](crate::ChunkList::chunk_skipped) method does this for you.
+    fn chunk_skipped(&mut self, _chunk_id: ChunkID, _reason: &str) {
+    }
+    /// The given chunk has been revoked with [`NCServerHandle::cancel_chunk()`]: the node has dropped it or its result
+    /// has arrived after the chunk had been revoked and has been dropped. Usually the chunk should be marked as finished,
+    /// the [`ChunkList::chunk_revoked()`](crate::ChunkList::chunk_revoked) method does this for you.
+    fn chunk_revoked(&mut self, _chunk_id: ChunkID) {
+    }
+    /// The given chunk has been assigned by assign_chunk() but it's not part of the sample of a sampled run (sample_fraction
+    /// in the NCConfiguration), so it's not sent to the node, see the [`nc_sample`](crate::nc_sample) module. Usually the chunk
+    /// should be marked as finished, the [`ChunkList::chunk_left_out()`](crate::ChunkList::chunk_left_out) method does this for you.
+    fn chunk_left_out(&mut self, _chunk_id: ChunkID) {
+    }
+    /// process_data_from_node() has returned an error for the given chunk (see on_process_error in the NCConfiguration),
+    /// or the result of an offline node (see the [`nc_offline`](crate::nc_offline) module) has failed or is not valid.
+    /// If requeue is true the chunk should be returned to the pool of free chunks, otherwise it should be marked as failed.
+    /// This is also called for a chunk that has run out of attempts (see chunk_dead()) and for one that is reset with
+    /// the NCAdminCommand::ResetDeadChunk command. The [`ChunkList::chunk_rejected()`](crate::ChunkList::chunk_rejected) method does this for you.
+    fn chunk_rejected(&mut self, _chunk_id: ChunkID, _requeue: bool) {
+    }
+    /// An aggregator (to) wants to take over the given chunk from the node (from), see the [`nc_aggregator`](crate::nc_aggregator) module.
+    /// Return true if the chunk now belongs to the aggregator, the [`ChunkList::delegate_chunk()`](crate::ChunkList::delegate_chunk)
+    /// method does this for you. Then a heartbeat timeout of the aggregator (or a node error from it) releases the chunk.
+    /// The default returns false, then the node sends its result to the server directly.
+    fn chunk_delegated(&mut self, _chunk_id: ChunkID, _from: NodeID, _to: NodeID) -> bool {
+        false
+    }
+    /// An aggregator has sent the combined result (see the NCNode trait method reduce()) of the given chunks.
+    /// This is called right away and not from the result thread, the result is not post processed.
+    /// If this returns an error, chunk_rejected() is called for all the chunks and they are given to a node again.
+    /// The default returns an error.
+    fn process_aggregate(&mut self, _node_id: NodeID, _chunks: &[ChunkID], _data: &Self::ProcessedDataT) -> Result<(), NCError> {
+        Err(NCError::custom("process_aggregate() is not implemented"))
+    }
+    /// If ordered_results is set in the NCConfiguration this method is called when the result for the given chunk is missing
+    /// for longer than max_reorder_wait seconds, for example because the chunk has failed permanently.
+    /// By default the chunk is skipped, so that the results with higher chunk ids can be processed.
+    fn chunk_gap(&mut self, _chunk_id: ChunkID) -> NCGapAction {
+        NCGapAction::Skip
+    }
+    /// The given chunk has been sent max_chunk_attempts times (see the NCConfiguration) without a result and is on the dead-letter list now,
+    /// last_error is how the last attempt has ended. This is also called for the chunks on the dead-letter list of the checkpoint
+    /// when they are assigned again after a restart. By default the job continues without the chunk.
+    fn chunk_dead(&mut self, _chunk_id: ChunkID, _attempts: u32, _last_error: &str) -> NCDeadChunkAction {
+        NCDeadChunkAction::Continue
+    }
+    /// The node could not process its data and has sent the error from the user code back to the server.
+    /// Usually the chunk should be given to another node if the error is retryable or marked as failed otherwise,
+    /// the [`ChunkList::chunk_failed()`](crate::ChunkList::chunk_failed) method does this for you.
+    fn process_node_error(&mut self, _node_id: NodeID, _error: &NCJobError) {
+    }
+    /// If checkpoint_file is set in the NCConfiguration this method is called every checkpoint_interval seconds and at the end of the job.
+    /// Return the state of the job, usually `NCCheckpoint::new(&self.chunk_list)`, the server writes it to the checkpoint file.
+    /// When the server is restarted the chunk list can be loaded again with [`NCCheckpoint::restore()`].
+    fn checkpoint(&mut self) -> Result<Option<NCCheckpoint>, NCError> {
+        Ok(None)
+    }
+    /// Returns the number of finished chunks and the total number of chunks, usually `Some(self.chunk_list.progress())`.
+    /// This is only used for the progress events (see [`NCServerStarter::progress_events()`]), it's called after every result.
+    fn job_progress(&self) -> Option<(u64, u64)> {
+        None
+    }
+    /// Returns true if all results of the job have been processed, usually if done == total for `self.chunk_list.progress()`
+    /// and there is no other phase to come. This is called after every result: the job ends right away, the node that has sent the last result
+    /// learns it from the NCServerMessage::ResultAck message and the nodes that wait at the server (long_poll_ms in the NCConfiguration) get
+    /// the NCJobStatus::Finished. The default returns false, then the job ends when assign_chunk() returns ChunkAssignment::Finished.
+    fn job_finished(&self) -> bool {
+        false
+    }
+    /// Every node has to send a heartbeat message to the server. If it doesn't arrive in time (1.2 * the heartbeat value in the NCConfiguration
+    /// + 1 second, since the nodes send their heartbeats at random intervals of heartbeat ± 20 %) then this method is called with the corresponding node id and the node should be marked as offline in this method.
     fn heartbeat_timeout(&mut self, nodes: Vec<NodeID>);
     /// When all the nodes are done with processing and all internal threads are also finished then this method is called.
     /// Usually you want to save all the results to disk and optionally you can write an e-mail to the user that he / she can start
@@ -86,40 +497,340 @@ pub trait NCServer {
     fn finish_job(&mut self);
 }
 
-/// Main data structure for managing and starting the server.
-pub struct NCServerStarter {
+/// Main data structure for managing and starting the server. T is the type of your NCServer implementation, usually it's inferred from start().
+pub struct NCServerStarter<T: NCServer> {
     /// Configuration for the server and the node.
     config: NCConfiguration,
+    /// Receives the progress events, if progress_events() has been called.
+    progress_sender: Option<mpsc::Sender<NCProgressEvent>>,
+    /// Setting this flag ends the job, if abort_handle() has been called.
+    job_done: Option<Arc<AtomicBool>>,
+    /// Exports chunks and imports results while the job is running, if offline_handle() has been called.
+    offline_handle: Option<NCOfflineHandle>,
+    /// Sends custom messages to groups of nodes, if broadcast_handle() has been called.
+    broadcast_handle: Option<NCBroadcastHandle<T::CustomMessageT>>,
+    /// Sends the results to the NCResultStream, if results() has been called.
+    result_sender: Option<NCResultSender<T::ProcessedDataT>>,
+    /// Knows the address of the server once it's listening, see server_handle().
+    server_handle: NCServerHandle,
 }
 
-impl NCServerStarter {
+impl<T: NCServer + Send + 'static> NCServerStarter<T> {
     /// Create a new NCServerStarter using the given configuration
     pub fn new(config: NCConfiguration) -> Self {
         debug!("NCServerStarter::new()");
 
-        NCServerStarter{ config }
+        NCServerStarter{ config, progress_sender: None, job_done: None, offline_handle: None, broadcast_handle: None, result_sender: None, server_handle: NCServerHandle::default() }
+    }
+
+    /// Returns a handle with the address that the server is listening on once start() has opened the socket,
+    /// this is useful with port 0 in the NCConfiguration.
+    pub fn server_handle(&self) -> NCServerHandle {
+        debug!("NCServerStarter::server_handle()");
+
+        self.server_handle.clone()
+    }
+
+    /// Returns the receiving end for the progress events of the next start(), see [`NCProgressEvent`].
+    /// The events can be shown with [`NCServerProgress`](crate::nc_progress::NCServerProgress) (progress feature).
+    /// The channel is unbounded, so events must be received while the server is running.
+    pub fn progress_events(&mut self) -> mpsc::Receiver<NCProgressEvent> {
+        debug!("NCServerStarter::progress_events()");
+
+        let (sender, receiver) = mpsc::channel();
+        self.progress_sender = Some(sender);
+        receiver
+    }
+
+    /// Returns a flag that ends the next start() like the NCAdminCommand::AbortJob command: once it's set the server stops
+    /// with the next node connection, processes the remaining results and calls finish_job(). Used by [`nc_local::run()`](crate::nc_local::run).
+    pub(crate) fn abort_handle(&mut self) -> Arc<AtomicBool> {
+        debug!("NCServerStarter::abort_handle()");
+
+        let job_done = Arc::new(AtomicBool::new(false));
+        self.job_done = Some(job_done.clone());
+        job_done
+    }
+
+    /// Returns a handle that exports chunks for nodes without network access and imports their results while the next start()
+    /// is running, see [`NCOfflineHandle`]. Before start() and after the job is done the handle returns an error.
+    pub fn offline_handle(&mut self) -> NCOfflineHandle {
+        debug!("NCServerStarter::offline_handle()");
+
+        let offline_handle = NCOfflineHandle::default();
+        self.offline_handle = Some(offline_handle.clone());
+        offline_handle
+    }
+
+    /// Returns a handle that sends custom messages to all nodes of a group (see node_tags in the NCConfiguration) while the next start()
+    /// is running, see [`NCBroadcastHandle`]. The messages have the type CustomMessageT of the NCServer.
+    /// Before start() and after the job is done the handle returns an error.
+    pub fn broadcast_handle(&mut self) -> NCBroadcastHandle<T::CustomMessageT> {
+        debug!("NCServerStarter::broadcast_handle()");
+
+        let broadcast_handle = NCBroadcastHandle::default();
+        self.broadcast_handle = Some(broadcast_handle.clone());
+        broadcast_handle
+    }
+
+    /// Returns the results of the next start() as an iterator that ends when the job is done, see [`NCResultStream`].
+    /// Every result that the NCServer trait method process_data_from_node() has accepted is sent to the stream with its chunk id.
+    /// At most capacity results wait in the stream, after that the server waits for the program (backpressure).
+    /// The results have the type ProcessedDataT of the NCServer. The NCServer must not have a post processor, otherwise start() returns an error.
+    pub fn results(&mut self, capacity: usize) -> NCResultStream<T::ProcessedDataT> {
+        debug!("NCServerStarter::results()");
+
+        let (result_sender, result_stream) = NCResultSender::new(capacity);
+        self.result_sender = Some(result_sender);
+        result_stream
+    }
+
+    /// Stops the next start() gracefully when Ctrl-C is pressed: the remaining results are processed, finish_job() is called and
+    /// the job summary is written (with NCJobEndReason::Stopped). Pressing Ctrl-C a second time exits the process right away.
+    /// This can only be called once per process.
+    #[cfg(feature = "ctrlc")]
+    pub fn stop_on_ctrl_c(&mut self) -> Result<(), NCError> {
+        debug!("NCServerStarter::stop_on_ctrl_c()");
+
+        let job_done = self.abort_handle();
+        let loopback = loopback(&self.config);
+        let server_handle = self.server_handle();
+
+        ctrlc::set_handler(move || {
+            if job_done.swap(true, Ordering::Relaxed) {
+                error!("Ctrl-C pressed again, exit now");
+                std::process::exit(130)
+            }
+
+            info!("Ctrl-C pressed, stop server");
+            // Wake up the main loop of the server, it checks the flag after every connection.
+            if let Some(local_addr) = server_handle.local_addr() {
+                let _ = TcpStream::connect(SocketAddr::new(loopback, local_addr.port()));
+            }
+        }).map_err(|e| NCError::custom(format!("Could not set Ctrl-C handler: {}", e)))
     }
 
     /// This is the main method that you call when you start the server. It expects your custom data structure that implements the NCServer trait.
-    pub fn start<T: NCServer + Send + 'static>(&mut self, nc_server: T) -> Result<(), NCError> {
-        debug!("NCServerStarter::new()");
+    /// Returns the summary of the job when it's done or has been aborted, see [`NCJobSummary`].
+    pub fn start(&mut self, nc_server: T) -> Result<NCJobSummary, NCError> {
+        debug!("NCServerStarter::start()");
+
+        let listener = NCListener::bind(&self.config)?;
+
+        self.run(nc_server, listener).map(|(_, job_summary)| job_summary)
+    }
+
+
+    /// Runs the server on the given listener until the job is done, then returns the user data structure and the job summary.
+    /// The heartbeat thread sends its messages to the port of the listener, it can differ from the port in the configuration (port 0).
+    pub(crate) fn run<L: Into<NCListener>>(&mut self, nc_server: T, listener: L) -> Result<(T, NCJobSummary), NCError> {
+        debug!("NCServerStarter::run()");
+
+        self.config.check_keys()?;
+        let listener: NCListener = listener.into();
+        let local_addr = listener.local_addr()?;
+
+        if local_addr.port() != self.config.port {
+            info!("The server listens on port {}", local_addr.port());
+        }
+
+        self.config.log_settings("server");
+        self.config.check_text_protocol().map_err(|problem| NCError::TextProtocol(problem.to_string()))?;
+        self.config.check_signatures().map_err(|problem| NCError::Signature(problem.to_string()))?;
+        self.config.check_mdns().map_err(|problem| NCError::Mdns(problem.to_string()))?;
+        self.config.check_transport().map_err(|problem| NCError::SharedMemory(problem.to_string()))?;
+        let listener = listener.with_transport(&self.config.transport)?;
+
+        // Deregisters the service when the server is done
+        #[cfg(feature = "mdns")]
+        let _mdns_service = match self.config.mdns_announce {
+            true => Some(NCMdnsService::register(&self.config, local_addr.port())?),
+            false => None,
+        };
+
+        let broadcast_handle = self.broadcast_handle.take();
+
+        // The process and the checkpoints use the same seed, also if it has been chosen randomly or comes from the checkpoint
+        if let Some(sample) = sample_for_run(&self.config)? {
+            info!("Sampled run: {}", sample);
+            self.config.sample_seed = Some(sample.seed);
+        }
+
+        let mut server_process = NCServerProcess::new(&self.config, nc_server)?;
+        server_process.progress_sender = self.progress_sender.take();
+
+        // The batches are given to process_result_batch(), so the results can't be post processed afterwards
+        if server_process.result_batcher.is_some() && server_process.post_process_queue.is_some() {
+            return Err(NCError::custom("A post processor can't be used together with result_batch_size"))
+        }
+
+        match self.result_sender.take() {
+            Some(_) if server_process.post_process_queue.is_some() => return Err(NCError::custom("The result stream can't be used together with a post processor")),
+            Some(_) if server_process.result_batcher.is_some() => return Err(NCError::custom("The result stream can't be used together with result_batch_size")),
+            result_sender => server_process.result_sender = result_sender,
+        }
+
+        server_process.replicator = NCReplicator::new(&self.config)?;
+        server_process.event_log = NCEventLog::open(&self.config)?;
+
+        if let Some(path) = &self.config.locality_manifest {
+            let locality = LocalityMap::from_manifest(path)?.with_fallback(Duration::from_millis(self.config.locality_fallback_ms));
+            info!("Locality manifest loaded: {}", path.display());
+            server_process.locality = Some(Arc::new(locality));
+        }
+
+        #[cfg(feature = "ed25519")]
+        if let Some(path) = &self.config.node_keys_file {
+            let key_ring = NCKeyRing::load(path)?;
+            info!("Accept results signed with {} public keys from {}", key_ring.len(), path.display());
+            server_process.key_ring = Some(key_ring);
+        }
+
+        if let Some(job_done) = self.job_done.take() {
+            server_process.job_done = job_done;
+        }
 
-        // let time_start = Instant::now();
-        let server_process = Arc::new(NCServerProcess::new(&self.config, nc_server));
-        let server_heartbeat = NCServerHeartbeat::new(&self.config);
+        server_process.report_progress(NCProgressEvent::Listening(local_addr));
+        self.server_handle.set_local_addr(local_addr)?;
+        server_process.report_job_progress()?;
+        let server_process = Arc::new(server_process);
+        let server_heartbeat = NCServerHeartbeat::new(&NCConfiguration { port: local_addr.port(), ..self.config.clone() })?;
         let thread_pool = ThreadPool::new((self.config.pool_size + 1) as usize);
 
-        self.start_heartbeat_thread(&thread_pool, server_heartbeat);
-        self.start_main_loop(&thread_pool, server_process.clone());
+        let (stop_heartbeat, stop_receiver) = mpsc::channel();
+        self.start_heartbeat_thread(&thread_pool, server_heartbeat, stop_receiver);
+        let result_thread = self.start_result_thread(server_process.clone());
+        let watchdog_thread = self.start_watchdog_thread(server_process.clone());
+        let replication_thread = self.start_replication_thread(server_process.clone());
+        let offline_handle = self.offline_handle.take();
 
-        // let time_taken = (Instant::now() - time_start).as_secs_f64();
-        let time_taken = server_process.calc_total_time();
+        if let Some(offline_handle) = &offline_handle {
+            offline_handle.set_job(Some(server_process.clone()))?;
+        }
 
-        info!("Time taken: {} s, {} min, {} h", time_taken, time_taken / 60.0, time_taken / (60.0 * 60.0));
+        if let Some(broadcast_handle) = &broadcast_handle {
+            broadcast_handle.set_job(Some(server_process.clone()))?;
+        }
+
+        self.server_handle.set_job(Some(server_process.clone()))?;
+
+        self.accept_connections(&listener, &thread_pool, server_process.clone());
+
+        // Otherwise the heartbeat thread would wait up to 2 * heartbeat seconds before it notices that the server is gone.
+        let _ = stop_heartbeat.send(());
+        self.linger(&listener, &thread_pool, server_process.clone());
+
+        // Waits for a running export or import.
+        if let Some(offline_handle) = &offline_handle {
+            offline_handle.set_job(None)?;
+        }
+
+        if let Some(broadcast_handle) = &broadcast_handle {
+            broadcast_handle.set_job(None)?;
+        }
+
+        self.server_handle.set_job(None)?;
+
+        if let Err(payload) = watchdog_thread.join() {
+            let message = panic_message(payload.as_ref());
+            error!("Watchdog thread panicked: {}", message);
+            server_process.report_progress(NCProgressEvent::TaskFailed("watchdog".to_string(), message));
+        }
+
+        if let Some(Err(payload)) = replication_thread.map(|replication_thread| replication_thread.join()) {
+            let message = panic_message(payload.as_ref());
+            error!("Replication thread panicked: {}", message);
+            server_process.report_progress(NCProgressEvent::TaskFailed("replication".to_string(), message));
+        }
 
+        // The node tasks that are still sending or receiving get shutdown_grace_ms to finish their message.
+        let closed = server_process.watchdog.drain(Duration::from_millis(self.config.shutdown_grace_ms))?;
+
+        if closed > 0 {
+            warn!("{} node connections have been closed at shutdown", closed);
+        }
+
+        let job_summary = server_process.finish(result_thread)?;
         thread_pool.join();
 
-        Ok(())
+        // All the threads that have used the server process are done now.
+        match Arc::try_unwrap(server_process) {
+            Ok(server_process) => server_process.nc_server.into_inner().map(|nc_server| (nc_server, job_summary))
+                .map_err(|_| NCError::poisoned::<T>("NCServerStarter::start, take back the server")),
+            Err(_) => Err(NCError::internal("NCServerStarter::start", "the server is still used by another thread")),
+        }
+    }
+
+    /// Checks the configuration and the user code without starting the server, for example before a job that runs for days:
+    /// - the configuration and the encryption keys are valid
+    /// - the port can be bound, it's released right away
+    /// - a sample payload can be encoded and decoded again with every codec
+    /// - initial_data() and assign_chunk() (for a synthetic node) work and their data can be decoded again
+    /// - the metadata of the chunk (see chunk_meta()) is not larger than max_chunk_meta_bytes
+    ///
+    /// The chunk from assign_chunk() is returned with chunk_send_failed(), so nc_server can still be used for start() afterwards.
+    /// Use dry_run_for_node() to decode the data with the types of the node.
+    pub fn dry_run(&self, nc_server: &mut T) -> DryRunReport {
+        debug!("NCServerStarter::dry_run()");
+
+        self.dry_run_as::<T::InitialDataT, T::NewDataT, T::CustomMessageT>(nc_server)
+    }
+
+    /// Same as dry_run(), but the initial data and the data for the chunk are decoded with the types of the given
+    /// NCNode implementation, like the node does it. This catches type mismatches between server and node.
+    pub fn dry_run_for_node<N: NCNode>(&self, nc_server: &mut T) -> DryRunReport {
+        debug!("NCServerStarter::dry_run_for_node()");
+
+        self.dry_run_as::<N::InitialDataT, N::NewDataT, N::CustomMessageT>(nc_server)
+    }
+
+    /// Does all the checks of the dry run, the messages from the server are decoded with the given types.
+    fn dry_run_as<I: DeserializeOwned, N: DeserializeOwned, M: DeserializeOwned>(&self, nc_server: &mut T) -> DryRunReport {
+        let mut report = DryRunReport::new();
+
+        report.check("configuration", self.config.check());
+
+        report.check("bind port", NCListener::bind(&self.config).map(|_| ()));
+
+        let mut nc_communicator = match check_round_trip(&self.config, &mut report) {
+            Some(nc_communicator) => nc_communicator,
+            None => return report
+        };
+
+        let node_id = NodeID::random();
+
+        let result = nc_server.initial_data().and_then(|initial_data| {
+            let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::InitialData(node_id, initial_data, NCCodec::None);
+            let data = nc_communicator.nc_encode_data(&message)?;
+            nc_communicator.nc_decode_data::<NCServerMessage<I, N, M>>(&data).map(|_| ())
+        });
+        report.check("initial data", result);
+
+        let result = match nc_server.assign_chunk(node_id) {
+            Ok(ChunkAssignment::Assigned(chunk_id, data)) => {
+                let metadata = nc_server.chunk_meta(chunk_id);
+                let meta_size = metadata.as_ref().map(chunk_meta_size).unwrap_or(0);
+                let chunk_info = NCChunkInfo { chunk_id, deadline: nc_server.chunk_deadline(chunk_id), metadata, ..Default::default() };
+                let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::JobStatus(NCJobStatus::Unfinished(data, chunk_info));
+                let result = if meta_size > self.config.max_chunk_meta_bytes {
+                    Err(format!("metadata of chunk {} is too large: {} bytes, max: {} bytes", chunk_id, meta_size, self.config.max_chunk_meta_bytes))
+                } else {
+                    nc_communicator.nc_encode_data(&message)
+                        .and_then(|data| nc_communicator.nc_decode_data::<NCServerMessage<I, N, M>>(&data))
+                        .map(|_| ())
+                        .map_err(|e| e.to_string())
+                };
+                nc_server.chunk_send_failed(chunk_id);
+                result
+            }
+            Ok(ChunkAssignment::Waiting) => Err("assign_chunk() returned Waiting instead of a chunk".to_string()),
+            Ok(ChunkAssignment::Finished) => Err("assign_chunk() returned Finished instead of a chunk".to_string()),
+            Ok(ChunkAssignment::PhaseFinished(phase)) => Err(format!("assign_chunk() returned PhaseFinished({}) instead of a chunk", phase)),
+            Err(e) => Err(e.to_string()),
+        };
+        report.check("assign chunk", result);
+
+        report
     }
 
     /// The heartbeat check thread is started here in an endless loop.
@@ -128,12 +839,16 @@ impl NCServerStarter {
     /// If there is an IO error the loop exits because the server also has finished its main loop and
     /// doesn't accept any tcp connections anymore.
     /// The job is done and no more heartbeats will arrive.
-    fn start_heartbeat_thread(&mut self, thread_pool: &ThreadPool, server_heartbeat: NCServerHeartbeat) {
+    /// The thread also exits when something is sent to the stop channel.
+    fn start_heartbeat_thread(&mut self, thread_pool: &ThreadPool, server_heartbeat: NCServerHeartbeat, stop: mpsc::Receiver<()>) {
         debug!("NCServerStarter::start_heartbeat_thread()");
 
         thread_pool.execute(move || {
             loop {
-                server_heartbeat.sleep();
+                if server_heartbeat.sleep_or_stop(&stop) {
+                    debug!("Heartbeat thread stopped");
+                    break
+                }
 
                 if let Err(e) = server_heartbeat.send_check_heartbeat_message() {
                     error!("Error in start_heartbeat_thread(), couldn't send CheckHeartbeat message: {}", e);
@@ -144,21 +859,69 @@ impl NCServerStarter {
         });
     }
 
-    /// In here the main loop and the tcp server are started.
-    /// For every node connection the method start_node_thread() is called, which handles the node request in a separate thread.
-    /// If the job is done one the main loop will exited
-    fn start_main_loop<T: NCServer + Send + 'static>(&self, thread_pool: &ThreadPool, server_process: Arc<NCServerProcess<T, T::CustomMessageT>>) {
-        debug!("NCServerStarter::start_main_loop()");
+    /// The result thread takes the results from the nodes out of the result queue and calls process_data_from_node().
+    /// It exits when the result queue has been closed and all results have been processed.
+    fn start_result_thread(&self, server_process: Arc<NCServerProcess<T, T::CustomMessageT>>) -> thread::JoinHandle<()> {
+        debug!("NCServerStarter::start_result_thread()");
+
+        thread::spawn(move || {
+            server_process.process_results();
+            debug!("Exit start_result_thread() main loop");
+        })
+    }
+
+    /// The watchdog thread closes the node connections that are open for too long, see [`NCConnectionWatchdog`].
+    /// It exits when the job is done.
+    fn start_watchdog_thread(&self, server_process: Arc<NCServerProcess<T, T::CustomMessageT>>) -> thread::JoinHandle<()> {
+        debug!("NCServerStarter::start_watchdog_thread()");
+
+        thread::spawn(move || {
+            while !server_process.is_job_done() {
+                thread::sleep(server_process.watchdog.interval());
+
+                if let Err(e) = server_process.watchdog.check() {
+                    error!("Error in start_watchdog_thread(): {}", e);
+                }
+            }
+            debug!("Exit start_watchdog_thread() main loop");
+        })
+    }
+
+    /// Sends the changes of the chunks to the standby server every replication_interval_ms milliseconds until the job is done,
+    /// if replicate_to is set in the NCConfiguration. The last update is sent in finish(), see the nc_replication module.
+    fn start_replication_thread(&self, server_process: Arc<NCServerProcess<T, T::CustomMessageT>>) -> Option<thread::JoinHandle<()>> {
+        debug!("NCServerStarter::start_replication_thread()");
+
+        let interval = server_process.replicator.as_ref()?.interval();
+
+        Some(thread::spawn(move || {
+            while !server_process.is_job_done() {
+                thread::sleep(interval);
+
+                if let Err(e) = server_process.replicate() {
+                    error!("Error in start_replication_thread(): {}", e);
+                }
+            }
+            debug!("Exit start_replication_thread() main loop");
+        }))
+    }
 
-        let ip_addr: IpAddr = "0.0.0.0".parse().unwrap(); // TODO: Make this configurable ?
-        let socket_addr = SocketAddr::new(ip_addr, server_process.port);
-        let listener = TcpListener::bind(socket_addr).unwrap();
+    /// This is the main loop, it accepts the node connections until the job is done.
+    /// For every node connection the method start_node_thread() is called, which handles the node request in a separate thread.
+    /// If there are already max_connections connections (see [`NCConnectionWatchdog`]) the new connection is closed right away.
+    fn accept_connections(&self, listener: &NCListener, thread_pool: &ThreadPool, server_process: Arc<NCServerProcess<T, T::CustomMessageT>>) {
+        debug!("NCServerStarter::accept_connections()");
 
         loop {
             match listener.accept() {
                 Ok((stream, addr)) => {
                     debug!("Connection from node: {}", addr);
-                    self.start_node_thread(thread_pool, stream, server_process.clone());
+
+                    match NCConnectionWatchdog::register(&server_process.watchdog, &stream) {
+                        Ok(Some(guard)) => self.start_node_thread(thread_pool, stream, guard, server_process.clone()),
+                        Ok(None) => self.start_busy_thread(stream, server_process.clone()), // Too many connections
+                        Err(e) => error!("Could not register connection: {}", e),
+                    }
                 }
                 Err(e) => {
                     error!("IO error while accepting node connections: {}", e);
@@ -166,34 +929,114 @@ impl NCServerStarter {
             }
 
             if server_process.is_job_done() {
-                // Try to exit main loop as soon as possible.
-                // Don't bother with informing the nodes since they have a retry counter for IO errors
-                // and will exit when the counter reaches zero.
-                // The server check_heartbeat thread also exits if there is an IO error, that means the server
+                // Try to exit main loop as soon as possible, the nodes are told that the job is finished in linger().
+                // The server check_heartbeat thread exits if there is an IO error, that means the server
                 // doesn't accept connections anymore.
                 break
             }
         }
+    }
 
-        info!("Job is done, will call NCServer::finish_job()");
-        server_process.nc_server.lock().unwrap().finish_job();
+    /// After the job is done the server keeps accepting node connections for finish_linger_ms milliseconds.
+    /// Every node that asks for new data in that time gets a NCJobStatus::Finished and exits right away,
+    /// otherwise the nodes would only exit after their retry counter is zero.
+    /// Nodes that sleep for longer than that (delay_request_data) still exit with the retry counter.
+    fn linger(&self, listener: &NCListener, thread_pool: &ThreadPool, server_process: Arc<NCServerProcess<T, T::CustomMessageT>>) {
+        debug!("NCServerStarter::linger()");
+
+        if self.config.finish_linger_ms == 0 {
+            return
+        }
+
+        if let Err(e) = listener.set_nonblocking() {
+            error!("Could not tell the nodes that the job is finished: {}", e);
+            return
+        }
+
+        let linger_end = Instant::now() + Duration::from_millis(self.config.finish_linger_ms);
+
+        while Instant::now() < linger_end {
+            match listener.try_accept() {
+                Ok(Some((stream, addr))) => {
+                    debug!("Connection from node after the job is done: {}", addr);
+
+                    match NCConnectionWatchdog::register(&server_process.watchdog, &stream) {
+                        Ok(Some(guard)) => self.start_node_thread(thread_pool, stream, guard, server_process.clone()),
+                        Ok(None) => self.start_busy_thread(stream, server_process.clone()),
+                        Err(e) => error!("Could not register connection: {}", e),
+                    }
+                }
+                Ok(None) => thread::sleep(Duration::from_millis(10)),
+                Err(e) => error!("IO error while accepting node connections: {}", e),
+            }
+        }
     }
     /// This starts a new thread for each node that sends a message to the server and calls the handle_node() method in that thread.
-    fn start_node_thread<T: NCServer + Send + 'static>(&self, thread_pool: &ThreadPool, stream: TcpStream, server_process: Arc<NCServerProcess<T, T::CustomMessageT>>) {
+    /// The guard removes the connection from the watchdog when handle_node() is done.
+    fn start_node_thread(&self, thread_pool: &ThreadPool, stream: NCStream, guard: NCConnectionGuard, server_process: Arc<NCServerProcess<T, T::CustomMessageT>>) {
         debug!("NCServerStarter::start_node_thread()");
 
         thread_pool.execute(move || {
-            if let Err(e) = server_process.handle_node(stream) {
-                error!("Error in handle_node(): {}", e);
+            match server_process.handle_node(stream) {
+                Ok(()) => (),
+                Err(e @ NCError::PeerDisconnected(_)) => warn!("Node has disconnected in handle_node(): {}", e),
+                // The node sends its message again, see NCError::is_transient()
+                Err(e) if e.is_transient() => warn!("Transient error in handle_node(): {}", e),
+                Err(e) => error!("Error in handle_node(): {}", e),
+            }
+
+            drop(guard);
+        });
+    }
+
+    /// There are already max_connections connections, so the node gets a NCRejectReason::ServerBusy in a separate thread, see reject_busy().
+    /// The thread pool is not used, since all of its threads may be busy. If there are already MAX_BUSY_REJECTIONS of these threads
+    /// the stream is just closed and the node runs into an IO error instead.
+    fn start_busy_thread(&self, stream: NCStream, server_process: Arc<NCServerProcess<T, T::CustomMessageT>>) {
+        debug!("NCServerStarter::start_busy_thread()");
+
+        if server_process.busy_rejections.fetch_add(1, Ordering::SeqCst) >= MAX_BUSY_REJECTIONS {
+            server_process.busy_rejections.fetch_sub(1, Ordering::SeqCst);
+            return
+        }
+
+        thread::spawn(move || {
+            if let Err(e) = server_process.reject_busy(stream) {
+                debug!("Could not reject node, server is busy: {}", e);
             }
+
+            server_process.busy_rejections.fetch_sub(1, Ordering::SeqCst);
         });
     }
 }
 
+impl<I, R, F> NCServerStarter<NCDefinitionServer<I, R, F>>
+    where I: Serialize + DeserializeOwned + 'static, R: Serialize + DeserializeOwned + Send + 'static,
+    F: FnMut(ChunkID, &R) -> Result<(), NCError> + Send + 'static {
+    /// Runs the job from the given job definition file (see the [`nc_job_definition`](crate::nc_job_definition) module) with a
+    /// [`NCDefinitionServer`]: the settings from the file replace the ones in the NCConfiguration of this starter and type_check is turned on,
+    /// so that nodes with other input or result types fail right away. process_fn() is called with the id and the result of every chunk.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::TypeMismatch`] error if the file has been written for other types and a [`NCError::JobDefinition`] error
+    /// if it's not a job definition. Otherwise the same as start().
+    pub fn start_from_definition<P: AsRef<Path>>(&mut self, path: P, process_fn: F) -> Result<NCJobSummary, NCError> {
+        debug!("NCServerStarter::start_from_definition()");
+
+        let file = NCJobDefinition::<I, R>::load(path)?;
+        file.definition().settings.apply(&mut self.config);
+        self.config.type_check = true;
+        info!("Starting job '{}' with {} chunks", file.definition().name, file.len());
+
+        self.start(NCDefinitionServer::new(file, process_fn))
+    }
+}
+
 /// Takes care of all the heartbeat time stamps for all the registered nodes.
-struct NCServerHeartbeat {
+pub(crate) struct NCServerHeartbeat {
     /// The socket for the server itself.
-    server_socket: SocketAddr,
+    server_socket: NCServerAddr,
     /// heartbeat timeout duration * 2, this gives the node enough time to send their heartbeat messages.
     duration: Duration,
     /// Handles all the communication
@@ -202,31 +1045,38 @@ struct NCServerHeartbeat {
 
 impl NCServerHeartbeat {
     /// Creates a new ServerHeartbeat with the given configuration.
-    fn new(config: &NCConfiguration) -> Self {
+    /// If the configuration has a job id the CheckHeartbeat message is sent to that job, see [`NCMultiServerStarter`](crate::NCMultiServerStarter).
+    pub(crate) fn new(config: &NCConfiguration) -> Result<Self, NCError> {
         debug!("ServerHeartbeat::new()");
 
-        let ip_addr: IpAddr = "127.0.0.1".parse().unwrap();
-        let server_socket = SocketAddr::new(ip_addr, config.port);
+        let server_socket = NCServerAddr::new(loopback(config).to_string(), config.port);
         let duration = Duration::from_secs(2 * config.heartbeat);
 
-        NCServerHeartbeat{
+        Ok(NCServerHeartbeat{
             server_socket,
             duration,
-            nc_communicator: Mutex::new(NCCommunicator::new(config)),
-        }
+            nc_communicator: Mutex::new(NCCommunicator::new(config)?),
+        })
     }
 
     /// The current thread sleeps for the configured amount of time:
     /// 2 * heartbeat
-    fn sleep(&self) {
+    pub(crate) fn sleep(&self) {
         debug!("ServerHeartbeat::sleep()");
 
         thread::sleep(self.duration);
     }
 
+    /// Same as sleep() but returns early with true if something has been sent to the stop channel or the sender is gone.
+    pub(crate) fn sleep_or_stop(&self, stop: &mpsc::Receiver<()>) -> bool {
+        debug!("ServerHeartbeat::sleep_or_stop()");
+
+        !matches!(stop.recv_timeout(self.duration), Err(mpsc::RecvTimeoutError::Timeout))
+    }
+
     /// Sends the NCNodeMessage::CheckHeartbeat message to itself, so that the server
     /// can check all the registered nodes.
-    fn send_check_heartbeat_message(&self) -> Result<(), NCError> {
+    pub(crate) fn send_check_heartbeat_message(&self) -> Result<(), NCError> {
         debug!("ServerHeartbeat::send_check_heartbeat_message()");
         let message: NCNodeMessage<(), ()> = NCNodeMessage::CheckHeartbeat;
 
@@ -234,55 +1084,290 @@ impl NCServerHeartbeat {
     }
 }
 
-/// Some statistics about the server.
-/// This is the data that will be send when a
-/// NCNodeMessage::GetStatistics message arrived.
-/// More items may be added in the future.
-#[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct NCServerStatistics {
-    /// Total number of nodes, includes inactive nodes
-    num_of_nodes: usize,
-    /// total time from start of server as secs
-    time_taken: f64,
-    /// Node ids and time since last heartbeat as secs
-    hb_time_stamps: Vec<(NodeID, f64)>,
-}
+/// A result from a node together with the chunk it belongs to (if known) and the metadata the node has sent back,
+/// this is what waits in the result queue.
+type QueuedResult<P> = (Option<ChunkID>, Option<ChunkMeta>, P);
+
+/// A result in the result batcher together with the connection to the node that waits for the answer (if any).
+type BatchedResult<P> = (NCBatchedResult<P>, Option<NCStream>);
+
+/// The fields of the NCNodeMessage::Register message: the codecs, the build, the aggregator port and tag, the groups and the settings.
+type Registration = (Vec<NCCodec>, Option<String>, Option<u16>, Option<String>, Vec<String>, Option<NCSharedSettings>);
 
 /// In here the server handles all the messages and generates appropriate responses.
-struct NCServerProcess<T, U> {
-    /// The port the server will listen to.
-    port: u16,
+pub(crate) struct NCServerProcess<T: NCServer, U> {
     /// Every n seconds a heartbeat message is sent from the node to the server.
     heartbeat: u64,
+    /// The job id of the NCConfiguration for the context of the calls into the user code, see the [`nc_context`](crate::nc_context) module.
+    job_id: Option<String>,
     /// Time instance when the server was created
     time_start: Instant,
     /// The user defined data structure that implements the NCServer trait.
     nc_server: Mutex<T>,
     /// Internal list of all the registered nodes.
     node_list: Mutex<NCNodeList<U>>,
+    /// The heartbeats and resources of the nodes, shared with the node list, updated without the lock of the node list.
+    heartbeats: Arc<NCHeartbeatTable>,
     /// Indicates if the job is already done and the server can exit its main loop.
-    job_done: AtomicBool,
+    job_done: Arc<AtomicBool>,
     /// Optional setting if nodes have to move to a new server
     new_server: Mutex<Option<(String, u16)>>,
     /// Handles all the communication
     nc_communicator: Mutex<NCCommunicator>,
+    /// Key for checking the signature of admin messages.
+    admin_key: String,
+    /// Rejects admin messages that have been sent before.
+    replay_guard: Mutex<NCReplayGuard>,
+    /// The job has been paused via the admin protocol, the nodes will get a NCJobStatus::Waiting.
+    job_paused: AtomicBool,
+    /// How long a node waits before it tries again if it has been rejected with NCRejectReason::Paused or ServerBusy.
+    retry_after: Duration,
+    /// The number of threads that reject a connection because the server is busy, see NCServerStarter::start_busy_thread().
+    busy_rejections: AtomicU64,
+    /// The results from the nodes that wait to be processed.
+    result_queue: NCResultQueue<QueuedResult<T::ProcessedDataT>>,
+    /// What to do if process_data_from_node() returns an error.
+    on_process_error: OnProcessError,
+    /// How often a chunk is given to a node again if process_data_from_node() has returned an error for it.
+    max_process_retries: u32,
+    /// Number of times process_data_from_node() has returned an error for each chunk.
+    process_attempts: Mutex<HashMap<ChunkID, u32>>,
+    /// How often a chunk is sent to a node before it moves to the dead-letter list, 0 = no limit.
+    max_chunk_attempts: u32,
+    /// Number of errors reported by the nodes that are not retryable.
+    permanent_failures: AtomicU64,
+    /// Abort the job after n permanent failures, 0 = never.
+    max_permanent_failures: u64,
+    /// The codecs of the server in the order of preference, see [`NCCodec`].
+    codecs: Vec<NCCodec>,
+    /// The settings that must match the nodes, in an otherwise default configuration, see check_node_settings().
+    shared_config: NCConfiguration,
+    /// Number of chunks without any data.
+    empty_chunks: AtomicU64,
+    /// Number of skipped chunks for each reason.
+    skipped_chunks: Mutex<Vec<(String, u64)>>,
+    /// Results that arrived out of order, only used if ordered_results is set in the NCConfiguration.
+    reorder_buffer: Option<Mutex<NCReorderBuffer<QueuedResult<T::ProcessedDataT>>>>,
+    /// The results that wait for the NCServer trait method process_result_batch(), only used if result_batch_size is set in the NCConfiguration.
+    result_batcher: Option<NCResultBatcher<BatchedResult<T::ProcessedDataT>>>,
+    /// The data of the unfinished chunks, only used if cache_chunk_payloads is set in the NCConfiguration.
+    chunk_cache: Option<Mutex<NCChunkCache>>,
+    /// The serialized and compressed data of the unfinished chunks, only used if cache_chunk_frames is set in the NCConfiguration.
+    frame_cache: Option<Mutex<NCFrameCache>>,
+    /// Total time in nanoseconds and number of all the chunks that have been processed by the nodes, used for the average chunk time.
+    chunk_time_nanos: AtomicU64,
+    chunk_count: AtomicU64,
+    /// The checkpoint is written to this file, see [`NCCheckpoint`].
+    checkpoint_file: Option<PathBuf>,
+    /// Write a checkpoint every n seconds.
+    checkpoint_interval: Duration,
+    /// Time when the last checkpoint has been written.
+    last_checkpoint: Mutex<Instant>,
+    /// Limits the number and the lifetime of the node connections.
+    watchdog: Arc<NCConnectionWatchdog>,
+    /// The results that wait for post processing, only used if the NCServer trait method post_processor() returns one.
+    post_process_queue: Option<NCPostProcessQueue<T::ProcessedDataT>>,
+    /// Sends the processed results to the NCResultStream, if NCServerStarter::results() has been called.
+    result_sender: Option<NCResultSender<T::ProcessedDataT>>,
+    /// Sends the state of the chunks to the standby server, if replicate_to is set in the NCConfiguration.
+    replicator: Option<NCReplicator>,
+    /// Stores the artifacts from the nodes, only used if artifacts_dir is set in the NCConfiguration.
+    artifact_store: Option<NCArtifactStore>,
+    /// Nodes with a different build are asked to restart, [`None`] = every build is accepted.
+    required_node_build: Mutex<Option<String>>,
+    /// Number of nodes that have been asked to restart.
+    restart_requests: AtomicU64,
+    /// How long the last heartbeat check took, in microseconds.
+    heartbeat_sweep_micros: AtomicU64,
+    /// How long the slowest heartbeat check took, in microseconds.
+    max_heartbeat_sweep_micros: AtomicU64,
+    /// Number of panics in the user code that have been caught, see catch_user_panic().
+    user_panics: AtomicU64,
+    /// Wakes up the nodes that wait for new chunks, only used if the NCServer trait method chunk_queue_waker() returns one.
+    chunk_queue_waker: Option<ChunkQueueWaker>,
+    /// Nodes wait at most this long for new chunks, see wait_for_assignment().
+    long_poll: Duration,
+    /// No new chunks are handed out after this time, see time_budget_exhausted().
+    max_job_duration: Option<Duration>,
+    /// After max_job_duration the server waits this long for the chunks that are still being processed, see check_time_budget().
+    job_drain_timeout: Duration,
+    /// The end of the time budget has been logged.
+    time_budget_logged: AtomicBool,
+    /// Gets the progress events, only used if NCServerStarter::progress_events() has been called.
+    progress_sender: Option<mpsc::Sender<NCProgressEvent>>,
+    /// Writes the events of the job to the event log, see the [`nc_events`](crate::nc_events) module.
+    event_log: Option<NCEventLog>,
+    /// The shard tags of the chunks from locality_manifest in the NCConfiguration, see the [`nc_locality`](crate::nc_locality) module.
+    locality: Option<Arc<LocalityMap>>,
+    /// Nodes with less free memory (in bytes) get a NCJobStatus::Waiting, 0 = no limit.
+    min_node_free_mem: u64,
+    /// Nodes with less free disk space (in bytes) get a NCJobStatus::Waiting, 0 = no limit.
+    min_node_free_disk: u64,
+    /// Larger metadata of a chunk is not sent to the node and not accepted from the node.
+    max_chunk_meta_bytes: usize,
+    /// Collects the numbers for the job summary.
+    job_stats: Mutex<NCJobStats>,
+    /// Only the chunks of the sample are sent to the nodes, see the nc_sample module.
+    sample: Option<NCSample>,
+    /// The chunks that have been revoked with NCServerHandle::cancel_chunk() while a node holds them.
+    revoked_chunks: Mutex<HashSet<ChunkID>>,
+    /// Collects the first results for the zstd dictionary, [`None`] once it has been trained. See the nc_dictionary module.
+    dictionary_trainer: Mutex<Option<NCDictionaryTrainer>>,
+    /// The id of the zstd dictionary for the results, sent to the nodes with every chunk once it has been trained.
+    result_dictionary: OnceLock<u32>,
+    /// Why the job has ended, set by shut_down().
+    end_reason: Mutex<Option<NCJobEndReason>>,
+    /// Write the job summary next to the checkpoint file.
+    write_job_summary: bool,
+    /// Protocol anomalies abort the job, see protocol_anomaly().
+    strict_mode: bool,
+    /// Warn about nodes with a bigger clock skew, see set_clock_skew().
+    max_clock_skew_ms: u64,
+    /// The offline batches that wait for their results, see the nc_offline module.
+    offline_batches: Mutex<NCOfflineBatches>,
+    /// The nodes from the checkpoint file that haven't sent a message since the restart, see restore_node().
+    restored_nodes: Mutex<HashMap<NodeID, NCNodeContribution>>,
+    /// Results that are not signed are rejected, see the nc_keys module.
+    require_signed_results: bool,
+    /// The public keys that the nodes have sent with the NCNodeMessage::RegisterKey message.
+    #[cfg(feature = "ed25519")]
+    node_keys: Mutex<HashMap<NodeID, NCPublicKey>>,
+    /// Only these public keys are accepted, loaded from node_keys_file in the NCConfiguration. [`None`] = every key is accepted.
+    #[cfg(feature = "ed25519")]
+    key_ring: Option<NCKeyRing>,
+    /// Accept connections with the text protocol, see handle_text().
+    #[cfg(feature = "debug-protocol")]
+    text_protocol: bool,
+    /// Frames from the nodes must not be longer, see max_frame_bytes in the NCConfiguration.
+    max_frame_bytes: u64,
+}
+
+/// Reads the nodes, the offline batches and the dead-letter list from the checkpoint file of an earlier run,
+/// see the [`nc_checkpoint`](crate::nc_checkpoint) module. Without a checkpoint file all of them are empty.
+fn load_server_state(config: &NCConfiguration) -> (HashMap<NodeID, NCNodeContribution>, NCOfflineBatches, Vec<NCDeadChunk>) {
+    let mut offline_batches = NCOfflineBatches::new(config);
+
+    let checkpoint_file = match &config.checkpoint_file {
+        Some(checkpoint_file) if checkpoint_file.exists() => checkpoint_file,
+        _ => return (HashMap::new(), offline_batches, Vec::new())
+    };
+
+    let checkpoint = match NCCheckpoint::load(checkpoint_file) {
+        Ok(checkpoint) => checkpoint,
+        Err(e) => {
+            warn!("Could not read the nodes and offline batches from the checkpoint {}: {}", checkpoint_file.display(), e);
+            return (HashMap::new(), offline_batches, Vec::new())
+        }
+    };
+
+    let since_checkpoint = SystemTime::now().duration_since(checkpoint.saved_at()).unwrap_or_default();
+    let dead_chunks = checkpoint.dead_chunks().to_vec();
+    let (nodes, batches) = checkpoint.into_server_state();
+    info!("Restored {} nodes, {} offline batches and {} dead chunks from the checkpoint {}", nodes.len(), batches.len(), dead_chunks.len(),
+        checkpoint_file.display());
+    offline_batches.restore(batches, since_checkpoint);
+
+    (nodes.into_iter().map(|node| (node.node_id, node)).collect(), offline_batches, dead_chunks)
 }
 
 impl<T: NCServer> NCServerProcess<T, T::CustomMessageT> {
     /// Creates a new ServerProcess with the given user defined nc_server that implements the NCServer trait
-    fn new(config: &NCConfiguration, nc_server: T) -> Self {
+    pub(crate) fn new(config: &NCConfiguration, mut nc_server: T) -> Result<Self, NCError> {
         debug!("ServerProcess::new()");
 
-        NCServerProcess{
-            port: config.port,
+        let post_processor = nc_server.post_processor();
+        let chunk_queue_waker = nc_server.chunk_queue_waker();
+        let (restored_nodes, offline_batches, dead_chunks) = load_server_state(config);
+        let sample = NCSample::from_config(config);
+        let dictionary_trainer = match config.check_result_dictionary() {
+            Ok(()) => NCDictionaryTrainer::new(config),
+            Err(problem) => {
+                warn!("{}, the results are sent without a zstd dictionary", problem);
+                None
+            }
+        };
+        let mut job_stats = NCJobStats::default();
+        job_stats.set_sample(sample);
+        job_stats.restore_dead_chunks(dead_chunks);
+        let node_list = NCNodeList::new();
+        let heartbeats = node_list.heartbeats();
+
+        Ok(NCServerProcess{
             heartbeat: config.heartbeat,
+            job_id: config.job_id.clone(),
             time_start: Instant::now(),
             nc_server: Mutex::new(nc_server),
-            node_list: Mutex::new(NCNodeList::new()),
-            job_done: AtomicBool::new(false),
+            node_list: Mutex::new(node_list),
+            heartbeats,
+            job_done: Arc::new(AtomicBool::new(false)),
             new_server: Mutex::new(None),
-            nc_communicator: Mutex::new(NCCommunicator::new(config)),
-        }
+            nc_communicator: Mutex::new(NCCommunicator::new(config)?),
+            admin_key: config.admin_key.clone(),
+            replay_guard: Mutex::new(NCReplayGuard::new(config)),
+            job_paused: AtomicBool::new(false),
+            retry_after: Duration::from_secs(config.delay_request_data),
+            busy_rejections: AtomicU64::new(0),
+            result_queue: NCResultQueue::new(config),
+            on_process_error: config.on_process_error,
+            max_process_retries: config.max_process_retries,
+            max_chunk_attempts: config.max_chunk_attempts,
+            process_attempts: Mutex::new(HashMap::new()),
+            permanent_failures: AtomicU64::new(0),
+            max_permanent_failures: config.max_permanent_failures,
+            codecs: config.codecs(),
+            shared_config: config.shared_settings().to_config(),
+            empty_chunks: AtomicU64::new(0),
+            skipped_chunks: Mutex::new(Vec::new()),
+            reorder_buffer: if config.ordered_results { Some(Mutex::new(NCReorderBuffer::new(config))) } else { None },
+            result_batcher: if config.result_batch_size > 0 { Some(NCResultBatcher::new(config)) } else { None },
+            chunk_cache: if config.cache_chunk_payloads { Some(Mutex::new(NCChunkCache::new(config))) } else { None },
+            frame_cache: if config.cache_chunk_frames { Some(Mutex::new(NCFrameCache::new(config))) } else { None },
+            chunk_time_nanos: AtomicU64::new(0),
+            chunk_count: AtomicU64::new(0),
+            checkpoint_file: config.checkpoint_file.clone(),
+            checkpoint_interval: Duration::from_secs(config.checkpoint_interval),
+            last_checkpoint: Mutex::new(Instant::now()),
+            watchdog: Arc::new(NCConnectionWatchdog::new(config)),
+            post_process_queue: post_processor.map(|post_processor| NCPostProcessQueue::new(post_processor, config)),
+            result_sender: None,
+            replicator: None,
+            artifact_store: NCArtifactStore::new(config),
+            required_node_build: Mutex::new(config.required_node_build.clone()),
+            restart_requests: AtomicU64::new(0),
+            heartbeat_sweep_micros: AtomicU64::new(0),
+            max_heartbeat_sweep_micros: AtomicU64::new(0),
+            user_panics: AtomicU64::new(0),
+            chunk_queue_waker,
+            long_poll: Duration::from_millis(config.long_poll_ms),
+            max_job_duration: config.max_job_duration,
+            job_drain_timeout: Duration::from_secs(config.job_drain_timeout),
+            time_budget_logged: AtomicBool::new(false),
+            progress_sender: None,
+            event_log: None,
+            locality: None,
+            min_node_free_mem: config.min_node_free_mem,
+            min_node_free_disk: config.min_node_free_disk,
+            max_chunk_meta_bytes: config.max_chunk_meta_bytes,
+            job_stats: Mutex::new(job_stats),
+            sample,
+            revoked_chunks: Mutex::new(HashSet::new()),
+            dictionary_trainer: Mutex::new(dictionary_trainer),
+            result_dictionary: OnceLock::new(),
+            end_reason: Mutex::new(None),
+            write_job_summary: config.write_job_summary,
+            strict_mode: config.strict_mode,
+            max_clock_skew_ms: config.max_clock_skew_ms,
+            offline_batches: Mutex::new(offline_batches),
+            restored_nodes: Mutex::new(restored_nodes),
+            require_signed_results: config.require_signed_results,
+            #[cfg(feature = "ed25519")]
+            node_keys: Mutex::new(HashMap::new()),
+            #[cfg(feature = "ed25519")]
+            key_ring: None,
+            #[cfg(feature = "debug-protocol")]
+            text_protocol: config.text_protocol,
+            max_frame_bytes: config.max_frame_bytes,
+        })
     }
 
     /// Returns true if the job is finished
@@ -292,88 +1377,493 @@ impl<T: NCServer> NCServerProcess<T, T::CustomMessageT> {
         self.job_done.load(Ordering::Relaxed)
     }
 
-    /// Returns the total time the server has been running
-    fn calc_total_time(&self) -> f64 {
-        self.time_start.elapsed().as_secs_f64()
+    /// Returns true if the job has been running for max_job_duration (see the NCConfiguration), then no new chunks are handed out.
+    fn time_budget_exhausted(&self) -> bool {
+        let exhausted = self.max_job_duration.is_some_and(|max_job_duration| self.time_start.elapsed() >= max_job_duration);
+
+        if exhausted && !self.time_budget_logged.swap(true, Ordering::Relaxed) {
+            info!("Time budget of {:?} is exhausted, no new chunks are handed out", self.max_job_duration);
+        }
+
+        exhausted
     }
 
-    /// Shut down the server gracefully when the job is done or
-    /// when it is requested by the message NCNodeMessage::ShutDown
-    fn shut_down(&self) {
-        self.job_done.store(true, Ordering::Relaxed);
+    /// The time until the time budget is exhausted, [`None`] if there is no max_job_duration. It's sent to the node with every chunk.
+    fn job_time_remaining(&self) -> Option<Duration> {
+        self.max_job_duration.map(|max_job_duration| max_job_duration.saturating_sub(self.time_start.elapsed()))
     }
 
-    /// All the message that were sent from a node are handled here. It can be on of these types:
-    /// - NCNodeMessage::Register: every new node has to register first, the server then assigns a new node id and sends some optional initial data back to the node with the
-    ///   NCServerMessage::InitialData message. The server trait method initial_data() is called here.
-    /// - NCNodeMessage::NeedsData: the node needs some data to process and depending on the job state the server answers this request with a NCServerMessage::JobStatus message.
-    ///   The server trait method prepare_data_for_node() is called here.
-    /// - NCNodeMessage::HeartBeat: the node sends a heartbeat message and the server updates the internal node list with the corresponding current time stamp.
-    /// - NCNodeMessage::HasData: the node has finished processing the data and has sent the result back to the server.
-    ///   The server trait method process_data_from_node() is called here.
-    /// - NCNodeMessage::CheckHeartbeat: This message is sent from the check heartbeat thread to the server
-    ///   itself. All the nodes will be checked for the heartbeat time stamp and if a node missed it, the NCServer trait
-    ///   method heartbeat_timeout() is called where the node should be marked as offline.
-    fn handle_node(&self, mut stream: TcpStream) -> Result<(), NCError> {
-        debug!("ServerProcess::handle_node()");
+    /// After the time budget is exhausted the job ends with NCJobEndReason::TimeBudgetExhausted as soon as all the chunks that are still
+    /// being processed have come back, but at most job_drain_timeout seconds later.
+    fn check_time_budget(&self) -> Result<(), NCError> {
+        if !self.time_budget_exhausted() || self.is_job_done() {
+            return Ok(())
+        }
 
-        let request: NCNodeMessage<T::ProcessedDataT, T::CustomMessageT> =
-            self.nc_communicator.lock()?.nc_receive_data(&mut stream)?;
+        let chunks_in_flight = self.node_list.lock()?.chunks_in_flight();
+        let drain_end = self.max_job_duration.unwrap_or_default() + self.job_drain_timeout;
 
-        match request {
-            NCNodeMessage::Register => {
-                let node_id = self.node_list.lock()?.register_new_node();
-                let initial_data = self.nc_server.lock()?.initial_data()?;
-                info!("Registering new node: {}, {}", node_id, stream.peer_addr()?);
-                self.send_initial_data_message(node_id, initial_data, stream)?;
+        if chunks_in_flight == 0 || self.time_start.elapsed() >= drain_end {
+            if chunks_in_flight > 0 {
+                warn!("{} chunks have not come back within the job drain timeout", chunks_in_flight);
             }
-            NCNodeMessage::NeedsData(node_id) => {
-                debug!("Node {} needs data to process", node_id);
 
-                if let Some((server, port)) = self.new_server.lock()?.clone() {
-                    self.node_list.lock()?.remove_node(node_id);
-                    return self.send_new_server_message(server, port, stream)
-                }
+            self.shut_down(NCJobEndReason::TimeBudgetExhausted);
+        }
 
-                if let Some(custom_message) = self.node_list.lock()?.get_message(node_id) {
-                    debug!("Send custom message to node: {}", node_id);
-                    return self.send_custom_message(custom_message, stream)
-                }
+        Ok(())
+    }
 
-                let data_for_node = self.nc_server.lock()?.prepare_data_for_node(node_id)?;
+    /// Returns the total time the server has been running
+    fn calc_total_time(&self) -> f64 {
+        self.time_start.elapsed().as_secs_f64()
+    }
 
-                match data_for_node {
-                    NCJobStatus::Unfinished(data) => {
-                        debug!("Send data to node");
-                        self.send_job_status_unfinished(data, stream)?;
-                    }
-                    NCJobStatus::Waiting => {
-                        debug!("Waiting for other nodes to finish");
-                        self.send_job_status_waiting(stream)?;
-                    }
-                    NCJobStatus::Finished => {
-                        debug!("Job is done, will exit handle_node()");
-                        // Do not bother sending a message to the nodes, they will quit anyways after the retry counter is zero.
-                        // The counter will be decremented if there is an IO error.
-                        // Same for the server heartbeat thread, it will exit its loop if there is an IO error.
-                        self.shut_down();
+    /// Processes all the remaining results after the main loop has exited, then calls the NCServer trait method finish_job().
+    /// Returns the summary of the job, it's also logged and written next to the checkpoint file if write_job_summary is set.
+    /// If the job has been aborted in strict_mode (see protocol_anomaly()) a NCError::StrictViolation is returned instead.
+    fn finish(&self, result_thread: thread::JoinHandle<()>) -> Result<NCJobSummary, NCError> {
+        debug!("ServerProcess::finish()");
+
+        // Process all the remaining results before the job is finished.
+        self.flush_reorder_buffer()?;
+        self.result_queue.close()?;
+
+        if let Some(result_batcher) = &self.result_batcher {
+            result_batcher.close()?;
+        }
+
+        if let Err(payload) = result_thread.join() {
+            let message = panic_message(payload.as_ref());
+            error!("Result thread panicked: {}", message);
+            self.report_progress(NCProgressEvent::TaskFailed("result thread".to_string(), message));
+        }
+
+        self.finish_post_processing()?;
+
+        if let Err(e) = self.save_checkpoint(true) {
+            error!("Could not write checkpoint: {}", e);
+        }
+
+        if let Err(e) = self.replicate() {
+            error!("Could not send the last update to the standby server: {}", e);
+        }
+
+        info!("Job is done, will call NCServer::finish_job()");
+        let _context = nc_context::enter(self.call_context(None)?);
+        self.nc_server.lock()?.finish_job();
+        self.report_progress(NCProgressEvent::JobDone);
+
+        if let Some(result_sender) = &self.result_sender {
+            result_sender.close()?;
+        }
+
+        let time_taken = self.calc_total_time();
+
+        info!("Time taken: {} s, {} min, {} h", time_taken, time_taken / 60.0, time_taken / (60.0 * 60.0));
+
+        let job_summary = self.job_summary()?;
+        info!("Job summary:\n{}", job_summary);
+
+        if let Some(event_log) = &self.event_log {
+            event_log.finish(&job_summary.end_reason);
+        }
+
+        if self.write_job_summary {
+            match &self.checkpoint_file {
+                Some(checkpoint_file) => {
+                    let summary_file = NCJobSummary::file_for_checkpoint(checkpoint_file);
+
+                    match job_summary.save(&summary_file) {
+                        Ok(()) => info!("Job summary written: {}", summary_file.display()),
+                        Err(e) => error!("Could not write job summary: {}", e),
                     }
                 }
+                None => error!("write_job_summary needs a checkpoint_file in the NCConfiguration"),
+            }
+        }
+
+        if let NCJobEndReason::StrictViolation(description) = job_summary.end_reason {
+            return Err(NCError::StrictViolation(description))
+        }
+
+        Ok(job_summary)
+    }
+
+    /// Creates the summary of the job. If the job has not been ended with shut_down() (for example with Ctrl-C) the reason is NCJobEndReason::Stopped.
+    fn job_summary(&self) -> Result<NCJobSummary, NCError> {
+        let end_reason = self.end_reason.lock()?.clone().unwrap_or(NCJobEndReason::Stopped);
+        let progress = self.nc_server.lock()?.job_progress();
+        let duplicates = match &self.reorder_buffer {
+            Some(reorder_buffer) => reorder_buffer.lock()?.dropped(),
+            None => 0,
+        };
+        let skipped = self.skipped_chunks.lock()?.iter().map(|(_, count)| count).sum();
+        let chunks = (duplicates, self.empty_chunks.load(Ordering::Relaxed), skipped);
+
+        Ok(self.job_stats.lock()?.summary(end_reason, progress, chunks, self.time_start.elapsed()))
+    }
+
+    /// A heartbeat from the given node, with its resources if the node sends them.
+    /// Only the shard of the node in the heartbeat table is locked, so a busy node list doesn't delay the heartbeats.
+    fn heartbeat_received(&self, node_id: NodeID, resources: Option<NCResourceReport>) {
+        if self.heartbeats.update(node_id) {
+            self.protocol_anomaly(format!("heartbeat from node {} that has been declared offline", node_id));
+        }
+
+        if let Some(resources) = resources {
+            debug!("Resources of node {}: {}", node_id, resources);
+            self.heartbeats.set_resources(resources, node_id);
+        }
+    }
+
+    /// The context for a call into the user code about the given node, or about the whole job without a node.
+    fn call_context(&self, node_id: Option<NodeID>) -> Result<NCCallContext, NCError> {
+        let node_info = match node_id {
+            Some(node_id) => self.node_list.lock()?.context_info(node_id),
+            None => None,
+        };
+
+        Ok(NCCallContext::new(self.job_id.clone(), node_id, node_info))
+    }
+
+    /// The context for a call into the user code about the given chunk and node, without a node it's the node of the current context.
+    /// This must be called before the chunk is done, the attempts of a finished chunk are not counted anymore.
+    fn chunk_context(&self, node_id: Option<NodeID>, chunk_id: ChunkID) -> Result<NCCallContext, NCError> {
+        let context = match node_id {
+            Some(node_id) => self.call_context(Some(node_id))?,
+            None => NCCallContext { job_id: self.job_id.clone(), ..nc_context::current() },
+        };
+        let attempt = self.job_stats.lock()?.attempt(chunk_id);
+
+        Ok(context.with_chunk(chunk_id, attempt))
+    }
+
+    /// A protocol anomaly (a duplicate or late result, an invalid message, ...) that is only logged by the caller.
+    /// With strict_mode in the NCConfiguration it's added to the job summary and the job is aborted with the first one.
+    fn protocol_anomaly(&self, description: String) {
+        if !self.strict_mode {
+            return
+        }
+
+        error!("Strict mode violation: {}, job will be aborted", description);
+
+        if let Ok(mut job_stats) = self.job_stats.lock() {
+            job_stats.strict_violation(description.clone());
+        }
+
+        self.shut_down(NCJobEndReason::StrictViolation(description));
+    }
+
+    /// Shut down the server gracefully when the job is done or
+    /// when it is requested by the message NCNodeMessage::ShutDown.
+    /// Only the first reason is kept for the job summary.
+    fn shut_down(&self, end_reason: NCJobEndReason) {
+        if let Ok(mut current) = self.end_reason.lock() {
+            if current.is_none() && end_reason.is_abort() {
+                self.report_progress(NCProgressEvent::JobAborted(end_reason.clone()));
+            }
+
+            current.get_or_insert(end_reason);
+        }
+
+        self.job_done.store(true, Ordering::Relaxed);
+
+        // Nodes that wait for new chunks get the NCJobStatus::Finished right away.
+        if let Some(waker) = &self.chunk_queue_waker {
+            let _ = waker.wake();
+        }
+    }
+
+    /// All the message that were sent from a node are handled here. It can be on of these types:
+    /// - NCNodeMessage::Register: every new node has to register first, the server then assigns a new node id and sends some optional initial data back to the node with the
+    ///   NCServerMessage::InitialData message. The server trait method initial_data() is called here.
+    /// - NCNodeMessage::NeedsData: the node needs some data to process and depending on the job state the server answers this request with a NCServerMessage::JobStatus message.
+    ///   The server trait method assign_chunk() is called here and after sending the data either chunk_sent() or chunk_send_failed().
+    ///   If the node runs an outdated build it gets a NCServerMessage::PleaseRestart message instead and is removed from the node list.
+    ///   NCNodeMessage::NeedsDataWithHint is the same but comes with a work hint from the node, see needs_data().
+    ///   NCNodeMessage::NeedsDataPrefetch comes from a node that is still processing its current chunk, it can hold two chunks then.
+    /// - NCNodeMessage::HeartBeat: the node sends a heartbeat message and the server updates the internal node list with the corresponding current time stamp.
+    /// - NCNodeMessage::HasData: the node has finished processing the data and has sent the result back to the server.
+    ///   The result is checked with the server trait method validate() (see validate_result()) and put into the result queue and the server answers with a NCServerMessage::ResultAck message.
+    ///   If the queue is full this may block until there is enough space again (depending on the configuration).
+    ///   The answer to what may be the last result waits until it has been processed, see send_result_received_message().
+    ///   The server trait method process_data_from_node() is called later in process_results(), if it fails the node gets
+    ///   a NCServerMessage::ResultRejected message the next time it needs data (see result_rejected()).
+    ///   NCNodeMessage::HasDataWithMeta is the same but comes with the metadata of the chunk, see process_data_with_meta().
+    /// - NCNodeMessage::Empty, NCNodeMessage::Skip: the node didn't produce any data for the chunk, see chunk_empty() and chunk_skipped().
+    /// - NCNodeMessage::HasSignedData: same as HasDataWithMeta, but the result is signed by the node and the server checks the signature
+    ///   first, see verify_signature(). NCNodeMessage::RegisterKey sends the public key of the node, see register_key().
+    /// - NCNodeMessage::HasCachedData: a result from the cache of the node that has not been acknowledged, see cached_result().
+    /// - NCNodeMessage::NodeFailed: the node could not process the data, see node_failed().
+    /// - NCNodeMessage::ReleaseChunk: the node gives back a chunk that it has fetched in advance, see release_chunk().
+    /// - NCNodeMessage::PollHeartBeat: same as HeartBeat, the server answers with the revoked chunks of the node (see NCServerHandle::cancel_chunk()).
+    /// - NCNodeMessage::ChunkRevoked: the node has dropped a revoked chunk, see chunk_dropped().
+    /// - NCNodeMessage::Artifact: an artifact of a chunk that has been acknowledged already, see store_artifact().
+    /// - NCNodeMessage::GetDictionary: the node asks for the zstd dictionary from its chunk info, see train_result_dictionary().
+    ///   A message that has been compressed with an unknown dictionary gets a NCServerMessage::UnknownDictionary message.
+    /// - NCNodeMessage::CheckHeartbeat: This message is sent from the check heartbeat thread to the server
+    ///   itself. All the nodes will be checked for the heartbeat time stamp and if a node missed it, the NCServer trait
+    ///   method heartbeat_timeout() is called where the node should be marked as offline.
+    /// - NCNodeMessage::Admin: a command signed with the admin key, see handle_admin_message().
+    ///
+    /// A transient error (see [`NCError::is_transient()`]) before the first byte of the message has arrived is retried in place,
+    /// since nothing has been read from the stream yet. Other errors close the connection.
+    fn handle_node(&self, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::handle_node()");
+
+        retry_transient("handle_node()", true, || stream.peek(&mut [0]).map_err(NCError::from))?;
+
+        #[cfg(feature = "debug-protocol")]
+        if self.text_protocol && nc_text_protocol::is_text(&stream)? {
+            return self.handle_text(stream)
+        }
+
+        let data = NCCommunicator::nc_receive_frame(&mut stream, self.max_frame_bytes)?;
+        self.handle_frame(&data, stream)
+    }
+
+    /// Handles a connection with the text protocol (debug-protocol feature): every line is decoded from JSON, encoded as a binary
+    /// frame and handled by handle_frame(). The answer is decoded again and sent back as one line of JSON.
+    /// Messages without an answer (for example HeartBeat) don't get a line back.
+    #[cfg(feature = "debug-protocol")]
+    fn handle_text(&self, stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::handle_text()");
+        info!("Text protocol connection from: {}", stream.peer_addr()?);
+
+        nc_text_protocol::serve(stream, |line| {
+            let request: NCNodeMessage<T::ProcessedDataT, T::CustomMessageT> = nc_text_protocol::decode_line(line)?;
+            let needs_answer = request.needs_answer();
+            let frame = self.nc_communicator.lock()?.nc_encode_data(&request)?;
+            let (server_end, mut node_end) = nc_text_protocol::loopback_pair()?;
+
+            // The answer may be larger than the socket buffer, so it's read while handle_frame() writes it.
+            let data = thread::scope(|scope| {
+                let reader = scope.spawn(move || if needs_answer { NCCommunicator::nc_receive_frame(&mut node_end, u64::MAX).map(Some) } else { Ok(None) });
+                self.handle_frame(&frame, server_end)?;
+                reader.join().map_err(|payload| NCError::internal("text protocol", format!("reader thread panicked: {}", panic_message(payload.as_ref()))))?
+            })?;
+
+            match data {
+                Some(data) => {
+                    let answer: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = self.nc_communicator.lock()?.nc_decode_message(&data)?;
+                    nc_text_protocol::encode_line(&answer).map(Some)
+                }
+                None => Ok(None),
+            }
+        })
+    }
+
+    /// Decodes the message that has already been read from the stream and handles it, see handle_node().
+    /// A message that can't be decoded or doesn't fit is a protocol anomaly, see protocol_anomaly().
+    fn handle_frame(&self, data: &[u8], stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::handle_frame()");
+
+        let result = self.handle_request(data, stream);
+
+        if let Err(e) = &result {
+            if e.is_protocol_violation() {
+                self.protocol_anomaly(format!("invalid message from a node: {}", e));
+            }
+        }
+
+        result
+    }
+
+    /// Decodes the message and handles it, see handle_frame().
+    fn handle_request(&self, data: &[u8], stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::handle_request()");
+
+        let decoded: Result<(NCNodeMessage<T::ProcessedDataT, T::CustomMessageT>, _), _> = self.nc_communicator.lock()?.nc_decode_message_key(data);
+
+        let (request, previous_key) = match decoded {
+            Ok(decoded) => decoded,
+            Err(NCError::UnknownDictionary(id)) => {
+                info!("Message from {} has been compressed with the unknown zstd dictionary {:#010x}", stream.peer_addr()?, id);
+                return self.send_unknown_dictionary_message(id, stream)
+            }
+            Err(e) => {
+                self.reject_undecodable(&e, stream);
+                return Err(e)
+            }
+        };
+
+        if let Some(previous_key) = previous_key {
+            if request.needs_answer() {
+                info!("Node uses an old encryption key, send new key: {}", stream.peer_addr()?);
+                return self.send_rotate_key_message(previous_key, stream)
+            }
+        }
+
+        if let Some(node_id) = request.node_id() {
+            self.restore_node(node_id)?;
+        }
+
+        let _context = nc_context::enter(self.call_context(request.node_id())?);
+
+        match request {
+            NCNodeMessage::Register(node_codecs, node_build, aggregator_port, aggregator_tag, node_tags, node_settings) => {
+                self.register_node(None, (node_codecs, node_build, aggregator_port, aggregator_tag, node_tags, node_settings), stream)?;
+            }
+            NCNodeMessage::Rejoin(node_id, incarnation, node_codecs, node_build, aggregator_port, aggregator_tag, node_tags, node_settings) => {
+                self.register_node(Some((node_id, incarnation)), (node_codecs, node_build, aggregator_port, aggregator_tag, node_tags, node_settings), stream)?;
+            }
+            NCNodeMessage::NeedsData(node_id) => {
+                self.needs_data(node_id, None, false, stream)?;
+            }
+            NCNodeMessage::NeedsDataWithHint(node_id, work_hint) => {
+                self.needs_data(node_id, Some(work_hint), false, stream)?;
             }
-            NCNodeMessage::HeartBeat(node_id) => {
+            NCNodeMessage::NeedsDataPrefetch(node_id, work_hint) => {
+                self.needs_data(node_id, work_hint, true, stream)?;
+            }
+            NCNodeMessage::HeartBeat(node_id, resources) => {
                 debug!("Got heartbeat from node: {}", node_id);
-                self.node_list.lock()?.update_heartbeat(node_id);
+                self.heartbeat_received(node_id, resources);
+            }
+            NCNodeMessage::PollHeartBeat(node_id, resources) => {
+                debug!("Got heartbeat from node: {}, send revoked chunks", node_id);
+                self.heartbeat_received(node_id, resources);
+                let chunks = self.node_list.lock()?.chunks_of_node(node_id);
+
+                let revoked_chunks = self.revoked_chunks.lock()?;
+                let revoked = chunks.into_iter().filter(|chunk_id| revoked_chunks.contains(chunk_id)).collect();
+                drop(revoked_chunks);
+                self.send_chunks_revoked_message(revoked, stream)?;
+            }
+            NCNodeMessage::ChunkRevoked(node_id, chunk_id) => {
+                self.chunk_dropped(node_id, chunk_id)?;
+                self.send_result_ack_message(stream)?;
+                self.report_job_progress()?;
             }
             NCNodeMessage::HasData(node_id, data) => {
                 debug!("Node {} has processed some data and we received the results", node_id);
-                self.nc_server.lock()?.process_data_from_node(node_id, &data)?;
+
+                let mut stream = Some(stream);
+
+                if self.check_unsigned_result(node_id)? && self.validate_result(node_id, &data)? {
+                    stream = self.queue_result(node_id, None, data, stream)?;
+                }
+
+                if let Some(stream) = stream {
+                    self.send_result_received_message(stream)?;
+                }
+            }
+            NCNodeMessage::HasDataWithMeta(node_id, meta, data) => {
+                debug!("Node {} has processed some data and we received the results with metadata", node_id);
+                let meta = self.check_chunk_meta(meta, "from node", node_id);
+                let mut stream = Some(stream);
+
+                if self.check_unsigned_result(node_id)? && self.validate_result(node_id, &data)? {
+                    stream = self.queue_result(node_id, meta, data, stream)?;
+                }
+
+                if let Some(stream) = stream {
+                    self.send_result_received_message(stream)?;
+                }
+            }
+            NCNodeMessage::HasSignedData(node_id, chunk_id, meta, signature, payload) => {
+                debug!("Node {} has processed some data and we received the signed results", node_id);
+                let mut meta = meta.and_then(|meta| self.check_chunk_meta(meta, "from node", node_id));
+                let mut stream = Some(stream);
+
+                if self.verify_signature(node_id, chunk_id, &signature, &payload, &mut meta)? {
+                    let data: T::ProcessedDataT = deserialize_limited(&payload)?;
+
+                    if self.validate_result(node_id, &data)? {
+                        stream = self.queue_result(node_id, meta, data, stream)?;
+                    }
+                }
+
+                if let Some(stream) = stream {
+                    self.send_result_received_message(stream)?;
+                }
+            }
+            NCNodeMessage::HasCachedData(node_id, chunk_id, meta, data) => {
+                self.cached_result(node_id, chunk_id, meta, data, stream)?;
+            }
+            NCNodeMessage::RegisterKey(node_id, public_key) => {
+                self.register_key(node_id, public_key, stream)?;
+            }
+            NCNodeMessage::GetDictionary(node_id, id) => {
+                debug!("Node {} asks for the zstd dictionary {:#010x}", node_id, id);
+                self.send_dictionary_message(id, stream)?;
+            }
+            NCNodeMessage::ClockProbe(node_id, _) => {
+                debug!("Clock probe from node: {}", node_id);
+                self.send_clock_time_message(stream)?;
+            }
+            NCNodeMessage::ClockReport(node_id, node_sent, server_time, node_received) => {
+                self.set_clock_skew(node_id, NCClockSkew::from_timestamps(node_sent, server_time, node_received))?;
+            }
+            NCNodeMessage::Empty(node_id) => {
+                self.chunk_empty(node_id)?;
+                self.send_result_received_message(stream)?;
+                self.report_job_progress()?;
+            }
+            NCNodeMessage::Skip(node_id, reason) => {
+                self.chunk_skipped(node_id, reason)?;
+                self.send_result_received_message(stream)?;
+                self.report_job_progress()?;
+            }
+            NCNodeMessage::NodeFailed(node_id, job_error) => {
+                self.node_failed(node_id, job_error)?;
+            }
+            NCNodeMessage::ReleaseChunk(node_id, chunk_id) => {
+                self.release_chunk(node_id, chunk_id)?;
+                self.send_result_ack_message(stream)?;
+            }
+            NCNodeMessage::Artifact(node_id, chunk_id, name, data) => {
+                self.store_artifact(node_id, chunk_id, &name, &data);
+                self.send_result_ack_message(stream)?;
+            }
+            NCNodeMessage::Aggregate(node_id, chunk_id, _) => {
+                error!("Node {} has sent the result of chunk {} for an aggregator to the server", node_id, chunk_id);
+                return Err(NCError::NodeMsgMismatch)
+            }
+            NCNodeMessage::Delegated(node_id, worker_id, chunk_id) => {
+                if self.chunk_delegated(chunk_id, worker_id, node_id)? {
+                    self.send_result_ack_message(stream)?;
+                } else {
+                    let job_error = NCJobError::result_rejected(format!("chunk {} can't be delegated", chunk_id), true);
+                    self.send_result_rejected_message(job_error, stream)?;
+                }
+            }
+            NCNodeMessage::Aggregated(node_id, chunks, data) => {
+                self.aggregate_received(node_id, chunks, data)?;
+                self.send_result_received_message(stream)?;
+                self.report_job_progress()?;
             }
             NCNodeMessage::CheckHeartbeat => {
                 debug!("Message CheckHeartbeat received!");
                 // Check the heartbeat for all the nodes and call the trait method heartbeat_timeout()
                 // with those nodes to react accordingly.
-                let nodes = self.node_list.lock()?.check_heartbeat(self.heartbeat).collect::<Vec<NodeID>>();
-                self.nc_server.lock()?.heartbeat_timeout(nodes);
+                let sweep_start = Instant::now();
+                let nodes = self.heartbeats.check(self.heartbeat);
+                let sweep_micros = sweep_start.elapsed().as_micros() as u64;
+                self.heartbeat_sweep_micros.store(sweep_micros, Ordering::Relaxed);
+                self.max_heartbeat_sweep_micros.fetch_max(sweep_micros, Ordering::Relaxed);
+
+                for node_id in nodes.iter() {
+                    self.job_stats.lock()?.node_released(*node_id, NCAttemptOutcome::Timeout);
+                    self.report_expired_chunks(*node_id)?;
+                    self.report_progress(NCProgressEvent::NodeOffline(*node_id));
+                }
+
+                {
+                    // Revoked chunks that no node holds anymore (failed, timed out, ...) are forgotten.
+                    let node_list = self.node_list.lock()?;
+                    self.revoked_chunks.lock()?.retain(|chunk_id| node_list.node_with_chunk(*chunk_id).is_some());
+                }
+
+                let mut nc_server = self.nc_server.lock()?;
+                nc_server.heartbeat_timeout(nodes.clone());
+                self.release_chunks(&nodes, true)?;
+                self.expire_offline_batches(&mut *nc_server)?;
+                drop(nc_server);
+                self.check_reorder_gap()?;
+                self.save_checkpoint(false)?;
+                self.check_time_budget()?;
             }
             NCNodeMessage::GetStatistics => {
                 debug!("Statistics requested");
@@ -381,19 +1871,64 @@ impl<T: NCServer> NCServerProcess<T, T::CustomMessageT> {
                 let num_of_nodes = self.node_list.lock()?.len();
                 let time_taken = self.calc_total_time();
                 let hb_time_stamps = self.node_list.lock()?.get_time_stamps();
+                let clock_skews = self.node_list.lock()?.clock_skews();
+                let (local_chunks, locality_fallbacks) = self.job_stats.lock()?.locality();
+                let queue_stats = self.result_queue.stats()?;
+                let batched_results = match &self.result_batcher {
+                    Some(result_batcher) => result_batcher.len()? as u64,
+                    None => 0
+                };
+                let (chunk_cache_hits, chunk_cache_misses) = match &self.chunk_cache {
+                    Some(chunk_cache) => {
+                        let chunk_cache = chunk_cache.lock()?;
+                        (chunk_cache.hits(), chunk_cache.misses())
+                    }
+                    None => (0, 0)
+                };
 
                 let server_statistics = NCServerStatistics{
                     num_of_nodes,
                     time_taken,
                     hb_time_stamps,
+                    result_queue_len: queue_stats.len + batched_results,
+                    result_queue_bytes: queue_stats.bytes,
+                    result_queue_spilled: queue_stats.spilled,
+                    empty_chunks: self.empty_chunks.load(Ordering::Relaxed),
+                    skipped_chunks: self.skipped_chunks.lock()?.clone(),
+                    chunk_cache_hits,
+                    chunk_cache_misses,
+                    frame_cache_hits: self.frame_cache.as_ref().map_or(Ok(0), |frame_cache| frame_cache.lock().map(|frame_cache| frame_cache.hits()))?,
+                    connections_in_flight: self.watchdog.in_flight()?,
+                    rejected_connections: self.watchdog.rejected(),
+                    killed_connections: self.watchdog.killed(),
+                    post_process_failures: self.post_process_queue.as_ref().map_or(0, |queue| queue.failure_count()),
+                    restart_requests: self.restart_requests.load(Ordering::Relaxed),
+                    uncompressed_frames: self.nc_communicator.lock()?.uncompressed_frames(),
+                    group_sizes: self.node_list.lock()?.group_sizes(),
+                    heartbeat_sweep_micros: self.heartbeat_sweep_micros.load(Ordering::Relaxed),
+                    max_heartbeat_sweep_micros: self.max_heartbeat_sweep_micros.load(Ordering::Relaxed),
+                    ipv4_connections: self.watchdog.ipv4_connections(),
+                    ipv6_connections: self.watchdog.ipv6_connections(),
+                    user_panics: self.user_panics.load(Ordering::Relaxed),
+                    clock_skews,
+                    local_chunks,
+                    locality_fallbacks,
                 };
 
                 self.send_server_statistics(server_statistics, stream)?;
             }
+            NCNodeMessage::Admin(message) => {
+                debug!("Admin message received");
+                self.handle_admin_message(message, stream)?;
+            }
+            NCNodeMessage::ShutDown | NCNodeMessage::NewServer(_, _) if !self.admin_key.is_empty() => {
+                error!("Rejected unsigned shut down or new server message from {}, the admin key is set", stream.peer_addr()?);
+                self.send_unauthorized_message(stream)?;
+            }
             NCNodeMessage::ShutDown => {
                 debug!("Shut down requested");
                 // Shut down server gracefully
-                self.shut_down();
+                self.shut_down(NCJobEndReason::Stopped);
             }
             NCNodeMessage::NewServer(server, port) => {
                 debug!("Move all nodes to a new server, address: {}, port: {}", server, port);
@@ -412,59 +1947,4736 @@ impl<T: NCServer> NCServerProcess<T, T::CustomMessageT> {
                     }
                     None => {
                         debug!("Add a custom message to all nodes");
-                        self.node_list.lock()?.add_message_all(message);
+                        let mut node_list = self.node_list.lock()?;
+                        node_list.add_message_all(message);
+                        let num_of_nodes = node_list.len();
+                        drop(node_list);
+                        self.report_progress(NCProgressEvent::Broadcast(None, num_of_nodes));
                     }
                 }
             }
+            NCNodeMessage::SetTags(node_id, node_tags) => {
+                info!("Node {} belongs to the groups: {:?}", node_id, node_tags);
+                self.set_node_tags(node_tags, node_id)?;
+            }
+            NCNodeMessage::GroupMessage(message, tag) => {
+                let num_of_nodes = self.node_list.lock()?.add_message_group(message, &tag);
+                debug!("Add a custom message to group '{}' ({} nodes)", tag, num_of_nodes);
+                self.report_progress(NCProgressEvent::Broadcast(Some(tag), num_of_nodes));
+            }
+            NCNodeMessage::Replicate(_) => {
+                warn!("Replication update from {} ignored, this server is not a standby", stream.peer_addr()?);
+                let job_error = NCJobError::new(NCJobError::REPLICATION, "This server is not a standby", false);
+                self.send_server_failed_message(job_error, stream)?;
+            }
         }
         Ok(())
     }
 
-    /// Sends the NCServerMessage::InitialData message to the node with the given node_id and optional initial_data
-    fn send_initial_data_message(&self, node_id: NodeID, initial_data: Option<T::InitialDataT>, mut stream: TcpStream) -> Result<(), NCError> {
-        debug!("ServerProcess::send_initial_data_message()");
-        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::InitialData(node_id, initial_data);
+    /// The node needs some data to process, see handle_frame(). The answer depends on the state of the job, if a chunk is assigned
+    /// the NCServer trait method assign_chunk_with_context() gets the latest resources of the node and the work hint from the message.
+    /// If the node asks in advance (prefetch) while it's still processing a chunk it gets a NCJobStatus::Waiting instead of
+    /// being moved to a new server, asked to restart or told that the time budget is exhausted, so that the result of its current chunk doesn't get lost.
+    /// The node asks again without prefetch after it has sent that result.
+    fn needs_data(&self, node_id: NodeID, work_hint: Option<NCWorkHint>, prefetch: bool, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::needs_data()");
+        debug!("Node {} needs data to process, work hint: {:?}, prefetch: {}", node_id, work_hint, prefetch);
 
-        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+        let holds_chunk = prefetch && self.node_list.lock()?.holds_chunk(node_id);
+
+        if let Some((server, port)) = self.new_server.lock()?.clone() {
+            if holds_chunk {
+                return self.send_job_status_waiting(stream)
+            }
+
+            self.node_list.lock()?.remove_node(node_id);
+            return self.send_new_server_message(server, port, stream)
+        }
+
+        if self.is_job_done() {
+            debug!("Job is done, tell node {} to exit", node_id);
+            return self.send_job_status_finished(stream)
+        }
+
+        if self.node_list.lock()?.is_disabled(node_id) {
+            info!("Node {} is disabled, will not send any more data", node_id);
+            let message = format!("node {} has been disabled by the admin of the server", node_id);
+            return self.send_rejected_message(NCRejectReason::Disabled, message, None, stream)
+        }
+
+        if let Some(required_build) = self.required_node_build.lock()?.clone() {
+            // An aggregator has to send its combined result first.
+            if self.node_list.lock()?.is_outdated(&required_build, node_id) && !self.node_list.lock()?.has_delegated_chunks(node_id) {
+                if holds_chunk {
+                    return self.send_job_status_waiting(stream)
+                }
+
+                // The node has already sent the result of its last chunk, so nothing gets lost.
+                info!("Node {} runs an outdated build, ask it to restart", node_id);
+                self.node_list.lock()?.remove_node(node_id);
+                self.restart_requests.fetch_add(1, Ordering::Relaxed);
+                let reason = format!("required node build: {}", required_build);
+                return self.send_please_restart_message(reason, stream)
+            }
+        }
+
+        if let Some(job_error) = self.node_list.lock()?.get_job_error(node_id) {
+            debug!("Send error to node: {}", node_id);
+
+            if job_error.code == NCJobError::RESULT_REJECTED {
+                return self.send_result_rejected_message(job_error, stream)
+            }
+
+            return self.send_server_failed_message(job_error, stream)
+        }
+
+        if let Some(custom_message) = self.node_list.lock()?.get_message(node_id) {
+            debug!("Send custom message to node: {}", node_id);
+            return self.send_custom_message(custom_message, stream)
+        }
+
+        if self.time_budget_exhausted() {
+            self.check_time_budget()?;
+
+            // The node has to send the result of its current chunk first.
+            if holds_chunk {
+                return self.send_job_status_waiting(stream)
+            }
+
+            debug!("Time budget is exhausted, tell node {} to exit", node_id);
+            return self.send_job_status_finished(stream)
+        }
+
+        if self.job_paused.load(Ordering::Relaxed) {
+            debug!("Job is paused");
+            return self.send_job_status_waiting(stream)
+        }
+
+        if self.reorder_buffer_full()? {
+            debug!("Reorder buffer is full, wait for missing chunk");
+            return self.send_job_status_waiting(stream)
+        }
+
+        let resources = self.heartbeats.get_resources(node_id);
+
+        if let Some(reason) = resources.as_ref().and_then(|resources| resources.below_floor(self.min_node_free_mem, self.min_node_free_disk)) {
+            info!("Node {} is low on resources ({}), let it wait", node_id, reason);
+            return self.send_job_status_waiting(stream)
+        }
+
+        let tags = self.node_list.lock()?.get_tags(node_id);
+        let context = NCAssignContext { resources, work_hint, tags, locality: self.locality.clone() };
+        let data_for_node = self.wait_for_assignment(node_id, &context);
+
+        match data_for_node {
+            Ok(ChunkAssignment::Assigned(chunk_id, data)) => {
+                debug!("Send data for chunk {} to node", chunk_id);
+                self.send_chunk(node_id, chunk_id, data, prefetch, &mut stream)?;
+            }
+            Ok(ChunkAssignment::Waiting) if self.is_job_done() => {
+                // The job has ended while the node was waiting at the server, see wait_for_assignment().
+                debug!("Job is done, tell node {} to exit", node_id);
+                self.send_job_status_finished(stream)?;
+            }
+            Ok(ChunkAssignment::Waiting) => {
+                debug!("Waiting for other nodes to finish");
+                self.send_job_status_waiting(stream)?;
+            }
+            Ok(ChunkAssignment::Finished) => {
+                debug!("Job is done, will exit handle_node()");
+                // The other nodes get the same message if they ask for new data before the server exits, see NCServerStarter::linger().
+                self.shut_down(NCJobEndReason::Finished);
+                self.send_job_status_finished(stream)?;
+            }
+            Err(NCError::Job(job_error)) => {
+                info!("Could not assign data to node {}: {}", node_id, job_error);
+                self.send_server_failed_message(job_error, stream)?;
+            }
+            Err(e @ NCError::UserPanic(_)) => {
+                // There is no chunk that could be given to a node again or dropped.
+                if self.on_process_error == OnProcessError::AbortJob {
+                    info!("Job aborted: {}", e);
+                    self.shut_down(NCJobEndReason::ProcessError(e.to_string()));
+                }
+
+                self.send_server_failed_message(NCJobError::user_panic(&e), stream)?;
+            }
+            Ok(ChunkAssignment::PhaseFinished(_)) => {
+                // next_assignment() doesn't return this.
+                self.send_job_status_waiting(stream)?;
+            }
+            Err(e) => return Err(e)
+        }
+
+        Ok(())
     }
 
-    /// Sends the NCServerMessage::JobStatus Unfinished message with the given data to the node,
-    fn send_job_status_unfinished(&self, data: T::NewDataT, mut stream: TcpStream) -> Result<(), NCError> {
-        debug!("ServerProcess::send_job_status_unfinished_message()");
-        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::JobStatus(NCJobStatus::Unfinished(data));
+    /// Checks the signature and the nonce of the admin message and executes the command.
+    /// If the message is not valid a NCServerMessage::Unauthorized message is sent back.
+    /// - NCAdminCommand::QueryStatus: the current job status is sent back with the NCServerMessage::Status message.
+    /// - NCAdminCommand::AbortJob: the server shuts down, finish_job() is called.
+    /// - NCAdminCommand::PauseJob / ResumeJob: while paused the nodes get a NCJobStatus::Waiting.
+    /// - NCAdminCommand::DisableNode: the node doesn't get any more data, the NCServer trait method heartbeat_timeout()
+    ///   is called with the node id, so that its chunk of data can be given to another node.
+    /// - NCAdminCommand::SetRequiredNodeBuild: nodes with a different build are asked to restart the next time they need data.
+    /// - NCAdminCommand::Promote: the server is already running, it's just acknowledged (see the nc_replication module).
+    /// - NCAdminCommand::ChunkHistory: the attempts for the chunk are sent back with the NCServerMessage::ChunkHistory message.
+    /// - NCAdminCommand::ResetDeadChunk: the chunk is taken from the dead-letter list and the NCServer trait method chunk_rejected()
+    ///   is called for it, so that it's given to a node again.
+    /// - NCAdminCommand::ShutDown / NewServer: the same as the unsigned NCNodeMessage::ShutDown / NewServer messages,
+    ///   which the server only accepts if it has no admin key.
+    fn handle_admin_message(&self, message: NCAdminMessage, stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::handle_admin_message()");
 
-        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+        let command = match self.check_admin_message(&message) {
+            Ok(command) => command,
+            Err(e) => {
+                error!("Rejected admin message from {}: {}", stream.peer_addr()?, e);
+                return self.send_unauthorized_message(stream)
+            }
+        };
+
+        match command {
+            NCAdminCommand::QueryStatus => {
+                let job_status = if self.is_job_done() {
+                    NCJobStatus::Finished
+                } else {
+                    NCJobStatus::Unfinished((), NCChunkInfo::default())
+                };
+
+                return self.send_status_message(job_status, stream)
+            }
+            NCAdminCommand::ChunkHistory(chunk_id) => {
+                let attempts = self.job_stats.lock()?.chunk_history(*chunk_id);
+                return self.send_chunk_history_message(attempts, stream)
+            }
+            NCAdminCommand::AbortJob(reason) => {
+                info!("Job aborted: {}", reason);
+                self.shut_down(NCJobEndReason::Aborted(reason.clone()));
+            }
+            NCAdminCommand::PauseJob => {
+                info!("Job paused");
+                self.job_paused.store(true, Ordering::Relaxed);
+            }
+            NCAdminCommand::ResumeJob => {
+                info!("Job resumed");
+                self.job_paused.store(false, Ordering::Relaxed);
+            }
+            NCAdminCommand::DisableNode(node_id) => {
+                info!("Disable node: {}", node_id);
+
+                if self.node_list.lock()?.disable_node(*node_id) {
+                    self.job_stats.lock()?.node_released(*node_id, NCAttemptOutcome::Released);
+                    self.report_progress(NCProgressEvent::NodeOffline(*node_id));
+                    let mut nc_server = self.nc_server.lock()?;
+                    nc_server.heartbeat_timeout(vec![*node_id]);
+                    self.release_chunks(&[*node_id], true)?;
+                }
+            }
+            NCAdminCommand::ResetDeadChunk(chunk_id) => {
+                if self.job_stats.lock()?.reset_dead_chunk(*chunk_id) {
+                    info!("Reset dead chunk {}", chunk_id);
+                    self.nc_server.lock()?.chunk_rejected(*chunk_id, true);
+
+                    if let Some(waker) = &self.chunk_queue_waker {
+                        waker.wake()?;
+                    }
+                } else {
+                    warn!("Chunk {} is not on the dead-letter list", chunk_id);
+                }
+            }
+            NCAdminCommand::SetRequiredNodeBuild(build) => {
+                info!("Set required node build: {:?}", build);
+                *self.required_node_build.lock()? = build.clone();
+            }
+            NCAdminCommand::Promote => {
+                info!("Promote: this server is not a standby, it's already serving the nodes");
+            }
+            NCAdminCommand::ShutDown => {
+                info!("Shut down requested");
+                self.shut_down(NCJobEndReason::Stopped);
+            }
+            NCAdminCommand::NewServer(server, port) => {
+                info!("Move all nodes to a new server, address: {}, port: {}", server, port);
+                *self.new_server.lock()? = Some((server.clone(), *port));
+            }
+            NCAdminCommand::RotateKey(key) => {
+                info!("Rotate encryption key");
+                // The admin client still uses the old key for the answer
+                if let Err(e) = NCCommunicator::check_key(key) {
+                    warn!("Rotate key rejected: {}", e);
+                    let problem = match &e {
+                        NCError::InvalidKey(problem) => problem.clone(),
+                        e => e.to_string(),
+                    };
+                    self.send_rejected_message(NCRejectReason::InvalidKey, problem, None, stream)?;
+                    return Err(e)
+                }
+
+                self.send_admin_ack_message(stream)?;
+                return self.nc_communicator.lock()?.rotate_key(key)
+            }
+        }
+
+        self.send_admin_ack_message(stream)
     }
 
-    /// Send the NCServerMessage::JobStatus Waiting message to the node.
-    fn send_job_status_waiting(&self, mut stream: TcpStream) -> Result<(), NCError> {
-        debug!("ServerProcess::send_job_status_waiting()");
-        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::JobStatus(NCJobStatus::Waiting);
+    /// Verifies the signature with the admin key and rejects replayed messages.
+    fn check_admin_message<'a>(&self, message: &'a NCAdminMessage) -> Result<&'a NCAdminCommand, NCError> {
+        debug!("ServerProcess::check_admin_message()");
 
-        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+        let command = message.verify(&self.admin_key)?;
+        self.replay_guard.lock()?.check(message)?;
+        Ok(command)
     }
 
-    /// Send the NCServerMessage::Statistics to the node.
-    fn send_server_statistics(&self, server_statistics: NCServerStatistics, mut stream: TcpStream) -> Result<(), NCError> {
-        debug!("ServerProcess::send_server_statistics()");
-        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::Statistics(server_statistics);
+    /// Sends the NCServerMessage::InitialData message to the node with the given node_id, optional initial_data and the chosen codec.
+    /// The message is already encoded with that codec.
+    fn send_initial_data_message(&self, node_id: NodeID, initial_data: Option<T::InitialDataT>, codec: NCCodec, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_initial_data_message()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::InitialData(node_id, initial_data, codec);
 
-        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+        self.nc_communicator.lock()?.nc_send_data2_codec(&message, codec, &mut stream)
     }
 
-    /// Send the NCServerMessage::NewServer message to the node.
-    fn send_new_server_message(&self, server: String, port: u16, mut stream: TcpStream) -> Result<(), NCError> {
-        debug!("ServerProcess::send_new_server_message()");
-        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::NewServer(server, port);
+    /// Sends the NCServerMessage::Rejected message with the given reason to the node. It's not compressed, since the node may not
+    /// have a codec in common with the server.
+    fn send_rejected_message(&self, reason: NCRejectReason, message: String, retry_after: Option<Duration>, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_rejected_message()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::Rejected { reason, message, retry_after };
 
-        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+        self.nc_communicator.lock()?.nc_send_data2_codec(&message, NCCodec::None, &mut stream)
     }
 
-    /// Send the NCServerMessage::Command message to the node.
-    fn send_custom_message(&self, custom_message: T::CustomMessageT, mut stream: TcpStream) -> Result<(), NCError> {
-        debug!("ServerProcess::send_custom_message()");
-        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::CustomMessage(custom_message);
+    /// Reads the message of the node and answers with NCRejectReason::ServerBusy, the node tries again after retry_after.
+    /// The node has one second to send its message.
+    fn reject_busy(&self, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::reject_busy()");
 
-        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        NCCommunicator::nc_receive_frame(&mut stream, self.max_frame_bytes)?;
+
+        info!("Server is busy, reject node: {}", stream.peer_addr()?);
+        let message = "the server has max_connections connections already, try again later".to_string();
+        self.send_rejected_message(NCRejectReason::ServerBusy, message, Some(self.retry_after), stream)
+    }
+
+    /// The message could not be decoded, so the node gets a rejection without encryption: NCRejectReason::WrongKey if it could not be
+    /// decrypted and VersionMismatch if the data doesn't fit, see the nc_communicator module. Other errors (for example a broken connection)
+    /// don't get an answer.
+    fn reject_undecodable(&self, error: &NCError, mut stream: NCStream) {
+        let (reason, message) = match error {
+            NCError::Decrypt => (NCRejectReason::WrongKey,
+                "the server could not decrypt the message, the node must use the same key (and encrypt setting) as the server".to_string()),
+            NCError::Deserialize(_) | NCError::Bincode(_) | NCError::DataTooShort(_, _) | NCError::Decompress(_) | NCError::UnknownCodec(_) | NCError::TypeMismatch { .. } =>
+                (NCRejectReason::VersionMismatch, format!("the server could not decode the message ({}), the node must run the same version of node_crunch and of the job as the server", error)),
+            _ => return,
+        };
+
+        if let Err(e) = self.nc_communicator.lock().map_err(NCError::from).and_then(|nc_communicator| nc_communicator.nc_send_plain_rejection(reason, message, &mut stream)) {
+            debug!("Could not send the rejection: {}", e);
+        }
+    }
+
+    /// Compares the settings of a new node with the settings of the server (see [`NCConfiguration::diff()`]) and logs every difference
+    /// with its severity. Returns the differences with the severity Error, the node is not registered then.
+    fn check_node_settings(&self, node_config: &NCConfiguration, peer: SocketAddr) -> Vec<ConfigDiff> {
+        debug!("ServerProcess::check_node_settings()");
+
+        let diffs = self.shared_config.diff(node_config);
+
+        for diff in diffs.iter() {
+            match diff.severity {
+                ConfigDiffSeverity::Info => info!("Setting of new node {} differs: {}", peer, diff),
+                ConfigDiffSeverity::Warning => warn!("Setting of new node {} differs: {}", peer, diff),
+                ConfigDiffSeverity::Error => error!("Setting of new node {} doesn't match: {}", peer, diff),
+            }
+        }
+
+        diffs.into_iter().filter(|diff| diff.severity == ConfigDiffSeverity::Error).collect()
+    }
+
+    /// Sends the NCServerMessage::JobStatus Unfinished message with the data and the chunk info (optional deadline,
+    /// average chunk time and metadata) for the given chunk to the node.
+    /// The data is encoded with the codec that has been negotiated with the node.
+    /// If the message could be sent completely the NCServer trait method chunk_sent() is called,
+    /// otherwise chunk_send_failed() is called. A chunk that the node has asked for in advance (prefetch) is kept together with its current chunk.
+    fn send_chunk<W: Write>(&self, node_id: NodeID, chunk_id: ChunkID, data: T::NewDataT, prefetch: bool, stream: &mut W) -> Result<(), NCError> {
+        debug!("ServerProcess::send_chunk()");
+        let attempt = self.job_stats.lock()?.attempt(chunk_id) + 1;
+        let _context = nc_context::enter(self.call_context(Some(node_id))?.with_chunk(chunk_id, attempt));
+        let (deadline, metadata) = {
+            let mut nc_server = self.nc_server.lock()?;
+            (nc_server.chunk_deadline(chunk_id), nc_server.chunk_meta(chunk_id))
+        };
+        let metadata = metadata.and_then(|metadata| self.check_chunk_meta(metadata, "for node", node_id));
+        let aggregator = self.node_list.lock()?.aggregator_for(node_id, self.heartbeat);
+        let codec = self.node_list.lock()?.get_codec(node_id);
+        // The aggregator doesn't have the dictionary, so only the results that come to the server directly use it
+        let result_dictionary = self.result_dictionary.get().copied().filter(|_| aggregator.is_none() && matches!(codec, NCCodec::Zstd(_)));
+        let chunk_info = NCChunkInfo { chunk_id, deadline, average_chunk_time: self.average_chunk_time()?, aggregator, metadata, attempt,
+            job_time_remaining: self.job_time_remaining(), result_dictionary };
+        let cache_frame = self.frame_cache.is_some() && self.nc_server.lock()?.cache_chunk(chunk_id);
+        let chunk_info_data = if cache_frame { Some(nc_envelope::serialize_versioned(&chunk_info).map_err(NCError::Serialize)?) } else { None };
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::JobStatus(NCJobStatus::Unfinished(data, chunk_info));
+
+        // The node holds the chunk and the attempt runs before the last byte is sent, otherwise a fast node may send its result
+        // (or fail) before the server knows the chunk. Both are taken back if the chunk can't be sent.
+        let first_send = self.job_stats.lock()?.chunk_sent(chunk_id, node_id);
+
+        if prefetch {
+            self.node_list.lock()?.set_prefetched_chunk(chunk_id, node_id);
+        } else {
+            self.node_list.lock()?.set_current_chunk(chunk_id, node_id);
+        }
+
+        let result = match chunk_info_data {
+            Some(chunk_info_data) => self.send_cached_frame(chunk_id, codec, &message, &chunk_info_data, stream),
+            None => self.nc_communicator.lock()?.nc_send_data2_codec(&message, codec, stream),
+        };
+
+        match result {
+            Ok(()) => {
+                if let Some(locality) = &self.locality {
+                    let local = locality.is_local(chunk_id, &self.node_list.lock()?.get_tags(node_id));
+                    self.job_stats.lock()?.chunk_locality(local);
+                }
+
+                self.report_progress(NCProgressEvent::ChunkSent(node_id, chunk_id));
+                self.nc_server.lock()?.chunk_sent(chunk_id)
+            }
+            Err(ref e) => {
+                let disconnected = matches!(e, NCError::PeerDisconnected(_));
+
+                if disconnected {
+                    warn!("Node {} has disconnected while chunk {} was sent: {}", node_id, chunk_id, e);
+                } else {
+                    error!("Could not send chunk {} to node: {}", chunk_id, e);
+                }
+
+                self.node_list.lock()?.release_chunk(chunk_id, node_id);
+                self.job_stats.lock()?.chunk_not_sent(chunk_id, node_id, first_send);
+                let mut nc_server = self.nc_server.lock()?;
+                nc_server.chunk_send_failed(chunk_id);
+
+                if disconnected {
+                    nc_server.node_disconnected(node_id);
+                }
+
+                self.release_cached_chunk(chunk_id, true)?;
+            }
+        }
+
+        result
+    }
+
+    /// Sends the NCServerMessage::JobStatus Unfinished message with the frame from the frame cache (see cache_chunk_frames in the NCConfiguration),
+    /// so only the serialized chunk info is compressed. If the chunk is not in the cache yet, the message is serialized and its chunk data
+    /// is compressed and stored in the cache. The serialized chunk info is the end of the serialized message.
+    fn send_cached_frame<W: Write>(&self, chunk_id: ChunkID, codec: NCCodec, message: &NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT>,
+        chunk_info_data: &[u8], stream: &mut W) -> Result<(), NCError> {
+        debug!("ServerProcess::send_cached_frame()");
+
+        let frame_cache = match &self.frame_cache {
+            Some(frame_cache) => frame_cache,
+            None => return self.nc_communicator.lock()?.nc_send_data2_codec(message, codec, stream),
+        };
+
+        let cached = frame_cache.lock()?.get(chunk_id, codec);
+
+        let frame = match cached {
+            Some(frame) => {
+                debug!("Use cached frame for chunk {}", chunk_id);
+                frame
+            }
+            None => {
+                let mut serialized = bincode::serialize(message).map_err(NCError::Serialize)?;
+                serialized.truncate(serialized.len() - chunk_info_data.len());
+                let message_type = type_name::<NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT>>();
+                let frame = Arc::new(self.nc_communicator.lock()?.prepare_frame(serialized, message_type, codec, message.payload_type())?);
+                frame_cache.lock()?.insert(chunk_id, codec, frame.clone());
+                frame
+            }
+        };
+
+        self.nc_communicator.lock()?.nc_send_prepared(&frame, chunk_info_data, stream)
+    }
+
+    /// Send the NCServerMessage::JobStatus Waiting message to the node.
+    fn send_job_status_waiting(&self, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_job_status_waiting()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::JobStatus(NCJobStatus::Waiting);
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+
+    /// Send the NCServerMessage::JobStatus message with NCJobStatus::Finished to the node, so that it exits.
+    /// If the job has been aborted (admin protocol, too many failures, an error in process_data_from_node(), a strict mode violation
+    /// or a chunk that has run out of attempts) the node gets the NCServerMessage::JobAborted message with the reason instead.
+    fn send_job_status_finished(&self, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_job_status_finished()");
+
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = match &*self.end_reason.lock()? {
+            Some(end_reason) if end_reason.is_abort() => NCServerMessage::JobAborted(end_reason.to_string()),
+            _ => NCServerMessage::JobStatus(NCJobStatus::Finished),
+        };
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+
+    /// Send the NCServerMessage::Statistics to the node.
+    fn send_server_statistics(&self, server_statistics: NCServerStatistics, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_server_statistics()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::Statistics(server_statistics);
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+
+    /// Send the NCServerMessage::NewServer message to the node.
+    fn send_new_server_message(&self, server: String, port: u16, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_new_server_message()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::NewServer(server, port);
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+
+    /// Send the NCServerMessage::Command message to the node.
+    fn send_custom_message(&self, custom_message: T::CustomMessageT, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_custom_message()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::CustomMessage(custom_message);
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+
+    /// Send the NCServerMessage::Status message to the node.
+    fn send_status_message(&self, job_status: NCJobStatus<()>, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_status_message()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::Status(job_status);
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+
+    /// Send the NCServerMessage::ChunksRevoked message with the given chunks to the node.
+    fn send_chunks_revoked_message(&self, chunks: Vec<ChunkID>, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_chunks_revoked_message()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::ChunksRevoked(chunks);
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+
+    /// Send the NCServerMessage::ChunkHistory message with the given attempts to the admin client.
+    fn send_chunk_history_message(&self, attempts: Vec<NCChunkAttempt>, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_chunk_history_message()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::ChunkHistory(attempts);
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+
+    /// Calls the NCServer trait method assign_chunk_with_context() for the given node.
+    /// If it returns ChunkAssignment::PhaseFinished then phase_finished() is called and assign_chunk_with_context() is called again.
+    /// If cache_chunk_payloads is set in the NCConfiguration, chunks that have to be sent again are assigned first with
+    /// the cached data (preferably one that fits the max_bytes hint of the node) and the data from assign_chunk() is put into the cache.
+    /// In a sampled run the chunks that are not part of the sample are given to the NCServer trait method chunk_left_out() instead.
+    /// Chunks that have run out of attempts (max_chunk_attempts in the NCConfiguration) are not sent again, see chunk_dead().
+    fn next_assignment(&self, node_id: NodeID, context: &NCAssignContext) -> Result<ChunkAssignment<T::NewDataT>, NCError> {
+        debug!("ServerProcess::next_assignment()");
+
+        // assign_chunk() must not be called after the time budget is exhausted, see time_budget_exhausted().
+        if self.time_budget_exhausted() {
+            return Ok(ChunkAssignment::Waiting)
+        }
+
+        let mut nc_server = self.nc_server.lock()?;
+
+        if let Some(chunk_cache) = &self.chunk_cache {
+            let cached = chunk_cache.lock()?.next_redispatch(context.max_bytes(), |chunk_id| nc_server.reassign_chunk(chunk_id, node_id));
+
+            match cached {
+                Some((chunk_id, _)) if self.job_stats.lock()?.attempts_exhausted(chunk_id, self.max_chunk_attempts) => {
+                    // The job goes on with a new chunk from assign_chunk() below
+                    let continue_job = self.chunk_dead(&mut nc_server, chunk_id)?;
+
+                    if !continue_job {
+                        return Ok(ChunkAssignment::Waiting)
+                    }
+                }
+                Some((chunk_id, payload)) => {
+                    debug!("Use cached data for chunk {}", chunk_id);
+                    let data = bincode::deserialize(&payload).map_err(NCError::Deserialize)?;
+                    return Ok(ChunkAssignment::Assigned(chunk_id, data))
+                }
+                None => (),
+            }
+        }
+
+        let mut left_out = None;
+        let mut dead = None;
+
+        loop {
+            match self.catch_user_panic("assign_chunk_with_context()", || nc_server.assign_chunk_with_context(node_id, context))? {
+                ChunkAssignment::PhaseFinished(phase) => {
+                    info!("Phase {} finished", phase);
+                    self.catch_user_panic("phase_finished()", || nc_server.phase_finished(phase))?;
+                    self.job_stats.lock()?.phase_finished();
+
+                    // The chunk ids of the next phase may have other data
+                    if let Some(frame_cache) = &self.frame_cache {
+                        frame_cache.lock()?.clear();
+                    }
+                }
+                ChunkAssignment::Assigned(chunk_id, _) if left_out == Some(chunk_id) => {
+                    warn!("Chunk {} is assigned again after it has been left out, chunk_left_out() should mark it as finished", chunk_id);
+                    return Ok(ChunkAssignment::Waiting)
+                }
+                ChunkAssignment::Assigned(chunk_id, _) if self.sample.is_some_and(|sample| !sample.contains(chunk_id)) => {
+                    debug!("Chunk {} is not part of the sample", chunk_id);
+                    let _context = nc_context::enter(self.chunk_context(Some(node_id), chunk_id)?);
+                    self.catch_user_panic("chunk_left_out()", || {
+                        nc_server.chunk_left_out(chunk_id);
+                        Ok(())
+                    })?;
+                    self.job_stats.lock()?.chunk_left_out();
+                    left_out = Some(chunk_id);
+                }
+                ChunkAssignment::Assigned(chunk_id, _) if dead == Some(chunk_id) => {
+                    warn!("Chunk {} is assigned again after it has run out of attempts, chunk_rejected() should mark it as failed", chunk_id);
+                    return Ok(ChunkAssignment::Waiting)
+                }
+                ChunkAssignment::Assigned(chunk_id, _) if self.job_stats.lock()?.attempts_exhausted(chunk_id, self.max_chunk_attempts) => {
+                    if !self.chunk_dead(&mut nc_server, chunk_id)? {
+                        return Ok(ChunkAssignment::Waiting)
+                    }
+
+                    dead = Some(chunk_id);
+                }
+                ChunkAssignment::Assigned(chunk_id, data) => {
+                    if let Some(chunk_cache) = &self.chunk_cache {
+                        if nc_server.cache_chunk(chunk_id) {
+                            let payload = bincode::serialize(&data).map_err(NCError::Serialize)?;
+                            chunk_cache.lock()?.insert(chunk_id, payload);
+                        }
+                    }
+
+                    return Ok(ChunkAssignment::Assigned(chunk_id, data))
+                }
+                assignment => return Ok(assignment)
+            }
+        }
+    }
+
+    /// The given chunk has run out of attempts: it's put on the dead-letter list and the NCServer trait method chunk_dead() decides
+    /// what happens next. Returns true if the job continues, then the chunk has been given to chunk_rejected() to mark it as failed.
+    fn chunk_dead(&self, nc_server: &mut T, chunk_id: ChunkID) -> Result<bool, NCError> {
+        debug!("ServerProcess::chunk_dead()");
+
+        let _context = nc_context::enter(self.chunk_context(None, chunk_id)?);
+        let dead_chunk = self.job_stats.lock()?.chunk_dead(chunk_id);
+        warn!("Chunk {} has run out of attempts: {}, last error: {}", chunk_id, dead_chunk.attempts, dead_chunk.last_error);
+        self.report_progress(NCProgressEvent::ChunkDead(chunk_id, dead_chunk.attempts, dead_chunk.last_error.clone()));
+        let action = self.catch_user_panic("chunk_dead()", || Ok(nc_server.chunk_dead(chunk_id, dead_chunk.attempts, &dead_chunk.last_error)))?;
+
+        match action {
+            NCDeadChunkAction::AbortJob => {
+                error!("Chunk {} is dead, job will be aborted", chunk_id);
+                self.shut_down(NCJobEndReason::ChunkDead(chunk_id));
+                Ok(false)
+            }
+            NCDeadChunkAction::Continue => {
+                nc_server.chunk_rejected(chunk_id, false);
+                self.job_stats.lock()?.chunk_failed();
+                self.release_cached_chunk(chunk_id, false)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Same as next_assignment(), but if the NCServer trait method chunk_queue_waker() has returned a waker a ChunkAssignment::Waiting
+    /// is not returned right away: the server waits up to long_poll_ms (see the NCConfiguration) for the waker and asks again.
+    fn wait_for_assignment(&self, node_id: NodeID, context: &NCAssignContext) -> Result<ChunkAssignment<T::NewDataT>, NCError> {
+        debug!("ServerProcess::wait_for_assignment()");
+
+        let waker = match &self.chunk_queue_waker {
+            Some(waker) if !self.long_poll.is_zero() => waker,
+            _ => return self.next_assignment(node_id, context),
+        };
+
+        let deadline = Instant::now() + self.long_poll;
+
+        loop {
+            // Taken before assign_chunk() is called, so that a new chunk in between is not missed.
+            let changes = waker.changes()?;
+            let assignment = self.next_assignment(node_id, context);
+            let now = Instant::now();
+
+            if !matches!(assignment, Ok(ChunkAssignment::Waiting)) || now >= deadline || self.is_job_done() || self.time_budget_exhausted() {
+                return assignment
+            }
+
+            debug!("Node {} waits for new chunks", node_id);
+            waker.wait(changes, deadline - now)?;
+        }
+    }
+
+    /// Takes the chunks of the given nodes out of the node list and calls release_cached_chunk() for each of them.
+    /// The current chunks are only taken if cache_chunk_payloads is set in the NCConfiguration. The delegated chunks of
+    /// aggregators are always taken, so that a combined result that arrives later for them is dropped, see aggregate_received().
+    fn release_chunks(&self, nodes: &[NodeID], retry: bool) -> Result<(), NCError> {
+        let chunks: Vec<ChunkID> = {
+            let mut node_list = self.node_list.lock()?;
+            nodes.iter().flat_map(|node_id| {
+                let mut chunks = node_list.take_delegated_chunks(*node_id);
+
+                if self.chunk_cache.is_some() {
+                    // Also the chunk that the node may have fetched in advance.
+                    chunks.extend(std::iter::from_fn(|| node_list.take_current_chunk(*node_id)).map(|(chunk_id, _)| chunk_id));
+                } else {
+                    // The user code gives all chunks of the node to other nodes, so a late release_chunk() must not do it again.
+                    node_list.take_prefetched_chunk(*node_id);
+                }
+
+                chunks
+            }).collect()
+        };
+
+        for chunk_id in chunks {
+            self.release_cached_chunk(chunk_id, retry)?;
+        }
+
+        Ok(())
+    }
+
+    /// If retry is true the given chunk is sent again with the cached data the next time a node needs data,
+    /// otherwise the chunk is done and its data and its frame are removed from the caches.
+    /// If the chunk has to be sent again, this must be called while the nc_server lock is held, so that assign_chunk()
+    /// can't hand out the chunk in the meantime.
+    fn release_cached_chunk(&self, chunk_id: ChunkID, retry: bool) -> Result<(), NCError> {
+        if let Some(frame_cache) = self.frame_cache.as_ref().filter(|_| !retry) {
+            frame_cache.lock()?.remove(chunk_id);
+        }
+
+        if let Some(chunk_cache) = &self.chunk_cache {
+            let mut chunk_cache = chunk_cache.lock()?;
+
+            if retry {
+                chunk_cache.redispatch(chunk_id)
+            } else {
+                chunk_cache.remove(chunk_id)
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the given metadata of a chunk if it's not empty and not larger than max_chunk_meta_bytes in the NCConfiguration.
+    /// Direction ("for node" / "from node") and node id are only used for the log message.
+    fn check_chunk_meta(&self, meta: ChunkMeta, direction: &str, node_id: NodeID) -> Option<ChunkMeta> {
+        let size = chunk_meta_size(&meta);
+
+        if size > self.max_chunk_meta_bytes {
+            error!("Metadata {} {} is too large: {} bytes, max: {} bytes, will be dropped", direction, node_id, size, self.max_chunk_meta_bytes);
+            None
+        } else if meta.is_empty() {
+            None
+        } else {
+            Some(meta)
+        }
+    }
+
+    /// Puts the result (and the metadata of its chunk) from the given node into the result queue.
+    /// If ordered_results is set in the NCConfiguration the result goes into the reorder buffer first and only the results
+    /// that are in chunk order are put into the result queue.
+    /// If result_batch_size is set the result goes into the result batcher together with the connection to the node, the node gets
+    /// the answer after its batch has been processed. Otherwise the connection is returned and the caller has to answer the node.
+    fn queue_result(&self, node_id: NodeID, meta: Option<ChunkMeta>, data: T::ProcessedDataT, stream: Option<NCStream>) -> Result<Option<NCStream>, NCError> {
+        debug!("ServerProcess::queue_result()");
+
+        let current_chunk = self.node_list.lock()?.take_current_chunk(node_id);
+
+        if let Some((chunk_id, _)) = current_chunk {
+            if self.revoked_chunks.lock()?.contains(&chunk_id) {
+                info!("Chunk {} has been revoked, result from node {} is dropped", chunk_id, node_id);
+                self.revoked_chunk(node_id, chunk_id)?;
+                return Ok(stream)
+            }
+        }
+
+        let result_bytes = bincode::serialized_size(&data).map_err(NCError::Serialize)?;
+        self.job_stats.lock()?.result_received(node_id, result_bytes, current_chunk.map(|(_, chunk_time)| chunk_time));
+        self.train_result_dictionary(&data)?;
+
+        match current_chunk {
+            Some((chunk_id, chunk_time)) => {
+                self.job_stats.lock()?.attempt_finished(chunk_id, node_id, NCAttemptOutcome::Success);
+                self.report_progress(NCProgressEvent::ChunkDone(node_id, chunk_id, chunk_time));
+                self.add_chunk_time(chunk_time);
+            }
+            None => self.protocol_anomaly(format!("result from node {} that has no chunk (duplicate or late result)", node_id)),
+        }
+
+        self.queue_chunk_result(node_id, current_chunk.map(|(chunk_id, _)| chunk_id), meta, data, stream)
+    }
+
+    /// Adds the serialized result to the samples for the zstd dictionary (result_dictionary_samples in the NCConfiguration) and trains
+    /// the dictionary once there are enough of them, see the nc_dictionary module. The nodes get its id with every chunk from then on.
+    /// The dictionary is trained without holding the lock, results that arrive in between are not needed anymore.
+    /// If it can't be trained the results are sent without a dictionary.
+    fn train_result_dictionary(&self, data: &T::ProcessedDataT) -> Result<(), NCError> {
+        let mut dictionary_trainer = self.dictionary_trainer.lock()?;
+
+        let ready = match dictionary_trainer.as_mut() {
+            Some(trainer) => trainer.add(bincode::serialize(data).map_err(NCError::Serialize)?),
+            None => false,
+        };
+
+        let trainer = if ready { dictionary_trainer.take() } else { None };
+        drop(dictionary_trainer);
+
+        match trainer.map(|trainer| trainer.train()) {
+            Some(Ok(dictionary)) => {
+                let size = dictionary.len();
+                let id = self.nc_communicator.lock()?.add_dictionary(dictionary)?;
+                let _ = self.result_dictionary.set(id);
+                info!("The zstd dictionary {:#010x} for the results has been trained, size: {} bytes", id, size);
+            }
+            Some(Err(problem)) => warn!("Could not train the zstd dictionary for the results: {}, the results are sent without it", problem),
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    /// The node offers a result from its cache (see the [`nc_result_cache`](crate::nc_result_cache) module) after the connection has broken
+    /// before the result was acknowledged. The result is handled like HasDataWithMeta if the chunk is still the current chunk of the node,
+    /// otherwise the server has the result already or has given the chunk to another node and answers with a NCServerMessage::ResultRejected message.
+    fn cached_result(&self, node_id: NodeID, chunk_id: ChunkID, meta: Option<ChunkMeta>, data: T::ProcessedDataT, stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::cached_result()");
+
+        if !self.node_list.lock()?.has_current_chunk(chunk_id, node_id) {
+            info!("Node {} has offered the cached result of chunk {}, the result has arrived already or the chunk belongs to another node", node_id, chunk_id);
+            let job_error = NCJobError::result_rejected(format!("chunk {} is not the current chunk of the node", chunk_id), false);
+            return self.send_result_rejected_message(job_error, stream)
+        }
+
+        info!("Node {} has sent the cached result of chunk {}", node_id, chunk_id);
+        let meta = meta.and_then(|meta| self.check_chunk_meta(meta, "from node", node_id));
+        let mut stream = Some(stream);
+
+        if self.check_unsigned_result(node_id)? && self.validate_result(node_id, &data)? {
+            stream = self.queue_result(node_id, meta, data, stream)?;
+        }
+
+        match stream {
+            Some(stream) => self.send_result_received_message(stream),
+            None => Ok(()),
+        }
+    }
+
+    /// Puts the result for the given chunk into the reorder buffer, the result batcher or the result queue, see queue_result().
+    /// Results without a chunk always go into the result queue.
+    fn queue_chunk_result(&self, node_id: NodeID, chunk_id: Option<ChunkID>, meta: Option<ChunkMeta>, data: T::ProcessedDataT,
+        stream: Option<NCStream>) -> Result<Option<NCStream>, NCError> {
+        match (&self.reorder_buffer, &self.result_batcher, chunk_id) {
+            (Some(reorder_buffer), _, Some(chunk_id)) => {
+                // Keep the lock while pushing, so that the results from different threads stay in order.
+                let mut reorder_buffer = reorder_buffer.lock()?;
+                let dropped = reorder_buffer.dropped();
+                let ready = reorder_buffer.insert(chunk_id, Some((node_id, (Some(chunk_id), meta, data))));
+
+                if reorder_buffer.dropped() > dropped {
+                    self.protocol_anomaly(format!("duplicate result for chunk {} from node {}", chunk_id, node_id));
+                }
+
+                self.push_results(ready)?;
+                Ok(stream)
+            }
+            (Some(_), _, None) => {
+                error!("Node {} has no chunk, result can not be ordered", node_id);
+                self.result_queue.push(node_id, (None, meta, data))?;
+                Ok(stream)
+            }
+            (None, Some(result_batcher), Some(chunk_id)) => {
+                let size = bincode::serialized_size(&data).map_err(NCError::Serialize)?;
+                result_batcher.push((NCBatchedResult { node_id, chunk_id, meta, data }, stream), size)?;
+                Ok(None)
+            }
+            (None, _, chunk_id) => {
+                self.result_queue.push(node_id, (chunk_id, meta, data))?;
+                Ok(stream)
+            }
+        }
+    }
+
+    /// Writes the checkpoint from the NCServer trait method checkpoint() to the checkpoint file if checkpoint_interval seconds
+    /// have passed since the last one or if force is true. Does nothing if there is no checkpoint file in the NCConfiguration.
+    fn save_checkpoint(&self, force: bool) -> Result<(), NCError> {
+        let checkpoint_file = match &self.checkpoint_file {
+            Some(checkpoint_file) => checkpoint_file,
+            None => return Ok(())
+        };
+
+        let mut last_checkpoint = self.last_checkpoint.lock()?;
+
+        if !force && last_checkpoint.elapsed() < self.checkpoint_interval {
+            return Ok(())
+        }
+
+        debug!("ServerProcess::save_checkpoint()");
+        *last_checkpoint = Instant::now();
+
+        let checkpoint = self.nc_server.lock()?.checkpoint()?;
+
+        if let Some(mut checkpoint) = checkpoint {
+            checkpoint.set_server_state(self.checkpoint_nodes()?, self.offline_batches.lock()?.saved());
+            checkpoint.set_sample(self.sample);
+            checkpoint.set_dead_chunks(self.job_stats.lock()?.dead_chunks());
+            checkpoint.save(checkpoint_file)?;
+            info!("Checkpoint written: {}", checkpoint_file.display());
+        }
+
+        Ok(())
+    }
+
+    /// Sends the changes since the last update to the standby server, if replicate_to is set in the NCConfiguration.
+    /// The state comes from the NCServer trait method checkpoint(), like for the checkpoint file.
+    fn replicate(&self) -> Result<(), NCError> {
+        let replicator = match &self.replicator {
+            Some(replicator) => replicator,
+            None => return Ok(()),
+        };
+
+        debug!("ServerProcess::replicate()");
+
+        let checkpoint = match self.nc_server.lock()?.checkpoint()? {
+            Some(mut checkpoint) => {
+                checkpoint.set_server_state(self.checkpoint_nodes()?, self.offline_batches.lock()?.saved());
+                checkpoint.set_sample(self.sample);
+                checkpoint.set_dead_chunks(self.job_stats.lock()?.dead_chunks());
+                Some(checkpoint)
+            }
+            None => None,
+        };
+
+        replicator.replicate(checkpoint)
+    }
+
+    /// All registered nodes with what they have contributed for the checkpoint, including the nodes from an earlier checkpoint
+    /// that haven't come back yet.
+    fn checkpoint_nodes(&self) -> Result<Vec<NCNodeContribution>, NCError> {
+        let mut nodes = self.job_stats.lock()?.nodes().to_vec();
+        let node_ids: Vec<NodeID> = self.node_list.lock()?.node_ids().collect();
+
+        for node_id in node_ids {
+            if !nodes.iter().any(|node| node.node_id == node_id) {
+                nodes.push(NCNodeContribution { node_id, results: 0, result_bytes: 0, busy_time: Duration::ZERO });
+            }
+        }
+
+        nodes.extend(self.restored_nodes.lock()?.values().cloned());
+        Ok(nodes)
+    }
+
+    /// A node from before the restart of the server (it's in the checkpoint file) is registered again with its id and keeps what
+    /// it has contributed to the job, but not its chunks: they are free again since the chunk list has been restored.
+    fn restore_node(&self, node_id: NodeID) -> Result<(), NCError> {
+        let contribution = match self.restored_nodes.lock()?.remove(&node_id) {
+            Some(contribution) => contribution,
+            None => return Ok(())
+        };
+
+        if self.node_list.lock()?.register_node(node_id) {
+            info!("Node {} is back after the restart of the server, {} results so far", node_id, contribution.results);
+            self.job_stats.lock()?.restore_node(contribution);
+            self.report_progress(NCProgressEvent::NodeRegistered(node_id));
+        }
+
+        Ok(())
+    }
+
+    /// Registers a node with the NCNodeMessage::Register message (rejoin is [`None`]) or the NCNodeMessage::Rejoin message
+    /// (the node id and the incarnation, see rejoin_node()) and sends the initial data to the node.
+    fn register_node(&self, rejoin: Option<(NodeID, u32)>, registration: Registration, stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::register_node()");
+
+        let (node_codecs, node_build, aggregator_port, aggregator_tag, node_tags, node_settings) = registration;
+
+        if self.job_paused.load(Ordering::Relaxed) {
+            info!("Job is paused, don't register new node: {}", stream.peer_addr()?);
+            let message = "the job is paused, new nodes are registered again when it's resumed".to_string();
+            return self.send_rejected_message(NCRejectReason::Paused, message, Some(self.retry_after), stream)
+        }
+
+        let codec = match NCCodec::negotiate(&self.codecs, &node_codecs) {
+            Some(codec) => codec,
+            None => {
+                error!("No common codec with new node, server: {:?}, node: {:?}", self.codecs, node_codecs);
+                let message = format!("the server supports the codecs {:?}, allowed_codecs of the node must contain one of them", self.codecs);
+                return self.send_rejected_message(NCRejectReason::NoCommonCodec(self.codecs.clone()), message, None, stream)
+            }
+        };
+
+        if let Some(node_settings) = node_settings {
+            let errors = self.check_node_settings(&node_settings.to_config(), stream.peer_addr()?);
+
+            if !errors.is_empty() {
+                let message = format!("{} settings of the node don't match the server, they must be the same", errors.len());
+                return self.send_rejected_message(NCRejectReason::ConfigMismatch(errors), message, None, stream)
+            }
+        }
+
+        let node_id = match rejoin {
+            Some((node_id, incarnation)) => self.rejoin_node(node_id, incarnation)?,
+            None => self.node_list.lock()?.register_new_node(),
+        };
+
+        self.node_list.lock()?.set_codec(codec, node_id);
+        info!("Registering new node: {}, {}, codec: {:?}, build: {:?}", node_id, stream.peer_addr()?, codec, node_build);
+        self.node_list.lock()?.set_build(node_build, node_id);
+
+        if let Some(port) = aggregator_port {
+            // The aggregator listens on the same ip address that it uses to talk to the server.
+            let aggregator_addr = SocketAddr::new(stream.peer_addr()?.ip(), port);
+            info!("Node {} is an aggregator: {}, tag: {:?}", node_id, aggregator_addr, aggregator_tag);
+            self.node_list.lock()?.set_aggregator(Some(aggregator_addr), aggregator_tag, node_id);
+        } else {
+            self.node_list.lock()?.set_aggregator(None, aggregator_tag, node_id);
+        }
+
+        self.report_progress(NCProgressEvent::NodeRegistered(node_id));
+
+        if !node_tags.is_empty() {
+            info!("Node {} belongs to the groups: {:?}", node_id, node_tags);
+            self.set_node_tags(node_tags, node_id)?;
+        }
+
+        let _context = nc_context::enter(self.call_context(Some(node_id))?);
+        let initial_data = self.nc_server.lock()?.initial_data()?;
+        self.send_initial_data_message(node_id, initial_data, codec, stream)
+    }
+
+    /// A new process wants to take over the given node id with the given incarnation, see NCNodeMessage::Rejoin.
+    /// If the server knows the node id with a lower incarnation the old process is gone: its chunks are released like after a heartbeat
+    /// timeout (the NCServer trait method heartbeat_timeout() is called for the node id), so that they are given to other nodes, and the
+    /// new process starts without the chunks and the errors of the old one (see NCNodeList::rejoin_node()). The results of the node id so far
+    /// still count for the node in the statistics. An unknown node id (for example after a restart of the server without checkpoint) is registered.
+    /// Returns the node id for the new process, a new one if the incarnation is not higher than the known one: then two processes
+    /// use the same file or a process has been started again with an old copy of it.
+    fn rejoin_node(&self, node_id: NodeID, incarnation: u32) -> Result<NodeID, NCError> {
+        debug!("ServerProcess::rejoin_node()");
+
+        let known = self.node_list.lock()?.incarnation(node_id);
+
+        match known {
+            Some(known) if incarnation <= known => {
+                warn!("Node {} has sent incarnation {}, but the server knows incarnation {}, the node gets a new node id", node_id, incarnation, known);
+                return Ok(self.node_list.lock()?.register_new_node())
+            }
+            Some(known) => {
+                info!("Node {} is back with incarnation {}, the chunks of incarnation {} are given to other nodes", node_id, incarnation, known);
+                self.job_stats.lock()?.node_released(node_id, NCAttemptOutcome::Timeout);
+                self.report_expired_chunks(node_id)?;
+                self.report_progress(NCProgressEvent::NodeOffline(node_id));
+
+                let mut nc_server = self.nc_server.lock()?;
+                nc_server.heartbeat_timeout(vec![node_id]);
+                self.release_chunks(&[node_id], true)?;
+                self.node_list.lock()?.rejoin_node(incarnation, node_id);
+            }
+            None => {
+                info!("Node {} joins with incarnation {}, the node id is new for the server", node_id, incarnation);
+                self.node_list.lock()?.rejoin_node(incarnation, node_id);
+            }
+        }
+
+        Ok(node_id)
+    }
+
+    /// Counts a chunk that has been processed by a node in the given time, for average_chunk_time().
+    fn add_chunk_time(&self, chunk_time: Duration) {
+        self.chunk_time_nanos.fetch_add(chunk_time.as_nanos() as u64, Ordering::Relaxed);
+        self.chunk_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The average time from sending a chunk to a node until its result arrived, [`None`] if there is no result yet.
+    /// The two counters are read one after the other, a result that arrives in between makes the average only slightly off.
+    fn average_chunk_time(&self) -> Result<Option<Duration>, NCError> {
+        let count = self.chunk_count.load(Ordering::Relaxed);
+        let total = self.chunk_time_nanos.load(Ordering::Relaxed);
+        Ok(total.checked_div(count).map(Duration::from_nanos))
+    }
+
+    /// Puts the given results into the result queue.
+    fn push_results(&self, results: Vec<(NodeID, QueuedResult<T::ProcessedDataT>)>) -> Result<(), NCError> {
+        for (node_id, data) in results {
+            self.result_queue.push(node_id, data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if ordered_results is set in the NCConfiguration and too many results are waiting for a missing chunk.
+    /// Also checks if the missing chunk has been waited for too long, see check_reorder_gap().
+    fn reorder_buffer_full(&self) -> Result<bool, NCError> {
+        match &self.reorder_buffer {
+            Some(reorder_buffer) => {
+                self.check_reorder_gap()?;
+                Ok(reorder_buffer.lock()?.is_full())
+            }
+            None => Ok(false)
+        }
+    }
+
+    /// If a chunk is missing for longer than max_reorder_wait seconds in the reorder buffer, the NCServer trait method chunk_gap()
+    /// is called which decides if the server waits longer or skips the missing chunk.
+    /// The reorder buffer is not locked while chunk_gap() runs, since nc_server has to be locked first (see the module documentation).
+    /// If another thread has handled the gap in the meantime, the decision is dropped.
+    fn check_reorder_gap(&self) -> Result<(), NCError> {
+        debug!("ServerProcess::check_reorder_gap()");
+
+        if let Some(reorder_buffer) = &self.reorder_buffer {
+            let gap = reorder_buffer.lock()?.gap();
+
+            if let Some(chunk_id) = gap {
+                let gap_action = self.nc_server.lock()?.chunk_gap(chunk_id);
+                let mut reorder_buffer = reorder_buffer.lock()?;
+
+                if reorder_buffer.gap() != Some(chunk_id) {
+                    return Ok(())
+                }
+
+                info!("Chunk {} is missing, {:?}", chunk_id, gap_action);
+
+                match gap_action {
+                    NCGapAction::Wait => reorder_buffer.wait_longer(),
+                    NCGapAction::Skip => {
+                        let ready = reorder_buffer.skip_gap();
+                        self.push_results(ready)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Puts all the remaining results from the reorder buffer into the result queue, the missing chunks are skipped.
+    fn flush_reorder_buffer(&self) -> Result<(), NCError> {
+        debug!("ServerProcess::flush_reorder_buffer()");
+
+        if let Some(reorder_buffer) = &self.reorder_buffer {
+            let mut reorder_buffer = reorder_buffer.lock()?;
+            let ready = reorder_buffer.flush();
+            self.push_results(ready)?;
+        }
+
+        Ok(())
+    }
+
+    /// Takes the results out of the result queue and calls the NCServer trait method process_data_from_node() for each of them.
+    /// Returns when the result queue has been closed and is empty.
+    fn process_results(&self) where Self: Sync {
+        debug!("ServerProcess::process_results()");
+
+        thread::scope(|scope| {
+            if let Some(result_batcher) = &self.result_batcher {
+                scope.spawn(move || self.process_result_batches(result_batcher));
+            }
+
+            self.process_queued_results();
+        });
+    }
+
+    /// Takes the results out of the result queue one after another until the queue has been closed, see process_results().
+    fn process_queued_results(&self) {
+        while let Some(result) = self.result_queue.pop() {
+            if let Err(e) = result.and_then(|(node_id, (chunk_id, meta, data))| self.process_result(node_id, chunk_id, meta, data)) {
+                error!("Error in process_results(): {}", e);
+            }
+
+            if let Err(e) = self.report_post_process_failures() {
+                error!("Error in process_results(): {}", e);
+            }
+
+            if let Err(e) = self.report_job_progress() {
+                error!("Error in process_results(): {}", e);
+            }
+
+            if let Err(e) = self.check_job_finished() {
+                error!("Error in process_results(): {}", e);
+            }
+
+            if let Err(e) = self.result_queue.processed() {
+                error!("Error in process_results(): {}", e);
+            }
+        }
+    }
+
+    /// Takes the batches out of the result batcher until it has been closed, processes them with process_result_batch()
+    /// and then sends the NCServerMessage::ResultAck message to the nodes that wait for the answer.
+    fn process_result_batches(&self, result_batcher: &NCResultBatcher<BatchedResult<T::ProcessedDataT>>) {
+        debug!("ServerProcess::process_result_batches()");
+
+        while let Some(batch) = result_batcher.pop() {
+            let (results, streams): (Vec<_>, Vec<_>) = match batch {
+                Ok(batch) => batch.into_iter().unzip(),
+                Err(e) => {
+                    error!("Error in process_result_batches(): {}", e);
+                    continue
+                }
+            };
+
+            if let Err(e) = self.process_result_batch(results) {
+                error!("Error in process_result_batches(): {}", e);
+            }
+
+            if let Err(e) = self.report_job_progress() {
+                error!("Error in process_result_batches(): {}", e);
+            }
+
+            if let Err(e) = self.check_job_finished() {
+                error!("Error in process_result_batches(): {}", e);
+            }
+
+            for stream in streams.into_iter().flatten() {
+                if let Err(e) = self.send_result_ack_message(stream) {
+                    error!("Error in process_result_batches(): {}", e);
+                }
+            }
+        }
+    }
+
+    /// Ends the job if the NCServer trait method job_finished() returns true, the nodes don't have to ask for new data first.
+    fn check_job_finished(&self) -> Result<(), NCError> {
+        if !self.is_job_done() && self.nc_server.lock()?.job_finished() {
+            info!("All results have been processed, the job is finished");
+            self.shut_down(NCJobEndReason::Finished);
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if the result that has just been put into the result queue may be the last one of the job:
+    /// all the other chunks are done or waiting in the result queue, according to the NCServer trait method job_progress().
+    fn may_be_last_result(&self) -> Result<bool, NCError> {
+        let queued = self.result_queue.stats()?.len;
+
+        Ok(match self.nc_server.lock()?.job_progress() {
+            // One more for the result that is being processed right now
+            Some((done, total)) => done + queued + 1 >= total,
+            None => false,
+        })
+    }
+
+    /// Moves the node to the given groups and reports the new groups as a progress event.
+    fn set_node_tags(&self, node_tags: Vec<String>, node_id: NodeID) -> Result<(), NCError> {
+        let mut node_list = self.node_list.lock()?;
+        node_list.set_tags(node_tags, node_id);
+        let node_tags = node_list.get_tags(node_id);
+        drop(node_list);
+
+        self.report_progress(NCProgressEvent::NodeTags(node_id, node_tags));
+        Ok(())
+    }
+
+    /// Sends the given event to the receiver from NCServerStarter::progress_events(), if any, and writes it to the event log.
+    fn report_progress(&self, event: NCProgressEvent) {
+        if let Some(event_log) = &self.event_log {
+            event_log.record(&event);
+        }
+
+        if let Some(progress_sender) = &self.progress_sender {
+            // The receiver may be gone already, the progress is not important for the job.
+            let _ = progress_sender.send(event);
+        }
+    }
+
+    /// Reports a NCProgressEvent::ChunkExpired event for every chunk of the given node that has missed its heartbeat,
+    /// before the chunks are released.
+    fn report_expired_chunks(&self, node_id: NodeID) -> Result<(), NCError> {
+        if self.progress_sender.is_some() || self.event_log.is_some() {
+            let chunks = self.node_list.lock()?.chunks_of_node(node_id);
+
+            for chunk_id in chunks {
+                self.report_progress(NCProgressEvent::ChunkExpired(node_id, chunk_id));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends the result of the NCServer trait method job_progress() as a progress event, if anybody is interested.
+    fn report_job_progress(&self) -> Result<(), NCError> {
+        if self.progress_sender.is_some() {
+            if let Some((finished, total)) = self.nc_server.lock()?.job_progress() {
+                self.report_progress(NCProgressEvent::JobProgress(finished, total));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Calls the NCServer trait method post_process_failed() for every error from the post processor since the last call.
+    fn report_post_process_failures(&self) -> Result<(), NCError> {
+        if let Some(post_process_queue) = &self.post_process_queue {
+            for (chunk_id, error) in post_process_queue.take_failures()? {
+                self.nc_server.lock()?.post_process_failed(chunk_id, &error);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Waits until all the results have been post processed, this is done before finish_job() is called.
+    fn finish_post_processing(&self) -> Result<(), NCError> {
+        debug!("ServerProcess::finish_post_processing()");
+
+        if let Some(post_process_queue) = &self.post_process_queue {
+            for message in post_process_queue.close()? {
+                self.report_progress(NCProgressEvent::TaskFailed("post processing worker".to_string(), message));
+            }
+        }
+
+        self.report_post_process_failures()
+    }
+
+    /// Calls the NCServer trait method process_data_with_meta() (by default process_data_from_node()) with the given result.
+    /// If the user code returns a NCError::Job error it will be sent to the node the next time it needs data.
+    /// Any other error is handled according to on_process_error in the NCConfiguration, see result_rejected().
+    fn process_result(&self, node_id: NodeID, chunk_id: Option<ChunkID>, meta: Option<ChunkMeta>, data: T::ProcessedDataT) -> Result<(), NCError> {
+        debug!("ServerProcess::process_result()");
+
+        // The results may be processed in another thread, so the context of the node is set here
+        let context = match chunk_id {
+            Some(chunk_id) => self.chunk_context(Some(node_id), chunk_id)?,
+            None => self.call_context(Some(node_id))?,
+        };
+        let _context = nc_context::enter(context);
+
+        let result = {
+            let mut nc_server = self.nc_server.lock()?;
+            self.catch_user_panic("process_data_from_node()", || nc_server.process_data_with_meta(node_id, &data, meta.as_ref()))
+        };
+
+        match result {
+            Ok(()) => {
+                if let Some(replicator) = &self.replicator {
+                    replicator.result_accepted(NCAcceptedResult { node_id, chunk_id, meta })?;
+                }
+
+                if let Some(post_process_queue) = &self.post_process_queue {
+                    post_process_queue.push(chunk_id, data)?;
+                } else if let Some(result_sender) = &self.result_sender {
+                    result_sender.send(chunk_id, data)?;
+                }
+
+                self.chunk_processed(chunk_id)
+            }
+            Err(NCError::Job(job_error)) => {
+                info!("Could not process data from node {}: {}", node_id, job_error);
+                self.node_list.lock()?.add_job_error(job_error, node_id);
+                self.chunk_processed(chunk_id)
+            }
+            Err(e) => self.result_rejected(node_id, chunk_id, e)
+        }
+    }
+
+    /// Calls the NCServer trait method process_result_batch() with the given results, like process_result() does for a single result.
+    /// If the user code returns an error, every result of the batch is handled like a result with that error.
+    fn process_result_batch(&self, results: Vec<NCBatchedResult<T::ProcessedDataT>>) -> Result<(), NCError> {
+        debug!("ServerProcess::process_result_batch()");
+
+        let chunks: Vec<(NodeID, ChunkID)> = results.iter().map(|result| (result.node_id, result.chunk_id)).collect();
+        let accepted: Vec<NCAcceptedResult> = match &self.replicator {
+            Some(_) => results.iter().map(|result| NCAcceptedResult { node_id: result.node_id, chunk_id: Some(result.chunk_id), meta: result.meta.clone() }).collect(),
+            None => Vec::new(),
+        };
+
+        let result = {
+            // The results of a batch come from different nodes, so the context only has the job
+            let _context = nc_context::enter(self.call_context(None)?);
+            let mut nc_server = self.nc_server.lock()?;
+            self.catch_user_panic("process_result_batch()", || nc_server.process_result_batch(results))
+        };
+
+        match result {
+            Ok(()) => {
+                if let Some(replicator) = &self.replicator {
+                    for accepted_result in accepted {
+                        replicator.result_accepted(accepted_result)?;
+                    }
+                }
+
+                for (_, chunk_id) in chunks {
+                    self.chunk_processed(Some(chunk_id))?;
+                }
+
+                Ok(())
+            }
+            Err(NCError::Job(job_error)) => {
+                info!("Could not process a batch of {} results: {}", chunks.len(), job_error);
+
+                for (node_id, chunk_id) in chunks {
+                    self.node_list.lock()?.add_job_error(job_error.clone(), node_id);
+                    self.chunk_processed(Some(chunk_id))?;
+                }
+
+                Ok(())
+            }
+            Err(e) => {
+                let message = e.to_string();
+
+                for (node_id, chunk_id) in chunks {
+                    let _context = nc_context::enter(self.chunk_context(Some(node_id), chunk_id)?);
+                    self.result_rejected(node_id, Some(chunk_id), NCError::custom(message.clone()))?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Calls the given NCServer trait method (user code) and turns a panic into a NCError::UserPanic error.
+    /// The panic is caught while the server lock is still held, so the lock is not poisoned and the other nodes can still be served.
+    fn catch_user_panic<R, F: FnOnce() -> Result<R, NCError>>(&self, method: &str, f: F) -> Result<R, NCError> {
+        panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+            let message = format!("{}: {}", method, panic_message(payload.as_ref()));
+            error!("User code panicked in {}", message);
+            self.user_panics.fetch_add(1, Ordering::Relaxed);
+            Err(NCError::UserPanic(message))
+        })
+    }
+
+    /// The result for the given chunk has been processed, the chunk will not be given to a node again.
+    fn chunk_processed(&self, chunk_id: Option<ChunkID>) -> Result<(), NCError> {
+        if let Some(chunk_id) = chunk_id {
+            self.job_stats.lock()?.chunk_done(chunk_id);
+            self.process_attempts.lock()?.remove(&chunk_id);
+            self.release_cached_chunk(chunk_id, false)?;
+        }
+
+        Ok(())
+    }
+
+    /// process_data_from_node() has returned an error (or panicked) for the result of the given node:
+    /// - OnProcessError::AbortJob: the server shuts down.
+    /// - OnProcessError::RequeueChunk: the NCServer trait method chunk_rejected() is called and the chunk is given to a node again.
+    ///   If this has happened more than max_process_retries times for the chunk, it's dropped instead.
+    /// - OnProcessError::DropChunk: chunk_rejected() is called and the chunk is marked as failed.
+    ///
+    /// The node gets a NCServerMessage::ResultRejected message the next time it needs data.
+    fn result_rejected(&self, node_id: NodeID, chunk_id: Option<ChunkID>, error: NCError) -> Result<(), NCError> {
+        debug!("ServerProcess::result_rejected()");
+
+        error!("Could not process data from node {}, chunk {:?}: {}, {:?}", node_id, chunk_id, error, self.on_process_error);
+
+        if let Some(chunk_id) = chunk_id {
+            self.job_stats.lock()?.result_rejected(chunk_id, node_id);
+        }
+
+        let requeue = match (self.on_process_error, chunk_id) {
+            (OnProcessError::AbortJob, _) => {
+                info!("Job aborted: {}", error);
+                self.shut_down(NCJobEndReason::ProcessError(error.to_string()));
+                false
+            }
+            (on_process_error, Some(chunk_id)) => {
+                let requeue = {
+                    let mut process_attempts = self.process_attempts.lock()?;
+                    let attempts = process_attempts.entry(chunk_id).or_insert(0);
+                    *attempts += 1;
+
+                    let requeue = on_process_error == OnProcessError::RequeueChunk && *attempts <= self.max_process_retries;
+
+                    if !requeue {
+                        process_attempts.remove(&chunk_id);
+                        self.job_stats.lock()?.chunk_failed();
+                    }
+
+                    requeue
+                };
+
+                info!("Chunk {} rejected, requeue: {}", chunk_id, requeue);
+                let mut nc_server = self.nc_server.lock()?;
+                nc_server.chunk_rejected(chunk_id, requeue);
+                self.release_cached_chunk(chunk_id, requeue)?;
+                requeue
+            }
+            (_, None) => {
+                error!("Result from node {} has no chunk, it can't be given to a node again", node_id);
+                false
+            }
+        };
+
+        let job_error = NCJobError::result_rejected(error.to_string(), requeue);
+        self.node_list.lock()?.add_job_error(job_error, node_id);
+        Ok(())
+    }
+
+    /// The aggregator (to) takes over the given chunk from the node (from), if the NCServer trait method chunk_delegated() agrees.
+    /// Returns false if the node doesn't have this chunk (anymore) or if ordered_results is set in the NCConfiguration,
+    /// since the combined results can't be ordered.
+    fn chunk_delegated(&self, chunk_id: ChunkID, from: NodeID, to: NodeID) -> Result<bool, NCError> {
+        debug!("ServerProcess::chunk_delegated()");
+
+        if self.reorder_buffer.is_some() {
+            info!("Ordered results, chunk {} of node {} is not delegated", chunk_id, from);
+            return Ok(false)
+        }
+
+        let mut nc_server = self.nc_server.lock()?;
+
+        if !self.node_list.lock()?.has_current_chunk(chunk_id, from) || !nc_server.chunk_delegated(chunk_id, from, to) {
+            info!("Chunk {} of node {} is not delegated to aggregator {}", chunk_id, from, to);
+            return Ok(false)
+        }
+
+        let chunk_time = self.node_list.lock()?.delegate_chunk(chunk_id, from, to);
+        drop(nc_server);
+
+        if let Some(chunk_time) = chunk_time {
+            debug!("Chunk {} of node {} is delegated to aggregator {}", chunk_id, from, to);
+            self.report_progress(NCProgressEvent::ChunkDone(from, chunk_id, chunk_time));
+            self.add_chunk_time(chunk_time);
+        }
+
+        Ok(true)
+    }
+
+    /// The given aggregator has sent the combined result of the given chunks, this calls the NCServer trait method process_aggregate().
+    /// If some of the chunks don't belong to the aggregator anymore (for example after a heartbeat timeout), the result is dropped.
+    /// If the result is dropped, reduce() has failed on the aggregator (data is [`None`]) or process_aggregate() returns an error,
+    /// chunk_rejected() is called for the remaining chunks of the aggregator and they are given to a node again.
+    fn aggregate_received(&self, node_id: NodeID, chunks: Vec<ChunkID>, data: Option<T::ProcessedDataT>) -> Result<(), NCError> {
+        debug!("ServerProcess::aggregate_received()");
+
+        if let Some(data) = &data {
+            let result_bytes = bincode::serialized_size(data).map_err(NCError::Serialize)?;
+            self.job_stats.lock()?.result_received(node_id, result_bytes, None);
+        }
+
+        let mut nc_server = self.nc_server.lock()?;
+        let delegated = self.node_list.lock()?.remove_delegated_chunks(&chunks, node_id);
+
+        let result = match data {
+            Some(_) if delegated.len() < chunks.len() => Err(NCError::custom("some of the chunks have been given to a node again")),
+            Some(data) => nc_server.process_aggregate(node_id, &chunks, &data),
+            None => Err(NCError::custom("reduce() has failed on the aggregator")),
+        };
+
+        match result {
+            Ok(()) => {
+                debug!("Combined result of {} chunks from aggregator {} processed", chunks.len(), node_id);
+                drop(nc_server);
+
+                for chunk_id in chunks {
+                    self.chunk_processed(Some(chunk_id))?;
+                }
+            }
+            Err(e) => {
+                error!("Could not use combined result from aggregator {}, chunks: {:?}: {}", node_id, chunks, e);
+
+                for chunk_id in delegated {
+                    nc_server.chunk_rejected(chunk_id, true);
+                    self.release_cached_chunk(chunk_id, true)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The chunk of the given node didn't produce any data. The NCServer trait method chunk_empty() is called with
+    /// the chunk that has been sent to the node last.
+    fn chunk_empty(&self, node_id: NodeID) -> Result<(), NCError> {
+        debug!("ServerProcess::chunk_empty()");
+
+        match self.node_list.lock()?.take_current_chunk(node_id) {
+            Some((chunk_id, chunk_time)) => {
+                debug!("Chunk {} from node {} is empty", chunk_id, node_id);
+                self.job_stats.lock()?.attempt_finished(chunk_id, node_id, NCAttemptOutcome::Success);
+                self.report_progress(NCProgressEvent::ChunkDone(node_id, chunk_id, chunk_time));
+                self.empty_chunk(chunk_id)
+            }
+            None => {
+                error!("Node {} has no chunk that could be empty", node_id);
+                self.protocol_anomaly(format!("empty chunk from node {} that has no chunk", node_id));
+                Ok(())
+            }
+        }
+    }
+
+    /// Counts the given chunk as empty and calls the NCServer trait method chunk_empty(), see chunk_empty().
+    fn empty_chunk(&self, chunk_id: ChunkID) -> Result<(), NCError> {
+        let _context = nc_context::enter(self.chunk_context(None, chunk_id)?);
+        self.empty_chunks.fetch_add(1, Ordering::Relaxed);
+        self.job_stats.lock()?.chunk_done(chunk_id);
+        self.release_cached_chunk(chunk_id, false)?;
+        self.nc_server.lock()?.chunk_empty(chunk_id);
+
+        if let Some(reorder_buffer) = &self.reorder_buffer {
+            let mut reorder_buffer = reorder_buffer.lock()?;
+            let ready = reorder_buffer.insert(chunk_id, None);
+            self.push_results(ready)?;
+        }
+
+        Ok(())
+    }
+
+    /// The given node has skipped its chunk. The NCServer trait method chunk_skipped() is called with
+    /// the chunk that has been sent to the node last and the reason is counted in the statistics.
+    fn chunk_skipped(&self, node_id: NodeID, reason: String) -> Result<(), NCError> {
+        debug!("ServerProcess::chunk_skipped()");
+
+        match self.node_list.lock()?.take_current_chunk(node_id) {
+            Some((chunk_id, chunk_time)) => {
+                info!("Node {} has skipped chunk {}: {}", node_id, chunk_id, reason);
+                self.job_stats.lock()?.attempt_finished(chunk_id, node_id, NCAttemptOutcome::Skipped);
+                self.report_progress(NCProgressEvent::ChunkDone(node_id, chunk_id, chunk_time));
+                self.skipped_chunk(chunk_id, reason)
+            }
+            None => {
+                error!("Node {} has no chunk that could be skipped", node_id);
+                self.protocol_anomaly(format!("skipped chunk from node {} that has no chunk", node_id));
+                Ok(())
+            }
+        }
+    }
+
+    /// Calls the NCServer trait method chunk_skipped() for the given chunk and counts the reason, see chunk_skipped().
+    fn skipped_chunk(&self, chunk_id: ChunkID, reason: String) -> Result<(), NCError> {
+        let _context = nc_context::enter(self.chunk_context(None, chunk_id)?);
+        self.job_stats.lock()?.chunk_done(chunk_id);
+        self.release_cached_chunk(chunk_id, false)?;
+        self.nc_server.lock()?.chunk_skipped(chunk_id, &reason);
+
+        let mut skipped_chunks = self.skipped_chunks.lock()?;
+
+        match skipped_chunks.iter_mut().find(|(other, _)| *other == reason) {
+            Some((_, count)) => *count += 1,
+            None => skipped_chunks.push((reason, 1)),
+        }
+
+        Ok(())
+    }
+
+    /// The node has dropped the given revoked chunk without a result, it's taken from the node (a chunk fetched in advance becomes
+    /// the current chunk) and handled like a revoked result, see revoked_chunk(). If the chunk hasn't been revoked or the node
+    /// doesn't hold it anymore (for example because of a heartbeat timeout) nothing happens.
+    fn chunk_dropped(&self, node_id: NodeID, chunk_id: ChunkID) -> Result<(), NCError> {
+        debug!("ServerProcess::chunk_dropped()");
+
+        if !self.revoked_chunks.lock()?.contains(&chunk_id) {
+            warn!("Node {} has dropped chunk {} that has not been revoked", node_id, chunk_id);
+            return Ok(())
+        }
+
+        let taken = {
+            let mut node_list = self.node_list.lock()?;
+
+            if node_list.has_current_chunk(chunk_id, node_id) {
+                node_list.take_current_chunk(node_id).is_some()
+            } else {
+                node_list.release_chunk(chunk_id, node_id)
+            }
+        };
+
+        if taken {
+            info!("Node {} has dropped revoked chunk {}", node_id, chunk_id);
+            self.revoked_chunk(node_id, chunk_id)
+        } else {
+            debug!("Node {} doesn't hold chunk {}, nothing to drop", node_id, chunk_id);
+            Ok(())
+        }
+    }
+
+    /// The given revoked chunk of the given node is done without a result: the NCServer trait method chunk_revoked() is called
+    /// and a NCProgressEvent::ChunkRevoked event is reported. With ordered_results the chunk doesn't hold back the following results.
+    fn revoked_chunk(&self, node_id: NodeID, chunk_id: ChunkID) -> Result<(), NCError> {
+        let _context = nc_context::enter(self.chunk_context(Some(node_id), chunk_id)?);
+        self.revoked_chunks.lock()?.remove(&chunk_id);
+        {
+            let mut job_stats = self.job_stats.lock()?;
+            job_stats.attempt_finished(chunk_id, node_id, NCAttemptOutcome::Revoked);
+            job_stats.chunk_done(chunk_id);
+        }
+        self.release_cached_chunk(chunk_id, false)?;
+        self.nc_server.lock()?.chunk_revoked(chunk_id);
+        self.report_progress(NCProgressEvent::ChunkRevoked(node_id, chunk_id));
+
+        if let Some(reorder_buffer) = &self.reorder_buffer {
+            let mut reorder_buffer = reorder_buffer.lock()?;
+            let ready = reorder_buffer.insert(chunk_id, None);
+            self.push_results(ready)?;
+        }
+
+        Ok(())
+    }
+
+    /// The node gives back the given chunk that it has fetched in advance (prefetch in the NCConfiguration) without processing it.
+    /// The NCServer trait method chunk_send_failed() is called, so that the chunk is given to another node. If the node doesn't hold
+    /// the chunk anymore (for example because it has failed in the meantime, see release_chunks()) nothing happens.
+    fn release_chunk(&self, node_id: NodeID, chunk_id: ChunkID) -> Result<(), NCError> {
+        debug!("ServerProcess::release_chunk()");
+
+        if !self.node_list.lock()?.release_chunk(chunk_id, node_id) {
+            debug!("Node {} doesn't hold chunk {}, nothing to release", node_id, chunk_id);
+            return Ok(())
+        }
+
+        info!("Node {} gives back chunk {}", node_id, chunk_id);
+        let _context = nc_context::enter(self.chunk_context(Some(node_id), chunk_id)?);
+        self.job_stats.lock()?.attempt_finished(chunk_id, node_id, NCAttemptOutcome::Released);
+        let mut nc_server = self.nc_server.lock()?;
+        nc_server.chunk_send_failed(chunk_id);
+        self.release_cached_chunk(chunk_id, true)
+    }
+
+    /// Writes the artifact to `artifacts_dir/chunk_id/name` and reports a NCProgressEvent::ArtifactStored event.
+    /// Without artifacts_dir, or if the artifact is too big, has an invalid name or could not be written, it's dropped with a warning:
+    /// the node gets a NCServerMessage::ResultAck message in any case, since its result has already been accepted.
+    fn store_artifact(&self, node_id: NodeID, chunk_id: ChunkID, name: &str, data: &[u8]) {
+        debug!("ServerProcess::store_artifact()");
+
+        let artifact_store = match &self.artifact_store {
+            Some(artifact_store) => artifact_store,
+            None => {
+                warn!("Artifact '{}' of chunk {} from node {} dropped, artifacts_dir is not set", name, chunk_id, node_id);
+                return
+            }
+        };
+
+        match artifact_store.store(chunk_id, name, data) {
+            Ok(Some(path)) => {
+                info!("Stored artifact of chunk {} from node {}: {}", chunk_id, node_id, path.display());
+                self.report_progress(NCProgressEvent::ArtifactStored(node_id, chunk_id, path));
+            }
+            Ok(None) => (),
+            Err(e) => warn!("Could not store artifact '{}' of chunk {} from node {}: {}", name, chunk_id, node_id, e),
+        }
+    }
+
+    /// The node could not process its data. The NCServer trait method process_node_error() is called with the error.
+    /// If the error is not retryable it counts as a permanent failure and if there are too many of them
+    /// (max_permanent_failures in the NCConfiguration) the job is aborted.
+    fn node_failed(&self, node_id: NodeID, job_error: NCJobError) -> Result<(), NCError> {
+        debug!("ServerProcess::node_failed()");
+
+        info!("Node {} failed: {}", node_id, job_error);
+        self.report_progress(NCProgressEvent::ChunkFailed(node_id));
+        let chunk_id = self.node_list.lock()?.current_chunk(node_id);
+        let context = match chunk_id {
+            Some(chunk_id) => self.chunk_context(Some(node_id), chunk_id)?,
+            None => self.call_context(Some(node_id))?,
+        };
+        let _context = nc_context::enter(context);
+        {
+            let mut job_stats = self.job_stats.lock()?;
+            job_stats.node_failed(node_id, &job_error);
+            job_stats.node_released(node_id, NCAttemptOutcome::Released);
+        }
+        let mut nc_server = self.nc_server.lock()?;
+        nc_server.process_node_error(node_id, &job_error);
+        self.release_chunks(&[node_id], job_error.retryable)?;
+        drop(nc_server);
+
+        if !job_error.retryable {
+            self.permanent_failure()?;
+        }
+
+        Ok(())
+    }
+
+    /// Counts a chunk that has failed and can't be retried. If there are too many of them
+    /// (max_permanent_failures in the NCConfiguration) the job is aborted.
+    fn permanent_failure(&self) -> Result<(), NCError> {
+        self.job_stats.lock()?.chunk_failed();
+        let failures = self.permanent_failures.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if self.max_permanent_failures > 0 && failures >= self.max_permanent_failures {
+            error!("Too many permanent failures: {}, job will be aborted", failures);
+            self.shut_down(NCJobEndReason::TooManyFailures(failures));
+        }
+
+        Ok(())
+    }
+
+    /// Checks the result with the NCServer trait method validate() and returns true if it can be queued.
+    /// Otherwise the node is handled as failed (see node_failed()) with a retryable NCJobError::VALIDATION_FAILED,
+    /// which is also sent to the node the next time it needs data.
+    fn validate_result(&self, node_id: NodeID, data: &T::ProcessedDataT) -> Result<bool, NCError> {
+        debug!("ServerProcess::validate_result()");
+
+        let validation = self.nc_server.lock()?.validate(data);
+
+        match validation {
+            Ok(()) => Ok(true),
+            Err(message) => {
+                let error = NCError::ValidationFailed(message);
+                error!("Result from node {} is not valid: {}", node_id, error);
+                let job_error = NCJobError::validation_failed(&error, true);
+                self.node_list.lock()?.add_job_error(job_error.clone(), node_id);
+                self.node_failed(node_id, job_error)?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Handles the node as failed (see node_failed()) with a retryable NCJobError::SIGNATURE_INVALID, which is also sent to the node
+    /// the next time it needs data.
+    fn signature_failed(&self, node_id: NodeID, error: NCError) -> Result<bool, NCError> {
+        error!("Signed result from node {} is rejected: {}", node_id, error);
+        let job_error = NCJobError::signature_invalid(&error);
+        self.node_list.lock()?.add_job_error(job_error.clone(), node_id);
+        self.node_failed(node_id, job_error)?;
+        Ok(false)
+    }
+
+    /// Returns true if a result without a signature can be queued. If require_signed_results is set in the NCConfiguration
+    /// the node is handled as failed instead, see signature_failed().
+    fn check_unsigned_result(&self, node_id: NodeID) -> Result<bool, NCError> {
+        if self.require_signed_results {
+            self.signature_failed(node_id, NCError::Signature("result is not signed".to_string()))
+        } else {
+            Ok(true)
+        }
+    }
+
+    /// Checks the signature of the result with the public key of the node, see the nc_keys module.
+    /// If it's valid the signature and the public key are added to the metadata of the chunk (see SIGNATURE_META_KEY and PUBLIC_KEY_META_KEY),
+    /// so that process_data_with_meta() can store them together with the result, and true is returned.
+    /// If the node has no key, the signature is wrong or it's for another chunk the node is handled as failed, see signature_failed().
+    #[cfg(feature = "ed25519")]
+    fn verify_signature(&self, node_id: NodeID, chunk_id: ChunkID, signature: &[u8], payload: &[u8], meta: &mut Option<ChunkMeta>) -> Result<bool, NCError> {
+        debug!("ServerProcess::verify_signature()");
+
+        let public_key = match self.node_keys.lock()?.get(&node_id) {
+            Some(public_key) => *public_key,
+            None => return self.signature_failed(node_id, NCError::Signature("unknown public key".to_string())),
+        };
+
+        if let Some(current_chunk) = self.node_list.lock()?.current_chunk(node_id).filter(|current_chunk| *current_chunk != chunk_id) {
+            return self.signature_failed(node_id, NCError::Signature(format!("signed for chunk {}, but the node processes chunk {}", chunk_id, current_chunk)))
+        }
+
+        if let Err(error) = public_key.verify(chunk_id, payload, signature) {
+            return self.signature_failed(node_id, error)
+        }
+
+        let meta = meta.get_or_insert_with(ChunkMeta::new);
+        meta.insert(SIGNATURE_META_KEY.to_string(), encode_hex(signature));
+        meta.insert(PUBLIC_KEY_META_KEY.to_string(), public_key.to_string());
+        Ok(true)
+    }
+
+    /// Without the ed25519 feature the signature can't be checked, the result is handled like an unsigned result.
+    #[cfg(not(feature = "ed25519"))]
+    fn verify_signature(&self, node_id: NodeID, _chunk_id: ChunkID, _signature: &[u8], _payload: &[u8], _meta: &mut Option<ChunkMeta>) -> Result<bool, NCError> {
+        warn!("Node {} signs its results, but the signature can only be checked with the ed25519 feature", node_id);
+        self.check_unsigned_result(node_id)
+    }
+
+    /// Stores the public key of the node and answers with a NCServerMessage::ResultAck message. If the key is not valid
+    /// or not in node_keys_file (see the NCConfiguration) a NCServerMessage::Unauthorized message is sent back instead.
+    #[cfg(feature = "ed25519")]
+    fn register_key(&self, node_id: NodeID, public_key: Vec<u8>, stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::register_key()");
+
+        let public_key = match NCPublicKey::from_bytes(&public_key) {
+            Ok(public_key) => public_key,
+            Err(e) => {
+                warn!("Node {} has sent an invalid public key: {}", node_id, e);
+                return self.send_unauthorized_message(stream)
+            }
+        };
+
+        if self.key_ring.as_ref().is_some_and(|key_ring| !key_ring.contains(&public_key)) {
+            warn!("Unknown public key from node {}: {}", node_id, public_key);
+            return self.send_unauthorized_message(stream)
+        }
+
+        info!("Node {} signs its results with the public key {}", node_id, public_key);
+        self.node_keys.lock()?.insert(node_id, public_key);
+        self.send_result_ack_message(stream)
+    }
+
+    /// Without the ed25519 feature the public key can't be used, the node is told that it's accepted and its results are handled like unsigned results.
+    #[cfg(not(feature = "ed25519"))]
+    fn register_key(&self, node_id: NodeID, _public_key: Vec<u8>, stream: NCStream) -> Result<(), NCError> {
+        warn!("Node {} has sent a public key, but signed results need the ed25519 feature", node_id);
+        self.send_result_ack_message(stream)
+    }
+
+    /// Stores the clock skew of the node, with a warning if it's bigger than max_clock_skew_ms in the NCConfiguration.
+    /// The skew is only used for time stamps from the node, the timeouts of the server don't depend on it.
+    fn set_clock_skew(&self, node_id: NodeID, clock_skew: NCClockSkew) -> Result<(), NCError> {
+        debug!("ServerProcess::set_clock_skew()");
+
+        if clock_skew.offset() > Duration::from_millis(self.max_clock_skew_ms) {
+            warn!("The clock of node {} is off: {}, max_clock_skew_ms: {}", node_id, clock_skew, self.max_clock_skew_ms);
+        } else {
+            debug!("Clock skew of node {}: {}", node_id, clock_skew);
+        }
+
+        self.node_list.lock()?.set_clock_skew(clock_skew, node_id);
+        self.report_progress(NCProgressEvent::ClockSkew(node_id, clock_skew));
+        Ok(())
+    }
+
+    /// Assigns up to count chunks to a new node id (that never sends a heartbeat) and writes them together with the initial data
+    /// to the given file, see [`NCOfflineHandle::export_chunks()`]. The NCServer trait method chunk_sent() is called for every chunk
+    /// once the file has been written, otherwise chunk_send_failed() is called. The chunks have no deadline.
+    fn export_chunks(&self, path: &Path, count: usize) -> Result<usize, NCError> {
+        debug!("ServerProcess::export_chunks()");
+
+        if self.is_job_done() || self.job_paused.load(Ordering::Relaxed) {
+            return Ok(0)
+        }
+
+        let node_id = NodeID::random();
+        let mut chunks = Vec::new();
+
+        while chunks.len() < count {
+            match self.next_assignment(node_id, &NCAssignContext::default()) {
+                Ok(ChunkAssignment::Assigned(chunk_id, data)) => chunks.push((chunk_id, data)),
+                Ok(_) => break,
+                Err(e) => {
+                    self.export_failed(chunks.iter().map(|(chunk_id, _)| *chunk_id))?;
+                    return Err(e)
+                }
+            }
+        }
+
+        if chunks.is_empty() {
+            return Ok(0)
+        }
+
+        let chunk_ids: Vec<ChunkID> = chunks.iter().map(|(chunk_id, _)| *chunk_id).collect();
+        let result = self.write_chunk_archive(path, node_id, chunks);
+        let batch_id = match result {
+            Ok(batch_id) => batch_id,
+            Err(e) => {
+                error!("Could not export chunks to {}: {}", path.display(), e);
+                self.export_failed(chunk_ids.into_iter())?;
+                return Err(e)
+            }
+        };
+
+        for chunk_id in chunk_ids.iter() {
+            self.job_stats.lock()?.chunk_sent(*chunk_id, node_id);
+            self.report_progress(NCProgressEvent::ChunkSent(node_id, *chunk_id));
+            self.nc_server.lock()?.chunk_sent(*chunk_id);
+        }
+
+        let count = chunk_ids.len();
+        let mut offline_batches = self.offline_batches.lock()?;
+        offline_batches.add(batch_id, node_id, chunk_ids);
+        info!("Exported {} chunks to {} (offline batch {}, {} batches wait for results)", count, path.display(), batch_id, offline_batches.len());
+        Ok(count)
+    }
+
+    /// Writes the given chunks with the initial data and the chunk infos to the given file and returns the id of the new batch.
+    fn write_chunk_archive(&self, path: &Path, node_id: NodeID, chunks: Vec<(ChunkID, T::NewDataT)>) -> Result<u64, NCError> {
+        let average_chunk_time = self.average_chunk_time()?;
+        let mut archive_chunks = Vec::with_capacity(chunks.len());
+
+        for (chunk_id, data) in chunks {
+            let metadata = self.nc_server.lock()?.chunk_meta(chunk_id);
+            let metadata = metadata.and_then(|metadata| self.check_chunk_meta(metadata, "for node", node_id));
+            archive_chunks.push((NCChunkInfo { chunk_id, average_chunk_time, metadata, ..Default::default() }, data));
+        }
+
+        let initial_data = self.nc_server.lock()?.initial_data()?;
+        let archive: NCChunkArchive<T::InitialDataT, T::NewDataT> = NCChunkArchive { batch_id: rand::random(), node_id, initial_data, chunks: archive_chunks };
+        write_archive(path, NCArchiveKind::Chunks, &archive, &mut self.nc_communicator.lock()?.share())?;
+        Ok(archive.batch_id)
+    }
+
+    /// Gives back the given chunks that could not be exported, see export_chunks().
+    fn export_failed<I: Iterator<Item = ChunkID>>(&self, chunks: I) -> Result<(), NCError> {
+        let mut nc_server = self.nc_server.lock()?;
+
+        for chunk_id in chunks {
+            nc_server.chunk_send_failed(chunk_id);
+            self.release_cached_chunk(chunk_id, true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the results of an offline batch from the given file, see [`NCOfflineHandle::import_results()`].
+    /// The results go through validate() and the result queue like the ones from a regular node, an empty or skipped chunk
+    /// calls chunk_empty() or chunk_skipped(). A chunk that has failed or is not valid is given to chunk_rejected(),
+    /// it's requeued if the error is retryable. Returns the number of accepted results.
+    fn import_results(&self, path: &Path) -> Result<usize, NCError> {
+        debug!("ServerProcess::import_results()");
+
+        let archive: NCResultArchive<T::ProcessedDataT> = read_archive(path, NCArchiveKind::Results, &self.nc_communicator.lock()?.share())?;
+        let node_id = archive.node_id;
+        let mut accepted = 0;
+
+        for (chunk_id, result) in archive.results {
+            if !self.offline_batches.lock()?.take_chunk(archive.batch_id, node_id, chunk_id) {
+                warn!("Chunk {} is not part of offline batch {} anymore (imported before or expired), result is dropped", chunk_id, archive.batch_id);
+                continue
+            }
+
+            match result {
+                NCOfflineResult::Data(meta, data) => {
+                    if let Err(message) = self.nc_server.lock()?.validate(&data) {
+                        let error = NCError::ValidationFailed(message);
+                        error!("Result for chunk {} from offline batch {} is not valid: {}", chunk_id, archive.batch_id, error);
+                        self.offline_chunk_failed(node_id, chunk_id, NCJobError::validation_failed(&error, true))?;
+                        continue
+                    }
+
+                    let meta = meta.and_then(|meta| self.check_chunk_meta(meta, "from node", node_id));
+                    let result_bytes = bincode::serialized_size(&data).map_err(NCError::Serialize)?;
+                    let mut job_stats = self.job_stats.lock()?;
+                    job_stats.result_received(node_id, result_bytes, None);
+                    job_stats.attempt_finished(chunk_id, node_id, NCAttemptOutcome::Success);
+                    drop(job_stats);
+                    self.queue_chunk_result(node_id, Some(chunk_id), meta, data, None)?;
+                }
+                NCOfflineResult::Empty => {
+                    self.job_stats.lock()?.attempt_finished(chunk_id, node_id, NCAttemptOutcome::Success);
+                    self.empty_chunk(chunk_id)?;
+                }
+                NCOfflineResult::Skip(reason) => {
+                    info!("Offline batch {} has skipped chunk {}: {}", archive.batch_id, chunk_id, reason);
+                    self.job_stats.lock()?.attempt_finished(chunk_id, node_id, NCAttemptOutcome::Skipped);
+                    self.skipped_chunk(chunk_id, reason)?;
+                }
+                NCOfflineResult::Failed(job_error) => {
+                    info!("Chunk {} from offline batch {} failed: {}", chunk_id, archive.batch_id, job_error);
+                    self.offline_chunk_failed(node_id, chunk_id, job_error)?;
+                    continue
+                }
+            }
+
+            accepted += 1;
+        }
+
+        info!("Imported {} results from {} (offline batch {})", accepted, path.display(), archive.batch_id);
+        self.check_reorder_gap()?;
+        self.report_job_progress()?;
+        self.save_checkpoint(false)?;
+        Ok(accepted)
+    }
+
+    /// A chunk of an offline batch has failed or its result is not valid. The NCServer trait method chunk_rejected() is called,
+    /// the chunk is requeued if the error is retryable, otherwise it counts as a permanent failure. node_id is the node id of the offline batch.
+    fn offline_chunk_failed(&self, node_id: NodeID, chunk_id: ChunkID, job_error: NCJobError) -> Result<(), NCError> {
+        self.job_stats.lock()?.attempt_finished(chunk_id, node_id, NCAttemptOutcome::Failed(job_error.code));
+        let mut nc_server = self.nc_server.lock()?;
+        nc_server.chunk_rejected(chunk_id, job_error.retryable);
+        self.release_cached_chunk(chunk_id, job_error.retryable)?;
+        drop(nc_server);
+
+        if !job_error.retryable {
+            self.permanent_failure()?;
+        }
+
+        Ok(())
+    }
+
+    /// The remaining chunks of the offline batches that are older than offline_batch_timeout_days (see NCConfiguration)
+    /// are given back with the NCServer trait method chunk_send_failed(), so that the regular nodes process them.
+    /// Must be called while the nc_server lock is held, see release_cached_chunk().
+    fn expire_offline_batches(&self, nc_server: &mut T) -> Result<(), NCError> {
+        let chunks = self.offline_batches.lock()?.take_expired();
+
+        for chunk_id in chunks {
+            warn!("Offline batch with chunk {} has expired, the chunk is given to another node", chunk_id);
+            self.job_stats.lock()?.chunk_released(chunk_id, NCAttemptOutcome::Timeout);
+            nc_server.chunk_send_failed(chunk_id);
+            self.release_cached_chunk(chunk_id, true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Send the NCServerMessage::ResultRejected message to the node.
+    fn send_result_rejected_message(&self, job_error: NCJobError, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_result_rejected_message()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::ResultRejected(job_error);
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+
+    /// Send the NCServerMessage::PleaseRestart message to the node.
+    fn send_please_restart_message(&self, reason: String, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_please_restart_message()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::PleaseRestart { reason };
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+
+    /// Send the NCServerMessage::ServerFailed message to the node.
+    fn send_server_failed_message(&self, job_error: NCJobError, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_server_failed_message()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::ServerFailed(job_error);
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+
+    /// Send the NCServerMessage::Dictionary message with the zstd dictionary to the node,
+    /// NCServerMessage::UnknownDictionary if the server doesn't have a dictionary with the given id.
+    fn send_dictionary_message(&self, id: u32, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_dictionary_message()");
+        let mut nc_communicator = self.nc_communicator.lock()?;
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = match nc_communicator.dictionary(id)? {
+            Some(dictionary) => NCServerMessage::Dictionary(id, dictionary.to_vec()),
+            None => NCServerMessage::UnknownDictionary(id),
+        };
+
+        nc_communicator.nc_send_data2(&message, &mut stream)
+    }
+
+    /// Send the NCServerMessage::UnknownDictionary message to the node, its message has been compressed with a zstd dictionary
+    /// that the server doesn't have (for example from the server before a NCServerMessage::NewServer message).
+    fn send_unknown_dictionary_message(&self, id: u32, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_unknown_dictionary_message()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::UnknownDictionary(id);
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+
+    /// Send the NCServerMessage::RotateKey message with the current key to the node, encrypted with the old key that the node has used.
+    fn send_rotate_key_message(&self, previous_key: usize, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_rotate_key_message()");
+        let mut nc_communicator = self.nc_communicator.lock()?;
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::RotateKey(nc_communicator.current_key()?);
+
+        nc_communicator.nc_send_data2_previous_key(&message, previous_key, &mut stream)
+    }
+
+    /// Send the NCServerMessage::ResultAck message for a result to the node. If it may be the last result of the job (see may_be_last_result())
+    /// the server waits up to long_poll_ms (see the NCConfiguration) until it has been processed, so that the node learns from the answer
+    /// if the job is finished (see check_job_finished()) and doesn't have to ask for new data again.
+    fn send_result_received_message(&self, stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_result_received_message()");
+
+        if !self.is_job_done() && self.may_be_last_result()? {
+            if !self.result_queue.wait_drained(self.long_poll)? {
+                debug!("The last results have not been processed yet");
+            }
+
+            self.check_job_finished()?;
+        }
+
+        self.send_result_ack_message(stream)
+    }
+
+    /// Send the NCServerMessage::ResultAck message to the node, job_finished is set if the job is done already.
+    fn send_result_ack_message(&self, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_result_ack_message()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::ResultAck { job_finished: self.is_job_done() };
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+
+    /// Send the NCServerMessage::ClockTime message with the current time of the server to the node.
+    fn send_clock_time_message(&self, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_clock_time_message()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::ClockTime(unix_micros(SystemTime::now()));
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+
+    /// Send the NCServerMessage::AdminAck message to the admin client.
+    fn send_admin_ack_message(&self, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_admin_ack_message()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::AdminAck;
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+
+    /// Send the NCServerMessage::Unauthorized message to the admin client.
+    fn send_unauthorized_message(&self, mut stream: NCStream) -> Result<(), NCError> {
+        debug!("ServerProcess::send_unauthorized_message()");
+        let message: NCServerMessage<T::InitialDataT, T::NewDataT, T::CustomMessageT> = NCServerMessage::Unauthorized;
+
+        self.nc_communicator.lock()?.nc_send_data2(&message, &mut stream)
+    }
+}
+
+impl<T: NCServer + Send + 'static> NCOfflineJob for NCServerProcess<T, T::CustomMessageT> {
+    fn export_chunks(&self, path: &Path, count: usize) -> Result<usize, NCError> {
+        NCServerProcess::export_chunks(self, path, count)
+    }
+
+    fn import_results(&self, path: &Path) -> Result<usize, NCError> {
+        NCServerProcess::import_results(self, path)
+    }
+}
+
+impl<T: NCServer + Send + 'static> NCChunkControl for NCServerProcess<T, T::CustomMessageT> {
+    fn cancel_chunk(&self, chunk_id: ChunkID) -> Result<bool, NCError> {
+        match self.node_list.lock()?.node_with_chunk(chunk_id) {
+            Some(node_id) => {
+                info!("Chunk {} of node {} revoked", chunk_id, node_id);
+                self.revoked_chunks.lock()?.insert(chunk_id);
+                Ok(true)
+            }
+            None => {
+                info!("Chunk {} is not held by any node, nothing to revoke", chunk_id);
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl<T: NCServer + Send + 'static> NCBroadcastJob<T::CustomMessageT> for NCServerProcess<T, T::CustomMessageT> {
+    fn broadcast_to(&self, tag: &str, message: T::CustomMessageT) -> Result<usize, NCError> {
+        let num_of_nodes = self.node_list.lock()?.add_message_group(message, tag);
+        debug!("Add a custom message to group '{}' ({} nodes)", tag, num_of_nodes);
+        self.report_progress(NCProgressEvent::Broadcast(Some(tag.to_string()), num_of_nodes));
+        Ok(num_of_nodes)
+    }
+
+    fn group_sizes(&self) -> Result<Vec<(String, u64)>, NCError> {
+        Ok(self.node_list.lock()?.group_sizes())
+    }
+}
+
+impl<T: NCServer + Send + 'static> NCJob for NCServerProcess<T, T::CustomMessageT> {
+    fn handle_frame(&self, data: &[u8], stream: NCStream) -> Result<(), NCError> {
+        NCServerProcess::handle_frame(self, data, stream)
+    }
+
+    fn is_job_done(&self) -> bool {
+        NCServerProcess::is_job_done(self)
+    }
+
+    fn run(self: Arc<Self>) -> Result<(), NCError> {
+        debug!("ServerProcess::run()");
+
+        let server_process = self.clone();
+        let result_thread = thread::spawn(move || {
+            server_process.process_results();
+            debug!("Exit result thread main loop");
+        });
+
+        while !self.is_job_done() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        self.finish(result_thread).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{fs, io};
+    use std::net::{TcpListener, TcpStream};
+
+    use crate::array2d::ChunkList;
+    use crate::nc_client::NCClient;
+    use crate::nc_node::{NodeResult, NCNodeStarter, NCProcessContext};
+    use crate::nc_offline::NCSavedOfflineBatch;
+    use crate::nc_context::NCContextNodeInfo;
+    use crate::nc_range::{RangeServer, RangeNode, RangeBatch, RangeResults};
+
+    struct TestServer {
+        chunk_list: ChunkList<u32>,
+        result_error: Option<NCJobError>,
+        fail_results: bool,
+        deadline: Option<Duration>,
+        post_processor: Option<Arc<TestPostProcessor>>,
+        post_process_failures: Vec<Option<ChunkID>>,
+        aggregates: Vec<Vec<ChunkID>>,
+        disconnected: Vec<NodeID>,
+        work_hints: Vec<Option<NCWorkHint>>,
+        metas: Vec<Option<ChunkMeta>>,
+        invalid_results: bool,
+        /// assign_chunk_with_context() and process_data_from_node() panic.
+        panics: bool,
+        /// The arguments of chunk_dead().
+        dead_chunks: Vec<(ChunkID, u32, String)>,
+        abort_dead_chunks: bool,
+        /// The call contexts of chunk_sent() and process_node_error().
+        contexts: Vec<NCCallContext>,
+        /// The number of results of every call to process_result_batch().
+        batches: Vec<usize>,
+    }
+
+    /// Records the chunk ids, fails for chunk 1.
+    struct TestPostProcessor {
+        chunks: Mutex<Vec<Option<ChunkID>>>,
+    }
+
+    impl NCPostProcessor<()> for TestPostProcessor {
+        fn post_process(&self, chunk_id: Option<ChunkID>, _data: ()) -> Result<(), NCError> {
+            self.chunks.lock()?.push(chunk_id);
+
+            if chunk_id == Some(1) {
+                Err(NCError::Custom(1))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl NCServer for TestServer {
+        type InitialDataT = ();
+        type NewDataT = u32;
+        type ProcessedDataT = ();
+        type CustomMessageT = ();
+
+        fn assign_chunk(&mut self, node_id: NodeID) -> Result<ChunkAssignment<u32>, NCError> {
+            match self.chunk_list.assign_next_chunk(node_id) {
+                Some((chunk_id, chunk)) => Ok(ChunkAssignment::Assigned(chunk_id, chunk.data)),
+                None => match self.chunk_list.finish_phase() {
+                    Some(phase) => Ok(ChunkAssignment::PhaseFinished(phase)),
+                    None => Ok(ChunkAssignment::Finished),
+                }
+            }
+        }
+
+        fn phase_finished(&mut self, phase: u32) -> Result<(), NCError> {
+            if phase == 0 {
+                self.chunk_list.push_phase(30, 1);
+            }
+
+            Ok(())
+        }
+
+        fn chunk_deadline(&mut self, _chunk_id: ChunkID) -> Option<Duration> {
+            self.deadline
+        }
+
+        fn job_progress(&self) -> Option<(u64, u64)> {
+            Some(self.chunk_list.progress())
+        }
+
+        fn chunk_sent(&mut self, chunk_id: ChunkID) {
+            self.contexts.push(nc_context::current());
+            self.chunk_list.chunk_sent(chunk_id)
+        }
+
+        fn chunk_send_failed(&mut self, chunk_id: ChunkID) {
+            self.chunk_list.chunk_send_failed(chunk_id)
+        }
+
+        fn node_disconnected(&mut self, node_id: NodeID) {
+            self.disconnected.push(node_id)
+        }
+
+        fn assign_chunk_with_context(&mut self, node_id: NodeID, context: &NCAssignContext) -> Result<ChunkAssignment<u32>, NCError> {
+            assert!(!self.panics, "no chunks today");
+            self.work_hints.push(context.work_hint().cloned());
+
+            if context.locality().is_some() {
+                // A node with the shard reads the input from its own drive, the others get it inline
+                return Ok(match self.chunk_list.assign_next_chunk_with_locality(node_id, context.tags(), context.locality()) {
+                    Some((chunk_id, _)) if context.is_local(chunk_id) => ChunkAssignment::Assigned(chunk_id, 0),
+                    Some((chunk_id, chunk)) => ChunkAssignment::Assigned(chunk_id, chunk.data),
+                    None => ChunkAssignment::Waiting,
+                })
+            }
+
+            self.assign_chunk(node_id)
+        }
+
+        fn chunk_meta(&mut self, chunk_id: ChunkID) -> Option<ChunkMeta> {
+            self.chunk_list.chunk_meta(chunk_id)
+        }
+
+        fn process_data_with_meta(&mut self, node_id: NodeID, data: &(), meta: Option<&ChunkMeta>) -> Result<(), NCError> {
+            self.metas.push(meta.cloned());
+            self.process_data_from_node(node_id, data)
+        }
+
+        fn process_result_batch(&mut self, batch: Vec<NCBatchedResult<()>>) -> Result<(), NCError> {
+            self.batches.push(batch.len());
+
+            if self.fail_results {
+                return Err(NCError::Custom(1))
+            }
+
+            Ok(())
+        }
+
+        fn validate(&self, _data: &()) -> Result<(), String> {
+            if self.invalid_results {
+                Err("wrong size".to_string())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn process_data_from_node(&mut self, _node_id: NodeID, _data: &()) -> Result<(), NCError> {
+            assert!(!self.panics, "broken result");
+
+            if self.fail_results {
+                return Err(NCError::Custom(1))
+            }
+
+            match self.result_error.clone() {
+                Some(job_error) => Err(NCError::Job(job_error)),
+                None => Ok(()),
+            }
+        }
+
+        fn chunk_empty(&mut self, chunk_id: ChunkID) {
+            self.chunk_list.chunk_empty(chunk_id)
+        }
+
+        fn chunk_skipped(&mut self, chunk_id: ChunkID, _reason: &str) {
+            self.chunk_list.chunk_skipped(chunk_id)
+        }
+
+        fn chunk_revoked(&mut self, chunk_id: ChunkID) {
+            self.chunk_list.chunk_revoked(chunk_id)
+        }
+
+        fn chunk_left_out(&mut self, chunk_id: ChunkID) {
+            self.chunk_list.chunk_left_out(chunk_id)
+        }
+
+        fn process_node_error(&mut self, node_id: NodeID, error: &NCJobError) {
+            self.contexts.push(nc_context::current());
+            self.chunk_list.chunk_failed(node_id, error.retryable)
+        }
+
+        fn chunk_rejected(&mut self, chunk_id: ChunkID, requeue: bool) {
+            self.chunk_list.chunk_rejected(chunk_id, requeue)
+        }
+
+        fn chunk_dead(&mut self, chunk_id: ChunkID, attempts: u32, last_error: &str) -> NCDeadChunkAction {
+            self.dead_chunks.push((chunk_id, attempts, last_error.to_string()));
+
+            if self.abort_dead_chunks {
+                NCDeadChunkAction::AbortJob
+            } else {
+                NCDeadChunkAction::Continue
+            }
+        }
+
+        fn chunk_delegated(&mut self, chunk_id: ChunkID, from: NodeID, to: NodeID) -> bool {
+            self.chunk_list.delegate_chunk(chunk_id, from, to)
+        }
+
+        fn process_aggregate(&mut self, _node_id: NodeID, chunks: &[ChunkID], _data: &()) -> Result<(), NCError> {
+            if self.fail_results {
+                return Err(NCError::Custom(0))
+            }
+
+            for chunk_id in chunks {
+                self.chunk_list.get(*chunk_id as usize).set_finished();
+            }
+
+            self.aggregates.push(chunks.to_vec());
+            Ok(())
+        }
+
+        fn cache_chunk(&mut self, chunk_id: ChunkID) -> bool {
+            chunk_id != 1
+        }
+
+        fn reassign_chunk(&mut self, chunk_id: ChunkID, node_id: NodeID) -> bool {
+            self.chunk_list.reassign_chunk(chunk_id, node_id)
+        }
+
+        fn checkpoint(&mut self) -> Result<Option<NCCheckpoint>, NCError> {
+            NCCheckpoint::new(&self.chunk_list).map(Some)
+        }
+
+        fn post_processor(&mut self) -> Option<Arc<dyn NCPostProcessor<()>>> {
+            self.post_processor.clone().map(|post_processor| post_processor as Arc<dyn NCPostProcessor<()>>)
+        }
+
+        fn post_process_failed(&mut self, chunk_id: Option<ChunkID>, _error: &NCError) {
+            self.post_process_failures.push(chunk_id)
+        }
+
+        fn heartbeat_timeout(&mut self, nodes: Vec<NodeID>) {
+            self.chunk_list.heartbeat_timeout(&nodes)
+        }
+
+        fn finish_job(&mut self) {}
+    }
+
+    /// A writer that always fails, like a node connection that has been closed.
+    struct BrokenStream;
+
+    impl Write for BrokenStream {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The reading side of a connection that is closed after the given number of bytes, in the middle of a frame.
+    struct ClosingStream {
+        bytes_left: usize,
+        error_kind: io::ErrorKind,
+    }
+
+    impl Write for ClosingStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.bytes_left == 0 {
+                return Err(io::Error::new(self.error_kind, "connection closed by node"))
+            }
+
+            let n = buf.len().min(self.bytes_left);
+            self.bytes_left -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn server_process_for_test() -> NCServerProcess<TestServer, ()> {
+        server_process_with_config(NCConfiguration::default())
+    }
+
+    fn server_process_with_config(config: NCConfiguration) -> NCServerProcess<TestServer, ()> {
+        let config = NCConfiguration { admin_key: "ZTXbsBVhz9tDzDhklykVDUXznjonhGil".to_string(), ..config };
+        let mut chunk_list = ChunkList::new();
+        chunk_list.push(10);
+        chunk_list.push(20);
+        NCServerProcess::new(&config, TestServer{ chunk_list, result_error: None, fail_results: false, deadline: None, post_processor: None, post_process_failures: Vec::new(), aggregates: Vec::new(), disconnected: Vec::new(), work_hints: Vec::new(), metas: Vec::new(), invalid_results: false, panics: false, dead_chunks: Vec::new(), abort_dead_chunks: false, contexts: Vec::new(), batches: Vec::new() }).unwrap()
+    }
+
+    fn assign_and_send<W: Write>(server_process: &NCServerProcess<TestServer, ()>, stream: &mut W) -> Result<ChunkID, NCError> {
+        assign_and_send_to(server_process, NodeID::random(), stream)
+    }
+
+    fn assign_and_send_to<W: Write>(server_process: &NCServerProcess<TestServer, ()>, node_id: NodeID, stream: &mut W) -> Result<ChunkID, NCError> {
+        let assignment = server_process.nc_server.lock()?.assign_chunk(node_id)?;
+
+        match assignment {
+            ChunkAssignment::Assigned(chunk_id, data) => {
+                server_process.send_chunk(node_id, chunk_id, data, false, stream)?;
+                Ok(chunk_id)
+            }
+            _ => panic!("Expected a chunk assignment"),
+        }
+    }
+
+    #[test]
+    fn test_send_chunk() {
+        let server_process = server_process_for_test();
+        let mut buffer: Vec<u8> = Vec::new();
+
+        assert_eq!(assign_and_send(&server_process, &mut buffer).unwrap(), 0);
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (1, 1, 0));
+        assert!(!buffer.is_empty());
+    }
+
+    #[test]
+    fn test_send_chunk_deadline() {
+        let server_process = server_process_for_test();
+        let mut buffer: Vec<u8> = Vec::new();
+
+        server_process.nc_server.lock().unwrap().deadline = Some(Duration::from_millis(1500));
+        assign_and_send(&server_process, &mut buffer).unwrap();
+
+        let message: NCServerMessage<(), u32, ()> = server_process.nc_communicator.lock().unwrap().nc_receive_data(&mut buffer.as_slice()).unwrap();
+
+        match message {
+            NCServerMessage::JobStatus(job_status) => assert_eq!(job_status,
+                NCJobStatus::Unfinished(10, NCChunkInfo { chunk_id: 0, deadline: Some(Duration::from_millis(1500)), average_chunk_time: None, aggregator: None, metadata: None, attempt: 1,
+                    job_time_remaining: None, result_dictionary: None })),
+            _ => panic!("Expected a JobStatus message"),
+        }
+    }
+
+    #[test]
+    fn test_send_chunk_average_time() {
+        let server_process = server_process_for_test();
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+        let mut buffer: Vec<u8> = Vec::new();
+
+        assign_and_send_to(&server_process, node_id, &mut buffer).unwrap();
+        assert_eq!(server_process.average_chunk_time().unwrap(), None);
+
+        thread::sleep(Duration::from_millis(50));
+        server_process.queue_result(node_id, None, (), None).unwrap();
+        let average_chunk_time = server_process.average_chunk_time().unwrap().unwrap();
+        assert!(average_chunk_time >= Duration::from_millis(50));
+
+        buffer.clear();
+        let chunk_id = assign_and_send_to(&server_process, node_id, &mut buffer).unwrap();
+        let message: NCServerMessage<(), u32, ()> = server_process.nc_communicator.lock().unwrap().nc_receive_data(&mut buffer.as_slice()).unwrap();
+
+        match message {
+            NCServerMessage::JobStatus(NCJobStatus::Unfinished(20, chunk_info)) => {
+                assert_eq!(chunk_info.chunk_id, chunk_id);
+                assert_eq!(chunk_info.average_chunk_time, Some(average_chunk_time));
+            }
+            _ => panic!("Expected a JobStatus message"),
+        }
+    }
+
+    #[test]
+    fn test_save_checkpoint() {
+        let checkpoint_file = std::env::temp_dir().join("nc_test_server_checkpoint.bin");
+        let config = NCConfiguration { checkpoint_file: Some(checkpoint_file.clone()), checkpoint_interval: 3600, ..Default::default() };
+        let server_process = server_process_with_config(config);
+        let mut buffer: Vec<u8> = Vec::new();
+
+        assign_and_send(&server_process, &mut buffer).unwrap();
+
+        // Interval has not passed yet
+        server_process.save_checkpoint(false).unwrap();
+        assert!(!checkpoint_file.exists());
+
+        server_process.save_checkpoint(true).unwrap();
+        let checkpoint = NCCheckpoint::load(&checkpoint_file).unwrap();
+        fs::remove_file(&checkpoint_file).unwrap();
+
+        let mut chunk_list: ChunkList<u32> = checkpoint.restore().unwrap();
+        assert_eq!(checkpoint.chunk_list().stats(), (1, 1, 0));
+        assert_eq!(chunk_list.stats(), (2, 0, 0));
+        assert_eq!(chunk_list.get(1).data, 20);
+    }
+
+    #[test]
+    fn test_sampled_run() {
+        let checkpoint_file = std::env::temp_dir().join(format!("nc_test_sampled_run_{}.bin", std::process::id()));
+        let config = NCConfiguration { checkpoint_file: Some(checkpoint_file.clone()), sample_fraction: Some(0.5), sample_seed: Some(3), ..Default::default() };
+        let sample = NCSample { fraction: 0.5, seed: 3 };
+        let server_process = server_process_with_config(config);
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+
+        for data in 3..=20 {
+            server_process.nc_server.lock().unwrap().chunk_list.push(data * 10);
+        }
+
+        // Only the chunks of the sample are assigned, the others are finished without a result
+        let mut assigned = Vec::new();
+
+        while let ChunkAssignment::Assigned(chunk_id, _) = server_process.next_assignment(node_id, &NCAssignContext::default()).unwrap() {
+            assigned.push(chunk_id);
+        }
+
+        let expected: Vec<ChunkID> = (0..20).filter(|chunk_id| sample.contains(*chunk_id)).collect();
+        assert_eq!(assigned, expected);
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (0, assigned.len() as u64, 20 - assigned.len() as u64));
+
+        let job_summary = server_process.job_summary().unwrap();
+        assert_eq!(job_summary.sample, Some(sample));
+        assert_eq!(job_summary.chunks_left_out, 20 - assigned.len() as u64);
+
+        // The checkpoint can only be resumed by the same sample
+        server_process.save_checkpoint(true).unwrap();
+        assert_eq!(NCCheckpoint::load(&checkpoint_file).unwrap().sample(), Some(sample));
+        let full_run = NCConfiguration { checkpoint_file: Some(checkpoint_file.clone()), ..Default::default() };
+        assert!(matches!(sample_for_run(&full_run), Err(NCError::Sample(_))));
+        fs::remove_file(&checkpoint_file).unwrap();
+    }
+
+    #[test]
+    fn test_send_chunk_failed() {
+        let server_process = server_process_for_test();
+
+        assert!(assign_and_send(&server_process, &mut BrokenStream{}).is_err());
+        // The chunk is back in the pool and will be given to the next node
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (2, 0, 0));
+
+        let mut buffer: Vec<u8> = Vec::new();
+        assert_eq!(assign_and_send(&server_process, &mut buffer).unwrap(), 0);
+        assert_eq!(assign_and_send(&server_process, &mut buffer).unwrap(), 1);
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (0, 2, 0));
+    }
+
+    #[test]
+    fn test_check_admin_message() {
+        let server_process = server_process_for_test();
+        let message = NCAdminMessage::new("ZTXbsBVhz9tDzDhklykVDUXznjonhGil", NCAdminCommand::PauseJob).unwrap();
+
+        assert_eq!(server_process.check_admin_message(&message).unwrap(), &NCAdminCommand::PauseJob);
+    }
+
+    #[test]
+    fn test_check_admin_message_wrong_key() {
+        let server_process = server_process_for_test();
+        let message = NCAdminMessage::new("XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX", NCAdminCommand::PauseJob).unwrap();
+
+        assert!(matches!(server_process.check_admin_message(&message), Err(NCError::Unauthorized)));
+    }
+
+    #[test]
+    fn test_check_admin_message_replayed() {
+        let server_process = server_process_for_test();
+        let message = NCAdminMessage::new("ZTXbsBVhz9tDzDhklykVDUXznjonhGil", NCAdminCommand::ResumeJob).unwrap();
+
+        assert!(server_process.check_admin_message(&message).is_ok());
+        assert!(matches!(server_process.check_admin_message(&message), Err(NCError::Unauthorized)));
+    }
+
+    /// Lets the server process handle one connection while the given client function runs.
+    fn handle_one<F: FnOnce(&mut NCClient) -> Result<(), NCError>>(server_process: &NCServerProcess<TestServer, ()>, admin_key: &str, client: F) -> Result<(), NCError> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = NCConfiguration { port: listener.local_addr().unwrap().port(), admin_key: admin_key.to_string(), ..Default::default() };
+
+        thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                // The unsigned messages have no answer, the client may be gone already
+                let _ = server_process.handle_node(Box::new(stream));
+            });
+
+            let result = client(&mut NCClient::connect(&config).unwrap());
+            server.join().unwrap();
+            result
+        })
+    }
+
+    #[test]
+    fn test_shut_down_needs_admin_key() {
+        const ADMIN_KEY: &str = "ZTXbsBVhz9tDzDhklykVDUXznjonhGil";
+        let server_process = server_process_for_test();
+
+        // The server has an admin key, the unsigned messages are ignored
+        handle_one(&server_process, "", |client| client.shut_down()).unwrap();
+        handle_one(&server_process, "", |client| client.new_server("10.0.0.1".to_string(), 3030)).unwrap();
+        assert!(!server_process.is_job_done());
+        assert_eq!(*server_process.new_server.lock().unwrap(), None);
+
+        // A wrong admin key is rejected, the right one is accepted
+        let wrong = handle_one(&server_process, "XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX", |client| client.new_server("10.0.0.1".to_string(), 3030));
+        assert!(matches!(wrong, Err(NCError::Unauthorized)));
+        assert_eq!(*server_process.new_server.lock().unwrap(), None);
+
+        handle_one(&server_process, ADMIN_KEY, |client| client.new_server("10.0.0.1".to_string(), 3030)).unwrap();
+        assert_eq!(*server_process.new_server.lock().unwrap(), Some(("10.0.0.1".to_string(), 3030)));
+        handle_one(&server_process, ADMIN_KEY, |client| client.shut_down()).unwrap();
+        assert!(server_process.is_job_done());
+        assert_eq!(server_process.job_summary().unwrap().end_reason, NCJobEndReason::Stopped);
+
+        // Without an admin key on the server the unsigned messages still work
+        let mut server_process = server_process_for_test();
+        server_process.admin_key = String::new();
+        handle_one(&server_process, "", |client| client.new_server("10.0.0.2".to_string(), 3031)).unwrap();
+        assert_eq!(*server_process.new_server.lock().unwrap(), Some(("10.0.0.2".to_string(), 3031)));
+        handle_one(&server_process, "", |client| client.shut_down()).unwrap();
+        assert!(server_process.is_job_done());
+    }
+
+    #[test]
+    fn test_node_failed() {
+        let config = NCConfiguration { max_permanent_failures: 2, ..Default::default() };
+        let server_process = server_process_with_config(config);
+        let node_id1 = NodeID::random();
+        let node_id2 = NodeID::random();
+
+        server_process.nc_server.lock().unwrap().assign_chunk(node_id1).unwrap();
+        server_process.nc_server.lock().unwrap().assign_chunk(node_id2).unwrap();
+
+        server_process.node_failed(node_id1, NCJobError::new(1, "try again", true)).unwrap();
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (1, 1, 0));
+
+        server_process.node_failed(node_id2, NCJobError::new(2, "broken data", false)).unwrap();
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (1, 0, 0));
+        assert!(!server_process.is_job_done());
+
+        server_process.node_failed(node_id2, NCJobError::new(2, "broken data", false)).unwrap();
+        assert!(server_process.is_job_done());
+    }
+
+    #[test]
+    fn test_chunk_history() {
+        let server_process = server_process_for_test();
+        let node_id1 = server_process.node_list.lock().unwrap().register_new_node();
+        let node_id2 = server_process.node_list.lock().unwrap().register_new_node();
+        let mut buffer: Vec<u8> = Vec::new();
+
+        let chunk_id = assign_and_send_to(&server_process, node_id1, &mut buffer).unwrap();
+        server_process.node_failed(node_id1, NCJobError::new(5, "try again", true)).unwrap();
+        assert_eq!(assign_and_send_to(&server_process, node_id2, &mut buffer).unwrap(), chunk_id);
+        server_process.queue_result(node_id2, None, (), None).unwrap();
+
+        let outcomes: Vec<(NodeID, NCAttemptOutcome)> = server_process.job_stats.lock().unwrap().chunk_history(chunk_id).iter()
+            .map(|attempt| (attempt.node_id, attempt.outcome)).collect();
+        assert_eq!(outcomes, vec![(node_id1, NCAttemptOutcome::Failed(5)), (node_id2, NCAttemptOutcome::Success)]);
+
+        // The history is kept after the chunk is done and is part of the summary
+        server_process.chunk_processed(Some(chunk_id)).unwrap();
+        let summary = server_process.job_summary().unwrap();
+        assert_eq!(summary.chunk_history[&chunk_id].len(), 2);
+    }
+
+    #[test]
+    fn test_dead_chunks() {
+        let checkpoint_file = std::env::temp_dir().join(format!("nc_test_dead_chunks_{}.bin", std::process::id()));
+        let config = NCConfiguration { checkpoint_file: Some(checkpoint_file.clone()), max_chunk_attempts: 2, ..Default::default() };
+        let server_process = server_process_with_config(config.clone());
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+        let mut buffer: Vec<u8> = Vec::new();
+
+        for _ in 0..2 {
+            assert_eq!(assign_and_send_to(&server_process, node_id, &mut buffer).unwrap(), 0);
+            server_process.node_failed(node_id, NCJobError::new(3, "degenerate geometry", true)).unwrap();
+        }
+
+        // Chunk 0 has run out of attempts, it's marked as failed and the next chunk is assigned instead
+        assert!(matches!(server_process.next_assignment(node_id, &NCAssignContext::default()).unwrap(), ChunkAssignment::Assigned(1, _)));
+        let last_error = "code: 3, message: 'degenerate geometry', retryable: true".to_string();
+        assert_eq!(server_process.nc_server.lock().unwrap().dead_chunks, vec![(0, 2, last_error.clone())]);
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.failed_chunks().collect::<Vec<ChunkID>>(), vec![0]);
+
+        let job_summary = server_process.job_summary().unwrap();
+        assert_eq!(job_summary.dead_chunks, vec![NCDeadChunk { chunk_id: 0, attempts: 2, last_error: last_error.clone() }]);
+        assert_eq!(job_summary.failed_chunks, 1);
+        server_process.save_checkpoint(true).unwrap();
+
+        // After a restart the dead chunk is not sent again, even if the chunk list doesn't know it
+        let restarted = server_process_with_config(config.clone());
+        fs::remove_file(&checkpoint_file).unwrap();
+        assert!(matches!(restarted.next_assignment(node_id, &NCAssignContext::default()).unwrap(), ChunkAssignment::Assigned(1, _)));
+        assert_eq!(restarted.nc_server.lock().unwrap().dead_chunks, vec![(0, 2, last_error)]);
+
+        // Until it's reset via the admin protocol
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let admin_config = NCConfiguration { port: listener.local_addr().unwrap().port(), admin_key: "ZTXbsBVhz9tDzDhklykVDUXznjonhGil".to_string(), ..Default::default() };
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                restarted.handle_node(Box::new(stream)).unwrap();
+            });
+
+            NCClient::connect(&admin_config).unwrap().reset_dead_chunk(0).unwrap();
+        });
+
+        assert!(restarted.job_summary().unwrap().dead_chunks.is_empty());
+        assert!(matches!(restarted.next_assignment(node_id, &NCAssignContext::default()).unwrap(), ChunkAssignment::Assigned(0, _)));
+
+        // The job can also be aborted
+        let server_process = server_process_with_config(NCConfiguration { max_chunk_attempts: 1, ..Default::default() });
+        server_process.nc_server.lock().unwrap().abort_dead_chunks = true;
+        assign_and_send_to(&server_process, node_id, &mut buffer).unwrap();
+        server_process.node_failed(node_id, NCJobError::new(3, "degenerate geometry", true)).unwrap();
+        assert!(matches!(server_process.next_assignment(node_id, &NCAssignContext::default()).unwrap(), ChunkAssignment::Waiting));
+        assert!(server_process.is_job_done());
+        assert_eq!(server_process.job_summary().unwrap().end_reason, NCJobEndReason::ChunkDead(0));
+    }
+
+    #[test]
+    fn test_call_context() {
+        let server_process = server_process_with_config(NCConfiguration { job_id: Some("tenant-a".to_string()), ..Default::default() });
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+        server_process.node_list.lock().unwrap().set_build(Some("v7".to_string()), node_id);
+        let mut buffer: Vec<u8> = Vec::new();
+
+        // Chunk 0 is sent again after every error, the attempt goes up
+        for _ in 0..2 {
+            assert_eq!(assign_and_send_to(&server_process, node_id, &mut buffer).unwrap(), 0);
+            server_process.node_failed(node_id, NCJobError::new(3, "out of memory", true)).unwrap();
+        }
+
+        let mut stream: Vec<u8> = Vec::new();
+        assert_eq!(assign_and_send_to(&server_process, node_id, &mut stream).unwrap(), 0);
+
+        let node_info = NCContextNodeInfo { build: Some("v7".to_string()), tags: Vec::new() };
+        let context = NCCallContext::new(Some("tenant-a".to_string()), Some(node_id), Some(node_info));
+        let attempts: Vec<NCCallContext> = [1, 1, 2, 2, 3].iter().map(|attempt| context.clone().with_chunk(0, *attempt)).collect();
+        assert_eq!(server_process.nc_server.lock().unwrap().contexts, attempts);
+
+        // The node gets the attempt with the chunk
+        let message: NCServerMessage<(), u32, ()> = server_process.nc_communicator.lock().unwrap().nc_decode_data(&stream[8..]).unwrap();
+        assert!(matches!(message, NCServerMessage::JobStatus(NCJobStatus::Unfinished(_, chunk_info)) if chunk_info.attempt == 3));
+
+        assert_eq!(nc_context::current(), NCCallContext::default());
+    }
+
+    #[test]
+    fn test_cancel_chunk() {
+        let server_process = server_process_for_test();
+        let node_id1 = server_process.node_list.lock().unwrap().register_new_node();
+        let node_id2 = server_process.node_list.lock().unwrap().register_new_node();
+        let mut buffer: Vec<u8> = Vec::new();
+
+        // No node holds the chunk yet
+        assert!(!server_process.cancel_chunk(0).unwrap());
+
+        let chunk_id1 = assign_and_send_to(&server_process, node_id1, &mut buffer).unwrap();
+        let chunk_id2 = assign_and_send_to(&server_process, node_id2, &mut buffer).unwrap();
+
+        // A chunk that has not been revoked can't be dropped
+        server_process.chunk_dropped(node_id1, chunk_id1).unwrap();
+        assert_eq!(server_process.node_list.lock().unwrap().node_with_chunk(chunk_id1), Some(node_id1));
+
+        assert!(server_process.cancel_chunk(chunk_id1).unwrap());
+        assert!(server_process.cancel_chunk(chunk_id2).unwrap());
+
+        // The first node drops the chunk (before or while processing it), the second one sends the result anyway, it's dropped
+        server_process.chunk_dropped(node_id1, chunk_id1).unwrap();
+        server_process.queue_result(node_id2, None, (), None).unwrap();
+
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (0, 0, 2));
+        assert!(server_process.revoked_chunks.lock().unwrap().is_empty());
+        assert!(server_process.job_stats.lock().unwrap().nodes().is_empty());
+        assert_eq!(server_process.node_list.lock().unwrap().node_with_chunk(chunk_id1), None);
+    }
+
+    #[test]
+    fn test_poll_heartbeat() {
+        let server_process = server_process_for_test();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = NCConfiguration { port: listener.local_addr().unwrap().port(), ..Default::default() };
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+        let mut buffer: Vec<u8> = Vec::new();
+
+        let chunk_id = assign_and_send_to(&server_process, node_id, &mut buffer).unwrap();
+        server_process.cancel_chunk(chunk_id).unwrap();
+
+        let revoked = thread::scope(|scope| {
+            scope.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                server_process.handle_node(Box::new(stream)).unwrap();
+            });
+
+            let mut nc_client = NCClient::connect(&config).unwrap();
+            nc_client.set_node_id(node_id);
+            nc_client.poll_heartbeat(None).unwrap()
+        });
+
+        assert_eq!(revoked, vec![chunk_id]);
+
+        // Without a server the handle can't revoke anything
+        assert!(matches!(NCServerHandle::default().cancel_chunk(chunk_id), Err(NCError::ServerNotRunning)));
+    }
+
+    #[test]
+    fn test_heartbeat_stress() {
+        let server_process = server_process_for_test();
+        let node_ids: Vec<NodeID> = (0..1000).map(|_| server_process.node_list.lock().unwrap().register_new_node()).collect();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::scope(|scope| {
+            // Something that keeps the node list busy, like a large checkpoint or a long sweep over the chunks.
+            // The heartbeats never wait for the node list, so they all get through while it's held.
+            let node_list = server_process.node_list.lock().unwrap();
+
+            for n in 0..4 {
+                let node_ids = &node_ids;
+                let server_process = &server_process;
+                let sender = sender.clone();
+                scope.spawn(move || {
+                    for (count, node_id) in node_ids.iter().enumerate().skip(n).step_by(4) {
+                        let resources = NCResourceReport { free_disk_bytes: Some(count as u64), ..Default::default() };
+                        server_process.heartbeat_received(*node_id, Some(resources));
+                    }
+
+                    sender.send(()).unwrap();
+                });
+            }
+
+            for _ in 0..4 {
+                receiver.recv_timeout(Duration::from_secs(30)).expect("the heartbeats have waited for the node list");
+            }
+
+            assert!(server_process.heartbeats.check(60).is_empty());
+            drop(node_list);
+        });
+
+        assert!(node_ids.iter().all(|node_id| server_process.heartbeats.get_resources(*node_id).is_some()));
+    }
+
+    #[test]
+    fn test_process_result_job_error() {
+        let server_process = server_process_for_test();
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+        let job_error = NCJobError::new(42, "result is invalid", false);
+
+        server_process.nc_server.lock().unwrap().result_error = Some(job_error.clone());
+        server_process.process_result(node_id, None, None, ()).unwrap();
+
+        assert_eq!(server_process.node_list.lock().unwrap().get_job_error(node_id), Some(job_error));
+    }
+
+    #[test]
+    fn test_next_assignment_phases() {
+        let server_process = server_process_for_test();
+        let node_id = NodeID::random();
+
+        assert_eq!(server_process.next_assignment(node_id, &NCAssignContext::default()).unwrap(), ChunkAssignment::Assigned(0, 10));
+        assert_eq!(server_process.next_assignment(node_id, &NCAssignContext::default()).unwrap(), ChunkAssignment::Assigned(1, 20));
+
+        server_process.nc_server.lock().unwrap().chunk_list.get(0).set_finished();
+        server_process.nc_server.lock().unwrap().chunk_list.get(1).set_finished();
+
+        // Chunk for phase 1 is created in phase_finished()
+        assert_eq!(server_process.next_assignment(node_id, &NCAssignContext::default()).unwrap(), ChunkAssignment::Assigned(2, 30));
+
+        server_process.nc_server.lock().unwrap().chunk_list.get(2).set_finished();
+
+        assert_eq!(server_process.next_assignment(node_id, &NCAssignContext::default()).unwrap(), ChunkAssignment::Finished);
+    }
+
+    fn register_with_codecs(server_process: &NCServerProcess<TestServer, ()>, node_codecs: Vec<NCCodec>) -> Result<Option<()>, NCError> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = NCConfiguration { port: listener.local_addr().unwrap().port(), allowed_codecs: node_codecs, ..Default::default() };
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                server_process.handle_node(Box::new(stream)).unwrap();
+            });
+
+            NCClient::connect(&config).unwrap().register::<()>()
+        })
+    }
+
+    #[test]
+    fn test_register_codec_negotiation() {
+        let config = NCConfiguration { allowed_codecs: vec![NCCodec::Zstd(3), NCCodec::Lz4], ..Default::default() };
+        let server_process = server_process_with_config(config);
+
+        assert!(register_with_codecs(&server_process, vec![NCCodec::Lz4, NCCodec::None]).is_ok());
+        assert!(register_with_codecs(&server_process, vec![NCCodec::Zstd(1)]).is_ok());
+
+        let codecs: Vec<NCCodec> = {
+            let node_list = server_process.node_list.lock().unwrap();
+            node_list.get_time_stamps().iter().map(|(node_id, _)| node_list.get_codec(*node_id)).collect()
+        };
+
+        assert_eq!(codecs, vec![NCCodec::Lz4, NCCodec::Zstd(3)]);
+
+        let result = register_with_codecs(&server_process, vec![NCCodec::None]);
+
+        assert!(matches!(result, Err(NCError::NoCommonCodec(server, node))
+            if server == vec![NCCodec::Zstd(3), NCCodec::Lz4] && node == vec![NCCodec::None]));
+        assert_eq!(server_process.node_list.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_rejected() {
+        let server_process = server_process_for_test();
+        let register = |port| NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap().register::<()>();
+
+        // The job is paused, the node tries again later
+        server_process.job_paused.store(true, Ordering::Relaxed);
+        let (result, _) = with_connections(&server_process, 1, register);
+        assert!(matches!(&result, Err(NCError::Paused(_, Some(retry_after))) if *retry_after == server_process.retry_after));
+        assert!(result.unwrap_err().is_retryable_rejection());
+        server_process.job_paused.store(false, Ordering::Relaxed);
+
+        // Too many connections
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let result = thread::scope(|scope| {
+            scope.spawn(|| server_process.reject_busy(Box::new(listener.accept().unwrap().0)).unwrap());
+            register(port)
+        });
+        assert!(matches!(result, Err(NCError::ServerBusy(_, Some(_)))));
+
+        // The node has been disabled by the admin
+        let mut nc_client = with_connections(&server_process, 1, |port| {
+            let mut nc_client = NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap();
+            nc_client.register::<()>().unwrap();
+            nc_client
+        }).0;
+        assert!(server_process.node_list.lock().unwrap().disable_node(nc_client.node_id()));
+        let (result, _) = with_connections(&server_process, 1, |port| {
+            nc_client.set_server("127.0.0.1", port).unwrap();
+            nc_client.request_data::<(), ()>()
+        });
+        assert!(matches!(&result, Err(NCError::NodeDisabled(_))));
+        assert!(result.unwrap_err().is_fatal_rejection());
+
+        // The node runs another version, the message doesn't fit
+        let (result, server_results) = with_connections(&server_process, 1, |port| {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let nc_communicator = NCCommunicator::new(&NCConfiguration::default()).unwrap();
+            stream.write_all(&5u64.to_le_bytes()).unwrap();
+            stream.write_all(&[0, 1, 2, 3, 4]).unwrap();
+            nc_communicator.nc_receive_data::<NCServerMessage<(), (), ()>, _>(&mut stream)
+        });
+        assert!(matches!(result, Err(NCError::VersionMismatch(_))), "{:?}", result);
+        assert!(server_results[0].is_err());
+    }
+
+    #[test]
+    fn test_measure_clock() {
+        let server_process = server_process_for_test();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = NCConfiguration { port: listener.local_addr().unwrap().port(), ..Default::default() };
+
+        let clock_skew = thread::scope(|scope| {
+            scope.spawn(|| {
+                // Register, ClockProbe, ClockReport
+                for _ in 0..3 {
+                    let (stream, _) = listener.accept().unwrap();
+                    server_process.handle_node(Box::new(stream)).unwrap();
+                }
+            });
+
+            let mut client = NCClient::connect(&config).unwrap();
+            client.register::<()>().unwrap();
+            client.measure_clock().unwrap()
+        });
+
+        // Same machine, same clock
+        assert!(clock_skew.offset() < Duration::from_secs(1));
+        let clock_skews = server_process.node_list.lock().unwrap().clock_skews();
+        assert_eq!(clock_skews.len(), 1);
+        assert_eq!(clock_skews[0].1, clock_skew);
+
+        // A node that is an hour behind only gets a warning
+        let node_id = clock_skews[0].0;
+        server_process.set_clock_skew(node_id, NCClockSkew { offset_micros: 3_600_000_000, rtt_micros: 100 }).unwrap();
+        assert_eq!(server_process.node_list.lock().unwrap().clock_skews()[0].1.offset(), Duration::from_secs(3600));
+        assert!(server_process.heartbeats.check(1).is_empty());
+    }
+
+    #[test]
+    fn test_chunk_empty_and_skipped() {
+        let server_process = server_process_for_test();
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+        let mut buffer: Vec<u8> = Vec::new();
+
+        assert_eq!(assign_and_send_to(&server_process, node_id, &mut buffer).unwrap(), 0);
+        server_process.chunk_empty(node_id).unwrap();
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (1, 0, 1));
+        assert_eq!(server_process.empty_chunks.load(Ordering::Relaxed), 1);
+
+        // No chunk has been sent to the node since then
+        server_process.chunk_empty(node_id).unwrap();
+        assert_eq!(server_process.empty_chunks.load(Ordering::Relaxed), 1);
+
+        assert_eq!(assign_and_send_to(&server_process, node_id, &mut buffer).unwrap(), 1);
+        server_process.chunk_skipped(node_id, "outside".to_string()).unwrap();
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (1, 0, 1));
+
+        assert_eq!(assign_and_send_to(&server_process, node_id, &mut buffer).unwrap(), 1);
+        server_process.chunk_skipped(node_id, "outside".to_string()).unwrap();
+        assert_eq!(*server_process.skipped_chunks.lock().unwrap(), vec![("outside".to_string(), 2)]);
+    }
+
+    #[test]
+    fn test_ordered_results() {
+        let config = NCConfiguration { ordered_results: true, ..Default::default() };
+        let server_process = server_process_with_config(config);
+        let node_id1 = server_process.node_list.lock().unwrap().register_new_node();
+        let node_id2 = server_process.node_list.lock().unwrap().register_new_node();
+        let mut buffer: Vec<u8> = Vec::new();
+
+        assert_eq!(assign_and_send_to(&server_process, node_id1, &mut buffer).unwrap(), 0);
+        assert_eq!(assign_and_send_to(&server_process, node_id2, &mut buffer).unwrap(), 1);
+
+        server_process.queue_result(node_id2, None, (), None).unwrap();
+        assert_eq!(server_process.result_queue.stats().unwrap().len, 0);
+
+        server_process.queue_result(node_id1, None, (), None).unwrap();
+        server_process.result_queue.close().unwrap();
+
+        assert_eq!(server_process.result_queue.pop().unwrap().unwrap().0, node_id1);
+        assert_eq!(server_process.result_queue.pop().unwrap().unwrap().0, node_id2);
+        assert!(server_process.result_queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_reorder_gap_lock_order() {
+        let config = NCConfiguration { ordered_results: true, max_reorder_wait: 0, ..Default::default() };
+        let server_process = server_process_with_config(config);
+        let node_id1 = server_process.node_list.lock().unwrap().register_new_node();
+        let node_id2 = server_process.node_list.lock().unwrap().register_new_node();
+        let mut buffer: Vec<u8> = Vec::new();
+
+        assert_eq!(assign_and_send_to(&server_process, node_id1, &mut buffer).unwrap(), 0);
+        assert_eq!(assign_and_send_to(&server_process, node_id2, &mut buffer).unwrap(), 1);
+        server_process.queue_result(node_id2, None, (), None).unwrap();
+
+        // While chunk_gap() waits for nc_server the reorder buffer is not locked
+        let nc_server = server_process.nc_server.lock().unwrap();
+
+        thread::scope(|scope| {
+            let checker = scope.spawn(|| server_process.check_reorder_gap());
+            thread::sleep(Duration::from_millis(50));
+            assert!(server_process.reorder_buffer.as_ref().unwrap().try_lock().is_ok());
+
+            drop(nc_server);
+            checker.join().unwrap().unwrap();
+        });
+
+        // Chunk 0 has been skipped
+        server_process.result_queue.close().unwrap();
+        assert_eq!(server_process.result_queue.pop().unwrap().unwrap().0, node_id2);
+        assert!(server_process.result_queue.pop().is_none());
+    }
+
+    /// Handles the given number of connections while the client code runs, returns the result of the client code
+    /// and the results of handle_node().
+    fn with_connections<R, F: FnOnce(u16) -> R>(server_process: &NCServerProcess<TestServer, ()>, connections: usize, client: F) -> (R, Vec<Result<(), NCError>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                (0..connections).map(|_| {
+                    let (stream, _) = listener.accept().unwrap();
+                    server_process.handle_node(Box::new(stream))
+                }).collect()
+            });
+
+            let result = client(port);
+            (result, server.join().unwrap())
+        })
+    }
+
+    const KEY1: &str = "u8PqN2lO3ZJbm6DHkTnR0cyVaSx1FWgi";
+    const KEY2: &str = "Hn3kqPZ0lXv8dWmYc1RtG5sJbQ2eA7fU";
+
+    #[test]
+    fn test_rotate_key_mid_transfer() {
+        let config = NCConfiguration { encrypt: true, key: KEY1.to_string(), ..Default::default() };
+        let server_process = server_process_with_config(config.clone());
+
+        let (mut nc_client, _) = with_connections(&server_process, 1, |port| {
+            let mut nc_client = NCClient::connect(&NCConfiguration { port, ..config.clone() }).unwrap();
+            nc_client.register::<()>().unwrap();
+            nc_client
+        });
+
+        // The node is processing its data while the key is rotated
+        let (result, server_results) = with_connections(&server_process, 1, |port| {
+            let admin_config = NCConfiguration { port, admin_key: "ZTXbsBVhz9tDzDhklykVDUXznjonhGil".to_string(), ..config.clone() };
+            NCClient::connect(&admin_config).unwrap().rotate_key(KEY2)
+        });
+        assert!(result.is_ok());
+        assert!(server_results[0].is_ok());
+        assert_eq!(server_process.nc_communicator.lock().unwrap().current_key().unwrap(), KEY2);
+
+        // A malformed key is rejected with the reason, the server keeps its key
+        let (result, server_results) = with_connections(&server_process, 1, |port| {
+            let admin_config = NCConfiguration { port, key: KEY2.to_string(), admin_key: "ZTXbsBVhz9tDzDhklykVDUXznjonhGil".to_string(), ..config.clone() };
+            NCClient::connect(&admin_config).unwrap().rotate_key("too short")
+        });
+        assert!(matches!(result, Err(NCError::InvalidKey(_))));
+        assert!(matches!(server_results[0], Err(NCError::InvalidKey(_))));
+        assert_eq!(server_process.nc_communicator.lock().unwrap().current_key().unwrap(), KEY2);
+
+        // First connection: the server sends the new key, second connection: the result is sent again with the new key
+        let mut heartbeat_client = nc_client.share();
+        let (result, server_results) = with_connections(&server_process, 2, |port| {
+            nc_client.set_server("127.0.0.1", port).unwrap();
+            nc_client.submit_result(())
+        });
+
+        assert!(result.is_ok());
+        assert!(server_results.iter().all(|result| result.is_ok()));
+        assert_eq!(server_process.result_queue.stats().unwrap().len, 1);
+
+        // All the clients of the node use the new key now, so only one connection is needed
+        let (result, _) = with_connections(&server_process, 1, |port| {
+            heartbeat_client.set_server("127.0.0.1", port).unwrap();
+            heartbeat_client.submit_empty()
+        });
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_expired_key() {
+        let config = NCConfiguration { encrypt: true, key: KEY2.to_string(), ..Default::default() };
+        let server_process = server_process_with_config(config.clone());
+
+        let (result, server_results) = with_connections(&server_process, 1, |port| {
+            NCClient::connect(&NCConfiguration { port, key: KEY1.to_string(), ..config }).unwrap().register::<()>()
+        });
+
+        // The server can't encrypt an answer that the node can read, so the rejection is sent without encryption
+        assert!(matches!(result, Err(NCError::WrongKey(_))));
+        assert!(matches!(server_results[0], Err(NCError::Decrypt)));
+        assert_eq!(server_process.node_list.lock().unwrap().len(), 0);
+    }
+
+    fn next_assignment_and_send(server_process: &NCServerProcess<TestServer, ()>, node_id: NodeID) -> ChunkID {
+        match server_process.next_assignment(node_id, &NCAssignContext::default()).unwrap() {
+            ChunkAssignment::Assigned(chunk_id, data) => {
+                server_process.send_chunk(node_id, chunk_id, data, false, &mut Vec::new()).unwrap();
+                chunk_id
+            }
+            assignment => panic!("Unexpected assignment: {:?}", assignment)
+        }
+    }
+
+    #[test]
+    fn test_chunk_cache() {
+        let config = NCConfiguration { cache_chunk_payloads: true, ..Default::default() };
+        let server_process = server_process_with_config(config);
+        let node_id1 = server_process.node_list.lock().unwrap().register_new_node();
+        let node_id2 = server_process.node_list.lock().unwrap().register_new_node();
+        let node_id3 = server_process.node_list.lock().unwrap().register_new_node();
+
+        assert_eq!(next_assignment_and_send(&server_process, node_id1), 0);
+        // The data for chunk 1 is not cached, see TestServer::cache_chunk()
+        assert_eq!(next_assignment_and_send(&server_process, node_id2), 1);
+
+        {
+            let mut nc_server = server_process.nc_server.lock().unwrap();
+            nc_server.heartbeat_timeout(vec![node_id1]);
+            server_process.release_chunks(&[node_id1], true).unwrap();
+        }
+        server_process.node_failed(node_id2, NCJobError::deadline_exceeded()).unwrap();
+
+        // Chunk 0 comes from the cache, chunk 1 has to be prepared again by assign_chunk()
+        assert_eq!(next_assignment_and_send(&server_process, node_id3), 0);
+        assert!(server_process.nc_server.lock().unwrap().chunk_list.get(0).is_processing(node_id3));
+        assert_eq!(next_assignment_and_send(&server_process, node_id2), 1);
+
+        server_process.queue_result(node_id3, None, (), None).unwrap();
+        server_process.queue_result(node_id2, None, (), None).unwrap();
+
+        let chunk_cache = server_process.chunk_cache.as_ref().unwrap().lock().unwrap();
+        assert_eq!(chunk_cache.hits(), 1);
+        assert_eq!(chunk_cache.misses(), 1);
+    }
+
+    #[test]
+    fn test_frame_cache() {
+        let config = NCConfiguration { cache_chunk_frames: true, encrypt: true, key: "u8PqN2lO3ZJbm6DHkTnR0cyVaSx1FWgi".to_string(),
+            type_check: true, compression_min_size: 0, ..Default::default() };
+        let server_process = server_process_with_config(config);
+        let node_ids: Vec<NodeID> = (0..3).map(|_| server_process.node_list.lock().unwrap().register_new_node()).collect();
+        let mut frames = Vec::new();
+
+        // The same chunk for three nodes, it's only serialized and compressed once
+        for node_id in node_ids.iter() {
+            server_process.node_list.lock().unwrap().set_codec(NCCodec::Lz4, *node_id);
+            let mut buffer = Vec::new();
+            server_process.send_chunk(*node_id, 0, 10, false, &mut buffer).unwrap();
+            frames.push(buffer);
+        }
+
+        // Chunk 1 is not cached, see TestServer::cache_chunk()
+        let mut buffer = Vec::new();
+        server_process.send_chunk(node_ids[0], 1, 20, false, &mut buffer).unwrap();
+        frames.push(buffer);
+
+        assert_eq!(server_process.frame_cache.as_ref().unwrap().lock().unwrap().hits(), 2);
+        // Every frame has its own nonce
+        assert_ne!(frames[0], frames[1]);
+
+        for (frame, expected) in frames.iter().zip([(0, 10), (0, 10), (0, 10), (1, 20)]) {
+            let message: NCServerMessage<(), u32, ()> = server_process.nc_communicator.lock().unwrap().nc_receive_data(&mut frame.as_slice()).unwrap();
+
+            match message {
+                NCServerMessage::JobStatus(NCJobStatus::Unfinished(data, chunk_info)) => assert_eq!((chunk_info.chunk_id, data), expected),
+                _ => panic!("Unexpected message"),
+            }
+        }
+
+        // The frame is dropped when the chunk is done
+        server_process.release_cached_chunk(0, false).unwrap();
+        server_process.send_chunk(node_ids[0], 0, 10, false, &mut Vec::new()).unwrap();
+        assert_eq!(server_process.frame_cache.as_ref().unwrap().lock().unwrap().hits(), 2);
+    }
+
+    /// A node that expects different data than the TestServer sends.
+    struct WrongTypeNode;
+
+    impl NCNode for WrongTypeNode {
+        type InitialDataT = ();
+        type NewDataT = (u64, u64, u64);
+        type ProcessedDataT = ();
+        type CustomMessageT = ();
+
+        fn process_data_from_server(&mut self, _data: &(u64, u64, u64)) -> Result<NodeResult<()>, NCError> {
+            Ok(NodeResult::Empty)
+        }
+    }
+
+    fn test_server() -> TestServer {
+        let mut chunk_list = ChunkList::new();
+        chunk_list.push(10);
+        TestServer{ chunk_list, result_error: None, fail_results: false, deadline: None, post_processor: None, post_process_failures: Vec::new(), aggregates: Vec::new(), disconnected: Vec::new(), work_hints: Vec::new(), metas: Vec::new(), invalid_results: false, panics: false, dead_chunks: Vec::new(), abort_dead_chunks: false, contexts: Vec::new(), batches: Vec::new() }
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let starter = NCServerStarter::new(NCConfiguration { port: 0, ..Default::default() });
+        let mut nc_server = test_server();
+
+        let report = starter.dry_run(&mut nc_server);
+        assert!(report.is_ok(), "{}", report);
+        // The chunk has been returned
+        assert_eq!(nc_server.chunk_list.stats(), (1, 0, 0));
+
+        let report = starter.dry_run_for_node::<WrongTypeNode>(&mut nc_server);
+        assert_eq!(report.problems().map(|check| check.name.as_str()).collect::<Vec<_>>(), vec!["assign chunk"]);
+    }
+
+    #[test]
+    fn test_dry_run_problems() {
+        let config = NCConfiguration { port: 0, pool_size: 0, key: "too short".to_string(), ..Default::default() };
+        let report = NCServerStarter::new(config).dry_run(&mut test_server());
+
+        assert_eq!(report.problems().map(|check| check.name.as_str()).collect::<Vec<_>>(), vec!["configuration", "encryption keys"]);
+
+        // No chunks left
+        let mut nc_server = TestServer{ chunk_list: ChunkList::new(), result_error: None, fail_results: false, deadline: None, post_processor: None, post_process_failures: Vec::new(), aggregates: Vec::new(), disconnected: Vec::new(), work_hints: Vec::new(), metas: Vec::new(), invalid_results: false, panics: false, dead_chunks: Vec::new(), abort_dead_chunks: false, contexts: Vec::new(), batches: Vec::new() };
+        let report = NCServerStarter::new(NCConfiguration { port: 0, ..Default::default() }).dry_run(&mut nc_server);
+        assert_eq!(report.problems().map(|check| check.name.as_str()).collect::<Vec<_>>(), vec!["assign chunk"]);
+    }
+
+    /// Sends a chunk to the given node, the node sends the result back and process_data_from_node() fails.
+    /// Returns the chunk id and the error for the node.
+    fn reject_result(server_process: &NCServerProcess<TestServer, ()>, node_id: NodeID) -> (ChunkID, NCJobError) {
+        let chunk_id = assign_and_send_to(server_process, node_id, &mut Vec::new()).unwrap();
+        server_process.queue_result(node_id, None, (), None).unwrap();
+        server_process.nc_server.lock().unwrap().fail_results = true;
+
+        let (node_id, (chunk_id2, meta, data)) = server_process.result_queue.pop().unwrap().unwrap();
+        assert_eq!(chunk_id2, Some(chunk_id));
+        server_process.process_result(node_id, chunk_id2, meta, data).unwrap();
+
+        (chunk_id, server_process.node_list.lock().unwrap().get_job_error(node_id).unwrap())
+    }
+
+    #[test]
+    fn test_on_process_error_requeue() {
+        let config = NCConfiguration { on_process_error: OnProcessError::RequeueChunk, max_process_retries: 1, ..Default::default() };
+        let server_process = server_process_with_config(config);
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+
+        let (chunk_id, job_error) = reject_result(&server_process, node_id);
+        assert_eq!(chunk_id, 0);
+        assert_eq!(job_error, NCJobError::result_rejected(NCError::Custom(1).to_string(), true));
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (2, 0, 0));
+
+        // Second time the retry limit is exceeded
+        let (chunk_id, job_error) = reject_result(&server_process, node_id);
+        assert_eq!(chunk_id, 0);
+        assert!(!job_error.retryable);
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.failed_chunks().collect::<Vec<_>>(), vec![0]);
+        assert!(server_process.process_attempts.lock().unwrap().is_empty());
+        assert!(!server_process.is_job_done());
+    }
+
+    #[test]
+    fn test_on_process_error_drop() {
+        let config = NCConfiguration { on_process_error: OnProcessError::DropChunk, ..Default::default() };
+        let server_process = server_process_with_config(config);
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+
+        let (chunk_id, job_error) = reject_result(&server_process, node_id);
+        assert!(!job_error.retryable);
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.failed_chunks().collect::<Vec<_>>(), vec![chunk_id]);
+        assert_eq!(assign_and_send_to(&server_process, node_id, &mut Vec::new()).unwrap(), 1);
+        assert!(!server_process.is_job_done());
+    }
+
+    #[test]
+    fn test_user_panic() {
+        let config = NCConfiguration { on_process_error: OnProcessError::DropChunk, ..Default::default() };
+        let server_process = server_process_with_config(config);
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+        let chunk_id = assign_and_send_to(&server_process, node_id, &mut Vec::new()).unwrap();
+        server_process.queue_result(node_id, None, (), None).unwrap();
+        server_process.nc_server.lock().unwrap().panics = true;
+
+        // The lock is not poisoned
+        let context = NCAssignContext { resources: None, work_hint: None, tags: Vec::new(), locality: None };
+        assert!(matches!(server_process.next_assignment(node_id, &context), Err(NCError::UserPanic(message)) if message == "assign_chunk_with_context(): no chunks today"));
+        assert!(server_process.nc_server.lock().is_ok());
+
+        // The chunk is dropped like after an error
+        let (node_id, (chunk_id2, meta, data)) = server_process.result_queue.pop().unwrap().unwrap();
+        assert_eq!(chunk_id2, Some(chunk_id));
+        server_process.process_result(node_id, chunk_id2, meta, data).unwrap();
+        let job_error = server_process.node_list.lock().unwrap().get_job_error(node_id).unwrap();
+        assert_eq!(job_error, NCJobError::result_rejected("User code panicked in process_data_from_node(): broken result", false));
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.failed_chunks().collect::<Vec<_>>(), vec![chunk_id]);
+        assert_eq!(server_process.user_panics.load(Ordering::Relaxed), 2);
+
+        server_process.nc_server.lock().unwrap().panics = false;
+        assert_eq!(assign_and_send_to(&server_process, node_id, &mut Vec::new()).unwrap(), 1);
+        assert!(!server_process.is_job_done());
+    }
+
+    /// Sums up the squares that the nodes send back, panics for the square of 3.
+    struct PanicServer {
+        chunk_list: ChunkList<u64>,
+        sum: u64,
+    }
+
+    impl NCServer for PanicServer {
+        type InitialDataT = ();
+        type NewDataT = (ChunkID, u64);
+        type ProcessedDataT = (ChunkID, u64);
+        type CustomMessageT = ();
+
+        fn assign_chunk(&mut self, node_id: NodeID) -> Result<ChunkAssignment<(ChunkID, u64)>, NCError> {
+            if let Some((chunk_id, chunk)) = self.chunk_list.assign_next_chunk(node_id) {
+                return Ok(ChunkAssignment::Assigned(chunk_id, (chunk_id, chunk.data)))
+            }
+
+            let (done, total) = self.chunk_list.progress();
+            Ok(if done == total { ChunkAssignment::Finished } else { ChunkAssignment::Waiting })
+        }
+
+        fn chunk_sent(&mut self, chunk_id: ChunkID) {
+            self.chunk_list.chunk_sent(chunk_id)
+        }
+
+        fn chunk_rejected(&mut self, chunk_id: ChunkID, requeue: bool) {
+            self.chunk_list.chunk_rejected(chunk_id, requeue)
+        }
+
+        fn process_data_from_node(&mut self, _node_id: NodeID, data: &(ChunkID, u64)) -> Result<(), NCError> {
+            assert!(data.1 != 9, "can't add 9");
+            self.chunk_list.get(data.0 as usize).set_finished();
+            self.sum += data.1;
+            Ok(())
+        }
+
+        fn heartbeat_timeout(&mut self, nodes: Vec<NodeID>) {
+            self.chunk_list.heartbeat_timeout(&nodes)
+        }
+
+        fn finish_job(&mut self) {}
+    }
+
+    struct SquareNode;
+
+    impl NCNode for SquareNode {
+        type InitialDataT = ();
+        type NewDataT = (ChunkID, u64);
+        type ProcessedDataT = (ChunkID, u64);
+        type CustomMessageT = ();
+
+        fn process_data_from_server(&mut self, data: &(ChunkID, u64)) -> Result<NodeResult<(ChunkID, u64)>, NCError> {
+            Ok(NodeResult::Data((data.0, data.1 * data.1)))
+        }
+    }
+
+    #[test]
+    fn test_user_panic_job() {
+        let config = NCConfiguration { port: 0, delay_request_data: 1, heartbeat: 1, on_process_error: OnProcessError::DropChunk, ..Default::default() };
+        let mut chunk_list = ChunkList::new();
+
+        for i in 1..=5 {
+            chunk_list.push(i);
+        }
+
+        // The job completes without the panicking chunk
+        let (nc_server, job_summary) = crate::nc_local::run(&config, PanicServer { chunk_list, sum: 0 }, |_| SquareNode, 2).unwrap();
+        assert_eq!(nc_server.sum, 1 + 4 + 16 + 25);
+        assert_eq!(nc_server.chunk_list.failed_chunks().collect::<Vec<_>>(), vec![2]);
+        assert_eq!(job_summary.failed_chunks, 1);
+    }
+
+    #[test]
+    fn test_on_process_error_abort() {
+        let config = NCConfiguration { on_process_error: OnProcessError::AbortJob, ..Default::default() };
+        let server_process = server_process_with_config(config);
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+
+        let (_, job_error) = reject_result(&server_process, node_id);
+        assert!(!job_error.retryable);
+        assert!(server_process.is_job_done());
+    }
+
+    #[test]
+    fn test_result_rejected_message() {
+        let server_process = server_process_for_test();
+
+        let (mut nc_client, _) = with_connections(&server_process, 1, |port| {
+            let mut nc_client = NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap();
+            nc_client.register::<()>().unwrap();
+            nc_client
+        });
+        let node_id = nc_client.node_id();
+
+        let (job_error, _) = with_connections(&server_process, 1, |port| {
+            nc_client.set_server("127.0.0.1", port).unwrap();
+            server_process.node_list.lock().unwrap().add_job_error(NCJobError::result_rejected("broken", true), node_id);
+
+            match nc_client.request_data::<u32, ()>().unwrap() {
+                NCServerMessage::ResultRejected(job_error) => job_error,
+                _ => panic!("Expected ResultRejected"),
+            }
+        });
+
+        assert_eq!(job_error.code, NCJobError::RESULT_REJECTED);
+    }
+
+    #[test]
+    fn test_stalled_connections() {
+        let config = NCConfiguration { max_connections: 2, max_connection_lifetime: 1, ..Default::default() };
+        let starter = NCServerStarter::new(config.clone());
+        let server_process = Arc::new(server_process_with_config(config.clone()));
+        let listener = NCListener::from(TcpListener::bind("127.0.0.1:0").unwrap());
+        let port = listener.port().unwrap();
+        let thread_pool = ThreadPool::new(2);
+
+        let watchdog_thread = starter.start_watchdog_thread(server_process.clone());
+
+        thread::scope(|scope| {
+            let accept_thread = scope.spawn(|| starter.accept_connections(&listener, &thread_pool, server_process.clone()));
+
+            // Connect and then send nothing, the third one is rejected
+            let _stalled: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(("127.0.0.1", port)).unwrap()).collect();
+            thread::sleep(Duration::from_millis(200));
+            assert_eq!(server_process.watchdog.in_flight().unwrap(), 2);
+            assert_eq!(server_process.watchdog.rejected(), 1);
+
+            // The watchdog closes the stalled connections, the pool threads are free again
+            thread::sleep(Duration::from_millis(2000));
+            assert_eq!(server_process.watchdog.killed(), 2);
+
+            let mut nc_client = NCClient::connect(&NCConfiguration { port, ..config.clone() }).unwrap();
+            let statistics = nc_client.get_statistics().unwrap();
+            assert_eq!(statistics.connections_in_flight(), 1);
+            assert_eq!(statistics.rejected_connections(), 1);
+            assert_eq!(statistics.killed_connections(), 2);
+
+            server_process.shut_down(NCJobEndReason::Stopped);
+            let _ = TcpStream::connect(("127.0.0.1", port));
+            accept_thread.join().unwrap();
+        });
+
+        watchdog_thread.join().unwrap();
+        thread_pool.join();
+    }
+
+    #[test]
+    fn test_post_process() {
+        let post_processor = Arc::new(TestPostProcessor { chunks: Mutex::new(Vec::new()) });
+        let mut nc_server = test_server();
+        nc_server.chunk_list.push(20);
+        nc_server.post_processor = Some(post_processor.clone());
+
+        let config = NCConfiguration { post_process_workers: 2, post_process_queue_len: 1, ..Default::default() };
+        let server_process = NCServerProcess::new(&config, nc_server).unwrap();
+        let node_id1 = server_process.node_list.lock().unwrap().register_new_node();
+        let node_id2 = server_process.node_list.lock().unwrap().register_new_node();
+
+        assign_and_send_to(&server_process, node_id1, &mut Vec::new()).unwrap();
+        assign_and_send_to(&server_process, node_id2, &mut Vec::new()).unwrap();
+        server_process.queue_result(node_id1, None, (), None).unwrap();
+        server_process.queue_result(node_id2, None, (), None).unwrap();
+
+        server_process.result_queue.close().unwrap();
+        server_process.process_results();
+        server_process.finish_post_processing().unwrap();
+
+        let mut chunks = post_processor.chunks.lock().unwrap().clone();
+        chunks.sort_unstable();
+        assert_eq!(chunks, vec![Some(0), Some(1)]);
+        assert_eq!(server_process.nc_server.lock().unwrap().post_process_failures, vec![Some(1)]);
+        assert_eq!(server_process.post_process_queue.as_ref().unwrap().failure_count(), 1);
+    }
+
+    #[test]
+    fn test_result_batch() {
+        let config = NCConfiguration { result_batch_size: 2, result_batch_ms: 60_000, ..Default::default() };
+        let server_process = server_process_with_config(config);
+        let node_id1 = server_process.node_list.lock().unwrap().register_new_node();
+        let node_id2 = server_process.node_list.lock().unwrap().register_new_node();
+        assign_and_send_to(&server_process, node_id1, &mut Vec::new()).unwrap();
+        assign_and_send_to(&server_process, node_id2, &mut Vec::new()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for _ in 0..2 {
+                    let (stream, _) = listener.accept().unwrap();
+                    server_process.handle_node(Box::new(stream)).unwrap();
+                }
+            });
+            scope.spawn(|| server_process.process_results());
+
+            let submit = |node_id| {
+                let mut nc_client = NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap();
+                nc_client.set_node_id(node_id);
+                nc_client.submit_result(()).unwrap();
+            };
+
+            // The first node gets the answer only after its batch has been processed
+            let first = scope.spawn(move || submit(node_id1));
+            thread::sleep(Duration::from_millis(200));
+            assert!(!first.is_finished());
+            assert!(server_process.nc_server.lock().unwrap().batches.is_empty());
+            assert_eq!(server_process.result_batcher.as_ref().unwrap().len().unwrap(), 1);
+
+            submit(node_id2);
+            first.join().unwrap();
+            assert_eq!(server_process.nc_server.lock().unwrap().batches, vec![2]);
+
+            server_process.result_queue.close().unwrap();
+            server_process.result_batcher.as_ref().unwrap().close().unwrap();
+        });
+
+        assert!(server_process.process_attempts.lock().unwrap().is_empty());
+
+        // If the batch fails, every result of it is rejected and its chunk is given to a node again
+        let config = NCConfiguration { result_batch_size: 2, result_batch_ms: 0, ..Default::default() };
+        let server_process = server_process_with_config(config);
+        server_process.nc_server.lock().unwrap().fail_results = true;
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+
+        for _ in 0..2 {
+            assign_and_send_to(&server_process, node_id, &mut Vec::new()).unwrap();
+            assert!(server_process.queue_result(node_id, None, (), None).unwrap().is_none());
+        }
+
+        server_process.result_queue.close().unwrap();
+        server_process.result_batcher.as_ref().unwrap().close().unwrap();
+        server_process.process_results();
+
+        assert_eq!(server_process.nc_server.lock().unwrap().batches.iter().sum::<usize>(), 2);
+        assert_eq!(server_process.process_attempts.lock().unwrap().len(), 2);
+
+        // The batches are not post processed
+        let mut nc_server = test_server();
+        nc_server.post_processor = Some(Arc::new(TestPostProcessor { chunks: Mutex::new(Vec::new()) }));
+        let mut starter = NCServerStarter::new(NCConfiguration { port: 0, result_batch_size: 2, ..Default::default() });
+        assert!(starter.start(nc_server).is_err());
+    }
+
+    #[test]
+    fn test_required_node_build() {
+        let config = NCConfiguration { required_node_build: Some("v2".to_string()), ..Default::default() };
+        let server_process = server_process_with_config(config);
+
+        let register = |build: &str| {
+            let (nc_client, _) = with_connections(&server_process, 1, |port| {
+                let mut nc_client = NCClient::connect(&NCConfiguration { port, node_build: Some(build.to_string()), ..Default::default() }).unwrap();
+                nc_client.register::<()>().unwrap();
+                nc_client
+            });
+            nc_client
+        };
+
+        let request_data = |nc_client: &mut NCClient| {
+            let (message, _) = with_connections(&server_process, 1, |port| {
+                nc_client.set_server("127.0.0.1", port).unwrap();
+                nc_client.request_data::<u32, ()>().unwrap()
+            });
+            message
+        };
+
+        let mut old_node = register("v1");
+        let mut new_node = register("v2");
+
+        assert!(matches!(request_data(&mut old_node), NCServerMessage::PleaseRestart { reason } if reason.contains("v2")));
+        assert!(matches!(request_data(&mut new_node), NCServerMessage::JobStatus(NCJobStatus::Unfinished(10, _))));
+        assert_eq!(server_process.node_list.lock().unwrap().len(), 1);
+        assert_eq!(server_process.restart_requests.load(Ordering::Relaxed), 1);
+
+        // Roll the fleet back to v1 via the admin protocol
+        with_connections(&server_process, 1, |port| {
+            let mut admin_client = NCClient::connect(&NCConfiguration { port, admin_key: "ZTXbsBVhz9tDzDhklykVDUXznjonhGil".to_string(), ..Default::default() }).unwrap();
+            admin_client.set_required_node_build(Some("v1".to_string())).unwrap();
+        });
+
+        assert!(matches!(request_data(&mut new_node), NCServerMessage::PleaseRestart { .. }));
+        assert_eq!(server_process.node_list.lock().unwrap().len(), 0);
+
+        let mut old_node = register("v1");
+        assert!(matches!(request_data(&mut old_node), NCServerMessage::JobStatus(NCJobStatus::Unfinished(_, _))));
+    }
+
+    #[test]
+    fn test_progress_events() {
+        let mut server_process = server_process_for_test();
+        let (sender, receiver) = mpsc::channel();
+        server_process.progress_sender = Some(sender);
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+
+        assert_eq!(assign_and_send_to(&server_process, node_id, &mut Vec::new()).unwrap(), 0);
+        server_process.chunk_empty(node_id).unwrap();
+        server_process.report_job_progress().unwrap();
+
+        let events: Vec<NCProgressEvent> = receiver.try_iter().collect();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0], NCProgressEvent::ChunkSent(node_id, 0));
+        assert!(matches!(events[1], NCProgressEvent::ChunkDone(id, 0, _) if id == node_id));
+        assert_eq!(events[2], NCProgressEvent::JobProgress(1, 2));
+    }
+
+    /// Registers a worker and an aggregator, every chunk is sent to the worker and delegated to the aggregator.
+    fn delegate_chunks(server_process: &NCServerProcess<TestServer, ()>) -> (NodeID, NodeID, Vec<ChunkID>) {
+        let worker = server_process.node_list.lock().unwrap().register_new_node();
+        let aggregator = server_process.node_list.lock().unwrap().register_new_node();
+        let aggregator_addr = SocketAddr::from(([127, 0, 0, 1], 9001));
+        server_process.node_list.lock().unwrap().set_aggregator(Some(aggregator_addr), None, aggregator);
+
+        let chunks = (0..2).map(|_| {
+            let mut stream = Vec::new();
+            let chunk_id = assign_and_send_to(server_process, worker, &mut stream).unwrap();
+
+            let message: NCServerMessage<(), u32, ()> = server_process.nc_communicator.lock().unwrap().nc_decode_data(&stream[8..]).unwrap();
+            let chunk_info = match message {
+                NCServerMessage::JobStatus(NCJobStatus::Unfinished(_, chunk_info)) => chunk_info,
+                message => panic!("Unexpected message: {:?}", message),
+            };
+            assert_eq!(chunk_info.aggregator, Some(aggregator_addr));
+
+            assert!(server_process.chunk_delegated(chunk_id, worker, aggregator).unwrap());
+            // The worker doesn't have the chunk anymore
+            assert!(!server_process.chunk_delegated(chunk_id, worker, aggregator).unwrap());
+            chunk_id
+        }).collect();
+
+        (worker, aggregator, chunks)
+    }
+
+    #[test]
+    fn test_aggregate_received() {
+        let server_process = server_process_for_test();
+        let (_, aggregator, chunks) = delegate_chunks(&server_process);
+        assert!(server_process.nc_server.lock().unwrap().chunk_list.get(0).is_processing(aggregator));
+
+        server_process.aggregate_received(aggregator, chunks.clone(), Some(())).unwrap();
+
+        let nc_server = server_process.nc_server.lock().unwrap();
+        assert_eq!(nc_server.aggregates, vec![chunks]);
+        assert_eq!(nc_server.chunk_list.stats(), (0, 0, 2));
+    }
+
+    #[test]
+    fn test_aggregate_released() {
+        // The aggregator has missed its heartbeat, its late result is dropped
+        let server_process = server_process_for_test();
+        let (_, aggregator, chunks) = delegate_chunks(&server_process);
+
+        server_process.nc_server.lock().unwrap().heartbeat_timeout(vec![aggregator]);
+        server_process.release_chunks(&[aggregator], true).unwrap();
+        server_process.aggregate_received(aggregator, chunks, Some(())).unwrap();
+
+        let nc_server = server_process.nc_server.lock().unwrap();
+        assert!(nc_server.aggregates.is_empty());
+        assert_eq!(nc_server.chunk_list.stats(), (2, 0, 0));
+        drop(nc_server);
+
+        // reduce() has failed on the aggregator
+        let server_process = server_process_for_test();
+        let (_, aggregator, chunks) = delegate_chunks(&server_process);
+
+        server_process.aggregate_received(aggregator, chunks, None).unwrap();
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (2, 0, 0));
+        assert!(!server_process.node_list.lock().unwrap().has_delegated_chunks(aggregator));
+    }
+
+    #[test]
+    fn test_min_node_resources() {
+        let config = NCConfiguration { min_node_free_disk: 1000, ..Default::default() };
+        let server_process = server_process_with_config(config);
+
+        let (mut nc_client, _) = with_connections(&server_process, 1, |port| {
+            let mut nc_client = NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap();
+            nc_client.register::<()>().unwrap();
+            nc_client
+        });
+
+        let heartbeat = |nc_client: &mut NCClient, free_disk_bytes: u64| {
+            with_connections(&server_process, 1, |port| {
+                nc_client.set_server("127.0.0.1", port).unwrap();
+                nc_client.heartbeat_with_resources(NCResourceReport { free_disk_bytes: Some(free_disk_bytes), ..Default::default() }).unwrap();
+            });
+        };
+
+        heartbeat(&mut nc_client, 500);
+
+        let node_id = server_process.node_list.lock().unwrap().get_time_stamps()[0].0;
+        assert_eq!(server_process.heartbeats.get_resources(node_id).unwrap().free_disk_bytes, Some(500));
+
+        let request_data = |nc_client: &mut NCClient| {
+            let (message, _) = with_connections(&server_process, 1, |port| {
+                nc_client.set_server("127.0.0.1", port).unwrap();
+                nc_client.request_data::<u32, ()>().unwrap()
+            });
+            message
+        };
+
+        // Below the floor the user code is not called
+        assert!(matches!(request_data(&mut nc_client), NCServerMessage::JobStatus(NCJobStatus::Waiting)));
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats().1, 0);
+
+        heartbeat(&mut nc_client, 5000);
+        assert!(matches!(request_data(&mut nc_client), NCServerMessage::JobStatus(NCJobStatus::Unfinished(10, _))));
+    }
+
+    #[test]
+    fn test_job_aborted_message() {
+        let server_process = server_process_for_test();
+
+        let mut nc_client = with_connections(&server_process, 1, |port| {
+            let mut nc_client = NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap();
+            nc_client.register::<()>().unwrap();
+            nc_client
+        }).0;
+
+        // The nodes are told why the job has ended
+        server_process.shut_down(NCJobEndReason::Aborted("maintenance".to_string()));
+        let (message, _) = with_connections(&server_process, 1, |port| {
+            nc_client.set_server("127.0.0.1", port).unwrap();
+            nc_client.request_data::<u32, ()>().unwrap()
+        });
+
+        assert!(matches!(&message, NCServerMessage::JobAborted(reason) if reason == "aborted: maintenance"), "{:?}", message);
+    }
+
+    #[test]
+    fn test_job_summary() {
+        let checkpoint_file = std::env::temp_dir().join(format!("nc_test_job_summary_{}.bin", std::process::id()));
+        let config = NCConfiguration { checkpoint_file: Some(checkpoint_file.clone()), write_job_summary: true, ..Default::default() };
+        let server_process = server_process_with_config(config);
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+
+        assign_and_send_to(&server_process, node_id, &mut Vec::new()).unwrap();
+        server_process.queue_result(node_id, None, (), None).unwrap();
+        server_process.result_queue.close().unwrap();
+        server_process.process_results();
+
+        // The job is aborted before the chunks are done, only the first reason counts
+        server_process.shut_down(NCJobEndReason::Aborted("test".to_string()));
+        server_process.shut_down(NCJobEndReason::Stopped);
+
+        let job_summary = server_process.finish(thread::spawn(|| ())).unwrap();
+        let summary_file = NCJobSummary::file_for_checkpoint(&checkpoint_file);
+        let json = fs::read_to_string(&summary_file).unwrap();
+        fs::remove_file(&summary_file).unwrap();
+        let _ = fs::remove_file(&checkpoint_file);
+
+        assert_eq!(job_summary.end_reason, NCJobEndReason::Aborted("test".to_string()));
+        assert_eq!((job_summary.done_chunks, job_summary.total_chunks), (Some(0), Some(2)));
+        assert_eq!(job_summary.chunks_sent, 1);
+        assert_eq!(job_summary.nodes.len(), 1);
+        assert_eq!(job_summary.nodes[0].node_id, node_id);
+        assert_eq!(job_summary.nodes[0].results, 1);
+        assert!(json.contains("\"Aborted\": \"test\""));
+    }
+
+    #[test]
+    fn test_strict_mode() {
+        // Without strict_mode an anomaly is only logged
+        let server_process = server_process_for_test();
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+        server_process.queue_result(node_id, None, (), None).unwrap();
+        assert!(!server_process.is_job_done());
+
+        let server_process = server_process_with_config(NCConfiguration { strict_mode: true, ..Default::default() });
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+        assign_and_send_to(&server_process, node_id, &mut Vec::new()).unwrap();
+        server_process.queue_result(node_id, None, (), None).unwrap();
+        assert!(!server_process.is_job_done());
+
+        // The chunk has already been delivered
+        server_process.queue_result(node_id, None, (), None).unwrap();
+        assert!(server_process.is_job_done());
+
+        // A broken message is the second anomaly
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _node_end = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        assert!(server_process.handle_frame(&[1, 2, 3], Box::new(stream)).is_err());
+
+        let job_summary = server_process.job_summary().unwrap();
+        assert_eq!(job_summary.strict_violations.len(), 2);
+        assert!(job_summary.strict_violations[1].starts_with("invalid message from a node"));
+
+        server_process.result_queue.close().unwrap();
+        server_process.process_results();
+
+        match server_process.finish(thread::spawn(|| ())) {
+            Err(NCError::StrictViolation(description)) => assert_eq!(description, format!("result from node {} that has no chunk (duplicate or late result)", node_id)),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_send_chunk_peer_disconnected() {
+        let server_process = server_process_for_test();
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+
+        for error_kind in [io::ErrorKind::BrokenPipe, io::ErrorKind::ConnectionReset] {
+            // The node reads the length of the frame and a part of the data, then it is killed
+            let result = assign_and_send_to(&server_process, node_id, &mut ClosingStream { bytes_left: 12, error_kind });
+            assert!(matches!(result, Err(NCError::PeerDisconnected(ref e)) if e.kind() == error_kind));
+        }
+
+        // The chunk is back in the pool right away and the node doesn't have a chunk
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (2, 0, 0));
+        assert_eq!(server_process.nc_server.lock().unwrap().disconnected, vec![node_id, node_id]);
+        assert!(!server_process.node_list.lock().unwrap().has_current_chunk(0, node_id));
+
+        // Other errors are not a disconnect
+        let result = assign_and_send_to(&server_process, node_id, &mut ClosingStream { bytes_left: 12, error_kind: io::ErrorKind::Other });
+        assert!(matches!(result, Err(NCError::IOError(_))));
+        assert_eq!(server_process.nc_server.lock().unwrap().disconnected.len(), 2);
+        assert_eq!(assign_and_send_to(&server_process, node_id, &mut Vec::new()).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_work_hint() {
+        let server_process = server_process_for_test();
+
+        let (mut nc_client, _) = with_connections(&server_process, 1, |port| {
+            let mut nc_client = NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap();
+            nc_client.register::<()>().unwrap();
+            nc_client
+        });
+
+        let work_hint = NCWorkHint { max_bytes: Some(1024), max_duration_hint: None, preferred_kind: Some("gpu".to_string()) };
+
+        let (message, _) = with_connections(&server_process, 1, |port| {
+            nc_client.set_server("127.0.0.1", port).unwrap();
+            nc_client.request_data_with_hint::<u32, ()>(Some(work_hint.clone())).unwrap()
+        });
+        assert!(matches!(message, NCServerMessage::JobStatus(NCJobStatus::Unfinished(10, _))));
+
+        // A node without a hint sends the old NeedsData message
+        let (message, _) = with_connections(&server_process, 1, |port| {
+            nc_client.set_server("127.0.0.1", port).unwrap();
+            nc_client.request_data::<u32, ()>().unwrap()
+        });
+        assert!(matches!(message, NCServerMessage::JobStatus(NCJobStatus::Unfinished(20, _))));
+
+        assert_eq!(server_process.nc_server.lock().unwrap().work_hints, vec![Some(work_hint), None]);
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let server_process = server_process_for_test();
+
+        let (mut nc_client, _) = with_connections(&server_process, 1, |port| {
+            let mut nc_client = NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap();
+            nc_client.register::<()>().unwrap();
+            nc_client
+        });
+        let node_id = nc_client.node_id();
+
+        let request = |nc_client: &mut NCClient, prefetch: bool| -> NCServerMessage<(), u32, ()> {
+            with_connections(&server_process, 1, |port| {
+                nc_client.set_server("127.0.0.1", port).unwrap();
+
+                if prefetch {
+                    let data = nc_client.request_data_prefetch(None).unwrap();
+                    nc_client.decode_prefetched(&data, None).unwrap()
+                } else {
+                    nc_client.request_data().unwrap()
+                }
+            }).0
+        };
+
+        // The node holds two chunks, the results belong to them in the order they have been sent
+        assert!(matches!(request(&mut nc_client, false), NCServerMessage::JobStatus(NCJobStatus::Unfinished(10, _))));
+        assert!(matches!(request(&mut nc_client, true), NCServerMessage::JobStatus(NCJobStatus::Unfinished(20, _))));
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (0, 2, 0));
+        assert!(server_process.node_list.lock().unwrap().has_current_chunk(0, node_id));
+
+        // A chunk that is given back goes to the next node
+        with_connections(&server_process, 1, |port| {
+            nc_client.set_server("127.0.0.1", port).unwrap();
+            nc_client.release_chunk(1).unwrap();
+        });
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (1, 1, 0));
+        assert!(server_process.node_list.lock().unwrap().has_current_chunk(0, node_id));
+
+        assert!(matches!(request(&mut nc_client, true), NCServerMessage::JobStatus(NCJobStatus::Unfinished(20, _))));
+        with_connections(&server_process, 1, |port| {
+            nc_client.set_server("127.0.0.1", port).unwrap();
+            nc_client.submit_result(()).unwrap();
+        });
+        assert!(server_process.node_list.lock().unwrap().has_current_chunk(1, node_id));
+
+        // The node is not moved away while it still has a chunk
+        *server_process.new_server.lock().unwrap() = Some(("127.0.0.1".to_string(), 9001));
+        assert!(matches!(request(&mut nc_client, true), NCServerMessage::JobStatus(NCJobStatus::Waiting)));
+        with_connections(&server_process, 1, |port| {
+            nc_client.set_server("127.0.0.1", port).unwrap();
+            nc_client.submit_result(()).unwrap();
+        });
+        assert!(matches!(request(&mut nc_client, true), NCServerMessage::NewServer(_, 9001)));
+    }
+
+    #[test]
+    fn test_chunk_meta() {
+        let config = NCConfiguration { max_chunk_meta_bytes: 10, ..Default::default() };
+        let server_process = server_process_with_config(config);
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+        let meta: ChunkMeta = vec![("tile".to_string(), "3/4".to_string())].into_iter().collect();
+        let too_large: ChunkMeta = vec![("samples".to_string(), "1024".to_string())].into_iter().collect();
+
+        {
+            let mut nc_server = server_process.nc_server.lock().unwrap();
+            nc_server.chunk_list = ChunkList::new();
+            nc_server.chunk_list.push_with_meta(10, meta.clone());
+            nc_server.chunk_list.push_with_meta(20, too_large.clone());
+        }
+
+        // The metadata is sent together with the data, too large metadata is dropped
+        for expected in [Some(meta.clone()), None] {
+            let mut buffer: Vec<u8> = Vec::new();
+            assign_and_send_to(&server_process, node_id, &mut buffer).unwrap();
+            let message: NCServerMessage<(), u32, ()> = server_process.nc_communicator.lock().unwrap().nc_receive_data(&mut buffer.as_slice()).unwrap();
+
+            match message {
+                NCServerMessage::JobStatus(NCJobStatus::Unfinished(_, chunk_info)) => assert_eq!(chunk_info.metadata, expected),
+                _ => panic!("Expected a JobStatus message"),
+            }
+        }
+
+        // The metadata from the node is passed to process_data_with_meta()
+        let meta = server_process.check_chunk_meta(meta.clone(), "from node", node_id);
+        server_process.queue_result(node_id, meta.clone(), (), None).unwrap();
+        server_process.queue_result(node_id, server_process.check_chunk_meta(too_large, "from node", node_id), (), None).unwrap();
+        server_process.queue_result(node_id, None, (), None).unwrap();
+        server_process.result_queue.close().unwrap();
+        server_process.process_results();
+
+        assert_eq!(server_process.nc_server.lock().unwrap().metas, vec![meta, None, None]);
+    }
+
+    #[test]
+    fn test_validate_result() {
+        let server_process = server_process_for_test();
+        server_process.nc_server.lock().unwrap().invalid_results = true;
+
+        let (mut nc_client, _) = with_connections(&server_process, 2, |port| {
+            let mut nc_client = NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap();
+            nc_client.register::<()>().unwrap();
+            assert!(matches!(nc_client.request_data::<u32, ()>().unwrap(), NCServerMessage::JobStatus(NCJobStatus::Unfinished(10, _))));
+            nc_client
+        });
+
+        // The result is acknowledged but not queued, the chunk is free again
+        with_connections(&server_process, 1, |port| {
+            nc_client.set_server("127.0.0.1", port).unwrap();
+            nc_client.submit_result(()).unwrap();
+        });
+
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (2, 0, 0));
+
+        let (message, _) = with_connections(&server_process, 1, |port| {
+            nc_client.set_server("127.0.0.1", port).unwrap();
+            nc_client.request_data::<u32, ()>().unwrap()
+        });
+
+        match message {
+            NCServerMessage::ServerFailed(job_error) => {
+                assert_eq!(job_error.code, NCJobError::VALIDATION_FAILED);
+                assert_eq!(job_error.message, "Validation failed: wrong size");
+                assert!(job_error.retryable);
+            }
+            _ => panic!("Expected ServerFailed"),
+        }
+
+        server_process.result_queue.close().unwrap();
+        assert!(server_process.result_queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_cached_result() {
+        let server_process = server_process_for_test();
+
+        let (mut nc_client, chunk_id) = with_connections(&server_process, 2, |port| {
+            let mut nc_client = NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap();
+            nc_client.register::<()>().unwrap();
+
+            match nc_client.request_data::<u32, ()>().unwrap() {
+                NCServerMessage::JobStatus(NCJobStatus::Unfinished(_, chunk_info)) => (nc_client, chunk_info.chunk_id),
+                _ => panic!("Expected Unfinished"),
+            }
+        }).0;
+
+        // The result of the current chunk is taken once, then the server has it already
+        let (taken, _) = with_connections(&server_process, 2, |port| {
+            nc_client.set_server("127.0.0.1", port).unwrap();
+            (nc_client.offer_cached_result(chunk_id, None, ()).unwrap(), nc_client.offer_cached_result(chunk_id, None, ()).unwrap())
+        });
+        assert_eq!(taken, (true, false));
+
+        // Not the chunk of this node
+        let (taken, _) = with_connections(&server_process, 1, |port| {
+            nc_client.set_server("127.0.0.1", port).unwrap();
+            nc_client.offer_cached_result(chunk_id + 1, None, ()).unwrap()
+        });
+        assert!(!taken);
+
+        server_process.result_queue.close().unwrap();
+        assert!(server_process.result_queue.pop().is_some());
+        assert!(server_process.result_queue.pop().is_none());
+    }
+
+    #[cfg(feature = "debug-protocol")]
+    #[test]
+    fn test_text_protocol() {
+        use std::io::{BufRead, BufReader};
+
+        let config = NCConfiguration { text_protocol: true, ..Default::default() };
+        let server_process = server_process_with_config(config);
+
+        let (lines, results) = with_connections(&server_process, 1, |port| {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+            let mut send = |line: &str| {
+                stream.write_all(line.as_bytes()).unwrap();
+                stream.write_all(b"\n").unwrap();
+            };
+
+            // Encryption can't be switched on for the text protocol, the node is not registered
+            send(r#"{"Register": [["None"], null, null, null, [], {"heartbeat": 60, "compress": false, "allowed_codecs": [], "compression_min_size": 0, "encrypt": true, "type_check": false}]}"#);
+            let config_mismatch = lines.next().unwrap().unwrap();
+            assert!(config_mismatch.starts_with(r#"{"Rejected":{"reason":{"ConfigMismatch":[{"key":"encrypt","#), "{}", config_mismatch);
+
+            send(r#"{"Register": [["None"], null, null, null, [], null]}"#);
+            let initial_data = lines.next().unwrap().unwrap();
+            let node_id = initial_data.trim_start_matches(r#"{"InitialData":["#).split(',').next().unwrap().to_string();
+
+            // The heartbeat has no answer, the next line is the answer to NeedsData
+            send(&format!(r#"{{"HeartBeat": [{}, null]}}"#, node_id));
+            send(&format!(r#"{{"NeedsData": {}}}"#, node_id));
+            let needs_data = lines.next().unwrap().unwrap();
+            send("{not json}");
+            let error = lines.next().unwrap().unwrap();
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+            vec![initial_data, needs_data, error]
+        });
+
+        assert!(results[0].is_ok());
+        assert!(lines[0].starts_with(r#"{"InitialData":["#), "{}", lines[0]);
+        assert!(lines[1].starts_with(r#"{"JobStatus":{"Unfinished":[10,"#), "{}", lines[1]);
+        assert!(lines[2].starts_with(r#"{"Error":"Text protocol error: "#), "{}", lines[2]);
+        assert_eq!(server_process.node_list.lock().unwrap().len(), 1);
+
+        // Binary clients still work on the same server
+        let (statistics, _) = with_connections(&server_process, 1, |port| {
+            NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap().get_statistics().unwrap()
+        });
+        assert_eq!(statistics.num_of_nodes(), 1);
+    }
+
+    #[test]
+    fn test_text_protocol_config() {
+        // Without the debug-protocol feature text_protocol is not allowed at all
+        let mut starter = NCServerStarter::new(NCConfiguration { text_protocol: true, encrypt: true, key: KEY1.to_string(), ..Default::default() });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        assert!(matches!(starter.run(test_server(), listener), Err(NCError::TextProtocol(_))));
+    }
+
+    /// Processes chunk 10 and fails (retryable) for every other chunk of an offline batch.
+    struct OfflineNode;
+
+    impl NCNode for OfflineNode {
+        type InitialDataT = ();
+        type NewDataT = u32;
+        type ProcessedDataT = ();
+        type CustomMessageT = ();
+
+        fn process_data_from_server(&mut self, data: &u32) -> Result<NodeResult<()>, NCError> {
+            if *data == 10 {
+                Ok(NodeResult::Data(()))
+            } else {
+                Err(NCError::Job(NCJobError::new(7, "broken", true)))
+            }
+        }
+    }
+
+    #[test]
+    fn test_offline_batch() {
+        let chunks_path = std::env::temp_dir().join(format!("nc_offline_chunks_{}.bin", std::process::id()));
+        let results_path = std::env::temp_dir().join(format!("nc_offline_results_{}.bin", std::process::id()));
+        let server_process = Arc::new(server_process_for_test());
+        let offline_handle = NCOfflineHandle::default();
+
+        assert!(matches!(offline_handle.export_chunks(&chunks_path, 5), Err(NCError::Archive(_))));
+        offline_handle.set_job(Some(server_process.clone())).unwrap();
+
+        assert_eq!(offline_handle.export_chunks(&chunks_path, 5).unwrap(), 2);
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (0, 2, 0));
+        // Nothing left to hand out
+        assert_eq!(offline_handle.export_chunks(&results_path, 5).unwrap(), 0);
+        assert!(!results_path.exists());
+
+        let mut node_starter = NCNodeStarter::new(NCConfiguration::default());
+        assert_eq!(node_starter.process_archive(OfflineNode, &chunks_path, &results_path).unwrap(), 2);
+
+        // Chunk 0 goes into the result queue, chunk 1 has failed and is requeued
+        assert_eq!(offline_handle.import_results(&results_path).unwrap(), 1);
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (1, 1, 0));
+        assert_eq!(server_process.result_queue.pop().unwrap().unwrap().1.0, Some(0));
+
+        // The same results are not imported twice
+        assert_eq!(offline_handle.import_results(&results_path).unwrap(), 0);
+        assert_eq!(server_process.result_queue.stats().unwrap().len, 0);
+
+        // A chunk archive is not a result archive
+        assert!(matches!(offline_handle.import_results(&chunks_path), Err(NCError::Archive(_))));
+
+        fs::remove_file(&chunks_path).unwrap();
+        fs::remove_file(&results_path).unwrap();
+        offline_handle.set_job(None).unwrap();
+        assert!(matches!(offline_handle.import_results(&results_path), Err(NCError::Archive(_))));
+    }
+
+    #[test]
+    fn test_offline_batch_expired() {
+        let chunks_path = std::env::temp_dir().join(format!("nc_offline_expired_chunks_{}.bin", std::process::id()));
+        let results_path = std::env::temp_dir().join(format!("nc_offline_expired_results_{}.bin", std::process::id()));
+        let server_process = server_process_with_config(NCConfiguration { offline_batch_timeout_days: 0, ..Default::default() });
+
+        assert_eq!(server_process.export_chunks(&chunks_path, 1).unwrap(), 1);
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (1, 1, 0));
+
+        let mut node_starter = NCNodeStarter::new(NCConfiguration::default());
+        assert_eq!(node_starter.process_archive(OfflineNode, &chunks_path, &results_path).unwrap(), 1);
+
+        {
+            let mut nc_server = server_process.nc_server.lock().unwrap();
+            server_process.expire_offline_batches(&mut *nc_server).unwrap();
+            assert_eq!(nc_server.chunk_list.stats(), (2, 0, 0));
+        }
+
+        // The late result is dropped
+        assert_eq!(server_process.import_results(&results_path).unwrap(), 0);
+        assert_eq!(server_process.result_queue.stats().unwrap().len, 0);
+
+        fs::remove_file(&chunks_path).unwrap();
+        fs::remove_file(&results_path).unwrap();
+    }
+
+    #[cfg(feature = "ed25519")]
+    #[test]
+    fn test_signed_results() {
+        use crate::nc_keys::{self, NCKeypair, NCKeyRing, SIGNATURE_META_KEY, PUBLIC_KEY_META_KEY};
+
+        let key_file = |name: &str| {
+            let path = std::env::temp_dir().join(format!("nc_test_{}_{}.key", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let _ = std::fs::remove_file(nc_keys::public_key_path(&path));
+            let keypair = nc_keys::generate(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            std::fs::remove_file(nc_keys::public_key_path(&path)).unwrap();
+            keypair
+        };
+
+        let keypair = key_file("signed_results");
+        let unknown_keypair = key_file("signed_results_unknown");
+
+        let config = NCConfiguration { require_signed_results: true, ..Default::default() };
+        let mut server_process = server_process_with_config(config);
+        let mut key_ring = NCKeyRing::default();
+        key_ring.insert(keypair.public_key());
+        server_process.key_ring = Some(key_ring);
+
+        let register = |keypair: &NCKeypair| {
+            let (result, _) = with_connections(&server_process, 2, |port| {
+                let mut nc_client = NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap();
+                nc_client.register::<()>().unwrap();
+                nc_client.set_keypair(keypair.clone());
+                nc_client.register_key().map(|_| nc_client)
+            });
+            result
+        };
+
+        let request_data = |nc_client: &mut NCClient| {
+            let (message, _) = with_connections(&server_process, 1, |port| {
+                nc_client.set_server("127.0.0.1", port).unwrap();
+                nc_client.request_data::<u32, ()>().unwrap()
+            });
+            message
+        };
+
+        // Keys that are not in the key ring are refused
+        assert!(matches!(register(&unknown_keypair), Err(NCError::Unauthorized)));
+
+        let mut nc_client = register(&keypair).unwrap();
+        let node_id = nc_client.node_id();
+        let chunk_id = match request_data(&mut nc_client) {
+            NCServerMessage::JobStatus(NCJobStatus::Unfinished(10, info)) => info.chunk_id,
+            _ => panic!("expected a chunk"),
+        };
+
+        // A tampered payload and a node without a public key are rejected
+        let payload = bincode::serialize(&()).unwrap();
+        let signature = keypair.sign(chunk_id, &payload);
+        assert!(!server_process.verify_signature(node_id, chunk_id, &signature, &[1], &mut None).unwrap());
+        assert!(!server_process.verify_signature(NodeID::random(), chunk_id, &signature, &payload, &mut None).unwrap());
+
+        match request_data(&mut nc_client) {
+            NCServerMessage::ServerFailed(job_error) => assert_eq!(job_error.code, NCJobError::SIGNATURE_INVALID),
+            _ => panic!("expected the signature error"),
+        }
+
+        // A valid signature is stored with the result
+        let chunk_id = match request_data(&mut nc_client) {
+            NCServerMessage::JobStatus(NCJobStatus::Unfinished(_, info)) => info.chunk_id,
+            _ => panic!("expected a chunk"),
+        };
+
+        with_connections(&server_process, 1, |port| {
+            nc_client.set_server("127.0.0.1", port).unwrap();
+            nc_client.submit_signed_result(chunk_id, &(), None).unwrap();
+        });
+        assert_eq!(server_process.result_queue.stats().unwrap().len, 1);
+
+        // Unsigned results are rejected with require_signed_results
+        with_connections(&server_process, 1, |port| {
+            nc_client.set_server("127.0.0.1", port).unwrap();
+            nc_client.submit_result(()).unwrap();
+        });
+        assert_eq!(server_process.result_queue.stats().unwrap().len, 1);
+
+        let (result_node, (result_chunk, meta, _)) = server_process.result_queue.pop().unwrap().unwrap();
+        let meta = meta.unwrap();
+        assert_eq!(result_node, node_id);
+        assert_eq!(result_chunk, Some(chunk_id));
+        assert_eq!(meta[SIGNATURE_META_KEY], nc_keys::encode_hex(&keypair.sign(chunk_id, &payload)));
+        assert_eq!(meta[PUBLIC_KEY_META_KEY], keypair.public_key().to_string());
+    }
+
+    #[test]
+    fn test_time_budget() {
+        let config = NCConfiguration { max_job_duration: Some(Duration::from_millis(100)), job_drain_timeout: 3600, ..Default::default() };
+        let server_process = server_process_with_config(config);
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+        let mut buffer: Vec<u8> = Vec::new();
+
+        assert_eq!(assign_and_send_to(&server_process, node_id, &mut buffer).unwrap(), 0);
+
+        // The node gets the remaining time of the job with the chunk
+        let message: NCServerMessage<(), u32, ()> = server_process.nc_communicator.lock().unwrap().nc_receive_data(&mut buffer.as_slice()).unwrap();
+        match message {
+            NCServerMessage::JobStatus(NCJobStatus::Unfinished(_, chunk_info)) => {
+                assert!(chunk_info.job_time_remaining.unwrap() <= Duration::from_millis(100));
+            }
+            _ => panic!("Expected a JobStatus message"),
+        }
+
+        thread::sleep(Duration::from_millis(150));
+
+        // No new chunks after the cutoff, a node that asks for data is told to exit
+        assert_eq!(server_process.next_assignment(node_id, &NCAssignContext::default()).unwrap(), ChunkAssignment::Waiting);
+
+        let (message, _) = with_connections(&server_process, 2, |port| {
+            let mut nc_client = NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap();
+            nc_client.register::<()>().unwrap();
+            nc_client.request_data::<u32, ()>().unwrap()
+        });
+        assert!(matches!(message, NCServerMessage::JobStatus(NCJobStatus::Finished)));
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (1, 1, 0));
+
+        // The job waits for the chunk that is still being processed
+        server_process.check_time_budget().unwrap();
+        assert!(!server_process.is_job_done());
+
+        // A result after the cutoff is still accepted during the drain window
+        server_process.queue_result(node_id, None, (), None).unwrap();
+        assert_eq!(server_process.result_queue.stats().unwrap().len, 1);
+        server_process.check_time_budget().unwrap();
+        assert!(server_process.is_job_done());
+        assert_eq!(server_process.job_summary().unwrap().end_reason, NCJobEndReason::TimeBudgetExhausted);
+
+        // Without a drain timeout the job ends right away
+        let config = NCConfiguration { max_job_duration: Some(Duration::ZERO), job_drain_timeout: 0, ..Default::default() };
+        let server_process = server_process_with_config(config);
+        let node_id = server_process.node_list.lock().unwrap().register_new_node();
+        server_process.node_list.lock().unwrap().set_current_chunk(0, node_id);
+
+        server_process.check_time_budget().unwrap();
+        assert!(server_process.is_job_done());
+        assert_eq!(server_process.job_summary().unwrap().end_reason, NCJobEndReason::TimeBudgetExhausted);
+    }
+
+    #[test]
+    fn test_result_dictionary() {
+        let config = NCConfiguration { allowed_codecs: vec![NCCodec::Zstd(3), NCCodec::Lz4], compression_min_size: 0, ..Default::default() };
+        let server_process = server_process_with_config(config.clone());
+        let dictionary: Vec<u8> = (0..100).flat_map(|i| format!("{{\"node\":{},\"result\":[]}}", i).into_bytes()).collect();
+        let id = server_process.nc_communicator.lock().unwrap().add_dictionary(dictionary).unwrap();
+        server_process.result_dictionary.set(id).unwrap();
+
+        // Only the node with zstd gets the dictionary, it asks for it and compresses its result with it
+        let (mut nc_client, results) = with_connections(&server_process, 6, |port| {
+            let mut nc_client = NCClient::connect(&NCConfiguration { port, allowed_codecs: vec![NCCodec::Zstd(3)], ..config.clone() }).unwrap();
+            nc_client.register::<()>().unwrap();
+            let chunk_info = match nc_client.request_data::<u32, ()>().unwrap() {
+                NCServerMessage::JobStatus(NCJobStatus::Unfinished(_, chunk_info)) => chunk_info,
+                message => panic!("Expected a chunk, got: {:?}", message),
+            };
+            assert_eq!(chunk_info.result_dictionary, Some(id));
+            nc_client.use_dictionary(chunk_info.result_dictionary).unwrap();
+            nc_client.submit_result(()).unwrap();
+
+            let mut lz4_client = NCClient::connect(&NCConfiguration { port, allowed_codecs: vec![NCCodec::Lz4], ..config.clone() }).unwrap();
+            lz4_client.register::<()>().unwrap();
+            match lz4_client.request_data::<u32, ()>().unwrap() {
+                NCServerMessage::JobStatus(NCJobStatus::Unfinished(_, chunk_info)) => assert_eq!(chunk_info.result_dictionary, None),
+                message => panic!("Expected a chunk, got: {:?}", message),
+            }
+
+            nc_client
+        });
+        assert!(results.iter().all(|result| result.is_ok()), "{:?}", results);
+        assert_eq!(server_process.result_queue.stats().unwrap().len, 1);
+
+        // Another server doesn't know the dictionary: the message is sent again without it and the dictionary can't be fetched
+        let other_process = server_process_with_config(config.clone());
+        let (_, results) = with_connections(&other_process, 3, |port| {
+            nc_client.set_server("127.0.0.1", port).unwrap();
+            nc_client.use_dictionary(Some(id)).unwrap();
+            nc_client.get_statistics().unwrap();
+            assert!(matches!(nc_client.use_dictionary(Some(id ^ 1)), Err(NCError::UnknownDictionary(other)) if other == id ^ 1));
+        });
+        assert!(results.iter().all(|result| result.is_ok()), "{:?}", results);
+    }
+
+    #[test]
+    fn test_node_groups() {
+        let server_process = Arc::new(server_process_for_test());
+        let cpu_node = server_process.node_list.lock().unwrap().register_new_node();
+        server_process.set_node_tags(vec!["cpu".to_string()], cpu_node).unwrap();
+
+        let ((gpu_node1, gpu_node2, statistics), results) = with_connections(&server_process, 5, |port| {
+            let config = NCConfiguration { port, node_tags: vec!["gpu".to_string()], ..Default::default() };
+            let mut nc_client1 = NCClient::connect(&config).unwrap();
+            nc_client1.register::<()>().unwrap();
+            let mut nc_client2 = NCClient::connect(&config).unwrap();
+            nc_client2.register::<()>().unwrap();
+
+            // The second node also does cpu work now
+            nc_client2.set_tags(vec!["gpu".to_string(), "cpu".to_string()]).unwrap();
+            nc_client1.send_custom_to_group((), "gpu").unwrap();
+            (nc_client1.node_id(), nc_client2.node_id(), nc_client1.get_statistics().unwrap())
+        });
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(statistics.group_sizes(), &[("cpu".to_string(), 2), ("gpu".to_string(), 2)]);
+
+        let mut node_list = server_process.node_list.lock().unwrap();
+        assert_eq!(node_list.get_message(gpu_node1), Some(()));
+        assert_eq!(node_list.get_message(gpu_node2), Some(()));
+        assert_eq!(node_list.get_message(cpu_node), None);
+        assert_eq!(node_list.get_tags(gpu_node2), vec!["cpu".to_string(), "gpu".to_string()]);
+        drop(node_list);
+
+        // The handle only works while the job is running
+        let broadcast_handle: NCBroadcastHandle<()> = NCBroadcastHandle::default();
+        assert!(matches!(broadcast_handle.broadcast_to("cpu", ()), Err(NCError::ServerNotRunning)));
+        broadcast_handle.set_job(Some(server_process.clone())).unwrap();
+
+        assert_eq!(broadcast_handle.broadcast_to("cpu", ()).unwrap(), 2);
+        assert_eq!(broadcast_handle.broadcast_to("fpga", ()).unwrap(), 0);
+        assert_eq!(server_process.node_list.lock().unwrap().get_message(cpu_node), Some(()));
+        assert_eq!(server_process.node_list.lock().unwrap().get_message(gpu_node1), None);
+        assert_eq!(broadcast_handle.group_sizes().unwrap().len(), 2);
+
+        broadcast_handle.set_job(None).unwrap();
+        assert!(matches!(broadcast_handle.group_sizes(), Err(NCError::ServerNotRunning)));
+    }
+
+    #[test]
+    fn test_locality() {
+        let mut server_process = server_process_for_test();
+        server_process.nc_server.lock().unwrap().chunk_list.push(30);
+        let locality = LocalityMap::parse("0 shard-a\n1-2 shard-b").unwrap().with_fallback(Duration::from_millis(300));
+        server_process.locality = Some(Arc::new(locality));
+
+        let (statistics, results) = with_connections(&server_process, 8, |port| {
+            let connect = |tag: &str| {
+                let mut nc_client = NCClient::connect(&NCConfiguration { port, node_tags: vec![tag.to_string()], ..Default::default() }).unwrap();
+                nc_client.register::<()>().unwrap();
+                nc_client
+            };
+            let (mut node_a, mut node_b, mut node_c) = (connect("shard-a"), connect("shard-b"), connect("shard-c"));
+            let chunk = |message: NCServerMessage<(), u32, ()>| match message {
+                NCServerMessage::JobStatus(NCJobStatus::Unfinished(data, chunk_info)) => Some((chunk_info.chunk_id, data)),
+                NCServerMessage::JobStatus(NCJobStatus::Waiting) => None,
+                message => panic!("Expected a chunk, got: {:?}", message),
+            };
+
+            // The nodes with the shard get the chunks without the data, the node without a shard has to wait
+            assert_eq!(chunk(node_a.request_data().unwrap()), Some((0, 0)));
+            assert_eq!(chunk(node_c.request_data().unwrap()), None);
+            assert_eq!(chunk(node_b.request_data().unwrap()), Some((1, 0)));
+
+            // Nobody else has shard-b, after the fallback time the chunk goes to the other node together with its data
+            thread::sleep(Duration::from_millis(400));
+            assert_eq!(chunk(node_c.request_data().unwrap()), Some((2, 30)));
+            node_c.get_statistics().unwrap()
+        });
+        assert!(results.iter().all(|result| result.is_ok()), "{:?}", results);
+        assert_eq!((statistics.local_chunks(), statistics.locality_fallbacks()), (2, 1));
+
+        let job_summary = server_process.job_summary().unwrap();
+        assert_eq!((job_summary.local_chunks, job_summary.locality_fallbacks), (2, 1));
+        assert!(job_summary.to_string().contains("Locality: 2 chunks sent to a node with their shard, 1 fallbacks"));
+    }
+
+    #[test]
+    fn test_restore_nodes() {
+        let checkpoint_file = std::env::temp_dir().join(format!("nc_restore_nodes_{}.checkpoint", std::process::id()));
+        let node_id = NodeID::random();
+        let batch_node_id = NodeID::random();
+        let mut checkpoint = NCCheckpoint::new(&ChunkList::<u32>::new()).unwrap();
+        let contribution = NCNodeContribution { node_id, results: 2, result_bytes: 10, busy_time: Duration::from_secs(1) };
+        let batch = NCSavedOfflineBatch { batch_id: 9, node_id: batch_node_id, chunks: vec![1], age: Duration::ZERO };
+        checkpoint.set_server_state(vec![contribution.clone()], vec![batch]);
+        checkpoint.save(&checkpoint_file).unwrap();
+
+        let server_process = server_process_with_config(NCConfiguration { checkpoint_file: Some(checkpoint_file.clone()), ..Default::default() });
+        fs::remove_file(&checkpoint_file).unwrap();
+        assert!(!server_process.node_list.lock().unwrap().contains(node_id));
+        assert_eq!(server_process.offline_batches.lock().unwrap().len(), 1);
+
+        // The node keeps using its old id, it's registered again without a chunk
+        let (_, results) = with_connections(&server_process, 2, |port| {
+            let mut nc_client = NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap();
+            nc_client.set_node_id(node_id);
+            nc_client.heartbeat().unwrap();
+
+            // Unknown ids are not registered
+            nc_client.set_node_id(NodeID::random());
+            nc_client.heartbeat().unwrap();
+        });
+        assert!(results.iter().all(|result| result.is_ok()));
+
+        let node_list = server_process.node_list.lock().unwrap();
+        assert!(node_list.contains(node_id));
+        assert_eq!(node_list.len(), 1);
+        assert!(!node_list.holds_chunk(node_id));
+        drop(node_list);
+
+        assert!(server_process.restored_nodes.lock().unwrap().is_empty());
+        assert_eq!(server_process.job_summary().unwrap().nodes, vec![contribution.clone()]);
+        assert_eq!(server_process.checkpoint_nodes().unwrap(), vec![contribution]);
+    }
+
+    #[test]
+    fn test_rejoin() {
+        let server_process = server_process_for_test();
+
+        // The first process takes a chunk and is gone, the next one takes over the node id
+        let (node_id, _) = with_connections(&server_process, 3, |port| {
+            let mut nc_client = NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap();
+            nc_client.register::<()>().unwrap();
+            let node_id = nc_client.node_id();
+            assert!(matches!(nc_client.request_data::<u32, ()>().unwrap(), NCServerMessage::JobStatus(NCJobStatus::Unfinished(10, _))));
+
+            let mut nc_client = NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap();
+            nc_client.register_again::<()>(node_id, 1).unwrap();
+            assert_eq!(nc_client.node_id(), node_id);
+            node_id
+        });
+
+        // The chunk of the old process is free again and the new process doesn't hold it
+        assert_eq!(server_process.nc_server.lock().unwrap().chunk_list.stats(), (2, 0, 0));
+        let node_list = server_process.node_list.lock().unwrap();
+        assert!(!node_list.holds_chunk(node_id));
+        assert_eq!(node_list.incarnation(node_id), Some(1));
+        assert_eq!(node_list.len(), 1);
+        drop(node_list);
+
+        // An old incarnation (a copy of the file) gets a new node id, an unknown node id is taken as it is
+        let other = NodeID::random();
+        let (node_ids, _) = with_connections(&server_process, 2, |port| {
+            let mut nc_client = NCClient::connect(&NCConfiguration { port, ..Default::default() }).unwrap();
+            nc_client.register_again::<()>(node_id, 1).unwrap();
+            let copy = nc_client.node_id();
+            nc_client.register_again::<()>(other, 4).unwrap();
+            (copy, nc_client.node_id())
+        });
+        assert_ne!(node_ids.0, node_id);
+        assert_eq!(node_ids.1, other);
+        assert_eq!(server_process.node_list.lock().unwrap().incarnation(node_id), Some(1));
+        assert_eq!(server_process.node_list.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_check_node_settings() {
+        let server_process = server_process_for_test();
+        let peer: SocketAddr = "127.0.0.1:2020".parse().unwrap();
+
+        // Only differences with the severity Error are returned, the others are just logged
+        let node_config = NCConfiguration { heartbeat: 1, type_check: true, ..Default::default() }.shared_settings().to_config();
+        assert!(server_process.check_node_settings(&node_config, peer).is_empty());
+
+        let node_config = NCConfiguration { encrypt: true, ..Default::default() }.shared_settings().to_config();
+        let errors = server_process.check_node_settings(&node_config, peer);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].key, "encrypt");
+
+        let error = NCError::ConfigMismatch(errors);
+        assert_eq!(error.to_string(), "Settings of node and server don't match: encrypt: false (server) != true (node)");
+    }
+
+    #[test]
+    fn test_port_zero() {
+        let config = NCConfiguration { port: 0, delay_request_data: 1, heartbeat: 1, ..Default::default() };
+        let mut server_starter = NCServerStarter::new(config.clone());
+        let server_handle = server_starter.server_handle();
+        let progress_events = server_starter.progress_events();
+        assert_eq!(server_handle.local_addr(), None);
+
+        let server = RangeServer::new(0..100, 10, 0_u64, |sum: &mut u64, _, values: &[u64]| *sum += values.iter().sum::<u64>());
+        let sum = server.accumulator();
+        let server_thread = thread::spawn(move || server_starter.start(server));
+
+        let local_addr = server_handle.wait_local_addr(Duration::from_secs(10)).unwrap();
+        assert_ne!(local_addr.port(), 0);
+        assert_eq!(server_handle.local_addr(), Some(local_addr));
+        assert!(matches!(progress_events.recv().unwrap(), NCProgressEvent::Listening(addr) if addr == local_addr));
+
+        NCNodeStarter::new(NCConfiguration { port: local_addr.port(), ..config }).start(RangeNode::new(|i| i * 2)).unwrap();
+        assert_eq!(server_thread.join().unwrap().unwrap().chunks_sent, 10);
+        assert_eq!(*sum.lock().unwrap(), 9900);
+    }
+
+    /// Attaches a log and an artifact that is too big to every chunk.
+    struct ArtifactNode;
+
+    impl NCNode for ArtifactNode {
+        type InitialDataT = ();
+        type NewDataT = RangeBatch;
+        type ProcessedDataT = RangeResults<u64>;
+        type CustomMessageT = ();
+
+        fn process_data_from_server(&mut self, data: &RangeBatch) -> Result<NodeResult<RangeResults<u64>>, NCError> {
+            Ok(NodeResult::Data(RangeResults { chunk_id: data.chunk_id, values: data.range.clone().collect() }))
+        }
+
+        fn process_data_with_context(&mut self, data: &RangeBatch, context: &NCProcessContext) -> Result<NodeResult<RangeResults<u64>>, NCError> {
+            assert!(context.attach_artifact("range.log", format!("{:?}", data.range).into_bytes()));
+            assert!(!context.attach_artifact("big.bin", vec![0; 100]));
+            self.process_data_from_server(data)
+        }
+    }
+
+    #[test]
+    fn test_artifacts() {
+        let dir = std::env::temp_dir().join(format!("nc_server_artifacts_{}", std::process::id()));
+        let config = NCConfiguration { port: 0, delay_request_data: 1, heartbeat: 1, artifacts_dir: Some(dir.clone()), max_artifact_bytes: 50, ..Default::default() };
+        let mut server_starter = NCServerStarter::new(config.clone());
+        let server_handle = server_starter.server_handle();
+        let progress_events = server_starter.progress_events();
+        let server = RangeServer::new(0..40, 10, 0_u64, |sum: &mut u64, _, values: &[u64]| *sum += values.iter().sum::<u64>());
+        let server_thread = thread::spawn(move || server_starter.start(server));
+
+        let port = server_handle.wait_local_addr(Duration::from_secs(10)).unwrap().port();
+        NCNodeStarter::new(NCConfiguration { port, ..config }).start(ArtifactNode).unwrap();
+        server_thread.join().unwrap().unwrap();
+
+        let mut stored: Vec<(ChunkID, PathBuf)> = progress_events.try_iter().filter_map(|event| match event {
+            NCProgressEvent::ArtifactStored(_, chunk_id, path) => Some((chunk_id, path)),
+            _ => None,
+        }).collect();
+        stored.sort();
+        assert_eq!(stored.len(), 4);
+
+        for (chunk_id, path) in stored {
+            assert_eq!(path, dir.join(chunk_id.to_string()).join("range.log"));
+            assert!(fs::read_to_string(&path).unwrap().ends_with("0"));
+        }
+
+        assert!(!dir.join("0").join("big.bin").exists());
+        fs::remove_dir_all(dir).unwrap();
     }
 }
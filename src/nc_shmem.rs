@@ -0,0 +1,920 @@
+//! This module contains the shared memory transport (shmem feature) for a server and nodes on the same machine, for example when every
+//! node is its own process to isolate native code that may crash. Over the loopback interface every payload is copied twice through
+//! the kernel, here it's copied once into a ring buffer and once out of it. The framing (see the nc_communicator module) is the same,
+//! only the byte channel changes, see the [`nc_transport`](crate::nc_transport) module.
+//!
+//! Set transport in the NCConfiguration to NCTransportKind::SharedMemory with the same directory for the server and the nodes.
+//! The directory should be on a memory file system like `/dev/shm`, otherwise the operating system writes the segments to the disk
+//! from time to time. The server also listens on its port (for the heartbeat and for nodes that use tcp).
+//!
+//! The server locks the file `server.lock` in the directory while it's running, its first four bytes count the connections that the
+//! clients have offered. Every connection is a segment file with two ring buffers of RING_BYTES bytes (one for each direction):
+//! 1. The client creates `<pid>-<random>.tmp`, locks it, maps it and renames it to `.seg`. Then it wakes up the server.
+//! 2. The server renames the `.seg` file to `.acc` (so that it's taken only once) and maps it too.
+//! 3. A side that waits for data (or for space in the ring buffer) sleeps on a futex in the segment (Linux, other systems sleep
+//!    for a moment and look again) and is woken up by the other side.
+//!
+//! The operating system releases the locks when a process exits, also after a crash. A side that waits looks at the lock of the other
+//! side every 100 ms: if it's not held anymore the other side is gone and the read or write returns an io::ErrorKind::ConnectionReset error.
+//! A server that starts removes the segments of a server or a client that has crashed: every `.acc` file (the connections of the
+//! previous server, their clients get an end of file) and every `.tmp` or `.seg` file that is not locked.
+//! Shared memory connections have the peer address 127.0.0.1:0.
+
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use memmap2::MmapMut;
+use fs2::FileExt;
+
+use crate::nc_error::NCError;
+use crate::nc_transport::{NCStream, NCTransport};
+
+/// The size of each of the two ring buffers of a connection.
+pub(crate) const RING_BYTES: usize = 4 * 1024 * 1024;
+
+/// The header of a segment with the magic bytes, the capacity of the ring buffers and their control words, the data follows after it.
+const HEADER_BYTES: usize = 4096;
+
+/// The first eight bytes of every segment.
+const MAGIC: &[u8; 8] = b"NCSHMEM1";
+
+/// The offset of the capacity of the ring buffers (u64) in the header.
+const CAPACITY_OFFSET: usize = 8;
+
+/// The offsets of the control words of the two ring buffers in the header.
+const RING_CONTROL: [usize; 2] = [64, 256];
+
+/// The offsets of the control words within RING_CONTROL: the number of bytes that have been written (head) and read (tail) so far,
+/// each on its own cache line, the futex that is incremented for every change, the number of waiting threads and the flags.
+const HEAD: usize = 0;
+const TAIL: usize = 64;
+const SIGNAL: usize = 128;
+const WAITERS: usize = 132;
+const FLAGS: usize = 136;
+
+/// The writer has closed the ring buffer, the reader gets an end of file after the remaining data.
+const WRITER_CLOSED: u32 = 1;
+/// The reader has closed the ring buffer, the writer gets an io::ErrorKind::BrokenPipe error.
+const READER_CLOSED: u32 = 2;
+
+/// The ring buffer from the client to the server.
+const TO_SERVER: usize = 0;
+/// The ring buffer from the server to the client.
+const TO_CLIENT: usize = 1;
+
+/// The lock file of the server, see the module documentation.
+const SERVER_LOCK: &str = "server.lock";
+
+/// A side that waits looks at the lock of the other side this often.
+const LIVENESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A mapped file, all words that both sides use are accessed with atomics.
+struct Mapping {
+    /// Keeps the mapping alive.
+    _map: MmapMut,
+    /// The start of the mapping.
+    base: *mut u8,
+    /// The length of the mapping.
+    len: usize,
+}
+
+// Safety: the pointer points into the mapping, which lives as long as the Mapping. The control words are only accessed with atomics
+// and the data of a ring buffer only between the positions that these atomics have published, see Ring.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Maps the whole file.
+    fn new(file: &File) -> io::Result<Self> {
+        // Safety: the length of the file is only set before it's mapped. Like with every mapped file a process that truncates it
+        // anyway makes the access fail with SIGBUS, only processes of this crate use the files in the directory.
+        let mut map = unsafe { MmapMut::map_mut(file)? };
+        let base = map.as_mut_ptr();
+        let len = map.len();
+
+        Ok(Mapping { _map: map, base, len })
+    }
+
+    /// The u64 at the given offset.
+    fn u64_at(&self, offset: usize) -> &AtomicU64 {
+        assert!(offset.is_multiple_of(8) && offset + 8 <= self.len, "offset {} is outside of the mapping", offset);
+        // Safety: the offset is within the mapping and aligned, since the mapping starts at a page. AtomicU64 has the layout of u64.
+        unsafe { &*(self.base.add(offset) as *const AtomicU64) }
+    }
+
+    /// The u32 at the given offset.
+    fn u32_at(&self, offset: usize) -> &AtomicU32 {
+        assert!(offset.is_multiple_of(4) && offset + 4 <= self.len, "offset {} is outside of the mapping", offset);
+        // Safety: see u64_at().
+        unsafe { &*(self.base.add(offset) as *const AtomicU32) }
+    }
+}
+
+/// One direction of a connection: a ring buffer with a single writer and a single reader.
+/// head and tail count all the bytes that have been written and read, the writer only writes between head and tail + capacity
+/// and the reader only reads between tail and head.
+struct Ring<'a> {
+    mapping: &'a Mapping,
+    /// The offset of the control words.
+    control: usize,
+    /// The offset of the data.
+    data: usize,
+    capacity: u64,
+}
+
+impl Ring<'_> {
+    fn head(&self) -> &AtomicU64 {
+        self.mapping.u64_at(self.control + HEAD)
+    }
+
+    fn tail(&self) -> &AtomicU64 {
+        self.mapping.u64_at(self.control + TAIL)
+    }
+
+    fn signal(&self) -> &AtomicU32 {
+        self.mapping.u32_at(self.control + SIGNAL)
+    }
+
+    fn waiters(&self) -> &AtomicU32 {
+        self.mapping.u32_at(self.control + WAITERS)
+    }
+
+    fn flags(&self) -> &AtomicU32 {
+        self.mapping.u32_at(self.control + FLAGS)
+    }
+
+    /// Returns true if one of the flags is set.
+    fn is_closed(&self, flags: u32) -> bool {
+        self.flags().load(Ordering::Acquire) & flags != 0
+    }
+
+    /// Sets the flag and wakes up the other side.
+    fn close(&self, flag: u32) {
+        self.flags().fetch_or(flag, Ordering::AcqRel);
+        self.notify();
+    }
+
+    /// Tells the other side that head, tail or the flags have changed. The futex is only woken up if a thread waits for it.
+    fn notify(&self) {
+        self.signal().fetch_add(1, Ordering::SeqCst);
+
+        if self.waiters().load(Ordering::SeqCst) > 0 {
+            futex::wake(self.signal());
+        }
+    }
+
+    /// The bytes between tail and head, a broken segment can't make it larger than the capacity.
+    fn used(&self, head: u64, tail: u64) -> u64 {
+        head.wrapping_sub(tail).min(self.capacity)
+    }
+
+    /// Copies as many bytes as there is space for into the ring buffer and returns their number.
+    fn push(&self, bytes: &[u8]) -> usize {
+        let head = self.head().load(Ordering::Relaxed);
+        let tail = self.tail().load(Ordering::Acquire);
+        let count = bytes.len().min((self.capacity - self.used(head, tail)) as usize);
+
+        if count > 0 {
+            self.copy(head, bytes[..count].as_ptr(), count, true);
+            self.head().store(head.wrapping_add(count as u64), Ordering::Release);
+            self.notify();
+        }
+
+        count
+    }
+
+    /// Copies as many bytes as there are (at most the length of the buffer) out of the ring buffer and returns their number.
+    /// The bytes are only removed if consume is true.
+    fn pop(&self, buffer: &mut [u8], consume: bool) -> usize {
+        let tail = self.tail().load(Ordering::Relaxed);
+        let head = self.head().load(Ordering::Acquire);
+        let count = buffer.len().min(self.used(head, tail) as usize);
+
+        if count > 0 {
+            self.copy(tail, buffer.as_mut_ptr(), count, false);
+
+            if consume {
+                self.tail().store(tail.wrapping_add(count as u64), Ordering::Release);
+                self.notify();
+            }
+        }
+
+        count
+    }
+
+    /// Copies count bytes from bytes into the ring buffer at the given position (into is true) or from there into bytes.
+    fn copy(&self, position: u64, bytes: *const u8, count: usize, into: bool) {
+        let start = (position % self.capacity) as usize;
+        let first = count.min(self.capacity as usize - start);
+
+        for (offset, bytes, count) in [(start, bytes, first), (0, bytes.wrapping_add(first), count - first)] {
+            // Safety: count is at most the capacity, so both parts are within the data of this ring buffer (the length of the mapping
+            // has been checked) and within the buffer of the caller. The other side doesn't touch these bytes until head or tail is published.
+            unsafe {
+                let ring = self.mapping.base.add(self.data + offset);
+
+                if into {
+                    ptr::copy_nonoverlapping(bytes, ring, count);
+                } else {
+                    ptr::copy_nonoverlapping(ring, bytes as *mut u8, count);
+                }
+            }
+        }
+    }
+}
+
+/// Counts a waiting thread for Ring::notify() as long as it exists.
+struct Waiting<'a>(&'a AtomicU32);
+
+impl<'a> Waiting<'a> {
+    fn new(waiters: &'a AtomicU32) -> Self {
+        waiters.fetch_add(1, Ordering::SeqCst);
+        Waiting(waiters)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Which side of the connection a segment belongs to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Client,
+    Server,
+}
+
+/// The segment of one connection, shared by all the clones of a NCShmemStream.
+struct Segment {
+    mapping: Mapping,
+    /// The capacity of each ring buffer.
+    capacity: u64,
+    side: Side,
+    /// The client holds the lock on this file as long as it's running, the server looks at the lock.
+    file: File,
+    /// The lock file of the server, only for the client.
+    server_lock: Option<File>,
+    /// This file is removed when the connection is closed: the `.acc` file for the server and the `.seg` file for the client
+    /// (if the server hasn't taken it yet).
+    path: PathBuf,
+    /// See NCTransport::set_read_timeout().
+    read_timeout: Mutex<Option<Duration>>,
+    /// Only one thread reads at a time, also with clones of the stream.
+    reading: Mutex<()>,
+    /// Only one thread writes at a time, also with clones of the stream.
+    writing: Mutex<()>,
+}
+
+impl Segment {
+    /// Creates a segment around the mapped file of the given side.
+    fn new(mapping: Mapping, capacity: u64, side: Side, file: File, path: PathBuf) -> Self {
+        Segment {
+            mapping,
+            capacity,
+            side,
+            file,
+            server_lock: None,
+            path,
+            read_timeout: Mutex::new(None),
+            reading: Mutex::new(()),
+            writing: Mutex::new(()),
+        }
+    }
+
+    /// Opens the segment that a client has offered, for the server.
+    fn open(path: &Path) -> io::Result<Self> {
+        let invalid = |problem: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), problem));
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len();
+
+        if len < HEADER_BYTES as u64 {
+            return Err(invalid("the segment is too short"))
+        }
+
+        let mapping = Mapping::new(&file)?;
+
+        if mapping.u64_at(0).load(Ordering::Acquire) != u64::from_le_bytes(*MAGIC) {
+            return Err(invalid("the segment has the wrong magic bytes"))
+        }
+
+        let capacity = mapping.u64_at(CAPACITY_OFFSET).load(Ordering::Relaxed);
+
+        if capacity == 0 || capacity.checked_mul(2).and_then(|bytes| bytes.checked_add(HEADER_BYTES as u64)) != Some(len) {
+            return Err(invalid("the capacity doesn't match the length of the segment"))
+        }
+
+        Ok(Segment::new(mapping, capacity, Side::Server, file, path.to_path_buf()))
+    }
+
+    fn ring(&self, index: usize) -> Ring<'_> {
+        Ring { mapping: &self.mapping, control: RING_CONTROL[index], data: HEADER_BYTES + index * self.capacity as usize, capacity: self.capacity }
+    }
+
+    /// The ring buffer that this side reads from.
+    fn incoming(&self) -> Ring<'_> {
+        self.ring(if self.side == Side::Server { TO_SERVER } else { TO_CLIENT })
+    }
+
+    /// The ring buffer that this side writes to.
+    fn outgoing(&self) -> Ring<'_> {
+        self.ring(if self.side == Side::Server { TO_CLIENT } else { TO_SERVER })
+    }
+
+    /// Returns false if the other side doesn't hold its lock anymore, see the module documentation.
+    fn peer_alive(&self) -> bool {
+        let lock = match self.side {
+            Side::Server => &self.file,
+            Side::Client => match &self.server_lock {
+                Some(server_lock) => server_lock,
+                None => return true,
+            },
+        };
+
+        is_locked(lock)
+    }
+
+    /// Waits until the signal of the ring buffer is not seen anymore, for at most LIVENESS_INTERVAL.
+    ///
+    /// # Errors
+    ///
+    /// Returns an io::ErrorKind::WouldBlock error if the deadline has passed (like a tcp stream with a timeout) and
+    /// an io::ErrorKind::ConnectionReset error if the other side is gone.
+    fn wait(&self, ring: &Ring, seen: u32, deadline: Option<Instant>, checked: &mut Instant) -> io::Result<()> {
+        let now = Instant::now();
+
+        if deadline.is_some_and(|deadline| now >= deadline) {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "the shared memory connection has timed out"))
+        }
+
+        if now.duration_since(*checked) >= LIVENESS_INTERVAL {
+            if !self.peer_alive() {
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset, "the other side of the shared memory connection is gone"))
+            }
+
+            *checked = now;
+        }
+
+        let timeout = deadline.map_or(LIVENESS_INTERVAL, |deadline| (deadline - now).min(LIVENESS_INTERVAL));
+        futex::wait(ring.signal(), seen, timeout);
+        Ok(())
+    }
+
+    /// Reads from the incoming ring buffer, waits until there is at least one byte or the other side has closed it.
+    fn read(&self, buffer: &mut [u8], consume: bool) -> io::Result<usize> {
+        if buffer.is_empty() {
+            return Ok(0)
+        }
+
+        let _reading = lock(&self.reading)?;
+        let ring = self.incoming();
+        let deadline = timeout_of(&self.read_timeout)?.map(|timeout| Instant::now() + timeout);
+        let mut checked = Instant::now();
+        let mut waiting = None;
+
+        loop {
+            let seen = ring.signal().load(Ordering::SeqCst);
+            // The flags are loaded first: the data that has been written before the ring buffer was closed is read in any case.
+            let closed = ring.is_closed(WRITER_CLOSED | READER_CLOSED);
+            let count = ring.pop(buffer, consume);
+
+            if count > 0 || closed {
+                return Ok(count)
+            }
+
+            if waiting.is_none() {
+                // Again, now that the other side knows that it has to wake this thread up
+                waiting = Some(Waiting::new(ring.waiters()));
+                continue
+            }
+
+            self.wait(&ring, seen, deadline, &mut checked)?;
+        }
+    }
+
+    /// Writes to the outgoing ring buffer, waits until there is space for at least one byte.
+    fn write(&self, bytes: &[u8]) -> io::Result<usize> {
+        if bytes.is_empty() {
+            return Ok(0)
+        }
+
+        let _writing = lock(&self.writing)?;
+        let ring = self.outgoing();
+        let mut checked = Instant::now();
+        let mut waiting = None;
+
+        loop {
+            let seen = ring.signal().load(Ordering::SeqCst);
+
+            if ring.is_closed(WRITER_CLOSED | READER_CLOSED) {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "the shared memory connection has been closed"))
+            }
+
+            let count = ring.push(bytes);
+
+            if count > 0 {
+                return Ok(count)
+            }
+
+            if waiting.is_none() {
+                waiting = Some(Waiting::new(ring.waiters()));
+                continue
+            }
+
+            self.wait(&ring, seen, None, &mut checked)?;
+        }
+    }
+
+    /// Closes both directions, see NCTransport::shutdown().
+    fn shutdown(&self) {
+        self.outgoing().close(WRITER_CLOSED);
+        self.incoming().close(READER_CLOSED);
+    }
+}
+
+impl Drop for Segment {
+    /// The other side gets an end of file, the file is removed (it stays mapped until the other side has closed it too).
+    fn drop(&mut self) {
+        self.shutdown();
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Returns true if another process (or another open file) holds the lock on the file.
+fn is_locked(file: &File) -> bool {
+    match file.try_lock_exclusive() {
+        Ok(()) => {
+            let _ = FileExt::unlock(file);
+            false
+        }
+        // Held by another process, or it can't be told: then the other side is treated as running
+        Err(_) => true,
+    }
+}
+
+/// Locks one direction of a segment.
+fn lock(mutex: &Mutex<()>) -> io::Result<MutexGuard<'_, ()>> {
+    mutex.lock().map_err(|_| io::Error::other("the shared memory lock is poisoned"))
+}
+
+/// Returns the current timeout.
+fn timeout_of(timeout: &Mutex<Option<Duration>>) -> io::Result<Option<Duration>> {
+    timeout.lock().map(|timeout| *timeout).map_err(|_| io::Error::other("the shared memory lock is poisoned"))
+}
+
+/// Sets the timeout, zero is not allowed like for a tcp stream.
+fn set_timeout(timeout: &Mutex<Option<Duration>>, value: Option<Duration>) -> io::Result<()> {
+    if value == Some(Duration::ZERO) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the timeout must not be zero"))
+    }
+
+    *timeout.lock().map_err(|_| io::Error::other("the shared memory lock is poisoned"))? = value;
+    Ok(())
+}
+
+/// A shared memory connection between the server and a node (or a client), see the module documentation.
+pub(crate) struct NCShmemStream {
+    segment: Arc<Segment>,
+}
+
+impl NCShmemStream {
+    /// Offers a new connection to the server that listens in the given directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an io::ErrorKind::ConnectionRefused error if no server listens in the directory, and the errors from creating the segment.
+    pub(crate) fn connect(dir: &Path) -> io::Result<Self> {
+        debug!("NCShmemStream::connect()");
+
+        let refused = || io::Error::new(io::ErrorKind::ConnectionRefused, format!("no server listens in {}", dir.display()));
+
+        let server_lock = match OpenOptions::new().read(true).write(true).open(dir.join(SERVER_LOCK)) {
+            Ok(server_lock) => server_lock,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(refused()),
+            Err(e) => return Err(e),
+        };
+
+        if !is_locked(&server_lock) {
+            return Err(refused())
+        }
+
+        let name = format!("{}-{:016x}", std::process::id(), rand::random::<u64>());
+        let creating = dir.join(format!("{}.tmp", name));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&creating)?;
+        let mapping = file.try_lock_exclusive()
+            .and_then(|()| file.set_len((HEADER_BYTES + 2 * RING_BYTES) as u64))
+            .and_then(|()| Mapping::new(&file));
+
+        let mapping = match mapping {
+            Ok(mapping) => mapping,
+            Err(e) => {
+                let _ = fs::remove_file(&creating);
+                return Err(e)
+            }
+        };
+
+        mapping.u64_at(CAPACITY_OFFSET).store(RING_BYTES as u64, Ordering::Relaxed);
+        mapping.u64_at(0).store(u64::from_le_bytes(*MAGIC), Ordering::Release);
+
+        // From here on the segment removes its file when it's dropped
+        let mut segment = Segment::new(mapping, RING_BYTES as u64, Side::Client, file, creating);
+        let offered = segment.path.with_extension("seg");
+        fs::rename(&segment.path, &offered)?;
+        segment.path = offered;
+
+        let wake_up = Mapping::new(&server_lock)?;
+        wake_up.u32_at(0).fetch_add(1, Ordering::SeqCst);
+        futex::wake(wake_up.u32_at(0));
+        segment.server_lock = Some(server_lock);
+
+        Ok(NCShmemStream { segment: Arc::new(segment) })
+    }
+}
+
+impl Read for NCShmemStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.segment.read(buffer, true)
+    }
+}
+
+impl Write for NCShmemStream {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.segment.write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl NCTransport for NCShmemStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(NCShmemListener::peer_addr())
+    }
+
+    fn peek(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.segment.read(buffer, false)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        set_timeout(&self.segment.read_timeout, timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.segment.shutdown();
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<NCStream> {
+        Ok(Box::new(NCShmemStream { segment: self.segment.clone() }))
+    }
+}
+
+/// The server side of the shared memory transport, it takes the connections that the clients offer in the directory.
+pub(crate) struct NCShmemListener {
+    dir: PathBuf,
+    /// The locked `server.lock` file, the lock is released when the listener is dropped.
+    _lock: File,
+    /// The mapped `server.lock` file, its first u32 counts the offered connections.
+    mapping: Mapping,
+    /// Set by close().
+    closed: AtomicBool,
+}
+
+impl NCShmemListener {
+    /// Listens in the given directory, it's created if it doesn't exist. The segments of a server or a client that has crashed are removed.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`NCError::SharedMemory`] error if another server listens in the directory, otherwise the IO errors.
+    pub(crate) fn bind(dir: &Path) -> Result<Self, NCError> {
+        debug!("NCShmemListener::bind()");
+
+        fs::create_dir_all(dir)?;
+        let lock = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(dir.join(SERVER_LOCK))?;
+        // Before the lock, so a client never maps an empty file
+        lock.set_len(HEADER_BYTES as u64)?;
+
+        if let Err(e) = lock.try_lock_exclusive() {
+            return if e.kind() == fs2::lock_contended_error().kind() {
+                Err(NCError::SharedMemory(format!("another server listens in {}", dir.display())))
+            } else {
+                Err(e.into())
+            }
+        }
+
+        let mapping = Mapping::new(&lock)?;
+        let removed = remove_stale_segments(dir)?;
+
+        if removed > 0 {
+            info!("Removed {} stale shared memory segments in {}", removed, dir.display());
+        }
+
+        Ok(NCShmemListener { dir: dir.to_path_buf(), _lock: lock, mapping, closed: AtomicBool::new(false) })
+    }
+
+    /// The peer address of every shared memory connection.
+    pub(crate) fn peer_addr() -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)
+    }
+
+    /// Waits for the next connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns an io::ErrorKind::NotConnected error after close() and the errors from reading the directory.
+    pub(crate) fn accept(&self) -> io::Result<NCShmemStream> {
+        let offered = self.mapping.u32_at(0);
+
+        loop {
+            let seen = offered.load(Ordering::SeqCst);
+
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(io::Error::new(io::ErrorKind::NotConnected, "the shared memory listener has been closed"))
+            }
+
+            if let Some(stream) = self.take_offered()? {
+                return Ok(stream)
+            }
+
+            // The timeout also covers a client that has renamed its segment but not woken up the server yet
+            futex::wait(offered, seen, LIVENESS_INTERVAL);
+        }
+    }
+
+    /// Takes the first segment that a client has offered.
+    fn take_offered(&self) -> io::Result<Option<NCShmemStream>> {
+        for entry in fs::read_dir(&self.dir)? {
+            let offered = entry?.path();
+
+            if offered.extension() != Some(OsStr::new("seg")) {
+                continue
+            }
+
+            let accepted = offered.with_extension("acc");
+
+            // The client has given up in the meantime
+            if fs::rename(&offered, &accepted).is_err() {
+                continue
+            }
+
+            match Segment::open(&accepted) {
+                Ok(segment) => return Ok(Some(NCShmemStream { segment: Arc::new(segment) })),
+                Err(e) => {
+                    warn!("Invalid shared memory segment: {}", e);
+                    let _ = fs::remove_file(&accepted);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Lets accept() return with an error, also in another thread.
+    pub(crate) fn close(&self) {
+        let offered = self.mapping.u32_at(0);
+
+        self.closed.store(true, Ordering::SeqCst);
+        offered.fetch_add(1, Ordering::SeqCst);
+        futex::wake(offered);
+    }
+}
+
+impl Drop for NCShmemListener {
+    /// The clients that look at the lock of the removed file see that the server is gone.
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.dir.join(SERVER_LOCK));
+    }
+}
+
+/// Removes the segments of a server or a client that has crashed, see the module documentation. Returns the number of removed segments.
+fn remove_stale_segments(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        match path.extension().and_then(OsStr::to_str) {
+            Some("acc") => {
+                // Dropping the segment tells the client that the connection is closed and removes the file
+                if let Err(e) = Segment::open(&path) {
+                    debug!("Could not open stale segment: {}", e);
+                    let _ = fs::remove_file(&path);
+                }
+
+                removed += 1;
+            }
+            Some("seg") | Some("tmp") => {
+                let stale = OpenOptions::new().read(true).write(true).open(&path).map(|file| !is_locked(&file)).unwrap_or(false);
+
+                if stale && fs::remove_file(&path).is_ok() {
+                    removed += 1;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Waiting for a change of a word in the shared memory with a futex.
+#[cfg(target_os = "linux")]
+mod futex {
+    use std::sync::atomic::AtomicU32;
+    use std::time::Duration;
+
+    /// Waits until the word is woken up, is not the expected value anymore or the timeout has passed.
+    pub(super) fn wait(word: &AtomicU32, expected: u32, timeout: Duration) {
+        let timeout = libc::timespec { tv_sec: timeout.as_secs() as libc::time_t, tv_nsec: timeout.subsec_nanos() as _ };
+
+        // Safety: the word and the timeout are valid for the duration of the call. Without FUTEX_PRIVATE_FLAG the futex works
+        // across all the processes that have mapped the same file. Spurious wake ups are fine, the caller looks again.
+        unsafe {
+            libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAIT, expected, &timeout as *const libc::timespec);
+        }
+    }
+
+    /// Wakes up all the threads that wait for the word.
+    pub(super) fn wake(word: &AtomicU32) {
+        // Safety: see wait().
+        unsafe {
+            libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAKE, i32::MAX);
+        }
+    }
+}
+
+/// Without futexes the waiting side sleeps for a moment and looks again.
+#[cfg(not(target_os = "linux"))]
+mod futex {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Sleeps for a moment (at most the timeout) if the word still has the expected value.
+    pub(super) fn wait(word: &AtomicU32, expected: u32, timeout: Duration) {
+        if word.load(Ordering::SeqCst) == expected {
+            thread::sleep(timeout.min(Duration::from_micros(50)));
+        }
+    }
+
+    /// The other side looks again by itself.
+    pub(super) fn wake(_word: &AtomicU32) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use crate::nc_config::{NCConfiguration, NCTransportKind};
+    use crate::nc_local;
+    use crate::nc_range::{RangeServer, RangeNode};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nc_shmem_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut files: Vec<String> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = test_dir("round_trip");
+        assert_eq!(NCShmemStream::connect(&dir).err().map(|e| e.kind()), Some(io::ErrorKind::ConnectionRefused));
+
+        let listener = NCShmemListener::bind(&dir).unwrap();
+        assert!(matches!(NCShmemListener::bind(&dir), Err(NCError::SharedMemory(_))));
+        let mut client = NCShmemStream::connect(&dir).unwrap();
+        let mut server = listener.accept().unwrap();
+        assert_eq!(files(&dir).len(), 2);
+
+        // More than fits into the ring buffer, in both directions at the same time
+        let data: Vec<u8> = (0..3 * RING_BYTES + 12345).map(|i| (i % 251) as u8).collect();
+        let expected = data.clone();
+        let writer = thread::spawn(move || client.write_all(&data).map(|()| client));
+        let mut received = vec![0; expected.len()];
+        let mut peeked = [0; 4];
+        assert_eq!(server.peek(&mut peeked).unwrap(), 4);
+        assert_eq!(peeked, expected[..4]);
+        server.read_exact(&mut received).unwrap();
+        assert!(received == expected);
+        let mut client = writer.join().unwrap().unwrap();
+
+        server.write_all(b"answer").unwrap();
+        let mut answer = [0; 6];
+        client.read_exact(&mut answer).unwrap();
+        assert_eq!(&answer, b"answer");
+
+        // Nothing arrives within the timeout
+        client.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        assert_eq!(client.read(&mut answer).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(client.set_read_timeout(Some(Duration::ZERO)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // The server closes its side, the clone of the client still reads the end of file
+        let mut clone = client.try_clone().unwrap();
+        server.write_all(b"bye").unwrap();
+        drop(server);
+        let mut rest = Vec::new();
+        clone.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"bye");
+        assert_eq!(client.write(b"more").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+
+        drop((client, clone));
+        drop(listener);
+        assert!(files(&dir).is_empty());
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_peer_gone() {
+        let dir = test_dir("peer_gone");
+        let listener = NCShmemListener::bind(&dir).unwrap();
+        let client = NCShmemStream::connect(&dir).unwrap();
+        let mut server = listener.accept().unwrap();
+
+        // Like a crash of the client: the lock is released, but the connection is not closed
+        FileExt::unlock(&client.segment.file).unwrap();
+        let start = Instant::now();
+        assert_eq!(server.read(&mut [0; 8]).unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // The server is gone
+        drop(server);
+        let mut client = NCShmemStream::connect(&dir).unwrap();
+        let _server = listener.accept().unwrap();
+        drop(listener);
+        assert_eq!(client.read(&mut [0; 8]).unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stale_segments() {
+        let dir = test_dir("stale");
+        let listener = NCShmemListener::bind(&dir).unwrap();
+        let mut client = NCShmemStream::connect(&dir).unwrap();
+        let server = listener.accept().unwrap();
+        let waiting = NCShmemStream::connect(&dir).unwrap();
+
+        // The server crashes: its lock is released, but nothing is closed or removed
+        FileExt::unlock(&listener._lock).unwrap();
+        std::mem::forget(listener);
+        std::mem::forget(server);
+        fs::write(dir.join("1-dead.tmp"), b"").unwrap();
+        fs::write(dir.join("2-dead.seg"), b"").unwrap();
+        assert_eq!(files(&dir).len(), 5);
+
+        // The new server removes the accepted connection of the old one and the segments of the dead clients,
+        // the segment of the client that is still waiting is taken over
+        let listener = NCShmemListener::bind(&dir).unwrap();
+        let mut files = files(&dir);
+        files.retain(|file| file != SERVER_LOCK);
+        assert_eq!(files, vec![waiting.segment.path.file_name().unwrap().to_string_lossy().into_owned()]);
+        assert_eq!(client.read(&mut [0; 8]).unwrap(), 0);
+
+        let mut server = listener.accept().unwrap();
+        let mut waiting = waiting;
+        waiting.write_all(b"ping").unwrap();
+        let mut ping = [0; 4];
+        server.read_exact(&mut ping).unwrap();
+        assert_eq!(&ping, b"ping");
+
+        drop((server, waiting, client, listener));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_local_job() {
+        let dir = test_dir("local_job");
+        let config = NCConfiguration { port: 0, delay_request_data: 1, heartbeat: 1, transport: NCTransportKind::SharedMemory { path: dir.clone() },
+            ..Default::default() };
+        let server = RangeServer::new(0..200, 7, Vec::new(), |values: &mut Vec<u64>, _range, results: &[u64]| values.extend_from_slice(results));
+        let values = server.accumulator();
+
+        let (_, job_summary) = nc_local::run(&config, server, |_| RangeNode::new(|i| i * i), 3).unwrap();
+        let mut values = values.lock().unwrap().clone();
+        values.sort_unstable();
+        assert_eq!(values, (0..200).map(|i| i * i).collect::<Vec<u64>>());
+        assert_eq!(job_summary.done_chunks, Some(29));
+        assert!(files(&dir).is_empty());
+
+        fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,620 @@
+//! This module contains a ready made node that runs an external program for every chunk (subprocess feature).
+//! This is meant for compute kernels that are not part of the node binary, for example programs that users have submitted:
+//! the node doesn't have to trust them, a kernel that crashes, hangs or eats up all the memory only fails its own chunk.
+//!
+//! The [`SubprocessRunner`] starts the program, writes the data to its standard input and closes it, the result is everything that
+//! the program writes to its standard output. The standard error is attached to the chunk as an artifact (see the
+//! [`nc_artifacts`](crate::nc_artifacts) module) and the last line of it is part of the error message if the program fails.
+//! The program runs in the scratch folder of the chunk and is killed if it runs longer than the timeout, if the deadline of the chunk
+//! is exceeded or if the server revokes the chunk. Its memory (address space) and CPU time are limited by the operating system:
+//! with rlimits on Unix (RLIMIT_AS and RLIMIT_CPU, the program and its children are in their own process group) and with a job object
+//! on Windows. On other platforms only the timeout and the output limit work.
+//!
+//! A failed run is a [`NCJobError`] that the node sends to the server:
+//! - [`NCJobError::SUBPROCESS_FAILED`]: the program could not be started, has exited with a nonzero code, has been killed by a signal
+//!   or its output could not be deserialized. The chunk is given to a node again.
+//! - [`NCJobError::SUBPROCESS_TIMEOUT`]: the program has run longer than the timeout. The chunk is given to a node again.
+//! - [`NCJobError::SUBPROCESS_LIMIT`]: the program has exceeded the memory limit, the CPU time limit or max_output_bytes.
+//!   It would do that again, so the chunk is marked as failed. Which limit a program has hit is a guess of the operating system:
+//!   on Unix a program that is killed by SIGXCPU has exceeded the CPU time and a program that is killed by SIGABRT, SIGSEGV, SIGBUS or
+//!   SIGKILL while there is a memory limit has most likely exceeded the memory (a failed allocation usually ends like that).
+//!
+//! The [`SubprocessNode`] implements the [`NCNode`] trait with a runner, the data and the result are serialized with JSON or bincode
+//! (see [`SubprocessFormat`]). To do more than that (initial data, custom messages) implement the NCNode trait yourself and call
+//! [`SubprocessRunner::run_with_context()`] from process_data_with_context():
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use node_crunch::{NCConfiguration, NCNodeStarter, SubprocessRunner, SubprocessNode, SubprocessFormat, RangeBatch, RangeResults};
+//!
+//! let runner = SubprocessRunner {
+//!     memory_limit: Some(512 * 1024 * 1024),
+//!     cpu_time_limit: Some(Duration::from_secs(60)),
+//!     timeout: Some(Duration::from_secs(120)),
+//!     ..SubprocessRunner::new("/opt/kernels/square")
+//! };
+//! let node: SubprocessNode<RangeBatch, RangeResults<u64>> = SubprocessNode::new(runner, SubprocessFormat::Json);
+//! NCNodeStarter::new(NCConfiguration::default()).start(node).unwrap();
+//! ```
+
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::nc_error::{NCError, NCJobError};
+use crate::nc_node::{NCNode, NCProcessContext, NodeResult};
+
+/// How often the runner checks if the program has exited, has run too long or the chunk has been cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The error message contains at most this many characters of the last line of the standard error.
+const MAX_STDERR_LINE: usize = 200;
+
+/// Runs an external program with the data of a chunk, see the module documentation.
+/// Create it with new() and change the fields that are needed: `SubprocessRunner { timeout: Some(...), ..SubprocessRunner::new(command) }`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubprocessRunner {
+    /// The program to run, either an absolute path or a name that is looked up in the PATH (the program runs in the scratch folder).
+    pub command: PathBuf,
+    /// The arguments for the program.
+    pub args: Vec<OsString>,
+    /// Maximum size of the address space of the program in bytes, [`None`] means no limit.
+    pub memory_limit: Option<u64>,
+    /// Maximum CPU time of the program, rounded up to whole seconds on Unix. [`None`] means no limit.
+    pub cpu_time_limit: Option<Duration>,
+    /// The program is killed if it runs longer than this (wall clock time), [`None`] means no timeout.
+    /// Independent of this the program is killed if the deadline of the chunk is exceeded.
+    pub timeout: Option<Duration>,
+    /// The program is killed if it writes more than this many bytes to its standard output or standard error. Default: 256 MiB.
+    pub max_output_bytes: u64,
+    /// The name of the artifact with the standard error of the program, [`None`] means the standard error is not attached.
+    /// Default: "stderr.log"
+    pub stderr_artifact: Option<String>,
+}
+
+/// What the program has written while processing a chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubprocessOutput {
+    /// The standard output of the program, this is the result.
+    pub stdout: Vec<u8>,
+    /// The standard error of the program.
+    pub stderr: Vec<u8>,
+    /// How long the program has run.
+    pub elapsed: Duration,
+}
+
+/// Why the runner has killed the program.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KillReason {
+    Timeout,
+    Cancelled,
+    Output,
+}
+
+impl SubprocessRunner {
+    /// A runner for the given program without arguments and limits.
+    pub fn new<P: Into<PathBuf>>(command: P) -> Self {
+        debug!("SubprocessRunner::new()");
+
+        SubprocessRunner {
+            command: command.into(),
+            args: Vec::new(),
+            memory_limit: None,
+            cpu_time_limit: None,
+            timeout: None,
+            max_output_bytes: 256 * 1024 * 1024,
+            stderr_artifact: Some("stderr.log".to_string()),
+        }
+    }
+
+    /// Runs the program in the current folder with the given input, it's killed when cancelled is set.
+    ///
+    /// # Errors
+    ///
+    /// Returns one of the subprocess errors from the module documentation or [`NCJobError::deadline_exceeded()`] if it has been cancelled.
+    pub fn run(&self, input: &[u8], cancelled: &AtomicBool) -> Result<SubprocessOutput, NCJobError> {
+        debug!("SubprocessRunner::run()");
+
+        self.execute(input, None, cancelled).map_err(|(e, _)| e)
+    }
+
+    /// Runs the program in the scratch folder of the chunk with the given input, it's killed when the chunk is cancelled
+    /// (see [`NCProcessContext::is_cancelled()`]). The standard error is attached as the artifact stderr_artifact,
+    /// also if the program has failed.
+    ///
+    /// # Errors
+    ///
+    /// See run().
+    pub fn run_with_context(&self, input: &[u8], context: &NCProcessContext) -> Result<SubprocessOutput, NCJobError> {
+        debug!("SubprocessRunner::run_with_context()");
+
+        let token = context.cancellation_token();
+        let result = self.execute(input, Some(context.scratch_dir()), &token);
+
+        if let Some(name) = &self.stderr_artifact {
+            let stderr = match &result {
+                Ok(output) => &output.stderr,
+                Err((_, stderr)) => stderr,
+            };
+
+            if !stderr.is_empty() && !context.attach_artifact(name, stderr.clone()) {
+                debug!("Standard error of {} has not been attached", self.command.display());
+            }
+        }
+
+        result.map_err(|(e, _)| e)
+    }
+
+    /// Starts the program, feeds it the input, collects its output and waits until it has exited or has been killed.
+    /// An error comes together with the standard error that the program has written until then.
+    fn execute(&self, input: &[u8], dir: Option<&Path>, cancelled: &AtomicBool) -> Result<SubprocessOutput, (NCJobError, Vec<u8>)> {
+        let mut command = Command::new(&self.command);
+        command.args(&self.args).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+
+        if let Some(dir) = dir.filter(|dir| !dir.as_os_str().is_empty()) {
+            command.current_dir(dir);
+        }
+
+        sandbox::prepare(&mut command, self);
+
+        let time_start = Instant::now();
+        let mut child = command.spawn().map_err(|e| (self.failed(format!("could not be started: {}", e)), Vec::new()))?;
+        let sandbox = match sandbox::Sandbox::attach(&child, self) {
+            Ok(sandbox) => sandbox,
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err((self.failed(format!("could not be limited: {}", e)), Vec::new()))
+            }
+        };
+
+        let overflow = Arc::new(AtomicBool::new(false));
+        let writer = child.stdin.take().map(|stdin| write_pipe(stdin, input.to_vec()));
+        let stdout = child.stdout.take().map(|stdout| read_pipe(stdout, self.max_output_bytes, overflow.clone()));
+        let stderr = child.stderr.take().map(|stderr| read_pipe(stderr, self.max_output_bytes, overflow.clone()));
+
+        let (status, killed) = self.wait(&mut child, &sandbox, time_start, cancelled, &overflow);
+        // Children of the program may still be running and keep the pipes open
+        sandbox.kill();
+
+        if let Some(writer) = writer {
+            let _ = writer.join();
+        }
+
+        let output = SubprocessOutput {
+            stdout: stdout.and_then(|reader| reader.join().ok()).unwrap_or_default(),
+            stderr: stderr.and_then(|reader| reader.join().ok()).unwrap_or_default(),
+            elapsed: time_start.elapsed(),
+        };
+
+        let error = match (killed, status) {
+            (Some(KillReason::Cancelled), _) => {
+                info!("Chunk has been cancelled, {} has been killed", self.command.display());
+                NCJobError::deadline_exceeded()
+            }
+            (Some(KillReason::Timeout), _) => NCJobError::new(NCJobError::SUBPROCESS_TIMEOUT,
+                format!("{} has been killed after the timeout of {:?}", self.command.display(), self.timeout.unwrap_or_default()), true),
+            (Some(KillReason::Output), _) => self.limit(format!("has written more than {} bytes", self.max_output_bytes)),
+            (None, Err(e)) => self.failed(format!("could not be waited for: {}", e)),
+            (None, Ok(status)) if status.success() => return Ok(output),
+            (None, Ok(status)) => {
+                let line = last_line(&output.stderr);
+
+                match sandbox.limit_exceeded(&status, self) {
+                    Some(limit) => self.limit(format!("{} ({}){}", limit, status, line)),
+                    None => self.failed(format!("{}{}", status, line)),
+                }
+            }
+        };
+
+        Err((error, output.stderr))
+    }
+
+    /// Waits until the program has exited or kills it if it has to be stopped, then also returns the reason.
+    fn wait(&self, child: &mut Child, sandbox: &sandbox::Sandbox, time_start: Instant, cancelled: &AtomicBool, overflow: &AtomicBool)
+        -> (io::Result<ExitStatus>, Option<KillReason>) {
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => return (Ok(status), None),
+                Ok(None) => {}
+                Err(e) => return (Err(e), None),
+            }
+
+            let reason = if cancelled.load(Ordering::Relaxed) {
+                Some(KillReason::Cancelled)
+            } else if self.timeout.is_some_and(|timeout| time_start.elapsed() >= timeout) {
+                Some(KillReason::Timeout)
+            } else if overflow.load(Ordering::Relaxed) {
+                Some(KillReason::Output)
+            } else {
+                None
+            };
+
+            if let Some(reason) = reason {
+                debug!("Kill {}: {:?}", self.command.display(), reason);
+                sandbox.kill();
+                let _ = child.kill();
+                return (child.wait(), Some(reason))
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// The error for a program that has failed, see [`NCJobError::SUBPROCESS_FAILED`].
+    fn failed(&self, message: String) -> NCJobError {
+        NCJobError::new(NCJobError::SUBPROCESS_FAILED, format!("{} {}", self.command.display(), message), true)
+    }
+
+    /// The error for a program that has exceeded a limit, see [`NCJobError::SUBPROCESS_LIMIT`].
+    fn limit(&self, message: String) -> NCJobError {
+        NCJobError::new(NCJobError::SUBPROCESS_LIMIT, format!("{} {}", self.command.display(), message), false)
+    }
+}
+
+/// Writes the input to the standard input of the program and closes it.
+/// A program that exits without reading all of it is not an error here, its exit status counts.
+fn write_pipe<W: Write + Send + 'static>(mut pipe: W, input: Vec<u8>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let _ = pipe.write_all(&input);
+    })
+}
+
+/// Reads the output of the program until it's closed or has more than max_bytes bytes, then overflow is set.
+fn read_pipe<R: Read + Send + 'static>(pipe: R, max_bytes: u64, overflow: Arc<AtomicBool>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut data = Vec::new();
+        let _ = pipe.take(max_bytes.saturating_add(1)).read_to_end(&mut data);
+
+        if data.len() as u64 > max_bytes {
+            data.truncate(max_bytes as usize);
+            overflow.store(true, Ordering::Relaxed);
+        }
+
+        data
+    })
+}
+
+/// The last non empty line of the standard error for the error message, with a leading ": ".
+fn last_line(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+
+    match stderr.lines().map(str::trim).rfind(|line| !line.is_empty()) {
+        Some(line) => format!(": {}", line.chars().take(MAX_STDERR_LINE).collect::<String>()),
+        None => String::new(),
+    }
+}
+
+/// The rlimits and the process group of the program on Unix.
+#[cfg(unix)]
+mod sandbox {
+    use std::io;
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+    use std::process::{Child, Command, ExitStatus};
+
+    use super::SubprocessRunner;
+
+    /// The process group of the program.
+    pub(super) struct Sandbox {
+        group: libc::pid_t,
+    }
+
+    /// Puts the program into its own process group and sets the rlimits in the child before the program is executed.
+    pub(super) fn prepare(command: &mut Command, runner: &SubprocessRunner) {
+        let memory_limit = runner.memory_limit;
+        // RLIMIT_CPU has whole seconds, SIGXCPU comes at the soft limit and SIGKILL one second later at the hard limit
+        let cpu_seconds = runner.cpu_time_limit.map(|limit| limit.as_secs() + u64::from(limit.subsec_nanos() > 0 || limit.as_secs() == 0));
+
+        command.process_group(0);
+
+        // Safety: the closure runs in the child between fork and exec, it only calls getrlimit() and setrlimit()
+        // which are async-signal-safe and doesn't allocate.
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(bytes) = memory_limit {
+                    set_limit(libc::RLIMIT_AS as _, bytes, bytes)?;
+                }
+
+                if let Some(seconds) = cpu_seconds {
+                    set_limit(libc::RLIMIT_CPU as _, seconds, seconds.saturating_add(1))?;
+                }
+
+                Ok(())
+            });
+        }
+    }
+
+    /// Lowers the given limit, but never above the hard limit that the node itself has.
+    unsafe fn set_limit(resource: i32, soft: u64, hard: u64) -> io::Result<()> {
+        let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+
+        if libc::getrlimit(resource as _, &mut limit) != 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        let max = limit.rlim_max;
+        limit.rlim_max = (hard as libc::rlim_t).min(max);
+        limit.rlim_cur = (soft as libc::rlim_t).min(limit.rlim_max);
+
+        if libc::setrlimit(resource as _, &limit) != 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        Ok(())
+    }
+
+    impl Sandbox {
+        /// The limits have already been set in prepare(), the process group has the id of the child.
+        pub(super) fn attach(child: &Child, _runner: &SubprocessRunner) -> io::Result<Self> {
+            Ok(Sandbox { group: child.id() as libc::pid_t })
+        }
+
+        /// Kills the program and all its children.
+        pub(super) fn kill(&self) {
+            // Safety: just a system call, it fails with ESRCH if the process group is already gone.
+            unsafe {
+                libc::kill(-self.group, libc::SIGKILL);
+            }
+        }
+
+        /// Returns which limit the program has exceeded, see the module documentation.
+        pub(super) fn limit_exceeded(&self, status: &ExitStatus, runner: &SubprocessRunner) -> Option<String> {
+            let cpu = runner.cpu_time_limit.map(|limit| format!("has exceeded the CPU time limit of {:?}", limit));
+            let memory = runner.memory_limit.map(|limit| format!("has most likely exceeded the memory limit of {} bytes", limit));
+
+            match status.signal()? {
+                libc::SIGXCPU => cpu,
+                libc::SIGABRT | libc::SIGSEGV | libc::SIGBUS => memory,
+                libc::SIGKILL => memory.or(cpu),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// The job object of the program on Windows.
+#[cfg(windows)]
+mod sandbox {
+    use std::ffi::c_void;
+    use std::io;
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+    use std::process::{Child, Command, ExitStatus};
+    use std::ptr;
+
+    use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+    use windows_sys::Win32::System::JobObjects::{AssignProcessToJobObject, CreateJobObjectW, QueryInformationJobObject,
+        SetInformationJobObject, TerminateJobObject, JobObjectBasicAccountingInformation, JobObjectExtendedLimitInformation,
+        JOBOBJECT_BASIC_ACCOUNTING_INFORMATION, JOBOBJECT_EXTENDED_LIMIT_INFORMATION, JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+        JOB_OBJECT_LIMIT_PROCESS_MEMORY, JOB_OBJECT_LIMIT_PROCESS_TIME};
+
+    use super::SubprocessRunner;
+
+    /// The job object of the program, all processes in it are killed when it's closed.
+    pub(super) struct Sandbox {
+        job: HANDLE,
+    }
+
+    /// The limits are set on the job object in attach().
+    pub(super) fn prepare(_command: &mut Command, _runner: &SubprocessRunner) {
+    }
+
+    impl Sandbox {
+        /// Creates the job object with the limits and puts the program into it.
+        /// The program runs for a moment before that, processes that it starts in that moment are not in the job object.
+        pub(super) fn attach(child: &Child, runner: &SubprocessRunner) -> io::Result<Self> {
+            // Safety: the job handle is checked and owned by the Sandbox, the structs are plain data that is valid when zeroed.
+            unsafe {
+                let job = CreateJobObjectW(ptr::null(), ptr::null());
+
+                if job.is_null() {
+                    return Err(io::Error::last_os_error())
+                }
+
+                let sandbox = Sandbox { job };
+                let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = mem::zeroed();
+                info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+
+                if let Some(bytes) = runner.memory_limit {
+                    info.BasicLimitInformation.LimitFlags |= JOB_OBJECT_LIMIT_PROCESS_MEMORY;
+                    info.ProcessMemoryLimit = bytes.min(usize::MAX as u64) as usize;
+                }
+
+                if let Some(limit) = runner.cpu_time_limit {
+                    info.BasicLimitInformation.LimitFlags |= JOB_OBJECT_LIMIT_PROCESS_TIME;
+                    // In units of 100 nanoseconds
+                    info.BasicLimitInformation.PerProcessUserTimeLimit = (limit.as_nanos() / 100).min(i64::MAX as u128) as i64;
+                }
+
+                if SetInformationJobObject(job, JobObjectExtendedLimitInformation, &info as *const _ as *const c_void,
+                    mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32) == 0 {
+                    return Err(io::Error::last_os_error())
+                }
+
+                if AssignProcessToJobObject(job, child.as_raw_handle() as HANDLE) == 0 {
+                    return Err(io::Error::last_os_error())
+                }
+
+                Ok(sandbox)
+            }
+        }
+
+        /// Kills the program and all its children.
+        pub(super) fn kill(&self) {
+            // Safety: the handle is valid until the Sandbox is dropped.
+            unsafe {
+                TerminateJobObject(self.job, 1);
+            }
+        }
+
+        /// Returns which limit the program has exceeded: the CPU time that the job object has counted or a peak memory usage
+        /// of at least 90% of the limit (the allocation that has failed is not part of the peak).
+        pub(super) fn limit_exceeded(&self, _status: &ExitStatus, runner: &SubprocessRunner) -> Option<String> {
+            // Safety: the handle is valid until the Sandbox is dropped, the structs are plain data that is valid when zeroed.
+            unsafe {
+                let mut accounting: JOBOBJECT_BASIC_ACCOUNTING_INFORMATION = mem::zeroed();
+                let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = mem::zeroed();
+
+                if let Some(limit) = runner.cpu_time_limit {
+                    if QueryInformationJobObject(self.job, JobObjectBasicAccountingInformation, &mut accounting as *mut _ as *mut c_void,
+                        mem::size_of::<JOBOBJECT_BASIC_ACCOUNTING_INFORMATION>() as u32, ptr::null_mut()) != 0
+                        && accounting.TotalUserTime as u128 >= limit.as_nanos() / 100 {
+                        return Some(format!("has exceeded the CPU time limit of {:?}", limit))
+                    }
+                }
+
+                if let Some(limit) = runner.memory_limit {
+                    if QueryInformationJobObject(self.job, JobObjectExtendedLimitInformation, &mut info as *mut _ as *mut c_void,
+                        mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32, ptr::null_mut()) != 0
+                        && info.PeakProcessMemoryUsed as u64 >= limit / 10 * 9 {
+                        return Some(format!("has most likely exceeded the memory limit of {} bytes", limit))
+                    }
+                }
+
+                None
+            }
+        }
+    }
+
+    impl Drop for Sandbox {
+        fn drop(&mut self) {
+            // Safety: the handle is owned by the Sandbox, closing it kills the processes that are left (JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE).
+            unsafe {
+                CloseHandle(self.job);
+            }
+        }
+    }
+}
+
+/// Other platforms have no limits, only the timeout and max_output_bytes.
+#[cfg(not(any(unix, windows)))]
+mod sandbox {
+    use std::io;
+    use std::process::{Child, Command, ExitStatus};
+
+    use log::warn;
+
+    use super::SubprocessRunner;
+
+    /// Nothing to keep, the program itself is killed by the runner.
+    pub(super) struct Sandbox;
+
+    /// Warns if there are limits, they can't be set here.
+    pub(super) fn prepare(_command: &mut Command, runner: &SubprocessRunner) {
+        if runner.memory_limit.is_some() || runner.cpu_time_limit.is_some() {
+            warn!("memory_limit and cpu_time_limit are not supported on this platform");
+        }
+    }
+
+    impl Sandbox {
+        pub(super) fn attach(_child: &Child, _runner: &SubprocessRunner) -> io::Result<Self> {
+            Ok(Sandbox)
+        }
+
+        pub(super) fn kill(&self) {
+        }
+
+        pub(super) fn limit_exceeded(&self, _status: &ExitStatus, _runner: &SubprocessRunner) -> Option<String> {
+            None
+        }
+    }
+}
+
+/// How the [`SubprocessNode`] serializes the data for the program and deserializes its result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubprocessFormat {
+    /// One JSON value on the standard input and one on the standard output, easy to use for programs in any language.
+    Json,
+    /// The data and the result as bincode, like node_crunch sends them over the network. Smaller and faster for big arrays.
+    Bincode,
+}
+
+impl SubprocessFormat {
+    /// Serializes the data for the standard input of the program.
+    fn encode<N: Serialize>(&self, data: &N) -> Result<Vec<u8>, NCError> {
+        match self {
+            SubprocessFormat::Json => serde_json::to_vec(data).map_err(|e| NCError::custom(format!("Could not serialize data as JSON: {}", e))),
+            SubprocessFormat::Bincode => bincode::serialize(data).map_err(NCError::Serialize),
+        }
+    }
+
+    /// Deserializes the result from the standard output of the program.
+    fn decode<P: DeserializeOwned>(&self, output: &[u8]) -> Result<P, String> {
+        match self {
+            SubprocessFormat::Json => serde_json::from_slice(output).map_err(|e| e.to_string()),
+            SubprocessFormat::Bincode => bincode::deserialize(output).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// A node that runs an external program for every chunk, see the module documentation.
+/// N is the data from the server (NewDataT), P the result of the program (ProcessedDataT).
+pub struct SubprocessNode<N, P> {
+    runner: SubprocessRunner,
+    format: SubprocessFormat,
+    data: PhantomData<fn(&N) -> P>,
+}
+
+impl<N, P> SubprocessNode<N, P> {
+    /// The node runs the program of the runner, the data and the result are serialized with the given format.
+    pub fn new(runner: SubprocessRunner, format: SubprocessFormat) -> Self {
+        debug!("SubprocessNode::new()");
+
+        if runner.memory_limit.is_none() && runner.cpu_time_limit.is_none() && runner.timeout.is_none() {
+            warn!("{} runs without any limits", runner.command.display());
+        }
+
+        SubprocessNode { runner, format, data: PhantomData }
+    }
+
+    /// The runner of this node.
+    pub fn runner(&self) -> &SubprocessRunner {
+        &self.runner
+    }
+}
+
+impl<N, P> NCNode for SubprocessNode<N, P> where N: Serialize + DeserializeOwned, P: Serialize + DeserializeOwned {
+    type InitialDataT = ();
+    type NewDataT = N;
+    type ProcessedDataT = P;
+    type CustomMessageT = ();
+
+    /// Without a context (for example in NCNodeStarter::process_archive()) the program runs in the current folder and
+    /// only the timeout stops it early.
+    fn process_data_from_server(&mut self, data: &N) -> Result<NodeResult<P>, NCError> {
+        debug!("SubprocessNode::process_data_from_server()");
+
+        let output = self.runner.run(&self.format.encode(data)?, &AtomicBool::new(false))?;
+        self.result(output)
+    }
+
+    fn process_data_with_context(&mut self, data: &N, context: &NCProcessContext) -> Result<NodeResult<P>, NCError> {
+        debug!("SubprocessNode::process_data_with_context()");
+
+        let output = self.runner.run_with_context(&self.format.encode(data)?, context)?;
+        self.result(output)
+    }
+}
+
+impl<N, P> SubprocessNode<N, P> where P: DeserializeOwned {
+    /// The result from the standard output of the program, a program that writes nothing has an empty chunk.
+    fn result(&self, output: SubprocessOutput) -> Result<NodeResult<P>, NCError> {
+        if output.stdout.is_empty() {
+            return Ok(NodeResult::Empty)
+        }
+
+        match self.format.decode(&output.stdout) {
+            Ok(result) => Ok(NodeResult::Data(result)),
+            Err(e) => Err(self.runner.failed(format!("has written an invalid result: {}", e)).into()),
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! This module contains the text protocol for debugging the server by hand (debug-protocol feature and text_protocol in the NCConfiguration).
+//! Instead of a binary frame the node (or a human with `nc localhost 2020`) sends one NCNodeMessage as JSON per line,
+//! for example `{"NeedsData": 1}` or `{"GetStatistics": null}`, and gets the answer of the server as one line of JSON.
+//! The text is neither compressed nor encrypted, so the server refuses to start with both text_protocol and encrypt.
+//!
+//! A text connection is detected by its first bytes: `{` followed by more text. A binary frame starts with its length (u64, little endian),
+//! so its eighth byte is always zero and binary peers are never mistaken for text.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use log::debug;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::error::Category;
+
+use crate::nc_error::NCError;
+use crate::nc_transport::NCStream;
+
+/// The first byte of every message in the text protocol.
+pub(crate) const TEXT_MAGIC: u8 = b'{';
+
+/// How often is_text() looks again if less than 8 bytes have arrived.
+const PEEK_RETRIES: u32 = 50;
+
+/// The time between two looks.
+const PEEK_DELAY: Duration = Duration::from_millis(10);
+
+/// Returns true if the connection uses the text protocol, the data is not removed from the stream.
+pub(crate) fn is_text(stream: &NCStream) -> Result<bool, NCError> {
+    debug!("nc_text_protocol::is_text()");
+
+    let mut start = [0; 8];
+    let mut bytes_read = stream.peek(&mut start)?;
+
+    // A short line (or a line typed by hand) may arrive in more than one piece.
+    for _ in 0..PEEK_RETRIES {
+        if bytes_read == start.len() || start[0] != TEXT_MAGIC || start[..bytes_read].contains(&b'\n') {
+            break
+        }
+
+        thread::sleep(PEEK_DELAY);
+        bytes_read = stream.peek(&mut start)?;
+    }
+
+    Ok(start[0] == TEXT_MAGIC && (bytes_read < start.len() || start[7] != 0))
+}
+
+/// Serializes the message as one line of JSON (without the newline).
+///
+/// # Errors
+///
+/// Returns a [`NCError::TextProtocol`] error if the message can't be serialized.
+pub(crate) fn encode_line<S: Serialize>(message: &S) -> Result<String, NCError> {
+    serde_json::to_string(message).map_err(|e| NCError::TextProtocol(e.to_string()))
+}
+
+/// Deserializes one line of JSON.
+///
+/// # Errors
+///
+/// Returns a [`NCError::TextProtocol`] error if the line is not valid JSON for the message. If the JSON is valid but doesn't match the message
+/// only the position is reported, since serde_json would put the values (the user data) into the error message.
+pub(crate) fn decode_line<D: DeserializeOwned>(line: &str) -> Result<D, NCError> {
+    serde_json::from_str(line).map_err(|e| match e.classify() {
+        Category::Data => NCError::TextProtocol(format!("the JSON doesn't match the message at line {} column {}", e.line(), e.column())),
+        _ => NCError::TextProtocol(e.to_string()),
+    })
+}
+
+/// Reads the lines of the text protocol from the given stream and calls handle_line() for every non empty line.
+/// If it returns some text it's written back as one line. If a line can't be handled the error is sent back as
+/// `{"Error": "..."}` and the next line is read.
+pub(crate) fn serve<F: FnMut(&str) -> Result<Option<String>, NCError>>(stream: NCStream, mut handle_line: F) -> Result<(), NCError> {
+    debug!("nc_text_protocol::serve()");
+
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue
+        }
+
+        let answer = match handle_line(line.trim()) {
+            Ok(Some(answer)) => answer,
+            Ok(None) => continue,
+            Err(e) => encode_line(&serde_json::json!({ "Error": e.to_string() }))?,
+        };
+
+        writer.write_all(answer.as_bytes())?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Returns two connected tcp streams on the loopback interface. The binary answer of the server is written to
+/// the first one and read from the second one, so that the server code doesn't have to know about the text protocol.
+pub(crate) fn loopback_pair() -> Result<(NCStream, TcpStream), NCError> {
+    let listener = TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))?;
+    let client = TcpStream::connect(listener.local_addr()?)?;
+    let (server, _) = listener.accept()?;
+
+    Ok((Box::new(server), client))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::SystemTime;
+
+    use crate::nc_node::NCNodeMessage;
+    use crate::nc_node_info::NodeID;
+    use crate::nc_server::{NCServerMessage, NCJobStatus, NCChunkInfo, NCServerStatistics};
+    use crate::nc_error::NCJobError;
+    use crate::nc_communicator::NCCodec;
+    use crate::nc_config::{NCConfiguration, ConfigDiff, ConfigDiffSeverity};
+    use crate::nc_admin::{NCAdminMessage, NCAdminCommand};
+    use crate::array2d::ChunkMeta;
+    use crate::nc_job_summary::{NCChunkAttempt, NCAttemptOutcome};
+    use crate::nc_message::NCRejectReason;
+
+    /// Encodes the message, decodes it again and compares the JSON of both.
+    fn round_trip<M: Serialize + DeserializeOwned>(message: M) -> String {
+        let line = encode_line(&message).unwrap();
+        let decoded: M = decode_line(&line).unwrap();
+
+        assert_eq!(encode_line(&decoded).unwrap(), line);
+        assert!(line.starts_with('{') || line.starts_with('"'));
+        line
+    }
+
+    #[test]
+    fn test_node_messages() {
+        let node_id = NodeID::random();
+        let meta: ChunkMeta = vec![("tile".to_string(), "3/4".to_string())].into_iter().collect();
+        let admin = NCAdminMessage::new("ZTXbsBVhz9tDzDhklykVDUXznjonhGil", NCAdminCommand::QueryStatus).unwrap();
+
+        let messages: Vec<NCNodeMessage<Vec<u32>, String>> = vec![
+            NCNodeMessage::Register(vec![NCCodec::Lz4, NCCodec::Zstd(3)], Some("v1".to_string()), Some(2021), None, vec!["gpu".to_string()],
+                Some(NCConfiguration::default().shared_settings())),
+            NCNodeMessage::NeedsData(node_id),
+            NCNodeMessage::HasData(node_id, vec![1, 2, 3]),
+            NCNodeMessage::Empty(node_id),
+            NCNodeMessage::Skip(node_id, "too small".to_string()),
+            NCNodeMessage::NodeFailed(node_id, NCJobError::new(7, "broken", true)),
+            NCNodeMessage::HeartBeat(node_id, None),
+            NCNodeMessage::CheckHeartbeat,
+            NCNodeMessage::GetStatistics,
+            NCNodeMessage::ShutDown,
+            NCNodeMessage::NewServer("127.0.0.1".to_string(), 2021),
+            NCNodeMessage::NodeMigrated(node_id),
+            NCNodeMessage::CustomMessage("hello".to_string(), Some(node_id)),
+            NCNodeMessage::Admin(admin),
+            NCNodeMessage::Aggregate(node_id, 4, vec![4]),
+            NCNodeMessage::Delegated(node_id, NodeID::random(), 4),
+            NCNodeMessage::Aggregated(node_id, vec![4, 5], Some(vec![9])),
+            NCNodeMessage::NeedsDataWithHint(node_id, Default::default()),
+            NCNodeMessage::HasDataWithMeta(node_id, meta, vec![1]),
+            NCNodeMessage::NeedsDataPrefetch(node_id, None),
+            NCNodeMessage::ReleaseChunk(node_id, 4),
+            NCNodeMessage::RegisterKey(node_id, vec![7; 32]),
+            NCNodeMessage::HasSignedData(node_id, 4, None, vec![8; 64], vec![1, 2]),
+            NCNodeMessage::SetTags(node_id, vec!["cpu".to_string()]),
+            NCNodeMessage::GroupMessage("hello".to_string(), "gpu".to_string()),
+            NCNodeMessage::ClockProbe(node_id, 1_700_000_000_000_000),
+            NCNodeMessage::ClockReport(node_id, 1_700_000_000_000_000, 1_700_000_000_000_600, 1_700_000_000_001_000),
+            NCNodeMessage::HasCachedData(node_id, 4, None, vec![1]),
+            NCNodeMessage::PollHeartBeat(node_id, None),
+            NCNodeMessage::ChunkRevoked(node_id, 4),
+            NCNodeMessage::Rejoin(node_id, 2, vec![NCCodec::Lz4], None, None, None, Vec::new(), None),
+            NCNodeMessage::GetDictionary(node_id, 0x0102_0304),
+        ];
+
+        for message in messages {
+            round_trip(message);
+        }
+
+        // Unit variants can also be written as an object, so that every line starts with the magic byte
+        assert!(matches!(decode_line::<NCNodeMessage<(), ()>>(r#"{"GetStatistics": null}"#), Ok(NCNodeMessage::GetStatistics)));
+        assert!(matches!(decode_line::<NCNodeMessage<(), ()>>(r#"{"NeedsData": 1}"#), Ok(NCNodeMessage::NeedsData(_))));
+        assert!(matches!(decode_line::<NCNodeMessage<(), ()>>(r#"{"NeedsMoreData": 1}"#), Err(NCError::TextProtocol(_))));
+
+        // The values are not part of the error message
+        let error = decode_line::<NCNodeMessage<Vec<u32>, String>>(r#"{"CustomMessage": [12345678, null]}"#).unwrap_err().to_string();
+        assert!(error.contains("doesn't match the message") && !error.contains("12345678"));
+    }
+
+    #[test]
+    fn test_server_messages() {
+        let node_id = NodeID::random();
+        // The fields are private, the statistics can only be created by the server or decoded
+        let statistics: NCServerStatistics = decode_line(concat!(r#"{"num_of_nodes":2,"time_taken":1.5,"hb_time_stamps":[[1,0.5]],"#,
+            r#""result_queue_len":0,"result_queue_bytes":0,"result_queue_spilled":0,"empty_chunks":1,"skipped_chunks":[["too small",2]],"#,
+            r#""chunk_cache_hits":0,"chunk_cache_misses":0,"frame_cache_hits":0,"connections_in_flight":1,"rejected_connections":0,"killed_connections":0,"#,
+            r#""post_process_failures":0,"restart_requests":0,"uncompressed_frames":3,"group_sizes":[["gpu",2]],"#,
+            r#""heartbeat_sweep_micros":12,"max_heartbeat_sweep_micros":40,"ipv4_connections":5,"ipv6_connections":1,"user_panics":0,"#,
+            r#""clock_skews":[[1,{"offset_micros":-250,"rtt_micros":900}]],"local_chunks":4,"locality_fallbacks":1}"#)).unwrap();
+
+        let messages: Vec<NCServerMessage<String, (u32, u32), String>> = vec![
+            NCServerMessage::InitialData(node_id, Some("start".to_string()), NCCodec::None),
+            NCServerMessage::NoCommonCodec(vec![NCCodec::Lz4]),
+            NCServerMessage::JobStatus(NCJobStatus::Unfinished((1, 2), NCChunkInfo::default())),
+            NCServerMessage::JobStatus(NCJobStatus::Waiting),
+            NCServerMessage::JobStatus(NCJobStatus::Finished),
+            NCServerMessage::Statistics(statistics),
+            NCServerMessage::NewServer("127.0.0.1".to_string(), 2021),
+            NCServerMessage::CustomMessage("hello".to_string()),
+            NCServerMessage::Status(NCJobStatus::Finished),
+            NCServerMessage::AdminAck,
+            NCServerMessage::Unauthorized,
+            NCServerMessage::ResultAck { job_finished: false },
+            NCServerMessage::ResultAck { job_finished: true },
+            NCServerMessage::ResultRejected(NCJobError::result_rejected("broken", true)),
+            NCServerMessage::ServerFailed(NCJobError::new(7, "broken", false)),
+            NCServerMessage::RotateKey("Hn3kqPZ0lXv8dWmYc1RtG5sJbQ2eA7fU".to_string()),
+            NCServerMessage::PleaseRestart { reason: "new build".to_string() },
+            NCServerMessage::UnknownJob("a".to_string()),
+            NCServerMessage::ConfigMismatch(vec![ConfigDiff { key: "encrypt".to_string(), local: "true".to_string(), remote: "false".to_string(),
+                severity: ConfigDiffSeverity::Error }]),
+            NCServerMessage::ClockTime(1_700_000_000_000_000),
+            NCServerMessage::ChunkHistory(vec![NCChunkAttempt { node_id, assigned_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+                outcome: NCAttemptOutcome::Failed(7), duration: Duration::from_millis(1500) }]),
+            NCServerMessage::ChunksRevoked(vec![4, 5]),
+            NCServerMessage::Rejected { reason: NCRejectReason::ServerBusy, message: "try again".to_string(), retry_after: Some(Duration::from_secs(5)) },
+            NCServerMessage::JobAborted("too many failures".to_string()),
+            NCServerMessage::Dictionary(0x0102_0304, vec![1, 2, 3]),
+            NCServerMessage::UnknownDictionary(0x0102_0304),
+        ];
+
+        for message in messages {
+            round_trip(message);
+        }
+    }
+
+    #[test]
+    fn test_is_text() {
+        let (server, mut node) = loopback_pair().unwrap();
+
+        node.write_all(b"{\"NeedsData\": 1}\n").unwrap();
+        assert!(is_text(&server).unwrap());
+
+        // A binary frame of 123 bytes starts with the magic byte too
+        let (server, mut node) = loopback_pair().unwrap();
+        node.write_all(&123u64.to_le_bytes()).unwrap();
+        assert!(!is_text(&server).unwrap());
+    }
+
+    #[test]
+    fn test_serve() {
+        let (server, mut node) = loopback_pair().unwrap();
+        let server_thread = thread::spawn(move || serve(server, |line| {
+            let message: NCNodeMessage<(), ()> = decode_line(line)?;
+
+            match message {
+                NCNodeMessage::GetStatistics => encode_line(&NCServerMessage::<(), (), ()>::ResultAck { job_finished: false }).map(Some),
+                _ => Ok(None),
+            }
+        }));
+
+        node.write_all(b"{\"GetStatistics\": null}\n\n{\"HeartBeat\": [1, null]}\nnot json\n").unwrap();
+        node.shutdown(std::net::Shutdown::Write).unwrap();
+        server_thread.join().unwrap().unwrap();
+
+        let lines: Vec<String> = BufReader::new(node).lines().map(|line| line.unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "{\"ResultAck\":{\"job_finished\":false}}");
+        assert!(lines[1].starts_with("{\"Error\":\"Text protocol error: "));
+    }
+}
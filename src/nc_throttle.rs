@@ -0,0 +1,172 @@
+//! This module contains the CPU limit of the node (cpu_limit_percent in the NCConfiguration).
+//! The limit is cooperative: the node measures how long it has been busy since the last checkpoint and then sleeps
+//! long enough that over the last THROTTLE_WINDOW it was busy at most cpu_limit_percent of the time.
+//! The node inserts a checkpoint after every chunk, user code with long chunks should call
+//! [`NCProcessContext::throttle()`](crate::NCProcessContext::throttle) from time to time, for example once per row.
+//! User code that processes the data with several threads should start at most
+//! [`NCProcessContext::max_threads()`](crate::NCProcessContext::max_threads) of them, so that the limit also holds roughly
+//! if throttle() is never called.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::nc_config::NCConfiguration;
+
+/// The busy and idle times of this window are used for the utilization.
+const THROTTLE_WINDOW: Duration = Duration::from_secs(2);
+
+/// One part of the time since the first checkpoint.
+#[derive(Debug)]
+struct Segment {
+    /// When the segment has ended.
+    end: Instant,
+    /// How long it was.
+    duration: Duration,
+    /// True if the node was working, false if it was sleeping or waiting for the server.
+    busy: bool,
+}
+
+/// The state that is shared by all the clones of a throttle.
+#[derive(Debug)]
+struct ThrottleState {
+    /// The time of the last checkpoint.
+    checkpoint: Instant,
+    /// The segments of the window, the oldest one first.
+    segments: VecDeque<Segment>,
+}
+
+impl ThrottleState {
+    /// Adds the time since the last checkpoint as a new segment and drops the segments that are outside of the window.
+    fn record(&mut self, busy: bool) {
+        let now = Instant::now();
+        self.segments.push_back(Segment { end: now, duration: now - self.checkpoint, busy });
+        self.checkpoint = now;
+
+        while self.segments.front().is_some_and(|segment| now - segment.end > THROTTLE_WINDOW) {
+            self.segments.pop_front();
+        }
+    }
+
+    /// The time the node has to sleep so that it's busy at most the given part of the window.
+    fn pause(&self, limit: f64) -> Duration {
+        let busy: Duration = self.segments.iter().filter(|segment| segment.busy).map(|segment| segment.duration).sum();
+        let total: Duration = self.segments.iter().map(|segment| segment.duration).sum();
+
+        busy.div_f64(limit).saturating_sub(total)
+    }
+}
+
+/// Keeps the CPU usage of the node below cpu_limit_percent, the clones share the measurements.
+#[derive(Debug, Clone)]
+pub(crate) struct NCThrottle {
+    /// cpu_limit_percent as fraction, [`None`] = no limit.
+    limit: Option<f64>,
+    /// The measurements.
+    state: Arc<Mutex<ThrottleState>>,
+}
+
+impl NCThrottle {
+    /// Creates a new throttle with the limit from the given configuration.
+    pub(crate) fn new(config: &NCConfiguration) -> Self {
+        debug!("NCThrottle::new()");
+
+        NCThrottle {
+            limit: config.cpu_limit_percent.map(|percent| percent.clamp(1, 100) as f64 / 100.0),
+            state: Arc::new(Mutex::new(ThrottleState { checkpoint: Instant::now(), segments: VecDeque::new() })),
+        }
+    }
+
+    /// The time since the last checkpoint was idle (for example waiting for the next chunk), the node starts working now.
+    pub(crate) fn resume(&self) {
+        if self.limit.is_some() {
+            if let Ok(mut state) = self.state.lock() {
+                state.record(false);
+            }
+        }
+    }
+
+    /// The time since the last checkpoint was busy, sleeps if the node has been busy for too long.
+    pub(crate) fn throttle(&self) {
+        let limit = match self.limit {
+            Some(limit) if limit < 1.0 => limit,
+            _ => return,
+        };
+
+        let pause = match self.state.lock() {
+            Ok(mut state) => {
+                state.record(true);
+                state.pause(limit)
+            }
+            Err(_) => return,
+        };
+
+        if !pause.is_zero() {
+            thread::sleep(pause);
+            self.resume();
+        }
+    }
+
+    /// The number of threads that keeps the node below its limit if all of them are busy all the time, at least one.
+    pub(crate) fn max_threads(&self) -> usize {
+        let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+
+        match self.limit {
+            Some(limit) => ((threads as f64 * limit).floor() as usize).max(1),
+            None => threads,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Works for the given time without sleeping.
+    fn busy_loop(duration: Duration) {
+        let start = Instant::now();
+        let mut x = 0_u64;
+
+        while start.elapsed() < duration {
+            x = std::hint::black_box(x.wrapping_mul(31).wrapping_add(7));
+        }
+    }
+
+    #[test]
+    fn test_duty_cycle() {
+        let throttle = NCThrottle::new(&NCConfiguration { cpu_limit_percent: Some(50), ..Default::default() });
+        let start = Instant::now();
+        let mut busy = Duration::ZERO;
+        throttle.resume();
+
+        while start.elapsed() < Duration::from_secs(2) {
+            let work = Instant::now();
+            busy_loop(Duration::from_millis(10));
+            busy += work.elapsed();
+            throttle.throttle();
+        }
+
+        let duty_cycle = busy.as_secs_f64() / start.elapsed().as_secs_f64();
+        assert!((0.4..0.6).contains(&duty_cycle), "duty cycle: {}", duty_cycle);
+    }
+
+    #[test]
+    fn test_no_limit() {
+        let throttle = NCThrottle::new(&NCConfiguration::default());
+        let start = Instant::now();
+
+        for _ in 0..10 {
+            busy_loop(Duration::from_millis(5));
+            throttle.throttle();
+        }
+
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(throttle.max_threads(), thread::available_parallelism().unwrap().get());
+
+        let throttle = NCThrottle::new(&NCConfiguration { cpu_limit_percent: Some(1), ..Default::default() });
+        assert_eq!(throttle.max_threads(), 1);
+    }
+}
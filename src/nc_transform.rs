@@ -0,0 +1,366 @@
+//! This module contains lossy transforms for floating point data, they make the messages smaller if the full precision is not needed.
+//! The transform is chosen per message type: wrap the data in [`Transformed`] in the associated types of the NCServer and NCNode traits,
+//! for example `type ProcessedDataT = Transformed<Array2D<f32>, QuantizeU16>;`. The id of the transform and its parameters are sent with the data,
+//! so the receiving side applies the inverse automatically. Data that has been sent with [`Transformed::raw()`] arrives unchanged,
+//! both kinds of data can be mixed in one job:
+//!
+//! ```
+//! use node_crunch::{Array2D, Transformed, QuantizeU16, quantize_f32_to_u16, nc_encoded_size};
+//!
+//! // A depth map where 16 bit precision is enough
+//! let depth_map = Array2D::new(256, 256, 12.5_f32);
+//! let quantized = Transformed::new(depth_map.clone(), quantize_f32_to_u16(0.0, 100.0));
+//! let raw: Transformed<_, QuantizeU16> = Transformed::raw(depth_map);
+//!
+//! assert!(nc_encoded_size(&quantized) < nc_encoded_size(&raw) * 6 / 10);
+//! ```
+
+use log::debug;
+use serde::{Serialize, Serializer, Deserialize, Deserializer, de::{DeserializeOwned, Error}};
+
+use crate::array2d::Array2D;
+
+/// A lossy transform for floating point data.
+pub trait Transform {
+    /// The id that is sent with the transformed data, every transform needs its own id.
+    const ID: u8;
+
+    /// Transforms the values into bytes. Everything that inverse() needs (the parameters of the transform) has to be part of the bytes.
+    fn forward(&self, values: &[f32]) -> Vec<u8>;
+
+    /// Restores the values from the bytes of forward(). Returns a description of the error if the bytes are not valid.
+    fn inverse(data: &[u8]) -> Result<Vec<f32>, String>;
+}
+
+/// Data that consists of floating point values and a shape, it's split into both parts before the values are transformed.
+pub trait FloatData: Sized {
+    /// Everything except the values, for example the dimension of an [`Array2D`].
+    type Shape: Serialize + DeserializeOwned;
+
+    /// Returns the shape of the data.
+    fn shape(&self) -> Self::Shape;
+
+    /// Returns all the values.
+    fn values(&self) -> &[f32];
+
+    /// Puts the data together again. Returns a description of the error if the values don't match the shape.
+    fn from_parts(shape: Self::Shape, values: Vec<f32>) -> Result<Self, String>;
+}
+
+impl FloatData for Vec<f32> {
+    type Shape = ();
+
+    fn shape(&self) {}
+
+    fn values(&self) -> &[f32] {
+        self
+    }
+
+    fn from_parts(_shape: (), values: Vec<f32>) -> Result<Self, String> {
+        Ok(values)
+    }
+}
+
+impl FloatData for Array2D<f32> {
+    type Shape = (u64, u64);
+
+    fn shape(&self) -> (u64, u64) {
+        self.dimensions()
+    }
+
+    fn values(&self) -> &[f32] {
+        self.as_slice()
+    }
+
+    fn from_parts((width, height): (u64, u64), values: Vec<f32>) -> Result<Self, String> {
+        Array2D::from_vec(width, height, values).map_err(|e| e.to_string())
+    }
+}
+
+/// The values as they are sent, borrowed from the data.
+#[derive(Serialize)]
+enum PayloadRef<'a> {
+    Raw(&'a [f32]),
+    Transformed(u8, Vec<u8>),
+}
+
+/// The values as they are received, this must match PayloadRef.
+#[derive(Deserialize)]
+enum Payload {
+    Raw(Vec<f32>),
+    Transformed(u8, Vec<u8>),
+}
+
+/// Floating point data that is sent with the transform X applied, or unchanged if it has been created with raw().
+/// The received data always contains the restored values, see transform_id() for the transform that has been used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transformed<T, X> {
+    /// The data itself.
+    data: T,
+    /// The transform that is applied when the data is sent, [`None`] for received data.
+    transform: Option<X>,
+    /// The id of the transform that is or has been used, [`None`] for raw data.
+    transform_id: Option<u8>,
+}
+
+impl<T: FloatData, X: Transform> Transformed<T, X> {
+    /// The data is sent with the given transform.
+    pub fn new(data: T, transform: X) -> Self {
+        Transformed { data, transform: Some(transform), transform_id: Some(X::ID) }
+    }
+
+    /// The data is sent unchanged.
+    pub fn raw(data: T) -> Self {
+        Transformed { data, transform: None, transform_id: None }
+    }
+
+    /// Returns the data.
+    pub fn data(&self) -> &T {
+        &self.data
+    }
+
+    /// Returns the data and drops the transform.
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+
+    /// Returns the id of the transform, [`None`] if the data is (or has been) sent unchanged.
+    pub fn transform_id(&self) -> Option<u8> {
+        self.transform_id
+    }
+}
+
+impl<T: FloatData, X: Transform> Serialize for Transformed<T, X> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let payload = match &self.transform {
+            Some(transform) => PayloadRef::Transformed(X::ID, transform.forward(self.data.values())),
+            None => PayloadRef::Raw(self.data.values()),
+        };
+
+        (self.data.shape(), payload).serialize(serializer)
+    }
+}
+
+impl<'de, T: FloatData, X: Transform> Deserialize<'de> for Transformed<T, X> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (shape, payload): (T::Shape, Payload) = Deserialize::deserialize(deserializer)?;
+
+        let (values, transform_id) = match payload {
+            Payload::Raw(values) => (values, None),
+            Payload::Transformed(id, data) if id == X::ID => (X::inverse(&data).map_err(D::Error::custom)?, Some(id)),
+            Payload::Transformed(id, _) => return Err(D::Error::custom(format!("unknown transform id: {}, expected: {}", id, X::ID))),
+        };
+
+        let data = T::from_parts(shape, values).map_err(D::Error::custom)?;
+        Ok(Transformed { data, transform: None, transform_id })
+    }
+}
+
+/// Maps every value in the range min..=max linearly to a u16, so that it needs only half of the space.
+/// Values outside of the range are clamped, NaN becomes min. The error for values inside the range is at most max_error().
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuantizeU16 {
+    min: f32,
+    max: f32,
+}
+
+/// Creates the transform that maps the values in the range min..=max to a u16, see [`QuantizeU16`].
+///
+/// # Panics
+///
+/// Panics if min or max is not finite or min is not smaller than max.
+pub fn quantize_f32_to_u16(min: f32, max: f32) -> QuantizeU16 {
+    debug!("nc_transform::quantize_f32_to_u16()");
+
+    assert!(min.is_finite() && max.is_finite() && min < max, "the range {}..={} can't be quantized", min, max);
+    QuantizeU16 { min, max }
+}
+
+impl QuantizeU16 {
+    /// The distance between two neighboring values that can be restored.
+    fn step(&self) -> f64 {
+        (self.max as f64 - self.min as f64) / u16::MAX as f64
+    }
+
+    /// The largest difference between a value inside the range and the restored value (half a step and the rounding of f32).
+    pub fn max_error(&self) -> f32 {
+        (self.step() / 2.0) as f32 + self.min.abs().max(self.max.abs()) * f32::EPSILON
+    }
+
+    /// Maps the values to u16.
+    pub fn quantize(&self, values: &[f32]) -> Vec<u16> {
+        let step = self.step();
+
+        // The cast saturates and turns NaN into 0
+        values.iter().map(|value| ((value.clamp(self.min, self.max) as f64 - self.min as f64) / step).round() as u16).collect()
+    }
+
+    /// Maps the u16 back to the range min..=max.
+    pub fn dequantize(&self, values: &[u16]) -> Vec<f32> {
+        let step = self.step();
+
+        values.iter().map(|value| (self.min as f64 + *value as f64 * step) as f32).collect()
+    }
+}
+
+impl Transform for QuantizeU16 {
+    const ID: u8 = 1;
+
+    /// The bytes are min and max followed by the quantized values, all little endian.
+    fn forward(&self, values: &[f32]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(8 + 2 * values.len());
+
+        data.extend_from_slice(&self.min.to_le_bytes());
+        data.extend_from_slice(&self.max.to_le_bytes());
+
+        for value in self.quantize(values) {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+
+        data
+    }
+
+    fn inverse(data: &[u8]) -> Result<Vec<f32>, String> {
+        if data.len() < 8 || !data.len().is_multiple_of(2) {
+            return Err(format!("invalid quantized data ({} bytes)", data.len()))
+        }
+
+        let min = f32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        let max = f32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+
+        if !(min.is_finite() && max.is_finite() && min < max) {
+            return Err(format!("invalid quantization range: {}..={}", min, max))
+        }
+
+        let values: Vec<u16> = data[8..].chunks_exact(2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]])).collect();
+        Ok(QuantizeU16 { min, max }.dequantize(&values))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::nc_communicator::nc_encoded_size;
+
+    type Depth = Transformed<Array2D<f32>, QuantizeU16>;
+
+    fn depth_map() -> Array2D<f32> {
+        let values = (0..64 * 32).map(|i| (i as f32 * 0.731).sin() * 40.0 + 50.0).collect();
+        Array2D::from_vec(64, 32, values).unwrap()
+    }
+
+    #[test]
+    fn test_quantize_error_bound() {
+        for (min, max) in [(0.0, 100.0), (-1.0, 1.0), (1000.0, 1000.5), (-3.0e6, 5.0e6)] {
+            let quantize = quantize_f32_to_u16(min, max);
+            let values: Vec<f32> = (0..=10_000).map(|i| min + (max - min) * i as f32 / 10_000.0).collect();
+            let restored = quantize.dequantize(&quantize.quantize(&values));
+
+            for (value, restored) in values.iter().zip(restored) {
+                assert!((value - restored).abs() <= quantize.max_error(), "{} -> {} ({}..={})", value, restored, min, max);
+            }
+        }
+
+        // The ends of the range are exact, values outside of the range and NaN are clamped
+        let quantize = quantize_f32_to_u16(-2.0, 6.0);
+        assert_eq!(quantize.quantize(&[-2.0, 6.0, -9.0, 99.0, f32::NAN]), vec![0, u16::MAX, 0, u16::MAX, 0]);
+        assert_eq!(quantize.dequantize(&[0, u16::MAX]), vec![-2.0, 6.0]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_quantize_invalid_range() {
+        quantize_f32_to_u16(1.0, 1.0);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let quantize = quantize_f32_to_u16(0.0, 100.0);
+        let quantized = Depth::new(depth_map(), quantize);
+        let raw = Depth::raw(depth_map());
+
+        let data = bincode::serialize(&quantized).unwrap();
+        assert!(data.len() < nc_encoded_size(&raw) * 6 / 10);
+
+        let decoded: Depth = bincode::deserialize(&data).unwrap();
+        assert_eq!(decoded.transform_id(), Some(QuantizeU16::ID));
+        assert_eq!(decoded.data().dimensions(), (64, 32));
+
+        for (value, restored) in depth_map().as_slice().iter().zip(decoded.data().as_slice()) {
+            assert!((value - restored).abs() <= quantize.max_error());
+        }
+
+        // Raw data arrives unchanged
+        let decoded: Depth = bincode::deserialize(&bincode::serialize(&raw).unwrap()).unwrap();
+        assert_eq!(decoded.transform_id(), None);
+        assert_eq!(decoded.into_inner(), depth_map());
+    }
+
+    /// A transform with another id, the receiving side only knows QuantizeU16.
+    struct Truncate;
+
+    impl Transform for Truncate {
+        const ID: u8 = 2;
+
+        fn forward(&self, values: &[f32]) -> Vec<u8> {
+            values.iter().map(|value| *value as u8).collect()
+        }
+
+        fn inverse(data: &[u8]) -> Result<Vec<f32>, String> {
+            Ok(data.iter().map(|value| *value as f32).collect())
+        }
+    }
+
+    #[test]
+    fn test_invalid_data() {
+        let data = bincode::serialize(&Transformed::new(vec![1.0_f32, 2.0], Truncate)).unwrap();
+        let error = bincode::deserialize::<Transformed<Vec<f32>, QuantizeU16>>(&data).unwrap_err().to_string();
+        assert!(error.contains("unknown transform id: 2"));
+
+        // The values must match the shape, the shape of a Vec is empty
+        let data = bincode::serialize(&Transformed::new(vec![1.0_f32, 2.0], quantize_f32_to_u16(0.0, 2.0))).unwrap();
+        let mut array_data = bincode::serialize(&(3u64, 1u64)).unwrap();
+        array_data.extend_from_slice(&data);
+        assert!(bincode::deserialize::<Depth>(&array_data).is_err());
+
+        assert!(QuantizeU16::inverse(&[0; 7]).is_err());
+        assert!(QuantizeU16::inverse(&[0; 10]).is_err());
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_mixed_job() {
+        use std::ops::Range;
+
+        use crate::nc_config::NCConfiguration;
+        use crate::nc_local;
+        use crate::nc_range::{RangeServer, RangeNode};
+
+        type Row = Transformed<Vec<f32>, QuantizeU16>;
+
+        let config = NCConfiguration { port: 0, delay_request_data: 1, heartbeat: 1, ..Default::default() };
+        let server = RangeServer::new(0..40, 3, Vec::new(), |rows: &mut Vec<(u64, Row)>, range: Range<u64>, values: &[Row]| {
+            rows.extend(range.zip(values.iter().cloned()))
+        });
+
+        // Every other row is quantized
+        let row = |i: u64| vec![i as f32 * 0.1, 50.0, 99.9];
+        let (server, _) = nc_local::run(&config, server, |_| RangeNode::new(move |i| {
+            if i % 2 == 0 { Row::new(row(i), quantize_f32_to_u16(0.0, 100.0)) } else { Row::raw(row(i)) }
+        }), 2).unwrap();
+
+        let rows = server.accumulator().lock().unwrap().clone();
+        assert_eq!(rows.len(), 40);
+
+        for (i, restored) in rows {
+            if i % 2 == 0 {
+                assert_eq!(restored.transform_id(), Some(QuantizeU16::ID));
+                assert!(row(i).iter().zip(restored.data()).all(|(value, restored)| (value - restored).abs() <= quantize_f32_to_u16(0.0, 100.0).max_error()));
+            } else {
+                assert_eq!(restored.transform_id(), None);
+                assert_eq!(restored.into_inner(), row(i));
+            }
+        }
+    }
+}
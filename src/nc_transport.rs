@@ -0,0 +1,52 @@
+//! This module contains the byte channel between the server and the nodes. The framing in the nc_communicator module only needs
+//! Read and Write, everything else that the server does with a connection (the peer address, the timeouts, peeking for the text protocol,
+//! closing it from the watchdog) is in the [`NCTransport`] trait. So the server handles a tcp connection and a shared memory connection
+//! (shmem feature, see the [`nc_shmem`](crate::nc_shmem) module) the same way, only the byte channel changes.
+//! Which one the node and the client use is set with transport in the NCConfiguration, see [`NCTransportKind`](crate::NCTransportKind).
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// A connection between the server and a node (or a client), see the module documentation.
+pub(crate) trait NCTransport: Read + Write + Send {
+    /// The address of the other side, for the logs and the aggregator.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Reads into the buffer without removing the data from the connection, waits until there is at least one byte.
+    fn peek(&self, buffer: &mut [u8]) -> io::Result<usize>;
+
+    /// A read waits at most this long, [`None`]: without a limit.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Closes both directions, also for the clones. A thread that waits in read() or write() returns right away.
+    fn shutdown(&self) -> io::Result<()>;
+
+    /// Returns a second handle to the same connection.
+    fn try_clone(&self) -> io::Result<NCStream>;
+}
+
+/// The connections that the server accepts and that the node and the client open.
+pub(crate) type NCStream = Box<dyn NCTransport>;
+
+impl NCTransport for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn peek(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        TcpStream::peek(self, buffer)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn try_clone(&self) -> io::Result<NCStream> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+}
@@ -0,0 +1,135 @@
+//! This module contains the upload thread of the node (upload_in_background in the NCConfiguration).
+//! The node sends the result of a chunk from this thread and processes the next chunk (fetched in advance, see prefetch)
+//! in the meantime, so that a large result doesn't keep the node from working.
+//!
+//! The jobs of the thread run one after the other in the order they have been added. The server keeps track of at most two
+//! chunks per node (the current one and the one fetched in advance) and maps a result to the current chunk, so the request
+//! for the next chunk in advance is added after the upload and only sent after the server has acknowledged the result.
+//! If the upload fails the request is not sent at all.
+//!
+//! The queue holds one job and the node waits for the last upload before it adds the next one, so there is at most
+//! one result uploaded while the next one is computed.
+
+use std::sync::mpsc;
+use std::thread::{spawn, JoinHandle};
+
+use log::debug;
+
+use crate::nc_error::NCError;
+
+/// A job for the upload thread, gets and returns true if the last upload has succeeded.
+type UploadJob = Box<dyn FnOnce(bool) -> bool + Send>;
+
+/// The upload thread of the node, the thread ends when this is dropped.
+#[derive(Debug)]
+pub(crate) struct NCUploader {
+    /// The queue of the thread.
+    jobs: Option<mpsc::SyncSender<UploadJob>>,
+    /// The thread itself.
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NCUploader {
+    /// Starts the upload thread.
+    pub(crate) fn start() -> Self {
+        debug!("NCUploader::start()");
+
+        let (jobs, receiver) = mpsc::sync_channel::<UploadJob>(1);
+        let thread = spawn(move || {
+            let mut uploaded = true;
+
+            for job in receiver {
+                uploaded = job(uploaded);
+            }
+        });
+
+        NCUploader { jobs: Some(jobs), thread: Some(thread) }
+    }
+
+    /// Adds the given upload to the queue, this blocks if the queue is full. The result of the upload is sent to the returned receiver.
+    pub(crate) fn upload<F>(&self, upload: F) -> mpsc::Receiver<Result<(), NCError>>
+        where F: FnOnce() -> Result<(), NCError> + Send + 'static {
+        debug!("NCUploader::upload()");
+
+        let (sender, receiver) = mpsc::channel();
+        self.add(Box::new(move |_| {
+            let result = upload();
+            let uploaded = result.is_ok();
+            // The node may have exited already
+            let _ = sender.send(result);
+            uploaded
+        }));
+
+        receiver
+    }
+
+    /// Adds the given request to the queue, its answer is sent to the returned receiver.
+    /// If after_upload is true the request is dropped without an answer when the upload before it has failed.
+    pub(crate) fn request<F, R>(&self, after_upload: bool, request: F) -> mpsc::Receiver<Result<R, NCError>>
+        where F: FnOnce() -> Result<R, NCError> + Send + 'static, R: Send + 'static {
+        debug!("NCUploader::request()");
+
+        let (sender, receiver) = mpsc::channel();
+        self.add(Box::new(move |uploaded| {
+            if uploaded || !after_upload {
+                let _ = sender.send(request());
+            } else {
+                debug!("Upload has failed, request is not sent");
+            }
+
+            uploaded
+        }));
+
+        receiver
+    }
+
+    /// Adds the job to the queue. If the thread is gone the job is dropped and its receiver gets no answer.
+    fn add(&self, job: UploadJob) {
+        if let Some(jobs) = &self.jobs {
+            let _ = jobs.send(job);
+        }
+    }
+}
+
+impl Drop for NCUploader {
+    /// Waits until the thread has finished all the jobs in the queue.
+    fn drop(&mut self) {
+        self.jobs.take();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_uploader() {
+        let uploader = NCUploader::start();
+        let start = Instant::now();
+
+        // The upload doesn't block the caller, the request is sent after it
+        let uploaded = uploader.upload(|| {
+            thread::sleep(Duration::from_millis(100));
+            Ok(())
+        });
+        let answer = uploader.request(true, move || Ok(start.elapsed()));
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert!(uploaded.recv().unwrap().is_ok());
+        assert!(answer.recv().unwrap().unwrap() >= Duration::from_millis(100));
+
+        // The request after a failed upload is dropped, other requests are sent
+        let uploaded = uploader.upload(|| Err(NCError::custom("connection lost")));
+        let dropped = uploader.request(true, || Ok(1));
+        let sent = uploader.request(false, || Ok(2));
+        assert!(uploaded.recv().unwrap().is_err());
+        assert!(dropped.recv().is_err());
+        assert_eq!(sent.recv().unwrap().unwrap(), 2);
+    }
+}
@@ -0,0 +1,272 @@
+//! This module contains the connection watchdog for the server.
+//! Every node connection is handled by a task in the thread pool. A node that connects and then sends nothing would keep
+//! its task (and a thread) busy forever, so the watchdog limits the number of connections that are handled at the same time
+//! (max_connections in the NCConfiguration) and closes connections that are open for longer than max_connection_lifetime seconds.
+//! The blocked task then gets an IO error and exits, if it was sending a chunk the chunk is released again (see chunk_send_failed()).
+//!
+//! When the server exits it waits up to shutdown_grace_ms milliseconds until every connection that is still open has finished
+//! its message, then the remaining connections are closed (see drain()). A connection is never cut by the server while a frame is
+//! being prepared, since every frame is encoded completely before its first byte is written. If a connection is closed in the middle
+//! of writing a frame the other side gets a [`NCError::UnexpectedEof`] error, so a truncated frame is never decoded.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use std::thread;
+
+use log::{debug, warn};
+
+use crate::nc_config::NCConfiguration;
+use crate::nc_error::NCError;
+use crate::nc_listener::is_ipv4;
+use crate::nc_transport::NCStream;
+
+/// Keeps track of all the open node connections of the server.
+pub(crate) struct NCConnectionWatchdog {
+    /// The open connections with the time they have been accepted.
+    connections: Mutex<HashMap<u64, (Instant, NCStream)>>,
+    /// The id for the next connection.
+    next_id: AtomicU64,
+    /// Maximum number of connections that are handled at the same time, 0 = no limit.
+    max_connections: usize,
+    /// Connections are closed after this time, [`None`] = no limit.
+    max_lifetime: Option<Duration>,
+    /// Number of connections that have been closed right away because there were too many.
+    rejected: AtomicU64,
+    /// Number of connections that have been closed by the watchdog because they were open for too long.
+    killed: AtomicU64,
+    /// Number of connections from IPv4 and from IPv6 addresses, see [`is_ipv4()`].
+    families: [AtomicU64; 2],
+}
+
+/// Removes the connection from the watchdog when the task is done.
+pub(crate) struct NCConnectionGuard {
+    watchdog: Arc<NCConnectionWatchdog>,
+    id: u64,
+}
+
+impl Drop for NCConnectionGuard {
+    fn drop(&mut self) {
+        if let Ok(mut connections) = self.watchdog.connections.lock() {
+            connections.remove(&self.id);
+        }
+    }
+}
+
+impl NCConnectionWatchdog {
+    /// Creates a new watchdog with the settings from the given configuration.
+    pub(crate) fn new(config: &NCConfiguration) -> Self {
+        debug!("NCConnectionWatchdog::new()");
+
+        NCConnectionWatchdog {
+            connections: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            max_connections: config.max_connections,
+            max_lifetime: if config.max_connection_lifetime == 0 { None } else { Some(Duration::from_secs(config.max_connection_lifetime)) },
+            rejected: AtomicU64::new(0),
+            killed: AtomicU64::new(0),
+            families: [AtomicU64::new(0), AtomicU64::new(0)],
+        }
+    }
+
+    /// Registers a new connection. Returns [`None`] if there are already max_connections connections,
+    /// then the connection should be closed right away. The node will try again later.
+    /// Every connection is counted for its address family.
+    pub(crate) fn register(watchdog: &Arc<Self>, stream: &NCStream) -> Result<Option<NCConnectionGuard>, NCError> {
+        let family = if is_ipv4(&stream.peer_addr()?) { 0 } else { 1 };
+        watchdog.families[family].fetch_add(1, Ordering::Relaxed);
+
+        let mut connections = watchdog.connections.lock()?;
+
+        if watchdog.max_connections > 0 && connections.len() >= watchdog.max_connections {
+            watchdog.rejected.fetch_add(1, Ordering::Relaxed);
+            warn!("Too many connections ({}), reject connection from: {}", connections.len(), stream.peer_addr()?);
+            return Ok(None)
+        }
+
+        let id = watchdog.next_id.fetch_add(1, Ordering::Relaxed);
+        connections.insert(id, (Instant::now(), stream.try_clone()?));
+
+        Ok(Some(NCConnectionGuard { watchdog: watchdog.clone(), id }))
+    }
+
+    /// Closes all the connections that are open for longer than max_connection_lifetime.
+    pub(crate) fn check(&self) -> Result<(), NCError> {
+        let max_lifetime = match self.max_lifetime {
+            Some(max_lifetime) => max_lifetime,
+            None => return Ok(())
+        };
+
+        let mut connections = self.connections.lock()?;
+
+        connections.retain(|_, (instant, stream)| {
+            if instant.elapsed() < max_lifetime {
+                return true
+            }
+
+            warn!("Connection is open for too long, close it: {:?}", stream.peer_addr());
+            // The connection may have been closed by the other side already.
+            let _ = stream.shutdown();
+            self.killed.fetch_add(1, Ordering::Relaxed);
+            false
+        });
+
+        Ok(())
+    }
+
+    /// Waits until all the connections are done or the grace time has passed, then closes the remaining ones.
+    /// Returns the number of connections that have been closed.
+    pub(crate) fn drain(&self, grace: Duration) -> Result<u64, NCError> {
+        debug!("NCConnectionWatchdog::drain()");
+
+        let deadline = Instant::now() + grace;
+
+        while self.in_flight()? > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut connections = self.connections.lock()?;
+
+        for (_, stream) in connections.values() {
+            warn!("Connection is still open at shutdown, close it: {:?}", stream.peer_addr());
+            let _ = stream.shutdown();
+        }
+
+        let closed = connections.len() as u64;
+        connections.clear();
+        Ok(closed)
+    }
+
+    /// The time between two calls of check().
+    pub(crate) fn interval(&self) -> Duration {
+        self.max_lifetime.map_or(Duration::from_secs(1), |max_lifetime| max_lifetime.min(Duration::from_secs(1)))
+    }
+
+    /// Number of connections that are currently handled.
+    pub(crate) fn in_flight(&self) -> Result<u64, NCError> {
+        Ok(self.connections.lock()?.len() as u64)
+    }
+
+    /// Number of connections that have been closed right away because there were too many.
+    pub(crate) fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections that have been closed because they were open for too long.
+    pub(crate) fn killed(&self) -> u64 {
+        self.killed.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections from IPv4 addresses, including IPv4 clients on an IPv6 socket.
+    pub(crate) fn ipv4_connections(&self) -> u64 {
+        self.families[0].load(Ordering::Relaxed)
+    }
+
+    /// Number of connections from IPv6 addresses.
+    pub(crate) fn ipv6_connections(&self) -> u64 {
+        self.families[1].load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+
+    use crate::nc_communicator::{NCCommunicator, NCCodec};
+
+    fn connect(listener: &TcpListener) -> (TcpStream, NCStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, Box::new(server))
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let config = NCConfiguration { max_connections: 2, ..Default::default() };
+        let watchdog = Arc::new(NCConnectionWatchdog::new(&config));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let (_client1, server1) = connect(&listener);
+        let (_client2, server2) = connect(&listener);
+        let (_client3, server3) = connect(&listener);
+
+        let guard1 = NCConnectionWatchdog::register(&watchdog, &server1).unwrap();
+        let guard2 = NCConnectionWatchdog::register(&watchdog, &server2).unwrap();
+        assert!(guard1.is_some() && guard2.is_some());
+        assert!(NCConnectionWatchdog::register(&watchdog, &server3).unwrap().is_none());
+        assert_eq!(watchdog.in_flight().unwrap(), 2);
+        assert_eq!(watchdog.rejected(), 1);
+        assert_eq!((watchdog.ipv4_connections(), watchdog.ipv6_connections()), (3, 0));
+
+        drop(guard1);
+        assert_eq!(watchdog.in_flight().unwrap(), 1);
+        assert!(NCConnectionWatchdog::register(&watchdog, &server3).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_max_lifetime() {
+        let config = NCConfiguration { max_connection_lifetime: 1, ..Default::default() };
+        let watchdog = Arc::new(NCConnectionWatchdog::new(&config));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let (_client, mut server) = connect(&listener);
+        let _guard = NCConnectionWatchdog::register(&watchdog, &server).unwrap();
+
+        // The stalled read returns as soon as the watchdog closes the connection
+        let reader = thread::spawn(move || server.read(&mut [0; 8]));
+
+        watchdog.check().unwrap();
+        assert_eq!(watchdog.killed(), 0);
+
+        thread::sleep(Duration::from_millis(1100));
+        watchdog.check().unwrap();
+        assert_eq!(watchdog.killed(), 1);
+        assert_eq!(watchdog.in_flight().unwrap(), 0);
+        assert!(matches!(reader.join().unwrap(), Ok(0) | Err(_)));
+    }
+
+    #[test]
+    fn test_drain() {
+        let config = NCConfiguration::default();
+        let watchdog = Arc::new(NCConnectionWatchdog::new(&config));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let communicator = Arc::new(NCCommunicator::new(&config).unwrap());
+        let frame = Arc::new(communicator.prepare_frame(vec![7; 32 * 1024 * 1024], "test", NCCodec::None, None).unwrap());
+        let frame_len = communicator.nc_encode_prepared(&frame, &[]).unwrap().len();
+
+        let send = |mut server: NCStream| {
+            let guard = NCConnectionWatchdog::register(&watchdog, &server).unwrap();
+            let (communicator, frame) = (communicator.clone(), frame.clone());
+
+            thread::spawn(move || {
+                let result = communicator.nc_send_prepared(&frame, &[], &mut server);
+                drop(guard);
+                result
+            })
+        };
+
+        // The node reads the whole frame within the grace time
+        let (mut client, server) = connect(&listener);
+        let sender = send(server);
+        let reader = thread::spawn(move || NCCommunicator::nc_receive_frame(&mut client, u64::MAX));
+        assert_eq!(watchdog.drain(Duration::from_secs(30)).unwrap(), 0);
+        assert!(sender.join().unwrap().is_ok());
+        assert_eq!(reader.join().unwrap().unwrap().len(), frame_len);
+
+        // The node doesn't read, the send is cut in the middle of the frame
+        let (mut client, server) = connect(&listener);
+        let sender = send(server);
+        assert_eq!(watchdog.drain(Duration::from_millis(200)).unwrap(), 1);
+        assert!(sender.join().unwrap().is_err());
+        assert_eq!(watchdog.in_flight().unwrap(), 0);
+
+        match NCCommunicator::nc_receive_frame(&mut client, u64::MAX) {
+            Ok(data) => assert_eq!(data.len(), frame_len),
+            Err(e) => assert!(matches!(e, NCError::UnexpectedEof(..) | NCError::IOError(_)), "{:?}", e),
+        }
+    }
+}
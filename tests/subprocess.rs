@@ -0,0 +1,225 @@
+//! Tests for the nc_subprocess module. This binary is also the external program for the tests:
+//! `subprocess fixture <mode>` reads the standard input and then behaves as the mode says, see fixture().
+
+use std::convert::TryInto;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::panic;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use node_crunch::{NCConfiguration, NCJobError, SubprocessRunner, SubprocessNode, SubprocessFormat, RangeServer, RangeBatch, RangeResults,
+    nc_local};
+
+/// The number that makes the square mode run out of memory.
+const POISON: u64 = 42;
+
+/// The external programs for the tests.
+fn fixture(mode: &str) {
+    let mut input = Vec::new();
+    io::stdin().read_to_end(&mut input).unwrap();
+
+    match mode {
+        "echo" => {
+            io::stdout().write_all(&input).unwrap();
+            eprintln!("echo done");
+        }
+        "fail" => {
+            eprintln!("some output\nbad input");
+            process::exit(3);
+        }
+        "hang" => thread::sleep(Duration::from_secs(60)),
+        "spin" => {
+            let mut n: u64 = 0;
+            loop {
+                n = std::hint::black_box(n.wrapping_add(1));
+            }
+        }
+        "oom" => out_of_memory(),
+        "flood" => {
+            let block = [b'x'; 65536];
+            loop {
+                io::stdout().write_all(&block).unwrap();
+            }
+        }
+        // A RangeBatch in, RangeResults<u64> with the squares out (bincode: chunk id, start, end / chunk id, length, values)
+        "square" => {
+            let field = |i: usize| u64::from_le_bytes(input[i * 8..(i + 1) * 8].try_into().unwrap());
+            let (chunk_id, range) = (field(0), field(1)..field(2));
+
+            if range.contains(&POISON) {
+                out_of_memory();
+            }
+
+            let mut output = Vec::new();
+            output.extend_from_slice(&chunk_id.to_le_bytes());
+            output.extend_from_slice(&(range.end - range.start).to_le_bytes());
+
+            for i in range {
+                output.extend_from_slice(&(i * i).to_le_bytes());
+            }
+
+            io::stdout().write_all(&output).unwrap();
+        }
+        _ => panic!("unknown fixture mode {}", mode),
+    }
+}
+
+/// Allocates and touches memory until the allocation fails.
+fn out_of_memory() {
+    let mut blocks = Vec::new();
+
+    for _ in 0..1024 {
+        blocks.push(vec![1u8; 64 * 1024 * 1024]);
+    }
+
+    println!("{}", blocks.len());
+}
+
+fn runner(mode: &str) -> SubprocessRunner {
+    SubprocessRunner {
+        args: vec!["fixture".into(), mode.into()],
+        timeout: Some(Duration::from_secs(30)),
+        ..SubprocessRunner::new(env::current_exe().unwrap())
+    }
+}
+
+fn run(runner: &SubprocessRunner) -> Result<Vec<u8>, NCJobError> {
+    runner.run(b"some input", &AtomicBool::new(false)).map(|output| output.stdout)
+}
+
+fn test_success() {
+    let output = runner("echo").run(b"some input", &AtomicBool::new(false)).unwrap();
+
+    assert_eq!(output.stdout, b"some input");
+    assert_eq!(output.stderr, b"echo done\n");
+    assert!(output.elapsed < Duration::from_secs(30));
+}
+
+fn test_exit_code() {
+    let error = run(&runner("fail")).unwrap_err();
+
+    assert_eq!(error.code, NCJobError::SUBPROCESS_FAILED);
+    assert!(error.retryable);
+    assert!(error.message.contains('3') && error.message.ends_with(": bad input"), "{}", error.message);
+
+    let error = run(&SubprocessRunner::new("/nonexistent/kernel")).unwrap_err();
+    assert_eq!(error.code, NCJobError::SUBPROCESS_FAILED);
+    assert!(error.message.contains("could not be started"), "{}", error.message);
+}
+
+fn test_timeout() {
+    let time_start = Instant::now();
+    let error = run(&SubprocessRunner { timeout: Some(Duration::from_millis(300)), ..runner("hang") }).unwrap_err();
+
+    assert_eq!(error.code, NCJobError::SUBPROCESS_TIMEOUT);
+    assert!(error.retryable);
+    assert!(time_start.elapsed() < Duration::from_secs(10));
+
+    // The chunk has been cancelled, for example the deadline has been exceeded
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let canceller = { let cancelled = cancelled.clone(); thread::spawn(move || { thread::sleep(Duration::from_millis(200)); cancelled.store(true, Ordering::Relaxed) }) };
+    let error = runner("hang").run(b"", &cancelled).unwrap_err();
+    canceller.join().unwrap();
+
+    assert_eq!(error.code, NCJobError::DEADLINE_EXCEEDED);
+    assert!(time_start.elapsed() < Duration::from_secs(20));
+}
+
+fn test_output_limit() {
+    let error = run(&SubprocessRunner { max_output_bytes: 1024 * 1024, ..runner("flood") }).unwrap_err();
+
+    assert_eq!(error.code, NCJobError::SUBPROCESS_LIMIT);
+    assert!(!error.retryable);
+}
+
+#[cfg(unix)]
+fn test_memory_limit() {
+    let error = run(&SubprocessRunner { memory_limit: Some(256 * 1024 * 1024), ..runner("oom") }).unwrap_err();
+
+    assert_eq!(error.code, NCJobError::SUBPROCESS_LIMIT, "{}", error.message);
+    assert!(!error.retryable);
+    assert!(error.message.contains("memory limit"), "{}", error.message);
+}
+
+#[cfg(unix)]
+fn test_cpu_time_limit() {
+    let time_start = Instant::now();
+    let error = run(&SubprocessRunner { cpu_time_limit: Some(Duration::from_millis(500)), ..runner("spin") }).unwrap_err();
+
+    assert_eq!(error.code, NCJobError::SUBPROCESS_LIMIT, "{}", error.message);
+    assert!(error.message.contains("CPU time limit"), "{}", error.message);
+    assert!(time_start.elapsed() < Duration::from_secs(10));
+}
+
+fn test_stderr_artifact() {
+    type SumFold = fn(&mut u64, Range<u64>, &[u64]);
+
+    let dir = env::temp_dir().join(format!("nc_subprocess_artifacts_{}", process::id()));
+    let config = NCConfiguration { port: 0, delay_request_data: 1, heartbeat: 1, max_chunk_attempts: 1, artifacts_dir: Some(dir.clone()), ..Default::default() };
+    let server: RangeServer<u64, u64, SumFold> = RangeServer::new(0..10, 10, 0, |sum, _range, values| *sum += values.iter().sum::<u64>());
+
+    // The program fails, its standard error is still attached to the chunk
+    let result = nc_local::run(&config, server, |_| SubprocessNode::<RangeBatch, RangeResults<u64>>::new(runner("fail"), SubprocessFormat::Bincode), 1);
+    let stderr = fs::read(dir.join("0").join("stderr.log"));
+    let _ = fs::remove_dir_all(&dir);
+
+    assert!(result.is_ok(), "{:?}", result.err());
+    assert_eq!(stderr.unwrap(), b"some output\nbad input\n");
+}
+
+#[cfg(unix)]
+fn test_node() {
+    type SumFold = fn(&mut u64, Range<u64>, &[u64]);
+
+    let config = NCConfiguration { port: 0, delay_request_data: 1, heartbeat: 1, ..Default::default() };
+    let server: RangeServer<u64, u64, SumFold> = RangeServer::new(0..100, 10, 0, |sum, _range, values| *sum += values.iter().sum::<u64>());
+    let sum = server.accumulator();
+    let runner = SubprocessRunner { memory_limit: Some(256 * 1024 * 1024), ..runner("square") };
+
+    let (server, _) = nc_local::run(&config, server, |_| SubprocessNode::<RangeBatch, RangeResults<u64>>::new(runner.clone(), SubprocessFormat::Bincode), 2).unwrap();
+
+    // The batch with the poison has run out of memory, that one is not given to a node again
+    assert_eq!(*sum.lock().unwrap(), (0..100).filter(|i| !(40..50).contains(i)).map(|i| i * i).sum::<u64>());
+    assert_eq!(server.failed_batches(), vec![40..50]);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.get(1).map(String::as_str) == Some("fixture") {
+        return fixture(&args[2])
+    }
+
+    let filter = args.iter().skip(1).find(|arg| !arg.starts_with('-')).cloned().unwrap_or_default();
+    let mut tests: Vec<(&str, fn())> = vec![
+        ("test_success", test_success),
+        ("test_exit_code", test_exit_code),
+        ("test_timeout", test_timeout),
+        ("test_output_limit", test_output_limit),
+        ("test_stderr_artifact", test_stderr_artifact),
+    ];
+
+    #[cfg(unix)]
+    tests.extend([("test_memory_limit", test_memory_limit as fn()), ("test_cpu_time_limit", test_cpu_time_limit), ("test_node", test_node)]);
+
+    tests.retain(|(name, _)| name.contains(&filter));
+    println!("\nrunning {} tests", tests.len());
+
+    let failed = tests.iter().filter(|(name, test)| {
+        let ok = panic::catch_unwind(test).is_ok();
+        println!("test {} ... {}", name, if ok { "ok" } else { "FAILED" });
+        !ok
+    }).count();
+
+    println!("\ntest result: {}. {} passed; {} failed\n", if failed == 0 { "ok" } else { "FAILED" }, tests.len() - failed, failed);
+
+    if failed > 0 {
+        process::exit(101);
+    }
+}